@@ -0,0 +1,81 @@
+//! Connection rate with one accept loop against several on SO_REUSEPORT sockets: clients open
+//! a connection per request, so accepting is as much of the work as handling.
+//!
+//! Run it with `cargo bench --bench accept > /dev/null`; the server logs every connection on
+//! stdout, the results go to stderr. The gain needs several cores to show, as the acceptors
+//! otherwise share one.
+
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use http_server_starter_rust::{
+    listener::Bind, request::Request, response::Response, server::Server, status::StatusCode,
+};
+
+const CLIENTS: usize = 32;
+const DURATION: Duration = Duration::from_secs(5);
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    eprintln!("{cores} cores, {CLIENTS} clients, {DURATION:?} per run");
+    for acceptors in [1, cores.max(2)] {
+        let rate = run(acceptors);
+        eprintln!("{acceptors} acceptor(s): {rate:.0} connections/s");
+    }
+}
+
+/// Serves on `acceptors` sockets until the clients are done, returning connections a second.
+fn run(acceptors: usize) -> f64 {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let addr = runtime.block_on(async {
+        let mut addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut listeners = Vec::new();
+        for _ in 0..acceptors {
+            let listener = Bind::new(addr).reuse_port(acceptors > 1).listen().unwrap();
+            addr = listener.local_addr().unwrap();
+            listeners.push(listener);
+        }
+        let handler = |_: Request| async { Ok(Response::text(StatusCode::OK, "ok")) };
+        tokio::spawn(Server::new(handler).serve_all(listeners));
+        addr
+    });
+
+    let done = Arc::new(AtomicBool::new(false));
+    let served = Arc::new(AtomicU64::new(0));
+    let clients = (0..CLIENTS)
+        .map(|_| {
+            let (done, served) = (done.clone(), served.clone());
+            thread::spawn(move || {
+                let mut response = Vec::new();
+                while !done.load(Ordering::Relaxed) {
+                    let mut stream = TcpStream::connect(addr).unwrap();
+                    stream
+                        .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+                        .unwrap();
+                    response.clear();
+                    stream.read_to_end(&mut response).unwrap();
+                    assert!(response.starts_with(b"HTTP/1.1 200"));
+                    served.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    thread::sleep(DURATION);
+    done.store(true, Ordering::Relaxed);
+    for client in clients {
+        client.join().unwrap();
+    }
+    let rate = served.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64();
+    runtime.shutdown_background();
+    rate
+}
@@ -0,0 +1,93 @@
+//! Download throughput for a large static file. Run it once as is and once with
+//! `--features io-uring` to compare reading files on the blocking thread pool with io_uring:
+//! `cargo bench --bench file_throughput > /dev/null`, results on stderr.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use http_server_starter_rust::{
+    listener::Bind, router::Router, server::Server, static_files::StaticDir,
+};
+
+const CLIENTS: usize = 8;
+const DURATION: Duration = Duration::from_secs(5);
+const FILE_SIZE: usize = 16 * 1024 * 1024;
+
+fn main() {
+    let root = std::env::temp_dir().join(format!("file-throughput-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("file.bin"), vec![b'x'; FILE_SIZE]).unwrap();
+    let backend = match cfg!(feature = "io-uring") {
+        true => "io_uring",
+        false => "thread pool",
+    };
+    eprintln!("{backend}, {CLIENTS} clients, {DURATION:?}");
+
+    let site = Router::new().mount("/", StaticDir::new(&root));
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let listener = Bind::new(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .listen()
+                .unwrap();
+            tx.send(listener.local_addr().unwrap()).unwrap();
+            Server::new(Arc::new(site)).serve(listener).await
+        })
+    });
+    let addr = rx.recv().unwrap();
+
+    let done = Arc::new(AtomicBool::new(false));
+    let received = Arc::new(AtomicU64::new(0));
+    let clients = (0..CLIENTS)
+        .map(|_| {
+            let (done, received) = (done.clone(), received.clone());
+            thread::spawn(move || download(addr, &done, &received))
+        })
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    thread::sleep(DURATION);
+    done.store(true, Ordering::Relaxed);
+    for client in clients {
+        client.join().unwrap();
+    }
+    let rate = received.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64();
+    eprintln!("{:.0} MB/s", rate / 1e6);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+/// Fetches the file over and over on one connection until `done`, counting the body bytes.
+fn download(addr: SocketAddr, done: &AtomicBool, received: &AtomicU64) {
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut buf = vec![0; 256 * 1024];
+    while !done.load(Ordering::Relaxed) {
+        writer
+            .write_all(b"GET /file.bin HTTP/1.1\r\nHost: bench\r\n\r\n")
+            .unwrap();
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.starts_with("HTTP/1.1 200"), "{line}");
+        while line != "\r\n" {
+            line.clear();
+            reader.read_line(&mut line).unwrap();
+        }
+        let mut left = FILE_SIZE;
+        while left > 0 {
+            let len = left.min(buf.len());
+            let n = reader.read(&mut buf[..len]).unwrap();
+            assert!(n > 0, "connection closed mid-body");
+            left -= n;
+            received.fetch_add(n as u64, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Request-head parsing, header map operations and response serialization, measured with
+//! criterion so that refactors of any of them can be compared against a baseline:
+//!
+//! ```text
+//! cargo bench --bench http -- --save-baseline before
+//! # make the change
+//! cargo bench --bench http -- --baseline before
+//! ```
+//!
+//! Requests are parsed by a real [`Server`] on an in-memory duplex stream, many pipelined on
+//! one connection.
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use http_server_starter_rust::{
+    headers::{ContentLength, HeaderMap, CONTENT_TYPE},
+    request::Request,
+    response::Response,
+    server::Server,
+    status::StatusCode,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// How many requests are sent on each connection.
+const PIPELINED: usize = 64;
+
+const MINIMAL: &str = "GET / HTTP/1.1\r\nHost: localhost\r\n\r\n";
+
+const BROWSER: &str = "GET /static/app.js?v=42 HTTP/1.1\r\n\
+    Host: www.example.com\r\n\
+    User-Agent: Mozilla/5.0 (X11; Linux x86_64; rv:125.0) Gecko/20100101 Firefox/125.0\r\n\
+    Accept: text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8\r\n\
+    Accept-Language: en-US,en;q=0.5\r\n\
+    Accept-Encoding: gzip, deflate, br\r\n\
+    Referer: https://www.example.com/\r\n\
+    Cookie: session=0123456789abcdef; theme=dark\r\n\
+    If-None-Match: \"5d8c72a5edda8\"\r\n\
+    Cache-Control: max-age=0\r\n\
+    Connection: keep-alive\r\n\r\n";
+
+fn request_head(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("request_head");
+    group.throughput(Throughput::Elements(PIPELINED as u64));
+    for (name, request) in [("minimal", MINIMAL), ("browser", BROWSER)] {
+        let requests = pipelined(request);
+        group.bench_function(name, |b| {
+            b.iter(|| runtime.block_on(exchange(&requests)));
+        });
+    }
+    group.finish();
+}
+
+/// `PIPELINED` copies of `request`, the last one closing the connection.
+fn pipelined(request: &str) -> Vec<u8> {
+    let head = request.strip_suffix("\r\n").unwrap();
+    let last = match head.contains("Connection:") {
+        true => request.replace("Connection: keep-alive", "Connection: close"),
+        false => format!("{head}Connection: close\r\n\r\n"),
+    };
+    let mut requests = request.repeat(PIPELINED - 1);
+    requests.push_str(&last);
+    requests.into_bytes()
+}
+
+/// Sends the requests on one connection and reads every response.
+async fn exchange(requests: &[u8]) -> usize {
+    let (mut client, server) = tokio::io::duplex(requests.len() + 64 * 1024);
+    let handler = |_: Request| async { Ok(Response::empty(StatusCode::NO_CONTENT)) };
+    let serving = tokio::spawn(Server::new(handler).serve_io(server));
+    client.write_all(requests).await.unwrap();
+    let mut responses = Vec::new();
+    client.read_to_end(&mut responses).await.unwrap();
+    serving.await.unwrap().unwrap();
+    responses.len()
+}
+
+fn header_map(c: &mut Criterion) {
+    let headers = BROWSER
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split_once(": "))
+        .collect::<Vec<_>>();
+    let map = headers.iter().fold(HeaderMap::new(), |mut map, (k, v)| {
+        map.append(k, v);
+        map
+    });
+
+    let mut group = c.benchmark_group("header_map");
+    group.bench_function("append_10", |b| {
+        b.iter(|| {
+            let mut map = HeaderMap::new();
+            for (k, v) in &headers {
+                map.append(k, v);
+            }
+            map
+        });
+    });
+    group.bench_function("get_hit", |b| b.iter(|| map.get("cache-control")));
+    group.bench_function("get_miss", |b| b.iter(|| map.get("Content-Length")));
+    group.bench_function("insert", |b| {
+        b.iter_batched_ref(
+            || map.clone(),
+            |map| map.insert("Accept", "*/*"),
+            criterion::BatchSize::SmallInput,
+        );
+    });
+    let mut with_length = map.clone();
+    with_length.typed_insert(&ContentLength(1234));
+    group.bench_function("typed_get", |b| {
+        b.iter(|| with_length.typed_get::<ContentLength>().unwrap());
+    });
+    group.finish();
+}
+
+fn response(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("response");
+    for (name, response) in [
+        ("empty", empty as fn() -> Response),
+        ("text", text),
+        ("json_10_headers", json),
+    ] {
+        let len = serialize(&runtime, response());
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_function(name, |b| b.iter(|| serialize(&runtime, response())));
+    }
+    for size in [1024, 64 * 1024] {
+        let body = vec![b'x'; size];
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("chunked", size), &body, |b, body| {
+            b.iter(|| {
+                let chunks = body
+                    .chunks(4096)
+                    .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+                    .collect::<Vec<_>>();
+                let response = Response::stream(StatusCode::OK, futures_util::stream::iter(chunks));
+                serialize(&runtime, response)
+            });
+        });
+    }
+    group.finish();
+}
+
+fn empty() -> Response {
+    Response::empty(StatusCode::NO_CONTENT)
+}
+
+fn text() -> Response {
+    Response::text(StatusCode::OK, "Hello, world!")
+}
+
+fn json() -> Response {
+    (0..8).fold(
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(r#"{"hello":"world"}"#.to_owned()),
+        |response, i| response.with_header(&format!("X-Header-{i}"), "value"),
+    )
+}
+
+/// The response as it would go out to an HTTP/1.1 client, returning its size.
+fn serialize(runtime: &tokio::runtime::Runtime, response: Response) -> usize {
+    let mut out = Vec::new();
+    runtime
+        .block_on(response.write_to_stream(&mut out, true))
+        .unwrap();
+    out.len()
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(3));
+    targets = request_head, header_map, response
+}
+criterion_main!(benches);
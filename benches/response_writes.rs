@@ -0,0 +1,159 @@
+//! Write calls per response, and time per response over a loopback socket, for
+//! `Response::write_to_stream` against writing the head a piece at a time, the way it used to
+//! be: the status line, then the name, separator, value and line end of every header.
+//!
+//! On a writer without a buffer in front of it, every write call is a syscall. Run it with
+//! `cargo bench --bench response_writes`.
+
+use std::{
+    future::Future,
+    io::{self, IoSlice},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use http_server_starter_rust::{
+    headers::{HeaderMap, CONTENT_TYPE},
+    response::Response,
+    status::StatusCode,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+const RESPONSES: usize = 20_000;
+
+fn main() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        for (name, response) in [
+            ("text, 2 headers", text as fn() -> Response),
+            ("json, 10 headers", json),
+        ] {
+            let piecewise = count(|w| Box::pin(write_piecewise(response(), w))).await;
+            let single = count(|w| Box::pin(response().write_to_stream(w, true))).await;
+            eprintln!("{name}: {piecewise} writes piece by piece, {single} with write_to_stream");
+            let piecewise = time(|w| Box::pin(write_piecewise(response(), w))).await;
+            let single = time(|w| Box::pin(response().write_to_stream(w, true))).await;
+            eprintln!(
+                "{name}: {:.1}µs piece by piece, {:.1}µs with write_to_stream",
+                piecewise * 1e6,
+                single * 1e6
+            );
+        }
+    });
+}
+
+fn text() -> Response {
+    Response::text(StatusCode::OK, "Hello, world!")
+}
+
+fn json() -> Response {
+    (0..8).fold(
+        Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(r#"{"hello":"world"}"#.to_owned()),
+        |response, i| response.with_header(&format!("X-Header-{i}"), "value"),
+    )
+}
+
+/// Writes a response, returning the size of its body.
+type Write<'a> = Pin<Box<dyn Future<Output = anyhow::Result<u64>> + 'a>>;
+
+/// How many write calls one response takes.
+async fn count(write: impl for<'a> Fn(&'a mut Counting) -> Write<'a>) -> usize {
+    let mut counting = Counting(0);
+    write(&mut counting).await.unwrap();
+    counting.0
+}
+
+/// Seconds per response, sending them one after another to a client that reads everything.
+async fn time(write: impl for<'a> Fn(&'a mut TcpStream) -> Write<'a>) -> f64 {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let mut client = TcpStream::connect(listener.local_addr().unwrap())
+        .await
+        .unwrap();
+    let reader = tokio::spawn(async move {
+        let mut sink = Vec::new();
+        client.read_to_end(&mut sink).await.unwrap();
+    });
+    let (mut server, _) = listener.accept().await.unwrap();
+    server.set_nodelay(true).unwrap();
+    let start = Instant::now();
+    for _ in 0..RESPONSES {
+        write(&mut server).await.unwrap();
+    }
+    server.shutdown().await.unwrap();
+    let elapsed = start.elapsed();
+    drop(server);
+    reader.await.unwrap();
+    elapsed.as_secs_f64() / RESPONSES as f64
+}
+
+/// The head the way it was written before it was built up in one buffer, then the body.
+async fn write_piecewise(
+    response: Response,
+    stream: &mut (impl AsyncWrite + Unpin),
+) -> anyhow::Result<u64> {
+    let mut head = Vec::new();
+    let body = response.write_to_stream(&mut head, true).await?;
+    let end = head.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let (status_line, rest) = std::str::from_utf8(&head[..end])?
+        .split_once("\r\n")
+        .unwrap();
+    stream
+        .write_all(format!("{status_line}\r\n").as_bytes())
+        .await?;
+    let mut headers = HeaderMap::new();
+    for line in rest.split("\r\n") {
+        let (k, v) = line.split_once(": ").unwrap();
+        headers.append(k, v);
+    }
+    for (k, v) in &headers {
+        stream.write_all(k.as_bytes()).await?;
+        stream.write_all(b": ").await?;
+        stream.write_all(v.as_bytes()).await?;
+        stream.write_all(b"\r\n").await?;
+    }
+    stream.write_all(b"\r\n").await?;
+    stream.write_all(&head[end + 4..]).await?;
+    stream.flush().await?;
+    Ok(body)
+}
+
+/// Takes everything it's given, counting the calls.
+struct Counting(usize);
+
+impl AsyncWrite for Counting {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.0 += 1;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.0 += 1;
+        Poll::Ready(Ok(bufs.iter().map(|b| b.len()).sum()))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
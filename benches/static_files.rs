@@ -0,0 +1,127 @@
+//! Requests a second for a small static file, from the default work-stealing runtime and from
+//! a runtime per core with [`PerCore`]. Clients keep their connections open, so this is about
+//! serving rather than accepting.
+//!
+//! Run it with `cargo bench --features thread-per-core --bench static_files > /dev/null`; the
+//! server logs every request on stdout, the results go to stderr.
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use http_server_starter_rust::{
+    listener::{Bind, Listener},
+    per_core::PerCore,
+    router::Router,
+    server::Server,
+    static_files::StaticDir,
+};
+
+const CLIENTS: usize = 32;
+const DURATION: Duration = Duration::from_secs(5);
+const FILE_SIZE: usize = 4096;
+
+fn main() {
+    let root = std::env::temp_dir().join(format!("static-files-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("file.txt"), vec![b'x'; FILE_SIZE]).unwrap();
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    eprintln!("{cores} cores, {CLIENTS} clients, {DURATION:?} per run");
+
+    let rate = measure(start_work_stealing(&root));
+    eprintln!("work-stealing runtime: {rate:.0} requests/s");
+    let rate = measure(start_per_core(&root, cores));
+    eprintln!("runtime per core: {rate:.0} requests/s");
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+fn site(root: &std::path::Path) -> Arc<Router> {
+    Arc::new(Router::new().mount("/", StaticDir::new(root)))
+}
+
+/// Listens on `count` sockets sharing a port.
+fn bind(count: usize) -> (SocketAddr, Vec<Listener>) {
+    let mut addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let mut listeners = Vec::new();
+    for _ in 0..count {
+        let listener = Bind::new(addr).reuse_port(count > 1).listen().unwrap();
+        addr = listener.local_addr().unwrap();
+        listeners.push(Listener::from(listener));
+    }
+    (addr, listeners)
+}
+
+fn start_work_stealing(root: &std::path::Path) -> SocketAddr {
+    let site = site(root);
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let (addr, listeners) = bind(1);
+            tx.send(addr).unwrap();
+            Server::new(site).serve_all(listeners).await
+        })
+    });
+    rx.recv().unwrap()
+}
+
+fn start_per_core(root: &std::path::Path, cores: usize) -> SocketAddr {
+    let site = site(root);
+    let (tx, rx) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        // The sockets are opened here and moved over to the cores' runtimes by PerCore.
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let (addr, listeners) = runtime.block_on(async { bind(cores) });
+        tx.send(addr).unwrap();
+        PerCore::new(cores).serve(listeners, move |listeners| {
+            Server::new(site.clone()).serve_all(listeners)
+        })
+    });
+    rx.recv().unwrap()
+}
+
+/// Runs the clients against `addr`, returning requests a second.
+fn measure(addr: SocketAddr) -> f64 {
+    let done = Arc::new(AtomicBool::new(false));
+    let served = Arc::new(AtomicU64::new(0));
+    let clients = (0..CLIENTS)
+        .map(|_| {
+            let (done, served) = (done.clone(), served.clone());
+            thread::spawn(move || {
+                let stream = TcpStream::connect(addr).unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut writer = stream;
+                let mut body = vec![0; FILE_SIZE];
+                while !done.load(Ordering::Relaxed) {
+                    writer
+                        .write_all(b"GET /file.txt HTTP/1.1\r\nHost: bench\r\n\r\n")
+                        .unwrap();
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    assert!(line.starts_with("HTTP/1.1 200"), "{line}");
+                    while line != "\r\n" {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+                    }
+                    reader.read_exact(&mut body).unwrap();
+                    served.fetch_add(1, Ordering::Relaxed);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    thread::sleep(DURATION);
+    done.store(true, Ordering::Relaxed);
+    for client in clients {
+        client.join().unwrap();
+    }
+    served.load(Ordering::Relaxed) as f64 / start.elapsed().as_secs_f64()
+}
@@ -0,0 +1,9 @@
+//! Arbitrary bytes as the start of a connection: request heads, bodies and whatever follows.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: &[u8]| {
+    http_server_starter_rust_fuzz::exchange(input);
+});
@@ -0,0 +1,10 @@
+//! Requests assembled from mostly valid parts, with the lengths and chunk sizes free to lie.
+
+#![no_main]
+
+use http_server_starter_rust_fuzz::StructuredRequest;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|request: StructuredRequest| {
+    http_server_starter_rust_fuzz::exchange(&request.to_bytes());
+});
@@ -0,0 +1,182 @@
+//! What the fuzz targets share: a server to throw bytes at over an in-memory connection, and a
+//! way to build those bytes out of the pieces of a request.
+//!
+//! ```text
+//! cargo +nightly fuzz run request_bytes
+//! cargo +nightly fuzz run request_structured
+//! ```
+//!
+//! Nothing installs a log subscriber, so the server's logging costs next to nothing.
+
+use std::time::Duration;
+
+use http_server_starter_rust::{
+    request::Request, response::Response, server::Server, status::StatusCode,
+};
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// The most of a body the handler reads into memory.
+const BODY_LIMIT: usize = 64 * 1024;
+
+thread_local! {
+    static RUNTIME: tokio::runtime::Runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap();
+}
+
+/// Feeds `input` to a server on a fresh connection, closing it after, and returns everything
+/// the server sent back. The handler reads every request's body in full, which puts the
+/// chunked decoder to work too.
+///
+/// A server that doesn't finish once the input runs out hangs here, for the fuzzer's timeout
+/// to catch.
+pub fn exchange(input: &[u8]) -> Vec<u8> {
+    RUNTIME.with(|runtime| {
+        runtime.block_on(async {
+            let (client, server) = tokio::io::duplex(16 * 1024);
+            let handler = |req: Request| async move {
+                let body = req.body.to_bytes(BODY_LIMIT).await?;
+                Ok(Response::text(StatusCode::OK, body.len().to_string()))
+            };
+            let server = Server::new(handler)
+                .head_timeout(Duration::from_secs(5))
+                .serve_io(server);
+            let serving = tokio::spawn(server);
+            let (mut reader, mut writer) = tokio::io::split(client);
+            let send = async {
+                // The server may stop reading and hang up partway, which is fine.
+                let _ = writer.write_all(input).await;
+                let _ = writer.shutdown().await;
+            };
+            let mut responses = Vec::new();
+            let receive = reader.read_to_end(&mut responses);
+            let ((), received) = tokio::join!(send, receive);
+            received.unwrap();
+            serving.await.unwrap().unwrap();
+            responses
+        })
+    })
+}
+
+/// A request made of parts the fuzzer picks, mostly well-formed, to get deeper into the parser
+/// than random bytes do.
+#[derive(Debug, Arbitrary)]
+pub struct StructuredRequest {
+    method: Token,
+    target: String,
+    version: Version,
+    headers: Vec<(Token, String)>,
+    body: BodyShape,
+    /// Sent as-is after the request, as a request pipelined behind it.
+    trailing: Vec<u8>,
+}
+
+#[derive(Debug, Arbitrary)]
+enum Token {
+    Get,
+    Post,
+    Head,
+    ContentLength,
+    TransferEncoding,
+    Connection,
+    Expect,
+    Other(String),
+}
+
+impl Token {
+    fn as_str(&self) -> &str {
+        match self {
+            Self::Get => "GET",
+            Self::Post => "POST",
+            Self::Head => "HEAD",
+            Self::ContentLength => "Content-Length",
+            Self::TransferEncoding => "Transfer-Encoding",
+            Self::Connection => "Connection",
+            Self::Expect => "Expect",
+            Self::Other(token) => token,
+        }
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+enum Version {
+    Http11,
+    Http10,
+    Other(String),
+}
+
+#[derive(Debug, Arbitrary)]
+enum BodyShape {
+    None,
+    /// A `Content-Length` body, `declared` bytes long if that's given, which can differ from
+    /// how much there is.
+    Length {
+        data: Vec<u8>,
+        declared: Option<u32>,
+    },
+    Chunked {
+        chunks: Vec<Chunk>,
+        trailers: Vec<(String, String)>,
+        /// Leaves off the last chunk, or the empty line after the trailers.
+        truncated: bool,
+    },
+}
+
+#[derive(Debug, Arbitrary)]
+struct Chunk {
+    data: Vec<u8>,
+    /// A size other than the data's length.
+    declared: Option<u32>,
+    extension: Option<String>,
+}
+
+impl StructuredRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let version = match &self.version {
+            Version::Http11 => "HTTP/1.1",
+            Version::Http10 => "HTTP/1.0",
+            Version::Other(version) => version,
+        };
+        let mut out =
+            format!("{} {} {version}\r\n", self.method.as_str(), self.target).into_bytes();
+        for (name, value) in &self.headers {
+            out.extend_from_slice(format!("{}: {value}\r\n", name.as_str()).as_bytes());
+        }
+        match &self.body {
+            BodyShape::None => out.extend_from_slice(b"\r\n"),
+            BodyShape::Length { data, declared } => {
+                let len = declared.map_or(data.len() as u64, u64::from);
+                out.extend_from_slice(format!("Content-Length: {len}\r\n\r\n").as_bytes());
+                out.extend_from_slice(data);
+            }
+            BodyShape::Chunked {
+                chunks,
+                trailers,
+                truncated,
+            } => {
+                out.extend_from_slice(b"Transfer-Encoding: chunked\r\n\r\n");
+                for chunk in chunks {
+                    let size = chunk.declared.map_or(chunk.data.len() as u64, u64::from);
+                    out.extend_from_slice(format!("{size:x}").as_bytes());
+                    if let Some(extension) = &chunk.extension {
+                        out.extend_from_slice(format!(";{extension}").as_bytes());
+                    }
+                    out.extend_from_slice(b"\r\n");
+                    out.extend_from_slice(&chunk.data);
+                    out.extend_from_slice(b"\r\n");
+                }
+                if !truncated {
+                    out.extend_from_slice(b"0\r\n");
+                    for (name, value) in trailers {
+                        out.extend_from_slice(format!("{name}: {value}\r\n").as_bytes());
+                    }
+                    out.extend_from_slice(b"\r\n");
+                }
+            }
+        }
+        out.extend_from_slice(&self.trailing);
+        out
+    }
+}
@@ -0,0 +1,365 @@
+//! The parts of `anyhow` the server uses, for builds without it: an [`Error`] that boxes any
+//! error, [`Context`] to wrap one in a message saying what was being done, and the
+//! [`anyhow!`], [`bail!`] and [`ensure!`] macros.
+//!
+//! The server's `minimal` feature puts this crate in `anyhow`'s place, under its name, so the
+//! code is the same either way. What anyhow has on top, backtraces and the small-pointer
+//! representation among them, is left out.
+
+use std::{
+    error::Error as StdError,
+    fmt::{self, Debug, Display},
+    ops::Deref,
+};
+
+/// Any error, with the messages of whatever [`Context`] it was given in front of it.
+///
+/// `{}` shows the outermost message, `{:#}` the whole chain separated by `: `, and `{:?}` the
+/// chain a line each, the way `main` prints it on the way out.
+pub struct Error(Box<dyn StdError + Send + Sync + 'static>);
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn new<E: StdError + Send + Sync + 'static>(error: E) -> Self {
+        Self(Box::new(error))
+    }
+
+    /// An error that's just `message`.
+    pub fn msg<M: Display + Debug + Send + Sync + 'static>(message: M) -> Self {
+        Self(Box::new(Message(message)))
+    }
+
+    /// This error, with `context` in front of it.
+    pub fn context<C: Display + Send + Sync + 'static>(self, context: C) -> Self {
+        Self(Box::new(WithContext {
+            context,
+            source: self,
+        }))
+    }
+
+    /// This error and its sources, outermost first.
+    pub fn chain(&self) -> Chain<'_> {
+        Chain(Some(&*self.0))
+    }
+
+    /// The innermost source.
+    pub fn root_cause(&self) -> &(dyn StdError + 'static) {
+        self.chain()
+            .last()
+            .expect("a chain has at least the error in it")
+    }
+
+    /// The first error in the chain that's an `E`.
+    pub fn downcast_ref<E: StdError + 'static>(&self) -> Option<&E> {
+        self.chain().find_map(|error| error.downcast_ref())
+    }
+
+    /// The error, if the outermost one is an `E`.
+    pub fn downcast<E: StdError + 'static>(self) -> Result<E, Self> {
+        self.0.downcast().map(|error| *error).map_err(Self)
+    }
+}
+
+impl<E: StdError + Send + Sync + 'static> From<E> for Error {
+    fn from(error: E) -> Self {
+        Self::new(error)
+    }
+}
+
+impl From<Error> for Box<dyn StdError + Send + Sync + 'static> {
+    fn from(error: Error) -> Self {
+        error.0
+    }
+}
+
+impl From<Error> for Box<dyn StdError + 'static> {
+    fn from(error: Error) -> Self {
+        error.0
+    }
+}
+
+impl Deref for Error {
+    type Target = dyn StdError + Send + Sync + 'static;
+
+    fn deref(&self) -> &Self::Target {
+        &*self.0
+    }
+}
+
+impl AsRef<dyn StdError + Send + Sync + 'static> for Error {
+    fn as_ref(&self) -> &(dyn StdError + Send + Sync + 'static) {
+        &*self.0
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)?;
+        if f.alternate() {
+            for source in self.chain().skip(1) {
+                write!(f, ": {source}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if f.alternate() {
+            return f.debug_tuple("Error").field(&self.0).finish();
+        }
+        write!(f, "{}", self.0)?;
+        let mut sources = self.chain().skip(1).enumerate().peekable();
+        if sources.peek().is_some() {
+            write!(f, "\n\nCaused by:")?;
+        }
+        for (i, source) in sources {
+            write!(f, "\n    {i}: {source}")?;
+        }
+        Ok(())
+    }
+}
+
+/// What [`Error::chain`] goes through.
+pub struct Chain<'a>(Option<&'a (dyn StdError + 'static)>);
+
+impl<'a> Iterator for Chain<'a> {
+    type Item = &'a (dyn StdError + 'static);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let error = self.0?;
+        self.0 = error.source();
+        Some(error)
+    }
+}
+
+struct Message<M>(M);
+
+impl<M: Display> Display for Message<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl<M: Debug> Debug for Message<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+impl<M: Display + Debug> StdError for Message<M> {}
+
+struct WithContext<C> {
+    context: C,
+    source: Error,
+}
+
+impl<C: Display> Display for WithContext<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.context, f)
+    }
+}
+
+impl<C: Display> Debug for WithContext<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Context")
+            .field("context", &format_args!("{}", self.context))
+            .field("source", &self.source.0)
+            .finish()
+    }
+}
+
+impl<C: Display> StdError for WithContext<C> {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&*self.source.0)
+    }
+}
+
+/// Wraps the error of a `Result`, or the `None` of an `Option`, in a message saying what was
+/// being done.
+pub trait Context<T, E>: private::Sealed {
+    fn context<C: Display + Send + Sync + 'static>(self, context: C) -> Result<T, Error>;
+
+    /// Like [`context`](Context::context), with the message only made if there's an error.
+    fn with_context<C, F>(self, context: F) -> Result<T, Error>
+    where
+        C: Display + Send + Sync + 'static,
+        F: FnOnce() -> C;
+}
+
+impl<T, E: private::IntoError> Context<T, E> for Result<T, E> {
+    fn context<C: Display + Send + Sync + 'static>(self, context: C) -> Result<T, Error> {
+        self.map_err(|error| error.into_error().context(context))
+    }
+
+    fn with_context<C, F>(self, context: F) -> Result<T, Error>
+    where
+        C: Display + Send + Sync + 'static,
+        F: FnOnce() -> C,
+    {
+        self.map_err(|error| error.into_error().context(context()))
+    }
+}
+
+impl<T> Context<T, std::convert::Infallible> for Option<T> {
+    fn context<C: Display + Send + Sync + 'static>(self, context: C) -> Result<T, Error> {
+        self.ok_or_else(|| Error::msg(DisplayOnly(context)))
+    }
+
+    fn with_context<C, F>(self, context: F) -> Result<T, Error>
+    where
+        C: Display + Send + Sync + 'static,
+        F: FnOnce() -> C,
+    {
+        self.ok_or_else(|| Error::msg(DisplayOnly(context())))
+    }
+}
+
+/// A context that's the whole error, for `None`, debug-printed as it's displayed.
+struct DisplayOnly<C>(C);
+
+impl<C: Display> Display for DisplayOnly<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+impl<C: Display> Debug for DisplayOnly<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+mod private {
+    use super::{Error, StdError};
+
+    pub trait Sealed {}
+
+    impl<T, E: IntoError> Sealed for Result<T, E> {}
+
+    impl<T> Sealed for Option<T> {}
+
+    /// What [`Context`](super::Context) takes the errors of: any error, or an [`Error`]
+    /// already.
+    pub trait IntoError {
+        fn into_error(self) -> Error;
+    }
+
+    impl<E: StdError + Send + Sync + 'static> IntoError for E {
+        fn into_error(self) -> Error {
+            Error::new(self)
+        }
+    }
+
+    impl IntoError for Error {
+        fn into_error(self) -> Error {
+            self
+        }
+    }
+}
+
+/// An [`Error`] from a format string and its arguments, or from a message.
+#[macro_export]
+macro_rules! anyhow {
+    ($message:literal $(,)?) => {
+        $crate::Error::msg(::std::format!($message))
+    };
+    ($message:expr $(,)?) => {
+        $crate::Error::msg($message)
+    };
+    ($format:expr, $($arg:tt)*) => {
+        $crate::Error::msg(::std::format!($format, $($arg)*))
+    };
+}
+
+/// Returns early with an [`anyhow!`] error.
+#[macro_export]
+macro_rules! bail {
+    ($($arg:tt)*) => {
+        return ::std::result::Result::Err($crate::anyhow!($($arg)*))
+    };
+}
+
+/// Returns early with an [`anyhow!`] error unless `condition` holds.
+#[macro_export]
+macro_rules! ensure {
+    ($condition:expr $(,)?) => {
+        if !$condition {
+            $crate::bail!(::std::concat!("Condition failed: `", ::std::stringify!($condition), "`"));
+        }
+    };
+    ($condition:expr, $($arg:tt)+) => {
+        if !$condition {
+            $crate::bail!($($arg)+);
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    fn failing() -> Result<()> {
+        let error = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        Err(error).context("reading config.json")?;
+        Ok(())
+    }
+
+    #[test]
+    fn shows_the_chain() {
+        let error = failing().context("starting up").unwrap_err();
+        assert_eq!(error.to_string(), "starting up");
+        assert_eq!(
+            format!("{error:#}"),
+            "starting up: reading config.json: no such file"
+        );
+        assert_eq!(
+            format!("{error:?}"),
+            "starting up\n\nCaused by:\n    0: reading config.json\n    1: no such file"
+        );
+        assert_eq!(error.root_cause().to_string(), "no such file");
+    }
+
+    #[test]
+    fn finds_errors_in_the_chain() {
+        let error = failing().unwrap_err();
+        let io = error.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io.kind(), io::ErrorKind::NotFound);
+        let io = Error::new(io::Error::from(io::ErrorKind::TimedOut));
+        assert_eq!(
+            io.downcast::<io::Error>().unwrap().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn takes_none_as_the_context() {
+        let error = None::<()>.context("no port given").unwrap_err();
+        assert_eq!(format!("{error:#}"), "no port given");
+    }
+
+    #[test]
+    fn macros_format() {
+        let port = 80;
+        assert_eq!(anyhow!("port {port} taken").to_string(), "port 80 taken");
+        assert_eq!(anyhow!("port {} taken", port).to_string(), "port 80 taken");
+        assert_eq!(anyhow!(String::from("taken")).to_string(), "taken");
+        let check = |port: u16| -> Result<()> {
+            ensure!(port != 0, "port {port} can't be listened on");
+            ensure!(port != 1);
+            Ok(())
+        };
+        assert_eq!(
+            check(0).unwrap_err().to_string(),
+            "port 0 can't be listened on"
+        );
+        assert_eq!(
+            check(1).unwrap_err().to_string(),
+            "Condition failed: `port != 1`"
+        );
+    }
+}
@@ -0,0 +1,493 @@
+//! `.http-access` files: who may reach a served directory's subtree, kept in the directory
+//! itself, like a much smaller `.htaccess`.
+//!
+//! ```text
+//! # files/team/.http-access
+//! allow 10.0.0.0/8 192.168.0.0/16
+//! deny all
+//! require valid-user
+//! require-writes user alice bob
+//! ```
+//!
+//! `allow` and `deny` take address ranges, or `all`, and the first line with a range the
+//! client is in decides whether to let it in; clients none of them mention are let in.
+//! `require` lines each name something a request must have authenticated as, and all of them
+//! must hold:
+//!
+//! - `require valid-user`, any identity at all;
+//! - `require user NAME...`, one of those identities;
+//! - `require role ROLE...` and `require mechanism MECHANISM...`, one of those
+//!   [`Grants`];
+//! - `require scope SCOPE...`, every one of those scopes;
+//! - `require none`, nothing, which opens up a subtree of a protected directory.
+//!
+//! `require-writes` lines take the same forms and only hold requests that can change
+//! something. The nearest file with `allow` or `deny` lines decides on addresses, and the
+//! nearest with `require` lines on authentication, each overriding the files further up, so a
+//! subdirectory can loosen its parent as well as tighten it.
+//!
+//! The files go by the [`Identity`] and [`Grants`] the authentication middleware in front put
+//! in the request: to ask unauthenticated clients for credentials, that middleware has to let
+//! them through with `optional`. They're parsed once and kept until they change, which each
+//! request checks for. A file that doesn't parse refuses everything under it, and the files
+//! themselves are never served or overwritten.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::{bail, Context as _};
+use tracing::warn;
+
+use crate::{
+    authz::{unauthenticated, MECHANISMS},
+    error::HttpError,
+    forwarded::{client_ip, Cidr},
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    request::{Grants, Identity, Request},
+    response::Response,
+    safe_path,
+    status::StatusCode,
+    tarpit,
+};
+
+/// The name access files go by.
+pub const ACCESS_FILE: &str = ".http-access";
+
+/// Whether `path` names an access file. The handlers serving files refuse those themselves,
+/// as if they weren't there, rather than count on [`AccessFiles`] being in front of them.
+pub(crate) fn is_access_file(path: &Path) -> bool {
+    path.file_name().is_some_and(|name| name == ACCESS_FILE)
+}
+
+#[derive(Debug, Clone)]
+enum Addresses {
+    All,
+    Ranges(Vec<Cidr>),
+}
+
+impl Addresses {
+    fn contains(&self, ip: Option<IpAddr>) -> bool {
+        match self {
+            Addresses::All => true,
+            Addresses::Ranges(ranges) => {
+                ip.is_some_and(|ip| ranges.iter().any(|range| range.contains(ip)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Requirement {
+    ValidUser,
+    Users(Vec<String>),
+    Roles(Vec<String>),
+    Mechanisms(Vec<String>),
+    Scopes(Vec<String>),
+}
+
+impl Requirement {
+    fn parse(words: &[&str]) -> anyhow::Result<Option<Self>> {
+        let owned = || words[1..].iter().map(|word| word.to_string()).collect();
+        let requirement = match words {
+            ["none"] => return Ok(None),
+            ["valid-user"] => Requirement::ValidUser,
+            ["user", _, ..] => Requirement::Users(owned()),
+            ["role", _, ..] => Requirement::Roles(owned()),
+            ["scope", _, ..] => Requirement::Scopes(owned()),
+            ["mechanism", mechanisms @ ..] if !mechanisms.is_empty() => {
+                if let Some(unknown) = mechanisms.iter().find(|m| !MECHANISMS.contains(m)) {
+                    bail!("unknown mechanism {unknown}");
+                }
+                Requirement::Mechanisms(owned())
+            }
+            _ => bail!("expected valid-user, none, or user, role, scope or mechanism and names"),
+        };
+        Ok(Some(requirement))
+    }
+
+    fn holds(&self, identity: &str, grants: Option<&Grants>) -> bool {
+        match self {
+            Requirement::ValidUser => true,
+            Requirement::Users(users) => users.iter().any(|user| user == identity),
+            Requirement::Roles(roles) => {
+                grants.is_some_and(|grants| roles.iter().any(|role| grants.roles.contains(role)))
+            }
+            Requirement::Mechanisms(mechanisms) => {
+                grants.is_some_and(|grants| mechanisms.iter().any(|m| m == grants.mechanism))
+            }
+            Requirement::Scopes(scopes) => grants
+                .is_some_and(|grants| scopes.iter().all(|scope| grants.scopes.contains(scope))),
+        }
+    }
+
+    /// The mechanisms worth challenging for when this doesn't hold for an anonymous request.
+    fn mechanisms(&self) -> &[String] {
+        match self {
+            Requirement::Mechanisms(mechanisms) => mechanisms,
+            _ => &[],
+        }
+    }
+}
+
+/// One `.http-access` file.
+#[derive(Debug, Clone, Default)]
+struct AccessFile {
+    /// The `allow` (true) and `deny` lines, in order.
+    addresses: Vec<(bool, Addresses)>,
+    /// `None` without any `require` lines, to leave it to the files further up.
+    require: Option<Vec<Requirement>>,
+    require_writes: Option<Vec<Requirement>>,
+}
+
+impl AccessFile {
+    fn parse(text: &str) -> anyhow::Result<Self> {
+        let mut file = AccessFile::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            file.add(&words)
+                .with_context(|| format!("line {}", i + 1))?;
+        }
+        Ok(file)
+    }
+
+    fn add(&mut self, words: &[&str]) -> anyhow::Result<()> {
+        match words {
+            [directive @ ("allow" | "deny"), ranges @ ..] => {
+                let addresses = match ranges {
+                    ["all"] => Addresses::All,
+                    [] => bail!("expected address ranges or all"),
+                    ranges => Addresses::Ranges(
+                        ranges
+                            .iter()
+                            .map(|range| range.parse().map_err(anyhow::Error::msg))
+                            .collect::<anyhow::Result<_>>()?,
+                    ),
+                };
+                self.addresses.push((*directive == "allow", addresses));
+            }
+            ["require", requirement @ ..] => self
+                .require
+                .get_or_insert_with(Vec::new)
+                .extend(Requirement::parse(requirement)?),
+            ["require-writes", requirement @ ..] => self
+                .require_writes
+                .get_or_insert_with(Vec::new)
+                .extend(Requirement::parse(requirement)?),
+            [other, ..] => bail!("unknown directive {other}"),
+            [] => {}
+        }
+        Ok(())
+    }
+}
+
+/// What a file was parsed from, to tell when it's changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+#[derive(Debug)]
+struct Parsed {
+    stamp: Stamp,
+    file: Arc<AccessFile>,
+}
+
+/// Checks requests under a prefix against the `.http-access` files in the directory it serves.
+#[derive(Debug, Clone)]
+pub struct AccessFiles {
+    prefix: String,
+    root: PathBuf,
+    /// Each file parsed so far, by its path.
+    cache: Arc<Mutex<HashMap<PathBuf, Parsed>>>,
+}
+
+impl AccessFiles {
+    /// Checks requests under `prefix` against the files in `root`, which is served there.
+    pub fn new(prefix: impl Into<String>, root: impl Into<PathBuf>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            root: root.into(),
+            cache: Arc::default(),
+        }
+    }
+
+    /// The access file of `dir`, `None` if it has none.
+    fn load(&self, dir: &Path) -> anyhow::Result<Option<Arc<AccessFile>>> {
+        let path = dir.join(ACCESS_FILE);
+        let metadata = match std::fs::metadata(&path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.cache.lock().unwrap().remove(&path);
+                return Ok(None);
+            }
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        let stamp = Stamp {
+            modified: metadata.modified().ok(),
+            len: metadata.len(),
+        };
+        if let Some(parsed) = self.cache.lock().unwrap().get(&path) {
+            if parsed.stamp == stamp {
+                return Ok(Some(parsed.file.clone()));
+            }
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("reading {}", path.display()))?;
+        let file =
+            Arc::new(AccessFile::parse(&text).with_context(|| format!("in {}", path.display()))?);
+        let parsed = Parsed {
+            stamp,
+            file: file.clone(),
+        };
+        self.cache.lock().unwrap().insert(path, parsed);
+        Ok(Some(file))
+    }
+
+    /// The access files that apply to `relative`, the request's path within the directory,
+    /// from the root down.
+    fn chain(&self, relative: &Path) -> anyhow::Result<Vec<Arc<AccessFile>>> {
+        let mut dir = self.root.clone();
+        let mut files = Vec::new();
+        files.extend(self.load(&dir)?);
+        let parents: Vec<_> = relative.components().collect();
+        // The last component may well be a directory too, whose own file counts.
+        for component in parents {
+            dir.push(component);
+            if !dir.is_dir() {
+                break;
+            }
+            files.extend(self.load(&dir)?);
+        }
+        Ok(files)
+    }
+}
+
+fn decide(files: &[Arc<AccessFile>], req: &Request) -> Result<(), HttpError> {
+    let ip = client_ip(req);
+    let addresses = files.iter().rev().find(|file| !file.addresses.is_empty());
+    if let Some(file) = addresses {
+        let allowed = file
+            .addresses
+            .iter()
+            .find(|(_, addresses)| addresses.contains(ip))
+            .is_none_or(|(allow, _)| *allow);
+        if !allowed {
+            let client = ip.map_or_else(
+                || "a client without an address".to_owned(),
+                |ip| ip.to_string(),
+            );
+            warn!("Denied {} to {client} by {ACCESS_FILE}", req.path);
+            tarpit::mark(req);
+            return Err(HttpError::forbidden());
+        }
+    }
+    let mut requirements: Vec<&Requirement> = Vec::new();
+    if let Some(require) = files.iter().rev().find_map(|file| file.require.as_ref()) {
+        requirements.extend(require);
+    }
+    if !req.method.is_safe() {
+        if let Some(require) = files
+            .iter()
+            .rev()
+            .find_map(|file| file.require_writes.as_ref())
+        {
+            requirements.extend(require);
+        }
+    }
+    if requirements.is_empty() {
+        return Ok(());
+    }
+    let Some(Identity(identity)) = req.extensions.get::<Identity>() else {
+        let mechanisms = requirements
+            .iter()
+            .find(|requirement| !requirement.mechanisms().is_empty())
+            .map_or(&[][..], |requirement| requirement.mechanisms());
+        return Err(unauthenticated(mechanisms));
+    };
+    let grants = req.extensions.get::<Grants>();
+    if let Some(unmet) = requirements.iter().find(|r| !r.holds(identity, grants)) {
+        if let Requirement::Mechanisms(mechanisms) = unmet {
+            return Err(unauthenticated(mechanisms));
+        }
+        warn!(
+            "{identity} isn't allowed to {} {} by {ACCESS_FILE}",
+            req.method, req.path
+        );
+        return Err(HttpError::forbidden());
+    }
+    Ok(())
+}
+
+impl Middleware for AccessFiles {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let Some(rest) = req.path.strip_prefix(&self.prefix) else {
+            return Box::pin(next.run(req));
+        };
+        // What doesn't resolve, the handler refuses anyway.
+        let Ok(relative) = safe_path::resolve(rest) else {
+            return Box::pin(next.run(req));
+        };
+        if is_access_file(&relative) {
+            return Box::pin(async { Err(HttpError::not_found()) });
+        }
+        let files = self.clone();
+        Box::pin(async move {
+            let chain = tokio::task::spawn_blocking(move || files.chain(&relative))
+                .await
+                .map_err(anyhow::Error::new)?;
+            let chain = match chain {
+                Ok(chain) => chain,
+                Err(e) => {
+                    warn!("Refusing {}: {e:#}", req.path);
+                    return Err(HttpError::new(StatusCode::INTERNAL_SERVER_ERROR, e));
+                }
+            };
+            decide(&chain, &req)?;
+            next.run(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+
+    fn files_from(texts: &[&str]) -> Vec<Arc<AccessFile>> {
+        texts
+            .iter()
+            .map(|text| Arc::new(AccessFile::parse(text).unwrap()))
+            .collect()
+    }
+
+    fn request(method: Method, ip: [u8; 4], identity: Option<(&str, Grants)>) -> Request {
+        let mut req = Request::new(method, "/files/a.txt");
+        req.remote_addr = Some((ip, 40000).into());
+        if let Some((name, grants)) = identity {
+            Identity(name.to_owned()).assign(&mut req);
+            req.extensions.insert(grants);
+        }
+        req
+    }
+
+    fn status(files: &[Arc<AccessFile>], req: &Request) -> Option<StatusCode> {
+        decide(files, req).err().map(|e| e.status)
+    }
+
+    fn grants(roles: &[&str]) -> Grants {
+        Grants {
+            mechanism: "basic",
+            scopes: Vec::new(),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn parses_directives() {
+        let file = AccessFile::parse(
+            "# team only\n\nallow 10.0.0.0/8 ::1\ndeny all\nrequire valid-user\n\
+             require-writes role admin\n",
+        )
+        .unwrap();
+        assert_eq!(file.addresses.len(), 2);
+        assert_eq!(file.require.as_ref().map(Vec::len), Some(1));
+        assert_eq!(file.require_writes.as_ref().map(Vec::len), Some(1));
+
+        let file = AccessFile::parse("require none").unwrap();
+        assert!(file.require.is_some_and(|require| require.is_empty()));
+    }
+
+    #[test]
+    fn refuses_malformed_lines() {
+        for text in [
+            "allow",
+            "allow 10.0.0.0/33",
+            "deny somewhere",
+            "require",
+            "require user",
+            "require mechanism carrier-pigeon",
+            "redirect /elsewhere",
+        ] {
+            let e = AccessFile::parse(&format!("# fine\n{text}")).unwrap_err();
+            assert_eq!(e.to_string(), "line 2", "{text}");
+        }
+    }
+
+    #[test]
+    fn first_matching_range_decides() {
+        let files = files_from(&["deny 10.0.0.1\nallow 10.0.0.0/8\ndeny all"]);
+        let anonymous = |ip| request(Method::Get, ip, None);
+        assert_eq!(
+            status(&files, &anonymous([10, 0, 0, 1])),
+            Some(StatusCode::FORBIDDEN)
+        );
+        assert_eq!(status(&files, &anonymous([10, 1, 2, 3])), None);
+        assert_eq!(
+            status(&files, &anonymous([192, 168, 0, 1])),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        // Clients no line mentions are let in.
+        let open = files_from(&["deny 10.0.0.0/8"]);
+        assert_eq!(status(&open, &anonymous([192, 168, 0, 1])), None);
+    }
+
+    #[test]
+    fn requirements_need_an_identity() {
+        let files = files_from(&["require role admin"]);
+        let req = request(Method::Get, [127, 0, 0, 1], None);
+        assert_eq!(status(&files, &req), Some(StatusCode::UNAUTHORIZED));
+        let req = request(Method::Get, [127, 0, 0, 1], Some(("bob", grants(&[]))));
+        assert_eq!(status(&files, &req), Some(StatusCode::FORBIDDEN));
+        let req = request(
+            Method::Get,
+            [127, 0, 0, 1],
+            Some(("ann", grants(&["admin"]))),
+        );
+        assert_eq!(status(&files, &req), None);
+    }
+
+    #[test]
+    fn require_writes_only_holds_writes() {
+        let files = files_from(&["require-writes user ann"]);
+        let bob = |method| request(method, [127, 0, 0, 1], Some(("bob", grants(&[]))));
+        assert_eq!(status(&files, &bob(Method::Get)), None);
+        assert_eq!(
+            status(&files, &bob(Method::Delete)),
+            Some(StatusCode::FORBIDDEN)
+        );
+    }
+
+    #[test]
+    fn nearest_file_overrides() {
+        // The root asks for a login, the subdirectory opens up.
+        let files = files_from(&[
+            "allow 10.0.0.0/8\ndeny all\nrequire valid-user",
+            "require none",
+        ]);
+        let req = request(Method::Get, [10, 0, 0, 1], None);
+        assert_eq!(status(&files, &req), None);
+        // The addresses still come from the root, the nearest with any.
+        let req = request(Method::Get, [192, 168, 0, 1], None);
+        assert_eq!(status(&files, &req), Some(StatusCode::FORBIDDEN));
+    }
+
+    #[test]
+    fn recognizes_access_files() {
+        assert!(is_access_file(Path::new(".http-access")));
+        assert!(is_access_file(Path::new("team/.http-access")));
+        assert!(!is_access_file(Path::new(".http-access/notes.txt")));
+        assert!(!is_access_file(Path::new("team/http-access")));
+    }
+}
@@ -0,0 +1,459 @@
+//! One line per request answered, in the Common or Combined Log Format web servers have long
+//! written, so that log analyzers like GoAccess or AWStats can read it as it is:
+//!
+//! ```text
+//! 127.0.0.1 - - [10/Oct/2024:13:55:36 +0000] "GET /index.html HTTP/1.1" 200 2326 "http://example.com/" "curl/8.5.0"
+//! ```
+//!
+//! The user is who the request was authenticated as, if it was, with spaces as `_`.
+//!
+//! Or as JSON, one object per line with the same fields every time, for log shippers to take
+//! in without a parser of their own:
+//!
+//! ```text
+//! {"ts":"2024-10-10T13:55:36.012Z","client_ip":"127.0.0.1","method":"GET","path":"/index.html","status":200,"duration_ms":1.93,"bytes_out":2326,"user_agent":"curl/8.5.0","request_id":null,"user":null}
+//! ```
+//!
+//! The server writes a line once a response has gone out in full, with the size of its body.
+//! Responses cut short by the client or a failing body aren't logged.
+//!
+//! Under load, [`AccessLog::sample`] keeps one in so many of the successful responses and
+//! [`AccessLog::exclude`] leaves out paths nobody reads the lines for, like health checks.
+//! Neither touches 4xx and 5xx responses, which are always logged.
+//!
+//! [`AccessLog::redact`] keeps credentials sent in the query string, like API keys, out of
+//! the request line.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    headers::{REFERER, USER_AGENT, X_REQUEST_ID},
+    log_file::{LogFile, Rotation},
+    method_override::OverriddenMethod,
+    request::{IdentitySlot, Request},
+};
+
+/// Which fields go in a line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Format {
+    /// Client, identity, user, time, request line, status and size.
+    Common,
+    /// Common, followed by the Referer and User-Agent.
+    #[default]
+    Combined,
+    /// An object with the fields of [`JsonLine`].
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "common" => Ok(Self::Common),
+            "combined" => Ok(Self::Combined),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("expected common, combined or json, got {s:?}")),
+        }
+    }
+}
+
+/// Where the lines go, and how they're laid out.
+pub struct AccessLog {
+    format: Format,
+    out: Mutex<Box<dyn Write + Send>>,
+    sample: u64,
+    exclude: Vec<String>,
+    redact: Vec<String>,
+    /// Successful responses seen, for sampling them.
+    successes: AtomicU64,
+}
+
+impl AccessLog {
+    pub fn new(out: impl Write + Send + 'static, format: Format) -> Self {
+        Self {
+            format,
+            out: Mutex::new(Box::new(out)),
+            sample: 1,
+            exclude: Vec::new(),
+            redact: Vec::new(),
+            successes: AtomicU64::new(0),
+        }
+    }
+
+    /// Logs only every `n`th response with a status under 400, the first among them; 1, the
+    /// default, logs them all.
+    pub fn sample(mut self, n: u64) -> Self {
+        self.sample = n.max(1);
+        self
+    }
+
+    /// Leaves out successful responses to requests for these paths and what's under them, so
+    /// `/healthz` leaves out `/healthz/live` too.
+    pub fn exclude<I: IntoIterator<Item = String>>(mut self, paths: I) -> Self {
+        self.exclude.extend(paths);
+        self
+    }
+
+    /// Writes the values of these query parameters as `REDACTED`.
+    pub fn redact<I: IntoIterator<Item = String>>(mut self, params: I) -> Self {
+        self.redact.extend(params);
+        self
+    }
+
+    /// Whether a `status` response to `entry` gets a line.
+    fn logs(&self, entry: &Entry, status: u16) -> bool {
+        if status >= 400 {
+            return true;
+        }
+        let excluded = entry.path.as_deref().is_some_and(|path| {
+            self.exclude.iter().any(|excluded| {
+                path.strip_prefix(excluded.trim_end_matches('/'))
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+        });
+        !excluded
+            && self
+                .successes
+                .fetch_add(1, Ordering::Relaxed)
+                .is_multiple_of(self.sample)
+    }
+
+    /// Appends to the file at `path`, creating it if need be and rotating it as `rotation`
+    /// says, or writes to stdout for `-`.
+    pub fn open(path: &Path, format: Format, rotation: Rotation) -> anyhow::Result<Self> {
+        if path == Path::new("-") {
+            return Ok(Self::new(io::stdout(), format));
+        }
+        let file = LogFile::open(path, rotation)
+            .with_context(|| format!("opening access log {}", path.display()))?;
+        Ok(Self::new(file, format))
+    }
+
+    /// Writes the line for a request that got a `status` response with `bytes` of body,
+    /// `duration` after its head was read, unless it's sampled out or excluded.
+    pub fn record(&self, entry: &Entry, status: u16, bytes: u64, duration: Duration) {
+        if !self.logs(entry, status) {
+            return;
+        }
+        let mut line = match self.format {
+            Format::Common | Format::Combined => self.clf_line(entry, status, bytes),
+            Format::Json => json_line(entry, status, bytes, duration),
+        };
+        line.push('\n');
+        // A single write, so that lines from different connections don't interleave.
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            warn!("Error writing to the access log: {e}");
+        }
+    }
+
+    fn clf_line(&self, entry: &Entry, status: u16, bytes: u64) -> String {
+        let user = match entry.identity.get() {
+            Some(user) => escape(&user.replace(' ', "_")),
+            None => "-".to_owned(),
+        };
+        let mut line = format!(
+            "{} - {user} [{}] \"{}\" {status} ",
+            entry
+                .remote_addr
+                .map_or("-".to_owned(), |addr| addr.ip().to_string()),
+            clf_time(entry.time),
+            escape(&self.redacted(&entry.request_line())),
+        );
+        match bytes {
+            0 => line.push('-'),
+            bytes => write!(line, "{bytes}").unwrap(),
+        }
+        if self.format == Format::Combined {
+            for field in [&entry.referer, &entry.user_agent] {
+                match field {
+                    Some(value) => write!(line, " \"{}\"", escape(value)).unwrap(),
+                    None => line.push_str(" \"-\""),
+                }
+            }
+        }
+        line
+    }
+
+    /// `request_line` with the values of the [`redact`](Self::redact) parameters replaced.
+    fn redacted(&self, request_line: &str) -> String {
+        let mut parts = request_line.splitn(3, ' ');
+        let (Some(method), Some(target), Some(version)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return request_line.to_owned();
+        };
+        let Some((path, query)) = target.split_once('?').filter(|_| !self.redact.is_empty()) else {
+            return request_line.to_owned();
+        };
+        let query = query
+            .split('&')
+            .map(|pair| match pair.split_once('=') {
+                Some((name, _)) if self.redact.iter().any(|param| param == name) => {
+                    format!("{name}=REDACTED")
+                }
+                _ => pair.to_owned(),
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        format!("{method} {path}?{query} {version}")
+    }
+}
+
+/// A line of the JSON format. Every field is always there, `null` when there's nothing to
+/// put in it, and new ones only ever get added.
+#[derive(Debug, Serialize)]
+pub struct JsonLine<'a> {
+    /// When the request's head was read, in RFC 3339 UTC with milliseconds.
+    pub ts: String,
+    pub client_ip: Option<String>,
+    /// Both `null` for a request whose head didn't parse.
+    pub method: Option<&'a str>,
+    pub path: Option<&'a str>,
+    pub status: u16,
+    pub duration_ms: f64,
+    pub bytes_out: u64,
+    pub user_agent: Option<&'a str>,
+    pub request_id: Option<&'a str>,
+    /// Who the request was authenticated as.
+    pub user: Option<String>,
+}
+
+fn json_line(entry: &Entry, status: u16, bytes: u64, duration: Duration) -> String {
+    let method = entry.method();
+    let line = JsonLine {
+        ts: rfc3339_time(entry.time),
+        client_ip: entry.remote_addr.map(|addr| addr.ip().to_string()),
+        method: method.as_deref(),
+        path: entry.path.as_deref(),
+        status,
+        // Rounded to the microsecond, which is as precise as it usefully gets.
+        duration_ms: (duration.as_secs_f64() * 1e6).round() / 1e3,
+        bytes_out: bytes,
+        user_agent: entry.user_agent.as_deref(),
+        request_id: entry.request_id.as_deref(),
+        user: entry.identity.get(),
+    };
+    serde_json::to_string(&line).expect("serializing an access log line")
+}
+
+/// What's logged about a request, taken from it before the handler gets it.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub remote_addr: Option<SocketAddr>,
+    pub time: SystemTime,
+    /// `-` for a request whose head didn't parse.
+    pub request_line: String,
+    pub method: Option<String>,
+    pub path: Option<String>,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// The request's `X-Request-Id`, which is its [`RequestId`] if the server gave it one.
+    ///
+    /// [`RequestId`]: crate::request_id::RequestId
+    pub request_id: Option<String>,
+    /// Filled in by the authentication, if there is any, as the request is handled.
+    pub identity: IdentitySlot,
+    /// Filled in by [`MethodOverride`](crate::method_override::MethodOverride), which
+    /// [`method`](Self::method) and the request line go by.
+    pub overridden: OverriddenMethod,
+}
+
+impl Entry {
+    pub fn new(req: &Request) -> Self {
+        Self {
+            remote_addr: req.remote_addr,
+            time: SystemTime::now(),
+            request_line: format!("{} {} {}", req.method, req.target, req.version),
+            method: Some(req.method.to_string()),
+            path: Some(req.path.clone()),
+            referer: req.headers.get(REFERER).map(str::to_owned),
+            user_agent: req.headers.get(USER_AGENT).map(str::to_owned),
+            request_id: req.headers.get(X_REQUEST_ID).map(str::to_owned),
+            identity: req
+                .extensions
+                .get::<IdentitySlot>()
+                .cloned()
+                .unwrap_or_default(),
+            overridden: req
+                .extensions
+                .get::<OverriddenMethod>()
+                .cloned()
+                .unwrap_or_default(),
+        }
+    }
+
+    /// The method the request was handled as, which is the one it was sent with unless it was
+    /// overridden.
+    pub fn method(&self) -> Option<String> {
+        match self.overridden.get() {
+            Some(method) => Some(method.to_string()),
+            None => self.method.clone(),
+        }
+    }
+
+    /// The request line, with the method it was handled as.
+    fn request_line(&self) -> String {
+        match (self.overridden.get(), self.request_line.split_once(' ')) {
+            (Some(method), Some((_, rest))) => format!("{method} {rest}"),
+            _ => self.request_line.clone(),
+        }
+    }
+
+    /// For a request that couldn't be read.
+    pub fn unparsed(remote_addr: Option<SocketAddr>) -> Self {
+        Self {
+            remote_addr,
+            time: SystemTime::now(),
+            request_line: "-".to_owned(),
+            method: None,
+            path: None,
+            referer: None,
+            user_agent: None,
+            request_id: None,
+            identity: IdentitySlot::default(),
+            overridden: OverriddenMethod::default(),
+        }
+    }
+}
+
+/// Quotes and backslashes escaped, and control characters as `\xNN`, so a client can't end a
+/// field early or forge a line.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c if c.is_control() => write!(escaped, "\\x{:02x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// `10/Oct/2024:13:55:36 +0000`, always in UTC.
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, secs) = civil(time);
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
+
+/// `2024-10-10T13:55:36.012Z`.
+pub(crate) fn rfc3339_time(time: SystemTime) -> String {
+    let (year, month, day, secs) = civil(time);
+    let millis = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.subsec_millis());
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{millis:03}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
+
+/// The UTC date of `time`, and the seconds since that day's midnight.
+pub(crate) fn civil(time: SystemTime) -> (i64, u32, u32, u64) {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    // Howard Hinnant's days_from_civil, backwards.
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month, day, secs % 86400)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        body::Body,
+        headers::{HeaderMap, X_HTTP_METHOD_OVERRIDE},
+        method_override::MethodOverride,
+        middleware::{Middleware, Next},
+        request::{Extensions, Method, Scheme},
+        response::Response,
+        status::StatusCode,
+    };
+
+    /// The entry for a POST to `/items/1` that asks to be handled as `method`, as the server
+    /// takes it, after the request has been through [`MethodOverride`].
+    async fn overridden_to(method: &str) -> Entry {
+        let mut headers = HeaderMap::new();
+        headers.insert(X_HTTP_METHOD_OVERRIDE, method);
+        let mut req = Request {
+            method: Method::Post,
+            target: "/items/1".to_owned(),
+            path: "/items/1".to_owned(),
+            query: String::new(),
+            version: "HTTP/1.1".to_owned(),
+            scheme: Scheme::Http,
+            remote_addr: None,
+            headers,
+            params: Vec::new(),
+            extensions: Extensions::default(),
+            body: Body::empty(),
+        };
+        req.extensions.insert(OverriddenMethod::default());
+        let entry = Entry::new(&req);
+        let layers: Arc<[Arc<dyn Middleware>]> = Arc::new([Arc::new(MethodOverride) as _]);
+        let handler = |_| async { Ok(Response::empty(StatusCode(204))) };
+        let _ = Next::new(layers, Arc::new(handler)).run(req).await;
+        entry
+    }
+
+    #[tokio::test]
+    async fn logs_the_overridden_method() {
+        let entry = overridden_to("delete").await;
+        let json: serde_json::Value =
+            serde_json::from_str(&json_line(&entry, 204, 0, Duration::ZERO)).unwrap();
+        assert_eq!(json["method"], "DELETE");
+        let clf = AccessLog::new(io::sink(), Format::Common).clf_line(&entry, 204, 0);
+        assert!(clf.contains("\"DELETE /items/1 HTTP/1.1\""), "{clf}");
+    }
+
+    #[tokio::test]
+    async fn logs_a_refused_override_as_sent() {
+        let entry = overridden_to("GET").await;
+        let json: serde_json::Value =
+            serde_json::from_str(&json_line(&entry, 400, 0, Duration::ZERO)).unwrap();
+        assert_eq!(json["method"], "POST");
+        let clf = AccessLog::new(io::sink(), Format::Common).clf_line(&entry, 400, 0);
+        assert!(clf.contains("\"POST /items/1 HTTP/1.1\""), "{clf}");
+    }
+}
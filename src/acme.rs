@@ -0,0 +1,384 @@
+//! Certificates from an ACME CA like Let's Encrypt, obtained on startup and renewed in the
+//! background without a restart.
+//!
+//! The account, certificate and key are kept in a cache directory, so restarts reuse them
+//! instead of running into the CA's rate limits.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context};
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, LetsEncrypt,
+    NewAccount, NewOrder, OrderStatus, RetryPolicy,
+};
+use tokio_rustls::rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivatePkcs8KeyDer},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use tracing::{info, warn};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    request::{Method, Request},
+    response::Response,
+    status::StatusCode,
+    tls::{self, TlsConfig},
+};
+
+/// The ALPN protocol TLS-ALPN-01 validation handshakes ask for (RFC 8737).
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+const HTTP_CHALLENGE_PREFIX: &str = "/.well-known/acme-challenge/";
+/// Renew once the certificate has less than this left, as Let's Encrypt recommends.
+const RENEW_BEFORE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How the CA checks that this server answers for the domains.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Challenge {
+    /// Fetches a token over plain HTTP on port 80, which [`Acme::http01_responder`] serves.
+    Http01,
+    /// Does a special TLS handshake on port 443, so no plain HTTP listener is needed.
+    TlsAlpn01,
+}
+
+impl FromStr for Challenge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http-01" => Ok(Self::Http01),
+            "tls-alpn-01" => Ok(Self::TlsAlpn01),
+            _ => Err(format!("expected http-01 or tls-alpn-01, got {s}")),
+        }
+    }
+}
+
+/// Certificates and pending challenges, shared by the renewal task and the handshakes and
+/// requests that need them.
+#[derive(Debug, Default)]
+struct State {
+    cert: RwLock<Option<Arc<CertifiedKey>>>,
+    /// Key authorizations by token, for HTTP-01.
+    http_tokens: Mutex<HashMap<String, String>>,
+    /// Validation certificates by domain, for TLS-ALPN-01.
+    alpn_certs: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl ResolvesServerCert for State {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let validating = hello
+            .alpn()
+            .is_some_and(|mut protocols| protocols.any(|p| p == ACME_TLS_ALPN));
+        if validating {
+            let domain = hello.server_name()?;
+            return self.alpn_certs.lock().unwrap().get(domain).cloned();
+        }
+        self.cert.read().unwrap().clone()
+    }
+}
+
+/// Keeps a certificate for `domains` current. [`tls_config`](Acme::tls_config) serves it, and
+/// [`run`](Acme::run) has to be spawned to obtain and renew it.
+#[derive(Clone)]
+pub struct Acme {
+    domains: Vec<String>,
+    contacts: Vec<String>,
+    directory: String,
+    cache_dir: PathBuf,
+    challenge: Challenge,
+    state: Arc<State>,
+}
+
+impl Acme {
+    /// Uses Let's Encrypt and HTTP-01 unless told otherwise.
+    pub fn new(domains: Vec<String>, cache_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            domains,
+            contacts: Vec::new(),
+            directory: LetsEncrypt::Production.url().to_owned(),
+            cache_dir: cache_dir.into(),
+            challenge: Challenge::Http01,
+            state: Arc::default(),
+        }
+    }
+
+    /// An address the CA can send expiry warnings to.
+    pub fn contact(mut self, email: &str) -> Self {
+        self.contacts.push(format!("mailto:{email}"));
+        self
+    }
+
+    /// The CA's directory URL, like Let's Encrypt's staging one for trying things out.
+    pub fn directory(mut self, url: impl Into<String>) -> Self {
+        self.directory = url.into();
+        self
+    }
+
+    pub fn challenge(mut self, challenge: Challenge) -> Self {
+        self.challenge = challenge;
+        self
+    }
+
+    /// TLS settings presenting the current certificate. Handshakes fail until there is one.
+    pub fn tls_config(&self) -> TlsConfig {
+        let mut config = TlsConfig::from_resolver(self.state.clone());
+        if self.challenge == Challenge::TlsAlpn01 {
+            config.extra_alpn.push(ACME_TLS_ALPN.to_vec());
+        }
+        config
+    }
+
+    /// Answers HTTP-01 validation requests; it has to see the requests of the plain HTTP
+    /// listener on port 80. Everything else passes through.
+    pub fn http01_responder(&self) -> Http01Responder {
+        Http01Responder {
+            state: self.state.clone(),
+        }
+    }
+
+    /// Loads the cached certificate, then keeps renewing it for as long as the server runs.
+    pub async fn run(self) {
+        match self.load_cached().await {
+            Ok(true) => info!("Loaded certificate for {} from cache", self.names()),
+            Ok(false) => {}
+            Err(e) => warn!("Ignoring cached certificate: {e:#}"),
+        }
+        loop {
+            let wait = match self.renew_if_needed().await {
+                Ok(()) => CHECK_INTERVAL,
+                Err(e) => {
+                    warn!("Obtaining certificate for {} failed: {e:#}", self.names());
+                    RETRY_INTERVAL
+                }
+            };
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    fn names(&self) -> String {
+        self.domains.join(", ")
+    }
+
+    fn cache_file(&self, name: &str) -> PathBuf {
+        self.cache_dir.join(name)
+    }
+
+    async fn load_cached(&self) -> anyhow::Result<bool> {
+        let (chain, key) = match (
+            tokio::fs::read(self.cache_file("cert.pem")).await,
+            tokio::fs::read(self.cache_file("key.pem")).await,
+        ) {
+            (Ok(chain), Ok(key)) => (chain, key),
+            _ => return Ok(false),
+        };
+        let cert = tls::certified_key(&chain, &key)?;
+        *self.state.cert.write().unwrap() = Some(cert);
+        Ok(true)
+    }
+
+    async fn renew_if_needed(&self) -> anyhow::Result<()> {
+        let current = self.state.cert.read().unwrap().clone();
+        if let Some(cert) = current {
+            let (expires, names) = inspect(cert.end_entity_cert()?)?;
+            let covered = self.domains.iter().all(|domain| names.contains(domain));
+            if covered && expires > SystemTime::now() + RENEW_BEFORE {
+                return Ok(());
+            }
+        }
+
+        info!("Requesting certificate for {}", self.names());
+        let account = self.account().await?;
+        let result = self.order(&account).await;
+        self.state.http_tokens.lock().unwrap().clear();
+        self.state.alpn_certs.lock().unwrap().clear();
+        let (chain, key) = result?;
+        let cert = tls::certified_key(chain.as_bytes(), key.as_bytes())?;
+
+        tokio::fs::write(self.cache_file("cert.pem"), &chain)
+            .await
+            .context("caching certificate")?;
+        write_private(&self.cache_file("key.pem"), key.as_bytes()).await?;
+        *self.state.cert.write().unwrap() = Some(cert);
+        info!("Installed new certificate for {}", self.names());
+        Ok(())
+    }
+
+    async fn account(&self) -> anyhow::Result<Account> {
+        let path = self.cache_file("account.json");
+        if let Ok(json) = tokio::fs::read(&path).await {
+            let credentials: AccountCredentials =
+                serde_json::from_slice(&json).context("reading cached ACME account")?;
+            return Account::builder()?
+                .from_credentials(credentials)
+                .await
+                .context("restoring ACME account");
+        }
+
+        let contacts = self.contacts.iter().map(String::as_str).collect::<Vec<_>>();
+        let (account, credentials) = Account::builder()?
+            .create(
+                &NewAccount {
+                    contact: &contacts,
+                    terms_of_service_agreed: true,
+                    only_return_existing: false,
+                },
+                self.directory.clone(),
+                None,
+            )
+            .await
+            .context("creating ACME account")?;
+        tokio::fs::create_dir_all(&self.cache_dir)
+            .await
+            .with_context(|| format!("creating {}", self.cache_dir.display()))?;
+        write_private(&path, &serde_json::to_vec(&credentials)?).await?;
+        Ok(account)
+    }
+
+    /// Runs an order through to the issued chain and its private key.
+    async fn order(&self, account: &Account) -> anyhow::Result<(String, String)> {
+        let identifiers = self
+            .domains
+            .iter()
+            .map(|domain| Identifier::Dns(domain.clone()))
+            .collect::<Vec<_>>();
+        let mut order = account
+            .new_order(&NewOrder::new(&identifiers))
+            .await
+            .context("creating order")?;
+
+        let kind = match self.challenge {
+            Challenge::Http01 => ChallengeType::Http01,
+            Challenge::TlsAlpn01 => ChallengeType::TlsAlpn01,
+        };
+        let mut authorizations = order.authorizations();
+        while let Some(authorization) = authorizations.next().await {
+            let mut authorization = authorization.context("fetching authorization")?;
+            match authorization.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => bail!("authorization is {status:?}"),
+            }
+            let mut challenge = authorization
+                .challenge(kind.clone())
+                .with_context(|| format!("the CA doesn't offer {kind:?}"))?;
+            let key_authorization = challenge.key_authorization();
+            match self.challenge {
+                Challenge::Http01 => {
+                    self.state.http_tokens.lock().unwrap().insert(
+                        challenge.token.clone(),
+                        key_authorization.as_str().to_owned(),
+                    );
+                }
+                Challenge::TlsAlpn01 => {
+                    let domain = challenge.identifier().to_string();
+                    let cert = validation_cert(&domain, key_authorization.digest().as_ref())?;
+                    self.state.alpn_certs.lock().unwrap().insert(domain, cert);
+                }
+            }
+            challenge
+                .set_ready()
+                .await
+                .context("submitting challenge")?;
+        }
+
+        let status = order
+            .poll_ready(&RetryPolicy::default())
+            .await
+            .context("waiting for validation")?;
+        if status != OrderStatus::Ready {
+            bail!("order is {status:?}");
+        }
+        let key = order.finalize().await.context("finalizing order")?;
+        let chain = order
+            .poll_certificate(&RetryPolicy::default())
+            .await
+            .context("downloading certificate")?;
+        Ok((chain, key))
+    }
+}
+
+/// When the certificate expires and which DNS names it covers.
+fn inspect(der: &CertificateDer<'_>) -> anyhow::Result<(SystemTime, Vec<String>)> {
+    let (_, cert) = X509Certificate::from_der(der).context("parsing certificate")?;
+    let expires = UNIX_EPOCH
+        + Duration::from_secs(
+            u64::try_from(cert.validity().not_after.timestamp()).unwrap_or_default(),
+        );
+    let names = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|san| {
+            san.value
+                .general_names
+                .iter()
+                .filter_map(|name| match name {
+                    GeneralName::DNSName(name) => Some(name.to_string()),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    Ok((expires, names))
+}
+
+/// The self-signed certificate carrying the key authorization digest that TLS-ALPN-01
+/// validation looks for.
+fn validation_cert(domain: &str, digest: &[u8]) -> anyhow::Result<Arc<CertifiedKey>> {
+    let mut params = rcgen::CertificateParams::new(vec![domain.to_owned()])?;
+    params.custom_extensions = vec![rcgen::CustomExtension::new_acme_identifier(digest)];
+    let key_pair = rcgen::KeyPair::generate()?;
+    let cert = params.self_signed(&key_pair)?;
+    let key = PrivatePkcs8KeyDer::from(key_pair.serialize_der());
+    let key = ring::sign::any_supported_type(&key.into()).context("loading validation key")?;
+    Ok(Arc::new(CertifiedKey::new(vec![cert.der().clone()], key)))
+}
+
+/// Writes a file only the server's user can read.
+async fn write_private(path: &Path, contents: &[u8]) -> anyhow::Result<()> {
+    let mut options = tokio::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(path)
+        .await
+        .with_context(|| format!("writing {}", path.display()))?;
+    tokio::io::AsyncWriteExt::write_all(&mut file, contents)
+        .await
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Serves the tokens of pending HTTP-01 challenges, see [`Acme::http01_responder`].
+#[derive(Clone)]
+pub struct Http01Responder {
+    state: Arc<State>,
+}
+
+impl Middleware for Http01Responder {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let key_authorization = req
+            .path
+            .strip_prefix(HTTP_CHALLENGE_PREFIX)
+            .filter(|_| req.method == Method::Get)
+            .and_then(|token| self.state.http_tokens.lock().unwrap().get(token).cloned());
+        match key_authorization {
+            Some(key_authorization) => {
+                Box::pin(async move { Ok(Response::text(StatusCode::OK, key_authorization)) })
+            }
+            None => Box::pin(next.run(req)),
+        }
+    }
+}
@@ -0,0 +1,363 @@
+//! Operational endpoints: shutting down or draining the server, reloading its configuration,
+//! connection counts and flushing what it keeps cached.
+//!
+//! Nothing here checks who's asking, so the [`Admin::router`] belongs on a listener of its own
+//! that only operators can reach (a loopback port or a Unix socket), never the public one.
+//!
+//! - `POST /shutdown` drains the public listeners and exits, like SIGTERM.
+//! - `POST /drain` stops accepting on the public listeners and lets their connections finish,
+//!   but keeps the process, and this listener, up until it's shut down. Useful for taking it
+//!   out of a load balancer before stopping it.
+//! - `POST /reload` reloads the configuration like SIGHUP does, answering 422 with the error if
+//!   it fails to load.
+//! - `GET /connections` has the connection and request counts, and how much of the
+//!   [memory budget](crate::memory) bodies take up.
+//! - `POST /cache/flush` drops cached state, and says how much there was.
+//! - `GET /settings` has the current [`Settings`], and `PATCH /settings` with a JSON object of
+//!   some of them, like `{"compression": false}`, changes those for requests from then on.
+//!   It answers with the settings as they are now.
+//! - `GET /log-filter` has the log's filter, like `info`, and `PUT /log-filter` with one, like
+//!   `{"filter": "info,http_server_starter_rust::server=debug"}`, swaps it in without a restart.
+//! - `GET /metrics` has the request [metrics](crate::metrics) in the Prometheus text format, in
+//!   builds with the `metrics` feature.
+//! - `GET /debug/pprof/profile` takes a [CPU profile](crate::profiling), when it's turned on
+//!   with [`Admin::profiling`] and built with the `profiling` feature.
+//! - `GET /stats` sums them up in JSON, with the uptime, open connections and files, how
+//!   often the server's pools had something to reuse and the latest
+//!   [sample of what the process uses](crate::process_stats), along with whatever else has
+//!   been added with [`Admin::report`].
+
+use std::{sync::Arc, time::Instant};
+
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::watch;
+use tracing::info;
+
+#[cfg(feature = "metrics")]
+use crate::metrics::Metrics;
+use crate::{
+    cache_stats::CacheStats,
+    error::HttpError,
+    extract::State,
+    memory,
+    process_stats::{self, ProcessStats},
+    response::{Json, Response},
+    router::Router,
+    server::ConnectionStats,
+    settings::Settings,
+    status::StatusCode,
+};
+
+type Reload = dyn Fn() -> Result<(), String> + Send + Sync;
+type Flush = dyn Fn() -> serde_json::Value + Send + Sync;
+type Report = Flush;
+type LogFilter = dyn Fn(Option<&str>) -> Result<String, String> + Send + Sync;
+type Caches = dyn Fn() -> Vec<(&'static str, CacheStats)> + Send + Sync;
+
+/// What the admin endpoints act on. Shutting down and draining come down to the `stop` and
+/// `drain` channels turning true; the servers have to be set up to stop on them.
+#[derive(Clone)]
+pub struct Admin {
+    started: Instant,
+    stats: Arc<ConnectionStats>,
+    stop: watch::Sender<bool>,
+    drain: watch::Sender<bool>,
+    reload: Option<Arc<Reload>>,
+    flush: Option<Arc<Flush>>,
+    settings: Option<watch::Sender<Settings>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+    caches: Option<Arc<Caches>>,
+    reports: Vec<(&'static str, Arc<Report>)>,
+    log_filter: Option<Arc<LogFilter>>,
+    process: Option<Arc<ProcessStats>>,
+    #[cfg(feature = "profiling")]
+    profiling: bool,
+}
+
+impl Admin {
+    pub fn new(
+        stats: Arc<ConnectionStats>,
+        stop: watch::Sender<bool>,
+        drain: watch::Sender<bool>,
+    ) -> Self {
+        Self {
+            started: Instant::now(),
+            stats,
+            stop,
+            drain,
+            reload: None,
+            flush: None,
+            settings: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            caches: None,
+            reports: Vec::new(),
+            log_filter: None,
+            process: None,
+            #[cfg(feature = "profiling")]
+            profiling: false,
+        }
+    }
+
+    /// What `POST /reload` runs; without it, the endpoint answers 501.
+    pub fn reload<F>(mut self, reload: F) -> Self
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.reload = Some(Arc::new(reload));
+        self
+    }
+
+    /// What `POST /cache/flush` runs, returning what it dropped for the response.
+    pub fn flush<F>(mut self, flush: F) -> Self
+    where
+        F: Fn() -> serde_json::Value + Send + Sync + 'static,
+    {
+        self.flush = Some(Arc::new(flush));
+        self
+    }
+
+    /// What `/settings` shows and changes; without them, it answers 501.
+    pub fn settings(mut self, settings: watch::Sender<Settings>) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// What `GET /metrics` exports; without it, it answers 501.
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// The hits and misses of the caches and pools `/stats` reports on, by name.
+    pub fn caches<F>(mut self, caches: F) -> Self
+    where
+        F: Fn() -> Vec<(&'static str, CacheStats)> + Send + Sync + 'static,
+    {
+        self.caches = Some(Arc::new(caches));
+        self
+    }
+
+    /// Adds `name` to `/stats`, with whatever `report` returns at the time.
+    pub fn report<F>(mut self, name: &'static str, report: F) -> Self
+    where
+        F: Fn() -> serde_json::Value + Send + Sync + 'static,
+    {
+        self.reports.push((name, Arc::new(report)));
+        self
+    }
+
+    /// What `/log-filter` shows and changes, given a filter to swap in or `None` just to look,
+    /// and returning the filter then in force; without it, it answers 501.
+    pub fn log_filter<F>(mut self, log_filter: F) -> Self
+    where
+        F: Fn(Option<&str>) -> Result<String, String> + Send + Sync + 'static,
+    {
+        self.log_filter = Some(Arc::new(log_filter));
+        self
+    }
+
+    /// The process samples `/stats` shows; something else has to take them.
+    pub fn process(mut self, process: Arc<ProcessStats>) -> Self {
+        self.process = Some(process);
+        self
+    }
+
+    /// Serves `/debug/pprof/profile`. Profiling slows the server down while it runs, so it's
+    /// off unless asked for.
+    #[cfg(feature = "profiling")]
+    pub fn profiling(mut self) -> Self {
+        self.profiling = true;
+        self
+    }
+
+    pub fn router(self) -> Router {
+        #[cfg_attr(not(feature = "profiling"), allow(unused_mut))]
+        let mut router = Router::new();
+        #[cfg(feature = "profiling")]
+        if self.profiling {
+            router = router.get("/debug/pprof/profile", crate::profiling::profile);
+        }
+        router
+            .post("/shutdown", shutdown)
+            .post("/drain", drain)
+            .post("/reload", reload)
+            .get("/connections", connections)
+            .post("/cache/flush", flush)
+            .get("/settings", settings)
+            .patch("/settings", change_settings)
+            .get("/log-filter", log_filter)
+            .put("/log-filter", change_log_filter)
+            .get("/metrics", metrics)
+            .get("/stats", stats)
+            .with_state(Arc::new(self))
+    }
+}
+
+async fn shutdown(State(admin): State<Arc<Admin>>) -> (StatusCode, Json<serde_json::Value>) {
+    info!("Shutting down, as asked on the admin listener");
+    admin.stop.send_replace(true);
+    (StatusCode::ACCEPTED, Json(json!({ "stopping": true })))
+}
+
+async fn drain(State(admin): State<Arc<Admin>>) -> (StatusCode, Json<serde_json::Value>) {
+    if !admin.drain.send_replace(true) {
+        info!("Draining, as asked on the admin listener");
+    }
+    (StatusCode::ACCEPTED, Json(json!({ "draining": true })))
+}
+
+async fn reload(
+    State(admin): State<Arc<Admin>>,
+) -> Result<(StatusCode, Json<serde_json::Value>), HttpError> {
+    let reload = admin
+        .reload
+        .clone()
+        .ok_or_else(|| unavailable("Nothing to reload"))?;
+    // Reloading reads files, and can build WebAssembly plugins.
+    let reloaded = tokio::task::spawn_blocking(move || reload())
+        .await
+        .map_err(anyhow::Error::new)?;
+    Ok(match reloaded {
+        Ok(()) => (StatusCode::OK, Json(json!({ "reloaded": true }))),
+        Err(e) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            Json(json!({ "reloaded": false, "error": e })),
+        ),
+    })
+}
+
+async fn connections(State(admin): State<Arc<Admin>>) -> Json<serde_json::Value> {
+    let stats = admin.stats.snapshot();
+    Json(json!({
+        "open": stats.open,
+        "accepted": stats.accepted,
+        "requests": stats.requests,
+        "turned_away": stats.turned_away,
+        "buffered_bytes": memory::in_use(),
+        "draining": *admin.drain.borrow(),
+    }))
+}
+
+async fn flush(State(admin): State<Arc<Admin>>) -> Json<serde_json::Value> {
+    let flushed = admin.flush.as_ref().map_or(json!({}), |flush| flush());
+    Json(json!({ "flushed": flushed }))
+}
+
+async fn settings(State(admin): State<Arc<Admin>>) -> Result<Json<Settings>, HttpError> {
+    let settings = admin.settings.as_ref().ok_or_else(no_settings)?;
+    Ok(Json(settings.borrow().clone()))
+}
+
+async fn change_settings(
+    State(admin): State<Arc<Admin>>,
+    Json(changes): Json<serde_json::Value>,
+) -> Result<Json<Settings>, HttpError> {
+    let live = admin.settings.as_ref().ok_or_else(no_settings)?;
+    let mut refused = None;
+    live.send_if_modified(|settings| match settings.patch(&changes) {
+        Ok(patched) => {
+            for (name, old, new) in settings.changes(&patched) {
+                info!("Changed setting {name} from {old} to {new}");
+            }
+            let modified = patched != *settings;
+            *settings = patched;
+            modified
+        }
+        Err(e) => {
+            refused = Some(e);
+            false
+        }
+    });
+    match refused {
+        Some(e) => Err(HttpError::bad_request(&e)),
+        None => Ok(Json(live.borrow().clone())),
+    }
+}
+
+#[derive(Deserialize)]
+struct NewLogFilter {
+    filter: String,
+}
+
+async fn log_filter(State(admin): State<Arc<Admin>>) -> Result<Json<serde_json::Value>, HttpError> {
+    let log_filter = admin.log_filter.as_ref().ok_or_else(no_log_filter)?;
+    let filter = log_filter(None).map_err(|e| HttpError::from(anyhow::anyhow!(e)))?;
+    Ok(Json(json!({ "filter": filter })))
+}
+
+async fn change_log_filter(
+    State(admin): State<Arc<Admin>>,
+    Json(new): Json<NewLogFilter>,
+) -> Result<Json<serde_json::Value>, HttpError> {
+    let log_filter = admin.log_filter.as_ref().ok_or_else(no_log_filter)?;
+    let filter = log_filter(Some(&new.filter)).map_err(|e| HttpError::bad_request(&e))?;
+    info!("Changed the log filter to {filter}, as asked on the admin listener");
+    Ok(Json(json!({ "filter": filter })))
+}
+
+#[cfg(feature = "metrics")]
+async fn metrics(State(admin): State<Arc<Admin>>) -> Result<Response, HttpError> {
+    let metrics = admin
+        .metrics
+        .as_ref()
+        .ok_or_else(|| unavailable("No metrics collected"))?;
+    Ok(metrics.response())
+}
+
+#[cfg(not(feature = "metrics"))]
+async fn metrics() -> Result<Response, HttpError> {
+    Err(unavailable("No metrics in this build"))
+}
+
+async fn stats(State(admin): State<Arc<Admin>>) -> Json<serde_json::Value> {
+    let connections = admin.stats.snapshot();
+    let caches = admin
+        .caches
+        .as_ref()
+        .map(|caches| caches())
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, stats)| (name.to_owned(), json!(stats)))
+        .collect::<serde_json::Map<_, _>>();
+    let mut stats = json!({
+        "uptime_secs": admin.started.elapsed().as_secs(),
+        "connections": {
+            "open": connections.open,
+            "accepted": connections.accepted,
+            "turned_away": connections.turned_away,
+        },
+        "requests": connections.requests,
+        "open_files": process_stats::open_files(),
+        "caches": caches,
+    });
+    if let Some(process) = &admin.process {
+        stats["process"] = process.json();
+    }
+    // The request totals, by status and with their bytes, when there's metrics to take them from.
+    #[cfg(feature = "metrics")]
+    if let Some(metrics) = &admin.metrics {
+        if let serde_json::Value::Object(summary) = json!(metrics.summary()) {
+            stats.as_object_mut().unwrap().extend(summary);
+        }
+    }
+    for (name, report) in &admin.reports {
+        stats[*name] = report();
+    }
+    Json(stats)
+}
+
+fn unavailable(message: &'static str) -> HttpError {
+    HttpError::new(StatusCode::NOT_IMPLEMENTED, anyhow::anyhow!(message))
+}
+
+fn no_settings() -> HttpError {
+    unavailable("No settings to change")
+}
+
+fn no_log_filter() -> HttpError {
+    unavailable("No log filter to change")
+}
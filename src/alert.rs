@@ -0,0 +1,186 @@
+//! An alert for when the share of requests answered with a 5xx status gets too high.
+//!
+//! [`ErrorRate`] counts responses over a sliding window and, once the window has seen enough
+//! of them and more than the threshold were server errors, fires its [`Action`] with a JSON
+//! description of the spike:
+//!
+//! ```text
+//! {"event":"error_rate","window_secs":60,"requests":214,"errors":87,"rate":0.4065,"threshold":0.1,"time":"2024-10-10T13:55:36.012Z"}
+//! ```
+//!
+//! It fires at most once per window, so a spike that lasts makes an alert a minute rather than
+//! one per request.
+
+use std::{
+    collections::VecDeque,
+    io::Write,
+    process::{Command, Stdio},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::{access_log::rfc3339_time, proxy::Upstream, webhook};
+
+/// What's done about a spike.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// POSTs the description to this URL.
+    Webhook(Upstream),
+    /// Runs this with `sh -c`, with the description on its stdin.
+    Command(String),
+}
+
+/// The 5xx rate over a sliding window, and what to do when it's too high.
+#[derive(Debug)]
+pub struct ErrorRate {
+    threshold: f64,
+    window: Duration,
+    min_requests: u64,
+    action: Action,
+    started: Instant,
+    state: Mutex<Window>,
+}
+
+/// Responses counted per second, oldest first, with their totals.
+#[derive(Debug, Default)]
+struct Window {
+    seconds: VecDeque<Second>,
+    requests: u64,
+    errors: u64,
+    last_alert: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Second {
+    at: u64,
+    requests: u64,
+    errors: u64,
+}
+
+impl ErrorRate {
+    /// Fires `action` when more than `threshold` (a fraction, like `0.1`) of the responses in
+    /// the last minute were 5xx, once there were at least 20 of them.
+    pub fn new(threshold: f64, action: Action) -> Self {
+        Self {
+            threshold,
+            window: Duration::from_secs(60),
+            min_requests: 20,
+            action,
+            started: Instant::now(),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Looks at the responses from the last `window`, rounded up to seconds, and fires at
+    /// most once per `window`.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Doesn't fire on windows with fewer than `min_requests` responses, where a couple of
+    /// errors would make for a high rate.
+    pub fn min_requests(mut self, min_requests: u64) -> Self {
+        self.min_requests = min_requests;
+        self
+    }
+
+    /// Counts a response with `status`.
+    pub fn record(&self, status: u16) {
+        let now = Instant::now();
+        let at = now.duration_since(self.started).as_secs();
+        let window_secs = self.window.as_secs_f64().ceil() as u64;
+        let error = u64::from(status >= 500);
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        while let Some(oldest) = state.seconds.front() {
+            if oldest.at + window_secs > at {
+                break;
+            }
+            state.requests -= oldest.requests;
+            state.errors -= oldest.errors;
+            state.seconds.pop_front();
+        }
+        match state.seconds.back_mut() {
+            Some(second) if second.at == at => {
+                second.requests += 1;
+                second.errors += error;
+            }
+            _ => state.seconds.push_back(Second {
+                at,
+                requests: 1,
+                errors: error,
+            }),
+        }
+        state.requests += 1;
+        state.errors += error;
+        if error == 0 || state.requests < self.min_requests {
+            return;
+        }
+        let rate = state.errors as f64 / state.requests as f64;
+        let cooling_down = state
+            .last_alert
+            .is_some_and(|last| now.duration_since(last) < self.window);
+        if rate <= self.threshold || cooling_down {
+            return;
+        }
+        state.last_alert = Some(now);
+        let payload = json!({
+            "event": "error_rate",
+            "window_secs": window_secs,
+            "requests": state.requests,
+            "errors": state.errors,
+            "rate": (rate * 10_000.0).round() / 10_000.0,
+            "threshold": self.threshold,
+            "time": rfc3339_time(SystemTime::now()),
+        })
+        .to_string();
+        drop(guard);
+        warn!(
+            "{:.1}% of the last {window_secs} s of requests were server errors",
+            rate * 100.0
+        );
+        let action = self.action.clone();
+        // Neither a slow webhook nor a slow command gets to hold up the request that tipped it.
+        let spawned = std::thread::Builder::new()
+            .name("error-rate-alert".to_owned())
+            .spawn(move || fire(&action, &payload));
+        if let Err(e) = spawned {
+            warn!("Error starting the error rate alert: {e}");
+        }
+    }
+}
+
+fn fire(action: &Action, payload: &str) {
+    match action {
+        Action::Webhook(url) => match webhook::post_json(url, "", payload) {
+            Ok(()) => info!("Sent the error rate alert to {}", url.authority()),
+            Err(e) => warn!(
+                "Error sending the error rate alert to {}: {e}",
+                url.authority()
+            ),
+        },
+        Action::Command(command) => {
+            let run = || -> std::io::Result<std::process::ExitStatus> {
+                let mut child = Command::new("sh")
+                    .arg("-c")
+                    .arg(command)
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                if let Some(mut stdin) = child.stdin.take() {
+                    // A command that doesn't read its stdin isn't an error.
+                    let _ = stdin.write_all(payload.as_bytes());
+                }
+                child.wait()
+            };
+            match run() {
+                Ok(status) if status.success() => {}
+                Ok(status) => warn!("The error rate alert command failed: {status}"),
+                Err(e) => warn!("Error running the error rate alert command: {e}"),
+            }
+        }
+    }
+}
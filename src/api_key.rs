@@ -0,0 +1,233 @@
+//! API keys, sent in a header or the query string, each with a name, a scope and, if it
+//! needs one, a rate limit of its own. They're kept in a key file:
+//!
+//! ```toml
+//! [[key]]
+//! name = "nightly-backup"
+//! key = "k_3q2-7wAAAAAA0bG4mZk9Hx"
+//! scope = "read-write"
+//!
+//! [[key]]
+//! name = "dashboard"
+//! key = "k_Zm9vYmFyYmF6cXV4"
+//! # Polls more often than the --rate-limit everyone else is kept to.
+//! rate-limit = { rate = 50.0, burst = 100 }
+//! ```
+//!
+//! Keys are `read-only` unless their scope says otherwise, which lets them make requests with
+//! safe methods like `GET` and nothing else. The key's name is the request's [`Identity`], and
+//! its `rate-limit` takes over from the per-address one through a [`ClientLimit`]. Keys are
+//! compared in constant time, all of them on every request.
+//!
+//! A key in the query string is taken out of it before the request goes on, so that it
+//! doesn't reach a proxied upstream, but headers are better: query strings end up in
+//! browser histories and other servers' logs.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::Context as _;
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::{
+    bearer_auth::constant_time_eq,
+    error::HttpError,
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    rate_limit::ClientLimit,
+    request::{Grants, Identity, Request},
+    response::Response,
+    secret::read_private,
+    security_log::{self, Event},
+    settings::RateLimit,
+    status::StatusCode,
+};
+
+/// The keys of a key file.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeys {
+    #[serde(default, rename = "key")]
+    keys: Vec<ApiKey>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ApiKey {
+    pub name: String,
+    key: String,
+    #[serde(default)]
+    pub scope: Scope,
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// What a key may do.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// Requests with safe methods.
+    #[default]
+    ReadOnly,
+    /// Any request.
+    ReadWrite,
+}
+
+impl ApiKeys {
+    /// Reads the key file at `path`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = read_private(path)?;
+        let keys: Self = toml::from_str(&text)
+            .with_context(|| format!("reading keys from {}", path.display()))?;
+        for (i, key) in keys.keys.iter().enumerate() {
+            if key.name.is_empty() || key.key.is_empty() {
+                anyhow::bail!("key {} in {} has no name or no key", i + 1, path.display());
+            }
+            if keys.keys[..i].iter().any(|other| other.name == key.name) {
+                anyhow::bail!("{} has two keys named {}", path.display(), key.name);
+            }
+        }
+        Ok(keys)
+    }
+
+    /// The key `sent` is.
+    pub fn find(&self, sent: &str) -> Option<&ApiKey> {
+        // Every key is looked at, whichever matches.
+        self.keys.iter().fold(None, |found, key| {
+            match constant_time_eq(key.key.as_bytes(), sent.as_bytes()) {
+                true => Some(key),
+                false => found,
+            }
+        })
+    }
+}
+
+/// Asks for an API key on requests under a prefix.
+#[derive(Clone)]
+pub struct ApiKeyAuth {
+    prefix: String,
+    keys: Arc<ApiKeys>,
+    header: String,
+    query: Option<String>,
+    optional: bool,
+}
+
+impl ApiKeyAuth {
+    /// Protects everything under `prefix` with `keys`, which can be shared between prefixes,
+    /// taking them from `X-API-Key` or the `api_key` query parameter.
+    pub fn new(prefix: impl Into<String>, keys: Arc<ApiKeys>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            keys,
+            header: "X-API-Key".to_owned(),
+            query: Some("api_key".to_owned()),
+            optional: false,
+        }
+    }
+
+    /// The header keys are sent in.
+    pub fn header(mut self, header: impl Into<String>) -> Self {
+        self.header = header.into();
+        self
+    }
+
+    /// The query parameter keys can be sent in instead, `None` to only take the header.
+    pub fn query(mut self, query: Option<String>) -> Self {
+        self.query = query;
+        self
+    }
+
+    /// Lets requests without a key through, anonymous, like
+    /// [`BasicAuth::optional`](crate::basic_auth::BasicAuth::optional).
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    /// The key `req` was sent with, taken out of its query string if it was there.
+    fn take_key(&self, req: &mut Request) -> Option<String> {
+        if let Some(key) = req.header(&self.header) {
+            return Some(key.trim().to_owned());
+        }
+        let param = self.query.as_deref()?;
+        let mut key = None;
+        let rest = req
+            .query
+            .split('&')
+            .filter(|pair| {
+                let decoded = serde_urlencoded::from_str::<Vec<(String, String)>>(pair);
+                match decoded.ok().and_then(|pairs| pairs.into_iter().next()) {
+                    Some((name, value)) if name == param => {
+                        key.get_or_insert(value);
+                        false
+                    }
+                    _ => true,
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        req.query = rest;
+        key
+    }
+}
+
+impl Middleware for ApiKeyAuth {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if !req.path.starts_with(&self.prefix) {
+            return Box::pin(next.run(req));
+        }
+        let key = self
+            .take_key(&mut req)
+            .filter(|key| !key.is_empty())
+            .map(|sent| self.keys.find(&sent).cloned().ok_or(sent));
+        let key = match key {
+            Some(Ok(key)) => key,
+            Some(Err(_)) => {
+                warn!("Unknown API key for {}", req.path);
+                security_log::record(&req, Event::AuthFailure, "unknown API key");
+                return Box::pin(async {
+                    Err(refused(StatusCode::UNAUTHORIZED, "Unknown API key"))
+                });
+            }
+            None if self.optional => return Box::pin(next.run(req)),
+            None => {
+                return Box::pin(async {
+                    Err(refused(StatusCode::UNAUTHORIZED, "An API key is required"))
+                })
+            }
+        };
+        if key.scope == Scope::ReadOnly && !req.method.is_safe() {
+            warn!(
+                "Refused {} {} to read-only API key {}",
+                req.method, req.path, key.name
+            );
+            return Box::pin(async {
+                Err(refused(StatusCode::FORBIDDEN, "The API key is read-only"))
+            });
+        }
+        if let Some(limit) = key.rate_limit {
+            req.extensions.insert(ClientLimit {
+                client: format!("api-key:{}", key.name),
+                limit,
+            });
+        }
+        let scopes = match key.scope {
+            Scope::ReadOnly => vec!["read".to_owned()],
+            Scope::ReadWrite => vec!["read".to_owned(), "write".to_owned()],
+        };
+        Identity(key.name).assign(&mut req);
+        req.extensions.insert(Grants {
+            mechanism: "api-key",
+            scopes,
+            roles: Vec::new(),
+        });
+        Box::pin(next.run(req))
+    }
+}
+
+fn refused(status: StatusCode, message: &'static str) -> HttpError {
+    HttpError::new(status, anyhow::anyhow!(message))
+}
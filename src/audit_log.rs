@@ -0,0 +1,150 @@
+//! A record of every change made, or tried, to the files under `/files/`: one JSON object per
+//! line, written once the handler has answered, whether it succeeded or not.
+//!
+//! ```text
+//! {"ts":"2024-10-10T13:55:36.012Z","client_ip":"127.0.0.1","identity":null,"method":"POST","path":"/files/a.txt","size":5,"status":201,"result":"ok","error":null}
+//! {"ts":"2024-10-10T13:55:37.120Z","client_ip":"127.0.0.1","identity":"CN=billing","method":"DELETE","path":"/files/b.txt","size":0,"status":404,"result":"failed","error":"Not found"}
+//! ```
+//!
+//! Every method but the ones that only read is logged, so `PATCH` and the WebDAV ones like
+//! `MOVE` and `MKCOL` are too, even though nothing here serves them yet. The file is only ever
+//! appended to, and never rotated.
+
+use std::{
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use anyhow::Context as _;
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{
+    access_log::rfc3339_time,
+    body::Body,
+    error::HttpError,
+    handler::BoxFuture,
+    log_file::{LogFile, Rotation},
+    middleware::{Middleware, Next},
+    request::{IdentitySlot, Request},
+    response::Response,
+};
+
+/// Where the changes are logged to. Clones write to the same place.
+#[derive(Clone)]
+pub struct AuditLog {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+/// A line of the log. Every field is always there, `null` when there's nothing to put in it.
+#[derive(Debug, Serialize)]
+pub struct Line<'a> {
+    /// When the request came in, in RFC 3339 UTC with milliseconds.
+    pub ts: String,
+    pub client_ip: Option<String>,
+    /// Who the request was authenticated as, by a middleware or a client certificate.
+    pub identity: Option<&'a str>,
+    pub method: &'a str,
+    pub path: &'a str,
+    /// The bytes of request body read, which for uploads is what was written.
+    pub size: u64,
+    pub status: u16,
+    /// `ok` for a 2xx or 3xx status, `failed` for anything else.
+    pub result: &'static str,
+    /// Why it failed, for requests that did.
+    pub error: Option<String>,
+}
+
+impl AuditLog {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(Box::new(out))),
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if need be.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = LogFile::open(path, Rotation::default())
+            .with_context(|| format!("opening audit log {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+
+    fn write(&self, line: &Line) {
+        let mut line = serde_json::to_string(line).expect("serializing an audit log line");
+        line.push('\n');
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            warn!("Error writing to the audit log: {e}");
+        }
+    }
+}
+
+/// The subject of the client certificate `req` came with, for it to go by without a
+/// middleware giving it an [`Identity`](crate::request::Identity).
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn certificate_subject(req: &Request) -> Option<String> {
+    #[cfg(feature = "tls")]
+    if let Some(cert) = req.extensions.get::<crate::tls::ClientCertificate>() {
+        return Some(cert.subject().to_owned());
+    }
+    None
+}
+
+impl Middleware for AuditLog {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if !req.path.starts_with("/files/") || req.method.is_safe() {
+            return Box::pin(next.run(req));
+        }
+        let log = self.clone();
+        Box::pin(async move {
+            let ts = rfc3339_time(SystemTime::now());
+            let client_ip = req.remote_addr.map(|addr| addr.ip().to_string());
+            let certificate = certificate_subject(&req);
+            // The server's, or one of its own for the authentication further in to fill.
+            let slot = match req.extensions.get::<IdentitySlot>() {
+                Some(slot) => slot.clone(),
+                None => {
+                    let slot = IdentitySlot::default();
+                    req.extensions.insert(slot.clone());
+                    slot
+                }
+            };
+            let method = req.method.to_string();
+            let path = req.path.clone();
+            let size = Arc::new(AtomicU64::new(0));
+            let counted = size.clone();
+            let body = std::mem::replace(&mut req.body, Body::empty());
+            req.body = body.inspect(move |chunk| {
+                counted.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+            });
+            let result = next.run(req).await;
+            let (status, error) = match &result {
+                Ok(response) => (response.status, None),
+                Err(e) => (e.status, Some(format!("{:#}", e.error))),
+            };
+            let ok = (200..400).contains(&status.as_u16());
+            let identity = slot.get().or(certificate);
+            log.write(&Line {
+                ts,
+                client_ip,
+                identity: identity.as_deref(),
+                method: &method,
+                path: &path,
+                size: size.load(Ordering::Relaxed),
+                status: status.as_u16(),
+                result: if ok { "ok" } else { "failed" },
+                error: error.filter(|_| !ok),
+            });
+            result
+        })
+    }
+}
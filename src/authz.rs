@@ -0,0 +1,241 @@
+//! Who may do what, decided in one place: a list of rules matching paths and methods to what a
+//! request needs to have authenticated with, kept in the config file rather than spread over
+//! the options of each authentication middleware.
+//!
+//! ```toml
+//! [[authorization]]
+//! pattern = "/files/**"
+//! methods = ["GET"]
+//! anonymous = true
+//!
+//! [[authorization]]
+//! pattern = "/files/**"
+//! methods = ["PUT", "POST", "DELETE"]
+//! scopes = ["write"]
+//!
+//! [[authorization]]
+//! pattern = "/admin/**"
+//! mechanisms = ["basic", "jwt"]
+//! roles = ["admin"]
+//!
+//! [roles]
+//! admin = ["alice"]
+//! ```
+//!
+//! In a pattern, `*` is any one path segment and `**` any number of them. The first rule whose
+//! pattern and methods match a request decides it, `GET` covering `HEAD` too; requests no
+//! rule matches are left to the authentication middleware alone.
+//!
+//! The rules go by the [`Grants`] those middleware put in the request's extensions, so they're
+//! evaluated further in than all of them. For a rule to see requests that come without
+//! credentials, the middleware in front of it has to let those through, with `optional`. A
+//! request that hasn't authenticated, or did with a mechanism the rule doesn't take, gets a
+//! 401; one that did but lacks a scope or role gets a 403.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
+
+use tracing::warn;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::WWW_AUTHENTICATE,
+    middleware::{Middleware, Next},
+    request::{Grants, Identity, Method, Request},
+    response::Response,
+    status::StatusCode,
+};
+
+/// The mechanisms [`Grants`] can name.
+pub const MECHANISMS: [&str; 5] = ["basic", "bearer", "jwt", "api-key", "signature"];
+
+/// What the requests matching a pattern and methods need.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pattern: Vec<String>,
+    methods: Vec<Method>,
+    anonymous: bool,
+    mechanisms: Vec<String>,
+    scopes: Vec<String>,
+    roles: Vec<String>,
+}
+
+impl Rule {
+    /// A rule for requests with any method whose path matches `pattern`, which lets them
+    /// through once they've authenticated in any way.
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.split('/').map(str::to_owned).collect(),
+            methods: Vec::new(),
+            anonymous: false,
+            mechanisms: Vec::new(),
+            scopes: Vec::new(),
+            roles: Vec::new(),
+        }
+    }
+
+    /// Only matches requests with `method`. Repeatable.
+    pub fn method(mut self, method: Method) -> Self {
+        self.methods.push(method);
+        self
+    }
+
+    /// Lets every matching request through, authenticated or not.
+    pub fn anonymous(mut self, anonymous: bool) -> Self {
+        self.anonymous = anonymous;
+        self
+    }
+
+    /// Takes requests authenticated with `mechanism`, out of [`MECHANISMS`]. Without any, every
+    /// mechanism is taken.
+    pub fn mechanism(mut self, mechanism: impl Into<String>) -> Self {
+        self.mechanisms.push(mechanism.into());
+        self
+    }
+
+    /// Needs requests to have been granted `scope`. Every scope added is needed.
+    pub fn scope(mut self, scope: impl Into<String>) -> Self {
+        self.scopes.push(scope.into());
+        self
+    }
+
+    /// Takes requests that have `role`. Any one of the roles added will do.
+    pub fn role(mut self, role: impl Into<String>) -> Self {
+        self.roles.push(role.into());
+        self
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        let method_matches = self.methods.is_empty()
+            || self.methods.iter().any(|method| {
+                *method == req.method || (*method == Method::Get && req.method == Method::Head)
+            });
+        let segments: Vec<&str> = req.path.split('/').collect();
+        method_matches && glob(&self.pattern, &segments)
+    }
+}
+
+/// Whether the `path` segments match the `pattern` ones.
+fn glob(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skipped| glob(rest, &path[skipped..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((segment, path)) if first == "*" || first == segment => glob(rest, path),
+            _ => false,
+        },
+    }
+}
+
+/// Checks each request against the first of its rules that matches it.
+#[derive(Debug, Clone, Default)]
+pub struct Authorization {
+    rules: Arc<Vec<Rule>>,
+    /// The identities given each role, on top of the roles a mechanism grants itself.
+    members: Arc<HashMap<String, HashSet<String>>>,
+}
+
+impl Authorization {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `rule` after the ones already added.
+    pub fn rule(mut self, rule: Rule) -> Self {
+        Arc::make_mut(&mut self.rules).push(rule);
+        self
+    }
+
+    /// Gives `identity` `role`, whichever mechanism it authenticated with.
+    pub fn member(mut self, role: impl Into<String>, identity: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.members)
+            .entry(role.into())
+            .or_default()
+            .insert(identity.into());
+        self
+    }
+
+    fn has_role(&self, grants: &Grants, identity: Option<&str>, role: &str) -> bool {
+        grants.roles.iter().any(|granted| granted == role)
+            || identity.is_some_and(|identity| {
+                self.members
+                    .get(role)
+                    .is_some_and(|members| members.contains(identity))
+            })
+    }
+
+    fn check(&self, req: &Request) -> Result<(), HttpError> {
+        let Some(rule) = self.rules.iter().find(|rule| rule.matches(req)) else {
+            return Ok(());
+        };
+        if rule.anonymous {
+            return Ok(());
+        }
+        let Some(grants) = req.extensions.get::<Grants>() else {
+            return Err(unauthenticated(&rule.mechanisms));
+        };
+        if !rule.mechanisms.is_empty() && !rule.mechanisms.iter().any(|m| m == grants.mechanism) {
+            return Err(unauthenticated(&rule.mechanisms));
+        }
+        let identity = req.extensions.get::<Identity>().map(|id| id.0.as_str());
+        let missing_scope = rule
+            .scopes
+            .iter()
+            .find(|scope| !grants.scopes.contains(scope));
+        let has_role = rule.roles.is_empty()
+            || rule
+                .roles
+                .iter()
+                .any(|role| self.has_role(grants, identity, role));
+        if missing_scope.is_some() || !has_role {
+            warn!(
+                "{} isn't allowed to {} {}",
+                identity.unwrap_or("an unnamed client"),
+                req.method,
+                req.path
+            );
+            return Err(HttpError::forbidden());
+        }
+        Ok(())
+    }
+}
+
+/// A 401 with a challenge for each of `mechanisms`, all of them if it's empty, that clients
+/// answer one for.
+pub(crate) fn unauthenticated(mechanisms: &[String]) -> HttpError {
+    let realm = env!("CARGO_PKG_NAME");
+    let takes =
+        |mechanism: &str| mechanisms.is_empty() || mechanisms.iter().any(|m| m == mechanism);
+    let mut challenges = Vec::new();
+    if takes("basic") {
+        challenges.push(format!("Basic realm=\"{realm}\", charset=\"UTF-8\""));
+    }
+    if takes("bearer") || takes("jwt") {
+        challenges.push(format!("Bearer realm=\"{realm}\""));
+    }
+    if takes("signature") {
+        challenges.push("HMAC-SHA256".to_owned());
+    }
+    let mut error = HttpError::new(
+        StatusCode::UNAUTHORIZED,
+        anyhow::anyhow!("Authentication required"),
+    );
+    if !challenges.is_empty() {
+        error = error.with_header(WWW_AUTHENTICATE, &challenges.join(", "));
+    }
+    error
+}
+
+impl Middleware for Authorization {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        match self.check(&req) {
+            Ok(()) => Box::pin(next.run(req)),
+            Err(e) => Box::pin(async { Err(e) }),
+        }
+    }
+}
@@ -0,0 +1,278 @@
+//! HTTP Basic authentication for the requests under a path prefix, checked against users in an
+//! htpasswd file:
+//!
+//! ```text
+//! # htpasswd -B -C 10 users.htpasswd alice
+//! alice:$2y$10$IdN0Mv0F2m6yB7TQ8hXwnO6oBdMy7iS6b3f1bA0xZ9oZC1n3y5n9W
+//! bob:$argon2id$v=19$m=19456,t=2,p=1$c29tZXNhbHQ$RdescudvJCsgt3ub+b+dWRWJTmaaJObG
+//! ```
+//!
+//! Only bcrypt (`$2a$`, `$2b$`, `$2y$`) and Argon2 (`$argon2id$` and the like) hashes are
+//! taken: the older crypt, MD5 and SHA-1 ones are quick enough to brute-force that a file with
+//! them is refused. Both are checked in constant time, and unknown users against a dummy hash,
+//! so timing tells nothing about which users exist. Checking a password takes tens of
+//! milliseconds by design, so it's done on the blocking pool.
+//!
+//! Requests that pass get the user's [`Identity`]; the others get a 401 with a
+//! `WWW-Authenticate` challenge, or a 429 once a [`Lockout`] has had enough of them.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+};
+
+use anyhow::{bail, Context as _};
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tracing::warn;
+
+use crate::{
+    error::HttpError,
+    extract::FromRequest,
+    forwarded::client_ip,
+    handler::BoxFuture,
+    headers::{AUTHORIZATION, RETRY_AFTER, WWW_AUTHENTICATE},
+    lockout::Lockout,
+    middleware::{Middleware, Next},
+    request::{Grants, Identity, Request},
+    response::Response,
+    security_log::{self, Event},
+    status::StatusCode,
+    tarpit,
+};
+
+/// Users and their password hashes.
+#[derive(Debug, Default)]
+pub struct Htpasswd {
+    users: HashMap<String, Hash>,
+}
+
+#[derive(Debug)]
+enum Hash {
+    Bcrypt(String),
+    Argon2(String),
+}
+
+impl Htpasswd {
+    /// Reads the `user:hash` lines of the file at `path`, skipping blank ones and `#` comments.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        text.parse()
+            .with_context(|| format!("reading users from {}", path.display()))
+    }
+
+    /// Checks `password` for `user`, false for users there aren't.
+    pub fn verify(&self, user: &str, password: &str) -> bool {
+        match self.users.get(user) {
+            Some(Hash::Bcrypt(hash)) => bcrypt::verify(password, hash).unwrap_or(false),
+            Some(Hash::Argon2(hash)) => PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+            None => {
+                // As long as checking a real user's password would have taken.
+                let _ = bcrypt::verify(password, dummy_hash());
+                false
+            }
+        }
+    }
+}
+
+impl FromStr for Htpasswd {
+    type Err = anyhow::Error;
+
+    fn from_str(text: &str) -> anyhow::Result<Self> {
+        let mut users = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, hash)) = line.split_once(':') else {
+                bail!("line {} isn't user:hash", i + 1);
+            };
+            let hash =
+                if hash.starts_with("$2a$") || hash.starts_with("$2b$") || hash.starts_with("$2y$")
+                {
+                    bcrypt::HashParts::from_str(hash)
+                        .with_context(|| format!("the bcrypt hash for {user}"))?;
+                    Hash::Bcrypt(hash.to_owned())
+                } else if hash.starts_with("$argon2") {
+                    PasswordHash::new(hash)
+                        .map_err(|e| anyhow::anyhow!("the Argon2 hash for {user}: {e}"))?;
+                    Hash::Argon2(hash.to_owned())
+                } else {
+                    bail!("{user} has a hash that isn't bcrypt or Argon2");
+                };
+            users.insert(user.to_owned(), hash);
+        }
+        Ok(Self { users })
+    }
+}
+
+/// A bcrypt hash of nothing in particular, to check unknown users' passwords against, at the
+/// cost `htpasswd -B -C 10` and most other tools hash at.
+fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| bcrypt::hash("", 10).expect("hashing with bcrypt"))
+}
+
+/// Asks for a user and password on requests under a prefix.
+#[derive(Clone)]
+pub struct BasicAuth {
+    prefix: String,
+    users: Arc<Htpasswd>,
+    realm: String,
+    writes_only: bool,
+    optional: bool,
+    lockout: Option<Arc<Lockout>>,
+}
+
+impl BasicAuth {
+    /// Protects everything under `prefix`, like `/files/`, in a realm named after the server.
+    pub fn new(prefix: impl Into<String>, users: Htpasswd) -> Self {
+        // Made now, so that the first unknown user doesn't wait on it and stand out.
+        dummy_hash();
+        Self {
+            prefix: prefix.into(),
+            users: Arc::new(users),
+            realm: env!("CARGO_PKG_NAME").to_owned(),
+            writes_only: false,
+            optional: false,
+            lockout: None,
+        }
+    }
+
+    /// The realm the challenge names, which browsers show when they ask for a password.
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    /// Lets requests with safe methods like `GET` through without asking.
+    pub fn writes_only(mut self, writes_only: bool) -> Self {
+        self.writes_only = writes_only;
+        self
+    }
+
+    /// Lets requests without credentials through, anonymous, leaving it to
+    /// [`Authorization`](crate::authz::Authorization) rules to say which may be. Wrong ones
+    /// are still refused.
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+
+    /// Counts failed logins in `lockout`, refusing the addresses and users it locks out.
+    pub fn lockout(mut self, lockout: Arc<Lockout>) -> Self {
+        self.lockout = Some(lockout);
+        self
+    }
+
+    fn challenge(&self) -> HttpError {
+        challenge(&self.realm)
+    }
+}
+
+/// A 401 asking for a user and password in `realm`.
+pub(crate) fn challenge(realm: &str) -> HttpError {
+    let realm = realm.replace(['"', '\\'], "");
+    HttpError::new(
+        StatusCode::UNAUTHORIZED,
+        anyhow::anyhow!("Authentication required"),
+    )
+    .with_header(
+        WWW_AUTHENTICATE,
+        &format!("Basic realm=\"{realm}\", charset=\"UTF-8\""),
+    )
+}
+
+/// The user and password a request sent with `Authorization: Basic`, unchecked, for handlers
+/// that check them themselves. Requests without them get a challenge.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicCredentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl FromRequest for BasicCredentials {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        let sent = req.header(AUTHORIZATION).and_then(credentials);
+        Box::pin(async move {
+            let (user, password) = sent.ok_or_else(|| challenge(env!("CARGO_PKG_NAME")))?;
+            Ok(BasicCredentials { user, password })
+        })
+    }
+}
+
+/// The user and password of an `Authorization: Basic` header.
+fn credentials(header: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_owned(), password.to_owned()))
+}
+
+impl Middleware for BasicAuth {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if !req.path.starts_with(&self.prefix) || (self.writes_only && req.method.is_safe()) {
+            return Box::pin(next.run(req));
+        }
+        let auth = self.clone();
+        Box::pin(async move {
+            let Some((user, password)) = req.header(AUTHORIZATION).and_then(credentials) else {
+                if auth.optional {
+                    return next.run(req).await;
+                }
+                return Err(auth.challenge());
+            };
+            let ip = client_ip(&req);
+            if let Some(left) = auth.lockout.as_ref().and_then(|l| l.locked(ip, &user)) {
+                let secs = left.as_secs() + u64::from(left.subsec_nanos() > 0);
+                tarpit::mark(&req);
+                security_log::record(
+                    &req,
+                    Event::AuthFailure,
+                    format!("Basic: {user:?} is locked out"),
+                );
+                return Err(
+                    HttpError::too_many_requests().with_header(RETRY_AFTER, &secs.to_string())
+                );
+            }
+            let users = auth.users.clone();
+            let checked = user.clone();
+            let verified = tokio::task::spawn_blocking(move || users.verify(&checked, &password))
+                .await
+                .map_err(anyhow::Error::new)?;
+            if !verified {
+                warn!("Failed Basic authentication for {user:?} on {}", req.path);
+                security_log::record(
+                    &req,
+                    Event::AuthFailure,
+                    format!("Basic: bad credentials for {user:?}"),
+                );
+                if let Some(lockout) = &auth.lockout {
+                    lockout.fail(ip, &user);
+                }
+                return Err(auth.challenge());
+            }
+            if let Some(lockout) = &auth.lockout {
+                lockout.succeed(&user);
+            }
+            Identity(user).assign(&mut req);
+            req.extensions.insert(Grants::of("basic"));
+            next.run(req).await
+        })
+    }
+}
@@ -0,0 +1,181 @@
+//! Static bearer tokens, for API clients and scripts that send `Authorization: Bearer <token>`
+//! rather than a user and password. Each token stands for an identity, which is what the
+//! request is logged as:
+//!
+//! ```text
+//! # identity:token
+//! backup-job:3q2+7wAAAAAA0bG4mZk9Hx
+//! ci:Zm9vYmFyYmF6cXV4
+//! ```
+//!
+//! Tokens are compared in constant time, and all of them every time, so timing says nothing
+//! about how close a guess came. Requests without a token, or with one that isn't known, get a
+//! 401 with a `WWW-Authenticate: Bearer` challenge, as RFC 6750 has it.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::bail;
+use tracing::warn;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::{AUTHORIZATION, WWW_AUTHENTICATE},
+    middleware::{Middleware, Next},
+    request::{Grants, Identity, Request},
+    response::Response,
+    secret::read_private,
+    security_log::{self, Event},
+    status::StatusCode,
+};
+
+/// Tokens and who they belong to.
+#[derive(Debug, Clone, Default)]
+pub struct BearerTokens {
+    tokens: Vec<(String, String)>,
+}
+
+impl BearerTokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `token`, authenticating requests as `identity`.
+    pub fn insert(&mut self, identity: impl Into<String>, token: impl Into<String>) {
+        self.tokens.push((token.into(), identity.into()));
+    }
+
+    /// Adds the `identity:token` lines of the file at `path`, skipping blank ones and `#`
+    /// comments.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text = read_private(path)?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((identity, token)) if !identity.is_empty() && !token.is_empty() => {
+                    self.insert(identity, token)
+                }
+                _ => bail!("line {} of {} isn't identity:token", i + 1, path.display()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    /// Who `token` belongs to.
+    pub fn identity(&self, token: &str) -> Option<&str> {
+        // Every token is looked at, whichever matches.
+        self.tokens.iter().fold(None, |found, (known, identity)| {
+            match constant_time_eq(known.as_bytes(), token.as_bytes()) {
+                true => Some(identity.as_str()),
+                false => found,
+            }
+        })
+    }
+}
+
+/// Whether `a` and `b` are the same, taking as long whatever bytes differ. Only their lengths
+/// can be told apart by timing.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Asks for a bearer token on requests under a prefix.
+#[derive(Clone)]
+pub struct BearerAuth {
+    prefix: String,
+    tokens: Arc<BearerTokens>,
+    realm: String,
+    writes_only: bool,
+    optional: bool,
+}
+
+impl BearerAuth {
+    /// Protects everything under `prefix` with `tokens`, which can be shared between prefixes.
+    pub fn new(prefix: impl Into<String>, tokens: Arc<BearerTokens>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            tokens,
+            realm: env!("CARGO_PKG_NAME").to_owned(),
+            writes_only: false,
+            optional: false,
+        }
+    }
+
+    /// The realm the challenge names.
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    /// Lets requests with safe methods like `GET` through without a token.
+    pub fn writes_only(mut self, writes_only: bool) -> Self {
+        self.writes_only = writes_only;
+        self
+    }
+
+    /// Lets requests without a token through, anonymous, like
+    /// [`BasicAuth::optional`](crate::basic_auth::BasicAuth::optional).
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+}
+
+/// A 401 asking for a bearer token in `realm`, with the `error` RFC 6750 gives for a token
+/// that was sent but is no good.
+pub(crate) fn challenge(realm: &str, invalid: bool) -> HttpError {
+    let realm = realm.replace(['"', '\\'], "");
+    let mut challenge = format!("Bearer realm=\"{realm}\"");
+    if invalid {
+        challenge.push_str(", error=\"invalid_token\"");
+    }
+    HttpError::new(
+        StatusCode::UNAUTHORIZED,
+        anyhow::anyhow!("Authentication required"),
+    )
+    .with_header(WWW_AUTHENTICATE, &challenge)
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub(crate) fn bearer_token(header: &str) -> Option<&str> {
+    let (scheme, token) = header.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("bearer")
+        .then(|| token.trim())
+        .filter(|token| !token.is_empty())
+}
+
+impl Middleware for BearerAuth {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if !req.path.starts_with(&self.prefix) || (self.writes_only && req.method.is_safe()) {
+            return Box::pin(next.run(req));
+        }
+        let Some(token) = req.header(AUTHORIZATION).and_then(bearer_token) else {
+            if self.optional {
+                return Box::pin(next.run(req));
+            }
+            let challenge = challenge(&self.realm, false);
+            return Box::pin(async move { Err(challenge) });
+        };
+        let Some(identity) = self.tokens.identity(token).map(str::to_owned) else {
+            warn!("Unknown bearer token for {}", req.path);
+            security_log::record(&req, Event::AuthFailure, "unknown bearer token");
+            let challenge = challenge(&self.realm, true);
+            return Box::pin(async move { Err(challenge) });
+        };
+        Identity(identity).assign(&mut req);
+        req.extensions.insert(Grants::of("bearer"));
+        Box::pin(next.run(req))
+    }
+}
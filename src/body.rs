@@ -0,0 +1,555 @@
+//! Request bodies, read lazily from the connection according to the request's framing.
+
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncReadExt, ReadBuf},
+    sync::OwnedMutexGuard,
+};
+
+use crate::{
+    error::HttpError,
+    memory::{self, Reservation},
+    min_rate::{MinRate, RateCheck},
+    request::BoxReader,
+    status::StatusCode,
+};
+
+/// Upper bound for a chunk-size or trailer line, so a peer can't make us buffer forever.
+const MAX_CHUNK_LINE_LEN: usize = 4096;
+
+/// Where the current request's body ends on the connection.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Framing {
+    Empty,
+    Length(u64),
+    Chunked(ChunkState),
+    /// Everything up to the end of the stream, how responses without a length are delimited.
+    UntilClose,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum ChunkState {
+    Size(Vec<u8>),
+    Data(u64),
+    DataEnd(Vec<u8>),
+    Trailers(Vec<u8>),
+    Done,
+}
+
+/// The read half of a connection together with the framing of the body being read.
+///
+/// It sits behind a mutex shared with the connection: the body holds the lock while the
+/// handler reads, and the connection takes it back afterwards to skip whatever is left.
+pub(crate) struct BodyReader<R = BoxReader> {
+    pub(crate) reader: R,
+    pub(crate) framing: Framing,
+}
+
+impl<R: AsyncBufRead + Unpin> BodyReader<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            reader,
+            framing: Framing::Empty,
+        }
+    }
+
+    /// Whether the body has been read to its end, leaving the reader at the next request.
+    pub(crate) fn is_done(&self) -> bool {
+        matches!(
+            self.framing,
+            Framing::Empty | Framing::Length(0) | Framing::Chunked(ChunkState::Done)
+        )
+    }
+
+    /// Reads one `\n`-terminated line into `line`, returning whether it is complete.
+    fn poll_line(
+        reader: &mut R,
+        line: &mut Vec<u8>,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<bool>> {
+        let available = ready!(Pin::new(&mut *reader).poll_fill_buf(cx))?;
+        if available.is_empty() {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let (consumed, complete) = match available.iter().position(|b| *b == b'\n') {
+            Some(i) => (i + 1, true),
+            None => (available.len(), false),
+        };
+        line.extend_from_slice(&available[..consumed]);
+        Pin::new(&mut *reader).consume(consumed);
+
+        if line.len() > MAX_CHUNK_LINE_LEN {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk line too long",
+            )));
+        }
+        Poll::Ready(Ok(complete))
+    }
+
+    fn poll_data(
+        reader: &mut R,
+        remaining: &mut u64,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let available = ready!(Pin::new(&mut *reader).poll_fill_buf(cx))?;
+        if available.is_empty() {
+            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+        }
+
+        let n = available
+            .len()
+            .min(buf.remaining())
+            .min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+        buf.put_slice(&available[..n]);
+        Pin::new(&mut *reader).consume(n);
+        *remaining -= n as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn invalid_chunk(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_owned())
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for BodyReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.framing {
+                Framing::Empty | Framing::Length(0) | Framing::Chunked(ChunkState::Done) => {
+                    return Poll::Ready(Ok(()))
+                }
+                Framing::Length(remaining) => {
+                    return Self::poll_data(&mut this.reader, remaining, cx, buf)
+                }
+                Framing::UntilClose => {
+                    let available = ready!(Pin::new(&mut this.reader).poll_fill_buf(cx))?;
+                    let n = available.len().min(buf.remaining());
+                    buf.put_slice(&available[..n]);
+                    Pin::new(&mut this.reader).consume(n);
+                    return Poll::Ready(Ok(()));
+                }
+                Framing::Chunked(state) => match state {
+                    ChunkState::Size(line) => {
+                        if !ready!(Self::poll_line(&mut this.reader, line, cx))? {
+                            continue;
+                        }
+                        let line = String::from_utf8_lossy(line);
+                        let size = line.split(';').next().unwrap_or_default().trim();
+                        let size = u64::from_str_radix(size, 16)
+                            .map_err(|_| invalid_chunk("invalid chunk size"))?;
+                        *state = match size {
+                            0 => ChunkState::Trailers(Vec::new()),
+                            size => ChunkState::Data(size),
+                        };
+                    }
+                    ChunkState::Data(0) => *state = ChunkState::DataEnd(Vec::new()),
+                    ChunkState::Data(remaining) => {
+                        return Self::poll_data(&mut this.reader, remaining, cx, buf)
+                    }
+                    ChunkState::DataEnd(line) => {
+                        if !ready!(Self::poll_line(&mut this.reader, line, cx))? {
+                            continue;
+                        }
+                        if line.as_slice() != b"\r\n" && line.as_slice() != b"\n" {
+                            return Poll::Ready(Err(invalid_chunk("missing CRLF after chunk")));
+                        }
+                        *state = ChunkState::Size(Vec::new());
+                    }
+                    ChunkState::Trailers(line) => {
+                        if !ready!(Self::poll_line(&mut this.reader, line, cx))? {
+                            continue;
+                        }
+                        // Trailer fields are skipped, an empty line ends the body.
+                        *state = if line.as_slice() == b"\r\n" || line.as_slice() == b"\n" {
+                            ChunkState::Done
+                        } else {
+                            ChunkState::Trailers(Vec::new())
+                        };
+                    }
+                    ChunkState::Done => unreachable!("handled above"),
+                },
+            }
+        }
+    }
+}
+
+enum Inner {
+    Empty,
+    /// A body already in memory, handed out without copying.
+    Bytes(Bytes),
+    Connection(OwnedMutexGuard<BodyReader>),
+    Reader(Box<dyn AsyncRead + Send + Unpin>),
+}
+
+/// A request body. Nothing is read from the connection until the body is.
+pub struct Body {
+    inner: Inner,
+    content_length: Option<u64>,
+    chunked: bool,
+}
+
+impl Body {
+    pub fn empty() -> Self {
+        Self {
+            inner: Inner::Empty,
+            content_length: Some(0),
+            chunked: false,
+        }
+    }
+
+    pub fn from_reader(reader: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Self {
+            inner: Inner::Reader(Box::new(reader)),
+            content_length: None,
+            chunked: false,
+        }
+    }
+
+    pub(crate) fn from_connection(reader: OwnedMutexGuard<BodyReader>) -> Self {
+        let (content_length, chunked) = match reader.framing {
+            Framing::Empty | Framing::UntilClose => (None, false),
+            Framing::Length(len) => (Some(len), false),
+            Framing::Chunked(_) => (None, true),
+        };
+        Self {
+            inner: Inner::Connection(reader),
+            content_length,
+            chunked,
+        }
+    }
+
+    /// The declared length, `None` for chunked bodies and requests that declared none.
+    pub fn content_length(&self) -> Option<u64> {
+        self.content_length
+    }
+
+    pub fn is_chunked(&self) -> bool {
+        self.chunked
+    }
+
+    /// Whether the request declared its body, via Content-Length or chunked encoding.
+    pub fn is_framed(&self) -> bool {
+        self.chunked || self.content_length.is_some()
+    }
+
+    /// Fails reads with a [`TooLarge`] error once more than `max` bytes have been read, for
+    /// bodies whose length isn't declared up front.
+    pub fn limit(self, max: u64) -> Self {
+        if matches!(&self.inner, Inner::Bytes(bytes) if bytes.len() as u64 <= max) {
+            return self;
+        }
+        let (content_length, chunked) = (self.content_length, self.chunked);
+        Self {
+            inner: Inner::Reader(Box::new(Limited {
+                body: self,
+                limit: max,
+                remaining: max,
+            })),
+            content_length,
+            chunked,
+        }
+    }
+
+    /// Fails reads once the client sends the body slower than `rate`, setting `too_slow` so
+    /// the connection knows to drop it rather than answer.
+    pub(crate) fn min_rate(self, rate: MinRate, too_slow: Arc<AtomicBool>) -> Self {
+        let (content_length, chunked) = (self.content_length, self.chunked);
+        Self {
+            inner: Inner::Reader(Box::new(Paced {
+                body: self,
+                check: RateCheck::new(rate),
+                too_slow,
+            })),
+            content_length,
+            chunked,
+        }
+    }
+
+    /// Hands each piece of the body to `inspect` as it's read, keeping its declared length.
+    pub fn inspect<F>(self, inspect: F) -> Self
+    where
+        F: FnMut(&[u8]) + Send + Unpin + 'static,
+    {
+        let (content_length, chunked) = (self.content_length, self.chunked);
+        Self {
+            inner: Inner::Reader(Box::new(Inspected {
+                body: self,
+                inspect,
+            })),
+            content_length,
+            chunked,
+        }
+    }
+
+    /// Reads the whole body into memory, rejecting it with 413 once it exceeds `limit` bytes,
+    /// or with 503 once it doesn't fit in the [memory budget](crate::memory).
+    pub async fn to_bytes(self, limit: usize) -> Result<Bytes, HttpError> {
+        if self.content_length.is_some_and(|len| len > limit as u64) {
+            return Err(HttpError::payload_too_large());
+        }
+        if let Inner::Bytes(bytes) = self.inner {
+            return Ok(bytes);
+        }
+
+        let declared = self.content_length.unwrap_or(0);
+        let mut reservation = Reservation::new(declared).ok_or_else(memory::exhausted)?;
+        let mut buf = Vec::with_capacity(declared as usize);
+        let mut body = self.take(limit as u64 + 1);
+        loop {
+            buf.reserve(8 * 1024);
+            let read = body
+                .read_buf(&mut buf)
+                .await
+                .map_err(|e| HttpError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!(e)))?;
+            if read == 0 {
+                break;
+            }
+            // A declared length was reserved up front.
+            if !reservation.resize(declared.max(buf.len() as u64)) {
+                return Err(memory::exhausted());
+            }
+        }
+        if buf.len() > limit {
+            return Err(HttpError::payload_too_large());
+        }
+        Ok(reservation.hold(buf))
+    }
+
+    /// The next piece of the body as it comes in, `None` once it's all been read. A body
+    /// already in memory comes in one piece, the very bytes it was made from.
+    pub async fn chunk(&mut self) -> io::Result<Option<Bytes>> {
+        if let Inner::Bytes(bytes) = &mut self.inner {
+            let bytes = std::mem::take(bytes);
+            return Ok((!bytes.is_empty()).then_some(bytes));
+        }
+        let mut chunk = BytesMut::with_capacity(8 * 1024);
+        match self.read_buf(&mut chunk).await? {
+            0 => Ok(None),
+            _ => Ok(Some(chunk.freeze())),
+        }
+    }
+
+    pub async fn text(self, limit: usize) -> Result<String, HttpError> {
+        let bytes = self.to_bytes(limit).await?;
+        String::from_utf8(bytes.into())
+            .map_err(|_| HttpError::bad_request("request body is not valid utf-8"))
+    }
+}
+
+impl From<Bytes> for Body {
+    fn from(value: Bytes) -> Self {
+        Self {
+            content_length: Some(value.len() as u64),
+            inner: Inner::Bytes(value),
+            chunked: false,
+        }
+    }
+}
+
+impl From<String> for Body {
+    fn from(value: String) -> Self {
+        Bytes::from(value).into()
+    }
+}
+
+impl From<Vec<u8>> for Body {
+    fn from(value: Vec<u8>) -> Self {
+        Bytes::from(value).into()
+    }
+}
+
+/// What reads of a [limited](Body::limit) body fail with past the limit, inside an
+/// [`io::Error`] of kind `InvalidData`.
+#[derive(Debug, thiserror::Error)]
+#[error("request body is larger than {limit} bytes")]
+pub struct TooLarge {
+    pub limit: u64,
+}
+
+impl TooLarge {
+    /// Whether `error` comes down to a body going over its limit.
+    pub fn caused(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| {
+            cause
+                .downcast_ref::<io::Error>()
+                .and_then(|e| e.get_ref())
+                .is_some_and(|inner| inner.is::<TooLarge>())
+        })
+    }
+}
+
+struct Limited {
+    body: Body,
+    limit: u64,
+    remaining: u64,
+}
+
+impl AsyncRead for Limited {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.body).poll_read(cx, buf))?;
+        let read = (buf.filled().len() - filled) as u64;
+        match self.remaining.checked_sub(read) {
+            Some(remaining) => {
+                self.remaining = remaining;
+                Poll::Ready(Ok(()))
+            }
+            None => {
+                let limit = self.limit;
+                let e = io::Error::new(io::ErrorKind::InvalidData, TooLarge { limit });
+                Poll::Ready(Err(e))
+            }
+        }
+    }
+}
+
+struct Paced {
+    body: Body,
+    check: RateCheck,
+    too_slow: Arc<AtomicBool>,
+}
+
+impl AsyncRead for Paced {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        if let Poll::Ready(result) = Pin::new(&mut self.body).poll_read(cx, buf) {
+            self.check.moved(buf.filled().len() - filled);
+            return Poll::Ready(result);
+        }
+        let e = ready!(self.check.poll_pending(cx));
+        self.too_slow.store(true, Ordering::Relaxed);
+        Poll::Ready(Err(e))
+    }
+}
+
+struct Inspected<F> {
+    body: Body,
+    inspect: F,
+}
+
+impl<F: FnMut(&[u8]) + Unpin> AsyncRead for Inspected<F> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        ready!(Pin::new(&mut self.body).poll_read(cx, buf))?;
+        (self.inspect)(&buf.filled()[filled..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for Body {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().inner {
+            Inner::Empty => Poll::Ready(Ok(())),
+            Inner::Bytes(bytes) => {
+                let n = bytes.len().min(buf.remaining());
+                buf.put_slice(&bytes.split_to(n));
+                Poll::Ready(Ok(()))
+            }
+            Inner::Connection(reader) => Pin::new(&mut **reader).poll_read(cx, buf),
+            Inner::Reader(reader) => Pin::new(reader).poll_read(cx, buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::BufReader;
+
+    use super::*;
+
+    fn chunked<R: AsyncBufRead + Unpin>(reader: R) -> BodyReader<R> {
+        BodyReader {
+            reader,
+            framing: Framing::Chunked(ChunkState::Size(Vec::new())),
+        }
+    }
+
+    async fn decode<R: AsyncBufRead + Unpin>(body: &mut BodyReader<R>) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        body.read_to_end(&mut decoded).await?;
+        Ok(decoded)
+    }
+
+    const BODY: &[u8] = b"5;name=value\r\nhello\r\n7\r\n, world\r\n0\r\nExpires: never\r\n\r\nGET";
+
+    #[tokio::test]
+    async fn decodes_chunks_up_to_the_next_request() {
+        let mut body = chunked(BODY);
+        assert_eq!(decode(&mut body).await.unwrap(), b"hello, world");
+        assert!(body.is_done());
+        assert_eq!(body.reader, b"GET");
+    }
+
+    #[tokio::test]
+    async fn decodes_chunks_split_across_reads() {
+        let mut body = chunked(BufReader::with_capacity(1, BODY));
+        assert_eq!(decode(&mut body).await.unwrap(), b"hello, world");
+        assert!(body.is_done());
+    }
+
+    #[tokio::test]
+    async fn takes_bare_newlines() {
+        let mut body = chunked(&b"3\nabc\n0\n\n"[..]);
+        assert_eq!(decode(&mut body).await.unwrap(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_chunks() {
+        let long_line = format!("1;{}\r\na\r\n0\r\n\r\n", "x".repeat(MAX_CHUNK_LINE_LEN));
+        for (input, kind) in [
+            (&b"zz\r\nabc\r\n0\r\n\r\n"[..], io::ErrorKind::InvalidData),
+            (&b"3\r\nabcd\r\n0\r\n\r\n"[..], io::ErrorKind::InvalidData),
+            (long_line.as_bytes(), io::ErrorKind::InvalidData),
+            (&b"5\r\nabc"[..], io::ErrorKind::UnexpectedEof),
+            (&b"3\r\nabc\r\n"[..], io::ErrorKind::UnexpectedEof),
+        ] {
+            let e = decode(&mut chunked(input)).await.unwrap_err();
+            assert_eq!(e.kind(), kind, "{}", String::from_utf8_lossy(input));
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_lengths_exactly() {
+        let mut body = BodyReader {
+            reader: &b"abcdefGET"[..],
+            framing: Framing::Length(6),
+        };
+        assert_eq!(decode(&mut body).await.unwrap(), b"abcdef");
+        assert!(body.is_done());
+        assert_eq!(body.reader, b"GET");
+    }
+}
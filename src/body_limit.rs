@@ -0,0 +1,58 @@
+//! Capping the size of request bodies.
+
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::{
+    body::{Body, TooLarge},
+    error::HttpError,
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+    settings::Settings,
+};
+
+/// Rejects requests whose body is larger than [`Settings::max_body_size`] with a 413. A body
+/// declaring its length is turned away before the handler runs; one sent chunked fails to read
+/// once it goes over, and an error the handler returns because of that becomes the 413.
+pub struct BodyLimit {
+    settings: watch::Receiver<Settings>,
+}
+
+impl BodyLimit {
+    pub fn new(settings: watch::Receiver<Settings>) -> Self {
+        Self { settings }
+    }
+}
+
+impl Middleware for BodyLimit {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let Some(max) = self.settings.borrow().max_body_size else {
+            return Box::pin(next.run(req));
+        };
+        match req.body.content_length() {
+            Some(len) if len > max => {
+                warn!("Refusing a {len} byte request body, the limit is {max}");
+                Box::pin(async { Err(HttpError::payload_too_large()) })
+            }
+            Some(_) => Box::pin(next.run(req)),
+            None => {
+                let body = std::mem::replace(&mut req.body, Body::empty());
+                req.body = body.limit(max);
+                Box::pin(async move {
+                    next.run(req)
+                        .await
+                        .map_err(|e| match TooLarge::caused(&e.error) {
+                            true => HttpError::payload_too_large(),
+                            false => e,
+                        })
+                })
+            }
+        }
+    }
+}
@@ -0,0 +1,402 @@
+//! Read and write buffers for client connections, handed back to a pool when the connection
+//! closes instead of being freed, so that a server going through many short connections isn't
+//! allocating and freeing a few buffers for each of them.
+//!
+//! [`PooledReader`] and [`PooledWriter`] work like tokio's `BufReader` and `BufWriter`, with a
+//! buffer from a [`BufferPool`].
+
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+};
+
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::cache_stats::CacheStats;
+
+/// Buffers of one size, keeping up to `max_idle` of those given back around for reuse.
+#[derive(Debug)]
+pub struct BufferPool {
+    size: usize,
+    max_idle: usize,
+    idle: Mutex<Vec<Box<[u8]>>>,
+    /// Buffers checked out and not yet given back.
+    in_use: AtomicUsize,
+    reused: AtomicU64,
+    allocated: AtomicU64,
+}
+
+impl Default for BufferPool {
+    /// 8 KiB buffers, the size tokio's default to, with up to 256 of them kept.
+    fn default() -> Self {
+        Self::new(8 * 1024, 256)
+    }
+}
+
+impl BufferPool {
+    pub fn new(size: usize, max_idle: usize) -> Self {
+        Self {
+            size: size.max(1),
+            max_idle,
+            idle: Mutex::default(),
+            in_use: AtomicUsize::new(0),
+            reused: AtomicU64::new(0),
+            allocated: AtomicU64::new(0),
+        }
+    }
+
+    /// A buffer from the pool, or a new one if it has none left.
+    pub fn get(self: &Arc<Self>) -> Buffer {
+        let idle = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let counter = match idle {
+            Some(_) => &self.reused,
+            None => &self.allocated,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        self.in_use.fetch_add(1, Ordering::Relaxed);
+        Buffer {
+            data: idle.unwrap_or_else(|| vec![0; self.size].into_boxed_slice()),
+            pool: self.clone(),
+        }
+    }
+
+    /// How many buffers are waiting to be reused.
+    pub fn idle(&self) -> usize {
+        self.idle.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    /// How many bytes of buffers are checked out, in connections' readers and writers.
+    pub fn bytes_in_use(&self) -> u64 {
+        (self.in_use.load(Ordering::Relaxed) * self.size) as u64
+    }
+
+    /// How many bytes of buffers are waiting to be reused.
+    pub fn bytes_idle(&self) -> u64 {
+        (self.idle() * self.size) as u64
+    }
+
+    /// How often a buffer asked for came from the pool rather than being allocated.
+    pub fn reuse(&self) -> CacheStats {
+        CacheStats {
+            hits: self.reused.load(Ordering::Relaxed),
+            misses: self.allocated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// A buffer checked out of a [`BufferPool`], going back to it when dropped. What's in it is
+/// whatever its last user left there.
+pub struct Buffer {
+    data: Box<[u8]>,
+    pool: Arc<BufferPool>,
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DerefMut for Buffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        self.pool.in_use.fetch_sub(1, Ordering::Relaxed);
+        let mut idle = self.pool.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < self.pool.max_idle {
+            idle.push(std::mem::take(&mut self.data));
+        }
+    }
+}
+
+/// Buffers reads from `inner`. Writes go straight through.
+pub struct PooledReader<R> {
+    inner: R,
+    buf: Buffer,
+    pos: usize,
+    filled: usize,
+}
+
+impl<R> PooledReader<R> {
+    pub fn new(inner: R, buf: Buffer) -> Self {
+        Self {
+            inner,
+            buf,
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// Hands the buffer back to the pool, keeping only what was read into it but not consumed
+    /// yet, to be read ahead of `inner`.
+    pub fn into_unread(self) -> Unread<R> {
+        Unread {
+            unread: self.buf[self.pos..self.filled].to_vec(),
+            pos: 0,
+            inner: self.inner,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for PooledReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        // Reads at least as large as the buffer skip it, when it's empty.
+        if self.pos == self.filled && out.remaining() >= self.buf.len() {
+            return Pin::new(&mut self.inner).poll_read(cx, out);
+        }
+        let available = ready!(self.as_mut().poll_fill_buf(cx))?;
+        let n = available.len().min(out.remaining());
+        out.put_slice(&available[..n]);
+        self.consume(n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncBufRead for PooledReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        if this.pos == this.filled {
+            let mut read = ReadBuf::new(&mut this.buf);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut read))?;
+            this.filled = read.filled().len();
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(&this.buf[this.pos..this.filled]))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.pos = (this.pos + amt).min(this.filled);
+    }
+}
+
+impl<R: AsyncWrite + Unpin> AsyncWrite for PooledReader<R> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// What a [`PooledReader`] had buffered and not handed out, followed by the rest of `inner`.
+/// Writes go straight through.
+pub struct Unread<R> {
+    unread: Vec<u8>,
+    pos: usize,
+    inner: R,
+}
+
+impl<R> Unread<R> {
+    /// `inner`, with nothing read ahead.
+    pub fn new(inner: R) -> Self {
+        Self {
+            unread: Vec::new(),
+            pos: 0,
+            inner,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Unread<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        out: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        if this.pos == this.unread.len() {
+            return Pin::new(&mut this.inner).poll_read(cx, out);
+        }
+        let n = (this.unread.len() - this.pos).min(out.remaining());
+        out.put_slice(&this.unread[this.pos..this.pos + n]);
+        this.pos += n;
+        if this.pos == this.unread.len() {
+            this.unread = Vec::new();
+            this.pos = 0;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncWrite + Unpin> AsyncWrite for Unread<R> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Buffers writes to `inner` until the buffer is full or it's flushed.
+pub struct PooledWriter<W> {
+    inner: W,
+    buf: Buffer,
+    /// How much of the buffer is taken, and how much of that has been written out already.
+    len: usize,
+    written: usize,
+}
+
+impl<W> PooledWriter<W> {
+    pub fn new(inner: W, buf: Buffer) -> Self {
+        Self {
+            inner,
+            buf,
+            len: 0,
+            written: 0,
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> PooledWriter<W> {
+    fn poll_flush_buf(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.written < self.len {
+            let pending = &self.buf[self.written..self.len];
+            match ready!(Pin::new(&mut self.inner).poll_write(cx, pending))? {
+                0 => return Poll::Ready(Err(io::ErrorKind::WriteZero.into())),
+                n => self.written += n,
+            }
+        }
+        self.len = 0;
+        self.written = 0;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for PooledWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        if this.len + buf.len() > this.buf.len() {
+            ready!(this.poll_flush_buf(cx))?;
+        }
+        if buf.len() >= this.buf.len() {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        this.buf[this.len..this.len + buf.len()].copy_from_slice(buf);
+        this.len += buf.len();
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    /// Gathers the slices into the buffer when they fit, and otherwise passes them on in one
+    /// vectored write, after what's buffered.
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let total = bufs
+            .iter()
+            .fold(0usize, |total, b| total.saturating_add(b.len()));
+        if this.len + total > this.buf.len() {
+            ready!(this.poll_flush_buf(cx))?;
+        }
+        if total >= this.buf.len() {
+            return Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        }
+        for buf in bufs {
+            this.buf[this.len..this.len + buf.len()].copy_from_slice(buf);
+            this.len += buf.len();
+        }
+        Poll::Ready(Ok(total))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_flush_buf(cx))?;
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_flush_buf(cx))?;
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn unread_keeps_what_was_buffered() {
+        let pool = Arc::new(BufferPool::new(16, 4));
+        let mut reader = PooledReader::new(&b"first\nsecond and the rest"[..], pool.get());
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "first\n");
+
+        let mut unread = reader.into_unread();
+        assert_eq!(pool.idle(), 1);
+        assert_eq!(pool.bytes_in_use(), 0);
+        let mut rest = String::new();
+        unread.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "second and the rest");
+    }
+}
@@ -0,0 +1,42 @@
+//! Hit and miss counts of what the server keeps around for reuse, like connection buffers and
+//! proxy connections, for the admin [`/stats`](crate::admin).
+
+use serde::Serialize;
+
+/// How often something asked for was there to be reused.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Hits out of every lookup, or `None` before the first.
+    pub fn hit_rate(&self) -> Option<f64> {
+        let total = self.hits + self.misses;
+        (total > 0).then(|| self.hits as f64 / total as f64)
+    }
+}
+
+impl std::ops::Add for CacheStats {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        Self {
+            hits: self.hits + other.hits,
+            misses: self.misses + other.misses,
+        }
+    }
+}
+
+impl Serialize for CacheStats {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut stats = serializer.serialize_struct("CacheStats", 3)?;
+        stats.serialize_field("hits", &self.hits)?;
+        stats.serialize_field("misses", &self.misses)?;
+        stats.serialize_field("hit_rate", &self.hit_rate())?;
+        stats.end()
+    }
+}
@@ -0,0 +1,315 @@
+//! Running CGI/1.1 scripts (RFC 3875) out of a directory.
+
+use std::{
+    io,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    process::Stdio,
+    task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, BufReader, ReadBuf},
+    process::{Child, ChildStdout, Command},
+};
+use tracing::warn;
+
+#[cfg(feature = "tls")]
+use crate::tls::ClientCertificate;
+use crate::{
+    body::Body,
+    error::HttpError,
+    forwarded,
+    handler::{BoxFuture, Handler},
+    headers::{
+        Host, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, PROXY, PROXY_AUTHORIZATION,
+    },
+    request::Request,
+    response::{Response, ResponseBody},
+    safe_path,
+    status::StatusCode,
+};
+
+/// Upper bound for the header section a script prints, so a runaway one can't make us buffer
+/// forever.
+const MAX_HEADER_BYTES: usize = 64 * 1024;
+
+/// Serves a directory of executables, meant to be [mounted](crate::router::Router::mount).
+/// `/prefix/script/extra` runs `script` with `PATH_INFO=/extra`.
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    root: PathBuf,
+}
+
+impl Cgi {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    /// Finds the script for `path`: the shortest leading part of it that names an executable
+    /// file. Returns the script and the rest of the path.
+    async fn locate<'a>(&self, path: &'a str) -> Option<(PathBuf, &'a str)> {
+        safe_path::resolve(path).ok()?;
+        let mut script = self.root.clone();
+        let mut consumed = 0;
+        for segment in path.split('/').skip(1) {
+            consumed += segment.len() + 1;
+            match Path::new(segment).components().next() {
+                Some(Component::Normal(part)) if part.to_str() == Some(segment) => {
+                    script.push(part);
+                }
+                _ => return None,
+            }
+
+            let metadata = tokio::fs::metadata(&script).await.ok()?;
+            if metadata.is_file() {
+                return is_executable(&metadata).then(|| (script, &path[consumed..]));
+            }
+        }
+        None
+    }
+
+    async fn run(&self, mut req: Request) -> Result<Response, HttpError> {
+        let Some((script, path_info)) = self.locate(&req.path).await else {
+            return Err(HttpError::not_found());
+        };
+
+        // The script's own URL, i.e. the request path minus PATH_INFO. The mount prefix was
+        // stripped from `req.path`, so work from the target.
+        let full_path = req.target.split('?').next().unwrap_or_default();
+        let script_name = full_path
+            .strip_suffix(path_info)
+            .unwrap_or(full_path)
+            .to_owned();
+
+        let mut command = Command::new(&script);
+        command
+            .env_clear()
+            .envs(environment(&req, &script_name, path_info))
+            .envs(std::env::var("PATH").map(|path| ("PATH", path)))
+            .current_dir(script.parent().unwrap_or(&self.root))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true);
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("spawning CGI script {}", script.display()))?;
+
+        // Feed the body concurrently, a script may start writing output before reading it all.
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        let mut body = std::mem::replace(&mut req.body, Body::empty());
+        tokio::spawn(async move {
+            if let Err(e) = tokio::io::copy(&mut body, &mut stdin).await {
+                warn!("Error writing request body to CGI script: {e}");
+            }
+        });
+
+        let mut stdout = BufReader::new(child.stdout.take().expect("stdout is piped"));
+        let mut response = read_headers(&mut stdout)
+            .await
+            .map_err(|e| HttpError::new(StatusCode::BAD_GATEWAY, e))?;
+        response.body = ResponseBody::Reader(Box::new(ScriptOutput {
+            stdout,
+            _child: child,
+        }));
+        Ok(response)
+    }
+}
+
+#[cfg(unix)]
+fn is_executable(metadata: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o111 != 0
+}
+
+#[cfg(not(unix))]
+fn is_executable(_metadata: &std::fs::Metadata) -> bool {
+    true
+}
+
+/// The CGI meta-variables for a request, also used as FastCGI params.
+pub(crate) fn environment(
+    req: &Request,
+    script_name: &str,
+    path_info: &str,
+) -> Vec<(String, String)> {
+    let host = req.headers.typed_get::<Host>().ok().flatten();
+    let (server_name, server_port) = match &host {
+        Some(host) => {
+            let name = host.hostname();
+            let port = host.0[name.len()..].strip_prefix(':').unwrap_or("80");
+            (name.to_owned(), port.to_owned())
+        }
+        None => ("localhost".to_owned(), "80".to_owned()),
+    };
+
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_owned()),
+        (
+            "SERVER_SOFTWARE",
+            concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION")).to_owned(),
+        ),
+        ("SERVER_PROTOCOL", req.version.clone()),
+        ("SERVER_NAME", server_name),
+        ("SERVER_PORT", server_port),
+        ("REQUEST_METHOD", req.method.as_str().to_owned()),
+        ("REQUEST_URI", req.target.clone()),
+        ("SCRIPT_NAME", script_name.to_owned()),
+        ("PATH_INFO", path_info.to_owned()),
+        ("QUERY_STRING", req.query.clone()),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_owned(), v))
+    .collect::<Vec<_>>();
+
+    // The bare address, IPv6 ones without brackets, as scripts expect.
+    if let Some(ip) = forwarded::client_ip(req) {
+        env.push(("REMOTE_ADDR".to_owned(), ip.to_string()));
+        // The port is only known when the peer is the client and not a proxy in between.
+        if let Some(addr) = req.remote_addr.filter(|addr| addr.ip() == ip) {
+            env.push(("REMOTE_PORT".to_owned(), addr.port().to_string()));
+        }
+    }
+    #[cfg(feature = "tls")]
+    if let Some(cert) = req.extensions.get::<ClientCertificate>() {
+        env.push(("SSL_CLIENT_S_DN".to_owned(), cert.subject().to_owned()));
+        if let Some(cn) = cert.common_name() {
+            env.push(("SSL_CLIENT_S_DN_CN".to_owned(), cn.to_owned()));
+        }
+    }
+
+    if let Some(len) = req.body.content_length().filter(|len| *len > 0) {
+        env.push(("CONTENT_LENGTH".to_owned(), len.to_string()));
+    }
+    if let Some(content_type) = req.header(CONTENT_TYPE) {
+        env.push(("CONTENT_TYPE".to_owned(), content_type.to_owned()));
+    }
+
+    for (name, value) in &req.headers {
+        // Already passed above, and Authorization is deliberately kept from scripts. So is
+        // Proxy, which as HTTP_PROXY would point the script's HTTP clients at whatever proxy
+        // the client likes (httpoxy), and so are names with underscores, which would pass for
+        // those with dashes, like Proxy_ for Proxy.
+        if name.contains('_')
+            || [
+                CONTENT_LENGTH,
+                CONTENT_TYPE,
+                AUTHORIZATION,
+                PROXY_AUTHORIZATION,
+                PROXY,
+            ]
+            .iter()
+            .any(|h| h.eq_ignore_ascii_case(name))
+        {
+            continue;
+        }
+        let key = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        match env.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, existing)) => {
+                existing.push_str(", ");
+                existing.push_str(value);
+            }
+            None => env.push((key, value.to_owned())),
+        }
+    }
+    env
+}
+
+/// Parses the script's header section into a response without a body.
+pub(crate) async fn read_headers<R>(stdout: &mut R) -> anyhow::Result<Response>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut response = Response::empty(StatusCode::OK);
+    let mut status = None;
+    let mut read = 0;
+    loop {
+        let mut line = String::new();
+        let n = stdout
+            .read_line(&mut line)
+            .await
+            .context("reading CGI script output")?;
+        read += n;
+        anyhow::ensure!(n > 0, "CGI script exited before ending its headers");
+        anyhow::ensure!(read <= MAX_HEADER_BYTES, "CGI script headers too large");
+
+        let line = line.trim_end_matches(['\r', '\n']);
+        if line.is_empty() {
+            break;
+        }
+        let (name, value) = line
+            .split_once(':')
+            .with_context(|| format!("invalid CGI header line {line:?}"))?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("Status") {
+            let code = value
+                .split(' ')
+                .next()
+                .and_then(|code| code.parse().ok())
+                .filter(|code| (100..1000).contains(code))
+                .with_context(|| format!("invalid CGI Status header {value:?}"))?;
+            status = Some(StatusCode(code));
+        } else {
+            response.headers.append(name, value);
+        }
+    }
+
+    response.status = match status {
+        Some(status) => status,
+        None if response.headers.contains(LOCATION) => StatusCode::FOUND,
+        None => StatusCode::OK,
+    };
+    Ok(response)
+}
+
+/// The rest of the script's output, keeping the process around (and killing it when dropped,
+/// e.g. because the client went away) until the body is done.
+struct ScriptOutput {
+    stdout: BufReader<ChildStdout>,
+    _child: Child,
+}
+
+impl AsyncRead for ScriptOutput {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stdout).poll_read(cx, buf)
+    }
+}
+
+impl Handler for Cgi {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let cgi = self.clone();
+        Box::pin(async move { cgi.run(req).await })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+
+    #[test]
+    fn keeps_proxy_and_underscored_headers_from_scripts() {
+        let mut req = Request::new(Method::Get, "/cgi-bin/env");
+        req.headers.insert("Proxy", "http://evil.example:3128");
+        req.headers.insert("Proxy_", "http://evil.example:3128");
+        req.headers.insert("X_Forwarded_For", "10.0.0.1");
+        req.headers.insert(AUTHORIZATION, "Basic YWxpY2U6c2VjcmV0");
+        req.headers.insert("X-Request-Id", "abc");
+        req.headers.append("Accept", "text/html");
+        req.headers.append("Accept", "text/plain");
+
+        let env = environment(&req, "/cgi-bin/env", "");
+        let var = |name: &str| env.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+        assert_eq!(var("HTTP_PROXY"), None);
+        assert_eq!(var("HTTP_X_FORWARDED_FOR"), None);
+        assert_eq!(var("HTTP_AUTHORIZATION"), None);
+        assert_eq!(var("HTTP_X_REQUEST_ID"), Some("abc"));
+        assert_eq!(var("HTTP_ACCEPT"), Some("text/html, text/plain"));
+    }
+}
@@ -0,0 +1,3160 @@
+//! The full command line, parsed with clap from the options, their environment variables and
+//! the config file, and the servers it sets up.
+
+use anyhow::{bail, Context};
+use clap::{builder::BoolishValueParser, ArgAction, CommandFactory, FromArgMatches, Parser};
+#[cfg(feature = "acme")]
+use http_server_starter_rust::acme::{self, Acme};
+#[cfg(feature = "compression")]
+use http_server_starter_rust::compression::Compression;
+#[cfg(unix)]
+use http_server_starter_rust::daemon::{self, PidFile};
+#[cfg(feature = "jwt")]
+use http_server_starter_rust::jwt::{JwtAuth, JwtVerifier};
+#[cfg(unix)]
+use http_server_starter_rust::listener::{self, UnixBind};
+#[cfg(feature = "native-plugins")]
+use http_server_starter_rust::native_plugin;
+#[cfg(feature = "thread-per-core")]
+use http_server_starter_rust::per_core::PerCore;
+#[cfg(unix)]
+use http_server_starter_rust::privileges::RunAs;
+#[cfg(feature = "tls")]
+use http_server_starter_rust::tls::{
+    CertificateFiles, ResolvesServerCert, SniCertificates, TlsAcceptor, TlsConfig, TlsPolicy,
+    TlsVersion,
+};
+#[cfg(feature = "wasm")]
+use http_server_starter_rust::wasm;
+use http_server_starter_rust::{
+    access_file::AccessFiles,
+    access_log::{self, AccessLog},
+    admin::Admin,
+    alert::{self, ErrorRate},
+    api_key::{ApiKeyAuth, ApiKeys},
+    audit_log::AuditLog,
+    basic_auth::{BasicAuth, Htpasswd},
+    bearer_auth::{BearerAuth, BearerTokens},
+    body_limit::BodyLimit,
+    buffer_pool::BufferPool,
+    cache_stats::CacheStats,
+    cgi::Cgi,
+    config::RouteConfig,
+    cookie_keys::CookieKeys,
+    cors::{AllowedOrigin, Cors},
+    csrf::Csrf,
+    fastcgi::FastCgi,
+    forwarded::{Cidr, TrustedProxies},
+    handler::Handler,
+    har::Har,
+    headers::RETRY_AFTER,
+    health::Health,
+    hotlink::Hotlink,
+    ip_filter::{IpFilter, IpRules},
+    kv::KvStore,
+    listener::{Bind, Inherited, Listener},
+    load_shed::LoadShed,
+    load_test::{LoadTest, Target},
+    lockout::Lockout,
+    log_file::{self, LogFile, Rotation},
+    memory,
+    method_override::MethodOverride,
+    min_rate::MinRate,
+    notices::{Notice, Notices},
+    otlp::{self, OtlpLayer},
+    process_stats::ProcessStats,
+    proxy::{Balance, CertField, HealthCheck, Proxy, RetryPolicy, Upstream},
+    rate_limit::RateLimiter,
+    redirect::{HttpsRedirect, Redirect, RedirectTable},
+    reload::{self, Reloadable},
+    replay,
+    response::Json,
+    rewrite::{Rewrite, RewriteRule},
+    router::Router,
+    routes,
+    secret::{Secret, HIDDEN, SECRET_OPTIONS},
+    security_headers::SecurityHeaders,
+    security_log::SecurityLog,
+    served_dir::{Backoff, ServedDir},
+    server::{ConnectionLimits, ConnectionStats, Server, WhenFull},
+    session::{MemoryStore, Sessions},
+    settings::{RateLimit, Settings},
+    shortener::Shortener,
+    signature::{SignedRequests, SigningSecrets},
+    state::AppState,
+    static_files::StaticDir,
+    status::StatusCode,
+    tarpit::Tarpit,
+    timeout::Timeout,
+    tunnel::{AllowedTarget, ConnectTunnel},
+    upload_expiry::UploadExpiry,
+    upload_filter::UploadFilter,
+    upload_scan::{CommandScanner, UploadScan},
+    vhost::VirtualHosts,
+    web_ui,
+    wire_trace::WireTrace,
+};
+#[cfg(feature = "metrics")]
+use http_server_starter_rust::{metrics::Metrics, statsd::StatsD};
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, RawFd};
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    net::SocketAddr,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::TcpListener,
+    sync::{broadcast, watch},
+};
+use tracing::{info, warn};
+use tracing_subscriber::{
+    filter::filter_fn, fmt::writer::BoxMakeWriter, layer::SubscriberExt, util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// An address to accept connections on, as `ADDRESS:PORT` or just a port to listen on every
+    /// interface. Repeat it to listen on several. Defaults to `127.0.0.1:4221` unless
+    /// `--listen-unix` is given.
+    #[arg(long, value_delimiter = ',', value_name = "address", value_parser = parse_listen)]
+    listen: Vec<SocketAddr>,
+    /// A Unix domain socket to accept connections on. A stale socket file from an earlier run
+    /// is replaced.
+    #[cfg(unix)]
+    #[arg(long, value_delimiter = ',', value_name = "path")]
+    listen_unix: Vec<PathBuf>,
+    /// An address for the admin endpoints (shutdown, drain, reload, connection counts, cache
+    /// flush, log filter and metrics), kept apart from the site's listeners. Nothing checks who's calling them, so keep
+    /// it to loopback or a private network.
+    #[arg(long, value_name = "address", value_parser = parse_listen)]
+    admin_listen: Option<SocketAddr>,
+    /// A Unix domain socket for the admin endpoints, only accessible to the server's user.
+    #[cfg(unix)]
+    #[arg(long, value_name = "path")]
+    admin_unix: Option<PathBuf>,
+    /// Seconds between the samples of the process's memory, file descriptors, tasks and
+    /// connection buffers that the admin `/stats` shows.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    process_sample_interval: u64,
+    /// Serves CPU profiles at `/debug/pprof/profile` on the admin listener, in pprof's format
+    /// or as a flamegraph.
+    #[cfg(feature = "profiling")]
+    #[arg(long)]
+    admin_profiling: bool,
+    /// Permissions for `--listen-unix` sockets in octal, e.g. `660`.
+    #[cfg(unix)]
+    #[arg(long, value_name = "mode", value_parser = parse_mode)]
+    unix_socket_mode: Option<u32>,
+    /// Keeps `[::]` listeners to IPv6; by default they take IPv4 connections as well.
+    #[arg(long)]
+    ipv6_only: bool,
+    /// Sockets to open on every TCP address, with SO_REUSEPORT so the kernel spreads new
+    /// connections across them, each accepted on by its own task. Above 1, it helps when a
+    /// single accept loop can't keep up with the connection rate.
+    #[arg(long, value_name = "count", default_value = "1")]
+    acceptors: NonZeroUsize,
+    /// How many connections the kernel queues on each TCP listener until they're accepted.
+    #[arg(long, value_name = "count", default_value_t = 1024)]
+    backlog: i32,
+    /// Sets TCP_NODELAY on TCP connections, so small responses go out without waiting.
+    #[arg(long)]
+    tcp_nodelay: bool,
+    /// Seconds a TCP connection can be silent before the kernel starts probing whether the
+    /// client is still there.
+    #[arg(long, value_name = "seconds")]
+    tcp_keepalive: Option<u64>,
+    /// Seconds between keepalive probes.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 15,
+        requires = "tcp_keepalive"
+    )]
+    tcp_keepalive_interval: u64,
+    /// The size of each connection's read and write buffers, in bytes.
+    #[arg(long, value_name = "bytes", default_value_t = 8 * 1024, value_parser = clap::value_parser!(u64).range(1..))]
+    io_buffer_size: u64,
+    /// How many buffers of connections that closed are kept for new ones to reuse.
+    #[arg(long, value_name = "count", default_value_t = 256)]
+    idle_buffers: usize,
+    /// The send buffer size of TCP connections, in bytes.
+    #[arg(long, value_name = "bytes")]
+    send_buffer_size: Option<usize>,
+    /// The receive buffer size of TCP connections, in bytes.
+    #[arg(long, value_name = "bytes")]
+    recv_buffer_size: Option<usize>,
+    /// Serves HTTPS on the TCP listeners, with the PEM certificate chain in this file. It and
+    /// the key are read again when either changes or on SIGHUP, for new connections.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "file", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM private key for `--tls-cert`.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "file", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Requires clients to present a certificate signed by a CA in this PEM bundle (mutual
+    /// TLS). Handlers can take it, with its subject and alternative names, as a
+    /// `ClientCertificate`, and `--proxy` can pass them on with `cert-header`.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "file")]
+    tls_client_ca: Option<PathBuf>,
+    /// Another certificate for clients asking for `host` over SNI, as `HOST=CERT,KEY`. Pair it
+    /// with `--vhost` to serve several HTTPS sites on one listener; other names get
+    /// `--tls-cert`, or no handshake without one. Repeatable, reloaded like `--tls-cert`.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "host=cert,key", value_parser = parse_tls_host)]
+    tls_host: Vec<(String, PathBuf, PathBuf)>,
+    /// The oldest TLS version handshakes may use, 1.2 or 1.3.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_name = "version", default_value = "1.2")]
+    tls_min_version: TlsVersion,
+    /// Only offers these cipher suites, by IANA name like `TLS13_AES_256_GCM_SHA384`, in order
+    /// of preference.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_delimiter = ',', value_name = "suite")]
+    tls_cipher_suites: Vec<String>,
+    /// Only offers these key exchange groups, like `X25519` or `secp384r1`.
+    #[cfg(feature = "tls")]
+    #[arg(long, value_delimiter = ',', value_name = "group")]
+    tls_kx_groups: Vec<String>,
+    /// Resumes TLS sessions from tickets the clients keep, not only from the server's cache.
+    #[cfg(feature = "tls")]
+    #[arg(long)]
+    tls_session_tickets: bool,
+    /// Makes every connection do a full TLS handshake, never resuming an earlier session.
+    #[cfg(feature = "tls")]
+    #[arg(long, conflicts_with = "tls_session_tickets")]
+    tls_no_resumption: bool,
+    /// An address that accepts plain HTTP even when TLS is on, like port 80 for ACME HTTP-01
+    /// challenges. Repeatable.
+    #[arg(long, value_delimiter = ',', value_name = "address", value_parser = parse_listen)]
+    listen_http: Vec<SocketAddr>,
+    /// Makes the `--listen-http` listeners redirect every request to HTTPS instead of serving
+    /// the site. ACME challenges are still answered there.
+    #[arg(long, requires = "listen_http")]
+    https_redirect: bool,
+    /// Gets the HTTPS certificate for this domain from an ACME CA and keeps it renewed, instead
+    /// of `--tls-cert`. Repeat it for a certificate covering several names.
+    #[cfg(feature = "acme")]
+    #[arg(long, value_delimiter = ',', value_name = "domain", conflicts_with_all = ["tls_cert", "tls_host"])]
+    acme_domain: Vec<String>,
+    /// A contact address for the ACME account, for expiry warnings.
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "email")]
+    acme_email: Option<String>,
+    /// The ACME directory, Let's Encrypt's by default. Try
+    /// `https://acme-staging-v02.api.letsencrypt.org/directory` first.
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "url")]
+    acme_directory: Option<String>,
+    /// Where the ACME account, certificate and key are kept between runs.
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "directory", default_value = "./acme")]
+    acme_cache: PathBuf,
+    /// `http-01`, answered on the `--listen-http` listeners (the CA connects to port 80), or
+    /// `tls-alpn-01`, answered on the HTTPS ones at port 443.
+    #[cfg(feature = "acme")]
+    #[arg(long, value_name = "challenge", default_value = "http-01")]
+    acme_challenge: acme::Challenge,
+    #[arg(long, value_name = "directory", default_value = "./test-files")]
+    directory: PathBuf,
+    /// Makes sure files are only ever opened inside `--directory`, the vhosts' directories and
+    /// the mounts, even through symlinks, as a safeguard on top of rejecting `..` in paths. On
+    /// Linux, the kernel checks every path (which takes Linux 5.6 or later).
+    #[arg(long)]
+    confine: bool,
+    /// File system errors in a row (not counting missing files or permissions) after which
+    /// `--directory`, the vhosts' directories and the mounts get a rest: their routes answer
+    /// 503 for `--fs-backoff-cool-down`, then try again.
+    #[arg(long, value_name = "count", value_parser = clap::value_parser!(u32).range(1..))]
+    fs_backoff: Option<u32>,
+    /// Seconds file routes answer 503 for once `--fs-backoff` errors have happened in a row.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 10,
+        requires = "fs_backoff"
+    )]
+    fs_backoff_cool_down: u64,
+    /// A command each upload to `/files` and the writable mounts is checked with before it's
+    /// kept, given the complete file's path as its last argument: like clamdscan, exiting 0
+    /// for clean and 1 for rejected, which gets the upload a 422.
+    #[arg(long, value_name = "command")]
+    upload_scan_command: Option<String>,
+    /// Seconds `--upload-scan-command` gets per file.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 30,
+        requires = "upload_scan_command"
+    )]
+    upload_scan_timeout: u64,
+    /// Keeps uploads `--upload-scan-command` fails on or takes too long with, rather than
+    /// refusing them with a 503.
+    #[arg(long, requires = "upload_scan_command")]
+    upload_scan_fail_open: bool,
+    /// Only accepts uploads to `/files` and the mounts whose name ends in one of
+    /// these extensions, refusing others with a 403.
+    #[arg(long, value_delimiter = ',', value_name = "extension")]
+    upload_allow_extensions: Vec<String>,
+    /// Refuses uploads with any of these extensions in their name, like `exe,php`, with a 403.
+    #[arg(long, value_delimiter = ',', value_name = "extension")]
+    upload_deny_extensions: Vec<String>,
+    /// Only accepts uploads sent with one of these `Content-Type`s, like `image/*` or
+    /// `application/pdf`, refusing others, and ones without, with a 415.
+    #[arg(long, value_delimiter = ',', value_name = "type")]
+    upload_allow_types: Vec<String>,
+    /// Refuses uploads sent with one of these `Content-Type`s with a 415.
+    #[arg(long, value_delimiter = ',', value_name = "type")]
+    upload_deny_types: Vec<String>,
+    /// Seconds between sweeps for uploads made with a TTL that has run out, which deletes
+    /// them. They stop being served the moment it runs out either way.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 60,
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    upload_sweep_interval: u64,
+    /// Seconds clients get to send a request's line and headers. Those that sent some of them
+    /// by then get a 408, those that sent nothing are disconnected.
+    #[arg(long, value_name = "seconds", default_value_t = 30)]
+    header_timeout: u64,
+    /// Seconds a connection is kept open waiting for the client's next request; 0 closes
+    /// connections after every response.
+    #[arg(long, value_name = "seconds", default_value_t = 5)]
+    keep_alive_timeout: u64,
+    /// Closes connections after this many requests.
+    #[arg(long, value_name = "count")]
+    max_requests_per_connection: Option<usize>,
+    /// Seconds a client may go without reading any of its response before it's dropped.
+    #[arg(long, value_name = "seconds", default_value_t = 30)]
+    write_timeout: u64,
+    /// Drops connections whose client sends a request body, or takes a response, at fewer
+    /// bytes a second than this over `--min-rate-period`, so slow POSTs and slow reads can't
+    /// hold connections for good. Only time spent waiting on the client counts.
+    #[arg(long, value_name = "bytes", value_parser = clap::value_parser!(u64).range(1..))]
+    min_rate: Option<u64>,
+    /// Seconds over which `--min-rate` is measured.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 10,
+        value_parser = clap::value_parser!(u64).range(1..),
+        requires = "min_rate"
+    )]
+    min_rate_period: u64,
+    /// Logs a warning for requests that take this many milliseconds or longer, finished or cut
+    /// short, with the time spent reading the head, in the handler and writing the response.
+    #[arg(long, value_name = "milliseconds")]
+    slow_request_threshold: Option<u64>,
+    /// Logs a hexdump of every byte each connection reads and writes, after TLS, for debugging
+    /// clients that get the framing wrong. Best kept to testing: it's slow, and it logs
+    /// everything, bodies and credentials included, unless `--trace-wire-redact`.
+    #[arg(long)]
+    trace_wire: bool,
+    /// Records the requests the sites answer, with their responses, into HAR files in this
+    /// directory, one per `--har-window`, for opening in browser devtools. Heads, credentials
+    /// included, are recorded in full and bodies up to `--har-max-body`.
+    #[arg(long, value_name = "dir")]
+    har_dir: Option<PathBuf>,
+    /// Seconds each HAR file covers.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 3600,
+        requires = "har_dir",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    har_window: u64,
+    /// The most of each request and response body recorded.
+    #[arg(
+        long,
+        value_name = "bytes",
+        default_value_t = 64 * 1024,
+        requires = "har_dir"
+    )]
+    har_max_body: usize,
+    /// How many bytes each way of a connection `--trace-wire` dumps before it stops.
+    #[arg(
+        long,
+        value_name = "bytes",
+        default_value_t = 64 * 1024,
+        requires = "trace_wire"
+    )]
+    trace_wire_limit: u64,
+    /// Masks `Authorization`, `Cookie`, `Set-Cookie` and `X-Api-Key` values in the dumps.
+    #[arg(long, requires = "trace_wire")]
+    trace_wire_redact: bool,
+    /// Seconds a request may take, including sending its body, before it's aborted.
+    #[arg(long, value_name = "seconds")]
+    request_timeout: Option<u64>,
+    /// The longest, in milliseconds, `/delay/{ms}` waits, and `/stream/{n}` between lines;
+    /// longer delays asked for are cut down to it.
+    #[arg(long, value_name = "ms", default_value_t = 10_000)]
+    max_delay: u64,
+    /// The most entries `/kv/{key}` holds at once; storing more is refused until some expire
+    /// or are deleted.
+    #[arg(long, value_name = "count", default_value_t = 10_000)]
+    kv_max_entries: usize,
+    /// The most bytes of keys and values `/kv/{key}` holds at once.
+    #[arg(long, value_name = "bytes", default_value_t = 64 * 1024 * 1024)]
+    kv_max_bytes: u64,
+    /// Where what the server keeps between runs goes, like the links `/shorten` makes and
+    /// when uploads with a TTL expire. Without it, they're forgotten when the server stops.
+    #[arg(long, value_name = "directory")]
+    data_dir: Option<PathBuf>,
+    /// The largest request body accepted, in bytes. Larger ones get a 413.
+    #[arg(long, value_name = "bytes")]
+    max_body_size: Option<u64>,
+    /// Gzips text, JSON, JavaScript and XML responses for clients that accept it.
+    #[cfg(feature = "compression")]
+    #[arg(long)]
+    compression: bool,
+    /// The most memory, in bytes, that request bodies read whole and gzipped responses may take
+    /// up across all requests. Bodies that don't fit get a 503, responses go out uncompressed.
+    #[arg(long, value_name = "bytes")]
+    memory_budget: Option<u64>,
+    /// Requests per second each client address may make, on average; those over it get a 429.
+    /// Behind a proxy, pair it with `--trusted-proxy` to tell the clients apart.
+    #[arg(long, value_name = "rate", value_parser = parse_rate)]
+    rate_limit: Option<f64>,
+    /// How many requests a client may make in a burst above `--rate-limit`; by default, a
+    /// second's worth.
+    #[arg(long, value_name = "count", requires = "rate_limit", value_parser = clap::value_parser!(u32).range(1..))]
+    rate_limit_burst: Option<u32>,
+    /// Seconds requests in progress get to finish after Ctrl-C or SIGTERM before the server
+    /// exits anyway. SIGUSR2 drains the same way, once it has started a new server process
+    /// (from the same path, with the same arguments) that takes over the listeners.
+    #[arg(long, value_name = "seconds", default_value_t = 30)]
+    drain_timeout: u64,
+    /// The most connections served at once. Further ones wait in the listen backlog until one
+    /// closes, unless `--reject-when-full` is set.
+    #[arg(long, value_name = "count")]
+    max_connections: Option<usize>,
+    /// Answers connections over `--max-connections` with a 503 straight away instead of
+    /// leaving them waiting. TLS connections are closed, as answering would mean a handshake.
+    #[arg(long, requires = "max_connections")]
+    reject_when_full: bool,
+    /// The most requests handled at once, across all connections; further ones get a 503,
+    /// unless there's room in `--request-queue`.
+    #[arg(long, value_name = "count")]
+    max_requests_in_flight: Option<NonZeroUsize>,
+    /// How many requests over `--max-requests-in-flight` may wait for one to finish, instead
+    /// of getting a 503 straight away.
+    #[arg(
+        long,
+        value_name = "count",
+        default_value_t = 0,
+        requires = "max_requests_in_flight"
+    )]
+    request_queue: usize,
+    /// Seconds a request waits in `--request-queue` before it gets a 503 after all.
+    #[arg(long, value_name = "seconds", default_value_t = 5)]
+    request_queue_timeout: u64,
+    /// The most connections one client address may have open; further ones get a 503 (or are
+    /// closed, over TLS).
+    #[arg(long, value_name = "count")]
+    max_connections_per_ip: Option<usize>,
+    /// Goes into the background once the listeners are open, with the log going to
+    /// `--log-file`.
+    #[cfg(unix)]
+    #[arg(long)]
+    daemon: bool,
+    /// Writes the process ID to this file and keeps it locked while running, so a second copy
+    /// started with it fails. Supervisors that track a pidfile can use it without `--daemon`.
+    #[cfg(unix)]
+    #[arg(long, value_name = "file")]
+    pidfile: Option<PathBuf>,
+    /// Switches to this user, by name or ID, once the listeners are open, so a server started
+    /// as root to use ports 80 and 443 doesn't serve requests as root. Files it reads later,
+    /// like certificates on reload, must be readable by the user.
+    #[cfg(unix)]
+    #[arg(long, value_name = "user")]
+    user: Option<String>,
+    /// Switches to this group along with `--user`, instead of the user's own, or by itself.
+    #[cfg(unix)]
+    #[arg(long, value_name = "group")]
+    group: Option<String>,
+    /// Appends the log to this file instead of printing it. SIGUSR1 opens it again, for
+    /// `logrotate` to use after moving it.
+    #[cfg(unix)]
+    #[arg(long, value_name = "file")]
+    log_file: Option<PathBuf>,
+    /// Rotates `--log-file` and `--access-log` before they grow past this many bytes: each is
+    /// renamed with the date and time after its name, and a new one started. The new files
+    /// belong to `--user`, who needs to be able to write to their directory.
+    #[arg(long, value_name = "bytes")]
+    log_rotate_size: Option<u64>,
+    /// Rotates the log files every this many seconds, counted from midnight UTC, so 86400
+    /// starts a new one every day.
+    #[arg(long, value_name = "seconds")]
+    log_rotate_every: Option<u64>,
+    /// Gzips log files once they've been rotated.
+    #[cfg(feature = "compression")]
+    #[arg(long)]
+    log_rotate_compress: bool,
+    /// How log lines are written: `text` for people, `json` for one object per line. Which
+    /// ones are written is up to `RUST_LOG`, `info` by default; `RUST_LOG=debug` adds every
+    /// request's head as it's parsed. The admin listener's `/log-filter` changes it while the
+    /// server runs, and SIGTTIN switches to `debug` and back (SIGUSR2 being taken by the
+    /// hand-off to a new process).
+    #[arg(long, value_name = "format", value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+    /// Writes a line for every request answered to this file, or to stdout for `-`, apart from
+    /// the rest of the log.
+    #[arg(long, value_name = "file")]
+    access_log: Option<PathBuf>,
+    /// `combined`, the Common Log Format with the Referer and User-Agent after it, just
+    /// `common`, or `json` for an object per line with the client, request, status, time
+    /// taken and size.
+    #[arg(
+        long,
+        value_name = "format",
+        default_value = "combined",
+        requires = "access_log"
+    )]
+    access_log_format: access_log::Format,
+    /// Logs only one in this many responses under 400 to the access log. Errors are always
+    /// logged.
+    #[arg(
+        long,
+        value_name = "n",
+        default_value_t = 1,
+        requires = "access_log",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    access_log_sample: u64,
+    /// Leaves successful requests for this path, and what's under it, out of the access log,
+    /// like `/healthz` or `/metrics`. Repeatable.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "path",
+        requires = "access_log"
+    )]
+    access_log_exclude: Vec<String>,
+    /// Appends a JSON line to this file for every change tried under `/files/`, uploads and
+    /// deletions, with the client, who it was authenticated as, the size and how it went.
+    #[arg(long, value_name = "file")]
+    audit_log: Option<PathBuf>,
+    /// Appends a line to this file for every failed authentication, rate limited request and
+    /// path traversal attempt, starting with the client's address, for fail2ban to go by.
+    #[arg(long, value_name = "file")]
+    security_log: Option<PathBuf>,
+    /// Leaves out the ID otherwise given to every request, which shows up in its log lines,
+    /// the `X-Request-Id` response header and the body of error responses.
+    #[arg(long)]
+    no_request_ids: bool,
+    /// Serves the request metrics in the Prometheus text format at this path of the site too,
+    /// for a scraper that can't reach the admin listener, which always has them at `/metrics`.
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "path", value_parser = parse_url_path)]
+    metrics_path: Option<String>,
+    /// Leaves out `/healthz`, which answers 200 while the server runs, and `/readyz`, which
+    /// answers 503 while the served directories can't be read, the connections are at
+    /// `--max-connections` or the server is draining.
+    #[arg(long)]
+    no_health_checks: bool,
+    /// Sends a span for every request, with its route, status, client IP and file path, to the
+    /// OpenTelemetry collector at this `http://` URL, like `http://localhost:4318`, over
+    /// OTLP/HTTP. They're sent whatever `RUST_LOG` leaves out of the log.
+    #[arg(long, value_name = "url")]
+    otlp_endpoint: Option<Upstream>,
+    /// The `service.name` the spans are sent under.
+    #[arg(long, value_name = "name", default_value = env!("CARGO_PKG_NAME"))]
+    otlp_service_name: String,
+    /// Sends a count, timing and sizes for every request to the StatsD daemon at this
+    /// `HOST:PORT` over UDP.
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "host:port")]
+    statsd: Option<String>,
+    /// What the StatsD metric names start with, before a dot.
+    #[cfg(feature = "metrics")]
+    #[arg(
+        long,
+        value_name = "prefix",
+        default_value = "http",
+        requires = "statsd"
+    )]
+    statsd_prefix: String,
+    /// Sends the DogStatsD format, tagging every metric with the request's route, method and
+    /// status.
+    #[cfg(feature = "metrics")]
+    #[arg(long, requires = "statsd")]
+    dogstatsd: bool,
+    /// A `KEY:VALUE` tag for every metric, which takes `--dogstatsd`. Repeat it for several.
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_delimiter = ',', value_name = "tag", requires = "statsd")]
+    statsd_tag: Vec<String>,
+    /// Alerts when more than this fraction of the responses in the last `--alert-window` were
+    /// 5xx, like `0.05`, with `--alert-webhook` or `--alert-command`. Fires once per window at
+    /// most.
+    #[arg(long, value_name = "fraction", value_parser = parse_fraction)]
+    alert_error_rate: Option<f64>,
+    /// Seconds of responses the error rate is over.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 60,
+        requires = "alert_error_rate",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    alert_window: u64,
+    /// The fewest responses in a window for it to alert, so that two errors out of three
+    /// requests don't.
+    #[arg(
+        long,
+        value_name = "count",
+        default_value_t = 20,
+        requires = "alert_error_rate"
+    )]
+    alert_min_requests: u64,
+    /// POSTs a JSON description of the spike, with the request count, error count and rate, to
+    /// this `http://` URL.
+    #[arg(long, value_name = "url", requires = "alert_error_rate")]
+    alert_webhook: Option<Upstream>,
+    /// Runs this with `sh -c` instead, with the same JSON on its stdin.
+    #[arg(
+        long,
+        value_name = "command",
+        requires = "alert_error_rate",
+        conflicts_with = "alert_webhook"
+    )]
+    alert_command: Option<String>,
+    /// Threads serving connections; one per CPU core by default.
+    #[arg(long, value_name = "count")]
+    workers: Option<NonZeroUsize>,
+    /// Gives each of the `--workers` threads a runtime of its own, pinned to a core and with
+    /// its own SO_REUSEPORT socket on every TCP address, instead of sharing work between them.
+    /// Connection limits then apply per thread.
+    #[cfg(feature = "thread-per-core")]
+    #[arg(long, conflicts_with = "acceptors")]
+    thread_per_core: bool,
+    /// The most threads at once for blocking work like file system access; 512 by default.
+    #[arg(long, value_name = "count")]
+    max_blocking_threads: Option<NonZeroUsize>,
+    /// Serves `host` from its own directory, e.g. `example.com=/srv/example`. Requests for any
+    /// other host are served from `--directory`.
+    #[arg(long, value_name = "host=directory", value_parser = parse_vhost)]
+    vhost: Vec<(String, PathBuf)>,
+    /// A rewrite rule applied before routing, as `PATTERN TARGET [FLAGS]`, e.g.
+    /// `"^/dl/(.+)$ /files/$1"` or `"/old/ /files/ [R=301]"`. The first matching rule wins.
+    #[arg(long, value_name = "rule")]
+    rewrite: Vec<RewriteRule>,
+    /// Redirects a moved path, as `PATH TARGET [STATUS]` with the status defaulting to 301.
+    #[arg(long, value_name = "redirect")]
+    redirect: Vec<Redirect>,
+    /// Honors `X-HTTP-Method-Override` on POST requests.
+    #[arg(long)]
+    method_override: bool,
+    /// Lets clients open CONNECT tunnels to targets matching `HOST:PORT`, where `HOST` may be
+    /// `*` or `*.domain` and `PORT` may be `*`. Repeat it to allow several.
+    #[arg(long, value_delimiter = ',', value_name = "host:port")]
+    connect_allow: Vec<AllowedTarget>,
+    /// Asks for a user and password, checked against an htpasswd file of bcrypt or Argon2
+    /// hashes, on requests under a prefix, as `PREFIX=FILE[,writes][,optional][,realm=NAME]`.
+    /// With `writes`, only requests that can change something are asked, so
+    /// `/files/=users.htpasswd,writes` leaves downloads open. With `optional`, requests without
+    /// credentials go through anonymous, for the config file's `[[authorization]]` rules to
+    /// decide on. The file is read again on reload. Repeatable.
+    #[arg(long, value_name = "prefix=file", value_parser = parse_basic_auth)]
+    basic_auth: Vec<BasicAuthRule>,
+    /// Failed `--basic-auth` logins in a row allowed from an address, or for a user, before
+    /// they're locked out with 429s; 0 never locks anyone out.
+    #[arg(long, value_name = "count", default_value_t = 5)]
+    login_attempts: u32,
+    /// Seconds the first lockout lasts, each one after it lasting twice as long.
+    #[arg(long, value_name = "seconds", default_value_t = 1)]
+    login_lockout: u64,
+    /// The most seconds a lockout lasts, which is also how long failures are remembered.
+    #[arg(long, value_name = "seconds", default_value_t = 900)]
+    login_lockout_max: u64,
+    /// Asks for one of the `--bearer-token`s on requests under a prefix, as
+    /// `PREFIX[,writes][,optional][,realm=NAME]`, with `writes` and `optional` like
+    /// `--basic-auth`'s. Repeatable.
+    #[arg(long, value_name = "prefix", value_parser = parse_bearer_auth)]
+    bearer_auth: Vec<BearerAuthRule>,
+    /// A token for `--bearer-auth`, as `IDENTITY=TOKEN`, with the identity what requests that
+    /// send it are logged as. Only taken from the environment or the config file, not the
+    /// command line, where other users can see it. Repeatable.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "identity=token",
+        value_parser = parse_bearer_token,
+        requires = "bearer_auth"
+    )]
+    bearer_token: Vec<(String, Secret)>,
+    /// A file of `IDENTITY:TOKEN` lines adding to the `--bearer-token`s, read again on reload.
+    #[arg(
+        long,
+        alias = "token-file",
+        value_name = "file",
+        requires = "bearer_auth"
+    )]
+    bearer_tokens_file: Option<PathBuf>,
+    /// Asks for a JWT on requests under a prefix, as `PREFIX[,writes][,optional][,realm=NAME]` like
+    /// `--bearer-auth`, checked with `--jwt-secret`, `--jwt-public-key` or `--jwt-jwks-url`.
+    /// Repeatable.
+    #[cfg(feature = "jwt")]
+    #[arg(long, value_name = "prefix", value_parser = parse_bearer_auth)]
+    jwt: Vec<BearerAuthRule>,
+    /// The secret JWTs are signed with, using HS256. Like `--bearer-token`, kept off the
+    /// command line.
+    #[cfg(feature = "jwt")]
+    #[arg(long, value_name = "secret", requires = "jwt", conflicts_with_all = ["jwt_secret_file", "jwt_public_key", "jwt_jwks_url"])]
+    jwt_secret: Option<Secret>,
+    /// A file with the `--jwt-secret` in it, read again on reload.
+    #[cfg(feature = "jwt")]
+    #[arg(long, value_name = "file", requires = "jwt", conflicts_with_all = ["jwt_public_key", "jwt_jwks_url"])]
+    jwt_secret_file: Option<PathBuf>,
+    /// A PEM file with the RSA or EC public key JWTs are signed with, using RS256 or ES256.
+    #[cfg(feature = "jwt")]
+    #[arg(
+        long,
+        value_name = "file",
+        requires = "jwt",
+        conflicts_with = "jwt_jwks_url"
+    )]
+    jwt_public_key: Option<PathBuf>,
+    /// Where the identity provider publishes the keys JWTs are signed with, as a JWK set.
+    #[cfg(feature = "jwt")]
+    #[arg(long, value_name = "url", requires = "jwt")]
+    jwt_jwks_url: Option<String>,
+    /// An audience JWTs must have in `aud`. Repeatable; any audience goes without one.
+    #[cfg(feature = "jwt")]
+    #[arg(long, value_delimiter = ',', value_name = "audience", requires = "jwt")]
+    jwt_audience: Vec<String>,
+    /// An issuer JWTs must have as `iss`. Repeatable; any issuer goes without one.
+    #[cfg(feature = "jwt")]
+    #[arg(long, value_delimiter = ',', value_name = "issuer", requires = "jwt")]
+    jwt_issuer: Vec<String>,
+    /// Seconds JWTs are still taken for after they expire, for clocks that disagree.
+    #[cfg(feature = "jwt")]
+    #[arg(long, value_name = "seconds", default_value_t = 60)]
+    jwt_leeway: u64,
+    /// Asks for one of the `--api-keys-file` keys on requests under a prefix, as
+    /// `PREFIX[,optional]` with `optional` like `--basic-auth`'s. Repeatable.
+    #[arg(long, value_name = "prefix", value_parser = parse_api_key_auth, requires = "api_keys_file")]
+    api_key_auth: Vec<(String, bool)>,
+    /// The `--api-key-auth` keys, with their scopes and rate limits, read again on reload.
+    #[arg(long, value_name = "file", requires = "api_key_auth")]
+    api_keys_file: Option<PathBuf>,
+    /// The header API keys are sent in.
+    #[arg(long, value_name = "name", default_value = "X-API-Key")]
+    api_key_header: String,
+    /// The query parameter API keys can be sent in instead of the header, whose values the
+    /// access log leaves out; empty to only take the header.
+    #[arg(long, value_name = "name", default_value = "api_key")]
+    api_key_query: String,
+    /// Asks for an HMAC signature from one of the `--signing-secret` clients on requests under
+    /// a prefix that change something, for upload agents and the like. Their bodies are read
+    /// into memory to check them, up to `--max-body-size`. Repeatable.
+    #[arg(long, value_name = "prefix")]
+    signed_writes: Vec<String>,
+    /// A client for `--signed-writes`, as `CLIENT=SECRET`, with the client ID what its
+    /// requests are logged as. Like `--bearer-token`, kept off the command line. Repeatable.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "client=secret",
+        value_parser = parse_bearer_token,
+        requires = "signed_writes"
+    )]
+    signing_secret: Vec<(String, Secret)>,
+    /// A file of `CLIENT:SECRET` lines adding to the `--signing-secret`s, read again on reload.
+    #[arg(long, value_name = "file", requires = "signed_writes")]
+    signing_secrets_file: Option<PathBuf>,
+    /// How many seconds a signed request's `X-Date` may be from the server's clock, either way.
+    #[arg(long, value_name = "seconds", default_value_t = 300)]
+    signature_skew: u64,
+    /// Follows the `.http-access` files in `--directory` and the mounted directories, which
+    /// can allow and deny addresses and ask for authentication for their subtree. The
+    /// authentication itself takes `--basic-auth` or the like on the prefix, with `optional`
+    /// for files that leave some of it open.
+    #[arg(long)]
+    access_files: bool,
+    /// Believes the forwarding headers (`X-Forwarded-For`, `Forwarded`, `X-Forwarded-Proto`) of
+    /// requests from this address or CIDR range when working out the client's address, and
+    /// keeps the `X-Request-Id` they send. Repeat it for several proxies.
+    #[arg(long, value_delimiter = ',', value_name = "cidr")]
+    trusted_proxy: Vec<Cidr>,
+    /// Only lets in clients from this address or CIDR range, and the others given. Connections
+    /// from elsewhere are closed as they're accepted (bar `--trusted-proxy`s), and requests
+    /// forwarded for clients from elsewhere get a 403.
+    #[arg(long, value_delimiter = ',', value_name = "cidr")]
+    ip_allow: Vec<Cidr>,
+    /// Keeps out clients from this address or CIDR range, even ones `--ip-allow` lets in.
+    #[arg(long, value_delimiter = ',', value_name = "cidr")]
+    ip_deny: Vec<Cidr>,
+    /// Only lets clients from these ranges make requests under a prefix, as
+    /// `PREFIX=CIDR[,CIDR...]`, answering the others with a 403. Repeatable.
+    #[arg(long, value_name = "prefix=cidrs", value_parser = parse_prefix_ranges)]
+    ip_allow_prefix: Vec<(String, Vec<Cidr>)>,
+    /// Answers clients from these ranges with a 403 under a prefix, as `PREFIX=CIDR[,CIDR...]`.
+    /// Repeatable.
+    #[arg(long, value_name = "prefix=cidrs", value_parser = parse_prefix_ranges)]
+    ip_deny_prefix: Vec<(String, Vec<Cidr>)>,
+    /// Lets browser apps from this origin call the server, as `https://app.example.com`,
+    /// `https://*.example.com` for its subdomains or `*` for any. Repeatable.
+    #[arg(long, value_delimiter = ',', value_name = "origin")]
+    cors_origin: Vec<AllowedOrigin>,
+    /// The methods `--cors-origin`s may use.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "method",
+        default_value = "GET,HEAD,POST,PUT,PATCH,DELETE"
+    )]
+    cors_methods: Vec<String>,
+    /// The request headers `--cors-origin`s may send, beyond those browsers always allow.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "header",
+        default_value = "Authorization,Content-Type,X-API-Key,X-CSRF-Token"
+    )]
+    cors_headers: Vec<String>,
+    /// Response headers scripts from `--cors-origin`s may read, like `ETag`.
+    #[arg(long, value_delimiter = ',', value_name = "header")]
+    cors_expose_headers: Vec<String>,
+    /// Lets `--cors-origin`s send cookies and credentials. Not with `--cors-origin '*'`.
+    #[arg(long, requires = "cors_origin")]
+    cors_credentials: bool,
+    /// Seconds browsers may cache the answer to a preflight for.
+    #[arg(long, value_name = "seconds", requires = "cors_origin")]
+    cors_max_age: Option<u64>,
+    /// Takes requests that change something under a prefix only with the token from the
+    /// `--csrf-cookie` cookie in the `--csrf-header` header, for browser-facing deployments.
+    /// Ones with a bearer token or an `--api-key-header` are let through. Repeatable.
+    #[arg(long, value_name = "prefix")]
+    csrf: Vec<String>,
+    /// The cookie `--csrf` issues its tokens in.
+    #[arg(long, value_name = "name", default_value = "csrf_token")]
+    csrf_cookie: String,
+    /// The header `--csrf` expects its tokens back in.
+    #[arg(long, value_name = "name", default_value = "X-CSRF-Token")]
+    csrf_header: String,
+    /// Gives requests server-side sessions, kept in memory under a signed `--session-cookie`,
+    /// for handlers that take a `Session`.
+    #[arg(long)]
+    sessions: bool,
+    /// The cookie `--sessions` keeps their IDs in.
+    #[arg(long, value_name = "name", default_value = "session")]
+    session_cookie: String,
+    /// Seconds a session lasts after the last request that came with it.
+    #[arg(long, value_name = "seconds", default_value_t = 86400)]
+    session_ttl: u64,
+    /// The key session cookies are signed with. Without one, a random key is made at start,
+    /// which is enough for sessions that live in memory. Changing it ends every session. Kept
+    /// off the command line.
+    #[arg(
+        long,
+        value_name = "secret",
+        requires = "sessions",
+        conflicts_with = "session_secret_file"
+    )]
+    session_secret: Option<Secret>,
+    /// A file with the `--session-secret` in it, read again on reload.
+    #[arg(long, value_name = "file", requires = "sessions")]
+    session_secret_file: Option<PathBuf>,
+    /// The most sessions kept at once, past which the ones closest to expiring are dropped.
+    #[arg(long, value_name = "count", default_value_t = 100_000)]
+    session_max: usize,
+    /// A secret for handlers to sign and encrypt cookies with, 32 bytes or more. The first is
+    /// used; later ones are only checked against, for cookies made before a rotation. Kept off
+    /// the command line. Repeatable.
+    #[arg(long, value_delimiter = ',', value_name = "secret")]
+    cookie_key: Vec<Secret>,
+    /// A file of `--cookie-key`s, one per line, the current one first, read again on reload.
+    #[arg(long, value_name = "file", conflicts_with = "cookie_key")]
+    cookie_keys_file: Option<PathBuf>,
+    /// Adds `X-Content-Type-Options: nosniff`, `X-Frame-Options: DENY` with a matching
+    /// `frame-ancestors` policy, `Referrer-Policy: strict-origin-when-cross-origin` and, over
+    /// HTTPS, a year of `Strict-Transport-Security` to responses that don't set their own.
+    #[arg(long)]
+    security_headers: bool,
+    /// Changes one of the `--security-headers`, or adds another, as `NAME: VALUE`; an empty
+    /// value leaves it out. Repeatable.
+    #[arg(long, value_name = "header", value_parser = parse_security_header, requires = "security_headers")]
+    security_header: Vec<(String, Option<String>)>,
+    /// Like `--security-header`, for requests under a prefix only, as `PREFIX=NAME: VALUE`:
+    /// `/embed/=X-Frame-Options:` with `/embed/=Content-Security-Policy:` lets pages there be
+    /// framed. Repeatable.
+    #[arg(long, value_name = "rule", value_parser = parse_prefix_security_header, requires = "security_headers")]
+    security_header_prefix: Vec<(String, (String, Option<String>))>,
+    /// Only serves images and videos to pages on this host, or on the server's own, going by
+    /// their `Referer` or `Origin`; `*.example.com` allows its subdomains too. Repeatable.
+    #[arg(long, value_delimiter = ',', value_name = "host")]
+    hotlink_allow: Vec<String>,
+    /// The file extensions `--hotlink-allow` protects, instead of the usual image and video
+    /// ones.
+    #[arg(
+        long,
+        value_delimiter = ',',
+        value_name = "extension",
+        requires = "hotlink_allow"
+    )]
+    hotlink_extensions: Vec<String>,
+    /// Also refuses protected files to requests with neither `Referer` nor `Origin`, which
+    /// includes opening them directly.
+    #[arg(long, requires = "hotlink_allow")]
+    hotlink_block_empty: bool,
+    /// An image to answer refused requests with, rather than a 403.
+    #[arg(long, value_name = "file", requires = "hotlink_allow")]
+    hotlink_placeholder: Option<PathBuf>,
+    /// Answers clients that are denied by `--ip-deny`, `--ip-allow` and the like, rate
+    /// limited or locked out a byte at a time rather than straight away, to slow down
+    /// scanners and scrapers. Up to this many answers drip at once; the rest are sent as
+    /// usual. Denied clients then get an answer instead of having their connection closed.
+    #[arg(long, value_name = "slots", value_parser = clap::value_parser!(u64).range(1..))]
+    tarpit: Option<u64>,
+    /// Seconds between each byte of a tarpitted answer.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 10,
+        requires = "tarpit"
+    )]
+    tarpit_interval: u64,
+    /// Seconds a tarpitted answer takes in all.
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value_t = 300,
+        requires = "tarpit"
+    )]
+    tarpit_duration: u64,
+    /// Expects connections to start with a PROXY protocol (v1 or v2) header, as sent by
+    /// HAProxy and most cloud load balancers, and takes the client address from it.
+    #[arg(long)]
+    proxy_protocol: bool,
+    /// Lists the route table as JSON at `/_routes`.
+    #[arg(long)]
+    routes_endpoint: bool,
+    /// Shows the health and load of the `--proxy` upstreams as JSON at `/_upstreams`.
+    #[arg(long)]
+    upstreams_endpoint: bool,
+    /// Serves a page at `/_ui` for browsing, previewing, uploading and deleting the files of
+    /// `--directory` from a browser.
+    #[arg(long)]
+    ui: bool,
+    /// Serves a directory under a prefix, as
+    /// `PREFIX=DIR[,autoindex][,rw][,confine][,cache=VALUE]`.
+    #[arg(long, value_name = "mount", value_parser = parse_mount)]
+    mount: Vec<(String, StaticDir)>,
+    /// Forwards a prefix to upstream origins, as `PREFIX=URL[,URL...][,balance=STRATEGY]
+    /// [,preserve-host]` with URLs like `http://HOST[:PORT][/BASE]`. The strategy is
+    /// `round-robin` (the default), `least-conn` or `ip-hash`. `check=tcp` or `check=PATH` probes
+    /// the upstreams every five seconds (or `check-interval=SECS`) and ejects the failing ones.
+    /// `retries=N` tries bodiless idempotent requests that fail with a connection error, 502,
+    /// 503 or 504 again on other upstreams, each try limited by `try-timeout=SECS`.
+    /// `cert-header=FIELD:HEADER` passes on the `subject`, `cn` or `san` of the client's TLS
+    /// certificate in a header, like `cert-header=cn:X-Client-Cert-CN`; repeat it for several.
+    #[arg(long, value_name = "prefix=url", value_parser = parse_proxy)]
+    proxy: Vec<(String, Proxy)>,
+    /// A TOML file with extra routes, mounts, redirects, rewrites and vhosts. Its `[server]`
+    /// table sets any of these options by name, with those given on the command line or in the
+    /// environment taking precedence. It's re-read when it changes or on SIGHUP, which applies
+    /// the routing options and connection timeouts; the others need a restart. A file that
+    /// fails to load leaves the current setup in place.
+    #[arg(long, value_name = "file")]
+    config: Option<PathBuf>,
+    /// Runs the CGI scripts in a directory under a prefix, as `PREFIX=DIR`.
+    #[arg(long, value_delimiter = ',', value_name = "prefix=dir", value_parser = parse_prefixed_path)]
+    cgi: Vec<(String, PathBuf)>,
+    /// Forwards a prefix to a FastCGI server such as php-fpm, as
+    /// `PREFIX=ADDRESS,root=DIR[,index=FILE][,split=EXT]`. `ADDRESS` is `HOST:PORT` or
+    /// `unix:PATH`, and `root` is the document root on the FastCGI server's side.
+    #[arg(long, value_name = "fastcgi", value_parser = parse_fastcgi)]
+    fastcgi: Vec<(String, FastCgi)>,
+    /// Serves a prefix with a WebAssembly plugin, as `PREFIX=FILE`.
+    #[cfg(feature = "wasm")]
+    #[arg(long, value_delimiter = ',', value_name = "prefix=file", value_parser = parse_prefixed_path)]
+    wasm_plugin: Vec<(String, PathBuf)>,
+    /// Fuel each plugin invocation gets, roughly the number of instructions it may run.
+    #[cfg(feature = "wasm")]
+    #[arg(long, value_name = "units", default_value_t = 100_000_000)]
+    wasm_fuel: u64,
+    /// Linear memory a plugin invocation may grow to, in MiB.
+    #[cfg(feature = "wasm")]
+    #[arg(long, value_name = "MiB", default_value_t = 64)]
+    wasm_max_memory: usize,
+    /// Loads a native plugin library and registers its routes. Only load libraries you trust.
+    #[cfg(feature = "native-plugins")]
+    #[arg(long, value_delimiter = ',', value_name = "library")]
+    plugin: Vec<PathBuf>,
+    /// Instead of listening, serves the site on a loopback port, sends it
+    /// `--self-test-requests` GET requests for these targets (`/path`, or `host/path` for a
+    /// virtual host) and prints the requests per second and latency percentiles it managed,
+    /// then exits. It fails if any request got no response.
+    #[arg(long, value_name = "target")]
+    self_test: Vec<Target>,
+    /// How many requests `--self-test` sends, spread evenly over its targets.
+    #[arg(
+        long,
+        value_name = "count",
+        default_value_t = 1000,
+        requires = "self_test"
+    )]
+    self_test_requests: usize,
+    /// How many connections `--self-test` sends requests on at once.
+    #[arg(
+        long,
+        value_name = "count",
+        default_value = "16",
+        requires = "self_test"
+    )]
+    self_test_concurrency: NonZeroUsize,
+    /// Instead of serving, sends the requests recorded in this file to `--replay-against`, one
+    /// after another, and prints those whose status, `Content-Type` or body came back different,
+    /// then exits, failing if any did. The file is HAR, like `--har-dir` records, or a JSON
+    /// object per line like `{"path":"/echo/a","status":200,"response_body":"a"}`.
+    #[arg(long, value_name = "file")]
+    replay: Option<PathBuf>,
+    /// The server `--replay` sends the requests to.
+    #[arg(
+        long,
+        value_name = "url",
+        default_value = "http://127.0.0.1:4221",
+        requires = "replay"
+    )]
+    replay_against: Upstream,
+    /// Every option's values as given (or defaulted), by name, to tell what a reload changed.
+    #[arg(skip)]
+    options: BTreeMap<String, Vec<String>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+/// The log's filter, for changing while the server runs.
+#[derive(Clone)]
+struct LogFilter {
+    handle: tracing_subscriber::reload::Handle<EnvFilter, Registry>,
+    /// What to go back to when debug logging is switched off again.
+    before_debug: Arc<std::sync::Mutex<Option<String>>>,
+}
+
+impl LogFilter {
+    fn current(&self) -> Result<String, String> {
+        self.handle
+            .with_current(|filter| filter.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    /// Swaps in `filter`, in `RUST_LOG`'s syntax.
+    fn set(&self, filter: &str) -> Result<String, String> {
+        let new = EnvFilter::try_new(filter).map_err(|e| format!("{filter:?}: {e}"))?;
+        self.handle.reload(new).map_err(|e| e.to_string())?;
+        self.current()
+    }
+
+    /// Shows or changes the filter for the admin endpoint. A change there leaves nothing for
+    /// [`toggle_debug`](Self::toggle_debug) to go back to.
+    fn admin(&self, filter: Option<&str>) -> Result<String, String> {
+        let Some(filter) = filter else {
+            return self.current();
+        };
+        let changed = self.set(filter)?;
+        *self.before_debug.lock().unwrap() = None;
+        Ok(changed)
+    }
+
+    /// Switches to `debug`, or back to what it was before.
+    fn toggle_debug(&self) -> Result<String, String> {
+        let mut before = self.before_debug.lock().unwrap();
+        match before.take() {
+            Some(previous) => self.set(&previous),
+            None => {
+                let previous = self.current()?;
+                let changed = self.set("debug")?;
+                *before = Some(previous);
+                Ok(changed)
+            }
+        }
+    }
+}
+
+/// Sends the log to `--log-file`, or stdout without one.
+fn init_logging(args: &Args) -> anyhow::Result<LogFilter> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let (filter, handle) = tracing_subscriber::reload::Layer::new(filter);
+    #[cfg(unix)]
+    let file = match &args.log_file {
+        Some(path) => {
+            let file = LogFile::open(path, rotation(args))
+                .with_context(|| format!("opening log file {}", path.display()))?;
+            file.capture_std_streams()
+                .context("sending output to the log file")?;
+            Some(file)
+        }
+        None => None,
+    };
+    #[cfg(not(unix))]
+    let file = None::<LogFile>;
+    let ansi = file.is_none() && std::io::stdout().is_terminal();
+    let writer = match file {
+        Some(file) => BoxMakeWriter::new(move || file.clone()),
+        None => BoxMakeWriter::new(std::io::stdout),
+    };
+    let log = tracing_subscriber::fmt::layer()
+        .with_ansi(ansi)
+        .with_writer(writer);
+    let log = match args.log_format {
+        LogFormat::Text => log.boxed(),
+        LogFormat::Json => log.json().boxed(),
+    };
+    // Filtered apart, so that spans are exported whatever the log leaves out.
+    let otlp = args.otlp_endpoint.clone().map(|endpoint| {
+        OtlpLayer::new(endpoint, &args.otlp_service_name).with_filter(filter_fn(otlp::is_exported))
+    });
+    tracing_subscriber::registry()
+        .with(log.with_filter(filter))
+        .with(otlp)
+        .init();
+    Ok(LogFilter {
+        handle,
+        before_debug: Arc::default(),
+    })
+}
+
+fn rotation(args: &Args) -> Rotation {
+    Rotation {
+        max_size: args.log_rotate_size,
+        every: args.log_rotate_every.map(Duration::from_secs),
+        #[cfg(feature = "compression")]
+        compress: args.log_rotate_compress,
+    }
+}
+
+fn error_rate(args: &Args) -> anyhow::Result<Option<ErrorRate>> {
+    let Some(threshold) = args.alert_error_rate else {
+        return Ok(None);
+    };
+    let action = match (&args.alert_webhook, &args.alert_command) {
+        (Some(url), _) => alert::Action::Webhook(url.clone()),
+        (None, Some(command)) => alert::Action::Command(command.clone()),
+        (None, None) => bail!("--alert-error-rate needs --alert-webhook or --alert-command"),
+    };
+    Ok(Some(
+        ErrorRate::new(threshold, action)
+            .window(Duration::from_secs(args.alert_window))
+            .min_requests(args.alert_min_requests),
+    ))
+}
+
+fn parse_listen(value: &str) -> Result<SocketAddr, String> {
+    match value.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([0, 0, 0, 0], port))),
+        Err(_) => value
+            .parse()
+            .map_err(|_| format!("expected ADDRESS:PORT or a port, got {value}")),
+    }
+}
+
+fn parse_rate(value: &str) -> Result<f64, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| rate.is_finite() && *rate > 0.0)
+        .ok_or_else(|| format!("expected a positive number of requests per second, got {value}"))
+}
+
+#[cfg(unix)]
+fn parse_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|mode| *mode <= 0o7777)
+        .ok_or_else(|| format!("expected an octal mode like 660, got {value}"))
+}
+
+#[cfg(feature = "metrics")]
+fn parse_url_path(value: &str) -> Result<String, String> {
+    match value.starts_with('/') && !value.contains(['{', '}']) {
+        true => Ok(value.to_owned()),
+        false => Err(format!("expected a path starting with '/', got {value}")),
+    }
+}
+
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if (0.0..1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("expected a fraction from 0 up to 1, got {value}")),
+    }
+}
+
+fn parse_vhost(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((host, dir)) if !host.is_empty() && !dir.is_empty() => {
+            Ok((host.to_owned(), PathBuf::from(dir)))
+        }
+        _ => Err("expected host=directory".to_owned()),
+    }
+}
+
+#[cfg(feature = "tls")]
+fn parse_tls_host(value: &str) -> Result<(String, PathBuf, PathBuf), String> {
+    let parsed = value.split_once('=').and_then(|(host, files)| {
+        let (cert, key) = files.split_once(',')?;
+        (!host.is_empty() && !cert.is_empty() && !key.is_empty())
+            .then(|| (host.to_owned(), PathBuf::from(cert), PathBuf::from(key)))
+    });
+    parsed.ok_or_else(|| "expected HOST=CERT,KEY".to_owned())
+}
+
+fn parse_prefixed_path(value: &str) -> Result<(String, PathBuf), String> {
+    match value.split_once('=') {
+        Some((prefix, file)) if prefix.starts_with('/') && !file.is_empty() => {
+            Ok((prefix.to_owned(), PathBuf::from(file)))
+        }
+        _ => Err("expected PREFIX=PATH with PREFIX starting with '/'".to_owned()),
+    }
+}
+
+fn parse_mount(value: &str) -> Result<(String, StaticDir), String> {
+    let mut options = value.split(',');
+    let (prefix, dir) = options
+        .next()
+        .and_then(|mount| mount.split_once('='))
+        .filter(|(prefix, dir)| prefix.starts_with('/') && !dir.is_empty())
+        .ok_or("expected PREFIX=DIR with PREFIX starting with '/'")?;
+
+    let mut static_dir = StaticDir::new(dir);
+    for option in options {
+        static_dir = match option.split_once('=') {
+            None if option == "autoindex" => static_dir.autoindex(true),
+            None if option == "rw" => static_dir.read_only(false),
+            None if option == "confine" => static_dir.confine(true),
+            Some(("cache", value)) => static_dir.cache_control(value),
+            _ => return Err(format!("unknown mount option {option}")),
+        };
+    }
+    Ok((prefix.to_owned(), static_dir))
+}
+
+/// A `--basic-auth`.
+#[derive(Debug, Clone)]
+struct BasicAuthRule {
+    prefix: String,
+    file: PathBuf,
+    writes_only: bool,
+    optional: bool,
+    realm: Option<String>,
+}
+
+fn parse_basic_auth(value: &str) -> Result<BasicAuthRule, String> {
+    let mut options = value.split(',');
+    let (prefix, file) = options
+        .next()
+        .and_then(|rule| rule.split_once('='))
+        .filter(|(prefix, file)| prefix.starts_with('/') && !file.is_empty())
+        .ok_or("expected PREFIX=FILE with PREFIX starting with '/'")?;
+    let mut rule = BasicAuthRule {
+        prefix: prefix.to_owned(),
+        file: file.into(),
+        writes_only: false,
+        optional: false,
+        realm: None,
+    };
+    for option in options {
+        match option.split_once('=') {
+            None if option == "writes" => rule.writes_only = true,
+            None if option == "optional" => rule.optional = true,
+            Some(("realm", realm)) => rule.realm = Some(realm.to_owned()),
+            _ => return Err(format!("unknown basic auth option {option}")),
+        }
+    }
+    Ok(rule)
+}
+
+fn parse_security_header(value: &str) -> Result<(String, Option<String>), String> {
+    let (name, value) = value
+        .split_once(':')
+        .filter(|(name, _)| !name.trim().is_empty())
+        .ok_or("expected NAME: VALUE")?;
+    let value = Some(value.trim()).filter(|value| !value.is_empty());
+    Ok((name.trim().to_owned(), value.map(str::to_owned)))
+}
+
+fn parse_prefix_security_header(value: &str) -> Result<(String, (String, Option<String>)), String> {
+    let (prefix, header) = value
+        .split_once('=')
+        .filter(|(prefix, _)| prefix.starts_with('/'))
+        .ok_or("expected PREFIX=NAME: VALUE with a PREFIX starting with '/'")?;
+    Ok((prefix.to_owned(), parse_security_header(header)?))
+}
+
+fn parse_prefix_ranges(value: &str) -> Result<(String, Vec<Cidr>), String> {
+    let (prefix, ranges) = value
+        .split_once('=')
+        .filter(|(prefix, _)| prefix.starts_with('/'))
+        .ok_or("expected PREFIX=CIDR[,CIDR...] with a PREFIX starting with '/'")?;
+    let ranges = ranges
+        .split(',')
+        .map(str::parse)
+        .collect::<Result<_, _>>()?;
+    Ok((prefix.to_owned(), ranges))
+}
+
+/// A `--bearer-auth`.
+#[derive(Debug, Clone)]
+struct BearerAuthRule {
+    prefix: String,
+    writes_only: bool,
+    optional: bool,
+    realm: Option<String>,
+}
+
+fn parse_bearer_auth(value: &str) -> Result<BearerAuthRule, String> {
+    let mut options = value.split(',');
+    let prefix = options
+        .next()
+        .filter(|prefix| prefix.starts_with('/'))
+        .ok_or("expected a PREFIX starting with '/'")?;
+    let mut rule = BearerAuthRule {
+        prefix: prefix.to_owned(),
+        writes_only: false,
+        optional: false,
+        realm: None,
+    };
+    for option in options {
+        match option.split_once('=') {
+            None if option == "writes" => rule.writes_only = true,
+            None if option == "optional" => rule.optional = true,
+            Some(("realm", realm)) => rule.realm = Some(realm.to_owned()),
+            _ => return Err(format!("unknown option {option}")),
+        }
+    }
+    Ok(rule)
+}
+
+fn parse_api_key_auth(value: &str) -> Result<(String, bool), String> {
+    match value.split_once(',') {
+        _ if !value.starts_with('/') => Err("expected a PREFIX starting with '/'".to_owned()),
+        None => Ok((value.to_owned(), false)),
+        Some((prefix, "optional")) => Ok((prefix.to_owned(), true)),
+        Some((_, option)) => Err(format!("unknown option {option}")),
+    }
+}
+
+fn parse_bearer_token(value: &str) -> Result<(String, Secret), String> {
+    value
+        .split_once('=')
+        .filter(|(identity, token)| !identity.is_empty() && !token.is_empty())
+        .map(|(identity, token)| (identity.to_owned(), Secret::new(token)))
+        .ok_or_else(|| "expected IDENTITY=TOKEN".to_owned())
+}
+
+fn parse_proxy(value: &str) -> Result<(String, Proxy), String> {
+    let mut options = value.split(',');
+    let (prefix, upstream) = options
+        .next()
+        .and_then(|proxy| proxy.split_once('='))
+        .filter(|(prefix, _)| prefix.starts_with('/'))
+        .ok_or("expected PREFIX=URL with PREFIX starting with '/'")?;
+
+    let mut upstreams = vec![upstream.parse()?];
+    let mut balance = Balance::default();
+    let mut preserve_host = false;
+    let mut health_check = None;
+    let mut check_interval = None;
+    let mut retry = RetryPolicy::default();
+    let mut cert_headers = Vec::new();
+    for option in options {
+        match option.split_once('=') {
+            _ if option.starts_with("http://") => upstreams.push(option.parse()?),
+            None if option == "preserve-host" => preserve_host = true,
+            Some(("cert-header", header)) => {
+                let (field, name) = header
+                    .split_once(':')
+                    .filter(|(_, name)| {
+                        !name.is_empty()
+                            && name.bytes().all(|b| {
+                                b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+                            })
+                    })
+                    .ok_or_else(|| format!("expected cert-header=FIELD:HEADER, got {header}"))?;
+                cert_headers.push((field.parse::<CertField>()?, name.to_owned()));
+            }
+            Some(("balance", strategy)) => balance = strategy.parse()?,
+            Some(("check", probe)) => health_check = Some(HealthCheck::new(probe.parse()?)),
+            Some(("check-interval", secs)) => {
+                let secs = secs
+                    .parse()
+                    .map_err(|_| format!("invalid check interval {secs}"))?;
+                check_interval = Some(Duration::from_secs(secs));
+            }
+            Some(("retries", retries)) => {
+                let retries = retries
+                    .parse::<u32>()
+                    .map_err(|_| format!("invalid retry count {retries}"))?;
+                retry.attempts = retries + 1;
+            }
+            Some(("try-timeout", secs)) => {
+                let secs = secs
+                    .parse()
+                    .map_err(|_| format!("invalid try timeout {secs}"))?;
+                retry.per_try_timeout = Some(Duration::from_secs(secs));
+            }
+            _ => return Err(format!("unknown proxy option {option}")),
+        }
+    }
+
+    let mut proxy = Proxy::balanced(upstreams, balance)
+        .preserve_host(preserve_host)
+        .retry(retry);
+    for (field, name) in cert_headers {
+        proxy = proxy.cert_header(field, name);
+    }
+    match (health_check, check_interval) {
+        (Some(mut check), interval) => {
+            check.interval = interval.unwrap_or(check.interval);
+            proxy = proxy.health_check(check);
+        }
+        (None, Some(_)) => return Err("check-interval needs check=tcp or check=PATH".to_owned()),
+        (None, None) => {}
+    }
+    Ok((prefix.to_owned(), proxy))
+}
+
+fn parse_fastcgi(value: &str) -> Result<(String, FastCgi), String> {
+    let mut options = value.split(',');
+    let (prefix, address) = options
+        .next()
+        .and_then(|upstream| upstream.split_once('='))
+        .filter(|(prefix, _)| prefix.starts_with('/'))
+        .ok_or("expected PREFIX=ADDRESS with PREFIX starting with '/'")?;
+    let address = address.parse()?;
+
+    let mut root = None;
+    let mut index = None;
+    let mut split = None;
+    for option in options {
+        match option.split_once('=') {
+            Some(("root", value)) => root = Some(value),
+            Some(("index", value)) => index = Some(value),
+            Some(("split", value)) => split = Some(value),
+            _ => return Err(format!("unknown fastcgi option {option}")),
+        }
+    }
+
+    let mut fastcgi = FastCgi::new(address, root.ok_or("missing root=DIR")?);
+    if let Some(index) = index {
+        fastcgi = fastcgi.index(index);
+    }
+    if let Some(extension) = split {
+        fastcgi = fastcgi.split_path_info(extension);
+    }
+    Ok((prefix.to_owned(), fastcgi))
+}
+
+fn site_router(
+    base_dir: PathBuf,
+    args: &Args,
+    config: &RouteConfig,
+    shared: &Shared,
+) -> anyhow::Result<Arc<Router>> {
+    let state_dir = base_dir.clone();
+    let mut base_dir = ServedDir::new(base_dir).confine(args.confine);
+    if let Some(backoff) = fs_backoff(args) {
+        base_dir = base_dir.backoff(backoff);
+    }
+    let upload_scan = upload_scan(args)?;
+    let upload_filter = upload_filter(args, config);
+    let state = Arc::new(AppState {
+        base_dir,
+        upload_scan: upload_scan.clone(),
+        upload_filter: upload_filter.clone(),
+        upload_expiry: shared.upload_expiry.clone(),
+        max_delay: Duration::from_millis(args.max_delay),
+        broadcast: broadcast::channel(64).0,
+        notices: shared.notices.clone(),
+        kv: shared.kv.clone(),
+        shortener: shared.shortener.clone(),
+    });
+    let mut router = routes::default_router(state);
+    if args.ui {
+        router = web_ui::routes(router);
+    }
+    // Outermost, so that every layer deciding on the path, like the authentication, sees the
+    // one the request is routed by.
+    if !args.redirect.is_empty() {
+        router = router.layer(args.redirect.iter().cloned().collect::<RedirectTable>());
+    }
+    if !args.rewrite.is_empty() {
+        router = router.layer(Rewrite::new(args.rewrite.clone()));
+    }
+    router = config.rewrite(router)?;
+    // Outermost but for the rewrites, to record what the client sent and got.
+    if let Some(har) = &shared.har {
+        router = router.layer(har.clone());
+    }
+    #[cfg(feature = "compression")]
+    {
+        router = router.layer(Compression::new(shared.settings.subscribe()));
+    }
+    router = router.layer(BodyLimit::new(shared.settings.subscribe()));
+    if !args.trusted_proxy.is_empty() {
+        router = router.layer(TrustedProxies::new(args.trusted_proxy.iter().copied()));
+    }
+    // After TrustedProxies, which tell it whether the client came over HTTPS for HSTS, and
+    // outside everything that might refuse a request.
+    if args.security_headers {
+        let mut headers = SecurityHeaders::new();
+        for (name, value) in &args.security_header {
+            headers = headers.set(name, value.as_deref());
+        }
+        for (prefix, (name, value)) in &args.security_header_prefix {
+            headers = headers.set_under(prefix, name, value.as_deref());
+        }
+        router = router.layer(headers);
+    }
+    // After TrustedProxies too, and in front of everything recording events.
+    if let Some(log) = &shared.security_log {
+        router = router.layer(log.clone());
+    }
+    // In front of everything that marks requests for it.
+    if let Some(tarpit) = &shared.tarpit {
+        router = router.layer(tarpit.clone());
+    }
+    // After TrustedProxies, for the client's address. The server already closed on peers the
+    // global rules deny, unless there's a tarpit; this catches the clients behind trusted
+    // proxies.
+    let global = ip_rules(args);
+    if !global.is_empty() {
+        router = router.layer(IpFilter::new("/", global));
+    }
+    let mut prefixes: Vec<&str> = Vec::new();
+    for (prefix, _) in args.ip_allow_prefix.iter().chain(&args.ip_deny_prefix) {
+        if !prefixes.contains(&prefix.as_str()) {
+            prefixes.push(prefix);
+        }
+    }
+    for prefix in prefixes {
+        let ranges = |rules: &[(String, Vec<Cidr>)]| {
+            rules
+                .iter()
+                .filter(|(rule, _)| rule == prefix)
+                .flat_map(|(_, ranges)| ranges.clone())
+                .collect::<Vec<_>>()
+        };
+        let rules = IpRules::new()
+            .allow(ranges(&args.ip_allow_prefix))
+            .deny(ranges(&args.ip_deny_prefix));
+        router = router.layer(IpFilter::new(prefix, rules));
+    }
+    if !args.hotlink_allow.is_empty() {
+        let mut hotlink = Hotlink::new(&args.hotlink_allow).block_empty(args.hotlink_block_empty);
+        if !args.hotlink_extensions.is_empty() {
+            hotlink = hotlink.extensions(&args.hotlink_extensions);
+        }
+        if let Some(file) = &args.hotlink_placeholder {
+            hotlink = hotlink.placeholder(file)?;
+        }
+        router = router.layer(hotlink);
+    }
+    // Before the authentication, which preflights don't carry credentials for, and so that
+    // browsers can read the 401s and 403s it answers with.
+    if !args.cors_origin.is_empty() {
+        // Any site could then read what's only meant for the user, with their cookies.
+        if args.cors_credentials && args.cors_origin.contains(&AllowedOrigin::Any) {
+            bail!("--cors-credentials can't be used with --cors-origin '*'");
+        }
+        let mut cors = Cors::new(args.cors_origin.iter().cloned())
+            .methods(args.cors_methods.iter().map(String::as_str))
+            .headers(args.cors_headers.iter().map(String::as_str))
+            .expose_headers(args.cors_expose_headers.iter().map(String::as_str))
+            .credentials(args.cors_credentials);
+        if let Some(secs) = args.cors_max_age {
+            cors = cors.max_age(Duration::from_secs(secs));
+        }
+        router = router.layer(cors);
+    }
+    for prefix in &args.csrf {
+        let csrf = Csrf::new(prefix)
+            .cookie(&args.csrf_cookie)
+            .header(&args.csrf_header)
+            .exempt_headers([args.api_key_header.clone()]);
+        router = router.layer(csrf);
+    }
+    let cookie_keys = match &args.cookie_keys_file {
+        Some(file) => Secret::read_lines(file)?,
+        None => args.cookie_key.clone(),
+    };
+    if let Some((current, previous)) = cookie_keys.split_first() {
+        if cookie_keys.iter().any(|key| key.len() < 32) {
+            bail!("every --cookie-key needs to be at least 32 bytes");
+        }
+        let keys = previous
+            .iter()
+            .fold(CookieKeys::new(current.expose().as_bytes()), |keys, key| {
+                keys.previous(key.expose().as_bytes())
+            });
+        router = router.layer(keys);
+    }
+    if args.sessions {
+        let secret = match &args.session_secret_file {
+            Some(file) => Some(Secret::read(file)?),
+            None => args.session_secret.clone(),
+        };
+        let key = match &secret {
+            Some(secret) => secret.expose().as_bytes(),
+            None => &shared.session_key,
+        };
+        let sessions = Sessions::new(shared.sessions.clone(), key)
+            .cookie(&args.session_cookie)
+            .ttl(Duration::from_secs(args.session_ttl));
+        router = router.layer(sessions);
+    }
+    // Inside TrustedProxies, for the client's address rather than its proxy's, but outside the
+    // authentication, so that refused writes are recorded too. Who the client turned out to
+    // be still reaches it through the request's IdentitySlot.
+    if let Some(audit_log) = &shared.audit_log {
+        router = router.layer(audit_log.clone());
+    }
+    for rule in &args.basic_auth {
+        let users = Htpasswd::load(&rule.file)?;
+        let mut auth = BasicAuth::new(&rule.prefix, users)
+            .writes_only(rule.writes_only)
+            .optional(rule.optional);
+        if let Some(realm) = &rule.realm {
+            auth = auth.realm(realm);
+        }
+        if let Some(lockout) = &shared.lockout {
+            auth = auth.lockout(lockout.clone());
+        }
+        router = router.layer(auth);
+    }
+    if !args.bearer_auth.is_empty() {
+        let mut tokens = BearerTokens::new();
+        for (identity, token) in &args.bearer_token {
+            tokens.insert(identity, token.expose());
+        }
+        if let Some(file) = &args.bearer_tokens_file {
+            tokens.load(file)?;
+        }
+        if tokens.is_empty() {
+            bail!("--bearer-auth needs --bearer-token or --bearer-tokens-file");
+        }
+        let tokens = Arc::new(tokens);
+        for rule in &args.bearer_auth {
+            let mut auth = BearerAuth::new(&rule.prefix, tokens.clone())
+                .writes_only(rule.writes_only)
+                .optional(rule.optional);
+            if let Some(realm) = &rule.realm {
+                auth = auth.realm(realm);
+            }
+            router = router.layer(auth);
+        }
+    }
+    if let Some(file) = &args.api_keys_file {
+        let keys = Arc::new(ApiKeys::load(file)?);
+        let query = Some(args.api_key_query.clone()).filter(|query| !query.is_empty());
+        for (prefix, optional) in &args.api_key_auth {
+            let auth = ApiKeyAuth::new(prefix, keys.clone())
+                .header(&args.api_key_header)
+                .query(query.clone())
+                .optional(*optional);
+            router = router.layer(auth);
+        }
+    }
+    #[cfg(feature = "jwt")]
+    if !args.jwt.is_empty() {
+        let secret = match &args.jwt_secret_file {
+            Some(file) => Some(Secret::read(file)?),
+            None => args.jwt_secret.clone(),
+        };
+        let verifier = if let Some(secret) = &secret {
+            JwtVerifier::hs256(secret.expose().as_bytes())
+        } else if let Some(file) = &args.jwt_public_key {
+            let pem = std::fs::read(file).with_context(|| format!("reading {}", file.display()))?;
+            JwtVerifier::public_key(&pem).with_context(|| format!("reading {}", file.display()))?
+        } else if let Some(url) = &args.jwt_jwks_url {
+            JwtVerifier::jwks(url)
+        } else {
+            bail!(
+                "--jwt needs --jwt-secret, --jwt-secret-file, --jwt-public-key or --jwt-jwks-url"
+            );
+        };
+        let verifier = args
+            .jwt_audience
+            .iter()
+            .fold(verifier, |verifier, audience| verifier.audience(audience));
+        let verifier = args
+            .jwt_issuer
+            .iter()
+            .fold(verifier, |verifier, issuer| verifier.issuer(issuer))
+            .leeway(Duration::from_secs(args.jwt_leeway));
+        let verifier = Arc::new(verifier);
+        for rule in &args.jwt {
+            let mut auth = JwtAuth::new(&rule.prefix, verifier.clone())
+                .writes_only(rule.writes_only)
+                .optional(rule.optional);
+            if let Some(realm) = &rule.realm {
+                auth = auth.realm(realm);
+            }
+            router = router.layer(auth);
+        }
+    }
+    if !args.signed_writes.is_empty() {
+        let mut secrets = SigningSecrets::new();
+        for (client, secret) in &args.signing_secret {
+            secrets.insert(client, secret.expose());
+        }
+        if let Some(file) = &args.signing_secrets_file {
+            secrets.load(file)?;
+        }
+        if secrets.is_empty() {
+            bail!("--signed-writes needs --signing-secret or --signing-secrets-file");
+        }
+        let secrets = Arc::new(secrets);
+        for prefix in &args.signed_writes {
+            let mut signed = SignedRequests::new(prefix, secrets.clone())
+                .skew(Duration::from_secs(args.signature_skew));
+            if let Some(max) = args.max_body_size {
+                signed = signed.max_body(max);
+            }
+            router = router.layer(signed);
+        }
+    }
+    // Inside all of the authentication, whose identities and grants they go by.
+    if args.access_files {
+        let mounts = args
+            .mount
+            .iter()
+            .map(|(prefix, dir)| (prefix.as_str(), dir.root()))
+            .chain(
+                config
+                    .mounts
+                    .iter()
+                    .map(|mount| (mount.prefix.as_str(), mount.directory.as_path())),
+            );
+        let files = std::iter::once(("/files/", state_dir.as_path()));
+        // The page's listings and the form's uploads go by the files of the directory they're in.
+        let files = files
+            .chain(args.ui.then_some(("/_ui/list", state_dir.as_path())))
+            .chain([("/upload", state_dir.as_path())]);
+        for (prefix, root) in files.chain(mounts) {
+            router = router.layer(AccessFiles::new(prefix, root));
+        }
+    }
+    if let Some(filter) = upload_filter {
+        router = router.layer(filter);
+    }
+    router = router.layer(shared.rate_limiter.clone());
+    if let Some(shed) = &shared.load_shed {
+        router = router.layer(shed.clone());
+    }
+    if let Some(secs) = args.request_timeout {
+        router = router.layer(Timeout::new(Duration::from_secs(secs)));
+    }
+    if !args.connect_allow.is_empty() {
+        router = router.layer(ConnectTunnel::new(args.connect_allow.iter().cloned()));
+    }
+    for (prefix, dir) in &args.mount {
+        let mut dir = match args.confine {
+            true => dir.clone().confine(true),
+            false => dir.clone(),
+        };
+        if let Some(backoff) = fs_backoff(args) {
+            dir = dir.backoff(backoff);
+        }
+        if let Some(scan) = &upload_scan {
+            dir = dir.upload_scan(scan.clone());
+        }
+        router = router.mount(prefix, dir);
+    }
+    for (prefix, dir) in &args.cgi {
+        router = router.mount(prefix, Cgi::new(dir));
+    }
+    for (prefix, proxy) in &args.proxy {
+        router = router.mount(prefix, proxy.clone());
+    }
+    for (prefix, fastcgi) in &args.fastcgi {
+        router = router.mount(prefix, fastcgi.clone());
+    }
+    if args.method_override {
+        router = router.layer(MethodOverride);
+    }
+    #[cfg(feature = "wasm")]
+    for (prefix, file) in &args.wasm_plugin {
+        let limits = wasm::PluginLimits {
+            fuel: args.wasm_fuel,
+            max_memory: args.wasm_max_memory * 1024 * 1024,
+        };
+        router = router.mount(prefix, wasm::WasmPlugin::load(file, limits)?);
+    }
+    #[cfg(feature = "native-plugins")]
+    for library in &args.plugin {
+        let plugin = native_plugin::NativePlugin::load(library)?;
+        for (method, pattern) in plugin.routes() {
+            anyhow::ensure!(
+                !router.has_route(method, pattern),
+                "plugin {} registers {method} {pattern}, which already exists",
+                library.display()
+            );
+        }
+        router = plugin.install(router);
+    }
+    router = config.apply(router)?;
+    if args.upstreams_endpoint {
+        let proxies = args.proxy.clone();
+        router = router.get("/_upstreams", move || {
+            let status = proxies
+                .iter()
+                .map(|(prefix, proxy)| (prefix.clone(), proxy.status()))
+                .collect::<BTreeMap<_, _>>();
+            async move { Json(status) }
+        });
+    }
+    if args.routes_endpoint {
+        router = router.route_listing("/_routes");
+    }
+    Ok(Arc::new(router))
+}
+
+fn fs_backoff(args: &Args) -> Option<Backoff> {
+    args.fs_backoff.map(|threshold| Backoff {
+        threshold,
+        cool_down: Duration::from_secs(args.fs_backoff_cool_down),
+    })
+}
+
+fn upload_filter(args: &Args, config: &RouteConfig) -> Option<UploadFilter> {
+    let filters_uploads = !(args.upload_allow_extensions.is_empty()
+        && args.upload_deny_extensions.is_empty()
+        && args.upload_allow_types.is_empty()
+        && args.upload_deny_types.is_empty());
+    if !filters_uploads {
+        return None;
+    }
+    let mounts = args.mount.iter().map(|(prefix, _)| prefix.clone());
+    let config_mounts = config
+        .mounts
+        .iter()
+        .filter(|mount| !mount.read_only)
+        .map(|mount| mount.prefix.clone());
+    let prefixes = std::iter::once("/files/".to_owned())
+        .chain(mounts)
+        .chain(config_mounts);
+    let filter = UploadFilter::new(prefixes)
+        .allow_extensions(&args.upload_allow_extensions)
+        .deny_extensions(&args.upload_deny_extensions)
+        .allow_types(&args.upload_allow_types)
+        .deny_types(&args.upload_deny_types);
+    Some(filter)
+}
+
+fn upload_scan(args: &Args) -> anyhow::Result<Option<UploadScan>> {
+    let Some(command) = &args.upload_scan_command else {
+        return Ok(None);
+    };
+    let scan = UploadScan::new(CommandScanner::new(command)?)
+        .timeout(Duration::from_secs(args.upload_scan_timeout))
+        .fail_open(args.upload_scan_fail_open);
+    Ok(Some(scan))
+}
+
+fn build_hosts(args: &Args, shared: &Shared) -> anyhow::Result<VirtualHosts> {
+    let mut config = match &args.config {
+        Some(path) => RouteConfig::load(path)?,
+        None => RouteConfig::default(),
+    };
+    for mount in &mut config.mounts {
+        mount.confine |= args.confine;
+        mount.backoff = fs_backoff(args);
+        mount.upload_scan = upload_scan(args)?;
+    }
+
+    let default = site_router(args.directory.clone(), args, &config, shared)?;
+    let mut hosts = VirtualHosts::new(default);
+    let vhosts = args.vhost.iter().cloned().chain(
+        config
+            .vhosts
+            .iter()
+            .map(|v| (v.host.clone(), v.directory.clone())),
+    );
+    for (host, dir) in vhosts {
+        hosts = hosts.host(&host, site_router(dir, args, &config, shared)?);
+    }
+    Ok(hosts)
+}
+
+pub fn main() -> anyhow::Result<()> {
+    let args = match load_args() {
+        Ok(args) => Arc::new(args),
+        // Usage errors, and --help and --version, are printed and exit the way clap does it.
+        Err(e) => match e.downcast_ref::<clap::Error>() {
+            Some(usage) => {
+                // Context, like which config file the options came from, goes first.
+                if usage.use_stderr() && e.to_string() != usage.to_string() {
+                    eprintln!("Error {e}:");
+                }
+                usage.exit()
+            }
+            None => return Err(e),
+        },
+    };
+    // Forking has to come before the runtime starts its threads.
+    #[cfg(unix)]
+    let (ready, _pidfile) = detach(&args)?;
+    #[cfg(not(unix))]
+    let ready = None::<std::convert::Infallible>;
+    let log_filter = init_logging(&args)?;
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    // The cores get runtimes of their own; this one just sets them up and handles signals.
+    #[cfg(feature = "thread-per-core")]
+    if args.thread_per_core {
+        runtime = tokio::runtime::Builder::new_current_thread();
+    }
+    runtime.enable_all();
+    if let Some(workers) = args.workers {
+        runtime.worker_threads(workers.get());
+    }
+    if let Some(max) = args.max_blocking_threads {
+        runtime.max_blocking_threads(max.get());
+    }
+    let runtime = runtime.build().context("starting the runtime")?;
+    runtime.block_on(run(args, log_filter, move || {
+        #[cfg(unix)]
+        if let Some(ready) = ready {
+            ready.notify();
+        }
+    }))
+}
+
+/// Goes into the background and writes the pidfile, as the options say.
+#[cfg(unix)]
+fn detach(args: &Args) -> anyhow::Result<(Option<daemon::Ready>, Option<PidFile>)> {
+    // A process taking over from an earlier one is wherever that one was, foreground or not,
+    // and finds its pidfile in use.
+    let handed_off = listener::is_handed_off();
+    let pidfile = args.pidfile.as_deref().map(|path| {
+        let pidfile = match handed_off {
+            true => PidFile::take_over(path),
+            false => PidFile::create(path),
+        };
+        pidfile.with_context(|| format!("writing pidfile {}", path.display()))
+    });
+    let pidfile = pidfile.transpose()?;
+    let ready = match (args.daemon && !handed_off, &args.log_file) {
+        (true, log) => {
+            let ready = daemon::daemonize(log.as_deref()).context("going into the background")?;
+            if let Some(pidfile) = &pidfile {
+                pidfile.refresh().context("writing the pidfile")?;
+            }
+            Some(ready)
+        }
+        // The log file is opened along with the log.
+        (false, _) => None,
+    };
+    Ok((ready, pidfile))
+}
+
+/// What the environment variables setting options start with.
+const ENV_PREFIX: &str = "HTTP_SERVER_";
+
+/// The environment variable for an option, like `HTTP_SERVER_MAX_CONNECTIONS` for
+/// `--max-connections`.
+fn env_var(option: &str) -> String {
+    format!("{ENV_PREFIX}{}", option.to_uppercase().replace('-', "_"))
+}
+
+/// Parses `command_line`, with every option also read from its environment variable when it's
+/// not on the command line. Lists there are separated by commas, like `--listen a,b` can be,
+/// and switches take `1`/`0`, `yes`/`no` and the like as well as `true`/`false`.
+fn parse_from(command_line: impl IntoIterator<Item = String>) -> Result<Args, clap::Error> {
+    let command = Args::command().mut_args(|arg| {
+        let Some(var) = arg.get_long().map(env_var) else {
+            return arg;
+        };
+        if !matches!(arg.get_action(), ArgAction::SetTrue) {
+            return arg.env(var);
+        }
+        // A switch turned off there is left out, as it would count as given for the options
+        // that require it.
+        let off = std::env::var(&var).is_ok_and(|value| {
+            let value = value.to_ascii_lowercase();
+            matches!(&*value, "" | "0" | "false" | "f" | "no" | "n" | "off")
+        });
+        match off {
+            true => arg,
+            false => arg.env(var).value_parser(BoolishValueParser::new()),
+        }
+    });
+    let options = command
+        .get_arguments()
+        .filter_map(|arg| Some((arg.get_id().clone(), arg.get_long()?.to_owned())))
+        .collect::<Vec<_>>();
+    let matches = command.try_get_matches_from(command_line)?;
+    let mut args = Args::from_arg_matches(&matches)?;
+    args.options = options
+        .into_iter()
+        .map(|(id, long)| {
+            let values = matches.get_raw(id.as_str()).into_iter().flatten();
+            (
+                long,
+                values.map(|v| v.to_string_lossy().into_owned()).collect(),
+            )
+        })
+        .collect();
+    Ok(args)
+}
+
+/// Parses the command line and environment, with the options in the config file's `[server]`
+/// table filled in for what neither sets. The file's lists don't add to theirs.
+fn load_args() -> anyhow::Result<Args> {
+    let command_line = std::env::args_os()
+        .map(|arg| arg.to_string_lossy().into_owned())
+        .collect::<Vec<_>>();
+    let given = |name: &str| {
+        command_line[1..].iter().position(|arg| {
+            let flag = arg.split_once('=').map_or(&**arg, |(flag, _)| flag);
+            flag.strip_prefix("--") == Some(name)
+        })
+    };
+    // Just a peek: the command line is parsed properly together with the file's options.
+    let config = match given("config") {
+        Some(i) => match command_line[i + 1].split_once('=') {
+            Some((_, path)) => Some(path.to_owned()),
+            None => command_line.get(i + 2).cloned(),
+        },
+        None => std::env::var(env_var("config")).ok(),
+    };
+    if let Some(name) = SECRET_OPTIONS.iter().find(|name| given(name).is_some()) {
+        bail!(
+            "--{name} can't be given on the command line, where other users can see it; set {} \
+             or put it in the config file's [server] table instead",
+            env_var(name)
+        );
+    }
+    let Some(path) = config else {
+        return Ok(parse_from(command_line)?);
+    };
+    let server = RouteConfig::load(Path::new(&path))?.server;
+
+    let mut from_file = Vec::new();
+    for (key, value) in &server {
+        let name = key.replace('_', "-");
+        anyhow::ensure!(name != "config", "the [server] table can't set --config");
+        if given(&name).is_some() || std::env::var_os(env_var(&name)).is_some() {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values.iter().collect(),
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => from_file.push(format!("--{name}")),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(value) => from_file.push(format!("--{name}={value}")),
+                toml::Value::Integer(_) | toml::Value::Float(_) => {
+                    from_file.push(format!("--{name}={value}"))
+                }
+                _ => bail!("[server] option {key} in {path} isn't a string, number or boolean"),
+            }
+        }
+    }
+    let command_line = command_line[..1]
+        .iter()
+        .cloned()
+        .chain(from_file)
+        .chain(command_line[1..].iter().cloned());
+    parse_from(command_line).with_context(|| format!("in the [server] options of {path}"))
+}
+
+/// Options whose changes a reload applies; the rest take a restart.
+const RELOADABLE: &[&str] = &[
+    "directory",
+    "confine",
+    "fs-backoff",
+    "fs-backoff-cool-down",
+    "upload-scan-command",
+    "upload-scan-timeout",
+    "upload-scan-fail-open",
+    "upload-allow-extensions",
+    "upload-deny-extensions",
+    "upload-allow-types",
+    "upload-deny-types",
+    "max-delay",
+    "header-timeout",
+    "keep-alive-timeout",
+    "max-requests-per-connection",
+    "write-timeout",
+    "min-rate",
+    "min-rate-period",
+    "request-timeout",
+    "max-body-size",
+    "compression",
+    "memory-budget",
+    "rate-limit",
+    "rate-limit-burst",
+    "vhost",
+    "rewrite",
+    "redirect",
+    "method-override",
+    "connect-allow",
+    "trusted-proxy",
+    "routes-endpoint",
+    "upstreams-endpoint",
+    "ui",
+    "mount",
+    "proxy",
+    "cgi",
+    "fastcgi",
+    "wasm-plugin",
+    "wasm-fuel",
+    "wasm-max-memory",
+    "plugin",
+    "basic-auth",
+    "bearer-auth",
+    "bearer-token",
+    "bearer-tokens-file",
+    "jwt",
+    "jwt-secret",
+    "jwt-secret-file",
+    "jwt-public-key",
+    "jwt-jwks-url",
+    "jwt-audience",
+    "jwt-issuer",
+    "jwt-leeway",
+    "api-key-auth",
+    "api-keys-file",
+    "api-key-header",
+    "api-key-query",
+    "signed-writes",
+    "signing-secret",
+    "signing-secrets-file",
+    "signature-skew",
+    "ip-allow",
+    "ip-deny",
+    "ip-allow-prefix",
+    "ip-deny-prefix",
+    "cors-origin",
+    "cors-methods",
+    "cors-headers",
+    "cors-expose-headers",
+    "cors-credentials",
+    "cors-max-age",
+    "csrf",
+    "csrf-cookie",
+    "csrf-header",
+    "sessions",
+    "session-cookie",
+    "session-ttl",
+    "session-secret",
+    "session-secret-file",
+    "cookie-key",
+    "cookie-keys-file",
+    "security-headers",
+    "security-header",
+    "security-header-prefix",
+    "hotlink-allow",
+    "hotlink-extensions",
+    "hotlink-block-empty",
+    "hotlink-placeholder",
+];
+
+/// What a reload can change while the server runs, and the connection counts and buffers that
+/// carry on across it. TLS certificates reload themselves, see [`watched_certificate`].
+struct Live {
+    args: std::sync::Mutex<Arc<Args>>,
+    hosts: Reloadable,
+    limits: watch::Sender<ConnectionLimits>,
+    ip_rules: watch::Sender<Arc<IpRules>>,
+    shared: Shared,
+    stats: Arc<ConnectionStats>,
+    buffers: Arc<BufferPool>,
+    /// Kept open from the start, for every server to write to.
+    access_log: Option<Arc<AccessLog>>,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+    #[cfg(feature = "metrics")]
+    statsd: Option<Arc<StatsD>>,
+    error_rate: Option<Arc<ErrorRate>>,
+}
+
+/// What the sites' routers share, and keep over a reload.
+struct Shared {
+    /// What the admin endpoints adjust, starting out as the options say.
+    settings: watch::Sender<Settings>,
+    rate_limiter: RateLimiter,
+    load_shed: Option<LoadShed>,
+    har: Option<Har>,
+    audit_log: Option<AuditLog>,
+    security_log: Option<SecurityLog>,
+    /// Kept across reloads, so that they don't log everyone out.
+    sessions: Arc<MemoryStore>,
+    /// What session cookies are signed with without a `--session-secret`.
+    session_key: Vec<u8>,
+    /// Kept across reloads too, so that reloading doesn't end a lockout.
+    lockout: Option<Arc<Lockout>>,
+    /// And so that the answers dripping when it happens still count against the slots.
+    tarpit: Option<Tarpit>,
+    /// And so that `/events` streams opened before it hear of it.
+    notices: Notices,
+    /// And so that it doesn't empty `/kv`.
+    kv: Arc<KvStore>,
+    /// And so that only the one writes the links file.
+    shortener: Arc<Shortener>,
+    /// And so that the sweep started with the server sees the uploads after a reload.
+    upload_expiry: Arc<UploadExpiry>,
+}
+
+impl Live {
+    fn new(args: Arc<Args>) -> anyhow::Result<Self> {
+        memory::set_budget(args.memory_budget);
+        let settings = watch::Sender::new(settings(&args));
+        let shared = Shared {
+            rate_limiter: RateLimiter::new(settings.subscribe()),
+            load_shed: args.max_requests_in_flight.map(|max| {
+                let timeout = Duration::from_secs(args.request_queue_timeout);
+                LoadShed::new(max.get()).queue(args.request_queue, timeout)
+            }),
+            har: match &args.har_dir {
+                Some(dir) => {
+                    let har = Har::new(dir)
+                        .with_context(|| format!("creating HAR directory {}", dir.display()))?
+                        .window(Duration::from_secs(args.har_window))
+                        .max_body(args.har_max_body);
+                    Some(har)
+                }
+                None => None,
+            },
+            audit_log: args.audit_log.as_deref().map(AuditLog::open).transpose()?,
+            security_log: (args.security_log.as_deref().map(SecurityLog::open)).transpose()?,
+            sessions: Arc::new(MemoryStore::new(args.session_max)),
+            session_key: [uuid::Uuid::new_v4(), uuid::Uuid::new_v4()]
+                .iter()
+                .flat_map(|uuid| *uuid.as_bytes())
+                .collect(),
+            lockout: (args.login_attempts > 0).then(|| {
+                let max = Duration::from_secs(args.login_lockout_max);
+                Arc::new(
+                    Lockout::new()
+                        .allowed(args.login_attempts)
+                        .first(Duration::from_secs(args.login_lockout))
+                        .max(max)
+                        .forget_after(max),
+                )
+            }),
+            tarpit: args.tarpit.map(|slots| {
+                Tarpit::new(slots as usize)
+                    .interval(Duration::from_secs(args.tarpit_interval))
+                    .duration(Duration::from_secs(args.tarpit_duration))
+            }),
+            notices: Notices::new(),
+            kv: Arc::new(
+                KvStore::new()
+                    .max_entries(args.kv_max_entries)
+                    .max_bytes(args.kv_max_bytes),
+            ),
+            shortener: Arc::new(match &args.data_dir {
+                Some(dir) => Shortener::open(dir)?,
+                None => Shortener::new(),
+            }),
+            upload_expiry: Arc::new(match &args.data_dir {
+                Some(dir) => UploadExpiry::open(dir)?,
+                None => UploadExpiry::new(),
+            }),
+            settings,
+        };
+        let stats = Arc::<ConnectionStats>::default();
+        Ok(Self {
+            hosts: Reloadable::new(build_hosts(&args, &shared)?),
+            limits: watch::Sender::new(connection_limits(&args)),
+            ip_rules: watch::Sender::new(Arc::new(ip_rules(&args))),
+            shared,
+            #[cfg(feature = "metrics")]
+            metrics: Arc::new(Metrics::new().connections(stats.clone())),
+            stats,
+            buffers: Arc::new(BufferPool::new(
+                args.io_buffer_size as usize,
+                args.idle_buffers,
+            )),
+            access_log: match &args.access_log {
+                Some(path) => {
+                    let format = args.access_log_format;
+                    let log = AccessLog::open(path, format, rotation(&args))?
+                        .sample(args.access_log_sample)
+                        .exclude(args.access_log_exclude.iter().cloned())
+                        .redact(
+                            Some(args.api_key_query.clone())
+                                .filter(|param| args.api_keys_file.is_some() && !param.is_empty()),
+                        );
+                    Some(Arc::new(log))
+                }
+                None => None,
+            },
+            #[cfg(feature = "metrics")]
+            statsd: match &args.statsd {
+                Some(addr) => {
+                    let statsd = StatsD::new(addr.as_str(), &args.statsd_prefix)
+                        .with_context(|| format!("setting up StatsD at {addr}"))?
+                        .dogstatsd(args.dogstatsd)
+                        .tags(&args.statsd_tag);
+                    Some(Arc::new(statsd))
+                }
+                None => None,
+            },
+            error_rate: error_rate(&args)?.map(Arc::new),
+            args: std::sync::Mutex::new(args),
+        })
+    }
+
+    /// Reads the options and config file again and applies them, all or nothing, logging the
+    /// options that changed. An error is also logged, and returned as its first line.
+    fn reload(&self) -> Result<(), String> {
+        let loaded = load_args().and_then(|args| Ok((build_hosts(&args, &self.shared)?, args)));
+        let (hosts, args) = match loaded {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                // Usage errors go on to print the usage.
+                let e = format!("{e:#}");
+                let e = e.lines().next().unwrap_or_default();
+                warn!("Error reloading configuration, keeping the old one: {e}");
+                return Err(e.to_owned());
+            }
+        };
+        let mut current = self.args.lock().unwrap_or_else(|e| e.into_inner());
+        self.hosts.swap(hosts);
+        self.limits.send_replace(connection_limits(&args));
+        self.ip_rules.send_replace(Arc::new(ip_rules(&args)));
+        memory::set_budget(args.memory_budget);
+        // Settings changed at runtime stick, unless the reload changes their option.
+        let (old, new) = (settings(&current), settings(&args));
+        self.shared
+            .settings
+            .send_modify(|live| *live = live.rebase(&old, &new));
+        info!("Reloaded configuration");
+        self.shared.notices.post(Notice::Reloaded);
+        for (name, values) in &args.options {
+            let old = current.options.get(name).map_or(&[][..], Vec::as_slice);
+            if old == values.as_slice() {
+                continue;
+            }
+            let show = |values: &[String]| match values {
+                [] => "unset".to_owned(),
+                _ if SECRET_OPTIONS.contains(&name.as_str()) => HIDDEN.to_owned(),
+                values => values.join(", "),
+            };
+            let restart = match RELOADABLE.contains(&name.as_str()) {
+                true => "",
+                false => ", which takes a restart",
+            };
+            info!(
+                "Changed --{name} from {} to {}{restart}",
+                show(old),
+                show(values)
+            );
+        }
+        *current = Arc::new(args);
+        Ok(())
+    }
+
+    /// Closes the idle connections to the `--proxy` upstreams.
+    fn close_idle(&self) -> usize {
+        let args = self.args.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        args.proxy.iter().map(|(_, proxy)| proxy.close_idle()).sum()
+    }
+
+    /// How often requests to the `--proxy` upstreams went out on an idle connection.
+    fn connection_reuse(&self) -> CacheStats {
+        let args = self.args.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        args.proxy
+            .iter()
+            .map(|(_, proxy)| proxy.connection_reuse())
+            .fold(CacheStats::default(), |total, reuse| total + reuse)
+    }
+}
+
+fn settings(args: &Args) -> Settings {
+    Settings {
+        max_body_size: args.max_body_size,
+        #[cfg(feature = "compression")]
+        compression: args.compression,
+        #[cfg(not(feature = "compression"))]
+        compression: false,
+        rate_limit: args.rate_limit.map(|rate| RateLimit {
+            rate,
+            burst: args
+                .rate_limit_burst
+                .unwrap_or_else(|| rate.ceil().clamp(1.0, u32::MAX.into()) as u32),
+        }),
+    }
+}
+
+/// The `--ip-allow` and `--ip-deny` rules, which trusted proxies pass at accept time.
+fn ip_rules(args: &Args) -> IpRules {
+    IpRules::new()
+        .allow(args.ip_allow.iter().copied())
+        .deny(args.ip_deny.iter().copied())
+        .trust(args.trusted_proxy.iter().copied())
+}
+
+fn connection_limits(args: &Args) -> ConnectionLimits {
+    ConnectionLimits {
+        head_timeout: Some(Duration::from_secs(args.header_timeout)),
+        idle_timeout: Duration::from_secs(args.keep_alive_timeout),
+        max_requests: args.max_requests_per_connection,
+        write_timeout: Some(Duration::from_secs(args.write_timeout)),
+        min_rate: args.min_rate.map(|bytes_per_sec| MinRate {
+            bytes_per_sec,
+            period: Duration::from_secs(args.min_rate_period),
+        }),
+    }
+}
+
+/// What `/readyz` checks, with the directories and limits as they are after any reload.
+fn health(live: Arc<Live>, drain: watch::Receiver<bool>) -> Health {
+    let args = |live: &Live| live.args.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    let dirs = live.clone();
+    Health::new()
+        .check("directory", move || {
+            let args = args(&dirs);
+            let vhosts = args.vhost.iter().map(|(_, dir)| dir);
+            for dir in std::iter::once(&args.directory).chain(vhosts) {
+                std::fs::read_dir(dir).map_err(|e| format!("{}: {e}", dir.display()))?;
+            }
+            Ok(())
+        })
+        .check("connections", move || {
+            let open = live.stats.snapshot().open;
+            match args(&live).max_connections {
+                Some(max) if open >= max => Err(format!("{open} of {max} connections open")),
+                _ => Ok(()),
+            }
+        })
+        .check("draining", move || match *drain.borrow() {
+            true => Err("draining".to_owned()),
+            false => Ok(()),
+        })
+}
+
+/// Serves as `args` say, calling `ready` once the listeners are open.
+async fn run(args: Arc<Args>, log_filter: LogFilter, ready: impl FnOnce()) -> anyhow::Result<()> {
+    if let Some(file) = &args.replay {
+        return replay(file, &args.replay_against).await;
+    }
+    let live = Arc::new(Live::new(args.clone())?);
+    if !args.self_test.is_empty() {
+        return self_test(&args, &live).await;
+    }
+    let shortener = live.shared.shortener.clone();
+    shortener.spawn(Duration::from_secs(30));
+    live.shared
+        .upload_expiry
+        .spawn(Duration::from_secs(args.upload_sweep_interval));
+    let reloaded = live.clone();
+    tokio::spawn(reload::watch(
+        args.config.clone(),
+        Duration::from_secs(2),
+        move || {
+            let _ = reloaded.reload();
+        },
+    ));
+
+    #[cfg(unix)]
+    let mut listeners = listener::from_systemd().context("taking over systemd sockets")?;
+    #[cfg(not(unix))]
+    let mut listeners = Vec::<Listener>::new();
+    if !listeners.is_empty() {
+        info!("Listening on {} sockets from systemd", listeners.len());
+    }
+    let mut inherited = Inherited::take().context("taking over the previous process's sockets")?;
+    #[cfg(feature = "tls")]
+    let default_certificate = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(watched_certificate(cert, key)?),
+        _ => None,
+    };
+    #[cfg(feature = "tls")]
+    let mut tls_config = match (default_certificate, args.tls_host.is_empty()) {
+        (Some(certificate), true) => Some(TlsConfig::from_resolver(certificate)),
+        (default, false) => {
+            let mut certificates = SniCertificates::new(default);
+            for (host, cert, key) in &args.tls_host {
+                certificates = certificates.host(host, watched_certificate(cert, key)?);
+            }
+            Some(TlsConfig::from_resolver(Arc::new(certificates)))
+        }
+        (None, true) => None,
+    };
+    #[cfg(feature = "acme")]
+    let acme = (!args.acme_domain.is_empty()).then(|| {
+        let mut acme =
+            Acme::new(args.acme_domain.clone(), &args.acme_cache).challenge(args.acme_challenge);
+        if let Some(email) = &args.acme_email {
+            acme = acme.contact(email);
+        }
+        if let Some(url) = &args.acme_directory {
+            acme = acme.directory(url);
+        }
+        tls_config = Some(acme.tls_config());
+        tokio::spawn(acme.clone().run());
+        acme
+    });
+    #[cfg(feature = "tls")]
+    if let Some(bundle) = &args.tls_client_ca {
+        tls_config = Some(
+            tls_config
+                .context("--tls-client-ca needs TLS to be set up")?
+                .client_ca(bundle),
+        );
+    }
+    #[cfg(feature = "tls")]
+    let tls_policy = TlsPolicy::new()
+        .min_version(args.tls_min_version)
+        .cipher_suites(args.tls_cipher_suites.iter().cloned())
+        .kx_groups(args.tls_kx_groups.iter().cloned())
+        .session_tickets(args.tls_session_tickets)
+        .resumption(!args.tls_no_resumption);
+    // Checked even without TLS, so a bad policy doesn't wait for a certificate to show up.
+    #[cfg(feature = "tls")]
+    tls_policy.validate()?;
+    #[cfg(feature = "tls")]
+    let tls = tls_config
+        .map(|config| config.policy(tls_policy).acceptor())
+        .transpose()?;
+    #[cfg(not(feature = "tls"))]
+    let tls = None;
+    listeners = listeners.into_iter().map(|l| secured(l, &tls)).collect();
+    #[cfg(unix)]
+    for path in &args.listen_unix {
+        let listener = match inherited.unix(path) {
+            Some(listener) => listener,
+            None => {
+                let mut bind = UnixBind::new(path);
+                if let Some(mode) = args.unix_socket_mode {
+                    bind = bind.mode(mode);
+                }
+                bind.listen()
+                    .with_context(|| format!("listening on {}", path.display()))?
+            }
+        };
+        info!("Listening on {}", path.display());
+        listeners.push(listener.into());
+    }
+    if args.https_redirect && tls.is_none() {
+        bail!("--https-redirect needs TLS to be set up");
+    }
+    let mut plain = Vec::new();
+    for addr in &args.listen_http {
+        let listeners = listen_tcp(*addr, &args, &mut inherited)?;
+        info!("Listening on {} (plain HTTP)", listeners[0].local_addr()?);
+        plain.extend(listeners.into_iter().map(Listener::from));
+    }
+    let mut addrs = args.listen.clone();
+    if addrs.is_empty() && listeners.is_empty() && plain.is_empty() && inherited.is_empty() {
+        addrs.push(SocketAddr::from(([127, 0, 0, 1], 4221)));
+    }
+    let mut https_port = None;
+    for addr in addrs {
+        let bound = listen_tcp(addr, &args, &mut inherited)?;
+        let local_addr = bound[0].local_addr()?;
+        info!("Listening on {local_addr}");
+        if tls.is_some() {
+            https_port.get_or_insert(local_addr.port());
+        }
+        for listener in bound.into_iter().map(Listener::from) {
+            listeners.push(secured(listener, &tls));
+        }
+    }
+    let mut admin = Vec::new();
+    if let Some(addr) = args.admin_listen {
+        let listener = match inherited.tcp(addr) {
+            Some(listener) => listener,
+            None => Bind::new(addr)
+                .listen()
+                .with_context(|| format!("listening on {addr}"))?,
+        };
+        info!("Admin endpoints on {}", listener.local_addr()?);
+        admin.push(Listener::from(listener));
+    }
+    #[cfg(unix)]
+    if let Some(path) = &args.admin_unix {
+        let listener = match inherited.unix(path) {
+            Some(listener) => listener,
+            None => UnixBind::new(path)
+                .mode(0o600)
+                .listen()
+                .with_context(|| format!("listening on {}", path.display()))?,
+        };
+        info!("Admin endpoints on {}", path.display());
+        admin.push(listener.into());
+    }
+    let inherited = inherited.into_listeners();
+    if !inherited.is_empty() {
+        info!(
+            "Listening on {} sockets from the previous process",
+            inherited.len()
+        );
+    }
+    listeners.extend(
+        inherited
+            .into_iter()
+            .map(|listener| secured(listener, &tls)),
+    );
+
+    #[cfg(unix)]
+    if args.user.is_some() || args.group.is_some() {
+        let run_as = RunAs {
+            user: args.user.clone(),
+            group: args.group.clone(),
+        };
+        run_as.apply().context("dropping privileges")?;
+        match (&args.user, &args.group) {
+            (Some(user), Some(group)) => info!("Running as {user}:{group}"),
+            (Some(user), None) => info!("Running as {user}"),
+            (None, group) => info!("Running as group {}", group.as_deref().unwrap_or("")),
+        }
+    }
+    ready();
+
+    let (stop_tx, stop) = watch::channel(false);
+    // Stopping drains the site's listeners; draining by itself leaves the admin ones up.
+    let (drain_tx, drain) = watch::channel(false);
+    #[cfg(unix)]
+    {
+        let fds = listeners.iter().chain(&plain).chain(&admin);
+        let fds = fds.map(AsRawFd::as_raw_fd).collect();
+        tokio::spawn(hand_off_on_sigusr2(fds, stop_tx.clone()));
+        tokio::spawn(reopen_logs_on_sigusr1());
+        tokio::spawn(toggle_debug_on_sigttin(log_filter.clone()));
+    }
+    let admin = (!admin.is_empty()).then(|| {
+        let (reloaded, flushed, cached) = (live.clone(), live.clone(), live.clone());
+        let shortened = shortener.clone();
+        let process = Arc::new(ProcessStats::new(live.buffers.clone(), live.stats.clone()));
+        process.spawn(Duration::from_secs(args.process_sample_interval));
+        #[cfg_attr(
+            not(any(feature = "profiling", feature = "metrics")),
+            allow(unused_mut)
+        )]
+        let mut endpoints = Admin::new(live.stats.clone(), stop_tx.clone(), drain_tx.clone())
+            .reload(move || reloaded.reload())
+            .flush(move || serde_json::json!({ "upstream_connections": flushed.close_idle() }))
+            .settings(live.shared.settings.clone())
+            .log_filter(move |filter| log_filter.admin(filter))
+            .process(process)
+            .report("short_links", move || shortened.stats())
+            .caches(move || {
+                vec![
+                    ("buffers", cached.buffers.reuse()),
+                    ("upstream_connections", cached.connection_reuse()),
+                ]
+            });
+        #[cfg(feature = "metrics")]
+        {
+            endpoints = endpoints.metrics(live.metrics.clone());
+        }
+        #[cfg(feature = "profiling")]
+        if args.admin_profiling {
+            endpoints = endpoints.profiling();
+        }
+        let router = endpoints.router();
+        tokio::spawn(serve_admin(router, args.clone(), admin, stop.clone()))
+    });
+    {
+        let mut stop = stop.clone();
+        tokio::spawn(async move {
+            let _ = stop.wait_for(|stop| *stop).await;
+            drain_tx.send_replace(true);
+        });
+        let (mut drain, notices) = (drain.clone(), live.shared.notices.clone());
+        tokio::spawn(async move {
+            if drain.wait_for(|drain| *drain).await.is_ok() {
+                notices.post(Notice::Draining);
+            }
+        });
+    }
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        stop_tx.send_replace(true);
+    });
+    let mut site = Router::new().mount("/", live.hosts.clone());
+    #[cfg(feature = "metrics")]
+    if let Some(path) = &args.metrics_path {
+        let metrics = live.metrics.clone();
+        site = site.get(path, move || std::future::ready(metrics.response()));
+    }
+    if !args.no_health_checks {
+        site = health(live.clone(), drain.clone()).routes(site);
+    }
+    let redirect = Router::new().mount("/", HttpsRedirect::new(https_port));
+    #[cfg(feature = "acme")]
+    let (site, redirect) = match &acme {
+        Some(acme) => (
+            site.layer(acme.http01_responder()),
+            redirect.layer(acme.http01_responder()),
+        ),
+        None => (site, redirect),
+    };
+    let served = serve_site(site, redirect, args, live, listeners, plain, drain).await;
+    // The hits since it last saved.
+    if let Err(e) = shortener.save().await {
+        warn!("Couldn't save short links: {e:#}");
+    }
+    match admin {
+        Some(admin) => {
+            served?;
+            admin.await.context("serving the admin endpoints")?
+        }
+        None => served,
+    }
+}
+
+/// Replays the requests recorded in `file` against `server`.
+async fn replay(file: &Path, server: &Upstream) -> anyhow::Result<()> {
+    let exchanges = replay::load(file)?;
+    let report = replay::replay(server, &exchanges).await;
+    print!("{report}");
+    let failed = report.failed();
+    anyhow::ensure!(
+        failed == 0,
+        "{failed} replayed requests weren't answered as recorded"
+    );
+    Ok(())
+}
+
+/// Load tests the site, served on a loopback port for the duration.
+async fn self_test(args: &Args, live: &Live) -> anyhow::Result<()> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+    let listener = Bind::new(addr)
+        .listen()
+        .context("listening for the self-test")?;
+    let load = LoadTest::new(listener.local_addr()?, args.self_test.clone())
+        .requests(args.self_test_requests)
+        .concurrency(args.self_test_concurrency.get());
+    let site = Arc::new(Router::new().mount("/", live.hosts.clone()));
+    let (stop_tx, stop) = watch::channel(false);
+    let (served, report) = tokio::join!(
+        serve(site, args, live, vec![listener.into()], stop),
+        async {
+            let report = load.run().await;
+            stop_tx.send_replace(true);
+            report
+        }
+    );
+    served?;
+    let report = report?;
+    print!("\n{report}");
+    let failed = report.errors();
+    anyhow::ensure!(failed == 0, "{failed} self-test requests got no response");
+    Ok(())
+}
+
+/// `listener`, serving HTTPS when there's an acceptor.
+#[cfg(feature = "tls")]
+fn secured(listener: Listener, tls: &Option<TlsAcceptor>) -> Listener {
+    match tls {
+        Some(tls) => listener.with_tls(tls),
+        None => listener,
+    }
+}
+
+/// `listener` as it is, there being no TLS in this build.
+#[cfg(not(feature = "tls"))]
+fn secured(listener: Listener, tls: &Option<std::convert::Infallible>) -> Listener {
+    match *tls {
+        Some(never) => match never {},
+        None => listener,
+    }
+}
+
+/// Serves the site on `listeners`, and on `plain` too unless those redirect to HTTPS, until
+/// `stop` turns true.
+async fn serve_site(
+    site: Router,
+    redirect: Router,
+    args: Arc<Args>,
+    live: Arc<Live>,
+    mut listeners: Vec<Listener>,
+    plain: Vec<Listener>,
+    stop: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    #[cfg(feature = "thread-per-core")]
+    if args.thread_per_core {
+        return serve_per_core(site, redirect, args, live, listeners, plain, stop).await;
+    }
+    if !args.https_redirect {
+        listeners.extend(plain);
+        return serve(Arc::new(site), &args, &live, listeners, stop).await;
+    }
+    let (site, redirect) = tokio::join!(
+        serve(Arc::new(site), &args, &live, listeners, stop.clone()),
+        serve(Arc::new(redirect), &args, &live, plain, stop),
+    );
+    site.and(redirect)
+}
+
+/// Serves the admin endpoints until `stop` turns true, leaving out the limits and counts the
+/// site's connections get.
+async fn serve_admin(
+    router: Router,
+    args: Arc<Args>,
+    listeners: Vec<Listener>,
+    mut stop: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    Server::new(Arc::new(router))
+        .graceful_shutdown(
+            async move {
+                let _ = stop.wait_for(|stop| *stop).await;
+            },
+            Duration::from_secs(args.drain_timeout),
+        )
+        .serve_all(listeners)
+        .await
+}
+
+/// Loads a certificate and keeps it up to date with its files.
+#[cfg(feature = "tls")]
+fn watched_certificate(cert: &Path, key: &Path) -> anyhow::Result<Arc<dyn ResolvesServerCert>> {
+    let certificate = Arc::new(CertificateFiles::load(cert, key)?);
+    let paths = certificate.paths().map(PathBuf::from);
+    let watched = certificate.clone();
+    tokio::spawn(reload::watch(
+        paths,
+        Duration::from_secs(2),
+        move || match watched.reload() {
+            Ok(()) => info!("Reloaded TLS certificate {}", watched.paths()[0].display()),
+            Err(e) => warn!("Error reloading TLS certificate, keeping the old one: {e:#}"),
+        },
+    ));
+    Ok(certificate)
+}
+
+/// Opens the `--acceptors` TCP listeners on `addr`, taking over any the previous process had
+/// on it first.
+fn listen_tcp(
+    addr: SocketAddr,
+    args: &Args,
+    inherited: &mut Inherited,
+) -> anyhow::Result<Vec<TcpListener>> {
+    let mut listeners: Vec<TcpListener> = Vec::new();
+    let acceptors = acceptors(args);
+    while listeners.len() < acceptors {
+        // The rest share the first one's port, which is only known once it's bound to port 0.
+        let addr = match listeners.first() {
+            Some(first) => first.local_addr()?,
+            None => addr,
+        };
+        let listener = match inherited.tcp(addr) {
+            Some(listener) => listener,
+            None => {
+                let mut bind = Bind::new(addr)
+                    .v6_only(args.ipv6_only)
+                    .reuse_port(acceptors > 1)
+                    .backlog(args.backlog);
+                if let Some(bytes) = args.send_buffer_size {
+                    bind = bind.send_buffer_size(bytes);
+                }
+                if let Some(bytes) = args.recv_buffer_size {
+                    bind = bind.recv_buffer_size(bytes);
+                }
+                bind.listen()
+                    .with_context(|| format!("listening on {addr}"))?
+            }
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// How many sockets to open on every TCP address: one per core in thread-per-core mode.
+fn acceptors(args: &Args) -> usize {
+    #[cfg(feature = "thread-per-core")]
+    if args.thread_per_core {
+        return cores(args);
+    }
+    args.acceptors.get()
+}
+
+#[cfg(feature = "thread-per-core")]
+fn cores(args: &Args) -> usize {
+    args.workers
+        .or_else(|| std::thread::available_parallelism().ok())
+        .map_or(1, NonZeroUsize::get)
+}
+
+/// Serves the site from a runtime on each core. Plain HTTP listeners that only redirect stay
+/// on this one.
+#[cfg(feature = "thread-per-core")]
+async fn serve_per_core(
+    site: Router,
+    redirect: Router,
+    args: Arc<Args>,
+    live: Arc<Live>,
+    mut listeners: Vec<Listener>,
+    plain: Vec<Listener>,
+    stop: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let plain = match args.https_redirect {
+        true => plain,
+        false => {
+            listeners.extend(plain);
+            Vec::new()
+        }
+    };
+    let mut per_core = PerCore::new(cores(&args));
+    if let Some(max) = args.max_blocking_threads {
+        per_core = per_core.max_blocking_threads(max.get());
+    }
+    let site = Arc::new(site);
+    let (core_args, core_live, core_stop) = (args.clone(), live.clone(), stop.clone());
+    let cores = tokio::task::spawn_blocking(move || {
+        per_core.serve(listeners, move |listeners| {
+            let (site, args) = (site.clone(), core_args.clone());
+            let (live, stop) = (core_live.clone(), core_stop.clone());
+            async move { serve(site, &args, &live, listeners, stop).await }
+        })
+    });
+    let cores = async { cores.await.context("serving on the cores")? };
+    if plain.is_empty() {
+        return cores.await;
+    }
+    let (site, redirect) =
+        tokio::join!(cores, serve(Arc::new(redirect), &args, &live, plain, stop));
+    site.and(redirect)
+}
+
+/// Serves until `stop` turns true, then drains.
+async fn serve(
+    handler: impl Handler,
+    args: &Args,
+    live: &Live,
+    listeners: Vec<Listener>,
+    mut stop: watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let mut server = Server::new(handler);
+    if let Some(limit) = args.max_connections {
+        let when_full = match args.reject_when_full {
+            true => WhenFull::Reject,
+            false => WhenFull::Wait,
+        };
+        server = server.max_connections(limit, when_full);
+    }
+    if let Some(limit) = args.max_connections_per_ip {
+        server = server.max_connections_per_ip(limit);
+    }
+    if let Some(idle) = args.tcp_keepalive {
+        let interval = Duration::from_secs(args.tcp_keepalive_interval);
+        server = server.tcp_keepalive(Duration::from_secs(idle), interval);
+    }
+    if let Some(log) = &live.access_log {
+        server = server.access_log(log.clone());
+    }
+    if !args.no_request_ids {
+        server = server.request_ids(args.trusted_proxy.iter().copied());
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(statsd) = &live.statsd {
+        server = server.statsd(statsd.clone());
+    }
+    if let Some(error_rate) = &live.error_rate {
+        server = server.error_rate(error_rate.clone());
+    }
+    if let Some(millis) = args.slow_request_threshold {
+        server = server.slow_requests(Duration::from_millis(millis));
+    }
+    if args.trace_wire {
+        let trace = WireTrace::new(args.trace_wire_limit).redact(args.trace_wire_redact);
+        server = server.trace_wire(trace);
+    }
+    // With a tarpit, denied clients are let in for the IP filter to tarpit them.
+    if args.tarpit.is_none() {
+        server = server.ip_rules(live.ip_rules.subscribe());
+    }
+    #[cfg(feature = "metrics")]
+    {
+        server = server.metrics(live.metrics.clone());
+    }
+    server
+        .connection_limits(live.limits.subscribe())
+        .stats(live.stats.clone())
+        .buffers(live.buffers.clone())
+        .tcp_nodelay(args.tcp_nodelay)
+        .error_handler(|e| {
+            let response = e.to_response();
+            // Overload and timeouts are usually transient, so hint clients to come back soon.
+            if e.status == StatusCode::SERVICE_UNAVAILABLE && response.header(RETRY_AFTER).is_none()
+            {
+                return response.with_header(RETRY_AFTER, "1");
+            }
+            response
+        })
+        .proxy_protocol(args.proxy_protocol)
+        .graceful_shutdown(
+            async move {
+                let _ = stop.wait_for(|stop| *stop).await;
+            },
+            Duration::from_secs(args.drain_timeout),
+        )
+        .serve_all(listeners)
+        .await
+}
+
+/// Opens the log files again on SIGUSR1, after `logrotate` or the like has moved them.
+#[cfg(unix)]
+async fn reopen_logs_on_sigusr1() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut reopen = signal(SignalKind::user_defined1()).expect("installing SIGUSR1 handler");
+    while reopen.recv().await.is_some() {
+        log_file::reopen();
+        info!("Reopened the log files");
+    }
+}
+
+/// Switches the log to `debug` on SIGTTIN, and back on the next one.
+#[cfg(unix)]
+async fn toggle_debug_on_sigttin(log_filter: LogFilter) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut toggle =
+        signal(SignalKind::from_raw(libc::SIGTTIN)).expect("installing SIGTTIN handler");
+    while toggle.recv().await.is_some() {
+        match log_filter.toggle_debug() {
+            Ok(filter) => info!("Changed the log filter to {filter}"),
+            Err(e) => warn!("Error changing the log filter: {e}"),
+        }
+    }
+}
+
+/// Starts a new server process on SIGUSR2, passing it the listeners, and stops this one once
+/// the new one is up. If it exits right away, say over a broken config, this one carries on.
+#[cfg(unix)]
+async fn hand_off_on_sigusr2(fds: Vec<RawFd>, stop: watch::Sender<bool>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut upgrade = signal(SignalKind::user_defined2()).expect("installing SIGUSR2 handler");
+    while upgrade.recv().await.is_some() {
+        let mut child = match listener::hand_off(&fds) {
+            Ok(child) => child,
+            Err(e) => {
+                warn!("Error starting the new server process: {e}");
+                continue;
+            }
+        };
+        info!("Started new server process {}", child.id());
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        match child.try_wait() {
+            Ok(None) => {
+                info!("Handed the listeners over to process {}", child.id());
+                stop.send_replace(true);
+                return;
+            }
+            Ok(Some(status)) => warn!("New server process exited with {status}, carrying on"),
+            Err(e) => warn!("Error checking on the new server process, carrying on: {e}"),
+        }
+    }
+}
+
+/// Resolves on Ctrl-C or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("installing SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use http_server_starter_rust::{
+        body::Body,
+        headers::HeaderMap,
+        request::{Extensions, Method, Request, Scheme},
+    };
+
+    use super::*;
+
+    async fn status(site: &Arc<Router>, method: Method, path: &str) -> StatusCode {
+        let req = Request {
+            method,
+            target: path.to_owned(),
+            path: path.to_owned(),
+            query: String::new(),
+            version: "HTTP/1.1".to_owned(),
+            scheme: Scheme::Http,
+            remote_addr: Some(([127, 0, 0, 1], 40000).into()),
+            headers: HeaderMap::new(),
+            params: Vec::new(),
+            extensions: Extensions::default(),
+            body: Body::empty(),
+        };
+        match site.call(req).await {
+            Ok(response) => response.status,
+            Err(e) => e.status,
+        }
+    }
+
+    #[tokio::test]
+    async fn basic_auth_sees_rewritten_path() {
+        let dir = std::env::temp_dir().join(format!("http-server-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("secret.txt"), "secret").unwrap();
+        let htpasswd = dir.join("htpasswd");
+        std::fs::write(&htpasswd, "").unwrap();
+
+        let basic_auth = format!("/files/={}", htpasswd.display());
+        let args = Args::try_parse_from([
+            "http-server",
+            "--directory",
+            &dir.display().to_string(),
+            "--basic-auth",
+            &basic_auth,
+            "--rewrite",
+            "^/dl/(.+)$ /files/$1",
+        ])
+        .unwrap();
+        let args = Arc::new(args);
+        let live = Live::new(args.clone()).unwrap();
+        let site = site_router(dir.clone(), &args, &RouteConfig::default(), &live.shared).unwrap();
+
+        for method in [Method::Get, Method::Delete] {
+            assert_eq!(
+                status(&site, method.clone(), "/files/secret.txt").await,
+                StatusCode::UNAUTHORIZED
+            );
+            assert_eq!(
+                status(&site, method, "/dl/secret.txt").await,
+                StatusCode::UNAUTHORIZED
+            );
+        }
+        assert!(dir.join("secret.txt").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_credentials_for_any_origin() {
+        let args = ["http-server", "--cors-origin", "*", "--cors-credentials"];
+        let args = Arc::new(Args::try_parse_from(args).unwrap());
+        let e = Live::new(args).err().unwrap();
+        assert!(e.to_string().contains("--cors-credentials"), "{e}");
+    }
+}
@@ -0,0 +1,99 @@
+//! Gzipping responses for clients that accept it.
+
+use std::io::{self, Write};
+
+use anyhow::Context;
+use flate2::{write::GzEncoder, Compression as Level};
+use tokio::sync::watch;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::{AcceptEncoding, CONTENT_ENCODING, CONTENT_TYPE, VARY},
+    memory::Reservation,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::{Response, ResponseBody},
+    settings::Settings,
+    status::StatusCode,
+};
+
+/// Gzips text-like responses (`text/*`, JSON, JavaScript, XML and SVG) while
+/// [`Settings::compression`] is on and the request's `Accept-Encoding` allows it. Only bodies
+/// already in memory are compressed; files and streams go out as they are, and so do bodies
+/// the [memory budget](crate::memory) has no room left to gzip.
+pub struct Compression {
+    settings: watch::Receiver<Settings>,
+}
+
+impl Compression {
+    pub fn new(settings: watch::Receiver<Settings>) -> Self {
+        Self { settings }
+    }
+}
+
+impl Middleware for Compression {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let accepted = self.settings.borrow().compression
+            && req
+                .headers
+                .typed_get::<AcceptEncoding>()
+                .ok()
+                .flatten()
+                .is_some_and(|accept| accept.accepts("gzip"));
+        Box::pin(async move {
+            let mut response = next.run(req).await?;
+            if !accepted || !compressible(&response) {
+                return Ok(response);
+            }
+            let ResponseBody::Bytes(body) = &response.body else {
+                return Ok(response);
+            };
+            let Some(reservation) = Reservation::new(body.len() as u64) else {
+                return Ok(response);
+            };
+            let gzipped = gzip(body).context("gzipping the response")?;
+            response.body = ResponseBody::Bytes(reservation.hold(gzipped));
+            response.set_header(CONTENT_ENCODING, "gzip");
+            let vary = response.header(VARY).map(str::to_owned);
+            match vary {
+                Some(vary) => response.set_header(VARY, &format!("{vary}, Accept-Encoding")),
+                None => response.set_header(VARY, "Accept-Encoding"),
+            }
+            Ok(response)
+        })
+    }
+}
+
+fn gzip(data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = GzEncoder::new(Vec::new(), Level::default());
+    encoder.write_all(data)?;
+    encoder.finish()
+}
+
+fn compressible(response: &Response) -> bool {
+    if response.header(CONTENT_ENCODING).is_some()
+        || matches!(
+            response.status,
+            StatusCode::NO_CONTENT | StatusCode::PARTIAL_CONTENT | StatusCode::NOT_MODIFIED
+        )
+    {
+        return false;
+    }
+    let Some(content_type) = response.header(CONTENT_TYPE) else {
+        return false;
+    };
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            &*mime,
+            "application/json" | "application/javascript" | "application/xml" | "image/svg+xml"
+        )
+}
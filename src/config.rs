@@ -0,0 +1,377 @@
+//! The configuration file: extra routes, mounts, redirects, rewrites and vhosts that can
+//! change without recompiling (or restarting, see [`reload`](crate::reload)), the
+//! [authorization rules](crate::authz), and a `[server]` table with the binary's command-line
+//! options.
+
+use std::{collections::BTreeMap, fmt, path::Path, path::PathBuf};
+
+use anyhow::Context;
+use bytes::Bytes;
+use serde::Deserialize;
+
+use crate::{
+    authz::{Authorization, Rule, MECHANISMS},
+    headers::{HeaderMap, CONTENT_TYPE},
+    redirect::RedirectTable,
+    request::Method,
+    response::Response,
+    rewrite::{Rewrite, RewriteRule},
+    router::Router,
+    secret::{HIDDEN, SECRET_OPTIONS},
+    served_dir::Backoff,
+    static_files::StaticDir,
+    status::StatusCode,
+    upload_scan::UploadScan,
+};
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteConfig {
+    /// Rules in the `--rewrite` syntax, tried in order.
+    pub rewrites: Vec<String>,
+    pub redirects: Vec<RedirectConfig>,
+    pub mounts: Vec<MountConfig>,
+    pub routes: Vec<StaticRouteConfig>,
+    pub vhosts: Vec<VhostConfig>,
+    /// Tried in order, the first that matches a request deciding it.
+    pub authorization: Vec<AuthorizationConfig>,
+    /// The identities given each role, like `admin = ["alice"]`.
+    pub roles: BTreeMap<String, Vec<String>>,
+    /// Command-line options by their long name, like `listen = ["0.0.0.0:80"]` or
+    /// `tcp-nodelay = true`. They're read once at startup; the router ignores them.
+    pub server: toml::Table,
+}
+
+/// With the values of the [secret options](SECRET_OPTIONS) in `[server]` hidden.
+impl fmt::Debug for RouteConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut server = self.server.clone();
+        for (name, value) in server.iter_mut() {
+            if SECRET_OPTIONS.contains(&name.replace('_', "-").as_str()) {
+                *value = toml::Value::String(HIDDEN.to_owned());
+            }
+        }
+        f.debug_struct("RouteConfig")
+            .field("rewrites", &self.rewrites)
+            .field("redirects", &self.redirects)
+            .field("mounts", &self.mounts)
+            .field("routes", &self.routes)
+            .field("vhosts", &self.vhosts)
+            .field("authorization", &self.authorization)
+            .field("roles", &self.roles)
+            .field("server", &server)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedirectConfig {
+    pub path: String,
+    pub target: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    StatusCode::MOVED_PERMANENTLY.0
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountConfig {
+    pub prefix: String,
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub autoindex: bool,
+    #[serde(default = "default_read_only")]
+    pub read_only: bool,
+    /// See [`StaticDir::confine`].
+    #[serde(default)]
+    pub confine: bool,
+    pub cache_control: Option<String>,
+    /// Not read from the file: `--fs-backoff` applies to every mount.
+    #[serde(skip)]
+    pub backoff: Option<Backoff>,
+    /// Not read from the file either: `--upload-scan-command` does.
+    #[serde(skip)]
+    pub upload_scan: Option<UploadScan>,
+}
+
+fn default_read_only() -> bool {
+    true
+}
+
+/// A route that always answers with the same response.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StaticRouteConfig {
+    #[serde(default = "default_method")]
+    pub method: String,
+    pub path: String,
+    #[serde(default = "default_status")]
+    pub status: u16,
+    #[serde(default)]
+    pub body: String,
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+}
+
+fn default_method() -> String {
+    Method::Get.as_str().to_owned()
+}
+
+fn default_status() -> u16 {
+    StatusCode::OK.0
+}
+
+/// See [`Rule`]: empty lists put no restriction on a request.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthorizationConfig {
+    pub pattern: String,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub anonymous: bool,
+    #[serde(default)]
+    pub mechanisms: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VhostConfig {
+    pub host: String,
+    pub directory: PathBuf,
+}
+
+impl RouteConfig {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("reading config file {}", path.display()))?;
+        let config: Self = toml::from_str(&contents)
+            .with_context(|| format!("parsing config file {}", path.display()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Catches mistakes that would otherwise only show up (or panic) while building a router.
+    fn validate(&self) -> anyhow::Result<()> {
+        self.rewrite_rules()?;
+        for redirect in &self.redirects {
+            anyhow::ensure!(
+                StatusCode(redirect.status).is_redirection(),
+                "{} is not a redirect status",
+                redirect.status
+            );
+        }
+        for mount in &self.mounts {
+            anyhow::ensure!(
+                mount.prefix.starts_with('/'),
+                "mount prefix {} must start with '/'",
+                mount.prefix
+            );
+        }
+        for route in &self.routes {
+            anyhow::ensure!(
+                route.path.starts_with('/'),
+                "route path {} must start with '/'",
+                route.path
+            );
+            let segments = route.path.split('/').collect::<Vec<_>>();
+            anyhow::ensure!(
+                segments[..segments.len() - 1]
+                    .iter()
+                    .all(|s| !s.starts_with("{*")),
+                "catch-all segment must be the last one in {}",
+                route.path
+            );
+        }
+        for rule in &self.authorization {
+            anyhow::ensure!(
+                rule.pattern.starts_with('/'),
+                "authorization pattern {} must start with '/'",
+                rule.pattern
+            );
+            if let Some(unknown) = rule
+                .mechanisms
+                .iter()
+                .find(|m| !MECHANISMS.contains(&m.as_str()))
+            {
+                anyhow::bail!(
+                    "unknown mechanism {unknown} for {}, expected one of {}",
+                    rule.pattern,
+                    MECHANISMS.join(", ")
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn authorization(&self) -> Authorization {
+        let rules = self.authorization.iter().map(|config| {
+            let rule = Rule::new(&config.pattern).anonymous(config.anonymous);
+            let rule = config.methods.iter().fold(rule, |rule, method| {
+                rule.method(Method::from(method.to_ascii_uppercase().as_str()))
+            });
+            let rule = config
+                .mechanisms
+                .iter()
+                .fold(rule, |rule, m| rule.mechanism(m));
+            let rule = config.scopes.iter().fold(rule, |rule, s| rule.scope(s));
+            config.roles.iter().fold(rule, |rule, r| rule.role(r))
+        });
+        let authorization = rules.fold(Authorization::new(), Authorization::rule);
+        self.roles
+            .iter()
+            .flat_map(|(role, identities)| identities.iter().map(move |id| (role, id)))
+            .fold(authorization, |authorization, (role, identity)| {
+                authorization.member(role, identity)
+            })
+    }
+
+    fn rewrite_rules(&self) -> anyhow::Result<Vec<RewriteRule>> {
+        self.rewrites
+            .iter()
+            .map(|rule| {
+                rule.parse()
+                    .with_context(|| format!("parsing rewrite rule {rule}"))
+            })
+            .collect()
+    }
+
+    /// Adds everything but the vhosts, the redirects and the rewrites to `router`.
+    pub fn apply(&self, mut router: Router) -> anyhow::Result<Router> {
+        for route in &self.routes {
+            let method = Method::from(route.method.to_ascii_uppercase().as_str());
+            anyhow::ensure!(
+                !router.has_route(&method, &route.path),
+                "{method} {} is already registered",
+                route.path
+            );
+            router = router.route(method, &route.path, static_response(route));
+        }
+        for mount in &self.mounts {
+            let mut dir = StaticDir::new(&mount.directory)
+                .autoindex(mount.autoindex)
+                .read_only(mount.read_only)
+                .confine(mount.confine);
+            if let Some(cache_control) = &mount.cache_control {
+                dir = dir.cache_control(cache_control);
+            }
+            if let Some(backoff) = mount.backoff {
+                dir = dir.backoff(backoff);
+            }
+            if let Some(scan) = &mount.upload_scan {
+                dir = dir.upload_scan(scan.clone());
+            }
+            router = router.mount(&mount.prefix, dir);
+        }
+        if !self.authorization.is_empty() {
+            router = router.layer(self.authorization());
+        }
+        Ok(router)
+    }
+
+    /// Layers the redirects and rewrites on `router`. They have to come before any layer that
+    /// looks at the path, like the [authorization rules](Self::apply), so that those see the
+    /// path the request is routed by: otherwise a rewrite into a protected prefix would get
+    /// around them.
+    pub fn rewrite(&self, mut router: Router) -> anyhow::Result<Router> {
+        if !self.redirects.is_empty() {
+            let table = self
+                .redirects
+                .iter()
+                .fold(RedirectTable::new(), |table, r| {
+                    table.add(&r.path, &r.target, StatusCode(r.status))
+                });
+            router = router.layer(table);
+        }
+        if !self.rewrites.is_empty() {
+            router = router.layer(Rewrite::new(self.rewrite_rules()?));
+        }
+        Ok(router)
+    }
+}
+
+fn static_response(
+    route: &StaticRouteConfig,
+) -> impl Fn() -> std::future::Ready<Response> + Send + Sync {
+    let status = StatusCode(route.status);
+    let body = Bytes::from(route.body.clone());
+    let mut headers = HeaderMap::new();
+    if let Some(content_type) = &route.content_type {
+        headers.insert(CONTENT_TYPE, content_type);
+    } else if !body.is_empty() {
+        headers.insert(CONTENT_TYPE, "text/plain");
+    }
+    for (name, value) in &route.headers {
+        headers.append(name, value);
+    }
+
+    move || {
+        let mut response = Response::builder().status(status).body(body.clone());
+        response.headers = headers.clone();
+        std::future::ready(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{handler::Handler, request::Request};
+
+    fn site(config: &str) -> Arc<Router> {
+        let config: RouteConfig = toml::from_str(config).unwrap();
+        config.validate().unwrap();
+        let router = config.rewrite(Router::new()).unwrap();
+        Arc::new(config.apply(router).unwrap())
+    }
+
+    async fn status(site: &Arc<Router>, method: Method, target: &str) -> StatusCode {
+        match site.call(Request::new(method, target)).await {
+            Ok(response) => response.status,
+            Err(e) => e.status,
+        }
+    }
+
+    const PROTECTED: &str = r#"
+        rewrites = ["^/dl/(.+)$ /files/$1"]
+
+        [[routes]]
+        path = "/files/secret.txt"
+        body = "secret"
+
+        [[routes]]
+        path = "/public"
+
+        [[authorization]]
+        pattern = "/files/**"
+        roles = ["admin"]
+
+        [[authorization]]
+        pattern = "/**"
+        anonymous = true
+    "#;
+
+    #[tokio::test]
+    async fn authorization_sees_rewritten_path() {
+        let site = site(PROTECTED);
+        assert_eq!(
+            status(&site, Method::Get, "/files/secret.txt").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(&site, Method::Get, "/dl/secret.txt").await,
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(status(&site, Method::Get, "/public").await, StatusCode::OK);
+    }
+}
@@ -0,0 +1,62 @@
+//! Rejecting request bodies of the wrong media type before they reach the handler.
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::ContentType,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+};
+
+/// Answers 415 to requests whose body isn't one of the accepted media types, so handlers can
+/// assume they're parsing what they expect. Patterns are `type/subtype` or `type/*`.
+///
+/// Requests without a body pass through, so the layer can wrap a route that also serves GET.
+#[derive(Debug, Clone)]
+pub struct RequireContentType {
+    accepted: Vec<String>,
+}
+
+impl RequireContentType {
+    pub fn new<I, S>(accepted: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        Self {
+            accepted: accepted
+                .into_iter()
+                .map(|mime| mime.as_ref().to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    fn accepts(&self, mime: &str) -> bool {
+        self.accepted
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(kind) => mime.split('/').next() == Some(kind),
+                None => pattern == mime,
+            })
+    }
+}
+
+impl Middleware for RequireContentType {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let content_type = match req.headers.typed_get::<ContentType>() {
+            Ok(content_type) => content_type,
+            Err(e) => return Box::pin(async move { Err(e.into()) }),
+        };
+        let result = match content_type {
+            Some(ContentType { mime, .. }) if !self.accepts(&mime) => {
+                Err(HttpError::unsupported_media_type(&mime))
+            }
+            None if req.body.is_framed() && req.body.content_length() != Some(0) => {
+                Err(HttpError::unsupported_media_type("(none)"))
+            }
+            _ => return Box::pin(next.run(req)),
+        };
+        Box::pin(async move { result })
+    }
+}
@@ -0,0 +1,186 @@
+//! Cookies the client holds but can't forge or read: small bits of state, like a flash message
+//! or who's logged in, that don't need a [session store](crate::session).
+//!
+//! A signed cookie's value can be seen but not changed, as any change breaks its HMAC; an
+//! encrypted one can't be seen either, being sealed with ChaCha20-Poly1305. Both are tied to
+//! their cookie's name, so one can't be passed off as another.
+//!
+//! ```ignore
+//! async fn save(Extension(keys): Extension<CookieKeys>) -> Response {
+//!     Response::see_other("/").with_cookie(&keys.sign(SetCookie::new("flash", "Saved!").path("/")))
+//! }
+//!
+//! async fn home(Extension(keys): Extension<CookieKeys>, cookies: Cookies) -> Response {
+//!     match cookies.get("flash").and_then(|flash| keys.verify("flash", flash)) {
+//!         Some(flash) => Response::text(StatusCode::OK, flash)
+//!             .with_cookie(&SetCookie::removal("flash").path("/")),
+//!         None => Response::empty(StatusCode::OK),
+//!     }
+//! }
+//! ```
+//!
+//! Keys are rotated by making the new one current and keeping the old one as a
+//! [`previous`](CookieKeys::previous) key: cookies are always signed and encrypted with the
+//! current key, but ones made with the previous keys still check out until they're dropped.
+
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{
+    bearer_auth::constant_time_eq,
+    cookies::SetCookie,
+    error::HttpError,
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+};
+
+/// The bytes of a nonce, which go before the ciphertext.
+const NONCE_LEN: usize = 12;
+
+/// What's derived from one secret.
+#[derive(Clone)]
+struct Keys {
+    sign: Vec<u8>,
+    encrypt: Key,
+}
+
+impl Keys {
+    fn derive(secret: &[u8]) -> Self {
+        let derive = |purpose: &str| {
+            let mut mac = hmac(secret);
+            mac.update(purpose.as_bytes());
+            mac.finalize().into_bytes()
+        };
+        Self {
+            sign: derive("cookie signing").to_vec(),
+            encrypt: derive("cookie encryption"),
+        }
+    }
+
+    /// The signature of a cookie called `name` with the encoded `value`.
+    fn signature(&self, name: &str, value: &str) -> Vec<u8> {
+        let mut mac = hmac(&self.sign);
+        mac.update(format!("{name}={value}").as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC takes keys of any length")
+}
+
+/// The keys cookies are signed and encrypted with, the current one first. Clones share them.
+///
+/// As a middleware, it puts itself in each request's extensions for handlers to take.
+#[derive(Clone)]
+pub struct CookieKeys {
+    keys: Arc<Vec<Keys>>,
+}
+
+impl CookieKeys {
+    /// Keys derived from `secret`, which should be long and random: 32 bytes or more.
+    pub fn new(secret: &[u8]) -> Self {
+        Self {
+            keys: Arc::new(vec![Keys::derive(secret)]),
+        }
+    }
+
+    /// Keeps accepting the cookies made with `secret`, without making any more with it.
+    pub fn previous(mut self, secret: &[u8]) -> Self {
+        Arc::make_mut(&mut self.keys).push(Keys::derive(secret));
+        self
+    }
+
+    fn current(&self) -> &Keys {
+        &self.keys[0]
+    }
+
+    /// `cookie`, with its value signed.
+    pub fn sign(&self, cookie: SetCookie) -> SetCookie {
+        let value = URL_SAFE_NO_PAD.encode(cookie.value());
+        let signature = self.current().signature(cookie.name(), &value);
+        let signed = format!("{value}.{}", URL_SAFE_NO_PAD.encode(signature));
+        cookie.replace_value(signed)
+    }
+
+    /// The value of a signed cookie called `name`, if `value`'s signature checks out.
+    pub fn verify(&self, name: &str, value: &str) -> Option<String> {
+        let (encoded, signature) = value.rsplit_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        let valid = self
+            .keys
+            .iter()
+            .any(|keys| constant_time_eq(&keys.signature(name, encoded), &signature));
+        if !valid {
+            return None;
+        }
+        String::from_utf8(URL_SAFE_NO_PAD.decode(encoded).ok()?).ok()
+    }
+
+    /// `cookie`, with its value encrypted.
+    pub fn encrypt(&self, cookie: SetCookie) -> SetCookie {
+        let cipher = ChaCha20Poly1305::new(&self.current().encrypt);
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: cookie.value().as_bytes(),
+            aad: cookie.name().as_bytes(),
+        };
+        let sealed = cipher
+            .encrypt(&nonce, payload)
+            .expect("cookies are far smaller than ChaCha20-Poly1305's limit");
+        let mut bytes = nonce.to_vec();
+        bytes.extend(sealed);
+        let encrypted = URL_SAFE_NO_PAD.encode(bytes);
+        cookie.replace_value(encrypted)
+    }
+
+    /// The value of an encrypted cookie called `name`, if `value` decrypts with one of the
+    /// keys.
+    pub fn decrypt(&self, name: &str, value: &str) -> Option<String> {
+        let bytes = URL_SAFE_NO_PAD.decode(value).ok()?;
+        if bytes.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let plain = self.keys.iter().find_map(|keys| {
+            let payload = Payload {
+                msg: sealed,
+                aad: name.as_bytes(),
+            };
+            ChaCha20Poly1305::new(&keys.encrypt)
+                .decrypt(Nonce::from_slice(nonce), payload)
+                .ok()
+        })?;
+        String::from_utf8(plain).ok()
+    }
+
+    /// The value of the signed cookie called `name` that `req` sent, if it checks out.
+    pub fn signed_cookie(&self, req: &Request, name: &str) -> Option<String> {
+        self.verify(name, req.cookie(name)?)
+    }
+
+    /// The value of the encrypted cookie called `name` that `req` sent, if it decrypts.
+    pub fn private_cookie(&self, req: &Request, name: &str) -> Option<String> {
+        self.decrypt(name, req.cookie(name)?)
+    }
+}
+
+impl Middleware for CookieKeys {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        req.extensions.insert(self.clone());
+        Box::pin(next.run(req))
+    }
+}
@@ -0,0 +1,279 @@
+//! Cookies: the ones a request sends, and the `Set-Cookie` headers that hand them out.
+//!
+//! ```ignore
+//! let theme = req.cookie("theme").unwrap_or("light");
+//! let response = Response::text(StatusCode::OK, "saved").with_cookie(
+//!     &SetCookie::new("theme", "dark")
+//!         .path("/")
+//!         .max_age(Duration::from_secs(365 * 24 * 60 * 60))
+//!         .same_site(SameSite::Lax),
+//! );
+//! ```
+//!
+//! Handlers can also take every cookie as [`Cookies`], which is a typed header too.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    access_log::civil,
+    error::HttpError,
+    extract::FromRequest,
+    handler::BoxFuture,
+    headers::{Header, HeaderError, COOKIE},
+    request::Request,
+};
+
+/// The cookies of a `Cookie` header, by name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Cookies(BTreeMap<String, String>);
+
+impl Cookies {
+    /// The value of the cookie called `name`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(String::as_str)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// No cookies, rather than a 400, for requests without a `Cookie` header.
+impl FromRequest for Cookies {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move { Ok(req.cookies()) })
+    }
+}
+
+/// The name and value of each cookie in `values`, the `Cookie` headers of a request, skipping
+/// pairs that aren't `name=value`. Quotes around a value are taken off.
+pub(crate) fn pairs<'a>(
+    values: impl IntoIterator<Item = &'a str>,
+) -> impl Iterator<Item = (&'a str, &'a str)> {
+    values
+        .into_iter()
+        .flat_map(|header| header.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .filter(|(name, _)| !name.is_empty())
+        .map(|(name, value)| {
+            let value = value.trim();
+            let unquoted = value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'));
+            (name.trim(), unquoted.unwrap_or(value))
+        })
+}
+
+impl Header for Cookies {
+    const NAME: &'static str = COOKIE;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        let mut cookies = BTreeMap::new();
+        // Browsers send the cookies with the most specific paths first, so the first wins.
+        for (name, value) in pairs(values.iter().copied()) {
+            cookies
+                .entry(name.to_owned())
+                .or_insert_with(|| value.to_owned());
+        }
+        Ok(Self(cookies))
+    }
+
+    fn encode(&self) -> String {
+        self.iter()
+            .map(|(name, value)| format!("{name}={value}"))
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+/// When a cookie is sent along with requests from other sites.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SameSite {
+    /// Never.
+    Strict,
+    /// Only when following a link to this site.
+    Lax,
+    /// Always, which browsers only allow for `Secure` cookies.
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Strict => "Strict",
+            Self::Lax => "Lax",
+            Self::None => "None",
+        }
+    }
+}
+
+/// A `Set-Cookie` header. Characters cookies can't have, like `;` and whitespace, are left out
+/// of the name and value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SetCookie {
+    name: String,
+    value: String,
+    expires: Option<SystemTime>,
+    max_age: Option<Duration>,
+    path: Option<String>,
+    domain: Option<String>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl SetCookie {
+    /// A cookie that lasts until the browser is closed.
+    pub fn new(name: impl Into<String>, value: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            value: value.into(),
+            expires: None,
+            max_age: None,
+            path: None,
+            domain: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Tells the browser to forget the cookie called `name`. It has to be given the same path
+    /// and domain the cookie was set with.
+    pub fn removal(name: impl Into<String>) -> Self {
+        Self::new(name, "")
+            .max_age(Duration::ZERO)
+            .expires(UNIX_EPOCH)
+    }
+
+    /// When the cookie stops being sent. Browsers go by [`max_age`](Self::max_age) when there
+    /// are both.
+    pub fn expires(mut self, time: SystemTime) -> Self {
+        self.expires = Some(time);
+        self
+    }
+
+    /// How long from now the cookie is kept for.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Which paths the cookie is sent with, the ones under the response's by default.
+    pub fn path(mut self, path: impl Into<String>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    /// Sends the cookie to `domain` and its subdomains, rather than only to the host that set
+    /// it.
+    pub fn domain(mut self, domain: impl Into<String>) -> Self {
+        self.domain = Some(domain.into());
+        self
+    }
+
+    /// Only sends the cookie over HTTPS.
+    pub fn secure(mut self, secure: bool) -> Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Keeps the cookie from scripts.
+    pub fn http_only(mut self, http_only: bool) -> Self {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Self {
+        self.same_site = Some(same_site);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    pub(crate) fn replace_value(mut self, value: String) -> Self {
+        self.value = value;
+        self
+    }
+}
+
+/// The characters of `s` that can go in a cookie, or in the value of one of its attributes.
+fn cookie_octets(s: &str) -> impl fmt::Display + '_ {
+    struct Octets<'a>(&'a str);
+    impl fmt::Display for Octets<'_> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            self.0
+                .chars()
+                .filter(|c| c.is_ascii_graphic() && !matches!(c, '"' | ',' | ';' | '\\'))
+                .try_for_each(|c| write!(f, "{c}"))
+        }
+    }
+    Octets(s)
+}
+
+/// `Wed, 21 Oct 2015 07:28:00 GMT`.
+pub(crate) fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let (year, month, day, secs) = civil(time);
+    let days = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) / 86400;
+    format!(
+        "{}, {day:02} {} {year} {:02}:{:02}:{:02} GMT",
+        DAYS[days as usize % 7],
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
+
+impl fmt::Display for SetCookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = cookie_octets(&self.name).to_string().replace('=', "");
+        write!(f, "{name}={}", cookie_octets(&self.value))?;
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", http_date(expires))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", cookie_octets(domain))?;
+        }
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", cookie_octets(path))?;
+        }
+        if self.secure {
+            f.write_str("; Secure")?;
+        }
+        if self.http_only {
+            f.write_str("; HttpOnly")?;
+        }
+        if let Some(same_site) = self.same_site {
+            write!(f, "; SameSite={}", same_site.as_str())?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+//! Cross-origin resource sharing, so that browser apps served from other origins can call the
+//! server: `fetch("https://files.example.com/files/report.csv")` from `https://app.example.com`
+//! only gets to read the response if it says that origin may.
+//!
+//! [`Cors`] answers the preflight `OPTIONS` requests browsers send before anything but the
+//! simplest requests itself, without them reaching the routes (or the authentication, since
+//! preflights never carry credentials), and adds `Access-Control-Allow-Origin` and the rest to
+//! other responses, errors included, for requests from the allowed origins. Requests from
+//! other origins go through untouched, which is what a browser needs to refuse them.
+//!
+//! Origins are given exactly, as `https://app.example.com`, with a wildcard for one or more
+//! subdomains, as `https://*.example.com`, or as `*` for any origin.
+
+use std::time::Duration;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::{
+        HeaderMap, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+        ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+    },
+    middleware::{Middleware, Next},
+    request::{Method, Request},
+    response::Response,
+    status::StatusCode,
+};
+
+/// An origin requests may come from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllowedOrigin {
+    Any,
+    Exact(String),
+    /// `scheme://*.domain`, as the bits before and after the `*`.
+    Subdomains {
+        scheme: String,
+        domain: String,
+    },
+}
+
+impl std::str::FromStr for AllowedOrigin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+        let Some((scheme, rest)) = s.split_once("://") else {
+            return Err(format!("{s} isn't an origin like https://example.com"));
+        };
+        if rest.is_empty() || rest.contains('/') {
+            return Err(format!("{s} isn't an origin like https://example.com"));
+        }
+        match rest.strip_prefix('*') {
+            Some(domain) if domain.starts_with('.') && !domain.contains('*') => {
+                Ok(Self::Subdomains {
+                    scheme: format!("{scheme}://").to_ascii_lowercase(),
+                    domain: domain.to_ascii_lowercase(),
+                })
+            }
+            Some(_) => Err(format!("{s} can only start its host with *.")),
+            None if rest.contains('*') => Err(format!("{s} can only start its host with *.")),
+            None => Ok(Self::Exact(s.to_ascii_lowercase())),
+        }
+    }
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            Self::Any => true,
+            Self::Exact(exact) => origin == *exact,
+            Self::Subdomains { scheme, domain } => origin
+                .strip_prefix(scheme.as_str())
+                .and_then(|host| host.strip_suffix(domain.as_str()))
+                .is_some_and(|sub| {
+                    !sub.is_empty()
+                        && sub
+                            .bytes()
+                            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.')
+                }),
+        }
+    }
+}
+
+/// Which origins may call the server, and with what.
+#[derive(Debug, Clone)]
+pub struct Cors {
+    origins: Vec<AllowedOrigin>,
+    methods: String,
+    headers: String,
+    expose: String,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Cors {
+    /// Lets requests from `origins` through, with the usual methods and the `Authorization`,
+    /// `Content-Type`, `X-API-Key` and `X-CSRF-Token` headers.
+    pub fn new(origins: impl IntoIterator<Item = AllowedOrigin>) -> Self {
+        Self {
+            origins: origins.into_iter().collect(),
+            methods: "GET, HEAD, POST, PUT, PATCH, DELETE".to_owned(),
+            headers: "Authorization, Content-Type, X-API-Key, X-CSRF-Token".to_owned(),
+            expose: String::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// The methods requests beyond `GET`, `HEAD` and `POST` may use.
+    pub fn methods<'a>(mut self, methods: impl IntoIterator<Item = &'a str>) -> Self {
+        self.methods = methods.into_iter().collect::<Vec<_>>().join(", ");
+        self
+    }
+
+    /// The headers requests may send beyond those browsers always allow.
+    pub fn headers<'a>(mut self, headers: impl IntoIterator<Item = &'a str>) -> Self {
+        self.headers = headers.into_iter().collect::<Vec<_>>().join(", ");
+        self
+    }
+
+    /// The response headers scripts may read beyond those browsers always show them, like
+    /// `ETag` or `Content-Range`.
+    pub fn expose_headers<'a>(mut self, headers: impl IntoIterator<Item = &'a str>) -> Self {
+        self.expose = headers.into_iter().collect::<Vec<_>>().join(", ");
+        self
+    }
+
+    /// Lets requests carry cookies and `Authorization`. The origin is then echoed back rather
+    /// than answered with `*`, which browsers don't take with credentials, so together with
+    /// [`AllowedOrigin::Any`] it lets every site make requests as the user and read the answers.
+    pub fn credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache a preflight's answer.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| allowed.matches(origin))
+    }
+
+    /// `Access-Control-Allow-Origin` and the headers on every response to `origin`.
+    fn decorate(&self, headers: &mut HeaderMap, origin: &str) {
+        let any = self.origins.contains(&AllowedOrigin::Any) && !self.credentials;
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, if any { "*" } else { origin });
+        if !any {
+            // The answer depends on who's asking, so caches must keep them apart.
+            let vary = headers.get(VARY).map(str::to_owned);
+            match vary {
+                Some(vary) if !vary.contains("Origin") => {
+                    headers.insert(VARY, &format!("{vary}, Origin"))
+                }
+                Some(_) => {}
+                None => headers.insert(VARY, "Origin"),
+            }
+        }
+        if self.credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_CREDENTIALS, "true");
+        }
+    }
+
+    fn preflight(&self, origin: &str) -> Response {
+        let mut response = Response::empty(StatusCode::NO_CONTENT)
+            .with_header(ACCESS_CONTROL_ALLOW_METHODS, &self.methods);
+        if !self.headers.is_empty() {
+            response.set_header(ACCESS_CONTROL_ALLOW_HEADERS, &self.headers);
+        }
+        if let Some(max_age) = self.max_age {
+            response.set_header(ACCESS_CONTROL_MAX_AGE, &max_age.as_secs().to_string());
+        }
+        self.decorate(&mut response.headers, origin);
+        response
+    }
+}
+
+impl Middleware for Cors {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let Some(origin) = req
+            .header(ORIGIN)
+            .filter(|origin| self.allows(origin))
+            .map(str::to_owned)
+        else {
+            return Box::pin(next.run(req));
+        };
+        if req.method == Method::Options && req.headers.contains(ACCESS_CONTROL_REQUEST_METHOD) {
+            let response = self.preflight(&origin);
+            return Box::pin(async { Ok(response) });
+        }
+        let cors = self.clone();
+        Box::pin(async move {
+            match next.run(req).await {
+                Ok(mut response) => {
+                    if !cors.expose.is_empty() {
+                        response.set_header(ACCESS_CONTROL_EXPOSE_HEADERS, &cors.expose);
+                    }
+                    cors.decorate(&mut response.headers, &origin);
+                    Ok(response)
+                }
+                Err(mut e) => {
+                    cors.decorate(&mut e.headers, &origin);
+                    Err(e)
+                }
+            }
+        })
+    }
+}
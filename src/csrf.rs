@@ -0,0 +1,153 @@
+//! Cross-site request forgery protection for browser-facing deployments, with double-submit
+//! cookies.
+//!
+//! [`Csrf`] hands browsers a random token in a `csrf_token` cookie on the first safe request
+//! (a `GET`, say) that comes without one, and then takes requests that change something
+//! (`POST`, `PUT`, `DELETE` and the like) only if they send the same token back in an
+//! `X-CSRF-Token` header. A page on another site can make the browser send the cookie, but it
+//! can't read it to fill in the header, nor set that header without a CORS preflight:
+//!
+//! ```js
+//! const token = document.cookie.match(/csrf_token=([^;]+)/)[1];
+//! await fetch("/files/notes.txt", { method: "PUT", headers: { "X-CSRF-Token": token }, body });
+//! ```
+//!
+//! Handlers that render forms can take the token from the request's [`CsrfToken`] extension.
+//! Requests with a bearer token or an API key are let through without one: browsers never
+//! send those on their own, so a forged request can't have them.
+
+use tracing::warn;
+
+use crate::{
+    bearer_auth::{bearer_token, constant_time_eq},
+    cookies::{SameSite, SetCookie},
+    error::HttpError,
+    handler::BoxFuture,
+    headers::AUTHORIZATION,
+    middleware::{Middleware, Next},
+    request::{Request, Scheme},
+    response::Response,
+    status::StatusCode,
+};
+
+/// The request's CSRF token, the one in its cookie or the one just issued, in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfToken(pub String);
+
+/// Checks the token on unsafe requests under a prefix, and issues it on the others.
+#[derive(Debug, Clone)]
+pub struct Csrf {
+    prefix: String,
+    cookie: String,
+    header: String,
+    exempt_headers: Vec<String>,
+}
+
+impl Csrf {
+    /// Protects everything under `prefix`, with the `csrf_token` cookie and the
+    /// `X-CSRF-Token` header, and exempts requests with an `X-API-Key`.
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            cookie: "csrf_token".to_owned(),
+            header: "X-CSRF-Token".to_owned(),
+            exempt_headers: vec!["X-API-Key".to_owned()],
+        }
+    }
+
+    /// The name of the cookie the token goes in.
+    pub fn cookie(mut self, name: impl Into<String>) -> Self {
+        self.cookie = name.into();
+        self
+    }
+
+    /// The name of the header the token comes back in.
+    pub fn header(mut self, name: impl Into<String>) -> Self {
+        self.header = name.into();
+        self
+    }
+
+    /// Lets requests with these headers, which carry credentials browsers don't send by
+    /// themselves, through without a token; `Authorization: Bearer` always is.
+    pub fn exempt_headers(mut self, names: impl IntoIterator<Item = String>) -> Self {
+        self.exempt_headers = names.into_iter().collect();
+        self
+    }
+
+    fn exempt(&self, req: &Request) -> bool {
+        req.header(AUTHORIZATION).and_then(bearer_token).is_some()
+            || self
+                .exempt_headers
+                .iter()
+                .any(|name| req.headers.contains(name))
+    }
+
+    /// The cookie issuing `token`. It's left readable to scripts, which have to copy it into
+    /// the header.
+    fn set_cookie(&self, token: &str, scheme: Scheme) -> SetCookie {
+        SetCookie::new(&self.cookie, token)
+            .path("/")
+            .same_site(SameSite::Strict)
+            .secure(scheme == Scheme::Https)
+    }
+}
+
+/// Whether `token` looks like one [`Csrf`] issued, so that a cookie set some other way doesn't
+/// get used as one.
+fn well_formed(token: &str) -> bool {
+    token.len() == 32 && token.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl Middleware for Csrf {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if !req.path.starts_with(&self.prefix) {
+            return Box::pin(next.run(req));
+        }
+        let token = req
+            .cookie(&self.cookie)
+            .filter(|token| well_formed(token))
+            .map(str::to_owned);
+        if !req.method.is_safe() && !self.exempt(&req) {
+            let sent = req.header(&self.header).map(str::trim);
+            let matches = match (&token, sent) {
+                (Some(token), Some(sent)) => constant_time_eq(token.as_bytes(), sent.as_bytes()),
+                _ => false,
+            };
+            if !matches {
+                warn!(
+                    "Refused {} {} without a matching CSRF token",
+                    req.method, req.path
+                );
+                return Box::pin(async {
+                    Err(HttpError::new(
+                        StatusCode::FORBIDDEN,
+                        anyhow::anyhow!("Missing or wrong CSRF token"),
+                    ))
+                });
+            }
+        }
+        let issue = match token {
+            Some(token) => {
+                req.extensions.insert(CsrfToken(token));
+                None
+            }
+            None => {
+                let token = uuid::Uuid::new_v4().simple().to_string();
+                req.extensions.insert(CsrfToken(token.clone()));
+                Some(self.set_cookie(&token, req.scheme))
+            }
+        };
+        let fut = next.run(req);
+        Box::pin(async move {
+            let response = fut.await?;
+            Ok(match issue {
+                Some(set_cookie) => response.with_cookie(&set_cookie),
+                None => response,
+            })
+        })
+    }
+}
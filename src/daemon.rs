@@ -0,0 +1,160 @@
+//! Running in the background: detaching from the terminal with [`daemonize`], and recording the
+//! process ID in a [`PidFile`].
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
+    os::fd::{AsRawFd, RawFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+/// Held by the background process until it's up; see [`daemonize`].
+pub struct Ready {
+    pipe: io::PipeWriter,
+}
+
+impl Ready {
+    /// Lets the process that started this one exit successfully.
+    pub fn notify(mut self) {
+        let _ = self.pipe.write_all(b"1");
+    }
+}
+
+/// Forks into a background process in a session of its own, with stdin from `/dev/null` and
+/// stdout and stderr going to `log`, or nowhere. Only the new process returns. The one that
+/// called this waits for it to [`notify`](Ready::notify) it's ready and then exits, or exits
+/// with an error if the new process exits first, so scripts starting the server still learn
+/// whether it started.
+///
+/// Only the calling thread carries over into the new process, so call it before starting any
+/// others, the async runtime's included.
+pub fn daemonize(log: Option<&Path>) -> io::Result<Ready> {
+    let output = match log {
+        Some(path) => open_log(path)?,
+        None => File::options().write(true).open("/dev/null")?,
+    };
+    let input = File::open("/dev/null")?;
+    let (reader, writer) = io::pipe()?;
+    // SAFETY: with no other threads, the new process doesn't start out with locks held by
+    // threads missing from it.
+    match unsafe { libc::fork() } {
+        -1 => return Err(io::Error::last_os_error()),
+        0 => {}
+        _ => {
+            drop(writer);
+            wait_for_start(reader, log);
+        }
+    }
+    drop(reader);
+    // SAFETY: setsid takes no arguments; the fork made sure this process can lead a session.
+    if unsafe { libc::setsid() } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    redirect(&input, libc::STDIN_FILENO)?;
+    redirect(&output, libc::STDOUT_FILENO)?;
+    redirect(&output, libc::STDERR_FILENO)?;
+    Ok(Ready { pipe: writer })
+}
+
+fn wait_for_start(mut pipe: io::PipeReader, log: Option<&Path>) -> ! {
+    let mut ready = [0];
+    if pipe.read(&mut ready).is_ok_and(|n| n == 1) {
+        std::process::exit(0);
+    }
+    match log {
+        Some(log) => eprintln!("The server exited while starting, see {}", log.display()),
+        None => eprintln!("The server exited while starting; --log-file would say why"),
+    }
+    std::process::exit(1);
+}
+
+fn open_log(path: &Path) -> io::Result<File> {
+    File::options().append(true).create(true).open(path)
+}
+
+fn redirect(file: &File, target: RawFd) -> io::Result<()> {
+    // SAFETY: dup2 only takes descriptors; the standard streams it replaces aren't owned by
+    // anything that would close them.
+    match unsafe { libc::dup2(file.as_raw_fd(), target) } {
+        -1 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// A file with the process ID in it, locked while the process runs so that a second server
+/// started with the same one fails instead of running alongside. It's removed on drop, unless
+/// a process that took over has put its own ID in it by then.
+pub struct PidFile {
+    file: Arc<File>,
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        // SAFETY: flock only takes the descriptor.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::WouldBlock {
+                return Err(e);
+            }
+            let pid = fs::read_to_string(path).unwrap_or_default();
+            let message = format!("process {} is running with it", pid.trim());
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, message));
+        }
+        let pid_file = Self {
+            file: Arc::new(file),
+            path: path.to_owned(),
+        };
+        pid_file.refresh()?;
+        Ok(pid_file)
+    }
+
+    /// For a process started by [`hand_off`](crate::listener::hand_off): writes this
+    /// process's ID right away, while the one it replaces is still draining, and takes the
+    /// lock once that one has exited.
+    pub fn take_over(path: &Path) -> io::Result<Self> {
+        let file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let pid_file = Self {
+            file: Arc::new(file),
+            path: path.to_owned(),
+        };
+        pid_file.refresh()?;
+        let file = pid_file.file.clone();
+        thread::Builder::new()
+            .name("pidfile".to_owned())
+            // SAFETY: flock only takes the descriptor, which the Arc keeps open.
+            .spawn(move || unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) })?;
+        Ok(pid_file)
+    }
+
+    /// Writes this process's ID, replacing what's there; after [`daemonize`], that's the new
+    /// process's. The lock carries over.
+    pub fn refresh(&self) -> io::Result<()> {
+        let mut file = &*self.file;
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let ours = fs::read_to_string(&self.path)
+            .is_ok_and(|pid| pid.trim() == std::process::id().to_string());
+        if ours {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! The JSON files in the data directory, where the server keeps what it has to remember
+//! between runs, like [short links](crate::shortener) and
+//! [when uploads expire](crate::upload_expiry).
+//!
+//! Each store keeps its state in memory and a [`JsonFile`] of it, which it marks as changed
+//! as it goes and saves when it has to: a snapshot of the whole state is written to a
+//! temporary file next to the file, and renamed over it, so it's never found half written.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Serialize};
+
+pub struct JsonFile {
+    path: PathBuf,
+    /// Whether the store changed since it was last saved.
+    changed: AtomicBool,
+    /// Held while writing the file, so that an older snapshot never lands after a newer one.
+    saving: tokio::sync::Mutex<()>,
+}
+
+impl JsonFile {
+    /// The file called `name` in `dir`, and what's in it, or the default if it isn't there
+    /// yet. The directory is created if need be.
+    pub fn open<T: DeserializeOwned + Default>(
+        dir: &Path,
+        name: &str,
+    ) -> anyhow::Result<(Self, T)> {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+        let path = dir.join(name);
+        let contents = match std::fs::read(&path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .with_context(|| format!("reading {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => T::default(),
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+        };
+        let file = Self {
+            path,
+            changed: AtomicBool::new(false),
+            saving: tokio::sync::Mutex::const_new(()),
+        };
+        Ok((file, contents))
+    }
+
+    /// Marks the store as changed, for the next [`save`](Self::save) to write.
+    pub fn changed(&self) {
+        self.changed.store(true, Ordering::SeqCst);
+    }
+
+    /// Writes what `snapshot` returns, if the store changed since it was last saved. If the
+    /// write fails, it's left marked as changed for the next try.
+    pub async fn save<T: Serialize>(&self, snapshot: impl FnOnce() -> T) -> anyhow::Result<()> {
+        let _saving = self.saving.lock().await;
+        // Taken before the snapshot, so that a change made while it's written is saved next.
+        if !self.changed.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = self.write(&snapshot()).await;
+        if result.is_err() {
+            self.changed();
+        }
+        result
+    }
+
+    async fn write(&self, contents: &impl Serialize) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(contents)?;
+        let mut temporary = self.path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        let temporary = PathBuf::from(temporary);
+        tokio::fs::write(&temporary, json)
+            .await
+            .with_context(|| format!("writing {}", temporary.display()))?;
+        tokio::fs::rename(&temporary, &self.path)
+            .await
+            .with_context(|| format!("moving {} into place", temporary.display()))
+    }
+}
@@ -0,0 +1,112 @@
+use crate::{
+    headers::{HeaderMap, X_REQUEST_ID},
+    response::Response,
+    status::StatusCode,
+};
+
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub error: anyhow::Error,
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl From<anyhow::Error> for HttpError {
+    fn from(value: anyhow::Error) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, value)
+    }
+}
+
+impl HttpError {
+    pub fn new(status: StatusCode, error: anyhow::Error) -> Self {
+        Self {
+            status,
+            headers: HeaderMap::new(),
+            error,
+        }
+    }
+
+    pub fn bad_request(msg: &str) -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("Bad request: {msg}"),
+        )
+    }
+
+    pub fn forbidden() -> Self {
+        Self::new(StatusCode::FORBIDDEN, anyhow::anyhow!("Forbidden"))
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(StatusCode::NOT_FOUND, anyhow::anyhow!("Not found"))
+    }
+
+    pub fn method_not_allowed(method: &str) -> Self {
+        Self::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            anyhow::anyhow!("Method {method} not allowed"),
+        )
+    }
+
+    pub fn payload_too_large() -> Self {
+        Self::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            anyhow::anyhow!("Payload too large"),
+        )
+    }
+
+    pub fn unsupported_media_type(mime: &str) -> Self {
+        Self::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            anyhow::anyhow!("Unsupported media type {mime}"),
+        )
+    }
+
+    pub fn too_many_requests() -> Self {
+        Self::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            anyhow::anyhow!("Too many requests"),
+        )
+    }
+
+    pub fn service_unavailable() -> Self {
+        Self::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow::anyhow!("Service unavailable"),
+        )
+    }
+
+    pub fn gateway_timeout() -> Self {
+        Self::new(
+            StatusCode::GATEWAY_TIMEOUT,
+            anyhow::anyhow!("Gateway timeout"),
+        )
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.append(key, value);
+        self
+    }
+
+    /// The default rendering: the status with the error message as a plain-text body, and
+    /// the request's ID after it when the server gave it one.
+    pub fn to_response(&self) -> Response {
+        let body = match self.headers.get(X_REQUEST_ID) {
+            Some(id) => format!("{} (request ID {id})", self.error),
+            None => self.error.to_string(),
+        };
+        let mut response = Response::text(self.status, body);
+        for (k, v) in &self.headers {
+            response.set_header(k, v);
+        }
+        response
+    }
+}
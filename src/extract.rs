@@ -0,0 +1,261 @@
+//! Handler parameters that are pulled out of the request before the handler runs.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+
+use crate::{
+    body::Body,
+    error::HttpError,
+    forwarded::{self, TrustedForwarding},
+    handler::BoxFuture,
+    headers::{Header, HeaderError, HeaderMap},
+    proxy_protocol::Balancer,
+    request::{Method, Request, Scheme},
+    response::Json,
+};
+
+/// Extractors run in parameter order against the same request; the ones that consume
+/// something (like [`Body`]) leave an empty value behind for later extractors.
+pub trait FromRequest: Sized + Send + 'static {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>>;
+}
+
+/// The single `{name}` or `{*name}` capture of the matched route, parsed into `T`.
+pub struct Path<T>(pub T);
+
+impl<T> FromRequest for Path<T>
+where
+    T: FromStr + Send + 'static,
+{
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            let [(name, value)] = req.params.as_slice() else {
+                return Err(anyhow::anyhow!(
+                    "Path<T> needs exactly one route parameter, found {}",
+                    req.params.len()
+                )
+                .into());
+            };
+            value
+                .parse()
+                .map(Path)
+                .map_err(|_| HttpError::bad_request(&format!("invalid path parameter {name}")))
+        })
+    }
+}
+
+/// Every route capture, in pattern order.
+pub struct PathParams(pub Vec<(String, String)>);
+
+impl FromRequest for PathParams {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move { Ok(PathParams(req.params.clone())) })
+    }
+}
+
+/// The query string deserialized into `T`.
+pub struct Query<T>(pub T);
+
+impl<T> FromRequest for Query<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            serde_urlencoded::from_str(&req.query)
+                .map(Query)
+                .map_err(|e| HttpError::bad_request(&format!("invalid query string: {e}")))
+        })
+    }
+}
+
+pub struct Headers(pub HeaderMap);
+
+impl Headers {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name)
+    }
+}
+
+impl FromRequest for Headers {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move { Ok(Headers(req.headers.clone())) })
+    }
+}
+
+/// A required header parsed into its typed form; missing or malformed values are a 400.
+/// Wrap it in an `Option` to make the header optional.
+pub struct TypedHeader<H>(pub H);
+
+impl<H> FromRequest for TypedHeader<H>
+where
+    H: Header + Send + 'static,
+{
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            let header = req
+                .headers
+                .typed_get::<H>()?
+                .ok_or(HeaderError::Missing(H::NAME))?;
+            Ok(TypedHeader(header))
+        })
+    }
+}
+
+/// Turns any extractor failure into `None` instead of rejecting the request.
+impl<T: FromRequest> FromRequest for Option<T> {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move { Ok(T::from_request(req).await.ok()) })
+    }
+}
+
+/// Bodies buffered by the [`Bytes`], [`String`] and [`Json`] extractors are capped at this size;
+/// handlers that need more should take the streaming [`Body`] instead.
+pub const DEFAULT_BODY_LIMIT: usize = 2 * 1024 * 1024;
+
+/// Takes the request body; extracting it twice yields an empty body the second time.
+impl FromRequest for Body {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move { Ok(std::mem::replace(&mut req.body, Body::empty())) })
+    }
+}
+
+impl FromRequest for Bytes {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            Body::from_request(req)
+                .await?
+                .to_bytes(DEFAULT_BODY_LIMIT)
+                .await
+        })
+    }
+}
+
+impl FromRequest for String {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            Body::from_request(req)
+                .await?
+                .text(DEFAULT_BODY_LIMIT)
+                .await
+        })
+    }
+}
+
+impl<T> FromRequest for Json<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            let bytes = Bytes::from_request(req).await?;
+            serde_json::from_slice(&bytes)
+                .map(Json)
+                .map_err(|e| HttpError::bad_request(&format!("invalid json body: {e}")))
+        })
+    }
+}
+
+/// A clone of the state registered with [`Router::with_state`](crate::router::Router::with_state).
+pub struct State<T>(pub T);
+
+impl<T> FromRequest for State<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            req.extensions
+                .get::<T>()
+                .cloned()
+                .map(State)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("no state of type {} registered", std::any::type_name::<T>())
+                        .into()
+                })
+        })
+    }
+}
+
+/// A clone of a value a middleware put into the request extensions.
+pub struct Extension<T>(pub T);
+
+impl<T> FromRequest for Extension<T>
+where
+    T: Clone + Send + Sync + 'static,
+{
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            req.extensions
+                .get::<T>()
+                .cloned()
+                .map(Extension)
+                .ok_or_else(|| {
+                    anyhow::anyhow!("missing request extension {}", std::any::type_name::<T>())
+                        .into()
+                })
+        })
+    }
+}
+
+/// The path and query string the request was sent with, as they were sent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub path: String,
+    pub query: String,
+}
+
+impl FromRequest for Target {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            Ok(Target {
+                path: req.path.clone(),
+                query: req.query.clone(),
+            })
+        })
+    }
+}
+
+/// Where the request came from, at each step the server knows of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemoteAddr {
+    /// The connection's peer, or the client a PROXY header named; `None` over a Unix socket.
+    pub peer: Option<SocketAddr>,
+    /// The balancer that sent the PROXY header, if one did.
+    pub balancer: Option<SocketAddr>,
+    /// The client's address, from the forwarding headers if a trusted proxy sent them.
+    pub client: Option<IpAddr>,
+    /// Whether the peer is a trusted proxy, whose forwarding headers are gone by.
+    pub forwarded: bool,
+}
+
+impl FromRequest for RemoteAddr {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            Ok(RemoteAddr {
+                peer: req.remote_addr,
+                balancer: req.extensions.get::<Balancer>().map(|b| b.0),
+                client: forwarded::client_ip(req),
+                forwarded: req.extensions.get::<TrustedForwarding>().is_some(),
+            })
+        })
+    }
+}
+
+/// `https` for requests over TLS, or from a trusted proxy that says they came to it that way.
+impl FromRequest for Scheme {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move { Ok(req.scheme) })
+    }
+}
+
+impl FromRequest for Method {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move { Ok(req.method.clone()) })
+    }
+}
@@ -0,0 +1,380 @@
+//! A FastCGI client, for handing a prefix to php-fpm or a similar application server.
+//!
+//! Every request gets its own connection: the server is asked to close it once the response is
+//! done, so there's no multiplexing to keep track of.
+
+use std::{
+    fmt, io,
+    path::PathBuf,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Context as _;
+use tokio::{
+    io::{AsyncBufRead, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    net::TcpStream,
+};
+use tracing::warn;
+
+use crate::{
+    body::Body,
+    cgi,
+    error::HttpError,
+    handler::{BoxFuture, Handler},
+    request::Request,
+    response::{Response, ResponseBody},
+    safe_path,
+    status::StatusCode,
+};
+
+const VERSION: u8 = 1;
+const REQUEST_ID: u16 = 1;
+
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+
+const ROLE_RESPONDER: u16 = 1;
+const HEADER_LEN: usize = 8;
+const MAX_CONTENT_LEN: usize = u16::MAX as usize;
+
+/// Where the FastCGI server listens: `host:port`, or `unix:/path/to/socket`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FastCgiAddress {
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for FastCgiAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(Self::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(format!("unix sockets aren't supported here: {path}"));
+        }
+        match s.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(Self::Tcp(s.to_owned()))
+            }
+            _ => Err(format!("expected HOST:PORT or unix:PATH, got {s}")),
+        }
+    }
+}
+
+impl fmt::Display for FastCgiAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(address) => f.write_str(address),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+trait Connection: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Connection for T {}
+
+impl FastCgiAddress {
+    async fn connect(&self) -> io::Result<Box<dyn Connection>> {
+        Ok(match self {
+            Self::Tcp(address) => Box::new(TcpStream::connect(address).await?),
+            #[cfg(unix)]
+            Self::Unix(path) => Box::new(tokio::net::UnixStream::connect(path).await?),
+        })
+    }
+}
+
+/// Forwards requests to a FastCGI responder, meant to be
+/// [mounted](crate::router::Router::mount).
+///
+/// `SCRIPT_FILENAME` is the request path below the mount, resolved against the document root
+/// as seen by the FastCGI server.
+#[derive(Debug, Clone)]
+pub struct FastCgi {
+    address: FastCgiAddress,
+    document_root: PathBuf,
+    index: Option<String>,
+    split_extension: Option<String>,
+}
+
+impl FastCgi {
+    pub fn new(address: FastCgiAddress, document_root: impl Into<PathBuf>) -> Self {
+        Self {
+            address,
+            document_root: document_root.into(),
+            index: None,
+            split_extension: None,
+        }
+    }
+
+    /// The script run for paths ending in `/`, e.g. `index.php`.
+    pub fn index(mut self, index: &str) -> Self {
+        self.index = Some(index.to_owned());
+        self
+    }
+
+    /// Splits the path after the first segment ending in `extension`, passing the rest as
+    /// `PATH_INFO`: with `.php`, `/app.php/users/1` runs `app.php` with `PATH_INFO=/users/1`.
+    pub fn split_path_info(mut self, extension: &str) -> Self {
+        self.split_extension = Some(extension.to_owned());
+        self
+    }
+
+    fn split<'a>(&self, path: &'a str) -> (&'a str, &'a str) {
+        let Some(extension) = &self.split_extension else {
+            return (path, "");
+        };
+        let mut end = 0;
+        for segment in path.split('/').skip(1) {
+            end += segment.len() + 1;
+            if segment.ends_with(extension.as_str()) {
+                return path.split_at(end);
+            }
+        }
+        (path, "")
+    }
+
+    fn params(&self, req: &Request) -> Result<Vec<(String, String)>, HttpError> {
+        let (script, path_info) = self.split(&req.path);
+        safe_path::resolve(script)?;
+        let full_path = req.target.split('?').next().unwrap_or_default();
+        let mut script_name = full_path
+            .strip_suffix(path_info)
+            .unwrap_or(full_path)
+            .to_owned();
+
+        let mut script_filename = format!(
+            "{}{script}",
+            self.document_root
+                .display()
+                .to_string()
+                .trim_end_matches('/')
+        );
+        if let Some(index) = self.index.as_deref().filter(|_| script.ends_with('/')) {
+            script_filename.push_str(index);
+            script_name.push_str(index);
+        }
+
+        let mut params = cgi::environment(req, &script_name, path_info);
+        params.push(("SCRIPT_FILENAME".to_owned(), script_filename));
+        params.push((
+            "DOCUMENT_ROOT".to_owned(),
+            self.document_root.display().to_string(),
+        ));
+        Ok(params)
+    }
+
+    async fn run(&self, mut req: Request) -> Result<Response, HttpError> {
+        let params = self.params(&req)?;
+        let conn = self
+            .address
+            .connect()
+            .await
+            .with_context(|| format!("connecting to FastCGI server {}", self.address))
+            .map_err(|e| HttpError::new(StatusCode::BAD_GATEWAY, e))?;
+        let (reader, mut writer) = tokio::io::split(conn);
+
+        let mut begin = Vec::with_capacity(8);
+        begin.extend_from_slice(&ROLE_RESPONDER.to_be_bytes());
+        // No FCGI_KEEP_CONN flag: the server closes the connection when it's done.
+        begin.extend_from_slice(&[0; 6]);
+        let mut head = record(BEGIN_REQUEST, &begin);
+        for chunk in encode_params(&params).chunks(MAX_CONTENT_LEN) {
+            head.extend_from_slice(&record(PARAMS, chunk));
+        }
+        head.extend_from_slice(&record(PARAMS, &[]));
+        writer
+            .write_all(&head)
+            .await
+            .context("sending FastCGI params")
+            .map_err(|e| HttpError::new(StatusCode::BAD_GATEWAY, e))?;
+
+        // Stream the body while reading the response, which may start before it's all sent.
+        let mut body = std::mem::replace(&mut req.body, Body::empty());
+        tokio::spawn(async move {
+            if let Err(e) = send_stdin(&mut body, &mut writer).await {
+                warn!("Error sending request body to FastCGI server: {e}");
+            }
+        });
+
+        let mut stdout = BufReader::new(Stdout::new(reader));
+        let mut response = cgi::read_headers(&mut stdout)
+            .await
+            .map_err(|e| HttpError::new(StatusCode::BAD_GATEWAY, e))?;
+        response.body = ResponseBody::Reader(Box::new(stdout));
+        Ok(response)
+    }
+}
+
+fn record(kind: u8, content: &[u8]) -> Vec<u8> {
+    debug_assert!(content.len() <= MAX_CONTENT_LEN);
+    let padding = (8 - content.len() % 8) % 8;
+    let mut record = Vec::with_capacity(HEADER_LEN + content.len() + padding);
+    record.extend_from_slice(&[VERSION, kind]);
+    record.extend_from_slice(&REQUEST_ID.to_be_bytes());
+    record.extend_from_slice(&(content.len() as u16).to_be_bytes());
+    record.extend_from_slice(&[padding as u8, 0]);
+    record.extend_from_slice(content);
+    record.resize(record.len() + padding, 0);
+    record
+}
+
+/// Name-value pairs, each length taking one byte below 128 and four bytes otherwise.
+fn encode_params(params: &[(String, String)]) -> Vec<u8> {
+    fn encode_len(buf: &mut Vec<u8>, len: usize) {
+        match u8::try_from(len) {
+            Ok(len) if len < 0x80 => buf.push(len),
+            _ => buf.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes()),
+        }
+    }
+
+    let mut buf = Vec::new();
+    for (name, value) in params {
+        encode_len(&mut buf, name.len());
+        encode_len(&mut buf, value.len());
+        buf.extend_from_slice(name.as_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf
+}
+
+async fn send_stdin(body: &mut Body, writer: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+    let mut buf = vec![0; 32 * 1024];
+    loop {
+        let n = body.read(&mut buf).await?;
+        writer.write_all(&record(STDIN, &buf[..n])).await?;
+        if n == 0 {
+            return writer.flush().await;
+        }
+    }
+}
+
+/// The application's output stream, unwrapped from its records. Anything sent to stderr is
+/// logged, and the stream ends with the END_REQUEST record.
+struct Stdout<R> {
+    conn: BufReader<R>,
+    header: Vec<u8>,
+    kind: u8,
+    content_left: usize,
+    padding_left: usize,
+    other: Vec<u8>,
+    done: bool,
+}
+
+impl<R: AsyncRead + Unpin> Stdout<R> {
+    fn new(conn: R) -> Self {
+        Self {
+            conn: BufReader::new(conn),
+            header: Vec::with_capacity(HEADER_LEN),
+            kind: 0,
+            content_left: 0,
+            padding_left: 0,
+            other: Vec::new(),
+            done: false,
+        }
+    }
+
+    fn finish_record(&mut self) {
+        match self.kind {
+            STDERR => {
+                for line in String::from_utf8_lossy(&self.other).lines() {
+                    warn!("FastCGI stderr: {line}");
+                }
+            }
+            END_REQUEST => {
+                let app_status = self
+                    .other
+                    .get(..4)
+                    .map_or(0, |s| u32::from_be_bytes(s.try_into().expect("4 bytes")));
+                let protocol_status = self.other.get(4).copied().unwrap_or(0);
+                if app_status != 0 || protocol_status != 0 {
+                    warn!(
+                        "FastCGI request ended with app status {app_status}, protocol status {protocol_status}"
+                    );
+                }
+                self.done = true;
+            }
+            _ => {}
+        }
+        self.header.clear();
+        self.other.clear();
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Stdout<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.done || buf.remaining() == 0 {
+                return Poll::Ready(Ok(()));
+            }
+            let available = ready!(Pin::new(&mut this.conn).poll_fill_buf(cx))?;
+            if available.is_empty() {
+                return Poll::Ready(Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "FastCGI server closed the connection mid-response",
+                )));
+            }
+
+            if this.header.len() < HEADER_LEN {
+                let n = available.len().min(HEADER_LEN - this.header.len());
+                this.header.extend_from_slice(&available[..n]);
+                Pin::new(&mut this.conn).consume(n);
+                if this.header.len() == HEADER_LEN {
+                    this.kind = this.header[1];
+                    this.content_left = u16::from_be_bytes([this.header[4], this.header[5]]).into();
+                    this.padding_left = this.header[6].into();
+                    if this.content_left == 0 && this.padding_left == 0 {
+                        this.finish_record();
+                    }
+                }
+                continue;
+            }
+
+            if this.content_left > 0 {
+                let n = available.len().min(this.content_left);
+                if this.kind == STDOUT {
+                    let n = n.min(buf.remaining());
+                    buf.put_slice(&available[..n]);
+                    Pin::new(&mut this.conn).consume(n);
+                    this.content_left -= n;
+                    if this.content_left == 0 && this.padding_left == 0 {
+                        this.finish_record();
+                    }
+                    return Poll::Ready(Ok(()));
+                }
+                this.other.extend_from_slice(&available[..n]);
+                this.content_left -= n;
+                Pin::new(&mut this.conn).consume(n);
+            } else {
+                let n = available.len().min(this.padding_left);
+                this.padding_left -= n;
+                Pin::new(&mut this.conn).consume(n);
+            }
+            if this.content_left == 0 && this.padding_left == 0 {
+                this.finish_record();
+            }
+        }
+    }
+}
+
+impl Handler for FastCgi {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let fastcgi = self.clone();
+        Box::pin(async move { fastcgi.run(req).await })
+    }
+}
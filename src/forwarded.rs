@@ -0,0 +1,272 @@
+//! Who the client really is when requests arrive through other proxies, and telling the next
+//! hop the same when proxying ourselves.
+//!
+//! Forwarding headers are trivial to forge, so they're only believed when the peer that sent
+//! them is a [trusted proxy](TrustedProxies).
+
+use std::{net::IpAddr, str::FromStr, sync::Arc};
+
+use tracing::debug;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::{HeaderMap, FORWARDED, HOST, X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PROTO},
+    middleware::{Middleware, Next},
+    request::{Request, Scheme},
+    response::Response,
+};
+
+/// An address range like `10.0.0.0/8` or `fd00::/8`; a bare address is a range of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // IPv4 peers on a dual-stack socket show up as mapped IPv6 addresses.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix_len: u8) -> bool {
+    let (full, rest) = (usize::from(prefix_len / 8), prefix_len % 8);
+    if net[..full] != ip[..full] {
+        return false;
+    }
+    let mask = match rest {
+        0 => return true,
+        rest => 0xffu8 << (8 - rest),
+    };
+    net[full] & mask == ip[full] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr, Some(len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid address in {s}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|len| *len <= max)
+                .ok_or_else(|| format!("invalid prefix length in {s}"))?,
+            None => max,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// The client's address as resolved by [`TrustedProxies`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Marks a request whose forwarding headers came from a trusted proxy, so a [`Proxy`] adds to
+/// them instead of starting over.
+///
+/// [`Proxy`]: crate::proxy::Proxy
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct TrustedForwarding;
+
+/// The address requests should be attributed to: the one resolved from forwarding headers if
+/// a trusted proxy sent them, the peer's otherwise.
+pub fn client_ip(req: &Request) -> Option<IpAddr> {
+    req.extensions
+        .get::<ClientIp>()
+        .map(|ip| ip.0)
+        .or_else(|| req.remote_addr.map(|addr| addr.ip()))
+}
+
+/// Resolves the client address from `X-Forwarded-For` (or `Forwarded`) for requests sent by a
+/// trusted proxy, also taking the scheme from `X-Forwarded-Proto`. The address is the last one
+/// in the chain not belonging to a trusted proxy, so a client can't spoof its way out by
+/// prepending entries.
+#[derive(Clone)]
+pub struct TrustedProxies {
+    trusted: Arc<[Cidr]>,
+}
+
+impl TrustedProxies {
+    pub fn new(trusted: impl IntoIterator<Item = Cidr>) -> Self {
+        Self {
+            trusted: trusted.into_iter().collect(),
+        }
+    }
+
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(ip))
+    }
+
+    fn resolve(&self, req: &mut Request) {
+        let Some(peer) = req.remote_addr.map(|addr| addr.ip()) else {
+            return;
+        };
+        if !self.is_trusted(peer) {
+            return;
+        }
+
+        let mut chain = forwarded_for(&req.headers);
+        chain.push(peer);
+        let client = chain
+            .iter()
+            .rev()
+            .find(|ip| !self.is_trusted(**ip))
+            .or(chain.first())
+            .copied()
+            .unwrap_or(peer);
+        if client != peer {
+            debug!("Client {client} forwarded by {peer}");
+        }
+        req.extensions.insert(ClientIp(client));
+        req.extensions.insert(TrustedForwarding);
+
+        if forwarded_proto(&req.headers).is_some_and(|proto| proto.eq_ignore_ascii_case("https")) {
+            req.scheme = Scheme::Https;
+        }
+    }
+}
+
+/// The addresses listed by forwarding headers, client first. Entries that aren't addresses
+/// (`unknown`, obfuscated identifiers) are skipped.
+fn forwarded_for(headers: &HeaderMap) -> Vec<IpAddr> {
+    let xff = headers
+        .get_all(X_FORWARDED_FOR)
+        .flat_map(|v| v.split(','))
+        .filter_map(|entry| parse_node(entry.trim()))
+        .collect::<Vec<_>>();
+    if !xff.is_empty() || headers.contains(X_FORWARDED_FOR) {
+        return xff;
+    }
+
+    forwarded_params(headers, "for")
+        .filter_map(parse_node)
+        .collect()
+}
+
+/// The scheme the client used to reach the first proxy.
+fn forwarded_proto(headers: &HeaderMap) -> Option<&str> {
+    match headers.get(X_FORWARDED_PROTO) {
+        Some(proto) => proto.split(',').next().map(str::trim),
+        None => forwarded_params(headers, "proto").next(),
+    }
+}
+
+/// The values of one parameter across all `Forwarded` elements, unquoted.
+fn forwarded_params<'a>(
+    headers: &'a HeaderMap,
+    name: &'a str,
+) -> impl Iterator<Item = &'a str> + 'a {
+    headers
+        .get_all(FORWARDED)
+        .flat_map(|v| v.split(','))
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| pair.trim().split_once('='))
+        .filter(move |(param, _)| param.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+}
+
+/// An address, optionally bracketed and with a port as in `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.rsplit_once(':')?.0.parse().ok()
+}
+
+impl Middleware for TrustedProxies {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        self.resolve(&mut req);
+        Box::pin(next.run(req))
+    }
+}
+
+/// Adds this hop to the forwarding headers of a request about to be proxied. Headers that
+/// didn't come from a trusted proxy are replaced rather than extended.
+pub(crate) fn add_forwarding_headers(headers: &mut HeaderMap, req: &Request) {
+    let trusted = req.extensions.get::<TrustedForwarding>().is_some();
+    if !trusted {
+        for name in [
+            FORWARDED,
+            X_FORWARDED_FOR,
+            X_FORWARDED_HOST,
+            X_FORWARDED_PROTO,
+        ] {
+            headers.remove(name);
+        }
+    }
+
+    let peer = req.remote_addr.map(|addr| addr.ip());
+    let host = req.headers.get(HOST);
+    let proto = req.scheme.as_str();
+
+    let xff = match (headers.get(X_FORWARDED_FOR), peer) {
+        (Some(previous), Some(peer)) => Some(format!("{previous}, {peer}")),
+        (None, Some(peer)) => Some(peer.to_string()),
+        (_, None) => None,
+    };
+    if let Some(xff) = xff {
+        headers.insert(X_FORWARDED_FOR, &xff);
+    }
+    if !headers.contains(X_FORWARDED_PROTO) {
+        headers.insert(X_FORWARDED_PROTO, proto);
+    }
+    if let Some(host) = host.filter(|_| !headers.contains(X_FORWARDED_HOST)) {
+        headers.insert(X_FORWARDED_HOST, host);
+    }
+
+    let mut element = match peer {
+        Some(IpAddr::V6(ip)) => format!("for=\"[{ip}]\""),
+        Some(ip) => format!("for={ip}"),
+        None => "for=unknown".to_owned(),
+    };
+    element.push_str(&format!(";proto={proto}"));
+    if let Some(host) = host {
+        element.push_str(&format!(";host={}", forwarded_value(host)));
+    }
+    let forwarded = match headers.get(FORWARDED) {
+        Some(previous) => format!("{previous}, {element}"),
+        None => element,
+    };
+    headers.insert(FORWARDED, &forwarded);
+}
+
+/// A `Forwarded` parameter value: a token as is, anything else quoted.
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    match is_token {
+        true => value.to_owned(),
+        false => format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")),
+    }
+}
@@ -0,0 +1,83 @@
+//! Predicates that decide whether a route's handler applies to a request beyond its path and
+//! method, so one path can be served by several handlers.
+
+use crate::{
+    headers::{ContentType, Host},
+    request::{Request, Scheme},
+};
+
+pub trait Guard: Send + Sync + 'static {
+    fn check(&self, req: &Request) -> bool;
+
+    fn and<G: Guard>(self, other: G) -> And<Self, G>
+    where
+        Self: Sized,
+    {
+        And(self, other)
+    }
+
+    fn or<G: Guard>(self, other: G) -> Or<Self, G>
+    where
+        Self: Sized,
+    {
+        Or(self, other)
+    }
+}
+
+impl<F> Guard for F
+where
+    F: Fn(&Request) -> bool + Send + Sync + 'static,
+{
+    fn check(&self, req: &Request) -> bool {
+        self(req)
+    }
+}
+
+pub struct And<A, B>(A, B);
+
+impl<A: Guard, B: Guard> Guard for And<A, B> {
+    fn check(&self, req: &Request) -> bool {
+        self.0.check(req) && self.1.check(req)
+    }
+}
+
+pub struct Or<A, B>(A, B);
+
+impl<A: Guard, B: Guard> Guard for Or<A, B> {
+    fn check(&self, req: &Request) -> bool {
+        self.0.check(req) || self.1.check(req)
+    }
+}
+
+pub fn not(guard: impl Guard) -> impl Guard {
+    move |req: &Request| !guard.check(req)
+}
+
+/// The header is present with exactly this value, ignoring ASCII case.
+pub fn header(name: &'static str, value: &'static str) -> impl Guard {
+    move |req: &Request| {
+        req.headers
+            .get_all(name)
+            .any(|v| v.eq_ignore_ascii_case(value))
+    }
+}
+
+/// The request body's media type is `mime`, parameters such as `charset` aside.
+pub fn content_type(mime: &'static str) -> impl Guard {
+    move |req: &Request| match req.headers.typed_get::<ContentType>() {
+        Ok(Some(content_type)) => content_type.mime.eq_ignore_ascii_case(mime),
+        _ => false,
+    }
+}
+
+/// The Host header names `host`, with any port ignored.
+pub fn host(host: &'static str) -> impl Guard {
+    move |req: &Request| match req.headers.typed_get::<Host>() {
+        Ok(Some(header)) => header.hostname().eq_ignore_ascii_case(host),
+        _ => false,
+    }
+}
+
+pub fn scheme(scheme: Scheme) -> impl Guard {
+    move |req: &Request| req.scheme == scheme
+}
@@ -0,0 +1,109 @@
+use std::{future::Future, marker::PhantomData, pin::Pin, sync::Arc};
+
+use crate::{
+    error::HttpError,
+    extract::FromRequest,
+    request::Request,
+    response::{IntoResponse, Response},
+};
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>>;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response, HttpError>> + Send + 'static,
+{
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        Box::pin(self(req))
+    }
+}
+
+/// Anything that can be registered on a route: either a plain `Fn(Request)` handler or an
+/// async function whose parameters are all [`FromRequest`] extractors, returning anything
+/// that implements [`IntoResponse`].
+///
+/// `Args` only exists to keep the implementations for different arities apart.
+pub trait IntoHandler<Args>: Send + Sync + 'static {
+    fn into_handler(self) -> Arc<dyn Handler>;
+}
+
+struct RequestHandler<F>(F);
+
+impl<F, Fut> Handler for RequestHandler<F>
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoResponse,
+{
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let fut = (self.0)(req);
+        Box::pin(async move { fut.await.into_response() })
+    }
+}
+
+impl<F, Fut> IntoHandler<Request> for F
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future + Send + 'static,
+    Fut::Output: IntoResponse,
+{
+    fn into_handler(self) -> Arc<dyn Handler> {
+        Arc::new(RequestHandler(self))
+    }
+}
+
+struct ExtractorHandler<F, Args> {
+    f: Arc<F>,
+    _args: PhantomData<fn() -> Args>,
+}
+
+macro_rules! impl_extractor_handler {
+    ($($ty:ident),*) => {
+        impl<F, Fut, $($ty,)*> Handler for ExtractorHandler<F, ($($ty,)*)>
+        where
+            F: Fn($($ty,)*) -> Fut + Send + Sync + 'static,
+            Fut: Future + Send + 'static,
+            Fut::Output: IntoResponse,
+            $($ty: FromRequest,)*
+        {
+            #[allow(non_snake_case, unused_mut, unused_variables)]
+            fn call(&self, mut req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+                let f = self.f.clone();
+                Box::pin(async move {
+                    $(let $ty = $ty::from_request(&mut req).await?;)*
+                    f($($ty,)*).await.into_response()
+                })
+            }
+        }
+
+        impl<F, Fut, $($ty,)*> IntoHandler<($($ty,)*)> for F
+        where
+            F: Fn($($ty,)*) -> Fut + Send + Sync + 'static,
+            Fut: Future + Send + 'static,
+            Fut::Output: IntoResponse,
+            $($ty: FromRequest,)*
+        {
+            fn into_handler(self) -> Arc<dyn Handler> {
+                Arc::new(ExtractorHandler {
+                    f: Arc::new(self),
+                    _args: PhantomData::<fn() -> ($($ty,)*)>,
+                })
+            }
+        }
+    };
+}
+
+impl_extractor_handler!();
+impl_extractor_handler!(T1);
+impl_extractor_handler!(T1, T2);
+impl_extractor_handler!(T1, T2, T3);
+impl_extractor_handler!(T1, T2, T3, T4);
+impl_extractor_handler!(T1, T2, T3, T4, T5);
+impl_extractor_handler!(T1, T2, T3, T4, T5, T6);
+impl_extractor_handler!(T1, T2, T3, T4, T5, T6, T7);
+impl_extractor_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
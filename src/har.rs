@@ -0,0 +1,418 @@
+//! Requests and responses recorded into HAR files, which browser devtools and most HTTP
+//! debugging tools open as they are, for looking at what a client that reported a problem
+//! actually sent and got.
+//!
+//! Every request the [`Har`] middleware sees goes into the file for the window it started in,
+//! like `20241010T130000Z.har` for an hour starting then: heads in full, bodies up to a size
+//! cap. Each file is valid HAR after every entry, so it can be opened while the server is still
+//! writing to it. Response heads are as the handler answered, without what the server adds as
+//! it writes them (`Content-Length`, `Connection` and the like), and file bodies are recorded
+//! by their size alone.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures_util::Stream;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, ReadBuf};
+use tracing::warn;
+
+use crate::{
+    access_log::{civil, rfc3339_time},
+    body::Body,
+    error::HttpError,
+    handler::BoxFuture,
+    headers::{HeaderMap, CONTENT_ENCODING, CONTENT_TYPE, HOST, LOCATION},
+    middleware::{Middleware, Next},
+    request::Request,
+    response::{Response, ResponseBody},
+    streaming::Frame,
+};
+
+/// What every file ends with, and what an entry is written over.
+const CLOSING: &[u8] = b"]}}";
+
+/// Records every request it sees. Clones write to the same files.
+#[derive(Clone)]
+pub struct Har {
+    max_body: usize,
+    window: Duration,
+    files: Arc<Files>,
+}
+
+impl Har {
+    /// Records into files in `dir`, which is created if need be, one per hour, with the first
+    /// 64 KiB of each body.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            max_body: 64 * 1024,
+            window: Duration::from_secs(3600),
+            files: Arc::new(Files {
+                dir,
+                open: Mutex::new(None),
+            }),
+        })
+    }
+
+    /// Starts a new file every `window`, counted from the Unix epoch so that windows start on
+    /// the hour, or the minute, when they divide it.
+    pub fn window(mut self, window: Duration) -> Self {
+        self.window = window.max(Duration::from_secs(1));
+        self
+    }
+
+    /// Records up to `max_body` bytes of each body, and only the size of the rest.
+    pub fn max_body(mut self, max_body: usize) -> Self {
+        self.max_body = max_body;
+        self
+    }
+}
+
+impl Middleware for Har {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let har = self.clone();
+        Box::pin(async move {
+            let started = SystemTime::now();
+            let clock = Instant::now();
+            let request = request_json(&req);
+            let request_mime = req.header(CONTENT_TYPE).unwrap_or_default().to_owned();
+            let request_body = Arc::new(Mutex::new(Captured::new(har.max_body)));
+            let body = std::mem::replace(&mut req.body, Body::empty());
+            let capture = request_body.clone();
+            req.body = body.inspect(move |chunk| capture.lock().unwrap().push(chunk));
+            let version = req.version.clone();
+            let result = next.run(req).await;
+            let (status, headers) = match &result {
+                Ok(response) => (response.status, &response.headers),
+                Err(e) => (e.status, &e.headers),
+            };
+            let mut pending = Pending {
+                har: har.clone(),
+                started,
+                clock,
+                waited: clock.elapsed(),
+                request,
+                request_mime,
+                request_body,
+                response: json!({
+                    "status": status.as_u16(),
+                    "statusText": status.reason(),
+                    "httpVersion": version,
+                    "cookies": [],
+                    "headers": headers_json(headers),
+                    "redirectURL": headers.get(LOCATION).unwrap_or_default(),
+                    "headersSize": -1,
+                }),
+                response_mime: headers.get(CONTENT_TYPE).unwrap_or_default().to_owned(),
+                encoded: headers.contains(CONTENT_ENCODING),
+                response_body: Captured::new(har.max_body),
+                comment: None,
+            };
+            let mut response = match result {
+                Ok(response) => response,
+                Err(e) => {
+                    pending.comment = Some("the body was the error handler's");
+                    return Err(e);
+                }
+            };
+            // Pending entries are written once they're dropped, with their body read in full.
+            response.body = match std::mem::replace(&mut response.body, ResponseBody::Empty) {
+                ResponseBody::Bytes(bytes) => {
+                    pending.response_body.push(&bytes);
+                    ResponseBody::Bytes(bytes)
+                }
+                ResponseBody::Reader(reader) => ResponseBody::Reader(Box::new(Recorded {
+                    inner: reader,
+                    pending,
+                })),
+                ResponseBody::Stream(stream) => ResponseBody::Stream(Box::pin(Recorded {
+                    inner: stream,
+                    pending,
+                })),
+                ResponseBody::File { file, len } => {
+                    pending.response_body.size = len;
+                    pending.comment = Some("file bodies aren't recorded");
+                    ResponseBody::File { file, len }
+                }
+                body @ (ResponseBody::Empty | ResponseBody::Omitted) => body,
+            };
+            Ok(response)
+        })
+    }
+}
+
+/// The first bytes of a body, and how long it was in all.
+struct Captured {
+    bytes: Vec<u8>,
+    size: u64,
+    max: usize,
+}
+
+impl Captured {
+    fn new(max: usize) -> Self {
+        Self {
+            bytes: Vec::new(),
+            size: 0,
+            max,
+        }
+    }
+
+    fn push(&mut self, chunk: &[u8]) {
+        let room = self.max.saturating_sub(self.bytes.len());
+        self.bytes
+            .extend_from_slice(&chunk[..chunk.len().min(room)]);
+        self.size += chunk.len() as u64;
+    }
+
+    /// The body as HAR has it: as text if it's UTF-8 and wasn't encoded, in base64 if not.
+    fn content(&self, mime: &str, encoded: bool) -> Value {
+        let mut content = json!({ "size": self.size, "mimeType": mime });
+        if self.bytes.is_empty() {
+            return content;
+        }
+        match std::str::from_utf8(&self.bytes) {
+            Ok(text) if !encoded => content["text"] = text.into(),
+            _ => {
+                content["text"] = STANDARD.encode(&self.bytes).into();
+                content["encoding"] = "base64".into();
+            }
+        }
+        if (self.bytes.len() as u64) < self.size {
+            content["comment"] = format!("only the first {} bytes were recorded", self.max).into();
+        }
+        content
+    }
+}
+
+/// An entry waiting for its response body to go out, written when it's dropped.
+struct Pending {
+    har: Har,
+    started: SystemTime,
+    clock: Instant,
+    /// How long the handler took to answer.
+    waited: Duration,
+    request: Value,
+    request_mime: String,
+    request_body: Arc<Mutex<Captured>>,
+    response: Value,
+    response_mime: String,
+    /// Whether the response has a `Content-Encoding`, making its body binary whatever type it
+    /// says it is.
+    encoded: bool,
+    response_body: Captured,
+    comment: Option<&'static str>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let mut request = std::mem::take(&mut self.request);
+        let request_body = self.request_body.lock().unwrap();
+        request["bodySize"] = request_body.size.into();
+        if request_body.size > 0 {
+            let mut post_data = request_body.content(&self.request_mime, false);
+            post_data.as_object_mut().unwrap().remove("size");
+            request["postData"] = post_data;
+        }
+        let mut response = std::mem::take(&mut self.response);
+        let mut content = self
+            .response_body
+            .content(&self.response_mime, self.encoded);
+        if let Some(comment) = self.comment {
+            content["comment"] = comment.into();
+        }
+        response["content"] = content;
+        response["bodySize"] = self.response_body.size.into();
+        let total = self.clock.elapsed();
+        let entry = json!({
+            "startedDateTime": rfc3339_time(self.started),
+            "time": millis(total),
+            "request": request,
+            "response": response,
+            "cache": {},
+            "timings": {
+                "send": 0,
+                "wait": millis(self.waited),
+                "receive": millis(total - self.waited),
+            },
+        });
+        self.har.files.append(self.har.window, self.started, &entry);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    (duration.as_secs_f64() * 1_000_000.0).round() / 1000.0
+}
+
+fn request_json(req: &Request) -> Value {
+    let url = match req.target.starts_with('/') {
+        true => {
+            let host = req.header(HOST).unwrap_or("localhost");
+            format!("{}://{host}{}", req.scheme.as_str(), req.target)
+        }
+        // An absolute-form or authority-form target, for a proxy.
+        false => req.target.clone(),
+    };
+    let query = serde_urlencoded::from_str::<Vec<(String, String)>>(&req.query)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect::<Vec<_>>();
+    json!({
+        "method": req.method.as_str(),
+        "url": url,
+        "httpVersion": req.version,
+        "cookies": [],
+        "headers": headers_json(&req.headers),
+        "queryString": query,
+        "headersSize": -1,
+    })
+}
+
+fn headers_json(headers: &HeaderMap) -> Value {
+    headers
+        .iter()
+        .map(|(name, value)| json!({ "name": name, "value": value }))
+        .collect()
+}
+
+/// A response body recorded as it's sent.
+struct Recorded<B> {
+    inner: B,
+    pending: Pending,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for Recorded<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = polled {
+            self.pending.response_body.push(&buf.filled()[filled..]);
+        }
+        polled
+    }
+}
+
+impl<S: Stream<Item = io::Result<Frame>> + Unpin> Stream for Recorded<S> {
+    type Item = io::Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let polled = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(Frame::Data(data)))) = &polled {
+            let data = data.clone();
+            self.pending.response_body.push(&data);
+        }
+        polled
+    }
+}
+
+/// The directory the files go in, and the one being written to.
+struct Files {
+    dir: PathBuf,
+    open: Mutex<Option<WindowFile>>,
+}
+
+struct WindowFile {
+    /// The window's number, counted in windows since the epoch.
+    window: u64,
+    file: File,
+    empty: bool,
+}
+
+impl Files {
+    fn append(&self, window: Duration, started: SystemTime, entry: &Value) {
+        let since_epoch = started.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let number = since_epoch.as_secs() / window.as_secs();
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        if open.as_ref().is_none_or(|file| file.window != number) {
+            let start = UNIX_EPOCH + Duration::from_secs(number * window.as_secs());
+            match self.open_window(start) {
+                Ok((file, empty)) => {
+                    *open = Some(WindowFile {
+                        window: number,
+                        file,
+                        empty,
+                    })
+                }
+                Err(e) => return warn!("Error opening a HAR file in {}: {e}", self.dir.display()),
+            }
+        }
+        let open = open.as_mut().unwrap();
+        let mut bytes = match open.empty {
+            true => Vec::new(),
+            false => b",".to_vec(),
+        };
+        serde_json::to_writer(&mut bytes, entry).unwrap();
+        bytes.extend_from_slice(CLOSING);
+        let written = open
+            .file
+            .seek(SeekFrom::End(-(CLOSING.len() as i64)))
+            .and_then(|_| open.file.write_all(&bytes));
+        match written {
+            Ok(()) => open.empty = false,
+            Err(e) => warn!("Error writing to a HAR file in {}: {e}", self.dir.display()),
+        }
+    }
+
+    /// The file for the window starting at `start`, picking up where a previous run left off
+    /// if it wrote one, and whether it has no entries yet.
+    fn open_window(&self, start: SystemTime) -> io::Result<(File, bool)> {
+        let (year, month, day, secs) = civil(start);
+        let stem = format!(
+            "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60
+        );
+        // Written out by hand, since the entries have to come last.
+        let head = format!(
+            r#"{{"log":{{"version":"1.2","creator":{{"name":"{}","version":"{}"}},"entries":[]}}}}"#,
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        );
+        for attempt in 0.. {
+            let name = match attempt {
+                0 => format!("{stem}.har"),
+                n => format!("{stem}-{n}.har"),
+            };
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(self.dir.join(name))?;
+            let len = file.metadata()?.len();
+            if len == 0 {
+                file.write_all(head.as_bytes())?;
+                return Ok((file, true));
+            }
+            // One that doesn't end like this one would isn't ours to append to.
+            let mut end = [0; CLOSING.len()];
+            if len >= end.len() as u64 {
+                file.seek(SeekFrom::End(-(end.len() as i64)))?;
+                file.read_exact(&mut end)?;
+                if end == CLOSING {
+                    return Ok((file, len == head.len() as u64));
+                }
+            }
+        }
+        unreachable!("one of the names is free")
+    }
+}
@@ -0,0 +1,675 @@
+//! Header names, an ordered multi-value header map, and typed parsers for the headers the
+//! routes care about.
+
+use std::fmt::Write;
+
+use crate::{error::HttpError, status::StatusCode};
+
+pub const ACCEPT: &str = "Accept";
+pub const ACCEPT_ENCODING: &str = "Accept-Encoding";
+pub const ACCEPT_RANGES: &str = "Accept-Ranges";
+pub const ACCESS_CONTROL_ALLOW_CREDENTIALS: &str = "Access-Control-Allow-Credentials";
+pub const ACCESS_CONTROL_ALLOW_HEADERS: &str = "Access-Control-Allow-Headers";
+pub const ACCESS_CONTROL_ALLOW_METHODS: &str = "Access-Control-Allow-Methods";
+pub const ACCESS_CONTROL_ALLOW_ORIGIN: &str = "Access-Control-Allow-Origin";
+pub const ACCESS_CONTROL_EXPOSE_HEADERS: &str = "Access-Control-Expose-Headers";
+pub const ACCESS_CONTROL_MAX_AGE: &str = "Access-Control-Max-Age";
+pub const ACCESS_CONTROL_REQUEST_HEADERS: &str = "Access-Control-Request-Headers";
+pub const ACCESS_CONTROL_REQUEST_METHOD: &str = "Access-Control-Request-Method";
+pub const ALLOW: &str = "Allow";
+pub const AUTHORIZATION: &str = "Authorization";
+pub const CACHE_CONTROL: &str = "Cache-Control";
+pub const CONNECTION: &str = "Connection";
+pub const CONTENT_ENCODING: &str = "Content-Encoding";
+pub const CONTENT_LENGTH: &str = "Content-Length";
+pub const CONTENT_RANGE: &str = "Content-Range";
+pub const CONTENT_SECURITY_POLICY: &str = "Content-Security-Policy";
+pub const CONTENT_TYPE: &str = "Content-Type";
+pub const COOKIE: &str = "Cookie";
+pub const DATE: &str = "Date";
+pub const ETAG: &str = "ETag";
+pub const EXPIRES: &str = "Expires";
+pub const FORWARDED: &str = "Forwarded";
+pub const HOST: &str = "Host";
+pub const IF_MODIFIED_SINCE: &str = "If-Modified-Since";
+pub const IF_NONE_MATCH: &str = "If-None-Match";
+pub const KEEP_ALIVE: &str = "Keep-Alive";
+pub const LAST_MODIFIED: &str = "Last-Modified";
+pub const LOCATION: &str = "Location";
+pub const ORIGIN: &str = "Origin";
+pub const PROXY: &str = "Proxy";
+pub const PROXY_AUTHENTICATE: &str = "Proxy-Authenticate";
+pub const PROXY_AUTHORIZATION: &str = "Proxy-Authorization";
+pub const RANGE: &str = "Range";
+pub const REFERER: &str = "Referer";
+pub const REFERRER_POLICY: &str = "Referrer-Policy";
+pub const RETRY_AFTER: &str = "Retry-After";
+pub const SET_COOKIE: &str = "Set-Cookie";
+pub const STRICT_TRANSPORT_SECURITY: &str = "Strict-Transport-Security";
+pub const TE: &str = "TE";
+pub const TRACEPARENT: &str = "traceparent";
+pub const TRACESTATE: &str = "tracestate";
+pub const TRAILER: &str = "Trailer";
+pub const TRANSFER_ENCODING: &str = "Transfer-Encoding";
+pub const UPGRADE: &str = "Upgrade";
+pub const USER_AGENT: &str = "User-Agent";
+pub const VARY: &str = "Vary";
+pub const WWW_AUTHENTICATE: &str = "WWW-Authenticate";
+pub const X_CONTENT_TYPE_OPTIONS: &str = "X-Content-Type-Options";
+pub const X_FORWARDED_FOR: &str = "X-Forwarded-For";
+pub const X_FORWARDED_HOST: &str = "X-Forwarded-Host";
+pub const X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+pub const X_FRAME_OPTIONS: &str = "X-Frame-Options";
+pub const X_HTTP_METHOD_OVERRIDE: &str = "X-HTTP-Method-Override";
+pub const X_REQUEST_ID: &str = "X-Request-Id";
+pub const X_TTL: &str = "X-TTL";
+
+/// Headers in the order they were received or added. Lookups ignore ASCII case and repeated
+/// headers are kept as separate entries.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HeaderMap {
+    entries: Vec<(String, String)>,
+}
+
+impl HeaderMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Adds another value, keeping the existing ones.
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.entries.push((name.to_owned(), value.to_owned()));
+    }
+
+    /// Replaces all existing values of the header.
+    pub fn insert(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(k, _)| !k.eq_ignore_ascii_case(name));
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+
+    /// Parses the header into its typed form, `Ok(None)` if it isn't present.
+    pub fn typed_get<H: Header>(&self) -> Result<Option<H>, HeaderError> {
+        let values = self.get_all(H::NAME).collect::<Vec<_>>();
+        if values.is_empty() {
+            return Ok(None);
+        }
+        H::decode(&values).map(Some)
+    }
+
+    pub fn typed_insert<H: Header>(&mut self, header: &H) {
+        self.insert(H::NAME, &header.encode());
+    }
+}
+
+impl<'a> IntoIterator for &'a HeaderMap {
+    type Item = &'a (String, String);
+    type IntoIter = std::slice::Iter<'a, (String, String)>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum HeaderError {
+    #[error("missing {0} header")]
+    Missing(&'static str),
+    #[error("invalid {name} header: {value:?}")]
+    Invalid { name: &'static str, value: String },
+}
+
+impl HeaderError {
+    fn invalid<H: Header>(values: &[&str]) -> Self {
+        HeaderError::Invalid {
+            name: H::NAME,
+            value: values.join(", "),
+        }
+    }
+}
+
+impl From<HeaderError> for HttpError {
+    fn from(value: HeaderError) -> Self {
+        HttpError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow::anyhow!("Bad request: {value}"),
+        )
+    }
+}
+
+pub trait Header: Sized {
+    const NAME: &'static str;
+
+    /// Receives every value the header appeared with, in order.
+    fn decode(values: &[&str]) -> Result<Self, HeaderError>;
+
+    fn encode(&self) -> String;
+}
+
+/// Splits comma-separated list headers, which may also be spread over multiple lines.
+fn list_items<'a>(values: &'a [&'a str]) -> impl Iterator<Item = &'a str> + 'a {
+    values
+        .iter()
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+}
+
+fn single<'a, H: Header>(values: &[&'a str]) -> Result<&'a str, HeaderError> {
+    match values {
+        [value] => Ok(value.trim()),
+        _ => Err(HeaderError::invalid::<H>(values)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ContentLength(pub u64);
+
+impl Header for ContentLength {
+    const NAME: &'static str = CONTENT_LENGTH;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        // Repeated identical values are tolerated, differing ones are a smuggling vector.
+        let mut lengths = values.iter().map(|v| v.trim().parse::<u64>());
+        let first = lengths
+            .next()
+            .and_then(Result::ok)
+            .ok_or_else(|| HeaderError::invalid::<Self>(values))?;
+        if lengths.any(|length| length.ok() != Some(first)) {
+            return Err(HeaderError::invalid::<Self>(values));
+        }
+        Ok(ContentLength(first))
+    }
+
+    fn encode(&self) -> String {
+        self.0.to_string()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ContentType {
+    /// The lowercased `type/subtype`.
+    pub mime: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn new(mime: &str) -> Self {
+        Self {
+            mime: mime.to_ascii_lowercase(),
+            params: Vec::new(),
+        }
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    pub fn type_(&self) -> &str {
+        self.mime.split('/').next().unwrap_or_default()
+    }
+}
+
+impl Header for ContentType {
+    const NAME: &'static str = CONTENT_TYPE;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        let value = single::<Self>(values)?;
+        let mut parts = value.split(';');
+        let mime = parts.next().unwrap_or_default().trim();
+        if !mime.contains('/') {
+            return Err(HeaderError::invalid::<Self>(values));
+        }
+
+        let params = parts
+            .filter_map(|param| param.split_once('='))
+            .map(|(k, v)| {
+                (
+                    k.trim().to_ascii_lowercase(),
+                    v.trim().trim_matches('"').to_owned(),
+                )
+            })
+            .collect();
+
+        Ok(Self {
+            mime: mime.to_ascii_lowercase(),
+            params,
+        })
+    }
+
+    fn encode(&self) -> String {
+        let mut encoded = self.mime.clone();
+        for (k, v) in &self.params {
+            let _ = write!(encoded, "; {k}={v}");
+        }
+        encoded
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserAgent(pub String);
+
+impl Header for UserAgent {
+    const NAME: &'static str = USER_AGENT;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        single::<Self>(values).map(|v| UserAgent(v.to_owned()))
+    }
+
+    fn encode(&self) -> String {
+        self.0.clone()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Host(pub String);
+
+impl Host {
+    /// The host name without a port.
+    pub fn hostname(&self) -> &str {
+        if self.0.starts_with('[') {
+            return self.0.split_inclusive(']').next().unwrap_or(&self.0);
+        }
+        self.0.split(':').next().unwrap_or(&self.0)
+    }
+}
+
+impl Header for Host {
+    const NAME: &'static str = HOST;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        single::<Self>(values).map(|v| Host(v.to_ascii_lowercase()))
+    }
+
+    fn encode(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// The media ranges a client accepts, each with its quality.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Accept(pub Vec<(String, f32)>);
+
+impl Accept {
+    /// The quality the client assigned to `media_type`, from the most specific range that
+    /// matches it: the type itself, then `type/*`, then `*/*`.
+    pub fn quality(&self, media_type: &str) -> f32 {
+        let kind = media_type.split('/').next().unwrap_or_default();
+        let ranges = [media_type.to_owned(), format!("{kind}/*"), "*/*".to_owned()];
+        ranges
+            .iter()
+            .find_map(|range| {
+                let found = self.0.iter().find(|(r, _)| r.eq_ignore_ascii_case(range));
+                found.map(|(_, q)| *q)
+            })
+            .unwrap_or(0.0)
+    }
+
+    /// Whether the client would rather have `media_type` than `over`.
+    pub fn prefers(&self, media_type: &str, over: &str) -> bool {
+        self.quality(media_type) > self.quality(over)
+    }
+}
+
+impl Header for Accept {
+    const NAME: &'static str = ACCEPT;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        list_items(values)
+            .map(|item| {
+                let mut parts = item.split(';');
+                let range = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+                let quality = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .next()
+                    .map(|q| q.parse::<f32>())
+                    .transpose()
+                    .map_err(|_| HeaderError::invalid::<Self>(values))?
+                    .unwrap_or(1.0);
+                Ok((range, quality))
+            })
+            .collect::<Result<_, _>>()
+            .map(Accept)
+    }
+
+    fn encode(&self) -> String {
+        self.0
+            .iter()
+            .map(|(range, q)| match *q == 1.0 {
+                true => range.clone(),
+                false => format!("{range};q={q}"),
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcceptEncoding(pub Vec<(String, f32)>);
+
+impl AcceptEncoding {
+    /// The quality the client assigned to `encoding`, taking `*` into account.
+    pub fn quality(&self, encoding: &str) -> f32 {
+        let exact = self
+            .0
+            .iter()
+            .find(|(e, _)| e.eq_ignore_ascii_case(encoding));
+        let wildcard = self.0.iter().find(|(e, _)| e == "*");
+        match (exact, wildcard) {
+            (Some((_, q)), _) | (None, Some((_, q))) => *q,
+            // identity is acceptable unless explicitly refused
+            (None, None) if encoding == "identity" => 1.0,
+            (None, None) => 0.0,
+        }
+    }
+
+    pub fn accepts(&self, encoding: &str) -> bool {
+        self.quality(encoding) > 0.0
+    }
+
+    /// The supported encoding with the highest quality, earlier entries in `supported` winning ties.
+    pub fn preferred<'a>(&self, supported: &[&'a str]) -> Option<&'a str> {
+        supported
+            .iter()
+            .map(|e| (*e, self.quality(e)))
+            .filter(|(_, q)| *q > 0.0)
+            .fold(None, |best: Option<(&str, f32)>, (e, q)| match best {
+                Some((_, best_q)) if best_q >= q => best,
+                _ => Some((e, q)),
+            })
+            .map(|(e, _)| e)
+    }
+}
+
+impl Header for AcceptEncoding {
+    const NAME: &'static str = ACCEPT_ENCODING;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        list_items(values)
+            .map(|item| {
+                let mut parts = item.split(';');
+                let encoding = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+                let quality = parts
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .next()
+                    .map(|q| q.parse::<f32>())
+                    .transpose()
+                    .map_err(|_| HeaderError::invalid::<Self>(values))?
+                    .unwrap_or(1.0);
+                Ok((encoding, quality))
+            })
+            .collect::<Result<_, _>>()
+            .map(AcceptEncoding)
+    }
+
+    fn encode(&self) -> String {
+        self.0
+            .iter()
+            .map(|(e, q)| {
+                if *q == 1.0 {
+                    e.clone()
+                } else {
+                    format!("{e};q={q}")
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ByteRange {
+    /// `start-end`, both inclusive.
+    FromTo(u64, u64),
+    /// `start-`
+    From(u64),
+    /// `-len`, the last `len` bytes.
+    Suffix(u64),
+}
+
+impl ByteRange {
+    /// Resolves the range against a resource of `len` bytes into an inclusive `(start, end)`,
+    /// `None` if it's unsatisfiable.
+    pub fn resolve(&self, len: u64) -> Option<(u64, u64)> {
+        let (start, end) = match *self {
+            ByteRange::FromTo(start, end) => (start, end.min(len.checked_sub(1)?)),
+            ByteRange::From(start) => (start, len.checked_sub(1)?),
+            ByteRange::Suffix(0) => return None,
+            ByteRange::Suffix(suffix) => (len.saturating_sub(suffix), len.checked_sub(1)?),
+        };
+        (start <= end && start < len).then_some((start, end))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Range(pub Vec<ByteRange>);
+
+impl Header for Range {
+    const NAME: &'static str = RANGE;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        let invalid = || HeaderError::invalid::<Self>(values);
+        let ranges = single::<Self>(values)?
+            .strip_prefix("bytes=")
+            .ok_or_else(invalid)?;
+
+        let ranges = ranges
+            .split(',')
+            .map(str::trim)
+            .map(|range| {
+                let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+                let parse = |v: &str| v.trim().parse::<u64>().map_err(|_| invalid());
+                match (start.trim().is_empty(), end.trim().is_empty()) {
+                    (false, false) => {
+                        let (start, end) = (parse(start)?, parse(end)?);
+                        if start > end {
+                            return Err(invalid());
+                        }
+                        Ok(ByteRange::FromTo(start, end))
+                    }
+                    (false, true) => Ok(ByteRange::From(parse(start)?)),
+                    (true, false) => Ok(ByteRange::Suffix(parse(end)?)),
+                    (true, true) => Err(invalid()),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if ranges.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Range(ranges))
+    }
+
+    fn encode(&self) -> String {
+        let ranges = self
+            .0
+            .iter()
+            .map(|range| match range {
+                ByteRange::FromTo(start, end) => format!("{start}-{end}"),
+                ByteRange::From(start) => format!("{start}-"),
+                ByteRange::Suffix(len) => format!("-{len}"),
+            })
+            .collect::<Vec<_>>();
+        format!("bytes={}", ranges.join(","))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag {
+    pub weak: bool,
+    /// The opaque tag without quotes.
+    pub tag: String,
+}
+
+impl ETag {
+    pub fn strong(tag: impl Into<String>) -> Self {
+        Self {
+            weak: false,
+            tag: tag.into(),
+        }
+    }
+
+    pub fn weak(tag: impl Into<String>) -> Self {
+        Self {
+            weak: true,
+            tag: tag.into(),
+        }
+    }
+
+    /// Weak comparison, which is what If-None-Match uses.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+
+    fn parse(value: &str) -> Option<Self> {
+        let (weak, quoted) = match value.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, value),
+        };
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        Some(Self {
+            weak,
+            tag: tag.to_owned(),
+        })
+    }
+}
+
+impl Header for ETag {
+    const NAME: &'static str = ETAG;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        ETag::parse(single::<Self>(values)?).ok_or_else(|| HeaderError::invalid::<Self>(values))
+    }
+
+    fn encode(&self) -> String {
+        if self.weak {
+            format!("W/\"{}\"", self.tag)
+        } else {
+            format!("\"{}\"", self.tag)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IfNoneMatch {
+    Any,
+    Tags(Vec<ETag>),
+}
+
+impl IfNoneMatch {
+    /// Whether a resource with `etag` matches, i.e. a GET should answer 304.
+    pub fn matches(&self, etag: &ETag) -> bool {
+        match self {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Tags(tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        }
+    }
+}
+
+impl Header for IfNoneMatch {
+    const NAME: &'static str = IF_NONE_MATCH;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        if values.iter().any(|v| v.trim() == "*") {
+            return Ok(IfNoneMatch::Any);
+        }
+        list_items(values)
+            .map(|item| ETag::parse(item).ok_or_else(|| HeaderError::invalid::<Self>(values)))
+            .collect::<Result<_, _>>()
+            .map(IfNoneMatch::Tags)
+    }
+
+    fn encode(&self) -> String {
+        match self {
+            IfNoneMatch::Any => "*".to_owned(),
+            IfNoneMatch::Tags(tags) => tags.iter().map(ETag::encode).collect::<Vec<_>>().join(", "),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location(pub String);
+
+impl Header for Location {
+    const NAME: &'static str = LOCATION;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        single::<Self>(values).map(|v| Location(v.to_owned()))
+    }
+
+    fn encode(&self) -> String {
+        self.0.clone()
+    }
+}
+
+/// The `Connection` header's options, lowercased.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Connection(pub Vec<String>);
+
+impl Connection {
+    pub fn has(&self, option: &str) -> bool {
+        self.0.iter().any(|o| o.eq_ignore_ascii_case(option))
+    }
+
+    pub fn is_close(&self) -> bool {
+        self.has("close")
+    }
+
+    pub fn is_keep_alive(&self) -> bool {
+        self.has("keep-alive")
+    }
+}
+
+impl Header for Connection {
+    const NAME: &'static str = CONNECTION;
+
+    fn decode(values: &[&str]) -> Result<Self, HeaderError> {
+        Ok(Connection(
+            list_items(values).map(str::to_ascii_lowercase).collect(),
+        ))
+    }
+
+    fn encode(&self) -> String {
+        self.0.join(", ")
+    }
+}
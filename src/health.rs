@@ -0,0 +1,95 @@
+//! Endpoints for Kubernetes probes and load balancer health checks.
+//!
+//! - `GET /healthz` answers 200 whenever the server gets to answer at all, for a liveness
+//!   probe: if it doesn't, the process is stuck and wants restarting.
+//! - `GET /readyz` answers 200 when every check given with [`Health::check`] passes and 503
+//!   when one doesn't, for a readiness probe: the server is up but shouldn't get traffic for
+//!   now. The body says how each check went:
+//!
+//! ```text
+//! {"checks":{"connections":"2048 of 2048 connections open","directory":"ok"},"ready":false}
+//! ```
+
+use std::sync::Arc;
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    error::HttpError,
+    headers::{CACHE_CONTROL, CONTENT_TYPE},
+    response::Response,
+    router::Router,
+    status::StatusCode,
+};
+
+type Check = dyn Fn() -> Result<(), String> + Send + Sync;
+
+/// What `/readyz` checks.
+#[derive(Clone, Default)]
+pub struct Health {
+    checks: Vec<(&'static str, Arc<Check>)>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a check that fails with why the server isn't ready. Checks run on the blocking
+    /// pool, so they can look at the file system.
+    pub fn check<F>(mut self, name: &'static str, check: F) -> Self
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.checks.push((name, Arc::new(check)));
+        self
+    }
+
+    /// Adds the two endpoints to `router`.
+    pub fn routes(self, router: Router) -> Router {
+        let health = Arc::new(self);
+        router
+            .get("/healthz", || async {
+                probe(StatusCode::OK, json!({ "status": "ok" }))
+            })
+            .get("/readyz", move || {
+                let health = health.clone();
+                async move { health.ready().await }
+            })
+    }
+
+    async fn ready(self: Arc<Self>) -> Result<Response, HttpError> {
+        let checked = tokio::task::spawn_blocking(move || {
+            self.checks
+                .iter()
+                .map(|(name, check)| (*name, check()))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(anyhow::Error::new)?;
+        let ready = checked.iter().all(|(_, result)| result.is_ok());
+        let checks = checked
+            .into_iter()
+            .map(|(name, result)| {
+                (
+                    name.to_owned(),
+                    Value::from(result.err().unwrap_or("ok".to_owned())),
+                )
+            })
+            .collect::<Map<_, _>>();
+        let status = match ready {
+            true => StatusCode::OK,
+            false => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        Ok(probe(status, json!({ "ready": ready, "checks": checks })))
+    }
+}
+
+/// A probe's answer, which nothing on the way should cache.
+fn probe(status: StatusCode, body: Value) -> Response {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .header(CACHE_CONTROL, "no-store")
+        .body(body.to_string())
+}
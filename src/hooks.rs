@@ -0,0 +1,213 @@
+//! Callbacks the server makes as connections and requests come and go, for embedders that
+//! keep accounts of their own (per-tenant bandwidth, billing, connection tracking) without
+//! forking the accept loop.
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! use http_server_starter_rust::{hooks::Hooks, router::Router, server::Server};
+//!
+//! let hooks = Hooks::new()
+//!     .on_connection_close(|connection, closed| {
+//!         println!("{:?} made {} requests", connection.remote_addr, closed.requests)
+//!     })
+//!     .on_request_finish(|_, request| println!("{} {} {}", request.method, request.path, request.status));
+//! let server = Server::new(Arc::new(Router::new())).hooks(hooks);
+//! ```
+//!
+//! They run on the connection's task, in the order they were added, so they should be quick:
+//! anything slow belongs on a channel to a task of its own.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::request::{Request, Scheme};
+
+type ConnectionHook = dyn Fn(&Connection) + Send + Sync;
+type CloseHook = dyn Fn(&Connection, &Closed) + Send + Sync;
+type StartHook = dyn Fn(&Connection, &Request) + Send + Sync;
+type FinishHook = dyn Fn(&Connection, &Finished) + Send + Sync;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A connection being served.
+#[derive(Debug, Clone)]
+pub struct Connection {
+    /// Unique within the process, counting up from 1.
+    pub id: u64,
+    pub remote_addr: Option<SocketAddr>,
+    pub scheme: Scheme,
+    /// The session's details, for connections over TLS.
+    pub tls: Option<TlsInfo>,
+}
+
+/// What a TLS handshake settled on.
+#[derive(Debug, Clone, Default)]
+pub struct TlsInfo {
+    /// Like `TLSv1_3`.
+    pub version: Option<String>,
+    /// Like `TLS13_AES_256_GCM_SHA384`.
+    pub cipher: Option<String>,
+    /// The protocol agreed through ALPN, like `http/1.1`.
+    pub alpn: Option<String>,
+    /// The host name the client asked for with SNI.
+    pub server_name: Option<String>,
+    /// The subject of the client's certificate, if it sent one.
+    pub client_subject: Option<String>,
+}
+
+/// How a connection went, once it's closed.
+#[derive(Debug, Clone)]
+pub struct Closed {
+    /// Requests read on it, answered or not.
+    pub requests: usize,
+    pub duration: Duration,
+}
+
+/// How a request went, once its response is written or given up on.
+#[derive(Debug, Clone)]
+pub struct Finished {
+    /// `-` for requests that didn't parse.
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    /// The body bytes written, 0 if the response was cut short.
+    pub bytes_sent: u64,
+    /// From the request's head being read to its response being written.
+    pub duration: Duration,
+    /// Whether the response went out in full, rather than failing or timing out.
+    pub completed: bool,
+}
+
+/// The callbacks to make.
+#[derive(Clone, Default)]
+pub struct Hooks {
+    opened: Vec<Arc<ConnectionHook>>,
+    closed: Vec<Arc<CloseHook>>,
+    started: Vec<Arc<StartHook>>,
+    finished: Vec<Arc<FinishHook>>,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Called once a connection is ready for requests, after its TLS handshake when it has
+    /// one. Connections turned away or failing the handshake aren't.
+    pub fn on_connection_open<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Connection) + Send + Sync + 'static,
+    {
+        self.opened.push(Arc::new(hook));
+        self
+    }
+
+    /// Called when a connection opened is done with, however it ended.
+    pub fn on_connection_close<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Connection, &Closed) + Send + Sync + 'static,
+    {
+        self.closed.push(Arc::new(hook));
+        self
+    }
+
+    /// Called with every request that parsed, before the handler gets it.
+    pub fn on_request_start<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Connection, &Request) + Send + Sync + 'static,
+    {
+        self.started.push(Arc::new(hook));
+        self
+    }
+
+    /// Called for every request read, parsed or not, once its response is written or given up
+    /// on.
+    pub fn on_request_finish<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Connection, &Finished) + Send + Sync + 'static,
+    {
+        self.finished.push(Arc::new(hook));
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self.opened.is_empty()
+            && self.closed.is_empty()
+            && self.started.is_empty()
+            && self.finished.is_empty()
+    }
+
+    /// Calls the open hooks for a connection, returning what calls the rest, or `None` if
+    /// there are no hooks at all.
+    pub(crate) fn open(
+        &self,
+        remote_addr: Option<SocketAddr>,
+        scheme: Scheme,
+        tls: Option<TlsInfo>,
+    ) -> Option<Tracked> {
+        if self.is_empty() {
+            return None;
+        }
+        let connection = Connection {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            remote_addr,
+            scheme,
+            tls,
+        };
+        for hook in &self.opened {
+            hook(&connection);
+        }
+        Some(Tracked {
+            hooks: self.clone(),
+            connection,
+            opened: Instant::now(),
+            requests: 0,
+        })
+    }
+}
+
+/// An open connection with hooks, calling the close ones when it's dropped.
+pub(crate) struct Tracked {
+    hooks: Hooks,
+    connection: Connection,
+    opened: Instant,
+    pub(crate) requests: usize,
+}
+
+impl Tracked {
+    /// Whether the finish hooks want to hear about requests, which takes their method and path.
+    pub(crate) fn wants_finished(&self) -> bool {
+        !self.hooks.finished.is_empty()
+    }
+
+    pub(crate) fn started(&self, request: &Request) {
+        for hook in &self.hooks.started {
+            hook(&self.connection, request);
+        }
+    }
+
+    pub(crate) fn finished(&self, finished: &Finished) {
+        for hook in &self.hooks.finished {
+            hook(&self.connection, finished);
+        }
+    }
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let closed = Closed {
+            requests: self.requests,
+            duration: self.opened.elapsed(),
+        };
+        for hook in &self.hooks.closed {
+            hook(&self.connection, &closed);
+        }
+    }
+}
@@ -0,0 +1,153 @@
+//! Hotlink protection: keeping other sites from embedding this one's images and videos, and
+//! having it pay for their bandwidth.
+//!
+//! A request for a protected file type has to come from a page on an allowed host, going by
+//! its `Referer`, or `Origin` when there's no `Referer`. Pages on the host the request was
+//! made to are always allowed. Requests with neither header are let through unless told
+//! otherwise, as browsers leave them out for privacy's sake and when the file is opened
+//! directly. The others get a 403, or a placeholder image in place of the real one.
+
+use std::path::Path;
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use tracing::debug;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::{CACHE_CONTROL, CONTENT_TYPE, HOST, ORIGIN, REFERER},
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+    safe_path,
+    static_files::content_type,
+};
+
+/// The file types protected unless told otherwise.
+pub const DEFAULT_EXTENSIONS: [&str; 12] = [
+    "png", "jpg", "jpeg", "gif", "webp", "avif", "svg", "mp4", "webm", "mov", "m4v", "ogv",
+];
+
+/// Refuses requests for images and videos embedded by pages on other sites.
+#[derive(Debug, Clone)]
+pub struct Hotlink {
+    /// Hosts, or `.example.com` for a domain and its subdomains.
+    hosts: Vec<String>,
+    extensions: Vec<String>,
+    block_empty: bool,
+    placeholder: Option<(Bytes, &'static str)>,
+}
+
+impl Hotlink {
+    /// Lets pages on the `allowed` hosts embed files, each host like `example.com` or
+    /// `*.example.com` for its subdomains too.
+    pub fn new<S: AsRef<str>>(allowed: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            hosts: allowed
+                .into_iter()
+                .map(|host| {
+                    let host = host.as_ref().to_ascii_lowercase();
+                    host.strip_prefix('*').map(str::to_owned).unwrap_or(host)
+                })
+                .collect(),
+            extensions: DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect(),
+            block_empty: false,
+            placeholder: None,
+        }
+    }
+
+    /// Protects files with these extensions instead of [`DEFAULT_EXTENSIONS`].
+    pub fn extensions<S: AsRef<str>>(mut self, extensions: impl IntoIterator<Item = S>) -> Self {
+        self.extensions = extensions
+            .into_iter()
+            .map(|e| e.as_ref().trim_start_matches('.').to_ascii_lowercase())
+            .collect();
+        self
+    }
+
+    /// Also refuses requests that say nothing about the page they came from.
+    pub fn block_empty(mut self, block_empty: bool) -> Self {
+        self.block_empty = block_empty;
+        self
+    }
+
+    /// Answers refused requests with the file at `path` rather than a 403.
+    pub fn placeholder(mut self, path: &Path) -> anyhow::Result<Self> {
+        let body = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        self.placeholder = Some((body.into(), content_type(path)));
+        Ok(self)
+    }
+
+    fn protects(&self, path: &str) -> bool {
+        let Ok(path) = safe_path::resolve(path) else {
+            return false;
+        };
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        name.rsplit_once('.').is_some_and(|(_, extension)| {
+            self.extensions
+                .iter()
+                .any(|protected| protected.eq_ignore_ascii_case(extension))
+        })
+    }
+
+    fn allows(&self, req: &Request) -> bool {
+        let from = req.header(REFERER).or_else(|| req.header(ORIGIN));
+        let Some(from) = from.filter(|from| !from.is_empty() && *from != "null") else {
+            return !self.block_empty;
+        };
+        let Some(from) = host_of(from) else {
+            return false;
+        };
+        let own = req.header(HOST).map(strip_port);
+        own.is_some_and(|own| own.eq_ignore_ascii_case(&from))
+            || self.hosts.iter().any(|host| match host.strip_prefix('.') {
+                Some(domain) => {
+                    from == domain
+                        || from
+                            .strip_suffix(host.as_str())
+                            .is_some_and(|sub| !sub.is_empty())
+                }
+                None => from == *host,
+            })
+    }
+}
+
+/// The lowercased host of a URL like `https://example.com:8443/page`.
+fn host_of(url: &str) -> Option<String> {
+    let (_, rest) = url.split_once("://")?;
+    let authority = rest.split(['/', '?', '#']).next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    Some(strip_port(host).to_ascii_lowercase()).filter(|host| !host.is_empty())
+}
+
+fn strip_port(host: &str) -> &str {
+    match host.rsplit_once(':') {
+        // An IPv6 address in brackets has colons of its own.
+        Some((host, port)) if !port.contains(']') => host,
+        _ => host,
+    }
+}
+
+impl Middleware for Hotlink {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if !req.method.is_safe() || !self.protects(&req.path) || self.allows(&req) {
+            return Box::pin(next.run(req));
+        }
+        debug!(
+            "Refusing hotlinked {} from {:?}",
+            req.path,
+            req.header(REFERER).or_else(|| req.header(ORIGIN))
+        );
+        let refused = match &self.placeholder {
+            Some((body, content_type)) => Ok(Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .header(CACHE_CONTROL, "no-store")
+                .body(body.clone())),
+            None => Err(HttpError::forbidden()),
+        };
+        Box::pin(async { refused })
+    }
+}
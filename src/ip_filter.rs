@@ -0,0 +1,214 @@
+//! Allowing and denying clients by address.
+//!
+//! [`IpRules`] are a list of ranges to allow and one to deny; an address is let in if it's in
+//! none of the denied ranges and, when there are allowed ones, in one of those. The server
+//! checks its peers against them as it accepts connections (see [`Server::ip_rules`]), closing
+//! on those that aren't let in without a word, and [`IpFilter`] checks the client of each
+//! request under a prefix, answering a 403. The second sees the address
+//! [`TrustedProxies`](crate::forwarded::TrustedProxies) resolved, so it goes after them; the
+//! first only sees the proxies, whose own addresses always pass it.
+//!
+//! Requests over a Unix socket have no address, and aren't filtered.
+//!
+//! [`Server::ip_rules`]: crate::server::Server::ip_rules
+
+use std::{net::IpAddr, sync::Arc};
+
+use tracing::warn;
+
+use crate::{
+    error::HttpError,
+    forwarded::{self, Cidr},
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+    tarpit,
+};
+
+/// Ranges of addresses to allow and to deny.
+#[derive(Debug, Clone, Default)]
+pub struct IpRules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    trusted: Vec<Cidr>,
+}
+
+impl IpRules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets in only the addresses in these ranges, and those given in other calls.
+    pub fn allow(mut self, ranges: impl IntoIterator<Item = Cidr>) -> Self {
+        self.allow.extend(ranges);
+        self
+    }
+
+    /// Keeps out the addresses in these ranges, even ones also allowed.
+    pub fn deny(mut self, ranges: impl IntoIterator<Item = Cidr>) -> Self {
+        self.deny.extend(ranges);
+        self
+    }
+
+    /// Lets these proxies connect whatever the other rules say, so that the clients behind
+    /// them can be checked on their requests.
+    pub fn trust(mut self, proxies: impl IntoIterator<Item = Cidr>) -> Self {
+        self.trusted.extend(proxies);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allow.is_empty() && self.deny.is_empty()
+    }
+
+    /// Whether `ip` is let in.
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    /// Whether a connection from `peer` is accepted.
+    pub fn admits(&self, peer: IpAddr) -> bool {
+        self.trusted.iter().any(|cidr| cidr.contains(peer)) || self.allows(peer)
+    }
+}
+
+/// Answers requests under a prefix from clients the rules don't let in with a 403.
+#[derive(Clone)]
+pub struct IpFilter {
+    prefix: String,
+    rules: Arc<IpRules>,
+}
+
+impl IpFilter {
+    /// Filters every request under `prefix`, `/` for all of them.
+    pub fn new(prefix: impl Into<String>, rules: IpRules) -> Self {
+        Self {
+            prefix: prefix.into(),
+            rules: Arc::new(rules),
+        }
+    }
+}
+
+impl Middleware for IpFilter {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let denied = forwarded::client_ip(&req)
+            .filter(|ip| req.path.starts_with(&self.prefix) && !self.rules.allows(*ip));
+        let Some(ip) = denied else {
+            return Box::pin(next.run(req));
+        };
+        warn!("Denying {} to {ip}", req.path);
+        tarpit::mark(&req);
+        Box::pin(async { Err(HttpError::forbidden()) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::*;
+    use crate::{request::Method, status::StatusCode};
+
+    fn ranges(ranges: &[&str]) -> Vec<Cidr> {
+        ranges.iter().map(|range| range.parse().unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn matches_prefixes() {
+        let [v4, odd, one, any, v6] = [
+            "10.0.0.0/8",
+            "192.168.4.0/22",
+            "192.0.2.7",
+            "0.0.0.0/0",
+            "fd00::/8",
+        ]
+        .map(|range| range.parse::<Cidr>().unwrap());
+        assert!(v4.contains(ip("10.255.0.1")));
+        assert!(!v4.contains(ip("11.0.0.1")));
+        // Prefixes that don't end on a byte.
+        assert!(odd.contains(ip("192.168.7.255")));
+        assert!(!odd.contains(ip("192.168.8.0")));
+        assert!(!odd.contains(ip("192.168.3.255")));
+        assert!(one.contains(ip("192.0.2.7")));
+        assert!(!one.contains(ip("192.0.2.8")));
+        assert!(any.contains(ip("203.0.113.1")));
+        assert!(v6.contains(ip("fd12:3456::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+    }
+
+    #[test]
+    fn matches_mapped_ipv4() {
+        let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(v4.contains(ip("::ffff:10.1.2.3")));
+        assert!(!v4.contains(ip("::ffff:11.1.2.3")));
+        // Families don't otherwise mix.
+        assert!(!v4.contains(ip("::a01:203")));
+        assert!(!"::/0".parse::<Cidr>().unwrap().contains(ip("10.1.2.3")));
+    }
+
+    #[test]
+    fn refuses_malformed_ranges() {
+        for range in [
+            "10.0.0.0/33",
+            "fd00::/129",
+            "10.0.0.0/",
+            "10.0.0/8",
+            "10.0.0.0/-1",
+            "all",
+        ] {
+            assert!(range.parse::<Cidr>().is_err(), "{range}");
+        }
+    }
+
+    #[test]
+    fn denied_ranges_win() {
+        let rules = IpRules::new()
+            .allow(ranges(&["10.0.0.0/8"]))
+            .deny(ranges(&["10.0.0.0/24"]))
+            .trust(ranges(&["192.0.2.1"]));
+        assert!(rules.allows(ip("10.1.0.1")));
+        assert!(!rules.allows(ip("10.0.0.1")));
+        assert!(!rules.allows(ip("172.16.0.1")));
+        // Proxies get connected whatever the rules say, their clients are checked on requests.
+        assert!(!rules.allows(ip("192.0.2.1")));
+        assert!(rules.admits(ip("192.0.2.1")));
+
+        let deny_only = IpRules::new().deny(ranges(&["10.0.0.0/8"]));
+        assert!(deny_only.allows(ip("172.16.0.1")));
+        assert!(IpRules::new().is_empty() && IpRules::new().allows(ip("10.0.0.1")));
+    }
+
+    #[tokio::test]
+    async fn filters_requests_under_the_prefix() {
+        let rules = IpRules::new().allow(ranges(&["10.0.0.0/8"]));
+        let filter: Arc<[Arc<dyn Middleware>]> =
+            Arc::from([Arc::new(IpFilter::new("/admin/", rules)) as Arc<dyn Middleware>]);
+        let status = |path: &'static str, peer: Option<&str>| {
+            let mut req = Request::new(Method::Get, path);
+            req.remote_addr = peer.map(|peer| SocketAddr::new(ip(peer), 40000));
+            let ok = |_| async { Ok(Response::empty(StatusCode::NO_CONTENT)) };
+            let next = Next::new(filter.clone(), Arc::new(ok));
+            async move { next.run(req).await.map_or_else(|e| e.status, |r| r.status) }
+        };
+        assert_eq!(
+            status("/admin/stats", Some("10.0.0.1")).await,
+            StatusCode::NO_CONTENT
+        );
+        assert_eq!(
+            status("/admin/stats", Some("172.16.0.1")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status("/files/a", Some("172.16.0.1")).await,
+            StatusCode::NO_CONTENT
+        );
+        // Unix sockets have no address to go by.
+        assert_eq!(status("/admin/stats", None).await, StatusCode::NO_CONTENT);
+    }
+}
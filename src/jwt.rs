@@ -0,0 +1,365 @@
+//! JSON Web Tokens as bearer credentials, for clients that get their tokens from an identity
+//! provider rather than from this server's configuration.
+//!
+//! A [`JwtVerifier`] checks a token's signature against one key, an HS256 secret or an RS256
+//! or ES256 public key, or against the keys an identity provider publishes as a JWK set:
+//!
+//! ```text
+//! --jwt /api/ --jwt-jwks-url https://login.example.com/.well-known/jwks.json \
+//!     --jwt-issuer https://login.example.com/ --jwt-audience files-api
+//! ```
+//!
+//! The JWK set is fetched when it's first needed and kept for [`JWKS_TTL`]; a token signed by
+//! a key it doesn't have (because the provider rotated its keys, say) has it fetched again,
+//! though not more than once every [`JWKS_REFETCH`]. A fetch that fails leaves the keys from
+//! the last one in use.
+//!
+//! Tokens must not have expired, and must name one of the audiences and issuers given, when
+//! any are. Those that pass have their claims put in the request for handlers to take with the
+//! [`Claims`] extractor, and their `sub` as the request's [`Identity`].
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use jsonwebtoken::{jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use tracing::{debug, warn};
+
+use crate::{
+    bearer_auth::{bearer_token, challenge},
+    error::HttpError,
+    extract::FromRequest,
+    handler::BoxFuture,
+    headers::AUTHORIZATION,
+    middleware::{Middleware, Next},
+    request::{Grants, Identity, Request},
+    response::Response,
+    security_log::{self, Event},
+    status::StatusCode,
+};
+
+/// How long a fetched JWK set is used before it's fetched again.
+pub const JWKS_TTL: Duration = Duration::from_secs(10 * 60);
+/// How soon a JWK set can be fetched again for a key it didn't have.
+pub const JWKS_REFETCH: Duration = Duration::from_secs(30);
+const JWKS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Checks tokens' signatures and claims.
+pub struct JwtVerifier {
+    keys: Keys,
+    audiences: Vec<String>,
+    issuers: Vec<String>,
+    leeway: Duration,
+}
+
+enum Keys {
+    Static(DecodingKey, Algorithm),
+    Jwks(Jwks),
+}
+
+/// A JWK set, as last fetched.
+struct Jwks {
+    url: String,
+    fetched: Mutex<Option<(Arc<JwkSet>, Instant)>>,
+    /// Held while fetching, so that requests arriving meanwhile wait for that fetch rather
+    /// than making their own.
+    fetching: tokio::sync::Mutex<()>,
+}
+
+impl JwtVerifier {
+    /// Tokens signed with HS256 and `secret`.
+    pub fn hs256(secret: &[u8]) -> Self {
+        Self::new(Keys::Static(
+            DecodingKey::from_secret(secret),
+            Algorithm::HS256,
+        ))
+    }
+
+    /// Tokens signed with RS256 or ES256, whichever the PEM public key is for.
+    pub fn public_key(pem: &[u8]) -> anyhow::Result<Self> {
+        let keys = match DecodingKey::from_rsa_pem(pem) {
+            Ok(key) => Keys::Static(key, Algorithm::RS256),
+            Err(_) => Keys::Static(
+                DecodingKey::from_ec_pem(pem).context("reading an RSA or EC public key")?,
+                Algorithm::ES256,
+            ),
+        };
+        Ok(Self::new(keys))
+    }
+
+    /// Tokens signed with RS256 or ES256 by one of the keys in the JWK set at `url`.
+    pub fn jwks(url: impl Into<String>) -> Self {
+        Self::new(Keys::Jwks(Jwks {
+            url: url.into(),
+            fetched: Mutex::new(None),
+            fetching: tokio::sync::Mutex::new(()),
+        }))
+    }
+
+    fn new(keys: Keys) -> Self {
+        Self {
+            keys,
+            audiences: Vec::new(),
+            issuers: Vec::new(),
+            leeway: Duration::from_secs(60),
+        }
+    }
+
+    /// Takes only tokens whose `aud` has one of the audiences given this way.
+    pub fn audience(mut self, audience: impl Into<String>) -> Self {
+        self.audiences.push(audience.into());
+        self
+    }
+
+    /// Takes only tokens whose `iss` is one of the issuers given this way.
+    pub fn issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuers.push(issuer.into());
+        self
+    }
+
+    /// How far the clocks here and at the issuer can disagree on `exp` and `nbf`, a minute by
+    /// default.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    /// The claims of `token`, or why it isn't taken.
+    pub async fn verify(&self, token: &str) -> Result<Value, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let (key, algorithm) = match &self.keys {
+            Keys::Static(key, algorithm) => (key.clone(), *algorithm),
+            Keys::Jwks(jwks) => jwks.key(&header).await?,
+        };
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway.as_secs();
+        validation.validate_nbf = true;
+        if self.audiences.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audiences);
+        }
+        if !self.issuers.is_empty() {
+            validation.set_issuer(&self.issuers);
+        }
+        jsonwebtoken::decode::<Value>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())
+    }
+}
+
+impl Jwks {
+    /// The key `header` names, fetching the set again if it's stale or doesn't have it.
+    async fn key(&self, header: &jsonwebtoken::Header) -> Result<(DecodingKey, Algorithm), String> {
+        if !matches!(header.alg, Algorithm::RS256 | Algorithm::ES256) {
+            return Err(format!("{:?} isn't taken from a JWK set", header.alg));
+        }
+        let mut set = self.current(JWKS_TTL).await;
+        if find(set.as_deref(), header).is_none() {
+            set = self.current(JWKS_REFETCH).await;
+        }
+        let jwk = find(set.as_deref(), header).ok_or("no key in the JWK set matches")?;
+        if let Some(algorithm) = &jwk.common.key_algorithm {
+            if algorithm.to_string().parse().ok() != Some(header.alg) {
+                return Err(format!("the key is for {algorithm}, not {:?}", header.alg));
+            }
+        }
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?;
+        Ok((key, header.alg))
+    }
+
+    /// The set, fetched again if it was fetched longer than `max_age` ago.
+    async fn current(&self, max_age: Duration) -> Option<Arc<JwkSet>> {
+        if let Some(set) = self.fresh(max_age) {
+            return Some(set);
+        }
+        let _fetching = self.fetching.lock().await;
+        // Whoever held the lock before may have just fetched it.
+        if let Some(set) = self.fresh(max_age) {
+            return Some(set);
+        }
+        let url = self.url.clone();
+        let result = tokio::task::spawn_blocking(move || fetch(&url))
+            .await
+            .map_err(anyhow::Error::new)
+            .and_then(|fetched| fetched);
+        let mut fetched = self.fetched.lock().unwrap_or_else(|e| e.into_inner());
+        match result {
+            Ok(set) => {
+                debug!("Fetched {} keys from {}", set.keys.len(), self.url);
+                *fetched = Some((Arc::new(set), Instant::now()));
+            }
+            Err(e) => {
+                warn!("Error fetching the JWK set from {}: {e:#}", self.url);
+                // Not tried again for a while; the old keys, if any, do until then.
+                if let Some((_, at)) = &mut *fetched {
+                    *at = Instant::now();
+                }
+            }
+        }
+        fetched.as_ref().map(|(set, _)| set.clone())
+    }
+
+    fn fresh(&self, max_age: Duration) -> Option<Arc<JwkSet>> {
+        let fetched = self.fetched.lock().unwrap_or_else(|e| e.into_inner());
+        let (set, at) = fetched.as_ref()?;
+        (at.elapsed() < max_age).then(|| set.clone())
+    }
+}
+
+/// The key with the `kid` of `header`, or the only key in a set for a token without one.
+fn find<'a>(
+    set: Option<&'a JwkSet>,
+    header: &jsonwebtoken::Header,
+) -> Option<&'a jsonwebtoken::jwk::Jwk> {
+    let set = set?;
+    match &header.kid {
+        Some(kid) => set.find(kid),
+        None => match set.keys.as_slice() {
+            [only] => Some(only),
+            _ => None,
+        },
+    }
+}
+
+fn fetch(url: &str) -> anyhow::Result<JwkSet> {
+    ureq::AgentBuilder::new()
+        .timeout(JWKS_TIMEOUT)
+        .build()
+        .get(url)
+        .call()
+        .context("requesting it")?
+        .into_json()
+        .context("reading it")
+}
+
+/// Asks for a valid JWT on requests under a prefix.
+#[derive(Clone)]
+pub struct JwtAuth {
+    prefix: String,
+    verifier: Arc<JwtVerifier>,
+    realm: String,
+    writes_only: bool,
+    optional: bool,
+}
+
+impl JwtAuth {
+    /// Protects everything under `prefix` with `verifier`, which can be shared between prefixes
+    /// (and should be, for a JWK set to be fetched once).
+    pub fn new(prefix: impl Into<String>, verifier: Arc<JwtVerifier>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            verifier,
+            realm: env!("CARGO_PKG_NAME").to_owned(),
+            writes_only: false,
+            optional: false,
+        }
+    }
+
+    /// The realm the challenge names.
+    pub fn realm(mut self, realm: impl Into<String>) -> Self {
+        self.realm = realm.into();
+        self
+    }
+
+    /// Lets requests with safe methods like `GET` through without a token.
+    pub fn writes_only(mut self, writes_only: bool) -> Self {
+        self.writes_only = writes_only;
+        self
+    }
+
+    /// Lets requests without a token through, anonymous, like
+    /// [`BasicAuth::optional`](crate::basic_auth::BasicAuth::optional).
+    pub fn optional(mut self, optional: bool) -> Self {
+        self.optional = optional;
+        self
+    }
+}
+
+/// The claims of the verified token, in the request's extensions.
+#[derive(Debug, Clone)]
+struct Verified(Value);
+
+impl Middleware for JwtAuth {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if !req.path.starts_with(&self.prefix) || (self.writes_only && req.method.is_safe()) {
+            return Box::pin(next.run(req));
+        }
+        let auth = self.clone();
+        Box::pin(async move {
+            let Some(token) = req.header(AUTHORIZATION).and_then(bearer_token) else {
+                if auth.optional {
+                    return next.run(req).await;
+                }
+                return Err(challenge(&auth.realm, false));
+            };
+            let claims = match auth.verifier.verify(token).await {
+                Ok(claims) => claims,
+                Err(reason) => {
+                    warn!("Refused a JWT for {}: {reason}", req.path);
+                    security_log::record(&req, Event::AuthFailure, format!("JWT: {reason}"));
+                    return Err(challenge(&auth.realm, true));
+                }
+            };
+            if let Some(subject) = claims.get("sub").and_then(Value::as_str) {
+                Identity(subject.to_owned()).assign(&mut req);
+            }
+            req.extensions.insert(Grants {
+                mechanism: "jwt",
+                scopes: strings(claims.get("scope").or_else(|| claims.get("scp"))),
+                roles: strings(claims.get("roles")),
+            });
+            req.extensions.insert(Verified(claims));
+            next.run(req).await
+        })
+    }
+}
+
+/// A claim that's a space-separated string, like OAuth's `scope`, or an array of strings.
+fn strings(claim: Option<&Value>) -> Vec<String> {
+    match claim {
+        Some(Value::String(s)) => s.split_whitespace().map(str::to_owned).collect(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .filter_map(Value::as_str)
+            .map(str::to_owned)
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// The claims of the request's verified JWT, deserialized into `T`; all of them as JSON by
+/// default. Requests that didn't come through a [`JwtAuth`] are refused with a 401.
+pub struct Claims<T = Value>(pub T);
+
+impl<T> FromRequest for Claims<T>
+where
+    T: DeserializeOwned + Send + 'static,
+{
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            let Some(Verified(claims)) = req.extensions.get::<Verified>() else {
+                return Err(HttpError::new(
+                    StatusCode::UNAUTHORIZED,
+                    anyhow::anyhow!("Authentication required"),
+                ));
+            };
+            serde_json::from_value(claims.clone())
+                .map(Claims)
+                .map_err(|e| {
+                    HttpError::new(
+                        StatusCode::FORBIDDEN,
+                        anyhow::anyhow!("The token doesn't have the claims needed: {e}"),
+                    )
+                })
+        })
+    }
+}
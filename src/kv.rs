@@ -0,0 +1,169 @@
+//! A key-value store in memory, behind `/kv/{key}`: a scratch space for demos, and something
+//! to throw load at. Nothing in it survives a restart.
+//!
+//! It holds at most so many entries and so many bytes of keys and values. Once either is
+//! reached, storing more is refused with a 507 rather than anything being evicted, except
+//! entries whose TTL has run out, which are dropped first.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use bytes::Bytes;
+
+use crate::{error::HttpError, status::StatusCode};
+
+/// A value, with the type it was stored as and when it expires, if ever.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub value: Bytes,
+    pub content_type: Option<String>,
+    pub expires: Option<Instant>,
+}
+
+impl Entry {
+    fn size(&self, key: &str) -> u64 {
+        (key.len() + self.value.len()) as u64
+    }
+
+    fn expired(&self, now: Instant) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+#[derive(Debug)]
+pub struct KvStore {
+    max_entries: usize,
+    max_bytes: u64,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<String, Entry>,
+    /// Of all the keys and values.
+    bytes: u64,
+}
+
+impl Inner {
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.bytes -= entry.size(key);
+        Some(entry)
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.remove(&key);
+        }
+    }
+}
+
+impl Default for KvStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KvStore {
+    /// A store of up to 10,000 entries and 64 MiB.
+    pub fn new() -> Self {
+        Self {
+            max_entries: 10_000,
+            max_bytes: 64 * 1024 * 1024,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub fn max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max;
+        self
+    }
+
+    /// The most the keys and values may take up together.
+    pub fn max_bytes(mut self, max: u64) -> Self {
+        self.max_bytes = max;
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn get(&self, key: &str) -> Option<Entry> {
+        let mut inner = self.lock();
+        match inner.entries.get(key) {
+            Some(entry) if entry.expired(Instant::now()) => {
+                inner.remove(key);
+                None
+            }
+            entry => entry.cloned(),
+        }
+    }
+
+    /// Stores `value` under `key`, for `ttl` if given, saying whether it replaced another.
+    pub fn put(
+        &self,
+        key: &str,
+        value: Bytes,
+        content_type: Option<String>,
+        ttl: Option<Duration>,
+    ) -> Result<bool, HttpError> {
+        let now = Instant::now();
+        let entry = Entry {
+            value,
+            content_type,
+            expires: ttl.map(|ttl| now + ttl),
+        };
+        let size = entry.size(key);
+        if size > self.max_bytes {
+            return Err(HttpError::payload_too_large());
+        }
+        let mut inner = self.lock();
+        let old = inner.remove(key);
+        let fits = |inner: &Inner| {
+            inner.entries.len() < self.max_entries && inner.bytes + size <= self.max_bytes
+        };
+        if !fits(&inner) {
+            inner.remove_expired(now);
+        }
+        if !fits(&inner) {
+            // Refused, so it stays as it was.
+            if let Some(old) = old {
+                inner.bytes += old.size(key);
+                inner.entries.insert(key.to_owned(), old);
+            }
+            return Err(HttpError::new(
+                StatusCode(507),
+                anyhow::anyhow!("The store is full"),
+            ));
+        }
+        inner.bytes += size;
+        inner.entries.insert(key.to_owned(), entry);
+        Ok(old.is_some_and(|old| !old.expired(now)))
+    }
+
+    /// Removes what's under `key`, saying whether there was anything.
+    pub fn remove(&self, key: &str) -> bool {
+        let mut inner = self.lock();
+        inner
+            .remove(key)
+            .is_some_and(|entry| !entry.expired(Instant::now()))
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,123 @@
+// Without anyhow, lean-error takes its name, so `anyhow::` means the same everywhere.
+#[cfg(not(any(feature = "cli", feature = "minimal")))]
+compile_error!("build with the `cli` feature, which is on by default, or with `minimal`");
+#[cfg(all(feature = "minimal", not(feature = "cli")))]
+extern crate lean_error as anyhow;
+
+pub mod access_file;
+pub mod access_log;
+#[cfg(feature = "acme")]
+pub mod acme;
+pub mod admin;
+pub mod alert;
+pub mod api_key;
+pub mod audit_log;
+pub mod authz;
+pub mod basic_auth;
+pub mod bearer_auth;
+pub mod body;
+pub mod body_limit;
+pub mod buffer_pool;
+pub mod cache_stats;
+pub mod cgi;
+#[cfg(feature = "compression")]
+pub mod compression;
+pub mod config;
+pub mod content_type;
+pub mod cookie_keys;
+pub mod cookies;
+pub mod cors;
+pub mod csrf;
+#[cfg(unix)]
+pub mod daemon;
+pub mod data_dir;
+pub mod error;
+pub mod extract;
+pub mod fastcgi;
+pub mod forwarded;
+pub mod guard;
+pub mod handler;
+pub mod har;
+pub mod headers;
+pub mod health;
+pub mod hooks;
+pub mod hotlink;
+pub mod ip_filter;
+#[cfg(feature = "jwt")]
+pub mod jwt;
+pub mod kv;
+pub mod listener;
+pub mod load_shed;
+pub mod load_test;
+pub mod lockout;
+pub mod log_file;
+pub mod memory;
+pub mod method_override;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod middleware;
+pub mod min_rate;
+pub mod multipart;
+#[cfg(feature = "native-plugins")]
+pub mod native_plugin;
+pub mod notices;
+pub mod openapi;
+pub mod otlp;
+#[cfg(feature = "thread-per-core")]
+pub mod per_core;
+#[cfg(unix)]
+pub mod privileges;
+pub mod process_stats;
+#[cfg(feature = "profiling")]
+pub mod profiling;
+pub mod proxy;
+pub mod proxy_protocol;
+pub mod rate_limit;
+pub mod redirect;
+pub mod reload;
+pub mod replay;
+pub mod request;
+pub mod request_id;
+pub mod response;
+pub mod rewrite;
+pub mod router;
+pub mod routes;
+pub mod rt;
+pub mod safe_path;
+pub mod secret;
+pub mod security_headers;
+pub mod security_log;
+pub mod served_dir;
+pub mod server;
+pub mod service;
+pub mod session;
+pub mod settings;
+pub mod shortener;
+pub mod signature;
+pub mod sse;
+pub mod state;
+pub mod static_files;
+#[cfg(feature = "metrics")]
+pub mod statsd;
+pub mod status;
+pub mod streaming;
+pub mod tarpit;
+pub mod timeout;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod trace_context;
+pub mod ttl;
+pub mod tunnel;
+pub mod upgrade;
+pub mod upload_expiry;
+pub mod upload_filter;
+pub mod upload_scan;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
+pub mod vhost;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod web_ui;
+pub mod webhook;
+pub mod websocket;
+pub mod wire_trace;
@@ -0,0 +1,388 @@
+//! Opening the sockets the server accepts connections on.
+
+#[cfg(unix)]
+use std::{
+    fs,
+    ops::Range,
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::{
+            fs::{FileTypeExt, PermissionsExt},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    process::{Child, Command},
+};
+use std::{io, net::SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+
+#[cfg(feature = "tls")]
+use crate::tls::TlsAcceptor;
+
+/// A socket [`Server::serve_all`](crate::server::Server::serve_all) accepts connections on.
+pub enum Listener {
+    Tcp(TcpListener),
+    /// HTTPS: every connection does a TLS handshake before its first request.
+    #[cfg(feature = "tls")]
+    Tls(TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+#[cfg(feature = "tls")]
+impl Listener {
+    /// Serves HTTPS instead of plain HTTP on a TCP listener. Unix sockets are left as they
+    /// are: whatever connects to them runs on the same host.
+    pub fn with_tls(self, acceptor: &TlsAcceptor) -> Self {
+        match self {
+            Self::Tcp(listener) => Self::Tls(listener, acceptor.clone()),
+            listener => listener,
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Self {
+        Self::Tcp(listener)
+    }
+}
+
+#[cfg(unix)]
+impl From<UnixListener> for Listener {
+    fn from(listener: UnixListener) -> Self {
+        Self::Unix(listener)
+    }
+}
+
+#[cfg(unix)]
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            #[cfg(feature = "tls")]
+            Self::Tls(listener, _) => listener.as_raw_fd(),
+            Self::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// The listen backlog unless [`Bind::backlog`] says otherwise.
+const DEFAULT_BACKLOG: i32 = 1024;
+/// Where passed sockets start, right after stdin, stdout and stderr.
+#[cfg(unix)]
+const LISTEN_FDS_START: RawFd = 3;
+/// Tells a process started by [`hand_off`] how many sockets it got.
+#[cfg(unix)]
+const INHERITED_FDS: &str = "HTTP_SERVER_INHERITED_FDS";
+
+/// Takes over the sockets systemd bound for this process when it was started by socket
+/// activation, an empty list otherwise. In a `.socket` unit, `Accept=` has to stay `no`.
+///
+/// The `LISTEN_*` variables are removed either way, so CGI scripts don't think the sockets
+/// were meant for them.
+#[cfg(unix)]
+pub fn from_systemd() -> io::Result<Vec<Listener>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    let count = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok());
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(var);
+    }
+    let Some(count) = count.filter(|_| for_us) else {
+        return Ok(Vec::new());
+    };
+
+    adopt(LISTEN_FDS_START..LISTEN_FDS_START + count, "systemd")
+}
+
+/// Wraps descriptors this process was started with as listeners.
+#[cfg(unix)]
+fn adopt(fds: Range<RawFd>, from: &str) -> io::Result<Vec<Listener>> {
+    fds.map(|fd| {
+        // SAFETY: the descriptors were passed to this process alone, and the variable
+        // announcing them was for us, so nothing else in it has claimed them.
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        if !socket.is_listener()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{from} passed descriptor {fd}, which isn't a listening socket"),
+            ));
+        }
+        socket.set_cloexec(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(match socket.local_addr()?.is_unix() {
+            true => Listener::Unix(UnixListener::from_std(socket.into())?),
+            false => Listener::Tcp(TcpListener::from_std(socket.into())?),
+        })
+    })
+    .collect()
+}
+
+/// Starts a new copy of the server, from the binary at the path this one was started from
+/// (so a deploy that replaced it runs the new version) and with the same arguments, handing it
+/// `listeners`. It finds them with [`Inherited::take`] and accepts on them alongside this
+/// process, which can then drain and exit without refusing a single connection.
+#[cfg(unix)]
+pub fn hand_off(listeners: &[RawFd]) -> io::Result<Child> {
+    let count = RawFd::try_from(listeners.len()).expect("too many listeners");
+    // Copies numbered past where the sockets go, so moving one into place can't clobber
+    // another that's still to be moved. They're close-on-exec, leaving only the moved ones.
+    let copies = listeners
+        .iter()
+        .map(|fd| {
+            // SAFETY: F_DUPFD_CLOEXEC only reads `fd` and returns a new descriptor we own.
+            match unsafe { libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, LISTEN_FDS_START + count) } {
+                -1 => Err(io::Error::last_os_error()),
+                copy => Ok(unsafe { OwnedFd::from_raw_fd(copy) }),
+            }
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    let copies = copies.iter().map(AsRawFd::as_raw_fd).collect::<Vec<_>>();
+
+    let mut args = std::env::args_os();
+    let program = args
+        .next()
+        .unwrap_or_else(|| "http-server-starter-rust".into());
+    let mut command = Command::new(program);
+    command.args(args).env(INHERITED_FDS, count.to_string());
+    // SAFETY: between fork and exec, only dup2 runs, which is async-signal-safe, and nothing
+    // allocates.
+    unsafe {
+        command.pre_exec(move || {
+            for (target, fd) in (LISTEN_FDS_START..).zip(&copies) {
+                if libc::dup2(*fd, target) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+/// Whether this process was started by [`hand_off`], to take over from another.
+#[cfg(unix)]
+pub fn is_handed_off() -> bool {
+    std::env::var_os(INHERITED_FDS).is_some()
+}
+
+/// Listening sockets handed over by the process this one replaces through [`hand_off`].
+/// Opening a listener should first try to take over the matching one from here; the ones
+/// left over are served like systemd's.
+#[derive(Default)]
+pub struct Inherited {
+    listeners: Vec<Listener>,
+}
+
+impl Inherited {
+    /// Takes the sockets this process was started with by [`hand_off`], if it was. The
+    /// variable announcing them is removed, as with [`from_systemd`].
+    pub fn take() -> io::Result<Self> {
+        #[cfg(unix)]
+        {
+            let count = std::env::var(INHERITED_FDS)
+                .ok()
+                .and_then(|count| count.parse::<RawFd>().ok());
+            std::env::remove_var(INHERITED_FDS);
+            if let Some(count) = count {
+                let listeners = adopt(
+                    LISTEN_FDS_START..LISTEN_FDS_START + count,
+                    "the previous process",
+                )?;
+                return Ok(Self { listeners });
+            }
+        }
+        Ok(Self::default())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.listeners.is_empty()
+    }
+
+    /// The TCP listener bound to exactly `addr`.
+    pub fn tcp(&mut self, addr: SocketAddr) -> Option<TcpListener> {
+        let index = self.listeners.iter().position(|listener| {
+            matches!(listener, Listener::Tcp(listener) if listener.local_addr().ok() == Some(addr))
+        })?;
+        match self.listeners.swap_remove(index) {
+            Listener::Tcp(listener) => Some(listener),
+            _ => unreachable!(),
+        }
+    }
+
+    /// The Unix listener at `path`.
+    #[cfg(unix)]
+    pub fn unix(&mut self, path: &Path) -> Option<UnixListener> {
+        let index = self.listeners.iter().position(|listener| {
+            matches!(listener, Listener::Unix(listener) if listener
+                .local_addr()
+                .is_ok_and(|addr| addr.as_pathname() == Some(path)))
+        })?;
+        match self.listeners.swap_remove(index) {
+            Listener::Unix(listener) => Some(listener),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Whatever no listener was opened for.
+    pub fn into_listeners(self) -> Vec<Listener> {
+        self.listeners
+    }
+}
+
+/// How to open a listening socket.
+///
+/// An unspecified IPv6 address like `[::]:4221` accepts IPv4 connections too, whatever the
+/// system default is, unless [`v6_only`](Bind::v6_only) is set.
+#[derive(Debug, Clone)]
+pub struct Bind {
+    addr: SocketAddr,
+    v6_only: bool,
+    reuse_port: bool,
+    backlog: i32,
+    send_buffer_size: Option<usize>,
+    recv_buffer_size: Option<usize>,
+}
+
+impl Bind {
+    pub fn new(addr: SocketAddr) -> Self {
+        Self {
+            addr,
+            v6_only: false,
+            reuse_port: false,
+            backlog: DEFAULT_BACKLOG,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+        }
+    }
+
+    /// Keeps an IPv6 socket from accepting IPv4 connections, say to bind `0.0.0.0` separately.
+    pub fn v6_only(mut self, v6_only: bool) -> Self {
+        self.v6_only = v6_only;
+        self
+    }
+
+    /// Sets `SO_REUSEPORT`, so several sockets can listen on the same address; the kernel
+    /// spreads incoming connections across them, letting each have its own accept loop.
+    /// Every socket on the address needs it, and it's only available on Unix.
+    pub fn reuse_port(mut self, reuse_port: bool) -> Self {
+        self.reuse_port = reuse_port;
+        self
+    }
+
+    /// How many connections the kernel queues up until they're accepted; 1024 by default. The
+    /// system caps it, at `net.core.somaxconn` on Linux.
+    pub fn backlog(mut self, backlog: i32) -> Self {
+        self.backlog = backlog;
+        self
+    }
+
+    /// `SO_SNDBUF` for accepted connections, which start with the listening socket's buffers.
+    pub fn send_buffer_size(mut self, bytes: usize) -> Self {
+        self.send_buffer_size = Some(bytes);
+        self
+    }
+
+    /// `SO_RCVBUF` for accepted connections. It has to be set before listening to count
+    /// towards the TCP window the handshake negotiates.
+    pub fn recv_buffer_size(mut self, bytes: usize) -> Self {
+        self.recv_buffer_size = Some(bytes);
+        self
+    }
+
+    /// Opens the socket; this has to run inside the Tokio runtime.
+    pub fn listen(&self) -> io::Result<TcpListener> {
+        let socket = Socket::new(
+            Domain::for_address(self.addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        if self.addr.is_ipv6() {
+            socket.set_only_v6(self.v6_only)?;
+        }
+        // Lets a restarted server bind again while old connections sit in TIME_WAIT.
+        #[cfg(not(windows))]
+        socket.set_reuse_address(true)?;
+        if self.reuse_port {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SO_REUSEPORT isn't available on this platform",
+            ));
+        }
+        if let Some(bytes) = self.send_buffer_size {
+            socket.set_send_buffer_size(bytes)?;
+        }
+        if let Some(bytes) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(bytes)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&self.addr.into())?;
+        socket.listen(self.backlog)?;
+        TcpListener::from_std(socket.into())
+    }
+}
+
+/// How to open a Unix domain socket, for a reverse proxy or sidecar on the same host.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixBind {
+    path: PathBuf,
+    mode: Option<u32>,
+}
+
+#[cfg(unix)]
+impl UnixBind {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            mode: None,
+        }
+    }
+
+    /// Permissions for the socket file, like `0o660` to let a group connect. Otherwise they
+    /// follow the umask.
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Opens the socket, first removing one a previous run left behind. A socket something is
+    /// still accepting on, or a file that isn't a socket, is left alone and makes this fail.
+    pub fn listen(&self) -> io::Result<UnixListener> {
+        if let Ok(metadata) = fs::symlink_metadata(&self.path) {
+            if !metadata.file_type().is_socket() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{} exists and isn't a socket", self.path.display()),
+                ));
+            }
+            match std::os::unix::net::UnixStream::connect(&self.path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another server", self.path.display()),
+                    ))
+                }
+                Err(_) => fs::remove_file(&self.path)?,
+            }
+        }
+
+        let listener = UnixListener::bind(&self.path)?;
+        if let Some(mode) = self.mode {
+            fs::set_permissions(&self.path, fs::Permissions::from_mode(mode))?;
+        }
+        Ok(listener)
+    }
+}
@@ -0,0 +1,88 @@
+//! Capping the requests handled at once, server-wide, and turning away the rest.
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::warn;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+};
+
+/// Lets at most `max_in_flight` requests be handled at once, across every router it's a layer
+/// of (clones share the count). Further ones get a 503 straight away, or with a
+/// [`queue`](Self::queue), wait their turn: only up to its length and timeout, so a server
+/// that can't keep up says so instead of piling up work it'll finish too late. Unlike
+/// `max_connections`, idle keep-alive connections don't count.
+///
+/// A request stops counting when its handler returns, so a response body still being sent
+/// from a file or stream doesn't.
+#[derive(Clone)]
+pub struct LoadShed {
+    permits: Arc<Semaphore>,
+    waiting: Arc<AtomicUsize>,
+    queue: usize,
+    queue_timeout: Duration,
+}
+
+impl LoadShed {
+    pub fn new(max_in_flight: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max_in_flight)),
+            waiting: Arc::default(),
+            queue: 0,
+            queue_timeout: Duration::ZERO,
+        }
+    }
+
+    /// Lets up to `len` requests over the limit wait for up to `timeout` each.
+    pub fn queue(mut self, len: usize, timeout: Duration) -> Self {
+        self.queue = len;
+        self.queue_timeout = timeout;
+        self
+    }
+
+    async fn admit(&self) -> Option<OwnedSemaphorePermit> {
+        if let Ok(permit) = self.permits.clone().try_acquire_owned() {
+            return Some(permit);
+        }
+        let queued = self
+            .waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < self.queue).then_some(waiting + 1)
+            });
+        if queued.is_err() {
+            return None;
+        }
+        let permit = tokio::time::timeout(self.queue_timeout, self.permits.clone().acquire_owned());
+        let permit = permit.await;
+        self.waiting.fetch_sub(1, Ordering::AcqRel);
+        permit.ok()?.ok()
+    }
+}
+
+impl Middleware for LoadShed {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let shed = self.clone();
+        Box::pin(async move {
+            let Some(_permit) = shed.admit().await else {
+                warn!(
+                    "Too many requests in flight, shedding {} {}",
+                    req.method, req.target
+                );
+                return Err(HttpError::service_unavailable());
+            };
+            next.run(req).await
+        })
+    }
+}
@@ -0,0 +1,299 @@
+//! A small load generator: a number of GET requests sent to a server over keep-alive
+//! connections, a few at a time, measuring how long each takes to be answered in full.
+//!
+//! It's behind `--self-test`, which starts the configured site on a loopback port and points
+//! this at it, so that the cost of a new middleware shows up as a drop in requests per second
+//! or a longer tail without setting up a separate tool.
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use tokio::{
+    io::{AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{
+    body::{BodyReader, Framing},
+    proxy::{read_response_head, response_framing},
+    request::Method,
+};
+
+/// One of the URLs requested, as `/path`, or `host/path` to pick a virtual host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub host: String,
+    pub path: String,
+}
+
+impl std::str::FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some(slash) = s.find('/') else {
+            return Err(format!("expected /path or host/path, got {s:?}"));
+        };
+        let (host, path) = s.split_at(slash);
+        if path
+            .chars()
+            .any(|c| c.is_ascii_whitespace() || c.is_ascii_control())
+        {
+            return Err(format!("{path:?} isn't a valid request target"));
+        }
+        Ok(Self {
+            host: match host {
+                "" => "localhost".to_owned(),
+                host => host.to_owned(),
+            },
+            path: path.to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.host.as_str() {
+            "localhost" => f.write_str(&self.path),
+            host => write!(f, "{host}{}", self.path),
+        }
+    }
+}
+
+/// `requests` requests to `addr`, spread evenly over the targets, with `concurrency` of them
+/// in flight at a time.
+#[derive(Debug, Clone)]
+pub struct LoadTest {
+    addr: SocketAddr,
+    targets: Vec<Target>,
+    requests: usize,
+    concurrency: usize,
+}
+
+impl LoadTest {
+    pub fn new(addr: SocketAddr, targets: Vec<Target>) -> Self {
+        Self {
+            addr,
+            targets,
+            requests: 1000,
+            concurrency: 16,
+        }
+    }
+
+    pub fn requests(mut self, requests: usize) -> Self {
+        self.requests = requests;
+        self
+    }
+
+    pub fn concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Sends every request and waits for the answers. A request that fails, because the
+    /// connection broke or the response didn't parse, is counted as an error rather than
+    /// stopping the rest.
+    pub async fn run(self) -> anyhow::Result<Report> {
+        anyhow::ensure!(!self.targets.is_empty(), "nothing to request");
+        let test = Arc::new(self);
+        let next = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+        let clients = (0..test.concurrency.min(test.requests))
+            .map(|_| tokio::spawn(client(test.clone(), next.clone())))
+            .collect::<Vec<_>>();
+        let mut results = Vec::with_capacity(test.requests);
+        for client in clients {
+            results.extend(client.await.context("running a load test client")?);
+        }
+        let elapsed = start.elapsed();
+
+        let mut report = Report {
+            elapsed,
+            concurrency: test.concurrency,
+            targets: test
+                .targets
+                .iter()
+                .map(|target| (target.to_string(), TargetReport::default()))
+                .collect(),
+        };
+        for (target, result) in results {
+            let target = &mut report.targets[target].1;
+            match result {
+                Ok((status, latency)) => {
+                    *target.statuses.entry(status).or_default() += 1;
+                    target.latencies.push(latency);
+                }
+                Err(e) => {
+                    if target.errors == 0 {
+                        target.first_error = Some(format!("{e:#}"));
+                    }
+                    target.errors += 1;
+                }
+            }
+        }
+        for (_, target) in &mut report.targets {
+            target.latencies.sort_unstable();
+        }
+        Ok(report)
+    }
+}
+
+type Outcome = (usize, anyhow::Result<(u16, Duration)>);
+
+/// Takes requests off the shared counter until there are none left, reusing its connection
+/// for as long as the server keeps it open.
+async fn client(test: Arc<LoadTest>, next: Arc<AtomicUsize>) -> Vec<Outcome> {
+    let mut results = Vec::new();
+    let mut connection = None;
+    loop {
+        let i = next.fetch_add(1, Ordering::Relaxed);
+        if i >= test.requests {
+            return results;
+        }
+        let target = i % test.targets.len();
+        let start = Instant::now();
+        let result = request(&test, &test.targets[target], &mut connection).await;
+        if result.is_err() {
+            connection = None;
+        }
+        results.push((target, result.map(|status| (status, start.elapsed()))));
+    }
+}
+
+/// Sends one request on `connection`, opening it first if need be, and reads the whole
+/// response, returning its status.
+async fn request(
+    test: &LoadTest,
+    target: &Target,
+    connection: &mut Option<BufReader<TcpStream>>,
+) -> anyhow::Result<u16> {
+    if connection.is_none() {
+        let stream = TcpStream::connect(test.addr)
+            .await
+            .with_context(|| format!("connecting to {}", test.addr))?;
+        stream.set_nodelay(true)?;
+        *connection = Some(BufReader::new(stream));
+    }
+    let stream = connection.as_mut().expect("connected above");
+    let head = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\n\r\n",
+        target.path, target.host
+    );
+    stream
+        .get_mut()
+        .write_all(head.as_bytes())
+        .await
+        .context("sending the request")?;
+    let response = read_response_head(stream).await?;
+    let framing = response_framing(&Method::Get, response.status, &response.headers)?;
+    let reuse = response.keep_alive && framing != Framing::UntilClose;
+    let mut body = BodyReader {
+        reader: &mut *stream,
+        framing,
+    };
+    tokio::io::copy(&mut body, &mut tokio::io::sink())
+        .await
+        .context("reading the response body")?;
+    if !reuse {
+        *connection = None;
+    }
+    Ok(response.status.0)
+}
+
+/// What a [`LoadTest`] measured.
+#[derive(Debug)]
+pub struct Report {
+    pub elapsed: Duration,
+    pub concurrency: usize,
+    /// Every target's results, in the order they were given.
+    pub targets: Vec<(String, TargetReport)>,
+}
+
+#[derive(Debug, Default)]
+pub struct TargetReport {
+    /// How many responses came back with each status code.
+    pub statuses: BTreeMap<u16, usize>,
+    /// How long each answered request took, shortest first.
+    pub latencies: Vec<Duration>,
+    /// Requests that got no response.
+    pub errors: usize,
+    pub first_error: Option<String>,
+}
+
+impl TargetReport {
+    /// The latency `p` (between 0 and 1) of the answered requests came in under.
+    pub fn percentile(&self, p: f64) -> Option<Duration> {
+        let rank = (p * self.latencies.len() as f64).ceil() as usize;
+        self.latencies.get(rank.saturating_sub(1)).copied()
+    }
+}
+
+impl Report {
+    /// How many requests were answered, of any status.
+    pub fn answered(&self) -> usize {
+        self.targets.iter().map(|(_, t)| t.latencies.len()).sum()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.targets.iter().map(|(_, t)| t.errors).sum()
+    }
+
+    pub fn requests_per_second(&self) -> f64 {
+        self.answered() as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{} requests in {:.2}s, {} at a time: {:.0} requests/s",
+            self.answered() + self.errors(),
+            self.elapsed.as_secs_f64(),
+            self.concurrency,
+            self.requests_per_second(),
+        )?;
+        for (name, target) in &self.targets {
+            let statuses = target
+                .statuses
+                .iter()
+                .map(|(status, count)| format!("{count}× {status}"))
+                .chain((target.errors > 0).then(|| format!("{}× error", target.errors)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            write!(f, "  {name}: {statuses}")?;
+            if let (Some(p50), Some(p90), Some(p99), Some(max)) = (
+                target.percentile(0.5),
+                target.percentile(0.9),
+                target.percentile(0.99),
+                target.latencies.last(),
+            ) {
+                write!(
+                    f,
+                    "; p50 {}, p90 {}, p99 {}, max {}",
+                    millis(p50),
+                    millis(p90),
+                    millis(p99),
+                    millis(*max),
+                )?;
+            }
+            writeln!(f)?;
+            if let Some(e) = &target.first_error {
+                writeln!(f, "    first error: {e}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2}ms", duration.as_secs_f64() * 1000.0)
+}
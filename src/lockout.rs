@@ -0,0 +1,174 @@
+//! Locking out clients that keep getting their password wrong, so that guessing one through
+//! [Basic authentication](crate::basic_auth) on a server anyone can reach takes years rather
+//! than an afternoon.
+//!
+//! Failures are counted for the client's address and, separately, for the user it tried, so
+//! that spreading guesses over many users or many addresses doesn't get around it. Past the
+//! allowed failures, each one locks the address or user out for twice as long as the last,
+//! up to a maximum, and requests from it get a 429 with `Retry-After` without their password
+//! being checked at all. Counts are forgotten after a while without failures, and a user's on
+//! logging in.
+//!
+//! Locking out users means someone who knows a user's name can keep them locked out too; the
+//! lockouts are kept short to begin with so that it takes that someone some effort.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use tracing::warn;
+
+/// The most addresses and users tracked at once, past which the ones that failed longest ago
+/// are forgotten.
+const MAX_TRACKED: usize = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Ip(IpAddr),
+    User(String),
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Key::Ip(ip) => write!(f, "{ip}"),
+            Key::User(user) => write!(f, "user {user:?}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// The failed logins of each address and user. Shared by everything that checks passwords.
+#[derive(Debug)]
+pub struct Lockout {
+    allowed: u32,
+    first: Duration,
+    max: Duration,
+    forget_after: Duration,
+    failures: Mutex<HashMap<Key, Failures>>,
+}
+
+impl Default for Lockout {
+    fn default() -> Self {
+        Self {
+            allowed: 5,
+            first: Duration::from_secs(1),
+            max: Duration::from_secs(15 * 60),
+            forget_after: Duration::from_secs(15 * 60),
+            failures: Mutex::default(),
+        }
+    }
+}
+
+impl Lockout {
+    /// Allows five failures, then locks out for a second, doubling up to 15 minutes, and
+    /// forgets failures after 15 minutes without one.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How many failures in a row go without a lockout.
+    pub fn allowed(mut self, allowed: u32) -> Self {
+        self.allowed = allowed;
+        self
+    }
+
+    /// How long the first lockout lasts.
+    pub fn first(mut self, first: Duration) -> Self {
+        self.first = first;
+        self
+    }
+
+    /// The longest a lockout lasts, however many failures there have been.
+    pub fn max(mut self, max: Duration) -> Self {
+        self.max = max;
+        self
+    }
+
+    /// How long since the last failure, with no lockout left, before the count starts over.
+    pub fn forget_after(mut self, forget_after: Duration) -> Self {
+        self.forget_after = forget_after;
+        self
+    }
+
+    fn keys(ip: Option<IpAddr>, user: &str) -> impl Iterator<Item = Key> {
+        ip.map(Key::Ip)
+            .into_iter()
+            .chain(Some(Key::User(user.to_owned())))
+    }
+
+    /// How much longer `ip` or `user` is locked out for, if either is.
+    pub fn locked(&self, ip: Option<IpAddr>, user: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        Self::keys(ip, user)
+            .filter_map(|key| failures.get(&key)?.locked_until)
+            .filter_map(|until| until.checked_duration_since(now))
+            .filter(|left| !left.is_zero())
+            .max()
+    }
+
+    /// Counts a failed login by `ip` as `user`.
+    pub fn fail(&self, ip: Option<IpAddr>, user: &str) {
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+        if failures.len() >= MAX_TRACKED {
+            self.prune(&mut failures, now);
+        }
+        for key in Self::keys(ip, user) {
+            let entry = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+                locked_until: None,
+            });
+            if self.forgotten(entry, now) {
+                entry.count = 0;
+            }
+            entry.count = entry.count.saturating_add(1);
+            entry.last = now;
+            if entry.count > self.allowed {
+                let doublings = (entry.count - self.allowed - 1).min(31);
+                let lockout = self.first.saturating_mul(1 << doublings).min(self.max);
+                entry.locked_until = Some(now + lockout);
+                warn!(
+                    "Locking out {key} for {}s after {} failed logins",
+                    lockout.as_secs_f64().ceil(),
+                    entry.count
+                );
+            }
+        }
+    }
+
+    /// Forgets the failures of `user`, who just logged in. The address's are kept, so that
+    /// logging in to one account doesn't let it go on guessing at others.
+    pub fn succeed(&self, user: &str) {
+        self.failures
+            .lock()
+            .unwrap()
+            .remove(&Key::User(user.to_owned()));
+    }
+
+    fn forgotten(&self, failures: &Failures, now: Instant) -> bool {
+        let locked = failures.locked_until.is_some_and(|until| until > now);
+        !locked && now.duration_since(failures.last) >= self.forget_after
+    }
+
+    fn prune(&self, failures: &mut HashMap<Key, Failures>, now: Instant) {
+        failures.retain(|_, f| !self.forgotten(f, now));
+        if failures.len() >= MAX_TRACKED {
+            let mut lasts: Vec<Instant> = failures.values().map(|f| f.last).collect();
+            let (_, median, _) = lasts.select_nth_unstable(MAX_TRACKED / 2);
+            let median = *median;
+            failures.retain(|_, f| f.last > median);
+        }
+    }
+}
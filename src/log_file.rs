@@ -0,0 +1,214 @@
+//! Log files that rotate themselves: once one grows past a size or a period of time ends, it's
+//! renamed with the date and time after its name, and possibly gzipped, and a new one started.
+//!
+//! For rotating with `logrotate` instead, have it move the file and send a SIGUSR1, which
+//! makes the server [`reopen`] its logs at their paths.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, Weak},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// When a [`LogFile`] is rotated; by default it never is.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Rotates before a write that would take the file past this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotates at every multiple of this since the Unix epoch, so a day's file ends at UTC
+    /// midnight.
+    pub every: Option<Duration>,
+    /// Gzips rotated files, on a thread of their own.
+    #[cfg(feature = "compression")]
+    pub compress: bool,
+}
+
+/// A file opened for appending, shared by whatever writes to it. Every write goes out in one
+/// call, under a lock, so lines written whole don't interleave.
+#[derive(Clone)]
+pub struct LogFile(Arc<Mutex<Inner>>);
+
+struct Inner {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    /// Which period of [`Rotation::every`] the file was started in.
+    period: u64,
+    /// Whether stdout and stderr follow the file to each new one.
+    std_streams: bool,
+}
+
+/// Every log file open, for [`reopen`].
+static OPEN: Mutex<Vec<Weak<Mutex<Inner>>>> = Mutex::new(Vec::new());
+
+impl LogFile {
+    pub fn open(path: &Path, rotation: Rotation) -> io::Result<Self> {
+        let (file, size) = open(path)?;
+        let inner = Inner {
+            path: path.to_owned(),
+            rotation,
+            file,
+            size,
+            period: period(rotation.every),
+            std_streams: false,
+        };
+        let log = Self(Arc::new(Mutex::new(inner)));
+        let mut open = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+        open.retain(|log| log.strong_count() > 0);
+        open.push(Arc::downgrade(&log.0));
+        Ok(log)
+    }
+
+    /// Points stdout and stderr at the file, now and after every rotation, so that panics and
+    /// anything else printed end up in it too.
+    #[cfg(unix)]
+    pub fn capture_std_streams(&self) -> io::Result<()> {
+        let mut inner = self.lock();
+        inner.std_streams = true;
+        redirect_std_streams(&inner.file)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut inner = self.lock();
+        if inner.is_due(buf.len() as u64) {
+            // Errors can't go to the log they're about; stderr is the best there is.
+            if let Err(e) = inner.rotate() {
+                eprintln!("Error rotating log {}: {e}", inner.path.display());
+            }
+        }
+        // All of it, so that a line isn't split across two files by a short write.
+        inner.file.write_all(buf)?;
+        inner.size += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.lock().file.flush()
+    }
+}
+
+impl Inner {
+    fn is_due(&self, adding: u64) -> bool {
+        let too_large = self
+            .rotation
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size + adding > max);
+        too_large || period(self.rotation.every) != self.period
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let rotated = rotated_path(&self.path);
+        fs::rename(&self.path, &rotated)?;
+        self.reopen()?;
+        #[cfg(feature = "compression")]
+        if self.rotation.compress {
+            std::thread::spawn(move || {
+                if let Err(e) = compress(&rotated) {
+                    eprintln!("Error compressing log {}: {e}", rotated.display());
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn reopen(&mut self) -> io::Result<()> {
+        let (file, size) = open(&self.path)?;
+        #[cfg(unix)]
+        if self.std_streams {
+            redirect_std_streams(&file)?;
+        }
+        self.file = file;
+        self.size = size;
+        self.period = period(self.rotation.every);
+        Ok(())
+    }
+}
+
+/// Opens every log file again at its path, for after something else has moved them.
+pub fn reopen() {
+    let open = OPEN.lock().unwrap_or_else(|e| e.into_inner());
+    for log in open.iter().filter_map(Weak::upgrade) {
+        let mut inner = log.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = inner.reopen() {
+            eprintln!("Error reopening log {}: {e}", inner.path.display());
+        }
+    }
+}
+
+fn open(path: &Path) -> io::Result<(File, u64)> {
+    let file = File::options().append(true).create(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok((file, size))
+}
+
+fn period(every: Option<Duration>) -> u64 {
+    let Some(every) = every.filter(|every| !every.is_zero()) else {
+        return 0;
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    (now.as_secs_f64() / every.as_secs_f64()) as u64
+}
+
+/// `access.log` rotated becomes `access.log.20241010-135536`, with a number after that if
+/// another was rotated within the same second.
+fn rotated_path(path: &Path) -> PathBuf {
+    let (year, month, day, secs) = crate::access_log::civil(SystemTime::now());
+    let stamp = format!(
+        "{year:04}{month:02}{day:02}-{:02}{:02}{:02}",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    );
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{stamp}"));
+    let mut rotated = PathBuf::from(&name);
+    let mut n = 1;
+    while rotated.exists() || with_gz(&rotated).exists() {
+        rotated = PathBuf::from(format!("{}-{n}", name.to_string_lossy()));
+        n += 1;
+    }
+    rotated
+}
+
+fn with_gz(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".gz");
+    name.into()
+}
+
+/// Replaces the file with a gzipped copy.
+#[cfg(feature = "compression")]
+fn compress(path: &Path) -> io::Result<()> {
+    use flate2::{write::GzEncoder, Compression};
+
+    let gz = with_gz(path);
+    let mut encoder = GzEncoder::new(File::create(&gz)?, Compression::default());
+    io::copy(&mut File::open(path)?, &mut encoder)?;
+    encoder.finish()?.sync_all()?;
+    fs::remove_file(path)
+}
+
+#[cfg(unix)]
+fn redirect_std_streams(file: &File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    for target in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: dup2 only takes descriptors; the standard streams it replaces aren't owned
+        // by anything that would close them.
+        if unsafe { libc::dup2(file.as_raw_fd(), target) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! A process-wide budget for bodies held in memory: request bodies read whole by
+//! [`Body::to_bytes`](crate::body::Body::to_bytes) (and the extractors built on it) and
+//! responses gzipped by [`Compression`](crate::compression::Compression).
+//!
+//! What they hold is counted from the moment it's read until the [`Bytes`] it ends up in is
+//! dropped. Once the budget is used up, request bodies get a 503 and responses go out
+//! uncompressed, streamed as they are, rather than the process growing without bound under a
+//! flood of large uploads.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use bytes::Bytes;
+
+use crate::{error::HttpError, headers::RETRY_AFTER, status::StatusCode};
+
+static IN_USE: AtomicU64 = AtomicU64::new(0);
+static BUDGET: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Caps what may be held at once, in bytes; `None` for no cap. Memory already held is kept,
+/// so lowering it below what's in use only makes new reservations fail.
+pub fn set_budget(budget: Option<u64>) {
+    BUDGET.store(budget.unwrap_or(u64::MAX), Ordering::Relaxed);
+}
+
+/// How many bytes are held right now.
+pub fn in_use() -> u64 {
+    IN_USE.load(Ordering::Relaxed)
+}
+
+/// Some of the budget, given back when dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    bytes: u64,
+}
+
+impl Reservation {
+    /// Reserves `bytes`, or `None` when that doesn't fit in what's left of the budget.
+    pub fn new(bytes: u64) -> Option<Self> {
+        let mut reservation = Self { bytes: 0 };
+        reservation.resize(bytes).then_some(reservation)
+    }
+
+    /// Grows or shrinks the reservation to `bytes`. Growing it past the budget fails, leaving
+    /// it as it was.
+    pub fn resize(&mut self, bytes: u64) -> bool {
+        if bytes <= self.bytes {
+            IN_USE.fetch_sub(self.bytes - bytes, Ordering::Relaxed);
+            self.bytes = bytes;
+            return true;
+        }
+        let more = bytes - self.bytes;
+        let grown = IN_USE.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |in_use| {
+            in_use
+                .checked_add(more)
+                .filter(|&total| total <= BUDGET.load(Ordering::Relaxed))
+        });
+        if grown.is_ok() {
+            self.bytes = bytes;
+        }
+        grown.is_ok()
+    }
+
+    /// `buf`, holding on to the reservation, grown to its length if there's room, until the
+    /// bytes are dropped.
+    pub fn hold(mut self, buf: Vec<u8>) -> Bytes {
+        self.resize(buf.len() as u64);
+        Bytes::from_owner(Held {
+            buf,
+            _reservation: self,
+        })
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        IN_USE.fetch_sub(self.bytes, Ordering::Relaxed);
+    }
+}
+
+struct Held {
+    buf: Vec<u8>,
+    _reservation: Reservation,
+}
+
+impl AsRef<[u8]> for Held {
+    fn as_ref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+/// The 503 for a request body that doesn't fit in the budget.
+pub fn exhausted() -> HttpError {
+    HttpError::new(
+        StatusCode::SERVICE_UNAVAILABLE,
+        anyhow::anyhow!("Not enough memory to buffer the request body, try again later"),
+    )
+    .with_header(RETRY_AFTER, "1")
+}
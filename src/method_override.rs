@@ -0,0 +1,65 @@
+//! Lets clients behind proxies that only pass GET and POST tunnel other methods through POST.
+
+use std::sync::{Arc, Mutex};
+
+use tracing::{debug, field, Span};
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::X_HTTP_METHOD_OVERRIDE,
+    middleware::{Middleware, Next},
+    request::{Method, Request},
+    response::Response,
+};
+
+/// Replaces the method of POST requests carrying `X-HTTP-Method-Override` with the one named in
+/// the header. Must be a router-wide layer so routing sees the effective method.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MethodOverride;
+
+/// The method a request was overridden to, for the access log, which has already taken the
+/// request's method by the time [`MethodOverride`] runs. The server puts one in a request's
+/// extensions and keeps a clone, which the middleware fills in.
+#[derive(Debug, Clone, Default)]
+pub struct OverriddenMethod(Arc<Mutex<Option<Method>>>);
+
+impl OverriddenMethod {
+    pub fn get(&self) -> Option<Method> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn set(req: &Request, method: &Method) {
+        if let Some(slot) = req.extensions.get::<Self>() {
+            *slot.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(method.clone());
+        }
+    }
+}
+
+impl Middleware for MethodOverride {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if req.method == Method::Post {
+            if let Some(value) = req.header(X_HTTP_METHOD_OVERRIDE) {
+                let method = Method::from(value.trim().to_ascii_uppercase().as_str());
+                // Only methods that could have been sent as a POST in the first place, so the
+                // header can't make a request look safe (GET, HEAD) to later layers.
+                if !matches!(method, Method::Put | Method::Patch | Method::Delete) {
+                    let error =
+                        HttpError::bad_request(&format!("cannot override POST with {method}"));
+                    return Box::pin(async move { Err(error) });
+                }
+                debug!("Overriding method: POST -> {method} {}", req.target);
+                // The request's span was opened with the method it was sent with.
+                Span::current().record("method", field::display(&method));
+                OverriddenMethod::set(&req, &method);
+                req.method = method;
+                req.headers.remove(X_HTTP_METHOD_OVERRIDE);
+            }
+        }
+        Box::pin(next.run(req))
+    }
+}
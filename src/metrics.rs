@@ -0,0 +1,368 @@
+//! Request metrics, exported in the Prometheus text format for scraping.
+//!
+//! Requests are counted by the pattern of the route they matched, like `/echo/{str}`, rather
+//! than their path, which would make a new series of every file ever asked for. Requests no
+//! route matched go under `(none)`, and those a router's fallback answered under `(fallback)`.
+//!
+//! ```text
+//! http_requests_total{route="/echo/{str}",method="GET",status="200"} 3
+//! http_request_duration_seconds_bucket{route="/echo/{str}",method="GET",le="0.005"} 3
+//! ```
+//!
+//! The median and 95th and 99th percentile latencies of every route are estimated from the
+//! histogram's buckets, the way Prometheus's `histogram_quantile` does, for whatever can't work
+//! them out itself.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use serde::Serialize;
+
+use crate::{headers::CONTENT_TYPE, response::Response, server::ConnectionStats};
+
+/// The quantiles estimated for each route.
+const QUANTILES: [f64; 3] = [0.5, 0.95, 0.99];
+/// Upper bounds of the latency histogram's buckets, in seconds.
+const BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 10.0,
+];
+
+/// What's been counted so far, shared by the servers given it with
+/// [`Server::metrics`](crate::server::Server::metrics).
+#[derive(Debug, Default)]
+pub struct Metrics {
+    routes: Mutex<HashMap<RouteKey, RouteMetrics>>,
+    in_flight: AtomicUsize,
+    connections: Option<Arc<ConnectionStats>>,
+}
+
+/// The totals over every route, as the admin listener's `/stats` has them.
+#[derive(Debug, Clone, Serialize)]
+pub struct Summary {
+    pub requests: u64,
+    /// Requests by the first digit of their status, `1xx` to `5xx`.
+    pub by_status: BTreeMap<String, u64>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub routes: Vec<RouteLatency>,
+}
+
+/// How long one route's requests took, estimated from its histogram.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteLatency {
+    pub route: String,
+    pub method: String,
+    pub requests: u64,
+    pub p50_ms: Option<f64>,
+    pub p95_ms: Option<f64>,
+    pub p99_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+struct RouteKey {
+    route: String,
+    method: String,
+}
+
+#[derive(Debug, Default)]
+struct RouteMetrics {
+    statuses: BTreeMap<u16, u64>,
+    bytes_in: u64,
+    bytes_out: u64,
+    latency: Histogram,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// How many fell in each bucket, not counting the ones before it.
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        if let Some(i) = BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.buckets[i] += 1;
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    /// The latency that a `q` of requests took at most, interpolated within the bucket it falls
+    /// in. Past the last bucket, that bucket's bound is as close as it gets.
+    fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank = q * self.count as f64;
+        let (mut below, mut lower) = (0, 0.0);
+        for (bound, count) in BUCKETS.iter().zip(self.buckets) {
+            if count > 0 && (below + count) as f64 >= rank {
+                let within = (rank - below as f64) / count as f64;
+                return Some(lower + (bound - lower) * within);
+            }
+            below += count;
+            lower = *bound;
+        }
+        Some(lower)
+    }
+}
+
+/// One request, as counted.
+#[derive(Debug, Clone)]
+pub struct Sample<'a> {
+    /// The matched route's pattern, if any.
+    pub route: Option<&'a str>,
+    pub method: &'a str,
+    pub status: u16,
+    /// What was read of the request, head included.
+    pub bytes_in: u64,
+    /// The response's body.
+    pub bytes_out: u64,
+    pub duration: Duration,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Exports the connection counts as well.
+    pub fn connections(mut self, stats: Arc<ConnectionStats>) -> Self {
+        self.connections = Some(stats);
+        self
+    }
+
+    pub fn record(&self, sample: &Sample) {
+        let key = RouteKey {
+            route: sample.route.unwrap_or("(none)").to_owned(),
+            method: sample.method.to_owned(),
+        };
+        let mut routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let route = routes.entry(key).or_default();
+        *route.statuses.entry(sample.status).or_default() += 1;
+        route.bytes_in += sample.bytes_in;
+        route.bytes_out += sample.bytes_out;
+        route.latency.observe(sample.duration.as_secs_f64());
+    }
+
+    /// Counts a request as in flight until the guard is dropped.
+    pub(crate) fn start(self: &Arc<Self>) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.clone())
+    }
+
+    pub fn summary(&self) -> Summary {
+        let routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let mut summary = Summary {
+            requests: 0,
+            by_status: (1..=5).map(|class| (format!("{class}xx"), 0)).collect(),
+            bytes_received: 0,
+            bytes_sent: 0,
+            routes: Vec::new(),
+        };
+        for (key, route) in routes.iter() {
+            // To the microsecond, which is more than the buckets can tell apart anyway.
+            let millis = |q| {
+                let secs = route.latency.quantile(q)?;
+                Some((secs * 1e6).round() / 1e3)
+            };
+            summary.routes.push(RouteLatency {
+                route: key.route.clone(),
+                method: key.method.clone(),
+                requests: route.latency.count,
+                p50_ms: millis(0.5),
+                p95_ms: millis(0.95),
+                p99_ms: millis(0.99),
+            });
+            for (status, count) in &route.statuses {
+                summary.requests += count;
+                if let Some(class) = summary.by_status.get_mut(&format!("{}xx", status / 100)) {
+                    *class += count;
+                }
+            }
+            summary.bytes_received += route.bytes_in;
+            summary.bytes_sent += route.bytes_out;
+        }
+        summary
+            .routes
+            .sort_by(|a, b| (&a.route, &a.method).cmp(&(&b.route, &b.method)));
+        summary
+    }
+
+    /// Everything in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let routes = self.routes.lock().unwrap_or_else(|e| e.into_inner());
+        let mut routes = routes.iter().collect::<Vec<_>>();
+        routes.sort_by(|a, b| a.0.cmp(b.0));
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "http_requests_total",
+            "counter",
+            "Requests answered, by matched route, method and status.",
+        );
+        for (key, route) in &routes {
+            for (status, count) in &route.statuses {
+                let labels = labels(key, &[("status", &status.to_string())]);
+                writeln!(out, "http_requests_total{labels} {count}").unwrap();
+            }
+        }
+        header(
+            &mut out,
+            "http_request_bytes_total",
+            "counter",
+            "Bytes read of requests, heads included.",
+        );
+        for (key, route) in &routes {
+            let labels = labels(key, &[]);
+            writeln!(out, "http_request_bytes_total{labels} {}", route.bytes_in).unwrap();
+        }
+        header(
+            &mut out,
+            "http_response_bytes_total",
+            "counter",
+            "Bytes of response bodies sent.",
+        );
+        for (key, route) in &routes {
+            let labels = labels(key, &[]);
+            writeln!(out, "http_response_bytes_total{labels} {}", route.bytes_out).unwrap();
+        }
+        header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time from reading a request's head to sending the last of its response.",
+        );
+        for (key, route) in &routes {
+            let histogram = &route.latency;
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(histogram.buckets) {
+                cumulative += count;
+                let labels = labels(key, &[("le", &bound.to_string())]);
+                writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{labels} {cumulative}"
+                )
+                .unwrap();
+            }
+            let labels_inf = labels(key, &[("le", "+Inf")]);
+            let labels = labels(key, &[]);
+            let (count, sum) = (histogram.count, histogram.sum);
+            writeln!(
+                out,
+                "http_request_duration_seconds_bucket{labels_inf} {count}"
+            )
+            .unwrap();
+            writeln!(out, "http_request_duration_seconds_sum{labels} {sum}").unwrap();
+            writeln!(out, "http_request_duration_seconds_count{labels} {count}").unwrap();
+        }
+        header(
+            &mut out,
+            "http_request_duration_quantile_seconds",
+            "gauge",
+            "Latency quantiles estimated from http_request_duration_seconds.",
+        );
+        for (key, route) in &routes {
+            for q in QUANTILES {
+                let Some(value) = route.latency.quantile(q) else {
+                    continue;
+                };
+                let labels = labels(key, &[("quantile", &q.to_string())]);
+                writeln!(
+                    out,
+                    "http_request_duration_quantile_seconds{labels} {value}"
+                )
+                .unwrap();
+            }
+        }
+        drop(routes);
+
+        header(
+            &mut out,
+            "http_requests_in_flight",
+            "gauge",
+            "Requests being handled or answered.",
+        );
+        let in_flight = self.in_flight.load(Ordering::Relaxed);
+        writeln!(out, "http_requests_in_flight {in_flight}").unwrap();
+        if let Some(stats) = &self.connections {
+            let stats = stats.snapshot();
+            for (name, kind, help, value) in [
+                (
+                    "http_connections_open",
+                    "gauge",
+                    "Connections open.",
+                    stats.open as u64,
+                ),
+                (
+                    "http_connections_accepted_total",
+                    "counter",
+                    "Connections served.",
+                    stats.accepted,
+                ),
+                (
+                    "http_connections_turned_away_total",
+                    "counter",
+                    "Connections turned away over the connection limits.",
+                    stats.turned_away,
+                ),
+            ] {
+                header(&mut out, name, kind, help);
+                writeln!(out, "{name} {value}").unwrap();
+            }
+        }
+        out
+    }
+
+    /// The response for a scrape.
+    pub fn response(&self) -> Response {
+        Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(self.render())
+    }
+}
+
+/// Takes a request off the in-flight count when dropped.
+pub(crate) struct InFlight(Arc<Metrics>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} {kind}").unwrap();
+}
+
+fn labels(key: &RouteKey, extra: &[(&str, &str)]) -> String {
+    let mut labels = format!(
+        "{{route=\"{}\",method=\"{}\"",
+        escape(&key.route),
+        escape(&key.method)
+    );
+    for (name, value) in extra {
+        write!(labels, ",{name}=\"{}\"", escape(value)).unwrap();
+    }
+    labels.push('}');
+    labels
+}
+
+/// Label values escaped the way the format wants.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
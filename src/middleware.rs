@@ -0,0 +1,68 @@
+use std::{future::Future, sync::Arc};
+
+use crate::{
+    error::HttpError,
+    handler::{BoxFuture, Handler},
+    request::Request,
+    response::Response,
+};
+
+/// Onion-style middleware: code before `next.run(req)` sees the request on the way in,
+/// code after it sees the response on the way out, and not calling `next` short-circuits.
+pub trait Middleware: Send + Sync + 'static {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>>;
+
+    /// How the middleware shows up in route listings.
+    fn name(&self) -> &str {
+        std::any::type_name::<Self>()
+    }
+}
+
+/// The rest of the chain: the remaining middlewares followed by the handler.
+#[derive(Clone)]
+pub struct Next {
+    middlewares: Arc<[Arc<dyn Middleware>]>,
+    position: usize,
+    endpoint: Arc<dyn Handler>,
+}
+
+impl Next {
+    pub(crate) fn new(middlewares: Arc<[Arc<dyn Middleware>]>, endpoint: Arc<dyn Handler>) -> Self {
+        Self {
+            middlewares,
+            position: 0,
+            endpoint,
+        }
+    }
+
+    pub async fn run(mut self, req: Request) -> Result<Response, HttpError> {
+        match self.middlewares.get(self.position).cloned() {
+            Some(middleware) => {
+                self.position += 1;
+                middleware.handle(req, self).await
+            }
+            None => self.endpoint.call(req).await,
+        }
+    }
+}
+
+pub struct FromFn<F>(F);
+
+/// Turns an `async fn(Request, Next) -> Result<Response, HttpError>` into a middleware.
+pub fn from_fn<F, Fut>(f: F) -> FromFn<F>
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response, HttpError>> + Send + 'static,
+{
+    FromFn(f)
+}
+
+impl<F, Fut> Middleware for FromFn<F>
+where
+    F: Fn(Request, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response, HttpError>> + Send + 'static,
+{
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        Box::pin((self.0)(req, next))
+    }
+}
@@ -0,0 +1,84 @@
+//! The slowest clients may send request bodies and take responses at, so that ones trickling
+//! a body in a few bytes at a time (a slow POST), or reading a response just as slowly, can't
+//! keep connections busy indefinitely with next to no bandwidth.
+//!
+//! Only time spent waiting on the client counts: a handler that takes its time reading the
+//! body, or a response produced slowly, doesn't count against the client, and neither does
+//! the wait between requests, which the idle timeout is for.
+
+use std::{
+    io,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use crate::rt::{self, Sleep};
+
+/// At least `bytes_per_sec` over every `period` spent waiting on the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MinRate {
+    pub bytes_per_sec: u64,
+    pub period: Duration,
+}
+
+impl MinRate {
+    /// The bytes a client has to move in one period.
+    fn required(&self) -> u64 {
+        (self.bytes_per_sec as f64 * self.period.as_secs_f64()).ceil() as u64
+    }
+}
+
+/// Keeps track of one direction of a connection against a [`MinRate`]. Its reader or writer
+/// reports what each poll came to with [`moved`](Self::moved) and
+/// [`poll_pending`](Self::poll_pending).
+pub(crate) struct RateCheck {
+    rate: MinRate,
+    /// Waited on the client this period, besides any wait still going on.
+    waited: Duration,
+    /// Bytes moved this period.
+    moved: u64,
+    /// When the current wait started, and the end of the period it'll run into.
+    waiting: Option<(Instant, Sleep)>,
+}
+
+impl RateCheck {
+    pub(crate) fn new(rate: MinRate) -> Self {
+        Self {
+            rate,
+            waited: Duration::ZERO,
+            moved: 0,
+            waiting: None,
+        }
+    }
+
+    /// After a poll that moved `bytes`. A client that's moved enough starts a new period.
+    pub(crate) fn moved(&mut self, bytes: usize) {
+        if let Some((since, _)) = self.waiting.take() {
+            self.waited += since.elapsed();
+        }
+        self.moved += bytes as u64;
+        if self.moved >= self.rate.required() {
+            self.waited = Duration::ZERO;
+            self.moved = 0;
+        }
+    }
+
+    /// After a poll that's waiting on the client: an error once it's waited out a period
+    /// without moving enough.
+    pub(crate) fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<io::Error> {
+        let left = self.rate.period.saturating_sub(self.waited);
+        let (_, period_end) = self
+            .waiting
+            .get_or_insert_with(|| (Instant::now(), rt::sleep(left)));
+        if period_end.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        Poll::Ready(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!(
+                "client moved {} bytes in {:?}, under {} bytes/s",
+                self.moved, self.rate.period, self.rate.bytes_per_sec
+            ),
+        ))
+    }
+}
@@ -0,0 +1,220 @@
+//! The command line of the `minimal` build: `--listen` and `--directory`, read from
+//! `HTTP_SERVER_LISTEN` and `HTTP_SERVER_DIRECTORY` when they're not given, as the full build
+//! reads them, and `--help` and `--version`. The server has the built-in routes and nothing
+//! in front of them.
+
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use http_server_starter_rust::{routes, served_dir::ServedDir, server::Server, state::AppState};
+use tokio::{net::TcpListener, sync::broadcast};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+const USAGE: &str = "\
+Usage: http-server-starter-rust [--listen <address>]... [--directory <directory>]
+
+Options:
+      --listen <address>       An address to accept connections on, as ADDRESS:PORT or just a
+                               port. Repeat it, or separate them with commas, to listen on
+                               several [default: 127.0.0.1:4221]
+      --directory <directory>  The directory /files serves and takes uploads to
+                               [default: ./test-files]
+  -h, --help                   Print help
+  -V, --version                Print version
+
+This is the minimal build, which takes no other options.";
+
+struct Args {
+    listen: Vec<SocketAddr>,
+    directory: PathBuf,
+}
+
+enum Parsed {
+    Run(Args),
+    Help,
+    Version,
+}
+
+pub fn main() -> anyhow::Result<()> {
+    let args = match parse(std::env::args().skip(1), |var| std::env::var(var).ok()) {
+        Ok(Parsed::Run(args)) => args,
+        Ok(Parsed::Help) => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+        Ok(Parsed::Version) => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            return Ok(());
+        }
+        // Exiting the way clap does in the full build.
+        Err(e) => {
+            eprintln!("error: {e}\n\n{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    tracing_subscriber::fmt().with_env_filter(filter).init();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("starting the runtime")?;
+    runtime.block_on(run(args))
+}
+
+/// The options on `command_line`, falling back on the environment variables `env` has.
+fn parse(
+    command_line: impl IntoIterator<Item = String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<Parsed, String> {
+    let mut listen = Vec::new();
+    let mut directory = None;
+    let mut command_line = command_line.into_iter();
+    while let Some(arg) = command_line.next() {
+        let (option, value) = match arg.split_once('=') {
+            Some((option, value)) if option.starts_with("--") => (option, Some(value.to_owned())),
+            _ => (arg.as_str(), None),
+        };
+        match option {
+            "-h" | "--help" => return Ok(Parsed::Help),
+            "-V" | "--version" => return Ok(Parsed::Version),
+            "--listen" | "--directory" => {
+                let value = value
+                    .or_else(|| command_line.next())
+                    .ok_or_else(|| format!("a value is required for '{option}'"))?;
+                match option {
+                    "--listen" => listen.extend(listen_addrs(&value)?),
+                    _ => directory = Some(PathBuf::from(value)),
+                }
+            }
+            _ => return Err(format!("unexpected argument '{arg}' found")),
+        }
+    }
+    if listen.is_empty() {
+        if let Some(value) = env("HTTP_SERVER_LISTEN") {
+            listen = listen_addrs(&value)?;
+        }
+    }
+    if listen.is_empty() {
+        listen.push(SocketAddr::from(([127, 0, 0, 1], 4221)));
+    }
+    let directory = directory
+        .or_else(|| env("HTTP_SERVER_DIRECTORY").map(PathBuf::from))
+        .unwrap_or_else(|| PathBuf::from("./test-files"));
+    Ok(Parsed::Run(Args { listen, directory }))
+}
+
+/// The addresses in a `--listen` value, separated by commas, each an `ADDRESS:PORT` or a port
+/// to listen on every interface.
+fn listen_addrs(value: &str) -> Result<Vec<SocketAddr>, String> {
+    let addr = |value: &str| match value.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([0, 0, 0, 0], port))),
+        Err(_) => value
+            .parse()
+            .map_err(|_| format!("expected ADDRESS:PORT or a port, got {value}")),
+    };
+    value.split(',').map(addr).collect()
+}
+
+async fn run(args: Args) -> anyhow::Result<()> {
+    let state = Arc::new(AppState {
+        base_dir: ServedDir::new(args.directory),
+        upload_scan: None,
+        upload_filter: None,
+        upload_expiry: Arc::default(),
+        max_delay: Duration::from_secs(10),
+        broadcast: broadcast::channel(64).0,
+        notices: Default::default(),
+        kv: Arc::default(),
+        shortener: Arc::default(),
+    });
+    state.upload_expiry.spawn(Duration::from_secs(60));
+    let mut listeners = Vec::new();
+    for addr in args.listen {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("listening on {addr}"))?;
+        info!("Listening on {}", listener.local_addr()?);
+        listeners.push(listener);
+    }
+    Server::new(Arc::new(routes::default_router(state)))
+        .graceful_shutdown(shutdown_signal(), Duration::from_secs(30))
+        .serve_all(listeners)
+        .await
+}
+
+/// Resolves on Ctrl-C or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate()).expect("installing SIGTERM handler");
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(command_line: &[&str], env: &[(&str, &str)]) -> Result<Args, String> {
+        let command_line = command_line.iter().map(|arg| arg.to_string());
+        let env = |var: &str| {
+            let value = env.iter().find(|(name, _)| *name == var);
+            value.map(|(_, value)| value.to_string())
+        };
+        match parse(command_line, env)? {
+            Parsed::Run(args) => Ok(args),
+            Parsed::Help | Parsed::Version => Err("help or version".to_owned()),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let args = parsed(&[], &[]).unwrap();
+        assert_eq!(args.listen, ["127.0.0.1:4221".parse().unwrap()]);
+        assert_eq!(args.directory, PathBuf::from("./test-files"));
+    }
+
+    #[test]
+    fn takes_the_options_either_way() {
+        let args = parsed(&["--directory", "/tmp/", "--listen=8080,[::1]:80"], &[]).unwrap();
+        assert_eq!(args.directory, PathBuf::from("/tmp/"));
+        let listen: Vec<SocketAddr> =
+            vec!["0.0.0.0:8080".parse().unwrap(), "[::1]:80".parse().unwrap()];
+        assert_eq!(args.listen, listen);
+        let args = parsed(&["--listen", "1", "--listen", "2"], &[]).unwrap();
+        assert_eq!(args.listen.len(), 2);
+    }
+
+    #[test]
+    fn falls_back_on_the_environment() {
+        let env = [
+            ("HTTP_SERVER_DIRECTORY", "/srv"),
+            ("HTTP_SERVER_LISTEN", "9000"),
+        ];
+        let args = parsed(&[], &env).unwrap();
+        assert_eq!(args.directory, PathBuf::from("/srv"));
+        assert_eq!(args.listen, ["0.0.0.0:9000".parse().unwrap()]);
+        let args = parsed(&["--directory", "/tmp/", "--listen", "1"], &env).unwrap();
+        assert_eq!(args.directory, PathBuf::from("/tmp/"));
+        assert_eq!(args.listen, ["0.0.0.0:1".parse().unwrap()]);
+    }
+
+    #[test]
+    fn refuses_what_it_doesnt_take() {
+        assert!(parsed(&["--max-connections", "10"], &[]).is_err());
+        assert!(parsed(&["--directory"], &[]).is_err());
+        assert!(parsed(&["--listen", "nowhere"], &[]).is_err());
+        assert!(matches!(
+            parse(["--help".to_owned()], |_| None),
+            Ok(Parsed::Help)
+        ));
+    }
+}
@@ -0,0 +1,480 @@
+//! `multipart/form-data` request bodies, as HTML forms with file inputs send them, read a part
+//! at a time without buffering any part whole.
+//!
+//! ```ignore
+//! async fn upload(mut form: Multipart) -> Result<StatusCode, HttpError> {
+//!     while let Some(mut part) = form.next_part().await? {
+//!         if let Some(name) = part.file_name().map(str::to_owned) {
+//!             tokio::io::copy(&mut part, &mut File::create(name).await?).await?;
+//!         }
+//!     }
+//!     Ok(StatusCode::CREATED)
+//! }
+//! ```
+
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use bytes::{Buf, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+
+use crate::{
+    body::Body, error::HttpError, extract::FromRequest, handler::BoxFuture, headers::CONTENT_TYPE,
+    request::Request,
+};
+
+/// The most a part's headers may take up.
+const MAX_HEADERS: usize = 8 * 1024;
+
+/// A `multipart/form-data` body. Taking it as an extractor answers 415 to requests of any
+/// other type.
+pub struct Multipart {
+    body: Body,
+    /// `\r\n--` and the boundary, which ends every part.
+    delimiter: Vec<u8>,
+    buf: BytesMut,
+    /// Whether `buf` is at a delimiter, rather than in the middle of a part.
+    at_delimiter: bool,
+    eof: bool,
+    done: bool,
+}
+
+/// One field of the form: its headers have been read, and reading it reads its contents.
+pub struct Part<'a> {
+    form: &'a mut Multipart,
+    name: Option<String>,
+    file_name: Option<String>,
+    content_type: Option<String>,
+}
+
+/// What reading a form that doesn't keep to the format fails with, inside an [`io::Error`] of
+/// kind `InvalidData`.
+#[derive(Debug, thiserror::Error)]
+#[error("Malformed form: {0}")]
+pub struct Malformed(&'static str);
+
+impl Malformed {
+    /// Whether `error` comes down to a malformed form, which is the client's fault.
+    pub fn caused(error: &anyhow::Error) -> bool {
+        error.chain().any(|cause| {
+            cause
+                .downcast_ref::<io::Error>()
+                .and_then(|e| e.get_ref())
+                .is_some_and(|inner| inner.is::<Malformed>())
+        })
+    }
+}
+
+fn malformed(why: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, Malformed(why))
+}
+
+fn truncated() -> io::Error {
+    malformed("the body ends before the form does")
+}
+
+/// The error for a failure reading the form: a 400 if it's malformed, otherwise it keeps the
+/// cause, so that a [body limit](crate::body_limit) can tell it went over.
+fn read_error(e: io::Error) -> HttpError {
+    match e.get_ref().is_some_and(|inner| inner.is::<Malformed>()) {
+        true => HttpError::bad_request(&e.to_string()),
+        false => anyhow::Error::new(e).context("reading form").into(),
+    }
+}
+
+/// The `boundary` parameter of a `multipart/form-data` type.
+fn boundary(content_type: &str) -> Option<String> {
+    let (essence, params) = content_type.split_once(';')?;
+    if !essence.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+    let boundary = parameters(params).find(|(name, _)| name.eq_ignore_ascii_case("boundary"));
+    boundary
+        .map(|(_, value)| value)
+        .filter(|value| (1..=70).contains(&value.len()))
+}
+
+/// The `name=value` parameters of a header, unquoting quoted values.
+fn parameters(params: &str) -> impl Iterator<Item = (String, String)> + '_ {
+    let mut rest = params;
+    std::iter::from_fn(move || {
+        rest = rest.trim_start_matches([';', ' ', '\t']);
+        let (name, after) = rest.split_once('=')?;
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+                while let Some((i, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+                (value, &quoted[end..])
+            }
+            None => {
+                let (value, after) = after.split_once(';').unwrap_or((after, ""));
+                (value.trim().to_owned(), after)
+            }
+        };
+        rest = after;
+        Some((name.trim().to_ascii_lowercase(), value))
+    })
+}
+
+impl FromRequest for Multipart {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            let content_type = req.header(CONTENT_TYPE).unwrap_or_default();
+            let Some(boundary) = boundary(content_type) else {
+                return Err(HttpError::unsupported_media_type(content_type));
+            };
+            Ok(Multipart::new(
+                std::mem::replace(&mut req.body, Body::empty()),
+                &boundary,
+            ))
+        })
+    }
+}
+
+impl Multipart {
+    pub fn new(body: Body, boundary: &str) -> Self {
+        let delimiter = format!("\r\n--{boundary}").into_bytes();
+        // As if there were a line break before the first delimiter, which saves treating it
+        // differently.
+        let mut buf = BytesMut::with_capacity(16 * 1024);
+        buf.extend_from_slice(b"\r\n");
+        Self {
+            body,
+            delimiter,
+            buf,
+            at_delimiter: false,
+            eof: false,
+            done: false,
+        }
+    }
+
+    /// Reads more of the body into the buffer, `false` at its end.
+    async fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let read = (&mut self.body)
+            .take(16 * 1024)
+            .read_buf(&mut self.buf)
+            .await?;
+        self.eof = read == 0;
+        Ok(!self.eof)
+    }
+
+    /// The next part, once whatever's left of the last one has been skipped, or `None` after
+    /// the last. A body that ends early or doesn't keep to the format is a 400.
+    pub async fn next_part(&mut self) -> Result<Option<Part<'_>>, HttpError> {
+        self.advance().await.map_err(read_error)?;
+        if self.done {
+            return Ok(None);
+        }
+        let headers = self.read_headers().await.map_err(read_error)?;
+        let mut part = Part {
+            form: self,
+            name: None,
+            file_name: None,
+            content_type: None,
+        };
+        for line in headers.split("\r\n") {
+            let Some((name, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if name.trim().eq_ignore_ascii_case("Content-Disposition") {
+                let params = value.split_once(';').map_or("", |(_, params)| params);
+                for (param, value) in parameters(params) {
+                    match param.as_str() {
+                        "name" => part.name = Some(value),
+                        // Some browsers send the whole path the file was picked from.
+                        "filename" => {
+                            let name = value.rsplit(['/', '\\']).next().unwrap_or_default();
+                            part.file_name = Some(name.to_owned());
+                        }
+                        _ => {}
+                    }
+                }
+            } else if name.trim().eq_ignore_ascii_case(CONTENT_TYPE) {
+                part.content_type = Some(value.to_owned());
+            }
+        }
+        Ok(Some(part))
+    }
+
+    /// Skips to the next delimiter and past it, noting whether it was the closing one.
+    async fn advance(&mut self) -> io::Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        while !self.at_delimiter {
+            // The preamble, or what a part's reader left unread.
+            tokio::io::copy(&mut PartReader(self), &mut tokio::io::sink()).await?;
+        }
+        // The delimiter, then `--` for the last, or optional whitespace and a line break.
+        while self.buf.len() < self.delimiter.len() + 2 {
+            if !self.fill().await? {
+                return Err(truncated());
+            }
+        }
+        self.buf.advance(self.delimiter.len());
+        self.at_delimiter = false;
+        if self.buf.starts_with(b"--") {
+            self.done = true;
+            return Ok(());
+        }
+        loop {
+            let padding = self.buf.iter().take_while(|&&b| b == b' ' || b == b'\t');
+            let padding = padding.count();
+            if self.buf.len() >= padding + 2 {
+                if &self.buf[padding..padding + 2] != b"\r\n" {
+                    return Err(malformed("no line break after a boundary"));
+                }
+                self.buf.advance(padding + 2);
+                return Ok(());
+            }
+            if self.buf.len() > MAX_HEADERS || !self.fill().await? {
+                return Err(malformed("no line break after a boundary"));
+            }
+        }
+    }
+
+    /// A part's headers, up to the blank line after them.
+    async fn read_headers(&mut self) -> io::Result<String> {
+        // Headers can be left out altogether, leaving just the blank line.
+        loop {
+            if self.buf.starts_with(b"\r\n") {
+                self.buf.advance(2);
+                return Ok(String::new());
+            }
+            // Only looking as far as headers may go, however much has come in at once.
+            let within = &self.buf[..self.buf.len().min(MAX_HEADERS + 4)];
+            if let Some(end) = within.windows(4).position(|w| w == b"\r\n\r\n") {
+                let headers = self.buf.split_to(end + 4);
+                return String::from_utf8(headers[..end].to_vec())
+                    .map_err(|_| malformed("part headers aren't UTF-8"));
+            }
+            if self.buf.len() > MAX_HEADERS {
+                return Err(malformed("part headers too long"));
+            }
+            if !self.fill().await? {
+                return Err(truncated());
+            }
+        }
+    }
+
+    fn poll_part(&mut self, cx: &mut Context<'_>, out: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        loop {
+            if self.at_delimiter {
+                return Poll::Ready(Ok(()));
+            }
+            let found = self
+                .buf
+                .windows(self.delimiter.len())
+                .position(|w| w == self.delimiter);
+            // Short of a delimiter, everything but what could be the start of one is the part's.
+            let safe = match found {
+                Some(at) => at,
+                None => self.buf.len().saturating_sub(self.delimiter.len() - 1),
+            };
+            if safe > 0 {
+                let n = safe.min(out.remaining());
+                out.put_slice(&self.buf.split_to(n));
+                return Poll::Ready(Ok(()));
+            }
+            if found.is_some() {
+                self.at_delimiter = true;
+                return Poll::Ready(Ok(()));
+            }
+            if self.eof {
+                return Poll::Ready(Err(truncated()));
+            }
+            let mut chunk = [0; 8 * 1024];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut self.body).poll_read(cx, &mut chunk))?;
+            self.eof = chunk.filled().is_empty();
+            self.buf.extend_from_slice(chunk.filled());
+        }
+    }
+}
+
+/// Reads the part the form is in, for skipping it.
+struct PartReader<'a>(&'a mut Multipart);
+
+impl AsyncRead for PartReader<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut().0.poll_part(cx, buf)
+    }
+}
+
+impl Part<'_> {
+    /// The form field's name.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The name of the file, without any directories, for file inputs; empty for one left
+    /// empty.
+    pub fn file_name(&self) -> Option<&str> {
+        self.file_name.as_deref()
+    }
+
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+/// Reads the part's contents, ending where the part does.
+impl AsyncRead for Part<'_> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.get_mut().form.poll_part(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::status::StatusCode;
+
+    use super::*;
+
+    /// Hands out the body a byte at a time, so every delimiter is split across reads.
+    struct Trickle(Vec<u8>);
+
+    impl AsyncRead for Trickle {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            if !self.0.is_empty() {
+                buf.put_slice(&[self.0.remove(0)]);
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const FORM: &[u8] = b"preamble\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\r\n\
+        Holiday\r\n--xyz\r\n\
+        Content-Disposition: form-data; name=\"photo\"; filename=\"C:\\\\pics\\\\beach.jpg\"\r\n\
+        Content-Type: image/jpeg\r\n\r\n\
+        \r\n--xy not quite\r\n--xyz--\r\nepilogue";
+
+    /// Each part as its name, file name, content type and contents.
+    type Parts = Vec<(Option<String>, Option<String>, Option<String>, Vec<u8>)>;
+
+    async fn parts(body: Body) -> Result<Parts, HttpError> {
+        let mut form = Multipart::new(body, "xyz");
+        let mut parts = Vec::new();
+        while let Some(mut part) = form.next_part().await? {
+            let (name, file_name, content_type) = (
+                part.name().map(str::to_owned),
+                part.file_name().map(str::to_owned),
+                part.content_type().map(str::to_owned),
+            );
+            let mut contents = Vec::new();
+            part.read_to_end(&mut contents).await.map_err(read_error)?;
+            parts.push((name, file_name, content_type, contents));
+        }
+        Ok(parts)
+    }
+
+    fn expected() -> Parts {
+        let some = |s: &str| Some(s.to_owned());
+        vec![
+            (some("title"), None, None, b"Holiday".to_vec()),
+            (
+                some("photo"),
+                some("beach.jpg"),
+                some("image/jpeg"),
+                b"\r\n--xy not quite".to_vec(),
+            ),
+        ]
+    }
+
+    async fn status(form: &[u8]) -> StatusCode {
+        match parts(Body::from(form.to_vec())).await {
+            Ok(_) => StatusCode::OK,
+            Err(e) => e.status,
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_parts() {
+        assert_eq!(parts(Body::from(FORM.to_vec())).await.unwrap(), expected());
+    }
+
+    #[tokio::test]
+    async fn reads_boundaries_split_across_reads() {
+        let body = Body::from_reader(Trickle(FORM.to_vec()));
+        assert_eq!(parts(body).await.unwrap(), expected());
+    }
+
+    #[tokio::test]
+    async fn skips_transport_padding() {
+        let form = b"--xyz \t \r\n\r\nfirst\r\n--xyz\t\r\n\r\nsecond\r\n--xyz--";
+        let parts = parts(Body::from_reader(Trickle(form.to_vec())))
+            .await
+            .unwrap();
+        let contents: Vec<_> = parts.into_iter().map(|(.., contents)| contents).collect();
+        assert_eq!(contents, [b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn skips_unread_parts() {
+        let mut form = Multipart::new(Body::from(FORM.to_vec()), "xyz");
+        assert!(form.next_part().await.unwrap().is_some());
+        let part = form.next_part().await.unwrap().unwrap();
+        assert_eq!(part.name(), Some("photo"));
+        assert!(form.next_part().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn refuses_forms_without_a_closing_delimiter() {
+        for form in [
+            &b"--xyz\r\n\r\nfirst\r\n--xyz"[..],
+            b"--xyz\r\n\r\nfirst\r\n--xyz\r\n",
+            b"--xyz\r\n\r\nfirst",
+            b"preamble, but no parts",
+        ] {
+            let start = String::from_utf8_lossy(&form[..form.len().min(32)]);
+            assert_eq!(status(form).await, StatusCode::BAD_REQUEST, "{start}");
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_forms() {
+        let long_header = format!(
+            "--xyz\r\nX-Padding: {}\r\n\r\n\r\n--xyz--",
+            "a".repeat(MAX_HEADERS)
+        );
+        for form in [
+            long_header.as_bytes(),
+            b"--xyz junk\r\n\r\n\r\n--xyz--",
+            b"--xyz\r\nX-Name: \xff\r\n\r\n\r\n--xyz--",
+        ] {
+            let start = String::from_utf8_lossy(&form[..form.len().min(32)]);
+            assert_eq!(status(form).await, StatusCode::BAD_REQUEST, "{start}");
+        }
+    }
+}
@@ -0,0 +1,280 @@
+//! Handlers loaded from native shared libraries at startup.
+//!
+//! A plugin is a `cdylib` exporting two C functions:
+//!
+//! ```c
+//! uint32_t http_plugin_abi_version(void);          // must return PLUGIN_ABI_VERSION
+//! void http_plugin_register(const Registrar *r);   // calls r->register once per route
+//! ```
+//!
+//! Handlers get the request as borrowed slices and build the response through the writer's
+//! callbacks, so no memory ever changes hands between the allocators of host and plugin. They
+//! return 0 on success; anything else becomes a 502. They run on the blocking pool and may be
+//! called from several threads at once.
+//!
+//! This loads arbitrary native code into the server: only point it at libraries you trust.
+
+use std::{
+    ffi::c_void,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context};
+use libloading::{Library, Symbol};
+use tracing::warn;
+
+use crate::{
+    body::Body,
+    error::HttpError,
+    extract::DEFAULT_BODY_LIMIT,
+    handler::{BoxFuture, Handler},
+    request::{Method, Request},
+    response::Response,
+    router::Router,
+    status::StatusCode,
+};
+
+/// Bumped whenever any of the `#[repr(C)]` types or callbacks below change.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Slice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+impl Slice {
+    fn new(bytes: &[u8]) -> Self {
+        Self {
+            ptr: bytes.as_ptr(),
+            len: bytes.len(),
+        }
+    }
+
+    /// # Safety
+    ///
+    /// `ptr` must point to `len` readable bytes for the returned lifetime.
+    unsafe fn as_bytes<'a>(self) -> &'a [u8] {
+        if self.len == 0 {
+            return &[];
+        }
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+}
+
+#[repr(C)]
+pub struct PluginHeader {
+    pub name: Slice,
+    pub value: Slice,
+}
+
+#[repr(C)]
+pub struct PluginRequest {
+    pub method: Slice,
+    pub path: Slice,
+    pub query: Slice,
+    pub headers: *const PluginHeader,
+    pub headers_len: usize,
+    pub body: Slice,
+}
+
+#[repr(C)]
+pub struct ResponseWriter {
+    pub ctx: *mut c_void,
+    pub set_status: extern "C" fn(ctx: *mut c_void, status: u16),
+    pub add_header: extern "C" fn(ctx: *mut c_void, name: Slice, value: Slice),
+    pub write_body: extern "C" fn(ctx: *mut c_void, data: Slice),
+}
+
+pub type PluginHandler =
+    extern "C" fn(req: *const PluginRequest, writer: *const ResponseWriter) -> i32;
+
+#[repr(C)]
+pub struct Registrar {
+    pub ctx: *mut c_void,
+    pub register:
+        extern "C" fn(ctx: *mut c_void, method: Slice, pattern: Slice, handler: PluginHandler),
+}
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type RegisterFn = unsafe extern "C" fn(registrar: *const Registrar);
+
+/// A loaded plugin library; keeps it mapped for as long as any of its handlers exist.
+pub struct NativePlugin {
+    library: Arc<Library>,
+    routes: Vec<(Method, String, PluginHandler)>,
+}
+
+impl NativePlugin {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        // SAFETY: loading runs the library's initializers; trusting them is the whole point of
+        // a native plugin.
+        let library = unsafe { Library::new(path) }
+            .with_context(|| format!("loading plugin {}", path.display()))?;
+
+        // SAFETY: the symbol types match the documented ABI of version 1, which is checked
+        // before anything else is called.
+        let routes = unsafe {
+            let version: Symbol<AbiVersionFn> = library
+                .get(b"http_plugin_abi_version\0")
+                .context("plugin doesn't export http_plugin_abi_version")?;
+            let version = version();
+            if version != PLUGIN_ABI_VERSION {
+                bail!(
+                    "plugin {} targets ABI version {version}, this server speaks {PLUGIN_ABI_VERSION}",
+                    path.display()
+                );
+            }
+
+            let register: Symbol<RegisterFn> = library
+                .get(b"http_plugin_register\0")
+                .context("plugin doesn't export http_plugin_register")?;
+            let routes = Mutex::new(Vec::new());
+            let registrar = Registrar {
+                ctx: &routes as *const _ as *mut c_void,
+                register: register_route,
+            };
+            register(&registrar);
+            routes.into_inner().unwrap_or_else(|e| e.into_inner())
+        };
+
+        Ok(Self {
+            library: Arc::new(library),
+            routes,
+        })
+    }
+
+    /// Adds every route the plugin registered to `router`.
+    pub fn install(&self, mut router: Router) -> Router {
+        for (method, pattern, handler) in &self.routes {
+            let handler = PluginRoute {
+                _library: self.library.clone(),
+                handler: *handler,
+            };
+            router = router.route(method.clone(), pattern, move |req: Request| {
+                handler.call(req)
+            });
+        }
+        router
+    }
+
+    pub fn routes(&self) -> impl Iterator<Item = (&Method, &str)> {
+        self.routes.iter().map(|(m, p, _)| (m, p.as_str()))
+    }
+}
+
+extern "C" fn register_route(
+    ctx: *mut c_void,
+    method: Slice,
+    pattern: Slice,
+    handler: PluginHandler,
+) {
+    // SAFETY: ctx is the Vec behind the mutex set up in `load`, alive during registration, and
+    // the slices are valid for the duration of the call per the ABI.
+    let (routes, method, pattern) = unsafe {
+        (
+            &*(ctx as *const Mutex<Vec<(Method, String, PluginHandler)>>),
+            String::from_utf8_lossy(method.as_bytes()).to_ascii_uppercase(),
+            String::from_utf8_lossy(pattern.as_bytes()).into_owned(),
+        )
+    };
+    if !pattern.starts_with('/') {
+        warn!("Plugin tried to register invalid pattern {pattern}, ignoring it");
+        return;
+    }
+    routes.lock().unwrap_or_else(|e| e.into_inner()).push((
+        Method::from(method.as_str()),
+        pattern,
+        handler,
+    ));
+}
+
+#[derive(Clone)]
+struct PluginRoute {
+    _library: Arc<Library>,
+    handler: PluginHandler,
+}
+
+impl PluginRoute {
+    fn invoke(&self, req: &Request, body: &[u8]) -> Result<Response, HttpError> {
+        let headers = req
+            .headers
+            .iter()
+            .map(|(name, value)| PluginHeader {
+                name: Slice::new(name.as_bytes()),
+                value: Slice::new(value.as_bytes()),
+            })
+            .collect::<Vec<_>>();
+        let plugin_req = PluginRequest {
+            method: Slice::new(req.method.as_str().as_bytes()),
+            path: Slice::new(req.path.as_bytes()),
+            query: Slice::new(req.query.as_bytes()),
+            headers: headers.as_ptr(),
+            headers_len: headers.len(),
+            body: Slice::new(body),
+        };
+
+        let mut response = Response::empty(StatusCode::OK);
+        let mut body = Vec::new();
+        let mut state = (&mut response, &mut body);
+        let writer = ResponseWriter {
+            ctx: &mut state as *mut _ as *mut c_void,
+            set_status,
+            add_header,
+            write_body,
+        };
+
+        let code = (self.handler)(&plugin_req, &writer);
+        if code != 0 {
+            return Err(HttpError::new(
+                StatusCode::BAD_GATEWAY,
+                anyhow::anyhow!("plugin handler failed with code {code}"),
+            ));
+        }
+        response.body = body.into();
+        Ok(response)
+    }
+}
+
+type WriterState<'a> = (&'a mut Response, &'a mut Vec<u8>);
+
+extern "C" fn set_status(ctx: *mut c_void, status: u16) {
+    // SAFETY: ctx is the WriterState living on the stack of `invoke` for the handler call.
+    let (response, _) = unsafe { &mut *(ctx as *mut WriterState) };
+    if (100..1000).contains(&status) {
+        response.status = StatusCode(status);
+    }
+}
+
+extern "C" fn add_header(ctx: *mut c_void, name: Slice, value: Slice) {
+    // SAFETY: see `set_status`; the slices are valid for the duration of the call.
+    let (response, _) = unsafe { &mut *(ctx as *mut WriterState) };
+    let (name, value) = unsafe { (name.as_bytes(), value.as_bytes()) };
+    response.headers.append(
+        &String::from_utf8_lossy(name),
+        &String::from_utf8_lossy(value),
+    );
+}
+
+extern "C" fn write_body(ctx: *mut c_void, data: Slice) {
+    // SAFETY: see `add_header`.
+    let (_, body) = unsafe { &mut *(ctx as *mut WriterState) };
+    body.extend_from_slice(unsafe { data.as_bytes() });
+}
+
+impl Handler for PluginRoute {
+    fn call(&self, mut req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let route = self.clone();
+        Box::pin(async move {
+            let body = std::mem::replace(&mut req.body, Body::empty())
+                .to_bytes(DEFAULT_BODY_LIMIT)
+                .await?;
+            // The request keeps its body out so it can move to the blocking pool.
+            tokio::task::spawn_blocking(move || route.invoke(&req, &body))
+                .await
+                .map_err(|e| HttpError::from(anyhow::anyhow!("plugin handler panicked: {e}")))?
+        })
+    }
+}
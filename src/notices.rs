@@ -0,0 +1,111 @@
+//! Notices of what happens to the server as a whole, like reloading its configuration, for
+//! `/events` to pass on to clients.
+//!
+//! Each notice is numbered, and the last few are kept, so a client reconnecting with the
+//! number of the last one it saw gets the ones it missed before the new ones.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::SystemTime,
+};
+
+use tokio::sync::broadcast;
+
+/// How many notices are kept for clients catching up.
+const HISTORY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Notice {
+    /// The configuration was reloaded.
+    Reloaded,
+    /// The server has stopped taking connections, and will exit once the open ones finish.
+    Draining,
+}
+
+impl fmt::Display for Notice {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Notice::Reloaded => "reload",
+            Notice::Draining => "drain",
+        })
+    }
+}
+
+/// A notice as it was posted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Posted {
+    /// Counting up from 1, for this run of the server.
+    pub id: u64,
+    pub notice: Notice,
+    pub at: SystemTime,
+}
+
+/// Where notices are posted. Clones post to the same place.
+#[derive(Clone)]
+pub struct Notices {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    /// Held while posting, so a subscriber never misses a notice nor gets one twice.
+    history: Mutex<VecDeque<Posted>>,
+    tx: broadcast::Sender<Posted>,
+    draining: AtomicBool,
+}
+
+impl Default for Notices {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                history: Mutex::new(VecDeque::with_capacity(HISTORY)),
+                tx: broadcast::channel(HISTORY).0,
+                draining: AtomicBool::new(false),
+            }),
+        }
+    }
+}
+
+impl Notices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn post(&self, notice: Notice) {
+        if notice == Notice::Draining {
+            self.inner.draining.store(true, Ordering::Relaxed);
+        }
+        let mut history = self.inner.history.lock().unwrap_or_else(|e| e.into_inner());
+        let posted = Posted {
+            id: history.back().map_or(1, |last| last.id + 1),
+            notice,
+            at: SystemTime::now(),
+        };
+        if history.len() == HISTORY {
+            history.pop_front();
+        }
+        history.push_back(posted);
+        // Nobody may be listening.
+        let _ = self.inner.tx.send(posted);
+    }
+
+    /// The notices kept that came after the one numbered `after`, none without it, and the
+    /// ones to come.
+    pub fn subscribe(&self, after: Option<u64>) -> (Vec<Posted>, broadcast::Receiver<Posted>) {
+        let history = self.inner.history.lock().unwrap_or_else(|e| e.into_inner());
+        let missed = match after {
+            Some(after) => history.iter().filter(|p| p.id > after).copied().collect(),
+            None => Vec::new(),
+        };
+        (missed, self.inner.tx.subscribe())
+    }
+
+    /// Whether the server has started draining.
+    pub fn draining(&self) -> bool {
+        self.inner.draining.load(Ordering::Relaxed)
+    }
+}
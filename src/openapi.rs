@@ -0,0 +1,81 @@
+//! An OpenAPI 3 description generated from the route table.
+
+use serde_json::{json, Map, Value};
+
+use crate::router::RouteInfo;
+
+/// Builds the document. Only what the route table knows goes in: paths, methods and path
+/// parameters, all typed as strings.
+pub fn document(title: &str, version: &str, routes: &[RouteInfo]) -> Value {
+    let mut paths = Map::new();
+    for route in routes {
+        let params = path_params(&route.pattern);
+        let parameters = params
+            .iter()
+            .map(|(name, catch_all)| {
+                let description = match catch_all {
+                    true => "The rest of the path, may contain slashes",
+                    false => "A single path segment",
+                };
+                json!({
+                    "name": name,
+                    "in": "path",
+                    "required": true,
+                    "description": description,
+                    "schema": { "type": "string" },
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut operations = Map::new();
+        for method in &route.methods {
+            operations.insert(
+                method.to_ascii_lowercase(),
+                json!({
+                    "operationId": operation_id(method, &route.pattern),
+                    "parameters": parameters,
+                    "responses": { "default": { "description": "Response" } },
+                }),
+            );
+        }
+        paths.insert(template(&route.pattern), Value::Object(operations));
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": { "title": title, "version": version },
+        "paths": paths,
+    })
+}
+
+/// `{name}` and `{*name}` params, in order, with whether they're catch-alls.
+fn path_params(pattern: &str) -> Vec<(&str, bool)> {
+    pattern
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .map(|name| match name.strip_prefix('*') {
+            Some(name) => (name, true),
+            None => (name, false),
+        })
+        .collect()
+}
+
+/// OpenAPI has no catch-all syntax, so `{*name}` is written as a plain `{name}`.
+fn template(pattern: &str) -> String {
+    pattern.replace("{*", "{")
+}
+
+fn operation_id(method: &str, pattern: &str) -> String {
+    let mut id = method.to_ascii_lowercase();
+    for part in pattern.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let mut chars = part.chars();
+        if let Some(first) = chars.next() {
+            id.push(first.to_ascii_uppercase());
+            id.extend(chars);
+        }
+    }
+    if id.len() == method.len() {
+        id.push_str("Root");
+    }
+    id
+}
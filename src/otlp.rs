@@ -0,0 +1,306 @@
+//! Request spans exported to an OpenTelemetry collector, so that the server's share of a
+//! request's latency shows up in Jaeger, Tempo or whatever else reads OTLP.
+//!
+//! [`OtlpLayer`] picks up the `request` span the server opens for every request, with the
+//! route it matched, its status, the client's IP and, for `/files`, the file's path, and sends
+//! them as server spans in the JSON encoding of OTLP/HTTP to `{endpoint}/v1/traces`, in the
+//! trace the request's [`TraceContext`](crate::trace_context::TraceContext) says. They go
+//! in batches, from a thread of their own, at most a couple of seconds after they end; spans
+//! that end as the process exits don't make it out.
+
+use std::{
+    fmt,
+    sync::mpsc::{self, Receiver, SyncSender},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    warn, Metadata, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+use crate::{proxy::Upstream, webhook};
+
+/// The name of the span exported, the one the server opens per request.
+const REQUEST_SPAN: &str = "request";
+/// How many ended spans wait for the exporter before new ones are dropped.
+const QUEUE: usize = 4096;
+/// The most spans sent in one export.
+const BATCH: usize = 512;
+/// How long a span can wait for the rest of its batch.
+const BATCH_DELAY: Duration = Duration::from_secs(2);
+
+/// A [`Layer`] sending request spans to a collector.
+pub struct OtlpLayer {
+    spans: SyncSender<SpanData>,
+}
+
+impl OtlpLayer {
+    /// Exports to the collector at `endpoint`, like `http://localhost:4318`, as the service
+    /// called `service_name`.
+    pub fn new(endpoint: Upstream, service_name: &str) -> Self {
+        let (spans, queued) = mpsc::sync_channel(QUEUE);
+        let exporter = Exporter {
+            endpoint,
+            resource: json!({
+                "attributes": [attribute("service.name", &AttrValue::Str(service_name.to_owned()))],
+            }),
+        };
+        std::thread::Builder::new()
+            .name("otlp-export".to_owned())
+            .spawn(move || exporter.run(queued))
+            .expect("spawning the OTLP exporter thread");
+        Self { spans }
+    }
+}
+
+/// Whether spans and events like the one in `metadata` are exported, for filtering the layer
+/// with [`filter_fn`](tracing_subscriber::filter::filter_fn) so that it doesn't have every
+/// span enabled for it.
+pub fn is_exported(metadata: &Metadata<'_>) -> bool {
+    metadata.is_span() && metadata.name() == REQUEST_SPAN
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !is_exported(attrs.metadata()) {
+            return;
+        }
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut data = SpanData::start();
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(data);
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(mut data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        data.end = SystemTime::now();
+        // A collector that can't keep up loses spans rather than holding up requests.
+        let _ = self.spans.try_send(data);
+    }
+}
+
+/// A request span, as recorded so far.
+struct SpanData {
+    trace_id: String,
+    span_id: String,
+    parent_span_id: Option<String>,
+    trace_state: Option<String>,
+    start: SystemTime,
+    end: SystemTime,
+    method: Option<String>,
+    route: Option<String>,
+    status: Option<u64>,
+    attributes: Vec<(&'static str, AttrValue)>,
+}
+
+enum AttrValue {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl SpanData {
+    fn start() -> Self {
+        let span_id = uuid::Uuid::new_v4().simple().to_string();
+        Self {
+            trace_id: uuid::Uuid::new_v4().simple().to_string(),
+            span_id: span_id[..16].to_owned(),
+            parent_span_id: None,
+            trace_state: None,
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            method: None,
+            route: None,
+            status: None,
+            attributes: Vec::new(),
+        }
+    }
+
+    fn set(&mut self, field: &Field, value: AttrValue) {
+        // The span's own field names, as the semantic conventions have them.
+        let key = match field.name() {
+            "method" => {
+                if let AttrValue::Str(method) = &value {
+                    self.method = Some(method.clone());
+                }
+                "http.request.method"
+            }
+            "route" => {
+                if let AttrValue::Str(route) = &value {
+                    self.route = Some(route.clone());
+                }
+                "http.route"
+            }
+            "status" => {
+                if let AttrValue::Int(status) = value {
+                    self.status = u64::try_from(status).ok();
+                }
+                "http.response.status_code"
+            }
+            // Where the span is in its trace, rather than what it's about.
+            "trace_id" | "span_id" | "parent_span_id" | "tracestate" => {
+                if let AttrValue::Str(value) = value {
+                    match field.name() {
+                        "trace_id" => self.trace_id = value,
+                        "span_id" => self.span_id = value,
+                        "parent_span_id" => self.parent_span_id = Some(value),
+                        _ => self.trace_state = Some(value),
+                    }
+                }
+                return;
+            }
+            "path" => "url.path",
+            "client_ip" => "client.address",
+            "file_path" => "file.path",
+            "bytes" => "http.response.body.size",
+            "id" => "http.request.id",
+            // That's what the start and end times are for.
+            "duration_ms" => return,
+            name => name,
+        };
+        self.attributes.retain(|(existing, _)| *existing != key);
+        self.attributes.push((key, value));
+    }
+
+    fn to_json(&self) -> Value {
+        let name = match (&self.method, &self.route) {
+            (Some(method), Some(route)) => format!("{method} {route}"),
+            (Some(method), None) => method.clone(),
+            (None, _) => "HTTP".to_owned(),
+        };
+        // Only server errors count as the span failing; a 404 is the server doing its job.
+        let status = match self.status {
+            Some(status) if status >= 500 => json!({ "code": 2 }),
+            _ => json!({}),
+        };
+        let mut span = json!({
+            "traceId": self.trace_id,
+            "spanId": self.span_id,
+            "name": name,
+            "kind": 2,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(key, value)| attribute(key, value))
+                .collect::<Vec<_>>(),
+            "status": status,
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = json!(parent);
+        }
+        if let Some(state) = &self.trace_state {
+            span["traceState"] = json!(state);
+        }
+        span
+    }
+}
+
+impl Visit for SpanData {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field, AttrValue::Str(value.to_owned()));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        match i64::try_from(value) {
+            Ok(value) => self.set(field, AttrValue::Int(value)),
+            Err(_) => self.set(field, AttrValue::Str(value.to_string())),
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field, AttrValue::Int(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field, AttrValue::Bool(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        // Fields recorded with `field::display` come through here, formatted with `Display`.
+        self.set(field, AttrValue::Str(format!("{value:?}")));
+    }
+}
+
+fn attribute(key: &str, value: &AttrValue) -> Value {
+    // 64-bit integers are strings in OTLP's JSON, so that they survive JavaScript.
+    let value = match value {
+        AttrValue::Str(s) => json!({ "stringValue": s }),
+        AttrValue::Int(i) => json!({ "intValue": i.to_string() }),
+        AttrValue::Bool(b) => json!({ "boolValue": b }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let nanos = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos());
+    nanos.to_string()
+}
+
+struct Exporter {
+    endpoint: Upstream,
+    resource: Value,
+}
+
+impl Exporter {
+    /// Sends batches until the layer is gone.
+    fn run(self, queued: Receiver<SpanData>) {
+        while let Ok(first) = queued.recv() {
+            let deadline = Instant::now() + BATCH_DELAY;
+            let mut batch = vec![first];
+            while batch.len() < BATCH {
+                let left = deadline.saturating_duration_since(Instant::now());
+                match queued.recv_timeout(left) {
+                    Ok(span) => batch.push(span),
+                    Err(_) => break,
+                }
+            }
+            if let Err(e) = self.export(&batch) {
+                warn!(
+                    "Error exporting {} spans to {}: {e}",
+                    batch.len(),
+                    self.endpoint.authority()
+                );
+            }
+        }
+    }
+
+    fn export(&self, batch: &[SpanData]) -> std::io::Result<()> {
+        let body = json!({
+            "resourceSpans": [{
+                "resource": self.resource,
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                    "spans": batch.iter().map(SpanData::to_json).collect::<Vec<_>>(),
+                }],
+            }],
+        })
+        .to_string();
+        webhook::post_json(&self.endpoint, "/v1/traces", &body)
+    }
+}
@@ -0,0 +1,188 @@
+//! Thread-per-core serving: a single-threaded runtime on every core, each pinned to it and
+//! accepting on sockets of its own, instead of one work-stealing runtime for everything. A
+//! connection stays on the core that accepted it, which saves the cross-core traffic of tasks
+//! moving around at the price of cores idling while another is swamped.
+//!
+//! Give every core a socket on each address with [`Bind::reuse_port`] and the kernel does the
+//! balancing.
+//!
+//! [`Bind::reuse_port`]: crate::listener::Bind::reuse_port
+
+use std::{future::Future, io, sync::Arc, thread};
+
+use anyhow::Context;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tracing::warn;
+
+use crate::listener::Listener;
+#[cfg(feature = "tls")]
+use crate::tls::TlsAcceptor;
+
+/// Runs a serving function on each of a number of cores.
+#[derive(Debug, Clone)]
+pub struct PerCore {
+    cores: usize,
+    max_blocking_threads: Option<usize>,
+}
+
+impl PerCore {
+    pub fn new(cores: usize) -> Self {
+        Self {
+            cores: cores.max(1),
+            max_blocking_threads: None,
+        }
+    }
+
+    /// Caps the threads each core's runtime starts for blocking work like file access.
+    pub fn max_blocking_threads(mut self, max: usize) -> Self {
+        self.max_blocking_threads = Some(max);
+        self
+    }
+
+    /// Hands `listeners` out to the cores in turn, so with a socket per core on each address
+    /// every core gets one of each, and runs `serve` with its share on every one of them.
+    /// Blocks until all have returned, with the first error if any failed.
+    ///
+    /// The listeners can come from any runtime; they're moved over to the one of their core.
+    pub fn serve<F, Fut>(self, listeners: Vec<Listener>, serve: F) -> anyhow::Result<()>
+    where
+        F: Fn(Vec<Listener>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let mut shares = (0..self.cores).map(|_| Vec::new()).collect::<Vec<_>>();
+        for (i, listener) in listeners.into_iter().enumerate() {
+            shares[i % self.cores].push(Detached::new(listener)?);
+        }
+        let cpus = allowed_cpus();
+        let serve = Arc::new(serve);
+        let threads = shares
+            .into_iter()
+            .enumerate()
+            .map(|(core, share)| {
+                let (serve, this) = (serve.clone(), self.clone());
+                let cpu = cpus.get(core % cpus.len().max(1)).copied();
+                thread::Builder::new()
+                    .name(format!("core-{core}"))
+                    .spawn(move || this.run_core(cpu, share, &*serve))
+                    .context("starting a core thread")
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let mut result = Ok(());
+        for thread in threads {
+            let finished = thread
+                .join()
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("a core thread panicked")));
+            result = result.and(finished);
+        }
+        result
+    }
+
+    fn run_core<F, Fut>(
+        &self,
+        cpu: Option<usize>,
+        share: Vec<Detached>,
+        serve: &F,
+    ) -> anyhow::Result<()>
+    where
+        F: Fn(Vec<Listener>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        if let Some(cpu) = cpu {
+            if let Err(e) = pin_to(cpu) {
+                warn!("Error pinning a thread to CPU {cpu}: {e}");
+            }
+        }
+        let mut runtime = tokio::runtime::Builder::new_current_thread();
+        runtime.enable_all();
+        if let Some(max) = self.max_blocking_threads {
+            runtime.max_blocking_threads(max);
+        }
+        let runtime = runtime.build().context("starting a core's runtime")?;
+        runtime.block_on(async {
+            let listeners = share
+                .into_iter()
+                .map(Detached::attach)
+                .collect::<io::Result<Vec<_>>>()
+                .context("moving listeners to their core")?;
+            serve(listeners).await
+        })
+    }
+}
+
+/// A listener taken out of its runtime, since Tokio sockets only work in the one that opened
+/// them.
+enum Detached {
+    Tcp(std::net::TcpListener),
+    #[cfg(feature = "tls")]
+    Tls(std::net::TcpListener, TlsAcceptor),
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Detached {
+    fn new(listener: Listener) -> io::Result<Self> {
+        Ok(match listener {
+            Listener::Tcp(listener) => Self::Tcp(listener.into_std()?),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, acceptor) => Self::Tls(listener.into_std()?, acceptor),
+            #[cfg(unix)]
+            Listener::Unix(listener) => Self::Unix(listener.into_std()?),
+        })
+    }
+
+    /// Registers the listener with the current runtime.
+    fn attach(self) -> io::Result<Listener> {
+        Ok(match self {
+            Self::Tcp(listener) => Listener::Tcp(TcpListener::from_std(listener)?),
+            #[cfg(feature = "tls")]
+            Self::Tls(listener, acceptor) => {
+                Listener::Tls(TcpListener::from_std(listener)?, acceptor)
+            }
+            #[cfg(unix)]
+            Self::Unix(listener) => Listener::Unix(UnixListener::from_std(listener)?),
+        })
+    }
+}
+
+/// The CPUs this process may run on, which in a container or under `taskset` can be fewer
+/// than the machine has.
+#[cfg(target_os = "linux")]
+fn allowed_cpus() -> Vec<usize> {
+    // SAFETY: cpu_set_t is a plain bit set, valid when zeroed, and sched_getaffinity writes at
+    // most the size it's given.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        if libc::sched_getaffinity(0, std::mem::size_of_val(&set), &mut set) != 0 {
+            return Vec::new();
+        }
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| libc::CPU_ISSET(*cpu, &set))
+            .collect()
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allowed_cpus() -> Vec<usize> {
+    Vec::new()
+}
+
+#[cfg(target_os = "linux")]
+fn pin_to(cpu: usize) -> io::Result<()> {
+    // SAFETY: as above; the set is only read, and 0 means the calling thread.
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        libc::CPU_SET(cpu, &mut set);
+        match libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_to(_: usize) -> io::Result<()> {
+    Ok(())
+}
@@ -0,0 +1,146 @@
+//! Giving up root once the listeners are open, so that a server started as root to bind ports
+//! 80 and 443 serves requests as an unprivileged account.
+
+use std::{
+    ffi::{CStr, CString},
+    io,
+};
+
+/// Who to become: a user by name or ID, and a group, by default the user's own.
+#[derive(Debug, Clone, Default)]
+pub struct RunAs {
+    pub user: Option<String>,
+    pub group: Option<String>,
+}
+
+impl RunAs {
+    /// Switches the whole process, every thread of it, to the user and group, with the
+    /// user's supplementary groups (or none, given just a group). It's checked afterwards
+    /// that root can't be regained.
+    ///
+    /// A process already running as them is left alone, so one started by
+    /// [`hand_off`](crate::listener::hand_off) from a process that dropped privileges can
+    /// carry on with the same options.
+    pub fn apply(&self) -> io::Result<()> {
+        let (uid, name, user_gid) = match &self.user {
+            Some(user) => {
+                let (uid, gid, name) = lookup_user(user)?;
+                (Some(uid), Some(name), Some(gid))
+            }
+            None => (None, None, None),
+        };
+        let gid = match &self.group {
+            Some(group) => lookup_group(group)?,
+            None => user_gid.ok_or_else(|| invalid("no user or group to run as".to_owned()))?,
+        };
+
+        // SAFETY: these only read or set the process's IDs.
+        let (current_uid, current_gid) = unsafe { (libc::geteuid(), libc::getegid()) };
+        if uid.is_none_or(|uid| uid == current_uid) && gid == current_gid {
+            return Ok(());
+        }
+        let groups = match &name {
+            // SAFETY: the name is a valid C string for the duration of the call.
+            Some(name) => unsafe { libc::initgroups(name.as_ptr(), gid as _) },
+            // SAFETY: the list is one valid group ID long.
+            None => unsafe { libc::setgroups(1, &gid) },
+        };
+        check(groups, "setting the supplementary groups")?;
+        // SAFETY: as above.
+        check(unsafe { libc::setgid(gid) }, "setting the group")?;
+        if let Some(uid) = uid {
+            // SAFETY: as above.
+            check(unsafe { libc::setuid(uid) }, "setting the user")?;
+        }
+
+        // SAFETY: as above.
+        let dropped = unsafe {
+            let uid_ok = uid.is_none_or(|uid| libc::getuid() == uid && libc::geteuid() == uid);
+            uid_ok && libc::getgid() == gid && libc::getegid() == gid
+        };
+        // SAFETY: as above; this one must fail.
+        let regained = uid.is_some_and(|uid| uid != 0 && unsafe { libc::setuid(0) } == 0);
+        if !dropped || regained {
+            return Err(io::Error::other("the process could still act as root"));
+        }
+        Ok(())
+    }
+}
+
+fn check(result: libc::c_int, what: &str) -> io::Result<()> {
+    match result {
+        -1 => {
+            let e = io::Error::last_os_error();
+            Err(io::Error::new(e.kind(), format!("{what}: {e}")))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// The ID, primary group and name of a user given by name or ID.
+fn lookup_user(user: &str) -> io::Result<(libc::uid_t, libc::gid_t, CString)> {
+    let key = CString::new(user).map_err(|_| invalid(format!("invalid user name {user}")))?;
+    let mut buf = vec![0; 1024];
+    loop {
+        // SAFETY: passwd is plain data that getpwnam_r/getpwuid_r fill in, with its strings
+        // pointing into `buf`; they're copied out before `buf` goes away.
+        let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let result = unsafe {
+            match user.parse::<libc::uid_t>() {
+                Ok(uid) => {
+                    libc::getpwuid_r(uid, &mut entry, buf.as_mut_ptr(), buf.len(), &mut found)
+                }
+                Err(_) => libc::getpwnam_r(
+                    key.as_ptr(),
+                    &mut entry,
+                    buf.as_mut_ptr(),
+                    buf.len(),
+                    &mut found,
+                ),
+            }
+        };
+        match result {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if found.is_null() => return Err(invalid(format!("no user {user}"))),
+            0 => {
+                let name = unsafe { CStr::from_ptr(entry.pw_name) }.to_owned();
+                return Ok((entry.pw_uid, entry.pw_gid, name));
+            }
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
+
+/// The ID of a group given by name or ID.
+fn lookup_group(group: &str) -> io::Result<libc::gid_t> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| invalid(format!("invalid group name {group}")))?;
+    let mut buf = vec![0; 1024];
+    loop {
+        // SAFETY: as in lookup_user, only the ID is read out of the entry.
+        let mut entry: libc::group = unsafe { std::mem::zeroed() };
+        let mut found = std::ptr::null_mut();
+        let result = unsafe {
+            libc::getgrnam_r(
+                name.as_ptr(),
+                &mut entry,
+                buf.as_mut_ptr(),
+                buf.len(),
+                &mut found,
+            )
+        };
+        match result {
+            libc::ERANGE => buf.resize(buf.len() * 2, 0),
+            0 if found.is_null() => return Err(invalid(format!("no group {group}"))),
+            0 => return Ok(entry.gr_gid),
+            e => return Err(io::Error::from_raw_os_error(e)),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! What the process itself is using, sampled every few seconds for the admin `/stats`: its
+//! resident memory, open file descriptors, live tokio tasks and connection buffers. A number
+//! that only ever goes up between samples (descriptors, say) points at a leak long before the
+//! process runs out.
+//!
+//! Memory and descriptors are read from `/proc` on Linux, and descriptors from `/dev/fd`
+//! elsewhere; what can't be told is `null`.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use serde::Serialize;
+
+use crate::{access_log::rfc3339_time, buffer_pool::BufferPool, server::ConnectionStats};
+
+/// Takes [`Sample`]s, keeping the latest and the highest of each figure.
+pub struct ProcessStats {
+    buffers: Arc<BufferPool>,
+    connections: Arc<ConnectionStats>,
+    sampled: Mutex<Option<Sampled>>,
+}
+
+/// The process at one moment.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+pub struct Sample {
+    pub rss_bytes: Option<u64>,
+    pub open_files: Option<usize>,
+    /// Tasks alive on the runtime that took the sample, the main one.
+    pub tasks: Option<usize>,
+    pub open_connections: usize,
+    /// The read and write buffers open connections have checked out.
+    pub buffer_bytes: u64,
+    /// `buffer_bytes` over the open connections.
+    pub buffer_bytes_per_connection: Option<u64>,
+    /// Buffers given back and waiting to be reused.
+    pub idle_buffer_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct Sampled {
+    time: String,
+    #[serde(flatten)]
+    latest: Sample,
+    /// The highest each figure has been in any sample.
+    peak: Sample,
+}
+
+impl ProcessStats {
+    pub fn new(buffers: Arc<BufferPool>, connections: Arc<ConnectionStats>) -> Self {
+        Self {
+            buffers,
+            connections,
+            sampled: Mutex::new(None),
+        }
+    }
+
+    /// Takes a sample every `every` until the runtime shuts down, starting now.
+    pub fn spawn(self: &Arc<Self>, every: Duration) {
+        let stats = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(every);
+            loop {
+                ticks.tick().await;
+                stats.sample();
+            }
+        });
+    }
+
+    /// Takes a sample now.
+    pub fn sample(&self) -> Sample {
+        let open_connections = self.connections.snapshot().open;
+        let buffer_bytes = self.buffers.bytes_in_use();
+        let sample = Sample {
+            rss_bytes: rss_bytes(),
+            open_files: open_files(),
+            tasks: tokio::runtime::Handle::try_current()
+                .ok()
+                .map(|runtime| runtime.metrics().num_alive_tasks()),
+            open_connections,
+            buffer_bytes,
+            buffer_bytes_per_connection: (open_connections > 0)
+                .then(|| buffer_bytes / open_connections as u64),
+            idle_buffer_bytes: self.buffers.bytes_idle(),
+        };
+        let mut sampled = self.sampled.lock().unwrap_or_else(|e| e.into_inner());
+        let peak = match &*sampled {
+            Some(before) => before.peak.max(&sample),
+            None => sample,
+        };
+        *sampled = Some(Sampled {
+            time: rfc3339_time(SystemTime::now()),
+            latest: sample,
+            peak,
+        });
+        sample
+    }
+
+    /// The latest sample, when it was taken and the peaks, as JSON; `null` before the first.
+    pub fn json(&self) -> serde_json::Value {
+        let sampled = self.sampled.lock().unwrap_or_else(|e| e.into_inner());
+        serde_json::to_value(&*sampled).expect("serializing process stats")
+    }
+}
+
+impl Sample {
+    fn max(&self, other: &Self) -> Self {
+        fn max<T: Ord>(a: Option<T>, b: Option<T>) -> Option<T> {
+            a.into_iter().chain(b).max()
+        }
+        Self {
+            rss_bytes: max(self.rss_bytes, other.rss_bytes),
+            open_files: max(self.open_files, other.open_files),
+            tasks: max(self.tasks, other.tasks),
+            open_connections: self.open_connections.max(other.open_connections),
+            buffer_bytes: self.buffer_bytes.max(other.buffer_bytes),
+            buffer_bytes_per_connection: max(
+                self.buffer_bytes_per_connection,
+                other.buffer_bytes_per_connection,
+            ),
+            idle_buffer_bytes: self.idle_buffer_bytes.max(other.idle_buffer_bytes),
+        }
+    }
+}
+
+/// The resident set size, from the second field of `/proc/self/statm`, in pages.
+#[cfg(target_os = "linux")]
+fn rss_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf only reads a configuration value.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn rss_bytes() -> Option<u64> {
+    None
+}
+
+/// How many file descriptors the process has open, where that can be told.
+pub(crate) fn open_files() -> Option<usize> {
+    let dir = match cfg!(target_os = "linux") {
+        true => "/proc/self/fd",
+        false => "/dev/fd",
+    };
+    // Less the one reading the directory.
+    let entries = std::fs::read_dir(dir).ok()?;
+    Some(entries.count().saturating_sub(1))
+}
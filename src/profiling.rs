@@ -0,0 +1,113 @@
+//! CPU profiles of the running server, taken on demand from the admin listener's
+//! `GET /debug/pprof/profile`, like Go's endpoint of the same name:
+//!
+//! ```text
+//! curl -o cpu.pb 'http://127.0.0.1:9000/debug/pprof/profile?seconds=30'
+//! go tool pprof -http :8080 cpu.pb
+//! curl -o cpu.svg 'http://127.0.0.1:9000/debug/pprof/profile?seconds=10&format=flamegraph'
+//! ```
+//!
+//! The profile samples every thread's stack `frequency` times a second (99 by default) for
+//! `seconds` (30 by default, 300 at most), and comes back as pprof's protobuf or as a
+//! flamegraph SVG. Only one runs at a time; asking for another meanwhile gets a 409.
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use anyhow::Context as _;
+use pprof::protos::Message as _;
+use serde::Deserialize;
+
+use crate::{
+    error::HttpError, extract::Query, headers::CONTENT_TYPE, response::Response, status::StatusCode,
+};
+
+const MAX_SECONDS: u64 = 300;
+
+/// Whether a profile is being taken.
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct ProfileQuery {
+    seconds: Option<u64>,
+    frequency: Option<i32>,
+    #[serde(default)]
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Format {
+    #[default]
+    Pprof,
+    Flamegraph,
+}
+
+/// Clears [`RUNNING`] when the profile is done with, however that goes.
+struct Running;
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        RUNNING.store(false, Ordering::Release);
+    }
+}
+
+pub(crate) async fn profile(Query(query): Query<ProfileQuery>) -> Result<Response, HttpError> {
+    let seconds = query.seconds.unwrap_or(30);
+    if !(1..=MAX_SECONDS).contains(&seconds) {
+        return Err(HttpError::bad_request(&format!(
+            "seconds must be between 1 and {MAX_SECONDS}"
+        )));
+    }
+    let frequency = query.frequency.unwrap_or(99);
+    if !(1..=1000).contains(&frequency) {
+        return Err(HttpError::bad_request(
+            "frequency must be between 1 and 1000",
+        ));
+    }
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return Err(HttpError::new(
+            StatusCode::CONFLICT,
+            anyhow::anyhow!("A profile is already being taken"),
+        ));
+    }
+    let running = Running;
+    tracing::info!("Taking a {seconds} s CPU profile, as asked on the admin listener");
+    // The profiler runs on signals and keeps no state worth holding across an await, so the
+    // whole of it goes on a blocking thread.
+    let format = query.format;
+    let profiled = tokio::task::spawn_blocking(move || {
+        let _running = running;
+        take(Duration::from_secs(seconds), frequency, format)
+    })
+    .await
+    .map_err(anyhow::Error::new)??;
+    let content_type = match format {
+        Format::Pprof => "application/octet-stream",
+        Format::Flamegraph => "image/svg+xml",
+    };
+    Ok(Response::bytes(StatusCode::OK, profiled).with_header(CONTENT_TYPE, content_type))
+}
+
+fn take(duration: Duration, frequency: i32, format: Format) -> anyhow::Result<Vec<u8>> {
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .context("starting the profiler")?;
+    std::thread::sleep(duration);
+    let report = guard.report().build().context("building the profile")?;
+    let mut out = Vec::new();
+    match format {
+        Format::Pprof => {
+            let profile = report.pprof().context("encoding the profile")?;
+            profile.encode(&mut out).context("encoding the profile")?;
+        }
+        Format::Flamegraph => report
+            .flamegraph(&mut out)
+            .context("drawing the flamegraph")?,
+    }
+    Ok(out)
+}
@@ -0,0 +1,951 @@
+//! Forwarding requests to an upstream HTTP/1.1 origin.
+//!
+//! Bodies are streamed in both directions rather than buffered.
+
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    io,
+    pin::Pin,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    task::{ready, Context, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+    net::TcpStream,
+};
+use tracing::{info, warn};
+
+use crate::{
+    body::{Body, BodyReader, ChunkState, Framing},
+    cache_stats::CacheStats,
+    error::HttpError,
+    forwarded,
+    handler::{BoxFuture, Handler},
+    headers::{
+        ContentLength, HeaderMap, CONNECTION, CONTENT_LENGTH, HOST, KEEP_ALIVE, PROXY_AUTHENTICATE,
+        PROXY_AUTHORIZATION, RETRY_AFTER, TE, TRAILER, TRANSFER_ENCODING, UPGRADE,
+    },
+    request::{Method, Request},
+    response::{self, Response, ResponseBody},
+    status::StatusCode,
+};
+
+/// Upper bound for the upstream's status line and headers.
+const MAX_HEAD_BYTES: usize = 64 * 1024;
+
+/// Idle keep-alive connections kept around per upstream.
+const MAX_IDLE_PER_UPSTREAM: usize = 8;
+
+/// How long an idle connection is trusted to still be open on the upstream's side.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Headers that only describe one hop and are never forwarded, on top of whatever the
+/// message's own `Connection` header lists.
+const HOP_BY_HOP: &[&str] = &[
+    CONNECTION,
+    KEEP_ALIVE,
+    "Proxy-Connection",
+    PROXY_AUTHENTICATE,
+    PROXY_AUTHORIZATION,
+    TE,
+    TRAILER,
+    TRANSFER_ENCODING,
+    UPGRADE,
+];
+
+/// An upstream given as `http://host[:port][/base]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    authority: String,
+    base_path: String,
+}
+
+impl Upstream {
+    pub fn authority(&self) -> &str {
+        &self.authority
+    }
+
+    pub(crate) fn address(&self) -> String {
+        match self.authority.rsplit_once(':') {
+            Some((_, port)) if !port.ends_with(']') => self.authority.clone(),
+            _ => format!("{}:80", self.authority),
+        }
+    }
+
+    /// The target sent upstream for a request path below the mount point.
+    pub(crate) fn target(&self, path: &str, query: &str) -> String {
+        let mut target = format!("{}{path}", self.base_path);
+        if target.is_empty() {
+            target.push('/');
+        }
+        if !query.is_empty() {
+            target.push('?');
+            target.push_str(query);
+        }
+        target
+    }
+}
+
+impl FromStr for Upstream {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("only http:// upstreams are supported, got {s}"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        if authority.is_empty() {
+            return Err(format!("upstream {s} has no host"));
+        }
+        Ok(Self {
+            authority: authority.to_owned(),
+            base_path: path.trim_end_matches('/').to_owned(),
+        })
+    }
+}
+
+/// How a proxy with several upstreams picks the one for a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Balance {
+    #[default]
+    RoundRobin,
+    /// The upstream with the fewest requests in flight.
+    LeastConnections,
+    /// A hash of the client's IP address, so each client sticks to one upstream.
+    IpHash,
+}
+
+impl FromStr for Balance {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "least-conn" => Ok(Self::LeastConnections),
+            "ip-hash" => Ok(Self::IpHash),
+            _ => Err(format!(
+                "unknown balancing strategy {s}, expected round-robin, least-conn or ip-hash"
+            )),
+        }
+    }
+}
+
+/// One upstream of a pool, with its idle keep-alive connections.
+struct Member {
+    upstream: Upstream,
+    in_flight: AtomicUsize,
+    /// Cleared by the health check while the upstream is out of rotation.
+    healthy: AtomicBool,
+    idle: std::sync::Mutex<Vec<(BufReader<TcpStream>, Instant)>>,
+}
+
+impl Member {
+    fn take_idle(&self) -> Option<BufReader<TcpStream>> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        while let Some((conn, since)) = idle.pop() {
+            if since.elapsed() < IDLE_TIMEOUT {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn put_idle(&self, conn: BufReader<TcpStream>) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if idle.len() < MAX_IDLE_PER_UPSTREAM {
+            idle.push((conn, Instant::now()));
+        }
+    }
+
+    async fn connect(&self) -> Result<TcpStream, HttpError> {
+        TcpStream::connect(self.upstream.address())
+            .await
+            .with_context(|| format!("connecting to upstream {}", self.upstream.authority))
+            .map_err(bad_gateway)
+    }
+}
+
+/// Counts a request against its upstream for as long as it's alive.
+struct InFlight(Arc<Member>);
+
+impl InFlight {
+    fn new(member: Arc<Member>) -> Self {
+        member.in_flight.fetch_add(1, Ordering::Relaxed);
+        Self(member)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Pool {
+    members: Vec<Arc<Member>>,
+    balance: Balance,
+    next: AtomicUsize,
+    /// Requests sent on an idle keep-alive connection, and on a new one.
+    reused: AtomicU64,
+    connected: AtomicU64,
+}
+
+impl Pool {
+    /// Picks among the healthy upstreams not `tried` yet, falling back to the tried ones and
+    /// then to all of them: trying one that's probably down beats failing outright.
+    fn pick(&self, req: &Request, tried: &[usize]) -> usize {
+        let healthy = |i: &usize| self.members[*i].healthy.load(Ordering::Relaxed);
+        let mut candidates = (0..self.members.len())
+            .filter(|i| healthy(i) && !tried.contains(i))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            candidates = (0..self.members.len()).filter(healthy).collect();
+        }
+        if candidates.is_empty() {
+            candidates = (0..self.members.len()).collect();
+        }
+
+        let len = candidates.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let index = match (self.balance, forwarded::client_ip(req)) {
+            (Balance::LeastConnections, _) => (0..len)
+                .map(|i| (start + i) % len)
+                .min_by_key(|i| {
+                    self.members[candidates[*i]]
+                        .in_flight
+                        .load(Ordering::Relaxed)
+                })
+                .unwrap_or_default(),
+            (Balance::IpHash, Some(ip)) => {
+                let mut hasher = DefaultHasher::new();
+                ip.hash(&mut hasher);
+                hasher.finish() as usize % len
+            }
+            // Without an address to hash, spread the requests instead.
+            (Balance::RoundRobin | Balance::IpHash, _) => start % len,
+        };
+        candidates[index]
+    }
+}
+
+/// When a request that failed upstream is tried again on another upstream.
+///
+/// Only idempotent requests without a body are retried: bodies are streamed rather than kept
+/// around, so there's nothing to send a second time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Tries in total, the first one included.
+    pub attempts: u32,
+    /// How long each try may take to get the response head back.
+    pub per_try_timeout: Option<Duration>,
+    /// The longest `Retry-After` on a 503 that's waited out before the next try. A 503 asking
+    /// for more is passed on to the client.
+    pub max_retry_after: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 1,
+            per_try_timeout: None,
+            max_retry_after: Duration::from_secs(1),
+        }
+    }
+}
+
+/// How a health check tells whether an upstream is up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    /// The upstream accepts a TCP connection.
+    Tcp,
+    /// A GET of this path answers with a 2xx or 3xx status.
+    Http(String),
+}
+
+impl FromStr for Probe {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            path if path.starts_with('/') => Ok(Self::Http(path.to_owned())),
+            _ => Err(format!("expected tcp or a path to GET, got {s}")),
+        }
+    }
+}
+
+/// What of the client's certificate to pass on to upstreams, each in a header of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertField {
+    /// The subject's distinguished name.
+    Subject,
+    CommonName,
+    /// The subject alternative names, comma separated, like `DNS:a.internal, URI:spiffe://x/a`.
+    AltNames,
+}
+
+impl FromStr for CertField {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "subject" => Ok(Self::Subject),
+            "cn" => Ok(Self::CommonName),
+            "san" => Ok(Self::AltNames),
+            _ => Err(format!("expected subject, cn or san, got {s}")),
+        }
+    }
+}
+
+/// `field` of the certificate the client of `req` presented, with control characters, which
+/// a certificate's strings can have but a header can't, percent-encoded.
+#[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+fn cert_value(req: &Request, field: CertField) -> Option<String> {
+    #[cfg(feature = "tls")]
+    if let Some(cert) = req.extensions.get::<crate::tls::ClientCertificate>() {
+        let value = match field {
+            CertField::Subject => cert.subject().to_owned(),
+            CertField::CommonName => cert.common_name()?.to_owned(),
+            CertField::AltNames if cert.alt_names().is_empty() => return None,
+            CertField::AltNames => {
+                let names = cert.alt_names().iter().map(ToString::to_string);
+                names.collect::<Vec<_>>().join(", ")
+            }
+        };
+        return Some(
+            value
+                .chars()
+                .map(|c| match c.is_control() {
+                    true => format!("%{:02X}", c as u32),
+                    false => c.to_string(),
+                })
+                .collect(),
+        );
+    }
+    None
+}
+
+/// Periodic probing of a proxy's upstreams, taking the failing ones out of rotation.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub probe: Probe,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// Consecutive failures after which an upstream is ejected.
+    pub fall: u32,
+    /// Consecutive successes after which an ejected upstream is reinstated.
+    pub rise: u32,
+}
+
+impl HealthCheck {
+    pub fn new(probe: Probe) -> Self {
+        Self {
+            probe,
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(2),
+            fall: 3,
+            rise: 2,
+        }
+    }
+
+    async fn check(&self, upstream: &Upstream) -> anyhow::Result<()> {
+        let mut stream = tokio::time::timeout(self.timeout, TcpStream::connect(upstream.address()))
+            .await
+            .context("timed out connecting")?
+            .context("connecting")?;
+        let Probe::Http(path) = &self.probe else {
+            return Ok(());
+        };
+
+        let head = format!(
+            "GET {path} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            upstream.authority
+        );
+        let exchange = async {
+            stream.write_all(head.as_bytes()).await?;
+            read_response_head(&mut BufReader::new(&mut stream)).await
+        };
+        let response = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .context("timed out waiting for a response")??;
+        anyhow::ensure!(
+            (200..400).contains(&response.status.0),
+            "answered {}",
+            response.status.0
+        );
+        Ok(())
+    }
+
+    /// Probes the pool until it's dropped.
+    async fn run(self, pool: Weak<Pool>) {
+        // Consecutive results of the same kind, positive for successes.
+        let mut streaks = Vec::new();
+        let mut interval = tokio::time::interval(self.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let Some(pool) = pool.upgrade() else {
+                return;
+            };
+            let results = futures_util::future::join_all(
+                pool.members
+                    .iter()
+                    .map(|member| self.check(&member.upstream)),
+            )
+            .await;
+
+            streaks.resize(pool.members.len(), 0i64);
+            for ((member, result), streak) in pool.members.iter().zip(results).zip(&mut streaks) {
+                *streak = match (&result, *streak) {
+                    (Ok(()), n) if n > 0 => n + 1,
+                    (Ok(()), _) => 1,
+                    (Err(_), n) if n < 0 => n - 1,
+                    (Err(_), _) => -1,
+                };
+
+                let healthy = member.healthy.load(Ordering::Relaxed);
+                if healthy && *streak <= -i64::from(self.fall) {
+                    member.healthy.store(false, Ordering::Relaxed);
+                    let e = result.expect_err("failure streak");
+                    warn!(
+                        "Upstream {} failed its health check, taking it out of rotation: {e:#}",
+                        member.upstream.authority
+                    );
+                } else if !healthy && *streak >= i64::from(self.rise) {
+                    member.healthy.store(true, Ordering::Relaxed);
+                    info!(
+                        "Upstream {} passed its health check, back in rotation",
+                        member.upstream.authority
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// A snapshot of one upstream, for status pages.
+#[derive(Debug, Clone, Serialize)]
+pub struct UpstreamStatus {
+    pub upstream: String,
+    pub healthy: bool,
+    pub in_flight: usize,
+}
+
+/// Forwards requests to upstream origins. When [mounted](crate::router::Router::mount), the
+/// prefix is replaced by the upstream's base path.
+///
+/// Requests without a body reuse idle connections to the upstream; ones with a body get a
+/// fresh connection that's closed afterwards, as their body is streamed while the response is
+/// already being read.
+///
+/// Protocol upgrades (WebSockets and the like) aren't forwarded: `Upgrade` is hop-by-hop and
+/// gets dropped like the rest.
+#[derive(Clone)]
+pub struct Proxy {
+    pool: Arc<Pool>,
+    preserve_host: bool,
+    retry: RetryPolicy,
+    cert_headers: Vec<(CertField, String)>,
+}
+
+impl Proxy {
+    pub fn new(upstream: Upstream) -> Self {
+        Self::balanced(vec![upstream], Balance::default())
+    }
+
+    /// Spreads requests over several upstreams.
+    ///
+    /// # Panics
+    ///
+    /// If `upstreams` is empty.
+    pub fn balanced(upstreams: Vec<Upstream>, balance: Balance) -> Self {
+        assert!(!upstreams.is_empty(), "a proxy needs at least one upstream");
+        let members = upstreams
+            .into_iter()
+            .map(|upstream| {
+                Arc::new(Member {
+                    upstream,
+                    in_flight: AtomicUsize::new(0),
+                    healthy: AtomicBool::new(true),
+                    idle: Default::default(),
+                })
+            })
+            .collect();
+        Self {
+            pool: Arc::new(Pool {
+                members,
+                balance,
+                next: AtomicUsize::new(0),
+                reused: AtomicU64::new(0),
+                connected: AtomicU64::new(0),
+            }),
+            preserve_host: false,
+            retry: RetryPolicy::default(),
+            cert_headers: Vec::new(),
+        }
+    }
+
+    /// Passes the client's Host header on instead of naming the upstream.
+    pub fn preserve_host(mut self, preserve: bool) -> Self {
+        self.preserve_host = preserve;
+        self
+    }
+
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = policy;
+        self
+    }
+
+    /// Tells upstreams `field` of the client's certificate in the header `name`, like
+    /// `X-Client-Cert-CN`. Whatever the client sent in that header is dropped either way, so
+    /// without a certificate upstreams get none rather than one the client made up.
+    pub fn cert_header(mut self, field: CertField, name: impl Into<String>) -> Self {
+        self.cert_headers.push((field, name.into()));
+        self
+    }
+
+    /// Starts probing the upstreams in the background, for as long as the proxy or a clone of
+    /// it is around. Must be called from within a Tokio runtime.
+    pub fn health_check(self, check: HealthCheck) -> Self {
+        tokio::spawn(check.run(Arc::downgrade(&self.pool)));
+        self
+    }
+
+    pub fn upstreams(&self) -> impl Iterator<Item = &Upstream> {
+        self.pool.members.iter().map(|member| &member.upstream)
+    }
+
+    /// Closes the idle keep-alive connections to the upstreams, returning how many there were,
+    /// so the next requests connect afresh (say, after an upstream's address changed in DNS).
+    pub fn close_idle(&self) -> usize {
+        self.pool
+            .members
+            .iter()
+            .map(|member| {
+                let mut idle = member.idle.lock().unwrap_or_else(|e| e.into_inner());
+                idle.drain(..).count()
+            })
+            .sum()
+    }
+
+    /// How often requests got to reuse an idle keep-alive connection.
+    pub fn connection_reuse(&self) -> CacheStats {
+        CacheStats {
+            hits: self.pool.reused.load(Ordering::Relaxed),
+            misses: self.pool.connected.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn status(&self) -> Vec<UpstreamStatus> {
+        self.pool
+            .members
+            .iter()
+            .map(|member| UpstreamStatus {
+                upstream: member.upstream.authority.clone(),
+                healthy: member.healthy.load(Ordering::Relaxed),
+                in_flight: member.in_flight.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    async fn forward(&self, req: Request) -> Result<Response, HttpError> {
+        let has_body = req.body.is_chunked() || req.body.content_length().unwrap_or(0) > 0;
+        if has_body {
+            let member = &self.pool.members[self.pool.pick(&req, &[])];
+            let head = self.request_head(&req, &member.upstream, false);
+            return self
+                .forward_streaming(req, head, InFlight::new(member.clone()))
+                .await;
+        }
+
+        let attempts = match is_idempotent(&req.method) {
+            true => self.retry.attempts.max(1),
+            false => 1,
+        };
+        let mut tried = Vec::new();
+        for attempt in 1..=attempts {
+            let index = self.pool.pick(&req, &tried);
+            tried.push(index);
+            let member = &self.pool.members[index];
+            let head = self.request_head(&req, &member.upstream, true);
+            let try_ = self.forward_pooled(&req.method, head, InFlight::new(member.clone()));
+            let result = match self.retry.per_try_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, try_)
+                    .await
+                    .unwrap_or_else(|_| Err(HttpError::gateway_timeout())),
+                None => try_.await,
+            };
+            if attempt == attempts {
+                return result;
+            }
+
+            let (reason, retry_after) = match &result {
+                Err(e) if is_retryable(e.status) => (e.to_string(), None),
+                Ok(response) if is_retryable(response.status) => {
+                    let retry_after = response
+                        .header(RETRY_AFTER)
+                        .and_then(|secs| secs.trim().parse().ok())
+                        .map(Duration::from_secs);
+                    (format!("answered {}", response.status.0), retry_after)
+                }
+                _ => return result,
+            };
+            match retry_after {
+                Some(delay) if delay > self.retry.max_retry_after => return result,
+                Some(delay) => {
+                    drop(result);
+                    tokio::time::sleep(delay).await;
+                }
+                None => {}
+            }
+            warn!(
+                "Upstream {} failed {} {}: {reason}, trying again",
+                member.upstream.authority, req.method, req.path
+            );
+        }
+        unreachable!("the last attempt returns")
+    }
+
+    async fn forward_pooled(
+        &self,
+        method: &Method,
+        head: String,
+        in_flight: InFlight,
+    ) -> Result<Response, HttpError> {
+        let member = in_flight.0.clone();
+        let (mut conn, reused) = match member.take_idle() {
+            Some(conn) => (conn, true),
+            None => (BufReader::new(member.connect().await?), false),
+        };
+        let counter = match reused {
+            true => &self.pool.reused,
+            false => &self.pool.connected,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        let response_head = match exchange(&mut conn, &head).await {
+            Ok(response_head) => response_head,
+            // An idle connection the upstream has closed in the meantime; nothing was
+            // processed, so it's safe to go again on a fresh one.
+            Err(_) if reused => {
+                conn = BufReader::new(member.connect().await?);
+                exchange(&mut conn, &head).await.map_err(bad_gateway)?
+            }
+            Err(e) => return Err(bad_gateway(e)),
+        };
+
+        let keep_alive = response_head.keep_alive;
+        let release: Release<BufReader<TcpStream>> = match keep_alive {
+            true => Some(Box::new(move |conn| member.put_idle(conn))),
+            false => None,
+        };
+        response_head.into_response(method, conn, release, in_flight)
+    }
+
+    async fn forward_streaming(
+        &self,
+        mut req: Request,
+        head: String,
+        in_flight: InFlight,
+    ) -> Result<Response, HttpError> {
+        let stream = in_flight.0.connect().await?;
+        let (reader, mut writer) = stream.into_split();
+        writer
+            .write_all(head.as_bytes())
+            .await
+            .context("sending request head upstream")
+            .map_err(bad_gateway)?;
+
+        // The upstream may answer before it has read the whole body, so send it alongside.
+        let body = std::mem::replace(&mut req.body, Body::empty());
+        tokio::spawn(async move {
+            if let Err(e) = send_body(body, &mut writer).await {
+                warn!("Error sending request body upstream: {e}");
+            }
+        });
+
+        let mut reader = BufReader::new(reader);
+        let response_head = read_response_head(&mut reader).await.map_err(bad_gateway)?;
+        response_head.into_response(&req.method, reader, None, in_flight)
+    }
+
+    fn request_head(&self, req: &Request, upstream: &Upstream, keep_alive: bool) -> String {
+        let mut headers = req.headers.clone();
+        remove_hop_by_hop(&mut headers);
+        headers.remove(CONTENT_LENGTH);
+        forwarded::add_forwarding_headers(&mut headers, req);
+        for (_, name) in &self.cert_headers {
+            headers.remove(name);
+        }
+        for (field, name) in &self.cert_headers {
+            if let Some(value) = cert_value(req, *field) {
+                headers.insert(name, &value);
+            }
+        }
+        if !self.preserve_host || !headers.contains(HOST) {
+            headers.insert(HOST, &upstream.authority);
+        }
+        if !keep_alive {
+            headers.insert(CONNECTION, "close");
+        }
+        if req.body.is_chunked() {
+            headers.insert(TRANSFER_ENCODING, "chunked");
+        } else if let Some(len) = req.body.content_length().filter(|len| *len > 0) {
+            headers.typed_insert(&ContentLength(len));
+        }
+
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\n",
+            req.method,
+            upstream.target(&req.path, &req.query)
+        );
+        for (name, value) in &headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        head
+    }
+}
+
+async fn exchange(conn: &mut BufReader<TcpStream>, head: &str) -> anyhow::Result<ResponseHead> {
+    conn.get_mut()
+        .write_all(head.as_bytes())
+        .await
+        .context("sending request head upstream")?;
+    read_response_head(conn).await
+}
+
+/// What to do with the connection once the response body has been read to its end.
+type Release<R> = Option<Box<dyn FnOnce(R) + Send>>;
+
+pub(crate) struct ResponseHead {
+    pub(crate) status: StatusCode,
+    pub(crate) headers: HeaderMap,
+    /// Whether the upstream is fine with another request on the same connection.
+    pub(crate) keep_alive: bool,
+}
+
+impl ResponseHead {
+    fn into_response<R>(
+        mut self,
+        method: &Method,
+        reader: R,
+        release: Release<R>,
+        in_flight: InFlight,
+    ) -> Result<Response, HttpError>
+    where
+        R: AsyncBufRead + Send + Unpin + 'static,
+    {
+        let framing = response_framing(method, self.status, &self.headers).map_err(bad_gateway)?;
+        remove_hop_by_hop(&mut self.headers);
+        let body = match framing {
+            Framing::Empty => {
+                if let Some(release) = release {
+                    release(reader);
+                }
+                ResponseBody::Empty
+            }
+            framing => {
+                // A body delimited by the upstream closing the connection leaves nothing to
+                // reuse.
+                let release = release.filter(|_| framing != Framing::UntilClose);
+                ResponseBody::Reader(Box::new(UpstreamBody {
+                    body: Some(BodyReader { reader, framing }),
+                    release,
+                    _in_flight: in_flight,
+                }))
+            }
+        };
+
+        let mut response = Response::empty(self.status);
+        response.headers = self.headers;
+        response.body = body;
+        Ok(response)
+    }
+}
+
+/// A response body read from an upstream connection, which is handed back once the body has
+/// been read to its end.
+struct UpstreamBody<R> {
+    body: Option<BodyReader<R>>,
+    release: Release<R>,
+    _in_flight: InFlight,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for UpstreamBody<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let Some(body) = &mut this.body else {
+            return Poll::Ready(Ok(()));
+        };
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut *body).poll_read(cx, buf))?;
+        if buf.filled().len() == before && buf.remaining() > 0 {
+            let body = this.body.take().expect("checked above");
+            if let Some(release) = this.release.take() {
+                release(body.reader);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        method,
+        Method::Get | Method::Head | Method::Options | Method::Trace | Method::Put | Method::Delete
+    )
+}
+
+fn is_retryable(status: StatusCode) -> bool {
+    status == StatusCode::BAD_GATEWAY
+        || status == StatusCode::SERVICE_UNAVAILABLE
+        || status == StatusCode::GATEWAY_TIMEOUT
+}
+
+fn bad_gateway(e: anyhow::Error) -> HttpError {
+    HttpError::new(StatusCode::BAD_GATEWAY, e)
+}
+
+fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let listed = headers
+        .get_all(CONNECTION)
+        .flat_map(|v| v.split(','))
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    for name in HOP_BY_HOP
+        .iter()
+        .copied()
+        .chain(listed.iter().map(String::as_str))
+    {
+        headers.remove(name);
+    }
+}
+
+async fn send_body(mut body: Body, writer: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+    if body.is_chunked() {
+        while let Some(chunk) = body.chunk().await.context("reading request body")? {
+            response::write_chunk(writer, &chunk).await?;
+        }
+        response::write_last_chunk(writer).await?;
+    } else {
+        tokio::io::copy(&mut body, writer)
+            .await
+            .context("copying request body")?;
+    }
+    writer.flush().await.context("flushing upstream")
+}
+
+/// Reads the final response head, skipping any interim 1xx responses.
+pub(crate) async fn read_response_head(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> anyhow::Result<ResponseHead> {
+    let mut read = 0;
+    loop {
+        let mut status_line = String::new();
+        read += reader
+            .read_line(&mut status_line)
+            .await
+            .context("reading upstream status line")?;
+        if status_line.is_empty() {
+            bail!("upstream closed the connection without responding");
+        }
+        let mut parts = status_line.trim_end().splitn(3, ' ');
+        let version = parts.next().unwrap_or_default();
+        if !version.starts_with("HTTP/1.") {
+            bail!("upstream sent an invalid status line {status_line:?}");
+        }
+        let status = parts
+            .next()
+            .and_then(|code| code.parse::<u16>().ok())
+            .filter(|code| (100..1000).contains(code))
+            .with_context(|| format!("upstream sent an invalid status line {status_line:?}"))?;
+
+        let mut headers = HeaderMap::new();
+        loop {
+            let mut line = String::new();
+            let n = reader
+                .read_line(&mut line)
+                .await
+                .context("reading upstream headers")?;
+            read += n;
+            anyhow::ensure!(n > 0, "upstream closed the connection mid-headers");
+            anyhow::ensure!(read <= MAX_HEAD_BYTES, "upstream response head too large");
+            if line.trim().is_empty() {
+                break;
+            }
+            let (name, value) = line
+                .split_once(':')
+                .with_context(|| format!("invalid upstream header line {line:?}"))?;
+            headers.append(name.trim(), value.trim());
+        }
+
+        if (100..200).contains(&status) {
+            continue;
+        }
+        let close = headers
+            .get_all(CONNECTION)
+            .flat_map(|v| v.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case("close"));
+        return Ok(ResponseHead {
+            status: StatusCode(status),
+            headers,
+            keep_alive: version == "HTTP/1.1" && !close,
+        });
+    }
+}
+
+/// How the upstream's body is delimited.
+pub(crate) fn response_framing(
+    method: &Method,
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> anyhow::Result<Framing> {
+    if *method == Method::Head
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return Ok(Framing::Empty);
+    }
+    if headers.contains(TRANSFER_ENCODING) {
+        let last_coding = headers
+            .get_all(TRANSFER_ENCODING)
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .last()
+            .unwrap_or_default();
+        if !last_coding.eq_ignore_ascii_case("chunked") {
+            return Ok(Framing::UntilClose);
+        }
+        return Ok(Framing::Chunked(ChunkState::Size(Vec::new())));
+    }
+    match headers
+        .typed_get::<ContentLength>()
+        .context("upstream sent an invalid Content-Length")?
+    {
+        Some(ContentLength(len)) => Ok(Framing::Length(len)),
+        None => Ok(Framing::UntilClose),
+    }
+}
+
+impl Handler for Proxy {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let proxy = self.clone();
+        Box::pin(async move { proxy.forward(req).await })
+    }
+}
@@ -0,0 +1,234 @@
+//! The [PROXY protocol] header load balancers like HAProxy send ahead of the client's bytes to
+//! pass on the address the connection really came from. Both the text (v1) and binary (v2)
+//! versions are understood.
+//!
+//! [PROXY protocol]: https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::{bail, Context};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest a v1 header can be, line ending included.
+const V1_MAX_LEN: usize = 107;
+
+/// The address of the balancer a connection came through, for requests whose client address
+/// its PROXY header gave. It's in the extensions of every request on the connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Balancer(pub SocketAddr);
+
+/// Reads the PROXY protocol header off the start of a connection and returns the client
+/// address it carries. That's `None` for connections the balancer opened itself (health checks)
+/// and for protocols other than TCP over IPv4 or IPv6.
+///
+/// The header is mandatory once the listener expects one: a connection without it is an
+/// error, since treating its first bytes as a request would let clients pick their address.
+pub async fn read_header<R>(reader: &mut R) -> anyhow::Result<Option<SocketAddr>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    // Both versions are longer than the v2 signature, so this never reads into the request.
+    let mut start = [0; V2_SIGNATURE.len()];
+    reader
+        .read_exact(&mut start)
+        .await
+        .context("reading PROXY protocol header")?;
+    if start == V2_SIGNATURE {
+        read_v2(reader).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(reader, &start).await
+    } else {
+        bail!("connection didn't start with a PROXY protocol header")
+    }
+}
+
+async fn read_v1<R>(reader: &mut R, start: &[u8]) -> anyhow::Result<Option<SocketAddr>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut line = start.to_vec();
+    reader
+        .take((V1_MAX_LEN - start.len()) as u64)
+        .read_until(b'\n', &mut line)
+        .await
+        .context("reading PROXY protocol header")?;
+    let line = std::str::from_utf8(&line)
+        .ok()
+        .and_then(|line| line.strip_suffix("\r\n"))
+        .context("PROXY protocol header isn't a CRLF-terminated line")?;
+
+    let mut fields = line.split(' ').skip(1);
+    match fields.next() {
+        Some("TCP4" | "TCP6") => {}
+        Some("UNKNOWN") => return Ok(None),
+        _ => bail!("unsupported PROXY protocol header: {line}"),
+    }
+    let (Some(ip), Some(_), Some(port), Some(_), None) = (
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+        fields.next(),
+    ) else {
+        bail!("malformed PROXY protocol header: {line}");
+    };
+    let ip: IpAddr = ip.parse().context("invalid source address")?;
+    let port: u16 = port.parse().context("invalid source port")?;
+    Ok(Some(SocketAddr::new(ip, port)))
+}
+
+async fn read_v2<R>(reader: &mut R) -> anyhow::Result<Option<SocketAddr>>
+where
+    R: AsyncBufRead + Unpin,
+{
+    let mut fixed = [0; 4];
+    reader
+        .read_exact(&mut fixed)
+        .await
+        .context("reading PROXY protocol header")?;
+    let [version_command, family, len @ ..] = fixed;
+    if version_command >> 4 != 2 {
+        bail!(
+            "unsupported PROXY protocol version {}",
+            version_command >> 4
+        );
+    }
+    // Addresses come first, followed by TLVs this server has no use for.
+    let mut payload = vec![0; usize::from(u16::from_be_bytes(len))];
+    reader
+        .read_exact(&mut payload)
+        .await
+        .context("reading PROXY protocol addresses")?;
+
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        command => bail!("unsupported PROXY protocol command {command}"),
+    }
+    let addr = match family {
+        0x11 => payload.first_chunk::<12>().map(|addrs| {
+            let ip = Ipv4Addr::from(*addrs.first_chunk::<4>().unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([addrs[8], addrs[9]]))
+        }),
+        0x21 => payload.first_chunk::<36>().map(|addrs| {
+            let ip = Ipv6Addr::from(*addrs.first_chunk::<16>().unwrap());
+            SocketAddr::new(ip.into(), u16::from_be_bytes([addrs[32], addrs[33]]))
+        }),
+        // UDP, unix sockets and unspecified families don't make a peer address.
+        _ => return Ok(None),
+    };
+    addr.map(Some)
+        .context("PROXY protocol header too short for its addresses")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(input: &[u8]) -> anyhow::Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut reader = input;
+        let addr = read_header(&mut reader).await?;
+        Ok((addr, reader.to_vec()))
+    }
+
+    fn v2(command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header.extend(b"GET / HTTP/1.1\r\n");
+        header
+    }
+
+    #[tokio::test]
+    async fn reads_v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.7 10.0.0.1 56324 443\r\nGET / HTTP/1.1\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("192.0.2.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::7 2001:db8::1 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:56324".parse().unwrap()));
+
+        let (addr, rest) = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nGET")
+            .await
+            .unwrap();
+        assert_eq!((addr, rest.as_slice()), (None, &b"GET"[..]));
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_v1() {
+        let longest = format!("PROXY TCP4 {} 10.0.0.1 1 2\r\n", "1".repeat(V1_MAX_LEN));
+        for input in [
+            &b"PROXY TCP4 192.0.2.7 10.0.0.1 56324"[..],
+            b"PROXY TCP4 192.0.2.7 10.0.0.1 56324 443\n",
+            b"PROXY TCP4 192.0.2.7 10.0.0.1 56324\r\n",
+            b"PROXY TCP4 192.0.2.7 10.0.0.1 56324 443 extra\r\n",
+            b"PROXY TCP4 192.0.2.300 10.0.0.1 56324 443\r\n",
+            b"PROXY TCP4 192.0.2.7 10.0.0.1 65536 443\r\n",
+            b"PROXY UDP4 192.0.2.7 10.0.0.1 56324 443\r\n",
+            longest.as_bytes(),
+        ] {
+            assert!(
+                read(input).await.is_err(),
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reads_v2() {
+        let mut ipv4 = vec![192, 0, 2, 7, 10, 0, 0, 1];
+        ipv4.extend(56324u16.to_be_bytes());
+        ipv4.extend(443u16.to_be_bytes());
+        // A TLV after the addresses, which is skipped.
+        ipv4.extend([0x04, 0x00, 0x01, 0xff]);
+        let (addr, rest) = read(&v2(1, 0x11, &ipv4)).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.7:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+
+        let mut ipv6 = "2001:db8::7".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        ipv6.extend(Ipv6Addr::LOCALHOST.octets());
+        ipv6.extend([0xdc, 0x04, 0x01, 0xbb]);
+        let (addr, _) = read(&v2(1, 0x21, &ipv6)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::7]:56324".parse().unwrap()));
+
+        // LOCAL, from the balancer's own health checks, whatever the addresses say.
+        let (addr, rest) = read(&v2(0, 0x11, &ipv4)).await.unwrap();
+        assert_eq!((addr, rest.as_slice()), (None, &b"GET / HTTP/1.1\r\n"[..]));
+        // UDP over IPv4.
+        let (addr, _) = read(&v2(1, 0x12, &ipv4)).await.unwrap();
+        assert_eq!(addr, None);
+    }
+
+    #[tokio::test]
+    async fn refuses_malformed_v2() {
+        // Shorter than the IPv4 address block.
+        assert!(read(&v2(1, 0x11, &[192, 0, 2, 7, 10, 0])).await.is_err());
+        // A length running past the end of the connection.
+        let mut truncated = v2(1, 0x11, &[0; 12]);
+        truncated.truncate(V2_SIGNATURE.len() + 4 + 6);
+        assert!(read(&truncated).await.is_err());
+        // Commands other than LOCAL and PROXY, and versions other than 2.
+        assert!(read(&v2(2, 0x11, &[0; 12])).await.is_err());
+        let mut v3 = v2(1, 0x11, &[0; 12]);
+        v3[V2_SIGNATURE.len()] = 0x31;
+        assert!(read(&v3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn refuses_connections_without_a_header() {
+        for input in [&b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"[..], b"PROXY", b""] {
+            assert!(
+                read(input).await.is_err(),
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+}
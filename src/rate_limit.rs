@@ -0,0 +1,178 @@
+//! Limiting how many requests each client makes, with a token bucket per address, or per
+//! [`ClientLimit`] for clients a middleware has told apart some other way.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+use tracing::warn;
+
+use crate::{
+    error::HttpError,
+    forwarded,
+    handler::BoxFuture,
+    headers::RETRY_AFTER,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+    security_log::{self, Event},
+    settings::{RateLimit, Settings},
+    tarpit,
+};
+
+/// How often buckets that have filled up again, which are as good as new, are dropped.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Answers clients going over [`Settings::rate_limit`] with a 429 and a `Retry-After` saying
+/// when their next request would get through. Clients are told apart by
+/// [`client_ip`](forwarded::client_ip), so behind a load balancer this goes after
+/// [`TrustedProxies`](forwarded::TrustedProxies); requests without an address (over a Unix
+/// socket) aren't limited.
+///
+/// Clones share their buckets, so one limiter can cover several routers, and outlive
+/// replacing them on a reload.
+#[derive(Clone)]
+pub struct RateLimiter {
+    settings: watch::Receiver<Settings>,
+    buckets: Arc<Mutex<Buckets>>,
+}
+
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    by_client: HashMap<String, (Bucket, RateLimit)>,
+    swept: Instant,
+}
+
+/// A limit of their own for the client of a request, in its extensions, instead of
+/// [`Settings::rate_limit`] for its address. Put there by what authenticates clients, like
+/// [`ApiKeyAuth`](crate::api_key::ApiKeyAuth) for keys with a `rate-limit`.
+#[derive(Debug, Clone)]
+pub struct ClientLimit {
+    /// What the client's bucket is kept under, unique among clients with a limit.
+    pub client: String,
+    pub limit: RateLimit,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn full(limit: &RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(limit.burst),
+            updated: now,
+        }
+    }
+
+    /// Takes a token, or says how long until there is one.
+    fn take(&mut self, limit: &RateLimit, now: Instant) -> Result<(), Duration> {
+        if self.refill(limit, now) >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - self.tokens) / limit.rate))
+    }
+
+    /// Tops the bucket up for the time since it was last used.
+    fn refill(&mut self, limit: &RateLimit, now: Instant) -> f64 {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * limit.rate).min(f64::from(limit.burst));
+        self.updated = now;
+        self.tokens
+    }
+}
+
+impl RateLimiter {
+    pub fn new(settings: watch::Receiver<Settings>) -> Self {
+        Self {
+            settings,
+            buckets: Arc::new(Mutex::new(Buckets {
+                by_ip: HashMap::new(),
+                by_client: HashMap::new(),
+                swept: Instant::now(),
+            })),
+        }
+    }
+
+    /// Takes a token from `ip`'s bucket, or says how long until there is one.
+    fn take(&self, ip: IpAddr, limit: &RateLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        buckets.sweep(Some(limit), now);
+        let bucket = buckets
+            .by_ip
+            .entry(ip)
+            .or_insert_with(|| Bucket::full(limit, now));
+        bucket.take(limit, now)
+    }
+
+    /// Takes a token from the bucket of a client with a limit of its own.
+    fn take_client(&self, client: &ClientLimit) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let general = self.settings.borrow().rate_limit;
+        buckets.sweep(general.as_ref(), now);
+        let (bucket, limit) = buckets
+            .by_client
+            .entry(client.client.clone())
+            .or_insert_with(|| (Bucket::full(&client.limit, now), client.limit));
+        // The limit may have changed since the bucket was made, on a reload.
+        *limit = client.limit;
+        bucket.take(limit, now)
+    }
+}
+
+impl Buckets {
+    /// Drops the buckets that have filled up again, every [`SWEEP_INTERVAL`]; `limit` is the
+    /// one the per-address buckets are kept to, if there is one.
+    fn sweep(&mut self, limit: Option<&RateLimit>, now: Instant) {
+        if now.duration_since(self.swept) < SWEEP_INTERVAL {
+            return;
+        }
+        match limit {
+            Some(limit) => {
+                let full = f64::from(limit.burst);
+                self.by_ip
+                    .retain(|_, bucket| bucket.refill(limit, now) < full);
+            }
+            None => self.by_ip.clear(),
+        }
+        self.by_client
+            .retain(|_, (bucket, limit)| bucket.refill(limit, now) < f64::from(limit.burst));
+        self.swept = now;
+    }
+}
+
+impl Middleware for RateLimiter {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let taken = if let Some(client) = req.extensions.get::<ClientLimit>() {
+            self.take_client(client)
+                .map_err(|wait| (wait, client.client.clone()))
+        } else {
+            let limit = self.settings.borrow().rate_limit;
+            let (Some(limit), Some(ip)) = (limit, forwarded::client_ip(&req)) else {
+                return Box::pin(next.run(req));
+            };
+            self.take(ip, &limit).map_err(|wait| (wait, ip.to_string()))
+        };
+        match taken {
+            Ok(()) => Box::pin(next.run(req)),
+            Err((wait, client)) => {
+                warn!("Rate limiting {client}");
+                tarpit::mark(&req);
+                security_log::record(&req, Event::RateLimited, "over the rate limit");
+                // Whole seconds, rounded up so that coming back then works.
+                let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                let error =
+                    HttpError::too_many_requests().with_header(RETRY_AFTER, &secs.to_string());
+                Box::pin(async { Err(error) })
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! A table of moved URLs, answered with redirects before routing, and sending plain HTTP
+//! clients over to HTTPS.
+
+use std::{collections::HashMap, str::FromStr};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    error::HttpError,
+    handler::{BoxFuture, Handler},
+    headers::Host,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+    status::StatusCode,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Redirect {
+    pub path: String,
+    pub target: String,
+    pub status: StatusCode,
+}
+
+/// Parses `PATH TARGET [STATUS]`, where the status defaults to 301.
+impl FromStr for Redirect {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let (Some(path), Some(target)) = (parts.next(), parts.next()) else {
+            bail!("expected a path and a target");
+        };
+        let status = match parts.next() {
+            Some(status) => StatusCode(status.parse().context("parsing redirect status")?),
+            None => StatusCode::MOVED_PERMANENTLY,
+        };
+        if !status.is_redirection() {
+            bail!("{status} is not a redirect status");
+        }
+        if parts.next().is_some() {
+            bail!("unexpected trailing input");
+        }
+        Ok(Self {
+            path: path.to_owned(),
+            target: target.to_owned(),
+            status,
+        })
+    }
+}
+
+/// Redirects requests for exactly the listed paths, whatever their method. The query string is
+/// carried over unless the target has its own.
+#[derive(Debug, Clone, Default)]
+pub struct RedirectTable {
+    entries: HashMap<String, (String, StatusCode)>,
+}
+
+impl RedirectTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(mut self, path: &str, target: &str, status: StatusCode) -> Self {
+        self.entries
+            .insert(path.to_owned(), (target.to_owned(), status));
+        self
+    }
+}
+
+impl FromIterator<Redirect> for RedirectTable {
+    fn from_iter<I: IntoIterator<Item = Redirect>>(iter: I) -> Self {
+        iter.into_iter().fold(Self::new(), |table, r| {
+            table.add(&r.path, &r.target, r.status)
+        })
+    }
+}
+
+impl Middleware for RedirectTable {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let Some((target, status)) = self.entries.get(&req.path) else {
+            return Box::pin(next.run(req));
+        };
+
+        let location = match target.contains('?') || req.query.is_empty() {
+            true => target.clone(),
+            false => format!("{target}?{}", req.query),
+        };
+        let response = Response::redirect(*status, &location);
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+/// Sends every request to the same URL over HTTPS with a 301, for the plain HTTP listener next
+/// to the TLS ones.
+#[derive(Debug, Clone, Copy)]
+pub struct HttpsRedirect {
+    port: Option<u16>,
+}
+
+impl HttpsRedirect {
+    /// `port` is where HTTPS is served, left out of the URLs when it's `None` or 443.
+    pub fn new(port: Option<u16>) -> Self {
+        Self { port }
+    }
+
+    fn location(&self, req: &Request) -> Result<String, HttpError> {
+        let Ok(Some(host)) = req.headers.typed_get::<Host>() else {
+            return Err(HttpError::bad_request(
+                "a Host header is needed to redirect to HTTPS",
+            ));
+        };
+        let mut location = match self.port {
+            Some(port) if port != 443 => format!("https://{}:{port}", host.hostname()),
+            _ => format!("https://{}", host.hostname()),
+        };
+        location.push_str(&req.path);
+        if !req.query.is_empty() {
+            location.push('?');
+            location.push_str(&req.query);
+        }
+        Ok(location)
+    }
+}
+
+impl Handler for HttpsRedirect {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let response = self
+            .location(&req)
+            .map(|location| Response::redirect(StatusCode::MOVED_PERMANENTLY, &location));
+        Box::pin(async move { response })
+    }
+}
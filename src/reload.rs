@@ -0,0 +1,91 @@
+//! Swapping the handler of a running server.
+
+use std::{
+    path::PathBuf,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    error::HttpError,
+    handler::{BoxFuture, Handler},
+    request::Request,
+    response::Response,
+};
+
+/// A handler that can be replaced while the server runs. Requests pick up the current handler
+/// when they arrive, so the ones in flight during a swap finish on the old one.
+#[derive(Clone)]
+pub struct Reloadable {
+    current: Arc<RwLock<Arc<dyn Handler>>>,
+}
+
+impl Reloadable {
+    pub fn new(handler: impl Handler) -> Self {
+        Self {
+            current: Arc::new(RwLock::new(Arc::new(handler))),
+        }
+    }
+
+    pub fn swap(&self, handler: impl Handler) {
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(handler);
+    }
+
+    fn current(&self) -> Arc<dyn Handler> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+impl Handler for Reloadable {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        self.current().call(req)
+    }
+}
+
+/// Calls `reload` whenever one of `paths` is modified (checked every `interval`) and, on unix,
+/// when the process receives SIGHUP. Runs forever, so spawn it.
+pub async fn watch(
+    paths: impl IntoIterator<Item = PathBuf>,
+    interval: Duration,
+    mut reload: impl FnMut(),
+) {
+    let paths = paths.into_iter().collect::<Vec<_>>();
+    let modified = || -> Vec<Option<SystemTime>> {
+        paths
+            .iter()
+            .map(|path| {
+                std::fs::metadata(path)
+                    .and_then(|metadata| metadata.modified())
+                    .ok()
+            })
+            .collect()
+    };
+    let mut last_modified = modified();
+    let mut ticker = tokio::time::interval(interval);
+
+    #[cfg(unix)]
+    let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+        .expect("installing SIGHUP handler");
+
+    loop {
+        #[cfg(unix)]
+        let signalled = tokio::select! {
+            _ = ticker.tick() => false,
+            _ = hangup.recv() => true,
+        };
+        #[cfg(not(unix))]
+        let signalled = {
+            ticker.tick().await;
+            false
+        };
+
+        let current = modified();
+        if signalled || current != last_modified {
+            last_modified = current;
+            reload();
+        }
+    }
+}
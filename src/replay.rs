@@ -0,0 +1,359 @@
+//! Recorded requests sent again, one after another, to see whether a server still answers
+//! them the way it did: the same status, the same `Content-Type` and the same body.
+//!
+//! They're read from a HAR file, like the ones [`Har`](crate::har::Har) records or browser
+//! devtools export, or from a file with a JSON object per line for writing by hand:
+//!
+//! ```text
+//! {"method":"GET","path":"/echo/abc","status":200,"response_body":"abc"}
+//! {"method":"POST","path":"/files/a.txt","headers":{"Content-Type":"text/plain"},"body":"hi","status":201}
+//! ```
+//!
+//! `method` defaults to `GET`; `headers`, `body`, `content_type` and `response_body` can be
+//! left out, and what's left out isn't compared.
+
+use std::{collections::BTreeMap, fmt, path::Path, time::Duration};
+
+use anyhow::Context as _;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{
+    body::{BodyReader, Framing},
+    headers::{CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST, KEEP_ALIVE, TRANSFER_ENCODING},
+    proxy::{read_response_head, response_framing, Upstream},
+    request::Method,
+};
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A request to send, and what it got when it was recorded.
+#[derive(Debug, Clone)]
+pub struct Exchange {
+    pub method: String,
+    /// The path and query.
+    pub target: String,
+    pub host: Option<String>,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// The body, or as much of it as was recorded, and whether that's all of it.
+    pub response_body: Option<(Vec<u8>, bool)>,
+}
+
+/// Reads the exchanges in the file at `path`, a HAR file or one with a JSON object per line.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Exchange>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    if let Ok(Value::Object(har)) = serde_json::from_str::<Value>(&text) {
+        if let Some(entries) = har.get("log").and_then(|log| log["entries"].as_array()) {
+            return entries
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| from_har(entry).with_context(|| format!("HAR entry {i}")))
+                .collect();
+        }
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            let line = serde_json::from_str::<Line>(line)
+                .with_context(|| format!("line {} of {}", i + 1, path.display()))?;
+            Ok(line.into())
+        })
+        .collect()
+}
+
+/// An exchange as a line of JSON.
+#[derive(Deserialize)]
+struct Line {
+    #[serde(default = "Line::get")]
+    method: String,
+    #[serde(alias = "url")]
+    path: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    #[serde(default)]
+    body: String,
+    status: u16,
+    content_type: Option<String>,
+    response_body: Option<String>,
+}
+
+impl Line {
+    fn get() -> String {
+        "GET".to_owned()
+    }
+}
+
+impl From<Line> for Exchange {
+    fn from(line: Line) -> Self {
+        let (host, target) = split_url(&line.path);
+        Self {
+            method: line.method,
+            target,
+            host,
+            headers: line.headers.into_iter().collect(),
+            body: line.body.into_bytes(),
+            status: line.status,
+            content_type: line.content_type,
+            response_body: line.response_body.map(|body| (body.into_bytes(), true)),
+        }
+    }
+}
+
+fn from_har(entry: &Value) -> anyhow::Result<Exchange> {
+    let request = &entry["request"];
+    let response = &entry["response"];
+    let url = request["url"].as_str().context("the request has no url")?;
+    let (host, target) = split_url(url);
+    let headers = |message: &Value| {
+        message["headers"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|header| Some((header["name"].as_str()?, header["value"].as_str()?)))
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect::<Vec<_>>()
+    };
+    let response_headers = headers(response);
+    let content = &response["content"];
+    let response_body = match decode(content)? {
+        Some(body) => {
+            let full = content["size"]
+                .as_u64()
+                .is_none_or(|size| size == body.len() as u64);
+            Some((body, full))
+        }
+        None => None,
+    };
+    Ok(Exchange {
+        method: request["method"].as_str().unwrap_or("GET").to_owned(),
+        target,
+        host,
+        headers: headers(request),
+        body: decode(&request["postData"])?.unwrap_or_default(),
+        status: response["status"]
+            .as_u64()
+            .and_then(|status| u16::try_from(status).ok())
+            .context("the response has no status")?,
+        content_type: response_headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(CONTENT_TYPE))
+            .map(|(_, value)| value.clone()),
+        response_body,
+    })
+}
+
+/// The `text` of a HAR body, base64-decoded if it says it's encoded.
+fn decode(content: &Value) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(text) = content["text"].as_str() else {
+        return Ok(None);
+    };
+    match content["encoding"].as_str() {
+        Some("base64") => Ok(Some(
+            STANDARD.decode(text).context("decoding a base64 body")?,
+        )),
+        _ => Ok(Some(text.as_bytes().to_vec())),
+    }
+}
+
+/// The host and the path and query of `url`, which may be just the path.
+fn split_url(url: &str) -> (Option<String>, String) {
+    let Some(rest) = url
+        .strip_prefix("http://")
+        .or_else(|| url.strip_prefix("https://"))
+    else {
+        return (None, url.to_owned());
+    };
+    match rest.find('/') {
+        Some(slash) => (Some(rest[..slash].to_owned()), rest[slash..].to_owned()),
+        None => (Some(rest.to_owned()), "/".to_owned()),
+    }
+}
+
+/// What came of sending an exchange again.
+#[derive(Debug)]
+pub struct Outcome {
+    pub method: String,
+    pub target: String,
+    /// How the response differed from the recorded one, empty if it didn't, or why there was
+    /// no response.
+    pub result: anyhow::Result<Vec<String>>,
+}
+
+/// Sends every exchange to `server` in turn, over one keep-alive connection for as long as
+/// the server keeps it open.
+pub async fn replay(server: &Upstream, exchanges: &[Exchange]) -> Report {
+    let mut connection = None;
+    let mut outcomes = Vec::with_capacity(exchanges.len());
+    for exchange in exchanges {
+        let sent = tokio::time::timeout(REQUEST_TIMEOUT, send(server, exchange, &mut connection))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("no response in {REQUEST_TIMEOUT:?}")));
+        if sent.is_err() {
+            connection = None;
+        }
+        outcomes.push(Outcome {
+            method: exchange.method.clone(),
+            target: exchange.target.clone(),
+            result: sent.map(|response| differences(exchange, &response)),
+        });
+    }
+    Report { outcomes }
+}
+
+struct Answer {
+    status: u16,
+    content_type: Option<String>,
+    body: Vec<u8>,
+}
+
+async fn send(
+    server: &Upstream,
+    exchange: &Exchange,
+    connection: &mut Option<BufReader<TcpStream>>,
+) -> anyhow::Result<Answer> {
+    if connection.is_none() {
+        let stream = TcpStream::connect(server.address())
+            .await
+            .with_context(|| format!("connecting to {}", server.authority()))?;
+        *connection = Some(BufReader::new(stream));
+    }
+    let stream = connection.as_mut().expect("connected above");
+    let (path, query) = match exchange.target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (exchange.target.as_str(), ""),
+    };
+    let host = exchange.host.as_deref().unwrap_or(server.authority());
+    let mut head = format!(
+        "{} {} HTTP/1.1\r\nHost: {host}\r\n",
+        exchange.method,
+        server.target(path, query)
+    );
+    // The framing is this connection's, and HTTP/2 pseudo-headers don't go in HTTP/1.1.
+    let skipped = [
+        HOST,
+        CONTENT_LENGTH,
+        TRANSFER_ENCODING,
+        CONNECTION,
+        KEEP_ALIVE,
+    ];
+    for (name, value) in &exchange.headers {
+        if !name.starts_with(':') && !skipped.iter().any(|s| s.eq_ignore_ascii_case(name)) {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+    }
+    if !exchange.body.is_empty() || matches!(exchange.method.as_str(), "POST" | "PUT" | "PATCH") {
+        head.push_str(&format!("Content-Length: {}\r\n", exchange.body.len()));
+    }
+    head.push_str("\r\n");
+    let writer = stream.get_mut();
+    writer
+        .write_all(head.as_bytes())
+        .await
+        .context("sending the request")?;
+    writer
+        .write_all(&exchange.body)
+        .await
+        .context("sending the request body")?;
+    let response = read_response_head(stream).await?;
+    let method = Method::from(exchange.method.as_str());
+    let framing = response_framing(&method, response.status, &response.headers)?;
+    let reuse = response.keep_alive && framing != Framing::UntilClose;
+    let mut body = Vec::new();
+    BodyReader {
+        reader: &mut *stream,
+        framing,
+    }
+    .read_to_end(&mut body)
+    .await
+    .context("reading the response body")?;
+    if !reuse {
+        *connection = None;
+    }
+    Ok(Answer {
+        status: response.status.0,
+        content_type: response.headers.get(CONTENT_TYPE).map(str::to_owned),
+        body,
+    })
+}
+
+fn differences(exchange: &Exchange, answer: &Answer) -> Vec<String> {
+    let mut differences = Vec::new();
+    if answer.status != exchange.status {
+        differences.push(format!("status {}, was {}", answer.status, exchange.status));
+    }
+    if let Some(recorded) = exchange.content_type.as_deref() {
+        let content_type = answer.content_type.as_deref().unwrap_or("none");
+        if content_type != recorded {
+            differences.push(format!("Content-Type {content_type}, was {recorded}"));
+        }
+    }
+    if let Some((recorded, full)) = &exchange.response_body {
+        let compared = match full {
+            true => &answer.body[..],
+            false => &answer.body[..answer.body.len().min(recorded.len())],
+        };
+        if compared != &recorded[..] {
+            let at = compared
+                .iter()
+                .zip(recorded)
+                .position(|(a, b)| a != b)
+                .unwrap_or(compared.len().min(recorded.len()));
+            differences.push(format!(
+                "body of {} bytes differs from byte {at}{}",
+                answer.body.len(),
+                match full {
+                    true => format!(", was {} bytes", recorded.len()),
+                    false => String::new(),
+                }
+            ));
+        }
+    }
+    differences
+}
+
+/// What a [`replay`] found.
+#[derive(Debug)]
+pub struct Report {
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Report {
+    /// How many exchanges got a response that differed, or none at all.
+    pub fn failed(&self) -> usize {
+        self.outcomes
+            .iter()
+            .filter(|outcome| !matches!(&outcome.result, Ok(d) if d.is_empty()))
+            .count()
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let failed = self.failed();
+        writeln!(
+            f,
+            "{} requests replayed: {} answered as recorded, {failed} not",
+            self.outcomes.len(),
+            self.outcomes.len() - failed,
+        )?;
+        for outcome in &self.outcomes {
+            let problems = match &outcome.result {
+                Ok(differences) if differences.is_empty() => continue,
+                Ok(differences) => differences.join("; "),
+                Err(e) => format!("error: {e:#}"),
+            };
+            writeln!(f, "  {} {}: {problems}", outcome.method, outcome.target)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,242 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::io::AsyncBufRead;
+
+use crate::{
+    body::Body,
+    cookies::{self, Cookies},
+    headers::{Header, HeaderMap, COOKIE},
+};
+
+pub type BoxReader = Box<dyn AsyncBufRead + Send + Unpin>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Method {
+    Get,
+    Head,
+    Post,
+    Put,
+    Delete,
+    Patch,
+    Options,
+    Connect,
+    Trace,
+    Other(String),
+}
+
+impl Method {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Method::Get => "GET",
+            Method::Head => "HEAD",
+            Method::Post => "POST",
+            Method::Put => "PUT",
+            Method::Delete => "DELETE",
+            Method::Patch => "PATCH",
+            Method::Options => "OPTIONS",
+            Method::Connect => "CONNECT",
+            Method::Trace => "TRACE",
+            Method::Other(method) => method,
+        }
+    }
+
+    /// Whether the method only reads, as RFC 9110 and WebDAV define them, so that requests
+    /// with it change nothing.
+    pub fn is_safe(&self) -> bool {
+        match self {
+            Method::Get | Method::Head | Method::Options | Method::Trace => true,
+            Method::Other(method) => method == "PROPFIND",
+            _ => false,
+        }
+    }
+}
+
+impl From<&str> for Method {
+    fn from(value: &str) -> Self {
+        match value {
+            "GET" => Method::Get,
+            "HEAD" => Method::Head,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "PATCH" => Method::Patch,
+            "OPTIONS" => Method::Options,
+            "CONNECT" => Method::Connect,
+            "TRACE" => Method::Trace,
+            other => Method::Other(other.to_owned()),
+        }
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// How the request reached us, as far as this server can tell.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Scheme {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scheme::Http => "http",
+            Scheme::Https => "https",
+        }
+    }
+}
+
+impl std::fmt::Display for Scheme {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Typed values attached to a request by middlewares, keyed by their type.
+#[derive(Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|previous| previous.downcast().ok().map(|b| *b))
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map
+            .get(&TypeId::of::<T>())
+            .and_then(|value| value.downcast_ref())
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .and_then(|value| value.downcast().ok().map(|b| *b))
+    }
+}
+
+/// Who a request was authenticated as, put in its extensions with [`Identity::assign`] by the
+/// middleware that did it, for handlers to go by.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity(pub String);
+
+impl Identity {
+    /// Gives `req` this identity, and fills in its [`IdentitySlot`] if it has one.
+    pub fn assign(self, req: &mut Request) {
+        if let Some(slot) = req.extensions.get::<IdentitySlot>() {
+            *slot.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(self.0.clone());
+        }
+        req.extensions.insert(self);
+    }
+}
+
+/// How a request was authenticated and what that lets it do, put in its extensions by the
+/// middleware that assigned its [`Identity`], for [`Authorization`](crate::authz::Authorization)
+/// rules to go by.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Grants {
+    /// `basic`, `bearer`, `jwt`, `api-key` or `signature`.
+    pub mechanism: &'static str,
+    pub scopes: Vec<String>,
+    pub roles: Vec<String>,
+}
+
+impl Grants {
+    /// The grants of a mechanism that has no scopes or roles of its own.
+    pub fn of(mechanism: &'static str) -> Self {
+        Self {
+            mechanism,
+            ..Self::default()
+        }
+    }
+}
+
+/// Who a request turned out to be from, for whatever put this in its extensions and kept a
+/// clone: the server, for the access log, and the audit log. An [`Identity`] assigned further
+/// in, once the request has been passed on, shows up here.
+#[derive(Debug, Clone, Default)]
+pub struct IdentitySlot(Arc<Mutex<Option<String>>>);
+
+impl IdentitySlot {
+    pub fn get(&self) -> Option<String> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+}
+
+pub struct Request {
+    pub method: Method,
+    /// The request target exactly as it appeared in the request line.
+    pub target: String,
+    /// The target's path, without the query string.
+    pub path: String,
+    /// Everything after the `?` of the request target, empty if there was none.
+    pub query: String,
+    pub version: String,
+    pub scheme: Scheme,
+    /// The address of the peer that sent the request, `None` when it didn't come over TCP.
+    pub remote_addr: Option<SocketAddr>,
+    pub headers: HeaderMap,
+    /// Values captured from `{name}` segments of the matched route pattern.
+    pub params: Vec<(String, String)>,
+    pub extensions: Extensions,
+    pub body: Body,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    /// The value of the cookie called `name`, the first one if it was sent more than once.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        cookies::pairs(self.headers.get_all(COOKIE))
+            .find(|(cookie, _)| *cookie == name)
+            .map(|(_, value)| value)
+    }
+
+    /// Every cookie the request sent.
+    pub fn cookies(&self) -> Cookies {
+        let values: Vec<_> = self.headers.get_all(COOKIE).collect();
+        Cookies::decode(&values).unwrap_or_default()
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(k, _)| k == name)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+#[cfg(test)]
+impl Request {
+    /// A request for `target` as it would come off the wire, with no headers and no body.
+    pub(crate) fn new(method: Method, target: &str) -> Self {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Self {
+            method,
+            target: target.to_owned(),
+            path: path.to_owned(),
+            query: query.to_owned(),
+            version: "HTTP/1.1".to_owned(),
+            scheme: Scheme::Http,
+            remote_addr: None,
+            headers: HeaderMap::new(),
+            params: Vec::new(),
+            extensions: Extensions::default(),
+            body: Body::empty(),
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! An ID for every request, to quote when something goes wrong and to find the request by in
+//! the logs. It goes in the request's span, the access log and the `X-Request-Id` response
+//! header, and is passed on to proxied upstreams and CGI scripts as a request header.
+//!
+//! A request that already has an `X-Request-Id` from a trusted proxy keeps it, so that the ID
+//! is the same all the way through; from anyone else a new one replaces it.
+
+use std::net::IpAddr;
+
+use crate::{forwarded::Cidr, headers::X_REQUEST_ID, request::Request};
+
+/// The ID given to a request, in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    /// A random UUID.
+    pub fn generate() -> Self {
+        Self(uuid::Uuid::new_v4().to_string())
+    }
+
+    /// The ID for `req`: its `X-Request-Id` if the peer is within `trusted` and the ID looks
+    /// sane, a new one otherwise. The header is set to it either way.
+    pub(crate) fn assign(req: &mut Request, trusted: &[Cidr]) -> Self {
+        let peer_trusted = |ip: IpAddr| trusted.iter().any(|cidr| cidr.contains(ip));
+        let adopted = req
+            .headers
+            .get(X_REQUEST_ID)
+            .filter(|_| req.remote_addr.is_some_and(|addr| peer_trusted(addr.ip())))
+            .filter(|id| is_valid(id))
+            .map(|id| Self(id.to_owned()));
+        let id = adopted.unwrap_or_else(Self::generate);
+        req.headers.insert(X_REQUEST_ID, &id.0);
+        req.extensions.insert(id.clone());
+        id
+    }
+}
+
+/// Short enough to log, and without anything that could break a log line or a header.
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 128 && id.bytes().all(|b| b.is_ascii_graphic())
+}
@@ -0,0 +1,584 @@
+use std::io::{self, IoSlice, Write};
+
+use anyhow::Context;
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use tokio::{
+    fs::File,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+};
+
+use crate::{
+    cookies::SetCookie,
+    error::HttpError,
+    headers::{
+        ContentLength, Header, HeaderMap, CONTENT_TYPE, LOCATION, SET_COOKIE, TRANSFER_ENCODING,
+    },
+    status::StatusCode,
+    streaming::{self, BodySender, BoxFrameStream, Frame},
+};
+
+pub type BoxBodyReader = Box<dyn AsyncRead + Send + Unpin>;
+
+pub enum ResponseBody {
+    Empty,
+    Bytes(Bytes),
+    /// A reader of unknown length, sent chunked (or close-delimited for HTTP/1.0 clients).
+    Reader(BoxBodyReader),
+    /// Frames produced by the handler as it goes, framed like [`ResponseBody::Reader`].
+    Stream(BoxFrameStream),
+    File {
+        file: File,
+        len: u64,
+    },
+    /// What [`Response::without_body`] leaves of a body of unknown length: nothing goes out,
+    /// but the head is framed the way it would be for the body, so without a Content-Length.
+    Omitted,
+}
+
+impl ResponseBody {
+    pub fn len(&self) -> Option<u64> {
+        match self {
+            ResponseBody::Empty => Some(0),
+            ResponseBody::Bytes(bytes) => Some(bytes.len() as u64),
+            ResponseBody::Reader(_) | ResponseBody::Stream(_) | ResponseBody::Omitted => None,
+            ResponseBody::File { len, .. } => Some(*len),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == Some(0)
+    }
+}
+
+impl From<String> for ResponseBody {
+    fn from(value: String) -> Self {
+        ResponseBody::Bytes(value.into())
+    }
+}
+
+impl From<&'static str> for ResponseBody {
+    fn from(value: &'static str) -> Self {
+        ResponseBody::Bytes(value.into())
+    }
+}
+
+impl From<Vec<u8>> for ResponseBody {
+    fn from(value: Vec<u8>) -> Self {
+        ResponseBody::Bytes(value.into())
+    }
+}
+
+impl From<Bytes> for ResponseBody {
+    fn from(value: Bytes) -> Self {
+        ResponseBody::Bytes(value)
+    }
+}
+
+pub struct Response {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: ResponseBody,
+}
+
+impl Response {
+    pub fn builder() -> ResponseBuilder {
+        ResponseBuilder::default()
+    }
+
+    pub fn empty(status: StatusCode) -> Self {
+        Self::builder().status(status).body(ResponseBody::Empty)
+    }
+
+    pub fn text(status: StatusCode, body: impl Into<String>) -> Self {
+        Self::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/plain")
+            .body(body.into())
+    }
+
+    pub fn bytes(status: StatusCode, body: impl Into<Bytes>) -> Self {
+        Self::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(body.into())
+    }
+
+    pub fn reader(status: StatusCode, body: impl AsyncRead + Send + Unpin + 'static) -> Self {
+        Self::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(ResponseBody::Reader(Box::new(body)))
+    }
+
+    /// A body made of the stream's chunks, each flushed to the client as soon as it's produced.
+    pub fn stream<S>(status: StatusCode, stream: S) -> Self
+    where
+        S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+    {
+        Self::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(ResponseBody::Stream(streaming::flushing(stream)))
+    }
+
+    /// A body fed through the returned sender, which controls when data is flushed.
+    pub fn channel(status: StatusCode) -> (BodySender, Self) {
+        let (sender, stream) = streaming::channel(16);
+        let response = Self::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(ResponseBody::Stream(stream));
+        (sender, response)
+    }
+
+    pub fn file(status: StatusCode, file: File, len: u64) -> Self {
+        Self::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "application/octet-stream")
+            .body(ResponseBody::File { file, len })
+    }
+
+    /// An empty response pointing the client at `location`.
+    pub fn redirect(status: StatusCode, location: &str) -> Self {
+        debug_assert!(status.is_redirection(), "{status} is not a redirect status");
+        Self::empty(status).with_header(LOCATION, location)
+    }
+
+    /// 301, the resource moved for good. Clients may turn a POST into a GET when following it.
+    pub fn moved_permanently(location: &str) -> Self {
+        Self::redirect(StatusCode::MOVED_PERMANENTLY, location)
+    }
+
+    /// 302, the resource is elsewhere for now. Clients may turn a POST into a GET.
+    pub fn found(location: &str) -> Self {
+        Self::redirect(StatusCode::FOUND, location)
+    }
+
+    /// 303, the result is available at `location` and should be fetched with GET.
+    pub fn see_other(location: &str) -> Self {
+        Self::redirect(StatusCode::SEE_OTHER, location)
+    }
+
+    /// 307, like 302 but the method and body must be kept.
+    pub fn temporary_redirect(location: &str) -> Self {
+        Self::redirect(StatusCode::TEMPORARY_REDIRECT, location)
+    }
+
+    /// 308, like 301 but the method and body must be kept.
+    pub fn permanent_redirect(location: &str) -> Self {
+        Self::redirect(StatusCode::PERMANENT_REDIRECT, location)
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name)
+    }
+
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.append(key, value);
+        self
+    }
+
+    /// Replaces every existing value of the header instead of appending another one.
+    pub fn set_header(&mut self, key: &str, value: &str) {
+        self.headers.insert(key, value);
+    }
+
+    pub fn with_typed_header<H: Header>(mut self, header: &H) -> Self {
+        self.headers.typed_insert(header);
+        self
+    }
+
+    /// Adds a `Set-Cookie`, keeping any others.
+    pub fn with_cookie(mut self, cookie: &SetCookie) -> Self {
+        self.headers.append(SET_COOKIE, &cookie.to_string());
+        self
+    }
+
+    /// Drops the body but keeps the headers, which is what a HEAD response needs, along with
+    /// the framing the body would have had: its Content-Length, or none at all if it's
+    /// streamed, rather than one saying 0.
+    pub fn without_body(mut self) -> Self {
+        self.body = match self.body.len() {
+            Some(len) => {
+                if !self.headers.contains(ContentLength::NAME) {
+                    self.headers.typed_insert(&ContentLength(len));
+                }
+                ResponseBody::Empty
+            }
+            None => ResponseBody::Omitted,
+        };
+        self
+    }
+
+    /// Writes just the head, without adding any framing headers: what follows on the
+    /// connection after a 101 or a CONNECT 2xx isn't a response body.
+    pub(crate) async fn write_upgrade_head(
+        self,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> anyhow::Result<()> {
+        write_head(stream, self.status, &self.headers).await?;
+        stream.flush().await.context("flushing stream")
+    }
+
+    /// Whether the client can tell where the response ends without the connection closing, so
+    /// it can be kept open for another request.
+    pub(crate) fn is_delimited(&self, chunked_allowed: bool) -> bool {
+        self.is_bodiless()
+            || self.body.len().is_some()
+            || matches!(self.body, ResponseBody::Omitted)
+            || self.headers.contains(ContentLength::NAME)
+            || chunked_allowed
+    }
+
+    fn is_bodiless(&self) -> bool {
+        self.status.0 < 200
+            || self.status == StatusCode::NO_CONTENT
+            || self.status == StatusCode::NOT_MODIFIED
+    }
+
+    /// Encodes the response. Bodies of unknown length are sent chunked when `chunked_allowed`
+    /// (the client speaks HTTP/1.1) unless the handler set a Content-Length itself, and are
+    /// otherwise delimited by closing the connection.
+    ///
+    /// Returns how many bytes of body went out, not counting the head or chunk framing.
+    pub async fn write_to_stream(
+        mut self,
+        stream: &mut (impl AsyncWrite + Unpin),
+        chunked_allowed: bool,
+    ) -> anyhow::Result<u64> {
+        let bodiless_status = self.is_bodiless();
+        let has_length = self.headers.contains(ContentLength::NAME);
+        if let Some(len) = self.body.len() {
+            if !has_length && !bodiless_status {
+                self.headers.typed_insert(&ContentLength(len));
+            }
+        }
+
+        let chunked = chunked_allowed && self.body.len().is_none() && !has_length;
+        if chunked {
+            self.headers.insert(TRANSFER_ENCODING, "chunked");
+        }
+
+        let head = encode_head(self.status, &self.headers);
+        // In-memory bodies go out along with the head.
+        if let ResponseBody::Bytes(bytes) = &self.body {
+            let mut parts = [IoSlice::new(&head), IoSlice::new(bytes)];
+            write_all_vectored(stream, &mut parts)
+                .await
+                .context("writing response to stream")?;
+            stream.flush().await.context("flushing stream")?;
+            return Ok(bytes.len() as u64);
+        }
+        stream
+            .write_all(&head)
+            .await
+            .context("writing head to stream")?;
+
+        let sent = match self.body {
+            ResponseBody::Empty | ResponseBody::Bytes(_) | ResponseBody::Omitted => 0,
+            ResponseBody::Reader(mut reader) if chunked => {
+                let mut buf = vec![0; 8 * 1024];
+                let mut sent = 0;
+                loop {
+                    let n = reader
+                        .read(&mut buf)
+                        .await
+                        .context("reading response body")?;
+                    if n == 0 {
+                        break;
+                    }
+                    write_chunk(stream, &buf[..n]).await?;
+                    sent += n as u64;
+                }
+                write_last_chunk(stream).await?;
+                sent
+            }
+            ResponseBody::Reader(mut reader) => tokio::io::copy(&mut reader, stream)
+                .await
+                .context("streaming byte stream to output stream")?,
+            ResponseBody::Stream(mut frames) => {
+                let mut sent = 0;
+                while let Some(frame) = frames.next().await {
+                    match frame.context("producing response body")? {
+                        Frame::Data(data) if data.is_empty() => {}
+                        Frame::Data(data) => {
+                            match chunked {
+                                true => write_chunk(stream, &data).await?,
+                                false => stream
+                                    .write_all(&data)
+                                    .await
+                                    .context("writing body to stream")?,
+                            }
+                            sent += data.len() as u64;
+                        }
+                        Frame::Flush => stream.flush().await.context("flushing stream")?,
+                    }
+                }
+                if chunked {
+                    write_last_chunk(stream).await?;
+                }
+                sent
+            }
+            ResponseBody::File { file, len } => {
+                copy_file(file, len, stream)
+                    .await
+                    .context("streaming file to output stream")?;
+                len
+            }
+        };
+
+        stream.flush().await.context("flushing stream")?;
+        Ok(sent)
+    }
+}
+
+#[derive(Default)]
+pub struct ResponseBuilder {
+    status: Option<StatusCode>,
+    headers: HeaderMap,
+}
+
+impl ResponseBuilder {
+    pub fn status(mut self, status: impl Into<StatusCode>) -> Self {
+        self.status = Some(status.into());
+        self
+    }
+
+    pub fn header(mut self, key: &str, value: &str) -> Self {
+        self.headers.append(key, value);
+        self
+    }
+
+    pub fn typed_header<H: Header>(mut self, header: &H) -> Self {
+        self.headers.typed_insert(header);
+        self
+    }
+
+    pub fn cookie(mut self, cookie: &SetCookie) -> Self {
+        self.headers.append(SET_COOKIE, &cookie.to_string());
+        self
+    }
+
+    pub fn body(self, body: impl Into<ResponseBody>) -> Response {
+        Response {
+            status: self.status.unwrap_or(StatusCode::OK),
+            headers: self.headers,
+            body: body.into(),
+        }
+    }
+}
+
+/// Anything a handler can return. Errors stay errors so the server can map them in one place.
+pub trait IntoResponse {
+    fn into_response(self) -> Result<Response, HttpError>;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Result<Response, HttpError> {
+        Ok(self)
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Result<Response, HttpError> {
+        Err(self)
+    }
+}
+
+impl<T, E> IntoResponse for Result<T, E>
+where
+    T: IntoResponse,
+    E: Into<HttpError>,
+{
+    fn into_response(self) -> Result<Response, HttpError> {
+        self.map_err(Into::into)
+            .and_then(IntoResponse::into_response)
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Result<Response, HttpError> {
+        Ok(Response::empty(self))
+    }
+}
+
+impl IntoResponse for () {
+    fn into_response(self) -> Result<Response, HttpError> {
+        StatusCode::OK.into_response()
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Result<Response, HttpError> {
+        Ok(Response::text(StatusCode::OK, self))
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Result<Response, HttpError> {
+        Ok(Response::text(StatusCode::OK, self))
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Result<Response, HttpError> {
+        Ok(Response::bytes(StatusCode::OK, self))
+    }
+}
+
+impl IntoResponse for Bytes {
+    fn into_response(self) -> Result<Response, HttpError> {
+        Ok(Response::bytes(StatusCode::OK, self))
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> Result<Response, HttpError> {
+        let (status, inner) = self;
+        let mut response = inner.into_response()?;
+        response.status = status;
+        Ok(response)
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, HeaderMap, T) {
+    fn into_response(self) -> Result<Response, HttpError> {
+        let (status, headers, inner) = self;
+        let mut response = inner.into_response()?;
+        response.status = status;
+        for (k, v) in &headers {
+            response.set_header(k, v);
+        }
+        Ok(response)
+    }
+}
+
+/// Serializes the value as an `application/json` body.
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Result<Response, HttpError> {
+        let body = serde_json::to_vec(&self.0).context("serializing json response")?;
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(body))
+    }
+}
+
+pub(crate) async fn write_chunk(
+    stream: &mut (impl AsyncWrite + Unpin),
+    data: &[u8],
+) -> anyhow::Result<()> {
+    let size_line = format!("{:x}\r\n", data.len());
+    let mut parts = [
+        IoSlice::new(size_line.as_bytes()),
+        IoSlice::new(data),
+        IoSlice::new(b"\r\n"),
+    ];
+    write_all_vectored(stream, &mut parts)
+        .await
+        .context("writing chunk to stream")
+}
+
+/// Sends `len` bytes of `file` from where it's positioned, through io_uring if it's built in.
+async fn copy_file(file: File, len: u64, stream: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    if let Some(ring) = crate::uring::Ring::shared() {
+        return ring.copy_file(file, len, stream).await;
+    }
+    tokio::io::copy(&mut file.take(len), stream).await?;
+    Ok(())
+}
+
+pub(crate) async fn write_last_chunk(stream: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+    stream
+        .write_all(b"0\r\n\r\n")
+        .await
+        .context("writing last chunk to stream")
+}
+
+async fn write_head(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: StatusCode,
+    headers: &HeaderMap,
+) -> anyhow::Result<()> {
+    stream
+        .write_all(&encode_head(status, headers))
+        .await
+        .context("writing head to stream")
+}
+
+/// The status line and headers, built up in one buffer so they can go out in one write.
+fn encode_head(status: StatusCode, headers: &HeaderMap) -> Vec<u8> {
+    let mut head = Vec::with_capacity(256);
+    let _ = write!(head, "HTTP/1.1 {status}\r\n");
+    for (k, v) in headers {
+        head.extend_from_slice(k.as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(v.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+    head
+}
+
+/// Writes all of `bufs`, with as few calls as the stream takes: one, for one that supports
+/// vectored writes and takes everything at once.
+async fn write_all_vectored(
+    stream: &mut (impl AsyncWrite + Unpin),
+    mut bufs: &mut [IoSlice<'_>],
+) -> io::Result<()> {
+    IoSlice::advance_slices(&mut bufs, 0);
+    while !bufs.is_empty() {
+        match stream.write_vectored(bufs).await? {
+            0 => return Err(io::ErrorKind::WriteZero.into()),
+            n => IoSlice::advance_slices(&mut bufs, n),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn head(response: Response, chunked_allowed: bool) -> String {
+        let mut written = Vec::new();
+        let sent = response
+            .without_body()
+            .write_to_stream(&mut written, chunked_allowed)
+            .await
+            .unwrap();
+        assert_eq!(sent, 0);
+        String::from_utf8(written).unwrap().to_ascii_lowercase()
+    }
+
+    fn streamed() -> Response {
+        let chunks = futures_util::stream::iter([Ok(Bytes::from("a")), Ok(Bytes::from("b"))]);
+        Response::stream(StatusCode::OK, chunks)
+    }
+
+    #[tokio::test]
+    async fn head_keeps_the_length() {
+        let written = head(Response::bytes(StatusCode::OK, "hello"), true).await;
+        assert!(written.contains("content-length: 5\r\n"), "{written}");
+        assert!(written.ends_with("\r\n\r\n"), "{written}");
+    }
+
+    #[tokio::test]
+    async fn head_of_a_stream_is_framed_like_the_stream() {
+        let written = head(streamed(), true).await;
+        assert!(!written.contains("content-length"), "{written}");
+        assert!(
+            written.contains("transfer-encoding: chunked\r\n"),
+            "{written}"
+        );
+        assert!(written.ends_with("\r\n\r\n"), "{written}");
+
+        let written = head(streamed(), false).await;
+        assert!(!written.contains("content-length"), "{written}");
+        assert!(!written.contains("transfer-encoding"), "{written}");
+    }
+}
@@ -0,0 +1,229 @@
+//! URL rewriting ahead of routing, in the spirit of mod_rewrite.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context};
+use regex::Regex;
+use tracing::debug;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+    status::StatusCode,
+};
+
+#[derive(Debug, Clone)]
+enum Pattern {
+    /// Replaces a leading `from` with `to`, keeping the rest of the path.
+    Prefix { from: String, to: String },
+    /// Replaces the whole path with `target`, where `$1`, `${name}`... refer to the captures.
+    Regex { regex: Regex, target: String },
+}
+
+#[derive(Debug, Clone)]
+pub struct RewriteRule {
+    pattern: Pattern,
+    /// Sends the client to the new URL instead of routing it internally.
+    redirect: Option<StatusCode>,
+}
+
+impl RewriteRule {
+    pub fn prefix(from: &str, to: &str) -> Self {
+        Self {
+            pattern: Pattern::Prefix {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            },
+            redirect: None,
+        }
+    }
+
+    pub fn regex(regex: Regex, target: &str) -> Self {
+        Self {
+            pattern: Pattern::Regex {
+                regex,
+                target: target.to_owned(),
+            },
+            redirect: None,
+        }
+    }
+
+    pub fn redirect(mut self, status: StatusCode) -> Self {
+        self.redirect = Some(status);
+        self
+    }
+
+    fn apply(&self, path: &str) -> Option<String> {
+        match &self.pattern {
+            Pattern::Prefix { from, to } => path
+                .strip_prefix(from.as_str())
+                .map(|rest| format!("{to}{rest}")),
+            Pattern::Regex { regex, target } => regex.captures(path).map(|captures| {
+                let mut rewritten = String::new();
+                captures.expand(target, &mut rewritten);
+                rewritten
+            }),
+        }
+    }
+}
+
+/// Parses `PATTERN TARGET [FLAGS]`. A pattern starting with `^` is a regex, anything else a
+/// path prefix. The only flag is `R` or `R=<status>`, which turns the rule into a redirect
+/// (302 unless specified).
+impl FromStr for RewriteRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let (Some(pattern), Some(target)) = (parts.next(), parts.next()) else {
+            bail!("expected a pattern and a target");
+        };
+
+        let mut rule = if pattern.starts_with('^') {
+            let regex = Regex::new(pattern).context("parsing rewrite pattern")?;
+            Self::regex(regex, target)
+        } else {
+            Self::prefix(pattern, target)
+        };
+
+        if let Some(flags) = parts.next() {
+            let flag = flags
+                .strip_prefix('[')
+                .and_then(|f| f.strip_suffix(']'))
+                .context("flags must be enclosed in brackets")?;
+            rule = match flag.split_once('=') {
+                None if flag == "R" => rule.redirect(StatusCode::FOUND),
+                Some(("R", status)) => {
+                    let status = StatusCode(status.parse().context("parsing redirect status")?);
+                    if !status.is_redirection() {
+                        bail!("{status} is not a redirect status");
+                    }
+                    rule.redirect(status)
+                }
+                _ => bail!("unknown rewrite flag {flag}"),
+            };
+        }
+        if parts.next().is_some() {
+            bail!("unexpected trailing input");
+        }
+        Ok(rule)
+    }
+}
+
+/// Runs the first matching rule over the request path. Register it with
+/// [`Router::layer`](crate::router::Router::layer) so routing sees the rewritten path, before
+/// any other layer that looks at the path, like the authentication, so they see it too.
+#[derive(Debug, Clone, Default)]
+pub struct Rewrite {
+    rules: Vec<RewriteRule>,
+}
+
+impl Rewrite {
+    pub fn new(rules: Vec<RewriteRule>) -> Self {
+        Self { rules }
+    }
+
+    /// Splits the rewritten target into path and query, keeping the original query too.
+    fn rewrite(req: &mut Request, target: &str) {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let query = match (query, req.query.as_str()) {
+            ("", original) => original.to_owned(),
+            (new, "") => new.to_owned(),
+            (new, original) => format!("{new}&{original}"),
+        };
+
+        req.path = path.to_owned();
+        req.target = match query.is_empty() {
+            true => req.path.clone(),
+            false => format!("{}?{query}", req.path),
+        };
+        req.query = query;
+    }
+}
+
+impl Middleware for Rewrite {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let rewritten = self
+            .rules
+            .iter()
+            .find_map(|rule| rule.apply(&req.path).map(|target| (rule, target)));
+        let Some((rule, target)) = rewritten else {
+            return Box::pin(next.run(req));
+        };
+
+        debug!("Rewriting {} to {target}", req.path);
+        Self::rewrite(&mut req, &target);
+        match rule.redirect {
+            Some(status) => {
+                let response = Response::redirect(status, &req.target);
+                Box::pin(async move { Ok(response) })
+            }
+            None => Box::pin(next.run(req)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::Method;
+
+    fn apply(rule: &str, path: &str) -> Option<String> {
+        rule.parse::<RewriteRule>().unwrap().apply(path)
+    }
+
+    #[test]
+    fn prefix_keeps_the_rest_of_the_path() {
+        assert_eq!(apply("/old /new", "/old/a/b"), Some("/new/a/b".to_owned()));
+        assert_eq!(apply("/old /new", "/old"), Some("/new".to_owned()));
+        assert_eq!(apply("/old /new", "/other/old"), None);
+    }
+
+    #[test]
+    fn regex_expands_captures() {
+        let rule = r"^/dl/(.+)$ /files/$1";
+        assert_eq!(apply(rule, "/dl/a.txt"), Some("/files/a.txt".to_owned()));
+        assert_eq!(apply(rule, "/dl/"), None);
+
+        let rule = r"^/u/(?P<user>\w+)$ /users?name=${user}";
+        assert_eq!(apply(rule, "/u/ann"), Some("/users?name=ann".to_owned()));
+    }
+
+    #[test]
+    fn parses_redirect_flags() {
+        let rule: RewriteRule = "/a /b [R]".parse().unwrap();
+        assert_eq!(rule.redirect, Some(StatusCode::FOUND));
+        let rule: RewriteRule = "/a /b [R=301]".parse().unwrap();
+        assert_eq!(rule.redirect, Some(StatusCode::MOVED_PERMANENTLY));
+
+        for bad in [
+            "/a",
+            "/a /b [R=200]",
+            "/a /b [L]",
+            "/a /b R",
+            "/a /b [R] x",
+            "^( /b",
+        ] {
+            assert!(bad.parse::<RewriteRule>().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn rewrite_merges_queries() {
+        let mut req = Request::new(Method::Get, "/old?b=2");
+        Rewrite::rewrite(&mut req, "/new?a=1");
+        assert_eq!((req.path.as_str(), req.query.as_str()), ("/new", "a=1&b=2"));
+        assert_eq!(req.target, "/new?a=1&b=2");
+
+        let mut req = Request::new(Method::Get, "/old");
+        Rewrite::rewrite(&mut req, "/new");
+        assert_eq!((req.target.as_str(), req.query.as_str()), ("/new", ""));
+    }
+}
@@ -0,0 +1,655 @@
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use serde::Serialize;
+use tracing::debug;
+
+use crate::{
+    content_type::RequireContentType,
+    error::HttpError,
+    guard::Guard,
+    handler::{BoxFuture, Handler, IntoHandler},
+    headers::{ALLOW, CONTENT_TYPE},
+    middleware::{Middleware, Next},
+    openapi,
+    request::{Extensions, Method, Request},
+    response::Response,
+    status::StatusCode,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    /// `{*name}`, only allowed as the last segment; captures the rest of the path, slashes included.
+    CatchAll(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct PathPattern {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+impl PathPattern {
+    fn parse(pattern: &str) -> Self {
+        let raw_segments = pattern
+            .strip_prefix('/')
+            .unwrap_or_else(|| panic!("route pattern {pattern} must start with '/'"))
+            .split('/')
+            .collect::<Vec<_>>();
+
+        let segments = raw_segments
+            .iter()
+            .enumerate()
+            .map(
+                |(i, segment)| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                    Some(name) => match name.strip_prefix('*') {
+                        Some(name) => {
+                            assert!(
+                                i == raw_segments.len() - 1,
+                                "catch-all segment must be the last one in {pattern}"
+                            );
+                            Segment::CatchAll(name.to_owned())
+                        }
+                        None => Segment::Param(name.to_owned()),
+                    },
+                    None => Segment::Literal((*segment).to_owned()),
+                },
+            )
+            .collect();
+
+        Self {
+            raw: pattern.to_owned(),
+            segments,
+        }
+    }
+
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut rest = Some(path.strip_prefix('/')?);
+        let mut params = Vec::new();
+
+        for segment in &self.segments {
+            let current = rest?;
+            if let Segment::CatchAll(name) = segment {
+                params.push((name.clone(), current.to_owned()));
+                return Some(params);
+            }
+
+            let part = match current.split_once('/') {
+                Some((part, remainder)) => {
+                    rest = Some(remainder);
+                    part
+                }
+                None => {
+                    rest = None;
+                    current
+                }
+            };
+
+            match segment {
+                Segment::Literal(literal) if part == literal => {}
+                Segment::Param(name) if !part.is_empty() => {
+                    params.push((name.clone(), part.to_owned()));
+                }
+                _ => return None,
+            }
+        }
+
+        rest.is_none().then_some(params)
+    }
+}
+
+struct Endpoint {
+    method: Method,
+    guard: Option<Arc<dyn Guard>>,
+    handler: Arc<dyn Handler>,
+}
+
+struct Route {
+    pattern: PathPattern,
+    endpoints: Vec<Endpoint>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+}
+
+impl Route {
+    fn serves(&self, method: &Method) -> bool {
+        self.endpoints.iter().any(|e| &e.method == method)
+    }
+
+    /// Guarded endpoints are tried in registration order, the unguarded one (if any) last.
+    fn handler(&self, method: &Method, req: &Request) -> Option<&Arc<dyn Handler>> {
+        let mut candidates = self.endpoints.iter().filter(|e| &e.method == method);
+        candidates
+            .clone()
+            .find(|e| e.guard.as_ref().is_some_and(|g| g.check(req)))
+            .or_else(|| candidates.find(|e| e.guard.is_none()))
+            .map(|e| &e.handler)
+    }
+
+    async fn call(&self, handler: &Arc<dyn Handler>, req: Request) -> Result<Response, HttpError> {
+        if self.middlewares.is_empty() {
+            handler.call(req).await
+        } else {
+            Next::new(self.middlewares.as_slice().into(), handler.clone())
+                .run(req)
+                .await
+        }
+    }
+
+    /// The methods this route answers, including the ones derived from the route table.
+    fn allowed_methods(&self) -> Vec<&str> {
+        let mut methods = Vec::new();
+        for endpoint in &self.endpoints {
+            if !methods.contains(&endpoint.method.as_str()) {
+                methods.push(endpoint.method.as_str());
+            }
+        }
+        if self.serves(&Method::Get) && !self.serves(&Method::Head) {
+            methods.push(Method::Head.as_str());
+        }
+        if !self.serves(&Method::Options) {
+            methods.push(Method::Options.as_str());
+        }
+        methods
+    }
+}
+
+/// A registered route, as listed by [`Router::routes`].
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    pub pattern: String,
+    /// Every method the route answers, including the HEAD and OPTIONS ones the router derives.
+    pub methods: Vec<String>,
+    /// Methods that have at least one guarded handler.
+    pub guarded: Vec<String>,
+    /// The route's own middlewares, outermost first.
+    pub middlewares: Vec<String>,
+}
+
+/// Which route a request ended up at, for labelling it in metrics. The server puts one in a
+/// request's extensions and keeps a clone, which routers fill in as they dispatch, nested
+/// ones after the routers they're mounted in.
+#[derive(Debug, Clone, Default)]
+pub struct MatchedRoute(Arc<Mutex<MatchedPath>>);
+
+#[derive(Debug, Default)]
+struct MatchedPath {
+    /// The prefixes of the mounts the request went through.
+    prefix: String,
+    route: Option<String>,
+}
+
+impl MatchedRoute {
+    /// The pattern of the route, like `/api/users/{id}`, if the request matched one. Mounted
+    /// handlers that aren't routers show as their prefix followed by `/*`, and the fallback as
+    /// `(fallback)`.
+    pub fn get(&self) -> Option<String> {
+        self.lock().route.clone()
+    }
+
+    fn set(req: &Request, route: &str) {
+        if let Some(matched) = req.extensions.get::<Self>() {
+            let mut matched = matched.lock();
+            matched.route = Some(format!("{}{route}", matched.prefix));
+        }
+    }
+
+    /// A nested router's fallback leaves the route at its mount's.
+    fn fallback(req: &Request) {
+        if let Some(matched) = req.extensions.get::<Self>() {
+            let mut matched = matched.lock();
+            if matched.prefix.is_empty() {
+                matched.route = Some("(fallback)".to_owned());
+            }
+        }
+    }
+
+    /// Nothing matched in a router mounted in another, so the request didn't end up at the
+    /// mount after all.
+    fn unmatched(req: &Request) {
+        if let Some(matched) = req.extensions.get::<Self>() {
+            matched.lock().route = None;
+        }
+    }
+
+    fn enter_mount(req: &Request, prefix: &str) {
+        if let Some(matched) = req.extensions.get::<Self>() {
+            let mut matched = matched.lock();
+            matched.prefix.push_str(prefix);
+            matched.route = Some(format!("{}/*", matched.prefix));
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, MatchedPath> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+type StateInjector = Arc<dyn Fn(&mut Extensions) + Send + Sync>;
+
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middlewares: Vec<Arc<dyn Middleware>>,
+    state: Vec<StateInjector>,
+    mounts: Vec<(String, Arc<dyn Handler>)>,
+    fallback: Option<Arc<dyn Handler>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn route<Args>(
+        self,
+        method: Method,
+        pattern: &str,
+        handler: impl IntoHandler<Args>,
+    ) -> Self {
+        self.add_endpoint(method, pattern, None, handler)
+    }
+
+    /// Whether an unguarded `method` handler is registered for exactly `pattern`, in which case
+    /// registering another one would panic.
+    pub fn has_route(&self, method: &Method, pattern: &str) -> bool {
+        let pattern = PathPattern::parse(pattern);
+        self.routes.iter().any(|route| {
+            route.pattern == pattern
+                && route
+                    .endpoints
+                    .iter()
+                    .any(|e| &e.method == method && e.guard.is_none())
+        })
+    }
+
+    /// Registers a handler that only applies when `guard` accepts the request. When it doesn't,
+    /// matching carries on with the other handlers for the same method and path, then with the
+    /// routes registered after this one.
+    pub fn route_guarded<Args>(
+        self,
+        method: Method,
+        pattern: &str,
+        guard: impl Guard,
+        handler: impl IntoHandler<Args>,
+    ) -> Self {
+        self.add_endpoint(method, pattern, Some(Arc::new(guard)), handler)
+    }
+
+    fn add_endpoint<Args>(
+        mut self,
+        method: Method,
+        pattern: &str,
+        guard: Option<Arc<dyn Guard>>,
+        handler: impl IntoHandler<Args>,
+    ) -> Self {
+        let pattern = PathPattern::parse(pattern);
+        let route = self.route_mut(pattern);
+
+        assert!(
+            guard.is_some()
+                || !route
+                    .endpoints
+                    .iter()
+                    .any(|e| e.method == method && e.guard.is_none()),
+            "{method} {} registered twice",
+            route.pattern.raw
+        );
+        route.endpoints.push(Endpoint {
+            method,
+            guard,
+            handler: handler.into_handler(),
+        });
+        self
+    }
+
+    /// Makes `state` available to every handler and middleware of this router through the
+    /// [`State`](crate::extract::State) extractor. Each request gets its own clone, so wrap
+    /// anything expensive in an `Arc`.
+    pub fn with_state<S: Clone + Send + Sync + 'static>(mut self, state: S) -> Self {
+        self.state
+            .push(Arc::new(move |extensions: &mut Extensions| {
+                extensions.insert(state.clone());
+            }));
+        self
+    }
+
+    /// Handles every request no route matched, instead of answering 404. The router-wide
+    /// middlewares and state apply to it like to any other handler.
+    pub fn fallback<Args>(mut self, handler: impl IntoHandler<Args>) -> Self {
+        self.fallback = Some(handler.into_handler());
+        self
+    }
+
+    /// Hands every request under `prefix` that no route matched to `handler`, with the prefix
+    /// stripped from [`Request::path`] (the target is left untouched). Mounting a handler at a
+    /// prefix that's already mounted replaces it.
+    pub fn mount(mut self, prefix: &str, handler: impl Handler) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        assert!(
+            prefix.is_empty() || prefix.starts_with('/'),
+            "mount prefix {prefix} must start with '/'"
+        );
+        self.mounts.retain(|(p, _)| p != prefix);
+        self.mounts.push((prefix.to_owned(), Arc::new(handler)));
+        // Longest prefix first, so nested mounts win over the ones containing them.
+        self.mounts
+            .sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        self
+    }
+
+    fn mount_for(&self, path: &str) -> Option<(&str, &Arc<dyn Handler>, String)> {
+        self.mounts.iter().find_map(|(prefix, handler)| {
+            let rest = path.strip_prefix(prefix.as_str())?;
+            match rest {
+                "" => Some((prefix.as_str(), handler, "/".to_owned())),
+                rest if rest.starts_with('/') => Some((prefix.as_str(), handler, rest.to_owned())),
+                _ => None,
+            }
+        })
+    }
+
+    fn inject_state(&self, req: &mut Request) {
+        for inject in &self.state {
+            inject(&mut req.extensions);
+        }
+    }
+
+    /// Wraps the whole router, so the middleware also sees requests that end up as 404 or 405.
+    pub fn layer(mut self, middleware: impl Middleware) -> Self {
+        self.middlewares.push(Arc::new(middleware));
+        self
+    }
+
+    /// Wraps every method registered (now or later) on `pattern`, innermost added last.
+    pub fn route_layer(mut self, pattern: &str, middleware: impl Middleware) -> Self {
+        let pattern = PathPattern::parse(pattern);
+        self.route_mut(pattern)
+            .middlewares
+            .push(Arc::new(middleware));
+        self
+    }
+
+    /// Limits the request bodies `pattern` takes to the given media types, answering 415 to
+    /// anything else. See [`RequireContentType`].
+    pub fn accepts<I, S>(self, pattern: &str, accepted: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        self.route_layer(pattern, RequireContentType::new(accepted))
+    }
+
+    fn route_mut(&mut self, pattern: PathPattern) -> &mut Route {
+        match self.routes.iter().position(|r| r.pattern == pattern) {
+            Some(i) => &mut self.routes[i],
+            None => {
+                self.routes.push(Route {
+                    pattern,
+                    endpoints: Vec::new(),
+                    middlewares: Vec::new(),
+                });
+                self.routes.last_mut().unwrap()
+            }
+        }
+    }
+
+    pub fn get<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.route(Method::Get, pattern, handler)
+    }
+
+    pub fn head<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.route(Method::Head, pattern, handler)
+    }
+
+    pub fn post<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.route(Method::Post, pattern, handler)
+    }
+
+    pub fn put<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.route(Method::Put, pattern, handler)
+    }
+
+    pub fn delete<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.route(Method::Delete, pattern, handler)
+    }
+
+    pub fn patch<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.route(Method::Patch, pattern, handler)
+    }
+
+    pub fn options<Args>(self, pattern: &str, handler: impl IntoHandler<Args>) -> Self {
+        self.route(Method::Options, pattern, handler)
+    }
+
+    /// Routes GET (and so HEAD), POST, PUT, DELETE, PATCH and OPTIONS to the same handler.
+    pub fn any<Args>(mut self, pattern: &str, handler: impl IntoHandler<Args> + Clone) -> Self {
+        for method in [
+            Method::Get,
+            Method::Post,
+            Method::Put,
+            Method::Delete,
+            Method::Patch,
+            Method::Options,
+        ] {
+            self = self.route(method, pattern, handler.clone());
+        }
+        self
+    }
+
+    /// The route table in matching order.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.routes
+            .iter()
+            .map(|route| {
+                let mut guarded = Vec::new();
+                for endpoint in route.endpoints.iter().filter(|e| e.guard.is_some()) {
+                    if !guarded.contains(&endpoint.method.as_str()) {
+                        guarded.push(endpoint.method.as_str());
+                    }
+                }
+                RouteInfo {
+                    pattern: route.pattern.raw.clone(),
+                    methods: route
+                        .allowed_methods()
+                        .into_iter()
+                        .map(Into::into)
+                        .collect(),
+                    guarded: guarded.into_iter().map(Into::into).collect(),
+                    middlewares: route.middlewares.iter().map(|m| m.name().into()).collect(),
+                }
+            })
+            .collect()
+    }
+
+    /// The router-wide middlewares, outermost first.
+    pub fn layers(&self) -> Vec<String> {
+        self.middlewares.iter().map(|m| m.name().into()).collect()
+    }
+
+    /// Serves [`Router::routes`] and [`Router::layers`] as JSON at `pattern`, as they are at
+    /// the time of the call. Meant for debugging, so register it last.
+    pub fn route_listing(self, pattern: &str) -> Self {
+        #[derive(Serialize)]
+        struct Listing {
+            layers: Vec<String>,
+            routes: Vec<RouteInfo>,
+        }
+
+        let listing = Listing {
+            layers: self.layers(),
+            routes: self.routes(),
+        };
+        let body = serde_json::to_vec(&listing).expect("route listing serializes");
+        self.get(pattern, json_handler(body.into()))
+    }
+
+    /// Serves an [OpenAPI](crate::openapi) description of the routes registered so far at
+    /// `pattern`.
+    pub fn openapi(self, pattern: &str, title: &str, version: &str) -> Self {
+        let document = openapi::document(title, version, &self.routes());
+        let body = serde_json::to_vec(&document).expect("openapi document serializes");
+        self.get(pattern, json_handler(body.into()))
+    }
+
+    /// Matches the request against the route table, skipping the router-wide middlewares.
+    pub async fn dispatch(&self, mut req: Request) -> Result<Response, HttpError> {
+        self.inject_state(&mut req);
+        self.route_request(req).await
+    }
+
+    /// [`dispatch`](Self::dispatch) for a request the state is already in.
+    async fn route_request(&self, mut req: Request) -> Result<Response, HttpError> {
+        // The first route whose path matches but that doesn't serve the method at all answers
+        // OPTIONS and 405. Routes whose guards all rejected the request are skipped over.
+        let mut path_match = None;
+        for route in &self.routes {
+            let Some(params) = route.pattern.matches(&req.path) else {
+                continue;
+            };
+            req.params = params;
+            MatchedRoute::set(&req, &route.pattern.raw);
+
+            if let Some(handler) = route.handler(&req.method, &req) {
+                return route.call(handler, req).await;
+            }
+            if req.method == Method::Head {
+                if let Some(handler) = route.handler(&Method::Get, &req) {
+                    return Ok(route.call(handler, req).await?.without_body());
+                }
+            }
+
+            let serves = route.serves(&req.method)
+                || (req.method == Method::Head && route.serves(&Method::Get));
+            if !serves && path_match.is_none() {
+                path_match = Some(route);
+            }
+        }
+
+        match path_match {
+            Some(route) if req.method == Method::Options => {
+                Ok(Response::empty(StatusCode::NO_CONTENT)
+                    .with_header(ALLOW, &route.allowed_methods().join(", ")))
+            }
+            Some(route) => Err(method_not_allowed(route, &req.method)),
+            None => {
+                if let Some((prefix, handler, path)) = self.mount_for(&req.path) {
+                    MatchedRoute::enter_mount(&req, prefix);
+                    req.path = path;
+                    req.params.clear();
+                    return handler.call(req).await;
+                }
+                if let Some(fallback) = &self.fallback {
+                    MatchedRoute::fallback(&req);
+                    return fallback.call(req).await;
+                }
+                MatchedRoute::unmatched(&req);
+                debug!("No routes were matched, returning 404");
+                Err(HttpError::not_found())
+            }
+        }
+    }
+}
+
+fn json_handler(body: Bytes) -> impl Fn() -> std::future::Ready<Response> + Send + Sync {
+    move || {
+        let response = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.clone());
+        std::future::ready(response)
+    }
+}
+
+/// Lets a shared router act as a handler, running the router-wide middlewares around
+/// [`Router::dispatch`].
+impl Handler for Arc<Router> {
+    fn call(&self, mut req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let router = self.clone();
+        Box::pin(async move {
+            // Ahead of the middlewares, which can take it too.
+            router.inject_state(&mut req);
+            if router.middlewares.is_empty() {
+                return router.route_request(req).await;
+            }
+
+            let middlewares = router.middlewares.as_slice().into();
+            Next::new(middlewares, Arc::new(RouteTable(router)))
+                .run(req)
+                .await
+        })
+    }
+}
+
+struct RouteTable(Arc<Router>);
+
+impl Handler for RouteTable {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let router = self.0.clone();
+        Box::pin(async move { router.route_request(req).await })
+    }
+}
+
+fn method_not_allowed(route: &Route, method: &Method) -> HttpError {
+    HttpError::method_not_allowed(method.as_str())
+        .with_header(ALLOW, &route.allowed_methods().join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    /// State that counts how many times it's been put in a request.
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Clone for Counted {
+        fn clone(&self) -> Self {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Self(self.0.clone())
+        }
+    }
+
+    struct Passthrough;
+
+    impl Middleware for Passthrough {
+        fn handle(
+            &self,
+            req: Request,
+            next: Next,
+        ) -> BoxFuture<'static, Result<Response, HttpError>> {
+            Box::pin(next.run(req))
+        }
+    }
+
+    fn router(injected: &Arc<AtomicUsize>) -> Router {
+        let handler = |req: Request| async move {
+            assert!(req.extensions.get::<Counted>().is_some());
+            Ok::<_, HttpError>(Response::empty(StatusCode::NO_CONTENT))
+        };
+        Router::new()
+            .with_state(Counted(injected.clone()))
+            .route(Method::Get, "/", handler)
+    }
+
+    #[tokio::test]
+    async fn injects_state_once() {
+        let injected = Arc::new(AtomicUsize::new(0));
+        let plain = Arc::new(router(&injected));
+        let layered = Arc::new(router(&injected).layer(Passthrough));
+        for router in [&plain, &layered] {
+            injected.store(0, Ordering::Relaxed);
+            let response = router.call(Request::new(Method::Get, "/")).await.unwrap();
+            assert_eq!(response.status, StatusCode::NO_CONTENT);
+            assert_eq!(injected.load(Ordering::Relaxed), 1);
+        }
+
+        injected.store(0, Ordering::Relaxed);
+        plain
+            .dispatch(Request::new(Method::Get, "/"))
+            .await
+            .unwrap();
+        assert_eq!(injected.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,1157 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyhow::Context;
+use base64::{
+    engine::general_purpose::{STANDARD, STANDARD_NO_PAD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use bytes::Bytes;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::AsyncReadExt,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{debug, Span};
+
+use crate::{
+    access_file,
+    access_log::rfc3339_time,
+    basic_auth::{self, BasicCredentials},
+    bearer_auth::constant_time_eq,
+    body::Body,
+    cookies::{http_date, Cookies, SetCookie},
+    error::HttpError,
+    extract::{Headers, Path, PathParams, Query, RemoteAddr, State, Target, TypedHeader},
+    headers::{
+        Accept, HeaderMap, UserAgent, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, EXPIRES, HOST,
+        LOCATION, VARY, X_TTL,
+    },
+    multipart::{Malformed, Multipart},
+    notices::{Notice, Posted},
+    request::{Method, Scheme},
+    response::{IntoResponse, Json, Response},
+    router::Router,
+    rt, safe_path, served_dir,
+    sse::{Event, LastEventId, Sse},
+    state::AppState,
+    static_files::escape_html,
+    status::StatusCode,
+    ttl::Ttl,
+    websocket::WebSocketUpgrade,
+};
+
+pub fn default_router(state: Arc<AppState>) -> Router {
+    Router::new()
+        .get("/", root)
+        .get("/echo/{*text}", echo)
+        .get("/echo-json/{*text}", echo_json)
+        .post("/echo", echo_body)
+        .put("/echo", echo_body)
+        .get("/user-agent", user_agent)
+        .get("/headers", headers)
+        .get("/ip", ip)
+        .any("/anything", anything)
+        .any("/anything/{*rest}", anything)
+        .get("/uuid", new_uuid)
+        .get("/bytes/{n}", random_bytes)
+        .get("/base64/{*value}", decode_base64)
+        .get("/stream/{n}", stream_lines)
+        .get("/drip", drip)
+        .get("/cookies", cookies)
+        .get("/cookies/set", set_cookies)
+        .get("/cookies/delete", delete_cookies)
+        .get("/redirect/{n}", redirect)
+        .get("/relative-redirect/{n}", relative_redirect)
+        .get("/absolute-redirect/{n}", absolute_redirect)
+        .get("/basic-auth/{user}/{password}", basic_auth)
+        .get("/ws/echo", ws_echo)
+        .get("/ws/broadcast", ws_broadcast)
+        .get("/events", events)
+        .get("/files/{*name}", get_file)
+        .post("/files/{*name}", post_file)
+        .put("/files/{*name}", put_file)
+        .delete("/files/{*name}", delete_file)
+        .get("/kv/{key}", kv_get)
+        .put("/kv/{key}", kv_put)
+        .delete("/kv/{key}", kv_delete)
+        .post("/shorten", shorten)
+        .get("/s/{code}", follow_link)
+        .get("/upload", upload_page)
+        .post("/upload", upload_form)
+        .get("/status/{code}", status)
+        .post("/status/{code}", status)
+        .put("/status/{code}", status)
+        .delete("/status/{code}", status)
+        .patch("/status/{code}", status)
+        .get("/delay/{ms}", delay)
+        .post("/delay/{ms}", delay)
+        .openapi(
+            "/openapi.json",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION"),
+        )
+        .with_state(state)
+}
+
+async fn root() -> StatusCode {
+    StatusCode::OK
+}
+
+#[derive(Serialize)]
+struct EchoMessage {
+    message: String,
+    /// In bytes.
+    length: usize,
+    received_at: String,
+}
+
+/// The text in the path, as it is, or as JSON to a client that would rather have that.
+async fn echo(
+    Path(text): Path<String>,
+    accept: Option<TypedHeader<Accept>>,
+) -> Result<Response, HttpError> {
+    let json =
+        accept.is_some_and(|TypedHeader(accept)| accept.prefers("application/json", "text/plain"));
+    let response = match json {
+        true => echo_json(Path(text)).await.into_response()?,
+        false => text.into_response()?,
+    };
+    Ok(response.with_header(VARY, ACCEPT))
+}
+
+/// The text in the path, with its length and when it came, as JSON.
+async fn echo_json(Path(text): Path<String>) -> Json<EchoMessage> {
+    Json(EchoMessage {
+        length: text.len(),
+        message: text,
+        received_at: rfc3339_time(SystemTime::now()),
+    })
+}
+
+/// The request's body straight back, with its `Content-Type`, each chunk sent on as it comes
+/// in. A body with a length gets the same length back; a chunked one goes back chunked.
+async fn echo_body(Headers(headers): Headers, body: Body) -> Response {
+    let len = body.content_length().filter(|_| !body.is_chunked());
+    let chunks = futures_util::stream::unfold(body, |mut body| async move {
+        match body.chunk().await {
+            Ok(Some(chunk)) => Some((Ok(chunk), body)),
+            Ok(None) => None,
+            Err(e) => Some((Err(e), Body::empty())),
+        }
+    });
+    let mut response = Response::stream(StatusCode::OK, chunks);
+    match headers.get(CONTENT_TYPE) {
+        Some(content_type) => response.set_header(CONTENT_TYPE, content_type),
+        None => response.headers.remove(CONTENT_TYPE),
+    }
+    if let Some(len) = len {
+        response.set_header(CONTENT_LENGTH, &len.to_string());
+    }
+    response
+}
+
+async fn user_agent(TypedHeader(UserAgent(user_agent)): TypedHeader<UserAgent>) -> String {
+    user_agent
+}
+
+#[derive(Deserialize)]
+struct StatusParams {
+    body: Option<String>,
+    location: Option<String>,
+}
+
+/// Answers with whatever status is asked for, for testing clients against: its code and
+/// reason as the body, or `?body=`, and for redirects a `Location` of `/`, or `?location=`,
+/// which has to be a path on this server, so this can't be used to send people elsewhere.
+async fn status(
+    Path(code): Path<u16>,
+    Query(params): Query<StatusParams>,
+) -> Result<Response, HttpError> {
+    // An informational status can't be the last word on a request.
+    if !(200..600).contains(&code) {
+        return Err(HttpError::bad_request(
+            "The status has to be from 200 to 599",
+        ));
+    }
+    let status = StatusCode(code);
+    let mut response = match code {
+        204 | 205 | 304 => Response::empty(status),
+        _ => {
+            let body = params
+                .body
+                .unwrap_or_else(|| format!("{code} {}", status.reason()).trim_end().to_owned());
+            Response::text(status, body)
+        }
+    };
+    if status.is_redirection() && code != 304 {
+        let location = params.location.unwrap_or_else(|| "/".to_owned());
+        let local = location.starts_with('/') && !location.starts_with("//");
+        if !local || location.contains(|c: char| c == '\\' || c.is_control()) {
+            return Err(HttpError::bad_request(
+                "The location has to be a path on this server",
+            ));
+        }
+        response.set_header(LOCATION, &location);
+    }
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct DelayParams {
+    /// Sends the head straight away and the body in this many lines over the delay, rather
+    /// than all of it after.
+    chunks: Option<u32>,
+}
+
+/// Answers after `ms` milliseconds, at most the configured maximum, for testing timeouts.
+async fn delay(
+    State(state): State<Arc<AppState>>,
+    Path(ms): Path<u64>,
+    Query(params): Query<DelayParams>,
+) -> Response {
+    let delay = Duration::from_millis(ms).min(state.max_delay);
+    let Some(chunks) = params.chunks.filter(|chunks| *chunks > 0) else {
+        rt::sleep(delay).await;
+        return Response::text(StatusCode::OK, format!("Waited {}ms\n", delay.as_millis()));
+    };
+    let chunks = chunks.min(1000);
+    let every = delay / chunks;
+    let lines = futures_util::stream::unfold(1, move |sent| async move {
+        if sent > chunks {
+            return None;
+        }
+        rt::sleep(every).await;
+        let line = format!("{sent}/{chunks} after {}ms\n", (every * sent).as_millis());
+        Some((Ok(Bytes::from(line)), sent + 1))
+    });
+    let mut response = Response::stream(StatusCode::OK, lines);
+    response.set_header(CONTENT_TYPE, "text/plain");
+    response
+}
+
+#[derive(Serialize)]
+struct HeaderList {
+    headers: Vec<Pair>,
+}
+
+/// A header, or a query parameter.
+#[derive(Serialize)]
+struct Pair {
+    name: String,
+    value: String,
+}
+
+fn pairs<'a>(all: impl IntoIterator<Item = (&'a str, &'a str)>) -> Vec<Pair> {
+    all.into_iter()
+        .map(|(name, value)| Pair {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        })
+        .collect()
+}
+
+/// The request's headers as a JSON list of `name` and `value` objects, in the order they came
+/// in, every repeated one included, as the handlers here see them.
+async fn headers(Headers(headers): Headers) -> Json<HeaderList> {
+    Json(HeaderList {
+        headers: pairs(headers.iter()),
+    })
+}
+
+#[derive(Serialize)]
+struct Addresses {
+    /// The client's address, as logged and rate limited by.
+    origin: Option<String>,
+    peer: Option<String>,
+    balancer: Option<String>,
+    forwarded: bool,
+}
+
+/// Where the request came from, for checking `--trusted-proxy` and PROXY protocol setups.
+async fn ip(addr: RemoteAddr) -> Json<Addresses> {
+    Json(Addresses {
+        origin: addr.client.map(|ip| ip.to_string()),
+        peer: addr.peer.map(|peer| peer.to_string()),
+        balancer: addr.balancer.map(|balancer| balancer.to_string()),
+        forwarded: addr.forwarded,
+    })
+}
+
+#[derive(Serialize)]
+struct Echo {
+    method: String,
+    path: String,
+    /// Every parameter, repeated ones included, in order.
+    query: Vec<Pair>,
+    headers: Vec<Pair>,
+    origin: Option<String>,
+    body: String,
+    /// `utf-8`, or `base64` for bodies that aren't text.
+    body_encoding: &'static str,
+}
+
+/// The whole request back as JSON, for any method, for seeing what a client or a proxy in
+/// front of the server sends.
+async fn anything(
+    method: Method,
+    target: Target,
+    Query(query): Query<Vec<(String, String)>>,
+    Headers(headers): Headers,
+    addr: RemoteAddr,
+    body: Bytes,
+) -> Json<Echo> {
+    let (body, body_encoding) = match std::str::from_utf8(&body) {
+        Ok(text) => (text.to_owned(), "utf-8"),
+        Err(_) => (STANDARD.encode(&body), "base64"),
+    };
+    let query = query
+        .iter()
+        .map(|(name, value)| (name.as_str(), value.as_str()));
+    Json(Echo {
+        method: method.to_string(),
+        path: target.path,
+        query: pairs(query),
+        headers: pairs(headers.iter()),
+        origin: addr.client.map(|ip| ip.to_string()),
+        body,
+        body_encoding,
+    })
+}
+
+#[derive(Serialize)]
+struct NewUuid {
+    uuid: String,
+}
+
+async fn new_uuid() -> Json<NewUuid> {
+    Json(NewUuid {
+        uuid: uuid::Uuid::new_v4().to_string(),
+    })
+}
+
+/// The most `/bytes` sends.
+const MAX_RANDOM_BYTES: u64 = 100 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct BytesParams {
+    /// Sends the same bytes for the same seed, every time.
+    seed: Option<u64>,
+}
+
+/// `n` random bytes, streamed in chunks. They're from SplitMix64, which is quick and can be
+/// seeded, but is no good for anything that has to be unpredictable.
+async fn random_bytes(
+    Path(n): Path<u64>,
+    Query(params): Query<BytesParams>,
+) -> Result<Response, HttpError> {
+    if n > MAX_RANDOM_BYTES {
+        return Err(HttpError::bad_request(&format!(
+            "At most {MAX_RANDOM_BYTES} bytes can be asked for"
+        )));
+    }
+    let seed = params
+        .seed
+        .unwrap_or_else(|| uuid::Uuid::new_v4().as_u64_pair().0);
+    let chunks = futures_util::stream::unfold((seed, n), |(mut state, left)| async move {
+        if left == 0 {
+            return None;
+        }
+        let len = left.min(16 * 1024) as usize;
+        let mut chunk = Vec::with_capacity(len + 8);
+        while chunk.len() < len {
+            // SplitMix64, as in https://prng.di.unimi.it/splitmix64.c.
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            chunk.extend_from_slice(&(z ^ (z >> 31)).to_le_bytes());
+        }
+        chunk.truncate(len);
+        Some((Ok(Bytes::from(chunk)), (state, left - len as u64)))
+    });
+    Ok(Response::stream(StatusCode::OK, chunks))
+}
+
+/// `value` decoded from base64, standard or URL-safe, padded or not.
+async fn decode_base64(Path(value): Path<String>) -> Result<Response, HttpError> {
+    let value = value.trim_end_matches('=');
+    let decoded = STANDARD_NO_PAD
+        .decode(value)
+        .or_else(|_| URL_SAFE_NO_PAD.decode(value))
+        .map_err(|_| HttpError::bad_request("The value isn't valid base64"))?;
+    Ok(match String::from_utf8(decoded) {
+        Ok(text) => Response::text(StatusCode::OK, text),
+        Err(e) => Response::bytes(StatusCode::OK, e.into_bytes()),
+    })
+}
+
+/// The most lines `/stream` sends.
+const MAX_STREAM_LINES: u32 = 10_000;
+
+#[derive(Deserialize)]
+struct StreamParams {
+    /// Milliseconds between lines, at most the configured maximum delay.
+    delay: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct StreamLine {
+    id: u32,
+    of: u32,
+    /// Milliseconds since the response started.
+    elapsed_ms: u128,
+}
+
+/// `n` lines of JSON, each its own chunk, flushed as soon as it's written. A stream rather
+/// than a task feeding a [`Response::channel`], so that it runs on whatever runs the server.
+async fn stream_lines(
+    State(state): State<Arc<AppState>>,
+    Path(n): Path<u32>,
+    Query(params): Query<StreamParams>,
+) -> Response {
+    let n = n.min(MAX_STREAM_LINES);
+    let delay = Duration::from_millis(params.delay.unwrap_or(0)).min(state.max_delay);
+    let start = Instant::now();
+    let lines = futures_util::stream::unfold(0, move |id| async move {
+        if id == n {
+            return None;
+        }
+        if id > 0 && !delay.is_zero() {
+            rt::sleep(delay).await;
+        }
+        let line = StreamLine {
+            id,
+            of: n,
+            elapsed_ms: start.elapsed().as_millis(),
+        };
+        let mut line = serde_json::to_vec(&line).expect("serializing a stream line");
+        line.push(b'\n');
+        Some((Ok(Bytes::from(line)), id + 1))
+    });
+    let mut response = Response::stream(StatusCode::OK, lines);
+    response.set_header(CONTENT_TYPE, "application/x-ndjson");
+    response
+}
+
+/// The most bytes `/drip` sends.
+const MAX_DRIP_BYTES: u64 = 10 * 1024 * 1024;
+
+#[derive(Deserialize)]
+struct DripParams {
+    /// Seconds to send the bytes over.
+    duration: Option<f64>,
+    numbytes: Option<u64>,
+    code: Option<u16>,
+    /// Seconds to wait before answering at all.
+    delay: Option<f64>,
+}
+
+/// `numbytes` asterisks (10 by default), evenly spread over `duration` seconds (2 by default),
+/// with a `Content-Length` so the client knows how many to wait for. The duration and delay
+/// are each cut down to the configured maximum delay.
+async fn drip(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<DripParams>,
+) -> Result<Response, HttpError> {
+    let seconds = |secs: Option<f64>, default: f64| {
+        let secs = secs.unwrap_or(default);
+        match secs.is_finite() && secs >= 0.0 {
+            true => Ok(Duration::from_secs_f64(secs.min(1e9)).min(state.max_delay)),
+            false => Err(HttpError::bad_request(
+                "Durations have to be seconds, from 0",
+            )),
+        }
+    };
+    let duration = seconds(params.duration, 2.0)?;
+    let delay = seconds(params.delay, 0.0)?;
+    let numbytes = params.numbytes.unwrap_or(10);
+    if numbytes > MAX_DRIP_BYTES {
+        return Err(HttpError::bad_request(&format!(
+            "At most {MAX_DRIP_BYTES} bytes can be dripped"
+        )));
+    }
+    let code = params.code.unwrap_or(200);
+    if !(200..600).contains(&code) {
+        return Err(HttpError::bad_request(
+            "The status has to be from 200 to 599",
+        ));
+    }
+    // These can't have a body: a client would read the bytes as the start of the next answer.
+    if matches!(code, 204 | 205 | 304) {
+        return Err(HttpError::bad_request(&format!(
+            "A {code} can't have a body to drip"
+        )));
+    }
+    rt::sleep(delay).await;
+    let every = duration.div_f64(numbytes.max(1) as f64);
+    let bytes = futures_util::stream::unfold(0, move |sent| async move {
+        if sent == numbytes {
+            return None;
+        }
+        rt::sleep(every).await;
+        Some((Ok(Bytes::from_static(b"*")), sent + 1))
+    });
+    let mut response = Response::stream(StatusCode(code), bytes);
+    response.set_header(CONTENT_LENGTH, &numbytes.to_string());
+    Ok(response)
+}
+
+#[derive(Serialize)]
+struct CookieList {
+    cookies: BTreeMap<String, String>,
+}
+
+/// The cookies the request came with, by name.
+async fn cookies(cookies: Cookies) -> Json<CookieList> {
+    let cookies = cookies
+        .iter()
+        .map(|(name, value)| (name.to_owned(), value.to_owned()));
+    Json(CookieList {
+        cookies: cookies.collect(),
+    })
+}
+
+/// Sets a cookie, for the whole site, for each `name=value` of the query, and sends the
+/// client back to `/cookies` to see them.
+async fn set_cookies(Query(cookies): Query<Vec<(String, String)>>) -> Response {
+    cookies
+        .into_iter()
+        .fold(Response::found("/cookies"), |response, (name, value)| {
+            response.with_cookie(&SetCookie::new(name, value).path("/"))
+        })
+}
+
+/// Deletes the cookies named in the query, as `?a&b`, set by `/cookies/set`.
+async fn delete_cookies(Query(cookies): Query<Vec<(String, String)>>) -> Response {
+    cookies
+        .into_iter()
+        .fold(Response::found("/cookies"), |response, (name, _)| {
+            response.with_cookie(&SetCookie::removal(name).path("/"))
+        })
+}
+
+/// The longest chain of redirects the `/redirect` routes make.
+const MAX_REDIRECTS: u32 = 100;
+
+#[derive(Deserialize)]
+struct RedirectParams {
+    /// The status to redirect with, 302 by default.
+    status: Option<u16>,
+    /// For `/redirect`, whether its `Location`s are absolute URLs rather than paths.
+    #[serde(default)]
+    absolute: bool,
+}
+
+/// `n` redirects, each to the route for one fewer, and the last to `/anything`.
+async fn redirect(
+    Path(n): Path<u32>,
+    Query(params): Query<RedirectParams>,
+    scheme: Scheme,
+    Headers(headers): Headers,
+) -> Result<Response, HttpError> {
+    let origin = match params.absolute {
+        true => Some(origin(scheme, &headers)?),
+        false => None,
+    };
+    let query = match params.absolute {
+        true => "absolute=true&",
+        false => "",
+    };
+    redirect_chain(n, "redirect", query, params.status, origin)
+}
+
+async fn relative_redirect(
+    Path(n): Path<u32>,
+    Query(params): Query<RedirectParams>,
+) -> Result<Response, HttpError> {
+    redirect_chain(n, "relative-redirect", "", params.status, None)
+}
+
+async fn absolute_redirect(
+    Path(n): Path<u32>,
+    Query(params): Query<RedirectParams>,
+    scheme: Scheme,
+    Headers(headers): Headers,
+) -> Result<Response, HttpError> {
+    let origin = origin(scheme, &headers)?;
+    redirect_chain(n, "absolute-redirect", "", params.status, Some(origin))
+}
+
+/// `http://host:port` for absolute `Location`s, from the `Host` the client asked for.
+fn origin(scheme: Scheme, headers: &HeaderMap) -> Result<String, HttpError> {
+    let host = headers.get(HOST).unwrap_or_default();
+    let valid = !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-.:[]".contains(&b));
+    if !valid {
+        return Err(HttpError::bad_request(
+            "Absolute redirects need a valid Host",
+        ));
+    }
+    Ok(format!("{}://{host}", scheme.as_str()))
+}
+
+fn redirect_chain(
+    n: u32,
+    route: &str,
+    query: &str,
+    status: Option<u16>,
+    origin: Option<String>,
+) -> Result<Response, HttpError> {
+    if !(1..=MAX_REDIRECTS).contains(&n) {
+        return Err(HttpError::bad_request(&format!(
+            "The chain has to be from 1 to {MAX_REDIRECTS} redirects long"
+        )));
+    }
+    let status = StatusCode(status.unwrap_or(302));
+    if !matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308) {
+        return Err(HttpError::bad_request(
+            "The status has to be 301, 302, 303, 307 or 308",
+        ));
+    }
+    let next = match n {
+        1 => "/anything".to_owned(),
+        _ => format!("/{route}/{}?{query}status={}", n - 1, status.as_u16()),
+    };
+    let location = format!("{}{next}", origin.unwrap_or_default());
+    Ok(Response::redirect(status, &location))
+}
+
+#[derive(Serialize)]
+struct Authenticated {
+    authenticated: bool,
+    user: String,
+}
+
+/// Asks for the user and password in the path, and says who the client authenticated as once
+/// it sends them.
+async fn basic_auth(
+    PathParams(params): PathParams,
+    credentials: BasicCredentials,
+) -> Result<Json<Authenticated>, HttpError> {
+    let [(_, user), (_, password)] = params.as_slice() else {
+        return Err(anyhow::anyhow!("/basic-auth takes a user and a password").into());
+    };
+    let matches = constant_time_eq(credentials.user.as_bytes(), user.as_bytes())
+        & constant_time_eq(credentials.password.as_bytes(), password.as_bytes());
+    if !matches {
+        return Err(basic_auth::challenge(env!("CARGO_PKG_NAME")));
+    }
+    Ok(Json(Authenticated {
+        authenticated: true,
+        user: credentials.user,
+    }))
+}
+
+/// Sends every message back to the client it came from.
+async fn ws_echo(ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(|socket| async move {
+        let (sender, mut receiver) = socket.split();
+        while let Some(message) = receiver.recv().await {
+            if sender.send(message).await.is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Sends every message to all the clients connected, the one it came from included.
+async fn ws_broadcast(State(state): State<Arc<AppState>>, ws: WebSocketUpgrade) -> Response {
+    let channel = state.broadcast.clone();
+    ws.on_upgrade(move |socket| async move {
+        let (sender, mut receiver) = socket.split();
+        let mut messages = channel.subscribe();
+        // Messages from the others go out through the same sender as the receiver's pongs,
+        // which keeps each whole.
+        let forward = async {
+            loop {
+                match messages.recv().await {
+                    Ok(message) => {
+                        if sender.send(message).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(RecvError::Lagged(missed)) => {
+                        debug!("A WebSocket client too slow to keep up missed {missed} messages")
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        };
+        let publish = async {
+            while let Some(message) = receiver.recv().await {
+                // Nobody may be listening, by now.
+                let _ = channel.send(message);
+            }
+        };
+        tokio::select! {
+            () = forward => {}
+            () = publish => {}
+        }
+    })
+}
+
+#[derive(Deserialize)]
+struct EventsParams {
+    /// Seconds between heartbeats.
+    interval: Option<u64>,
+}
+
+/// A `heartbeat` event with the time straight away and then every `?interval=` seconds (15 by
+/// default), and a `reload` or `drain` event whenever the server reloads or starts draining,
+/// after which the stream ends. Only those have ids, so a client reconnecting with
+/// `Last-Event-ID` gets the ones it missed first.
+async fn events(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<EventsParams>,
+    last: Option<LastEventId>,
+) -> Result<Sse<impl Stream<Item = Event>>, HttpError> {
+    let interval = params.interval.unwrap_or(15);
+    if !(1..=3600).contains(&interval) {
+        return Err(HttpError::bad_request(
+            "The interval must be from 1 to 3600 seconds",
+        ));
+    }
+    if state.notices.draining() {
+        return Err(HttpError::service_unavailable());
+    }
+    // Ids from another run of the server or from elsewhere don't say what was missed.
+    let after = last.and_then(|LastEventId(id)| id.parse().ok());
+    let (missed, notices) = state.notices.subscribe(after);
+    let events = EventStream {
+        missed: missed.into(),
+        notices,
+        next_heartbeat: Instant::now(),
+        interval: Duration::from_secs(interval),
+        first: true,
+        done: false,
+    };
+    Ok(Sse::new(futures_util::stream::unfold(
+        events,
+        EventStream::next,
+    )))
+}
+
+struct EventStream {
+    missed: VecDeque<Posted>,
+    notices: broadcast::Receiver<Posted>,
+    next_heartbeat: Instant,
+    interval: Duration,
+    first: bool,
+    done: bool,
+}
+
+#[derive(Serialize)]
+struct EventData {
+    time: String,
+}
+
+impl EventStream {
+    async fn next(mut self) -> Option<(Event, Self)> {
+        if self.done {
+            return None;
+        }
+        let mut event = loop {
+            let posted = match self.missed.pop_front() {
+                Some(posted) => posted,
+                None => tokio::select! {
+                    () = rt::sleep_until(self.next_heartbeat) => {
+                        self.next_heartbeat += self.interval;
+                        break event_data(SystemTime::now()).event("heartbeat");
+                    }
+                    received = self.notices.recv() => match received {
+                        Ok(posted) => posted,
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => return None,
+                    },
+                },
+            };
+            self.done = posted.notice == Notice::Draining;
+            break event_data(posted.at)
+                .event(posted.notice.to_string())
+                .id(posted.id.to_string());
+        };
+        if std::mem::take(&mut self.first) {
+            event = event.retry(Duration::from_secs(3));
+        }
+        Some((event, self))
+    }
+}
+
+fn event_data(time: SystemTime) -> Event {
+    let data = EventData {
+        time: rfc3339_time(time),
+    };
+    Event::new(serde_json::to_string(&data).expect("serializing event data"))
+}
+
+async fn get_file(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<Response, HttpError> {
+    record_file(&name);
+    let path = resolve_file(&name)?;
+    let file = state
+        .base_dir
+        .open(&path)
+        .await
+        .map_err(served_dir::not_found)?;
+    let metadata = file.metadata().await.context("reading file metadata")?;
+    let expires = state
+        .upload_expiry
+        .expires(&state.base_dir, &path, &metadata);
+    // Gone as far as clients can tell, even before the sweep deletes it.
+    if expires.is_some_and(|expires| expires <= SystemTime::now()) {
+        return Err(HttpError::not_found());
+    }
+    let response = Response::file(StatusCode::OK, file, metadata.len());
+    Ok(with_expires(response, expires))
+}
+
+/// The TTL an upload asks for, in `?ttl=` or an `X-TTL` header, as when it expires.
+fn upload_expires(
+    params: &TtlParams,
+    headers: &HeaderMap,
+) -> Result<Option<SystemTime>, HttpError> {
+    let ttl = params.ttl.as_deref().or_else(|| headers.get(X_TTL));
+    ttl.map(expires_after).transpose()
+}
+
+fn expires_after(ttl: &str) -> Result<SystemTime, HttpError> {
+    let Ttl(ttl) = ttl.parse()?;
+    Ok(SystemTime::now() + ttl)
+}
+
+fn with_expires(mut response: Response, expires: Option<SystemTime>) -> Response {
+    if let Some(expires) = expires {
+        response.set_header(EXPIRES, &http_date(expires));
+    }
+    response
+}
+
+/// Saves the body as the file, to be deleted once the TTL asked for runs out, if any, which
+/// the `Expires` of the answer says when.
+async fn post_file(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<TtlParams>,
+    Headers(headers): Headers,
+    body: Body,
+) -> Result<Response, HttpError> {
+    record_file(&name);
+    let path = resolve_file(&name)?;
+    let expires = upload_expires(&params, &headers)?;
+    write_file(&state, &path, body, expires).await?;
+    Ok(with_expires(Response::empty(StatusCode::CREATED), expires))
+}
+
+/// Like POST, but tells apart creating the file (201) and replacing it (204).
+async fn put_file(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+    Query(params): Query<TtlParams>,
+    Headers(headers): Headers,
+    body: Body,
+) -> Result<Response, HttpError> {
+    record_file(&name);
+    let path = resolve_file(&name)?;
+    let expires = upload_expires(&params, &headers)?;
+    let existed = state.base_dir.exists(&path).await;
+    write_file(&state, &path, body, expires).await?;
+    let status = match existed {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::CREATED,
+    };
+    Ok(with_expires(Response::empty(status), expires))
+}
+
+async fn delete_file(
+    State(state): State<Arc<AppState>>,
+    Path(name): Path<String>,
+) -> Result<StatusCode, HttpError> {
+    record_file(&name);
+    let path = resolve_file(&name)?;
+    match state.base_dir.remove_file(&path).await {
+        Ok(()) => {
+            state
+                .upload_expiry
+                .set(&state.base_dir, &path, None)
+                .await?;
+            Ok(StatusCode::NO_CONTENT)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(HttpError::not_found()),
+        Err(e) => Err(served_dir::failed(e, "deleting file")),
+    }
+}
+
+/// The longest key `/kv` takes.
+const MAX_KEY: usize = 250;
+
+fn check_key(key: &str) -> Result<(), HttpError> {
+    match key.len() {
+        0 => Err(HttpError::bad_request("The key can't be empty")),
+        len if len > MAX_KEY => Err(HttpError::bad_request(&format!(
+            "Keys can be at most {MAX_KEY} bytes long"
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The value under the key, with the type it was stored with, and an `Expires` if it will.
+async fn kv_get(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+) -> Result<Response, HttpError> {
+    check_key(&key)?;
+    let entry = state.kv.get(&key).ok_or_else(HttpError::not_found)?;
+    let mut response = Response::bytes(StatusCode::OK, entry.value);
+    if let Some(content_type) = &entry.content_type {
+        response.set_header(CONTENT_TYPE, content_type);
+    }
+    if let Some(expires) = entry.expires {
+        let left = expires.saturating_duration_since(Instant::now());
+        response.set_header(EXPIRES, &http_date(SystemTime::now() + left));
+    }
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct TtlParams {
+    /// How long to keep it, like `30s` or `1h`; for good without one.
+    ttl: Option<String>,
+}
+
+/// Stores the body under the key, answering 201 for a new key and 204 for one replaced.
+async fn kv_put(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+    Query(params): Query<TtlParams>,
+    Headers(headers): Headers,
+    value: Bytes,
+) -> Result<StatusCode, HttpError> {
+    check_key(&key)?;
+    let ttl = params.ttl.as_deref().map(str::parse::<Ttl>).transpose()?;
+    let content_type = headers.get(CONTENT_TYPE).map(str::to_owned);
+    let replaced = state
+        .kv
+        .put(&key, value, content_type, ttl.map(|Ttl(ttl)| ttl))?;
+    Ok(match replaced {
+        true => StatusCode::NO_CONTENT,
+        false => StatusCode::CREATED,
+    })
+}
+
+async fn kv_delete(
+    State(state): State<Arc<AppState>>,
+    Path(key): Path<String>,
+) -> Result<StatusCode, HttpError> {
+    check_key(&key)?;
+    match state.kv.remove(&key) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err(HttpError::not_found()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ShortenForm {
+    url: String,
+}
+
+#[derive(Serialize)]
+struct ShortLink {
+    code: String,
+    /// Absolute when the request had a valid `Host`.
+    short_url: String,
+    target: String,
+}
+
+/// A code for the URL, given as JSON or a form with a `url` field, or as the body by itself,
+/// which is how `curl -d` sends it even though it says it's a form. A new code is a 201; one
+/// the URL already had, a 200.
+async fn shorten(
+    State(state): State<Arc<AppState>>,
+    scheme: Scheme,
+    Headers(headers): Headers,
+    body: Bytes,
+) -> Result<Response, HttpError> {
+    let content_type = headers.get(CONTENT_TYPE).unwrap_or_default();
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    let form = match essence.eq_ignore_ascii_case("application/x-www-form-urlencoded") {
+        true => serde_urlencoded::from_bytes::<ShortenForm>(&body).ok(),
+        false => None,
+    };
+    let target = if essence.eq_ignore_ascii_case("application/json") {
+        let form: ShortenForm = serde_json::from_slice(&body)
+            .map_err(|e| HttpError::bad_request(&format!("invalid json body: {e}")))?;
+        form.url
+    } else if let Some(form) = form {
+        form.url
+    } else {
+        String::from_utf8(body.to_vec())
+            .map_err(|_| HttpError::bad_request("The URL isn't UTF-8"))?
+            .trim()
+            .to_owned()
+    };
+    let (code, new) = state.shortener.shorten(&target).await?;
+    let path = format!("/s/{code}");
+    let short_url = match origin(scheme, &headers) {
+        Ok(origin) => format!("{origin}{path}"),
+        Err(_) => path.clone(),
+    };
+    let status = match new {
+        true => StatusCode::CREATED,
+        false => StatusCode::OK,
+    };
+    let link = ShortLink {
+        code,
+        short_url,
+        target,
+    };
+    Ok((status, Json(link))
+        .into_response()?
+        .with_header(LOCATION, &path))
+}
+
+/// A 302 to where the code leads, counted as a hit.
+async fn follow_link(
+    State(state): State<Arc<AppState>>,
+    Path(code): Path<String>,
+) -> Result<Response, HttpError> {
+    let target = state
+        .shortener
+        .follow(&code)
+        .ok_or_else(HttpError::not_found)?;
+    Ok(Response::found(&target))
+}
+
+const UPLOAD_PAGE: &str = include_str!("routes/upload.html");
+
+/// A form for uploading files from a browser, to `/upload` or, dropped on the page, to
+/// `/files` one at a time.
+async fn upload_page() -> Response {
+    let mut response = Response::text(StatusCode::OK, UPLOAD_PAGE);
+    response.set_header(CONTENT_TYPE, "text/html; charset=utf-8");
+    response
+}
+
+/// Saves the files of a `multipart/form-data` form into the directory `/files` serves, as
+/// `POST /files/{name}` would, each under the name it was picked with. A `ttl` field, or a
+/// TTL given as for `/files`, has the files after it deleted once it runs out.
+async fn upload_form(
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<TtlParams>,
+    Headers(headers): Headers,
+    mut form: Multipart,
+) -> Result<Response, HttpError> {
+    let mut expires = upload_expires(&params, &headers)?;
+    let mut saved = Vec::new();
+    while let Some(mut part) = form.next_part().await? {
+        if part.name() == Some("ttl") && part.file_name().is_none() {
+            let mut ttl = Vec::new();
+            (&mut part)
+                .take(64)
+                .read_to_end(&mut ttl)
+                .await
+                .context("reading form")
+                .map_err(|e| form_error(e.into()))?;
+            expires = match std::str::from_utf8(&ttl).map(str::trim) {
+                Ok("") => None,
+                Ok(ttl) => Some(expires_after(ttl)?),
+                Err(_) => return Err(HttpError::bad_request("The TTL isn't UTF-8")),
+            };
+            continue;
+        }
+        // Other fields, and file inputs left empty.
+        let Some(name) = part.file_name().filter(|name| !name.is_empty()) else {
+            continue;
+        };
+        let name = name.to_owned();
+        let path = safe_path::resolve(&safe_path::encode_segment(&name))
+            .ok()
+            .filter(|path| path.file_name().is_some() && !access_file::is_access_file(path))
+            .ok_or_else(|| HttpError::bad_request(&format!("Can't save a file called {name:?}")))?;
+        if let Some(filter) = &state.upload_filter {
+            filter.check(&name, part.content_type())?;
+        }
+        state
+            .base_dir
+            .write(&path, &mut part, state.upload_scan.as_ref())
+            .await
+            .map_err(form_error)?;
+        state
+            .upload_expiry
+            .set(&state.base_dir, &path, expires)
+            .await?;
+        saved.push((name, expires));
+    }
+    if saved.is_empty() {
+        return Err(HttpError::bad_request("The form had no files in it"));
+    }
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><title>Uploaded</title></head><body>\n\
+         <h1>Uploaded</h1>\n<ul>\n",
+    );
+    for (name, expires) in &saved {
+        let href = safe_path::encode_segment(name);
+        let name = escape_html(name);
+        let until = match expires {
+            Some(expires) => format!(", until {}", http_date(*expires)),
+            None => String::new(),
+        };
+        html += &format!("<li><a href=\"/files/{href}\">{name}</a>{until}</li>\n");
+    }
+    html.push_str("</ul>\n<p><a href=\"/upload\">Upload more</a></p>\n</body></html>\n");
+    let mut response = Response::text(StatusCode::CREATED, html);
+    response.set_header(CONTENT_TYPE, "text/html; charset=utf-8");
+    Ok(response)
+}
+
+/// A 400 for a failure that comes down to a malformed form.
+fn form_error(e: HttpError) -> HttpError {
+    match Malformed::caused(&e.error) {
+        true => HttpError::bad_request(&e.error.root_cause().to_string()),
+        false => e,
+    }
+}
+
+/// Where the file called `name` is in the served directory. Access files are as good as not
+/// there: they're the server's, not the clients', to read or change.
+fn resolve_file(name: &str) -> Result<std::path::PathBuf, HttpError> {
+    let path = safe_path::resolve(name)?;
+    match access_file::is_access_file(&path) {
+        true => Err(HttpError::not_found()),
+        false => Ok(path),
+    }
+}
+
+/// Puts the file in the request's span, for tracing.
+fn record_file(name: &str) {
+    Span::current().record("file_path", name);
+}
+
+async fn write_file(
+    state: &AppState,
+    path: &std::path::Path,
+    mut body: Body,
+    expires: Option<SystemTime>,
+) -> Result<(), HttpError> {
+    if !body.is_framed() {
+        return Err(HttpError::bad_request(
+            "No valid Content-Length was provided",
+        ));
+    }
+    state
+        .base_dir
+        .write(path, &mut body, state.upload_scan.as_ref())
+        .await?;
+    state
+        .upload_expiry
+        .set(&state.base_dir, path, expires)
+        .await
+}
@@ -0,0 +1,136 @@
+//! The runtime services the connection code relies on, so that it can run under smol or
+//! async-std as well as tokio. All it needs are timers, for the header and idle timeouts,
+//! stalled writes and skipping unread bodies: locks and channels are tokio's, which work on any
+//! executor.
+//!
+//! By default the timers are tokio's. The `smol` and `async-std` features switch them to
+//! async-io's, the reactor both of those are built on, which runs on a thread of its own and
+//! so works under any executor, tokio's included. With either of them, a connection from
+//! smol or async-std can be served with [`Server::serve_io`], wrapped in [`Compat`]:
+//!
+//! ```ignore
+//! let (stream, _) = listener.accept().await?;
+//! smol::spawn(Server::new(router).serve_io(Compat::new(stream))).detach();
+//! ```
+//!
+//! Listening, TLS and the handlers that read files, run CGI scripts or proxy still use tokio's
+//! networking, file and process APIs, so those need a tokio runtime whatever the timers are.
+//!
+//! [`Server::serve_io`]: crate::server::Server::serve_io
+
+use std::{
+    future::Future,
+    pin::{pin, Pin},
+    time::{Duration, Instant},
+};
+
+use futures_util::future::{select, Either};
+
+/// A pending [`sleep`].
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send + Sync>>;
+
+/// The error for a future that didn't finish before its timeout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("deadline has elapsed")]
+pub struct Elapsed;
+
+/// Completes once `duration` has passed.
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(Instant::now() + duration)
+}
+
+/// Completes at `deadline`, straight away if that has passed.
+#[cfg(not(any(feature = "smol", feature = "async-std")))]
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    Box::pin(tokio::time::sleep_until(deadline.into()))
+}
+
+/// Completes at `deadline`, straight away if that has passed.
+#[cfg(any(feature = "smol", feature = "async-std"))]
+pub fn sleep_until(deadline: Instant) -> Sleep {
+    let timer = async_io::Timer::at(deadline);
+    Box::pin(async move {
+        timer.await;
+    })
+}
+
+/// `future`'s output, unless it takes longer than `duration`.
+pub async fn timeout<F: Future>(duration: Duration, future: F) -> Result<F::Output, Elapsed> {
+    timeout_at(Instant::now() + duration, future).await
+}
+
+/// `future`'s output, unless it isn't ready by `deadline`. Like tokio's, it polls the future
+/// before the timer, so one that's ready in time wins even when the deadline has passed too.
+pub async fn timeout_at<F: Future>(deadline: Instant, future: F) -> Result<F::Output, Elapsed> {
+    match select(pin!(future), sleep_until(deadline)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right(((), _)) => Err(Elapsed),
+    }
+}
+
+#[cfg(any(feature = "smol", feature = "async-std"))]
+pub use compat::Compat;
+
+#[cfg(any(feature = "smol", feature = "async-std"))]
+mod compat {
+    use std::{
+        io,
+        pin::Pin,
+        task::{ready, Context, Poll},
+    };
+
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    /// A stream with the `futures-io` traits smol and async-std implement, adapted to the
+    /// tokio ones the server reads and writes through.
+    #[derive(Debug)]
+    pub struct Compat<S>(S);
+
+    impl<S> Compat<S> {
+        pub fn new(stream: S) -> Self {
+            Self(stream)
+        }
+
+        pub fn into_inner(self) -> S {
+            self.0
+        }
+    }
+
+    impl<S: futures_io::AsyncRead + Unpin> AsyncRead for Compat<S> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let n = ready!(Pin::new(&mut self.0).poll_read(cx, buf.initialize_unfilled()))?;
+            buf.advance(n);
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl<S: futures_io::AsyncWrite + Unpin> AsyncWrite for Compat<S> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_write_vectored(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            bufs: &[io::IoSlice<'_>],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write_vectored(cx, bufs)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_close(cx)
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Turning a request path into one relative to a served directory: the one place that's done,
+//! so every route that maps requests onto files refuses the same tricks.
+//!
+//! [`resolve`] percent-decodes each segment once and refuses:
+//!
+//! - `..`, however it's written: plain, `%2e%2e`, `.%2E` and so on;
+//! - escapes that decode to another escape (double encoding, `%252e%252e`), and ones that
+//!   aren't valid, like `%u002e` or a lone `%`;
+//! - bytes that aren't UTF-8, which includes overlong encodings like `%c0%ae` for `.`;
+//! - separators inside a segment, `%2f` and backslashes, literal or encoded, which Windows
+//!   takes for `/`;
+//! - segments ending in a dot or a space, which Windows drops, so that `x.php.` would open
+//!   `x.php` past any check on its extension;
+//! - colons, which on NTFS name a file's alternate data streams (`x.txt::$DATA`) or a drive;
+//! - NUL and other control characters.
+//!
+//! Directories served from Linux are refused the Windows-only forms too, so that a tree
+//! behaves the same wherever it's served from. Empty and `.` segments are dropped.
+//!
+//! Middleware deciding on a path, like [`.http-access` files](crate::access_file) or the
+//! [upload filter](crate::upload_filter), go by what this makes of it too, so that they see
+//! the very file the handler will open.
+
+use std::{fmt::Write as _, path::PathBuf};
+
+use crate::{error::HttpError, status::StatusCode};
+
+/// Why a request path was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum PathError {
+    #[error("the path climbs out of the directory")]
+    Traversal,
+    #[error("the path has an invalid percent escape")]
+    BadEscape,
+    #[error("the path is percent-encoded twice")]
+    DoubleEncoded,
+    #[error("the path isn't UTF-8")]
+    NotUtf8,
+    #[error("the path has a separator inside a segment")]
+    Separator,
+    #[error("the path has a segment Windows would change")]
+    Trailing,
+    #[error("the path names a data stream or drive")]
+    Colon,
+    #[error("the path has control characters")]
+    Control,
+}
+
+impl From<PathError> for HttpError {
+    /// A 400 for paths no client would send by mistake being malformed, a 404 otherwise, as
+    /// there's no such file.
+    fn from(e: PathError) -> Self {
+        match e {
+            PathError::BadEscape | PathError::NotUtf8 => {
+                HttpError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!("Bad request: {e}"))
+            }
+            _ => HttpError::not_found(),
+        }
+    }
+}
+
+/// The file `path`, a request path or the part of one below a prefix, names within the
+/// directory it's served from.
+pub fn resolve(path: &str) -> Result<PathBuf, PathError> {
+    let mut resolved = PathBuf::new();
+    for raw in path.split('/') {
+        let segment = decode(raw)?;
+        match segment.as_str() {
+            "" | "." => continue,
+            ".." => return Err(PathError::Traversal),
+            _ => {}
+        }
+        check(&segment)?;
+        resolved.push(segment);
+    }
+    Ok(resolved)
+}
+
+fn hex(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|digit| digit as u8)
+}
+
+fn decode(segment: &str) -> Result<String, PathError> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != b'%' {
+            decoded.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+        let escape = bytes.get(i + 1..i + 3).ok_or(PathError::BadEscape)?;
+        let (Some(high), Some(low)) = (hex(escape[0]), hex(escape[1])) else {
+            return Err(PathError::BadEscape);
+        };
+        decoded.push(high << 4 | low);
+        i += 3;
+    }
+    let decoded = String::from_utf8(decoded).map_err(|_| PathError::NotUtf8)?;
+    if decoded != segment && has_escape(&decoded) {
+        return Err(PathError::DoubleEncoded);
+    }
+    Ok(decoded)
+}
+
+/// Whether `text` has anything that looks like a percent escape in it.
+fn has_escape(text: &str) -> bool {
+    let bytes = text.as_bytes();
+    bytes
+        .windows(3)
+        .any(|window| window[0] == b'%' && hex(window[1]).is_some() && hex(window[2]).is_some())
+}
+
+fn check(segment: &str) -> Result<(), PathError> {
+    if segment.contains(['/', '\\']) {
+        return Err(PathError::Separator);
+    }
+    if segment.chars().any(char::is_control) {
+        return Err(PathError::Control);
+    }
+    if segment.contains(':') {
+        return Err(PathError::Colon);
+    }
+    if segment.ends_with(['.', ' ']) {
+        return Err(PathError::Trailing);
+    }
+    Ok(())
+}
+
+/// `name` made safe to put in a URL path as one segment, escaping what [`resolve`] would
+/// otherwise read differently.
+pub fn encode_segment(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => {
+                let _ = write!(encoded, "%{byte:02X}");
+            }
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn ok(path: &str) -> PathBuf {
+        resolve(path).unwrap_or_else(|e| panic!("{path:?} was refused: {e}"))
+    }
+
+    fn refused(path: &str) -> PathError {
+        match resolve(path) {
+            Ok(resolved) => panic!("{path:?} was let through as {resolved:?}"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn plain_paths() {
+        assert_eq!(ok("/a/b.txt"), Path::new("a/b.txt"));
+        assert_eq!(ok("a/b.txt"), Path::new("a/b.txt"));
+        assert_eq!(ok("/dir/"), Path::new("dir"));
+        assert_eq!(ok(""), Path::new(""));
+        assert_eq!(ok("/"), Path::new(""));
+        assert_eq!(ok("/.hidden"), Path::new(".hidden"));
+        assert_eq!(ok("/a..b/c...d.txt"), Path::new("a..b/c...d.txt"));
+        assert_eq!(ok("/...x"), Path::new("...x"));
+    }
+
+    #[test]
+    fn empty_and_current_segments_are_dropped() {
+        assert_eq!(ok("//a///b"), Path::new("a/b"));
+        assert_eq!(ok("/./a/./b/."), Path::new("a/b"));
+        assert_eq!(ok("/%2e/a"), Path::new("a"));
+    }
+
+    #[test]
+    fn escapes_are_decoded_once() {
+        assert_eq!(ok("/my%20file.txt"), Path::new("my file.txt"));
+        assert_eq!(ok("/caf%C3%A9"), Path::new("café"));
+        assert_eq!(ok("/caf%c3%a9"), Path::new("café"));
+        assert_eq!(ok("/100%25.txt"), Path::new("100%.txt"));
+        assert_eq!(ok("/%7euser"), Path::new("~user"));
+        assert_eq!(ok("/café"), Path::new("café"));
+    }
+
+    #[test]
+    fn parent_segments() {
+        for path in [
+            "..",
+            "/..",
+            "/../etc/passwd",
+            "/a/../../etc/passwd",
+            "/a/..",
+            "/a/../b",
+            "../",
+            "/a/b/../../..",
+        ] {
+            assert_eq!(refused(path), PathError::Traversal, "{path}");
+        }
+    }
+
+    #[test]
+    fn encoded_parent_segments() {
+        for path in [
+            "/%2e%2e/etc/passwd",
+            "/%2E%2E/etc/passwd",
+            "/.%2e/etc/passwd",
+            "/%2e./etc/passwd",
+            "/a/%2e%2e/%2e%2e/etc/passwd",
+            "/%2E./%2e%2E/x",
+        ] {
+            assert_eq!(refused(path), PathError::Traversal, "{path}");
+        }
+    }
+
+    #[test]
+    fn encoded_separators() {
+        for path in [
+            "/..%2fetc%2fpasswd",
+            "/%2e%2e%2fetc",
+            "/a%2Fb",
+            "/%2f",
+            "/..%5c..%5cwindows",
+            "/a%5Cb",
+        ] {
+            assert_eq!(refused(path), PathError::Separator, "{path}");
+        }
+    }
+
+    #[test]
+    fn backslashes() {
+        for path in ["/..\\..\\windows\\win.ini", "/a\\b", "\\", "/dir\\"] {
+            assert_eq!(refused(path), PathError::Separator, "{path}");
+        }
+    }
+
+    #[test]
+    fn double_encoding() {
+        for path in [
+            "/%252e%252e/etc/passwd",
+            "/%252E%252E%252Fetc",
+            "/%25%32%65%25%32%65",
+            "/a%255cb",
+            "/%2525",
+        ] {
+            assert_eq!(refused(path), PathError::DoubleEncoded, "{path}");
+        }
+    }
+
+    #[test]
+    fn percent_signs_that_arent_escapes() {
+        assert_eq!(ok("/%25"), Path::new("%"));
+        assert_eq!(ok("/50%25%20off"), Path::new("50% off"));
+        assert_eq!(ok("/%25zz"), Path::new("%zz"));
+        // A name that is itself an escape can't be asked for without looking double encoded.
+        assert_eq!(refused("/%2541"), PathError::DoubleEncoded);
+    }
+
+    #[test]
+    fn invalid_escapes() {
+        for path in [
+            "/%",
+            "/a%",
+            "/a%2",
+            "/%zz",
+            "/%g0",
+            "/%u002e%u002e/etc",
+            "/%%32%65",
+            "/%-1",
+            "/%+f",
+        ] {
+            assert_eq!(refused(path), PathError::BadEscape, "{path}");
+        }
+    }
+
+    #[test]
+    fn overlong_and_invalid_utf8() {
+        for path in [
+            // `.` and `/` as two and three bytes.
+            "/%c0%ae%c0%ae/etc/passwd",
+            "/%C0%AE%C0%AE%C0%AF",
+            "/%e0%80%ae%e0%80%ae",
+            "/%c0%af",
+            "/%c1%9c",
+            "/%f0%80%80%ae",
+            // Lone continuation bytes, truncated sequences and UTF-16 surrogates.
+            "/%80",
+            "/%c3",
+            "/%ed%a0%80",
+            "/%ff%fe",
+        ] {
+            assert_eq!(refused(path), PathError::NotUtf8, "{path}");
+        }
+    }
+
+    #[test]
+    fn trailing_dots_and_spaces() {
+        for path in [
+            "/x.php.",
+            "/x.php%2e",
+            "/x.php ",
+            "/x.php%20",
+            "/x.php. . ",
+            "/dir./x",
+            "/...",
+            "/.. ",
+            "/..%20",
+            "/%2e%2e%2e",
+        ] {
+            assert_eq!(refused(path), PathError::Trailing, "{path}");
+        }
+    }
+
+    #[test]
+    fn alternate_data_streams_and_drives() {
+        for path in [
+            "/x.txt::$DATA",
+            "/x.php::$DATA",
+            "/x.txt:hidden",
+            "/x.txt%3a%3a$DATA",
+            "/x.txt%3A%3A%24DATA",
+            "/C:/windows",
+            "/c:",
+            "/a/C%3a%5cwindows",
+        ] {
+            let e = refused(path);
+            assert!(
+                matches!(e, PathError::Colon | PathError::Separator),
+                "{path}: {e:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn control_characters() {
+        for path in [
+            "/x.txt%00.jpg",
+            "/%00",
+            "/a%0ab",
+            "/a%0d%0aSet-Cookie:%20x",
+            "/a%09b",
+            "/a%7fb",
+            "/a\u{0}b",
+            "/a%c2%85b",
+        ] {
+            assert_eq!(refused(path), PathError::Control, "{path}");
+        }
+    }
+
+    #[test]
+    fn nothing_resolved_climbs_out() {
+        let attempts = [
+            "/%2e%2e",
+            "/..%00/",
+            "/....//",
+            "/..;/",
+            "/.%00./",
+            "/%c0%2e%c0%2e/",
+            "/%uff0e%uff0e/",
+            "/\u{ff0e}\u{ff0e}/x",
+            "/a/b/c/%2e%2e/%2e%2e/%2e%2e/%2e%2e",
+        ];
+        for path in attempts {
+            if let Ok(resolved) = resolve(path) {
+                for component in resolved.components() {
+                    assert!(
+                        matches!(component, std::path::Component::Normal(_)),
+                        "{path} resolved to {resolved:?}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn errors_become_responses() {
+        assert_eq!(
+            HttpError::from(PathError::Traversal).status,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            HttpError::from(PathError::Colon).status,
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            HttpError::from(PathError::BadEscape).status,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            HttpError::from(PathError::NotUtf8).status,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[test]
+    fn encoded_names_resolve_to_themselves() {
+        for name in [
+            "plain.txt",
+            "my file.txt",
+            "100%.txt",
+            "café",
+            "a?b#c",
+            "semi;colon",
+            "&<\">'",
+        ] {
+            let encoded = encode_segment(name);
+            assert_eq!(ok(&encoded), Path::new(name), "{name} as {encoded}");
+        }
+        assert_eq!(encode_segment("a b/c"), "a%20b%2Fc");
+    }
+}
@@ -0,0 +1,105 @@
+//! Secrets: tokens, signing keys and the like, which the server needs the value of but
+//! nothing should ever show.
+//!
+//! A [`Secret`] only hands its value over through [`expose`](Secret::expose). Its `Debug`
+//! and `Serialize` say `(hidden)` instead, so a secret that ends up in a log line, a dumped
+//! config or a JSON answer from the admin listener gives nothing away.
+//!
+//! The binary takes secrets from the environment, the config file's `[server]` table or a
+//! file of their own, and refuses the [`SECRET_OPTIONS`] on the command line, where anyone
+//! on the machine can read them in the process list.
+
+use std::{convert::Infallible, fmt, path::Path, str::FromStr, sync::Arc};
+
+use anyhow::Context as _;
+use serde::{Serialize, Serializer};
+use tracing::warn;
+
+/// What's shown in place of a secret.
+pub const HIDDEN: &str = "(hidden)";
+
+/// The binary's options that take secrets.
+pub const SECRET_OPTIONS: &[&str] = &[
+    "bearer-token",
+    "jwt-secret",
+    "signing-secret",
+    "session-secret",
+    "cookie-key",
+];
+
+/// A value that's kept out of logs and dumps. Clones share it.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Arc<str>);
+
+impl Secret {
+    pub fn new(value: impl Into<String>) -> Self {
+        Self(value.into().into())
+    }
+
+    /// The secret itself, only to be handed to whatever uses it.
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The contents of the file at `path`, without the newline editors leave at the end.
+    pub fn read(path: &Path) -> anyhow::Result<Self> {
+        let text = read_private(path)?;
+        let secret = text.trim_end_matches(['\r', '\n']);
+        anyhow::ensure!(!secret.is_empty(), "{} is empty", path.display());
+        Ok(Self::new(secret))
+    }
+
+    /// Each line of the file at `path`, skipping blank ones and `#` comments.
+    pub fn read_lines(path: &Path) -> anyhow::Result<Vec<Self>> {
+        let text = read_private(path)?;
+        Ok(text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(Self::new)
+            .collect())
+    }
+}
+
+/// Reads a file of secrets, warning if users other than the server's can read it too.
+pub(crate) fn read_private(path: &Path) -> anyhow::Result<String> {
+    #[cfg(unix)]
+    if let Ok(metadata) = std::fs::metadata(path) {
+        use std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "{} holds secrets but others can read it; chmod 600 it",
+                path.display()
+            );
+        }
+    }
+    std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))
+}
+
+impl FromStr for Secret {
+    type Err = Infallible;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        Ok(Self::new(value))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(HIDDEN)
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(HIDDEN)
+    }
+}
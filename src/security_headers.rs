@@ -0,0 +1,147 @@
+//! The response headers that tell browsers to be careful with what they got from this server:
+//!
+//! ```text
+//! Strict-Transport-Security: max-age=31536000; includeSubDomains
+//! X-Content-Type-Options: nosniff
+//! X-Frame-Options: DENY
+//! Content-Security-Policy: frame-ancestors 'none'
+//! Referrer-Policy: strict-origin-when-cross-origin
+//! ```
+//!
+//! `Strict-Transport-Security` only goes on responses over HTTPS, as browsers ignore it on
+//! plain HTTP. Any of them can be changed or left out, everywhere or under a prefix (letting
+//! `/embed/` be framed, say), and a handler that sets one itself keeps its own.
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::{
+        HeaderMap, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+        X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+    },
+    middleware::{Middleware, Next},
+    request::{Request, Scheme},
+    response::Response,
+};
+
+/// Adds the security headers to every response.
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    /// Each header and its value, `None` to leave it out.
+    headers: Vec<(String, Option<String>)>,
+    /// Changes under a prefix, as prefix, header and value.
+    overrides: Vec<(String, String, Option<String>)>,
+}
+
+impl Default for SecurityHeaders {
+    fn default() -> Self {
+        let defaults = [
+            (
+                STRICT_TRANSPORT_SECURITY,
+                "max-age=31536000; includeSubDomains",
+            ),
+            (X_CONTENT_TYPE_OPTIONS, "nosniff"),
+            (X_FRAME_OPTIONS, "DENY"),
+            (CONTENT_SECURITY_POLICY, "frame-ancestors 'none'"),
+            (REFERRER_POLICY, "strict-origin-when-cross-origin"),
+        ];
+        Self {
+            headers: defaults
+                .into_iter()
+                .map(|(name, value)| (name.to_owned(), Some(value.to_owned())))
+                .collect(),
+            overrides: Vec::new(),
+        }
+    }
+}
+
+impl SecurityHeaders {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends `name` with `value` instead of its default, or leaves it out for `None`. Headers
+    /// without a default are added.
+    pub fn set(mut self, name: &str, value: Option<&str>) -> Self {
+        let value = value.map(str::to_owned);
+        match self
+            .headers
+            .iter_mut()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        {
+            Some((_, old)) => *old = value,
+            None => self.headers.push((name.to_owned(), value)),
+        }
+        self
+    }
+
+    /// Like [`set`](Self::set), for responses to requests under `prefix` only. Where prefixes
+    /// overlap, the longest wins.
+    pub fn set_under(mut self, prefix: &str, name: &str, value: Option<&str>) -> Self {
+        self.overrides
+            .push((prefix.to_owned(), name.to_owned(), value.map(str::to_owned)));
+        self
+    }
+
+    /// The headers for a response to a request for `path`.
+    fn for_path(&self, path: &str) -> Vec<(&str, &str)> {
+        let mut overrides: Vec<_> = self
+            .overrides
+            .iter()
+            .filter(|(prefix, _, _)| path.starts_with(prefix.as_str()))
+            .collect();
+        // Stable, so among equally long prefixes the one set last wins.
+        overrides.sort_by_key(|(prefix, _, _)| prefix.len());
+        let mut headers: Vec<(&str, Option<&str>)> = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_deref()))
+            .collect();
+        for (_, name, value) in overrides {
+            match headers
+                .iter_mut()
+                .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            {
+                Some((_, old)) => *old = value.as_deref(),
+                None => headers.push((name, value.as_deref())),
+            }
+        }
+        headers
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, value?)))
+            .collect()
+    }
+}
+
+fn add(headers: &mut HeaderMap, wanted: &[(String, String)]) {
+    for (name, value) in wanted {
+        if !headers.contains(name) {
+            headers.insert(name, value);
+        }
+    }
+}
+
+impl Middleware for SecurityHeaders {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let https = req.scheme == Scheme::Https;
+        let wanted: Vec<(String, String)> = self
+            .for_path(&req.path)
+            .into_iter()
+            .filter(|(name, _)| https || !name.eq_ignore_ascii_case(STRICT_TRANSPORT_SECURITY))
+            .map(|(name, value)| (name.to_owned(), value.to_owned()))
+            .collect();
+        let fut = next.run(req);
+        Box::pin(async move {
+            match fut.await {
+                Ok(mut response) => {
+                    add(&mut response.headers, &wanted);
+                    Ok(response)
+                }
+                Err(mut e) => {
+                    add(&mut e.headers, &wanted);
+                    Err(e)
+                }
+            }
+        })
+    }
+}
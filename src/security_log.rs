@@ -0,0 +1,153 @@
+//! A log of the events a firewall should hear about, in a format meant for fail2ban: failed
+//! authentication, rate limiting and path traversal attempts, one line each, with the
+//! client's address first.
+//!
+//! ```text
+//! 203.0.113.7 2024-10-10T13:55:36.012Z auth-failure GET /admin "Basic: bad credentials for \"bob\""
+//! 203.0.113.7 2024-10-10T13:55:37.120Z rate-limited GET /search "over the rate limit"
+//! 198.51.100.2 2024-10-10T13:55:38.004Z traversal GET /files/%2e%2e/etc/passwd "the path climbs out of the directory"
+//! ```
+//!
+//! The fields are separated by single spaces, and none but the last two ever has one in it;
+//! a path with anything but printable ASCII other than `"` and `\` in it is quoted like the
+//! detail always is, with those escaped, so a client can't forge a line of its own or put
+//! another address at the start of one. The event names are stable. A filter for it:
+//!
+//! ```ini
+//! [Definition]
+//! failregex = ^<HOST> \S+ (?:auth-failure|rate-limited|traversal)
+//! datepattern = ^\S+ %%Y-%%m-%%dT%%H:%%M:%%S
+//! ```
+//!
+//! The address is the one [`client_ip`] makes out, so behind trusted proxies it's the
+//! client's rather than the proxy's, which suits a ban action acting on the proxy's side.
+
+use std::{
+    fmt,
+    io::Write,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use anyhow::Context as _;
+use tracing::warn;
+
+use crate::{
+    access_log::rfc3339_time,
+    error::HttpError,
+    forwarded::client_ip,
+    handler::BoxFuture,
+    log_file::{LogFile, Rotation},
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+    safe_path::{self, PathError},
+};
+
+/// What happened.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// Credentials of any kind that didn't check out, or a client locked out for them.
+    AuthFailure,
+    RateLimited,
+    /// A path trying to climb out of the directories served.
+    Traversal,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Event::AuthFailure => "auth-failure",
+            Event::RateLimited => "rate-limited",
+            Event::Traversal => "traversal",
+        })
+    }
+}
+
+/// Where events are logged to. Clones write to the same place.
+#[derive(Clone)]
+pub struct SecurityLog {
+    out: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+/// In the extensions of requests the [`SecurityLog`] sees, for [`record`] to write with.
+#[derive(Clone)]
+struct Recorder {
+    log: SecurityLog,
+    client: Option<IpAddr>,
+}
+
+/// Logs `event` about the client of `req`, if there's a [`SecurityLog`], with `detail` saying
+/// what it was about.
+pub fn record(req: &Request, event: Event, detail: impl fmt::Display) {
+    if let Some(recorder) = req.extensions.get::<Recorder>() {
+        let detail = detail.to_string();
+        recorder.log.write(recorder.client, event, req, &detail);
+    }
+}
+
+/// `path` as it is if it's plain, quoted otherwise.
+fn field(path: &str) -> String {
+    let plain = !path.is_empty()
+        && path
+            .bytes()
+            .all(|b| b.is_ascii_graphic() && b != b'"' && b != b'\\');
+    match plain {
+        true => path.to_owned(),
+        false => format!("{path:?}"),
+    }
+}
+
+impl SecurityLog {
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            out: Arc::new(Mutex::new(Box::new(out))),
+        }
+    }
+
+    /// Appends to the file at `path`, creating it if need be.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = LogFile::open(path, Rotation::default())
+            .with_context(|| format!("opening security log {}", path.display()))?;
+        Ok(Self::new(file))
+    }
+
+    fn write(&self, client: Option<IpAddr>, event: Event, req: &Request, detail: &str) {
+        let client = client.map_or_else(|| "-".to_owned(), |ip| ip.to_string());
+        let line = format!(
+            "{client} {} {event} {} {} {detail:?}\n",
+            rfc3339_time(SystemTime::now()),
+            req.method,
+            field(&req.path),
+        );
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            warn!("Error writing to the security log: {e}");
+        }
+    }
+}
+
+impl Middleware for SecurityLog {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let recorder = Recorder {
+            log: self.clone(),
+            client: client_ip(&req),
+        };
+        // Whatever route it's for: no client has a reason to send one of these.
+        if let Err(e @ (PathError::Traversal | PathError::DoubleEncoded | PathError::NotUtf8)) =
+            safe_path::resolve(&req.path)
+        {
+            recorder
+                .log
+                .write(recorder.client, Event::Traversal, &req, &e.to_string());
+        }
+        req.extensions.insert(recorder);
+        Box::pin(next.run(req))
+    }
+}
@@ -0,0 +1,482 @@
+//! The directory files are served from, by [`StaticDir`](crate::static_files::StaticDir) and
+//! the `/files` routes.
+//!
+//! Both resolve request paths with [`safe_path`](crate::safe_path) before they get here, but a
+//! [confined](ServedDir::confine) directory doesn't rely on that: on Linux, every path is
+//! opened with `openat2` and `RESOLVE_BENEATH`, so the kernel refuses to resolve one out of the
+//! directory, whether through `..`, an absolute symlink or a symlink a client managed to
+//! upload. Elsewhere, paths
+//! are resolved with symlinks followed and refused if they end up outside, which catches the
+//! same mistakes but not a symlink swapped in at just the wrong moment.
+//!
+//! With a [`Backoff`], a directory on a failing disk or a hung NFS mount stops being touched
+//! for a while once errors pile up, and the routes answer 503 instead of tying up the blocking
+//! threads on it.
+
+use std::{
+    ffi::OsString,
+    fs::{File, Metadata},
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+use tracing::{info, warn};
+
+use crate::{error::HttpError, headers::RETRY_AFTER, status::StatusCode, upload_scan::UploadScan};
+
+#[derive(Debug, Clone)]
+pub struct ServedDir {
+    root: PathBuf,
+    confined: bool,
+    breaker: Option<Arc<Breaker>>,
+}
+
+/// When to give a failing file system a rest: after `threshold` errors in a row, operations
+/// fail straight away with [`BackingOff`] for `cool_down`. After that, one at a time is let
+/// through to probe it; the first to succeed ends the backoff, a failure starts another.
+///
+/// Only errors that say something about the file system count, not missing files or
+/// permissions. Reads and writes of files already open aren't watched.
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    pub threshold: u32,
+    pub cool_down: Duration,
+}
+
+/// The error for operations refused while backing off.
+#[derive(Debug, thiserror::Error)]
+#[error("the file system is failing, retrying in {}s", retry_after_secs(*.retry_after))]
+pub struct BackingOff {
+    pub retry_after: Duration,
+}
+
+impl From<BackingOff> for HttpError {
+    fn from(e: BackingOff) -> Self {
+        let secs = retry_after_secs(e.retry_after).to_string();
+        HttpError::new(StatusCode::SERVICE_UNAVAILABLE, anyhow::Error::new(e))
+            .with_header(RETRY_AFTER, &secs)
+    }
+}
+
+/// 404 for a file that couldn't be opened, or 503 while backing off.
+pub fn not_found(e: io::Error) -> HttpError {
+    match into_backing_off(e) {
+        Ok(e) => e.into(),
+        Err(_) => HttpError::not_found(),
+    }
+}
+
+/// 500 with `context` for an operation that failed, or 503 while backing off.
+pub fn failed(e: io::Error, context: &'static str) -> HttpError {
+    match into_backing_off(e) {
+        Ok(e) => e.into(),
+        Err(e) => anyhow::Error::new(e).context(context).into(),
+    }
+}
+
+fn into_backing_off(e: io::Error) -> Result<BackingOff, io::Error> {
+    match e.get_ref().is_some_and(|inner| inner.is::<BackingOff>()) {
+        true => Ok(*e.into_inner().unwrap().downcast().unwrap()),
+        false => Err(e),
+    }
+}
+
+fn retry_after_secs(retry_after: Duration) -> u64 {
+    (retry_after.as_secs_f64().ceil() as u64).max(1)
+}
+
+impl ServedDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            confined: false,
+            breaker: None,
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Opens files strictly beneath the directory, as described in the [module docs](self).
+    pub fn confine(mut self, confined: bool) -> Self {
+        self.confined = confined;
+        self
+    }
+
+    /// Backs off from the file system once it keeps failing, see [`Backoff`].
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.breaker = Some(Arc::new(Breaker {
+            root: self.root.clone(),
+            backoff,
+            state: Mutex::default(),
+        }));
+        self
+    }
+
+    /// The file at `path`, relative to the directory.
+    pub async fn open(&self, path: &Path) -> io::Result<tokio::fs::File> {
+        let file = self.blocking(path, |dir, path| {
+            #[cfg(target_os = "linux")]
+            if dir.confined {
+                return dir.open_beneath(&path, libc::O_RDONLY);
+            }
+            File::open(dir.resolve(&path)?)
+        });
+        Ok(tokio::fs::File::from_std(file.await?))
+    }
+
+    /// Creates the file at `path`, or truncates it if it's there.
+    pub async fn create(&self, path: &Path) -> io::Result<tokio::fs::File> {
+        let file = self.blocking(path, |dir, path| {
+            #[cfg(target_os = "linux")]
+            if dir.confined {
+                return dir.open_beneath(&path, libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC);
+            }
+            File::create(dir.resolve_parent(&path)?)
+        });
+        Ok(tokio::fs::File::from_std(file.await?))
+    }
+
+    /// Writes `contents` to a temporary file next to `path`, and renames it to `path` once
+    /// it's all there and `scan`, if any, has passed it, so that nobody ever downloads half an
+    /// upload. The temporary file is removed if anything goes wrong.
+    pub async fn write(
+        &self,
+        path: &Path,
+        contents: &mut (impl AsyncRead + Unpin),
+        scan: Option<&UploadScan>,
+    ) -> Result<(), HttpError> {
+        let name = path.file_name().ok_or_else(HttpError::not_found)?;
+        let mut temporary = OsString::from(".");
+        temporary.push(name);
+        temporary.push(format!(".{}.upload", uuid::Uuid::new_v4().simple()));
+        let temporary = path.with_file_name(temporary);
+        let mut file = self
+            .create(&temporary)
+            .await
+            .map_err(|e| failed(e, "opening file for write"))?;
+        let written = async {
+            tokio::io::copy(contents, &mut file)
+                .await
+                .context("writing contents to file")?;
+            file.flush().await.context("writing contents to file")?;
+            drop(file);
+            if let Some(scan) = scan {
+                scan.check(&self.root.join(&temporary), path).await?;
+            }
+            self.rename(&temporary, path)
+                .await
+                .map_err(|e| failed(e, "moving the upload into place"))
+        };
+        let result = written.await;
+        if result.is_err() {
+            let _ = self.remove_file(&temporary).await;
+        }
+        result
+    }
+
+    /// Renames the file at `from` to `to`, which has to be in the same directory, replacing
+    /// whatever was there.
+    pub async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let from = from.to_owned();
+        self.blocking(to, move |dir, to| {
+            if from.parent() != to.parent() {
+                return Err(io::ErrorKind::InvalidInput.into());
+            }
+            #[cfg(target_os = "linux")]
+            if dir.confined {
+                return dir.rename_beneath(&from, &to);
+            }
+            std::fs::rename(dir.resolve_parent(&from)?, dir.resolve_parent(&to)?)
+        })
+        .await
+    }
+
+    /// Follows symlinks, like [`std::fs::metadata`].
+    pub async fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        self.blocking(path, |dir, path| {
+            #[cfg(target_os = "linux")]
+            if dir.confined {
+                return dir.open_beneath(&path, libc::O_PATH)?.metadata();
+            }
+            std::fs::metadata(dir.resolve(&path)?)
+        })
+        .await
+    }
+
+    pub async fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).await.is_ok()
+    }
+
+    pub async fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.blocking(path, |dir, path| {
+            #[cfg(target_os = "linux")]
+            if dir.confined {
+                return dir.unlink_beneath(&path);
+            }
+            std::fs::remove_file(dir.resolve_parent(&path)?)
+        })
+        .await
+    }
+
+    /// The names in the directory at `path`, with whether each is a directory itself.
+    pub async fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, bool)>> {
+        self.blocking(path, |dir, path| {
+            #[cfg(target_os = "linux")]
+            if dir.confined {
+                use std::os::fd::AsRawFd;
+
+                let listed = dir.open_beneath(&path, libc::O_PATH | libc::O_DIRECTORY)?;
+                // The descriptor's entry in /proc leads to the very directory that was opened.
+                return list(format!("/proc/self/fd/{}", listed.as_raw_fd()));
+            }
+            list(dir.resolve(&path)?)
+        })
+        .await
+    }
+
+    /// Runs `f` on the blocking thread pool, as `tokio::fs` does, unless backing off.
+    fn blocking<T, F>(&self, path: &Path, f: F) -> impl Future<Output = io::Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Self, PathBuf) -> io::Result<T> + Send + 'static,
+    {
+        let breaker = self.breaker.clone();
+        let admitted = breaker.as_ref().map_or(Ok(()), |breaker| breaker.admit());
+        let (dir, path) = (self.clone(), path.to_owned());
+        let task = admitted.map(|()| tokio::task::spawn_blocking(move || f(&dir, path)));
+        async move {
+            let result = task.map_err(io::Error::other)?.await;
+            let result = result.map_err(io::Error::other).and_then(|result| result);
+            if let Some(breaker) = breaker {
+                breaker.record(result.as_ref().err());
+            }
+            result
+        }
+    }
+
+    /// Where `path` is. When confined without `openat2`, it must stay inside once symlinks
+    /// are followed.
+    fn resolve(&self, path: &Path) -> io::Result<PathBuf> {
+        let joined = self.root.join(path);
+        if self.confined {
+            let resolved = std::fs::canonicalize(&joined)?;
+            if !resolved.starts_with(std::fs::canonicalize(&self.root)?) {
+                return Err(io::ErrorKind::NotFound.into());
+            }
+        }
+        Ok(joined)
+    }
+
+    /// Like [`resolve`](Self::resolve), for a file that may not be there: its directory has to.
+    fn resolve_parent(&self, path: &Path) -> io::Result<PathBuf> {
+        let name = path.file_name().ok_or(io::ErrorKind::NotFound)?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        Ok(self.resolve(parent)?.join(name))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn open_beneath(&self, path: &Path, flags: libc::c_int) -> io::Result<File> {
+        use std::os::fd::{AsRawFd, FromRawFd};
+
+        let root = open_path(&self.root)?;
+        // An empty path would be the directory itself.
+        let path = match path.as_os_str().is_empty() {
+            true => Path::new("."),
+            false => path,
+        };
+        let path = c_path(path.as_os_str())?;
+        // SAFETY: open_how is plain data; openat2 reads it and the path during the call, and
+        // returns a new descriptor we own.
+        let fd = unsafe {
+            let mut how: libc::open_how = std::mem::zeroed();
+            how.flags = (flags | libc::O_CLOEXEC) as u64;
+            if flags & libc::O_CREAT != 0 {
+                how.mode = 0o666;
+            }
+            how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+            libc::syscall(
+                libc::SYS_openat2,
+                root.as_raw_fd(),
+                path.as_ptr(),
+                &how as *const libc::open_how,
+                std::mem::size_of::<libc::open_how>(),
+            )
+        };
+        match fd {
+            -1 => Err(beneath_error()),
+            fd => Ok(unsafe { File::from_raw_fd(fd as _) }),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn rename_beneath(&self, from: &Path, to: &Path) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let parent = to.parent().unwrap_or(Path::new(""));
+        let parent = self.open_beneath(parent, libc::O_PATH | libc::O_DIRECTORY)?;
+        let from = c_path(from.file_name().ok_or(io::ErrorKind::NotFound)?)?;
+        let to = c_path(to.file_name().ok_or(io::ErrorKind::NotFound)?)?;
+        let parent = parent.as_raw_fd();
+        // SAFETY: renameat reads the names during the call.
+        match unsafe { libc::renameat(parent, from.as_ptr(), parent, to.as_ptr()) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn unlink_beneath(&self, path: &Path) -> io::Result<()> {
+        use std::os::fd::AsRawFd;
+
+        let name = path.file_name().ok_or(io::ErrorKind::NotFound)?;
+        let parent = path.parent().unwrap_or(Path::new(""));
+        let parent = self.open_beneath(parent, libc::O_PATH | libc::O_DIRECTORY)?;
+        let name = c_path(name)?;
+        // SAFETY: unlinkat reads the name during the call.
+        match unsafe { libc::unlinkat(parent.as_raw_fd(), name.as_ptr(), 0) } {
+            -1 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Breaker {
+    root: PathBuf,
+    backoff: Backoff,
+    state: Mutex<BreakerState>,
+}
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    /// Errors in a row.
+    failures: u32,
+    /// Until when operations are refused, while backing off.
+    until: Option<Instant>,
+    /// When the operation probing the file system after a cool-down started. One that's been
+    /// going for a whole cool-down without finishing is given up on.
+    probe: Option<Instant>,
+}
+
+impl Breaker {
+    fn admit(&self) -> Result<(), BackingOff> {
+        let mut state = self.state.lock().unwrap();
+        let Some(until) = state.until else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if now < until {
+            return Err(BackingOff {
+                retry_after: until - now,
+            });
+        }
+        match state.probe {
+            Some(started) if now - started < self.backoff.cool_down => Err(BackingOff {
+                retry_after: Duration::from_secs(1),
+            }),
+            _ => {
+                state.probe = Some(now);
+                Ok(())
+            }
+        }
+    }
+
+    fn record(&self, error: Option<&io::Error>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(error) = error.filter(|e| counts(e)) else {
+            if state.until.take().is_some() {
+                info!(
+                    "The file system under {} is answering again",
+                    self.root.display()
+                );
+            }
+            *state = BreakerState::default();
+            return;
+        };
+        state.failures = state.failures.saturating_add(1);
+        let probed = state.until.is_some();
+        if !probed && state.failures < self.backoff.threshold {
+            return;
+        }
+        let secs = self.backoff.cool_down.as_secs();
+        match probed {
+            true => warn!(
+                "The file system under {} is still failing ({error}), backing off for another {secs}s",
+                self.root.display()
+            ),
+            false => warn!(
+                "{} file system errors in a row under {}, the last {error}; backing off for {secs}s",
+                state.failures,
+                self.root.display()
+            ),
+        }
+        state.until = Some(Instant::now() + self.backoff.cool_down);
+        state.probe = None;
+    }
+}
+
+/// Whether `e` is the file system failing, rather than an answer about the file.
+fn counts(e: &io::Error) -> bool {
+    use io::ErrorKind::*;
+
+    !matches!(
+        e.kind(),
+        NotFound
+            | PermissionDenied
+            | AlreadyExists
+            | InvalidInput
+            | InvalidFilename
+            | NotADirectory
+            | IsADirectory
+            | DirectoryNotEmpty
+            | StorageFull
+            | QuotaExceeded
+            | FileTooLarge
+            | ReadOnlyFilesystem
+    )
+}
+
+fn list(dir: impl AsRef<Path>) -> io::Result<Vec<(OsString, bool)>> {
+    std::fs::read_dir(dir)?
+        .map(|entry| {
+            let entry = entry?;
+            let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+            Ok((entry.file_name(), is_dir))
+        })
+        .collect()
+}
+
+#[cfg(target_os = "linux")]
+fn open_path(dir: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    File::options()
+        .read(true)
+        .custom_flags(libc::O_PATH | libc::O_DIRECTORY)
+        .open(dir)
+}
+
+#[cfg(target_os = "linux")]
+fn c_path(path: &std::ffi::OsStr) -> io::Result<std::ffi::CString> {
+    use std::os::unix::ffi::OsStrExt;
+
+    std::ffi::CString::new(path.as_bytes()).map_err(|_| io::ErrorKind::NotFound.into())
+}
+
+/// What went wrong with `openat2`. Paths that would lead out of the directory fail with
+/// EXDEV, which is as good as not found.
+#[cfg(target_os = "linux")]
+fn beneath_error() -> io::Error {
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::EXDEV) => io::Error::new(io::ErrorKind::NotFound, e),
+        _ => e,
+    }
+}
@@ -0,0 +1,1677 @@
+use std::{
+    any::Any,
+    collections::HashMap,
+    future::Future,
+    io,
+    net::{IpAddr, SocketAddr},
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{ready, Context as TaskContext, Poll},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use futures_util::FutureExt;
+use serde::Serialize;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch, Mutex, OwnedMutexGuard, OwnedSemaphorePermit, Semaphore},
+    task::JoinSet,
+};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+#[cfg(feature = "tls")]
+use crate::tls::{ClientCertificate, TlsAcceptor};
+use crate::{
+    access_log::{self, AccessLog},
+    alert::ErrorRate,
+    body::{Body, BodyReader, ChunkState, Framing},
+    buffer_pool::{BufferPool, PooledReader, PooledWriter, Unread},
+    error::HttpError,
+    forwarded::Cidr,
+    handler::{BoxFuture, Handler},
+    headers::{
+        Connection, ContentLength, HeaderMap, CONNECTION, CONTENT_LENGTH, KEEP_ALIVE,
+        TRANSFER_ENCODING, X_REQUEST_ID,
+    },
+    hooks::{Finished, Hooks, TlsInfo},
+    ip_filter::IpRules,
+    listener::Listener,
+    method_override::OverriddenMethod,
+    min_rate::{MinRate, RateCheck},
+    proxy_protocol,
+    request::{BoxReader, Extensions, IdentitySlot, Method, Request, Scheme},
+    request_id::RequestId,
+    response::Response,
+    router::{MatchedRoute, Router},
+    rt::{self, Sleep},
+    status::StatusCode,
+    trace_context::TraceContext,
+    upgrade::PendingUpgrade,
+    wire_trace::{Traced, WireTrace},
+};
+#[cfg(feature = "metrics")]
+use crate::{
+    metrics::{self, Metrics},
+    statsd::StatsD,
+};
+
+/// How long a client gets to finish the TLS handshake.
+#[cfg(feature = "tls")]
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many connections can be in the middle of getting a 503 at once; beyond that, they're
+/// closed without one.
+const TURN_AWAY_SLOTS: usize = 64;
+/// How long an idle connection is kept open for the client's next request by default.
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The most of an unread request body that's skipped to reuse the connection; with more left,
+/// closing it is cheaper.
+const MAX_SKIPPED_BODY: u64 = 64 * 1024;
+/// Upper bound for a request's line and headers together, so a client can't make us buffer
+/// forever by never ending a line.
+const MAX_HEAD_BYTES: u64 = 64 * 1024;
+/// How long accepting pauses after an error, doubling with every one in a row up to the max.
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Turns errors that reach the connection (from handlers, middleware or request parsing) into
+/// the response that's sent back.
+pub type ErrorHandler = Arc<dyn Fn(&HttpError) -> Response + Send + Sync>;
+
+pub struct Server {
+    handler: Arc<dyn Handler>,
+    error_handler: ErrorHandler,
+    proxy_protocol: bool,
+    max_connections: Option<(usize, WhenFull)>,
+    max_connections_per_ip: Option<usize>,
+    ip_rules: Option<watch::Receiver<Arc<IpRules>>>,
+    limits: ConnectionLimits,
+    live_limits: Option<watch::Receiver<ConnectionLimits>>,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<TcpKeepalive>,
+    stats: Arc<ConnectionStats>,
+    buffers: Arc<BufferPool>,
+    access_log: Option<Arc<AccessLog>>,
+    request_ids: Option<Arc<[Cidr]>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+    #[cfg(feature = "metrics")]
+    statsd: Option<Arc<StatsD>>,
+    error_rate: Option<Arc<ErrorRate>>,
+    slow_requests: Option<Duration>,
+    trace_wire: Option<WireTrace>,
+    hooks: Hooks,
+    shutdown: Option<Shutdown>,
+}
+
+/// How long connections may take over the parts of a request and how many requests they get,
+/// as set with [`Server::head_timeout`] and the methods after it. [`Server::connection_limits`]
+/// lets them change while the server runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub head_timeout: Option<Duration>,
+    pub idle_timeout: Duration,
+    pub max_requests: Option<usize>,
+    pub write_timeout: Option<Duration>,
+    pub min_rate: Option<MinRate>,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            head_timeout: None,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_requests: None,
+            write_timeout: None,
+            min_rate: None,
+        }
+    }
+}
+
+/// Running counts of a server's connections and requests. Servers given the same one with
+/// [`Server::stats`] add up into it.
+#[derive(Debug, Default)]
+pub struct ConnectionStats {
+    accepted: AtomicU64,
+    open: AtomicUsize,
+    requests: AtomicU64,
+    turned_away: AtomicU64,
+}
+
+/// [`ConnectionStats`] at one moment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct StatsSnapshot {
+    /// Connections served, open or closed since, not counting the ones turned away.
+    pub accepted: u64,
+    pub open: usize,
+    pub requests: u64,
+    /// Connections over `max_connections` or `max_connections_per_ip`, or from peers the
+    /// `ip_rules` don't admit.
+    pub turned_away: u64,
+}
+
+impl ConnectionStats {
+    pub fn snapshot(&self) -> StatsSnapshot {
+        StatsSnapshot {
+            accepted: self.accepted.load(Ordering::Relaxed),
+            open: self.open.load(Ordering::Relaxed),
+            requests: self.requests.load(Ordering::Relaxed),
+            turned_away: self.turned_away.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Counts a connection as open until the guard is dropped.
+    fn open(self: &Arc<Self>) -> OpenConnection {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+        self.open.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(self.clone())
+    }
+}
+
+struct OpenConnection(Arc<ConnectionStats>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// What happens to new connections while [`Server::max_connections`] are open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhenFull {
+    /// They aren't accepted until one closes, leaving them in the listen backlog.
+    Wait,
+    /// They're accepted and answered with a 503 right away. Over TLS, where that would take a
+    /// handshake, they're just closed.
+    Reject,
+}
+
+struct Shutdown {
+    signal: BoxFuture<'static, ()>,
+    drain_timeout: Duration,
+}
+
+/// What the connections of a running server share. It goes away with the last of them, which
+/// is how a shutdown knows they're all done.
+struct Running {
+    handler: Arc<dyn Handler>,
+    error_handler: ErrorHandler,
+    proxy_protocol: bool,
+    /// One permit per open connection, practically unlimited without a maximum.
+    slots: Arc<Semaphore>,
+    when_full: WhenFull,
+    /// The response for connections turned away, rendered up front.
+    busy: Vec<u8>,
+    turning_away: Arc<Semaphore>,
+    per_ip: Option<Arc<PerIp>>,
+    ip_rules: Option<watch::Receiver<Arc<IpRules>>>,
+    limits: watch::Receiver<ConnectionLimits>,
+    tcp_nodelay: bool,
+    tcp_keepalive: Option<TcpKeepalive>,
+    stats: Arc<ConnectionStats>,
+    buffers: Arc<BufferPool>,
+    access_log: Option<Arc<AccessLog>>,
+    request_ids: Option<Arc<[Cidr]>>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<Arc<Metrics>>,
+    #[cfg(feature = "metrics")]
+    statsd: Option<Arc<StatsD>>,
+    error_rate: Option<Arc<ErrorRate>>,
+    slow_requests: Option<Duration>,
+    trace_wire: Option<WireTrace>,
+    hooks: Hooks,
+    draining: watch::Receiver<bool>,
+    _alive: mpsc::Sender<()>,
+}
+
+impl Running {
+    /// Whether requests are counted anywhere, which takes their method, route and size.
+    #[cfg(feature = "metrics")]
+    fn counts_requests(&self) -> bool {
+        self.metrics.is_some() || self.statsd.is_some()
+    }
+
+    /// Whether requests are counted anywhere, which they can't be in a build without metrics.
+    #[cfg(not(feature = "metrics"))]
+    fn counts_requests(&self) -> bool {
+        false
+    }
+
+    /// Applies the socket options to a TCP connection just accepted.
+    fn configure(&self, stream: &TcpStream) {
+        let socket = SockRef::from(stream);
+        let configured =
+            socket
+                .set_nodelay(self.tcp_nodelay)
+                .and_then(|()| match &self.tcp_keepalive {
+                    Some(keepalive) => socket.set_tcp_keepalive(keepalive),
+                    None => Ok(()),
+                });
+        if let Err(e) = configured {
+            warn!("Error setting socket options: {e}");
+        }
+    }
+
+    /// A slot for a connection just accepted: the one waited for, or a free one if there is.
+    fn admit(&self, waited: Option<OwnedSemaphorePermit>) -> Option<OwnedSemaphorePermit> {
+        waited.or_else(|| self.slots.clone().try_acquire_owned().ok())
+    }
+
+    /// Answers a connection there's no slot for with a 503, unless `answer` is off or too many
+    /// are being answered already.
+    fn turn_away<S>(self: &Arc<Self>, stream: S, remote_addr: Option<SocketAddr>, answer: bool)
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        self.stats.turned_away.fetch_add(1, Ordering::Relaxed);
+        match remote_addr {
+            Some(addr) => warn!("Too many connections, turning away {addr}"),
+            None => warn!("Too many connections, turning one away"),
+        }
+        let Some(slot) = answer
+            .then(|| self.turning_away.clone().try_acquire_owned().ok())
+            .flatten()
+        else {
+            return;
+        };
+        let server = self.clone();
+        tokio::spawn(async move {
+            let _slot = slot;
+            send_busy(stream, &server.busy).await;
+        });
+    }
+}
+
+/// Sends the 503 and reads whatever the client still sends until it hangs up: closing with
+/// unread data would reset the connection, which can lose the response.
+async fn send_busy(mut stream: impl AsyncRead + AsyncWrite + Unpin, busy: &[u8]) {
+    if stream.write_all(busy).await.is_ok() && stream.shutdown().await.is_ok() {
+        let mut sink = tokio::io::sink();
+        let drain = tokio::io::copy(&mut stream, &mut sink);
+        let _ = rt::timeout(Duration::from_secs(1), drain).await;
+    }
+}
+
+/// How many connections each client address has open, for [`Server::max_connections_per_ip`].
+struct PerIp {
+    limit: usize,
+    open: std::sync::Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl PerIp {
+    fn admit(self: &Arc<Self>, ip: IpAddr) -> Option<IpSlot> {
+        let mut open = self.open.lock().unwrap_or_else(|e| e.into_inner());
+        let count = open.entry(ip).or_default();
+        if *count >= self.limit {
+            return None;
+        }
+        *count += 1;
+        Some(IpSlot {
+            per_ip: self.clone(),
+            ip,
+        })
+    }
+}
+
+/// Counts towards its address's connections until dropped.
+struct IpSlot {
+    per_ip: Arc<PerIp>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.per_ip.open.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+impl Server {
+    /// Serves every request with `handler`, usually an `Arc<Router>`.
+    pub fn new(handler: impl Handler) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            error_handler: Arc::new(HttpError::to_response),
+            proxy_protocol: false,
+            max_connections: None,
+            max_connections_per_ip: None,
+            ip_rules: None,
+            limits: ConnectionLimits::default(),
+            live_limits: None,
+            tcp_nodelay: false,
+            tcp_keepalive: None,
+            stats: Arc::default(),
+            buffers: Arc::default(),
+            access_log: None,
+            request_ids: None,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+            #[cfg(feature = "metrics")]
+            statsd: None,
+            error_rate: None,
+            slow_requests: None,
+            trace_wire: None,
+            hooks: Hooks::default(),
+            shutdown: None,
+        }
+    }
+
+    /// Replaces the default plain-text rendering of [`HttpError`]s.
+    pub fn error_handler<F>(mut self, handler: F) -> Self
+    where
+        F: Fn(&HttpError) -> Response + Send + Sync + 'static,
+    {
+        self.error_handler = Arc::new(handler);
+        self
+    }
+
+    /// Expects every connection to start with a PROXY protocol header, as sent by a load
+    /// balancer in front of the server, and attributes requests to the address in it.
+    pub fn proxy_protocol(mut self, enabled: bool) -> Self {
+        self.proxy_protocol = enabled;
+        self
+    }
+
+    /// Caps how many connections are open at once, across all listeners, so a flood of them
+    /// can't run the process out of memory or file descriptors.
+    pub fn max_connections(mut self, limit: usize, when_full: WhenFull) -> Self {
+        self.max_connections = Some((limit, when_full));
+        self
+    }
+
+    /// Caps the connections open at once from one client address, so a single client can't
+    /// take up all of [`max_connections`](Self::max_connections). Further ones are answered
+    /// like [`WhenFull::Reject`] says, whatever the global setting. Behind the PROXY protocol,
+    /// the address is the one the header gives.
+    pub fn max_connections_per_ip(mut self, limit: usize) -> Self {
+        self.max_connections_per_ip = Some(limit);
+        self
+    }
+
+    /// Closes connections from peers `rules` doesn't [admit](IpRules::admits) as soon as
+    /// they're accepted, following the rules as they change. Behind the PROXY protocol, the
+    /// address is the one the header gives.
+    pub fn ip_rules(mut self, rules: watch::Receiver<Arc<IpRules>>) -> Self {
+        self.ip_rules = Some(rules);
+        self
+    }
+
+    /// Gives clients `timeout`, from the end of the TLS handshake if there is one, to send a
+    /// request's line and headers, so ones trickling them in a byte at a time don't hold
+    /// connections forever. A client that sent part of its head by then gets a 408; one that
+    /// sent nothing is just disconnected. On a connection kept alive, the time counts from the
+    /// first byte of the next request.
+    pub fn head_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.head_timeout = Some(timeout);
+        self
+    }
+
+    /// How long a connection is kept open after a response, waiting for the client's next
+    /// request; 5 seconds unless set. `Duration::ZERO` closes every connection after one
+    /// response.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.idle_timeout = timeout;
+        self
+    }
+
+    /// Closes connections after `max` requests, so clients reconnect (and get spread over the
+    /// servers behind a load balancer) now and then. Clients learn how many are left from the
+    /// `Keep-Alive` header.
+    pub fn max_requests_per_connection(mut self, max: usize) -> Self {
+        self.limits.max_requests = Some(max);
+        self
+    }
+
+    /// Drops connections whose client hasn't taken any of the response for `timeout`. Without
+    /// it, one that stops reading halfway through a large download keeps the connection, and
+    /// the file it's sent from, open for good.
+    pub fn write_timeout(mut self, timeout: Duration) -> Self {
+        self.limits.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Drops connections whose client sends a request body, or takes a response, slower than
+    /// `rate`, counting only the time spent waiting on it. The write timeout alone lets a
+    /// client that takes a byte now and then keep a connection for as long as it likes.
+    pub fn min_rate(mut self, rate: MinRate) -> Self {
+        self.limits.min_rate = Some(rate);
+        self
+    }
+
+    /// Follows `limits` instead of the timeouts and request limit set here. Connections pick
+    /// up changes when they start on their next request, except for the write timeout and
+    /// minimum rate of responses, which are fixed when they open.
+    pub fn connection_limits(mut self, limits: watch::Receiver<ConnectionLimits>) -> Self {
+        self.live_limits = Some(limits);
+        self
+    }
+
+    /// Sets `TCP_NODELAY` on accepted TCP connections, sending small responses right away
+    /// instead of letting Nagle's algorithm hold them back for more data.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.tcp_nodelay = nodelay;
+        self
+    }
+
+    /// Turns on TCP keepalive for accepted TCP connections: after `idle` without traffic, the
+    /// kernel probes the peer every `interval`, and drops the connection if it's gone. That
+    /// catches clients that vanished without closing, like a laptop taken off the network.
+    pub fn tcp_keepalive(mut self, idle: Duration, interval: Duration) -> Self {
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        // Elsewhere, the system-wide interval applies.
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            windows
+        ))]
+        let keepalive = keepalive.with_interval(interval);
+        #[cfg(not(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "netbsd",
+            windows
+        )))]
+        let _ = interval;
+        self.tcp_keepalive = Some(keepalive);
+        self
+    }
+
+    /// Counts connections and requests into `stats` instead of counts of this server's own.
+    pub fn stats(mut self, stats: Arc<ConnectionStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Takes connections' read and write buffers from `buffers`, which can be shared with
+    /// other servers, instead of a pool of its own with the default sizes.
+    pub fn buffers(mut self, buffers: Arc<BufferPool>) -> Self {
+        self.buffers = buffers;
+        self
+    }
+
+    /// Writes a line to `log` for every request answered.
+    pub fn access_log(mut self, log: Arc<AccessLog>) -> Self {
+        self.access_log = Some(log);
+        self
+    }
+
+    /// Gives every request a [`RequestId`], adopting the `X-Request-Id` of those from peers
+    /// within `trusted`, and sends it back in the response's `X-Request-Id`. Error responses
+    /// get it in their body as well, when they're rendered from the [`HttpError`]'s headers.
+    pub fn request_ids(mut self, trusted: impl IntoIterator<Item = Cidr>) -> Self {
+        self.request_ids = Some(trusted.into_iter().collect());
+        self
+    }
+
+    /// Counts every request answered into `metrics`, by the route it matched if the handler is
+    /// a [`Router`].
+    #[cfg(feature = "metrics")]
+    pub fn metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Sends metrics for every request answered to `statsd`.
+    #[cfg(feature = "metrics")]
+    pub fn statsd(mut self, statsd: Arc<StatsD>) -> Self {
+        self.statsd = Some(statsd);
+        self
+    }
+
+    /// Counts every response's status into `error_rate`, which alerts when too many are 5xx.
+    pub fn error_rate(mut self, error_rate: Arc<ErrorRate>) -> Self {
+        self.error_rate = Some(error_rate);
+        self
+    }
+
+    /// Logs a warning for every request that takes `threshold` or longer from its first byte
+    /// to the last of its response, or to being cut short, with how long reading its head,
+    /// running the handler and writing the response each took.
+    pub fn slow_requests(mut self, threshold: Duration) -> Self {
+        self.slow_requests = Some(threshold);
+        self
+    }
+
+    /// Calls `hooks` as connections open and close and requests start and finish.
+    pub fn hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = hooks;
+        self
+    }
+
+    /// Logs a hexdump of what every connection reads and writes, as `trace` has it.
+    pub fn trace_wire(mut self, trace: WireTrace) -> Self {
+        self.trace_wire = Some(trace);
+        self
+    }
+
+    /// Stops accepting connections once `signal` resolves. Idle ones are closed, and those busy
+    /// get to finish their request (and are told with `Connection: close` that it's their last)
+    /// for up to `drain_timeout`, after which the serve methods return.
+    pub fn graceful_shutdown(
+        mut self,
+        signal: impl Future<Output = ()> + Send + 'static,
+        drain_timeout: Duration,
+    ) -> Self {
+        self.shutdown = Some(Shutdown {
+            signal: Box::pin(signal),
+            drain_timeout,
+        });
+        self
+    }
+
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
+        self.serve_all([listener]).await
+    }
+
+    /// Accepts connections on every listener at once, all of them served the same way.
+    pub async fn serve_all<L>(
+        mut self,
+        listeners: impl IntoIterator<Item = L>,
+    ) -> anyhow::Result<()>
+    where
+        L: Into<Listener>,
+    {
+        let (draining_tx, draining) = watch::channel(false);
+        let (alive, mut finished) = mpsc::channel(1);
+        let shutdown = self.shutdown.take();
+        let server = self.start(draining, alive).await?;
+        // Each listener gets a task of its own, so they accept in parallel on different threads.
+        let mut accept_loops = JoinSet::new();
+        for listener in listeners {
+            accept_loops.spawn(accept_loop(listener.into(), server.clone()));
+        }
+        drop(server);
+
+        let Some(Shutdown {
+            signal,
+            drain_timeout,
+        }) = shutdown
+        else {
+            accept_loops.join_all().await;
+            return Ok(());
+        };
+        // Dropping the accept loops closes the listeners, so nothing new comes in.
+        tokio::select! {
+            _ = accept_loops.join_all() => return Ok(()),
+            () = signal => {}
+        }
+        info!("Shutting down, waiting for open connections to finish");
+        draining_tx.send_replace(true);
+        if rt::timeout(drain_timeout, finished.recv()).await.is_err() {
+            warn!("Connections still open after {drain_timeout:?}, closing them");
+        }
+        Ok(())
+    }
+
+    /// Serves one connection that's already open, over any transport, until the client is
+    /// done with it. A graceful shutdown doesn't apply.
+    pub async fn serve_io<S>(self, stream: S) -> anyhow::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        let (_draining, draining) = watch::channel(false);
+        let (alive, _finished) = mpsc::channel(1);
+        let server = self.start(draining, alive).await?;
+        let info = ConnectionInfo {
+            remote_addr: None,
+            balancer: None,
+            scheme: Scheme::Http,
+            #[cfg(feature = "tls")]
+            client_cert: None,
+            tls: None,
+        };
+        let _open = server.stats.open();
+        serve_connection(stream, info, server)
+            .instrument(connection_span(None))
+            .await;
+        Ok(())
+    }
+
+    /// Serves connections from a std listener until accepting fails, blocking the calling
+    /// thread, for programs that don't run an async runtime of their own. Every connection gets
+    /// an OS thread, which drives it on a single-threaded runtime made just for it: handlers
+    /// still run as futures, and anything they spawn only lives as long as their connection.
+    ///
+    /// A graceful shutdown doesn't apply; TLS and PROXY headers are left out too.
+    #[cfg(feature = "blocking")]
+    pub fn serve_blocking(mut self, listener: std::net::TcpListener) -> anyhow::Result<()> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .context("starting a runtime")?;
+        let (_draining, draining) = watch::channel(false);
+        let (alive, _finished) = mpsc::channel(1);
+        self.proxy_protocol = false;
+        let server = runtime.block_on(self.start(draining, alive))?;
+        loop {
+            let waited = match server.when_full {
+                WhenFull::Wait => runtime.block_on(server.slots.clone().acquire_owned()).ok(),
+                WhenFull::Reject => None,
+            };
+            let (stream, addr) = listener.accept().context("accepting a connection")?;
+            let server = server.clone();
+            let spawned = std::thread::Builder::new()
+                .name("connection".to_owned())
+                .spawn(move || serve_thread(stream, canonical(addr), waited, server));
+            if let Err(e) = spawned {
+                warn!("Error starting a connection thread: {e}");
+            }
+        }
+    }
+
+    /// What the connections will share, once the server starts.
+    async fn start(
+        self,
+        draining: watch::Receiver<bool>,
+        alive: mpsc::Sender<()>,
+    ) -> anyhow::Result<Arc<Running>> {
+        let (limit, when_full) = self
+            .max_connections
+            .unwrap_or((Semaphore::MAX_PERMITS, WhenFull::Reject));
+        let mut busy = Vec::new();
+        (self.error_handler)(&HttpError::service_unavailable())
+            .with_header(CONNECTION, "close")
+            .write_to_stream(&mut busy, false)
+            .await?;
+        Ok(Arc::new(Running {
+            handler: self.handler,
+            error_handler: self.error_handler,
+            proxy_protocol: self.proxy_protocol,
+            slots: Arc::new(Semaphore::new(limit)),
+            when_full,
+            busy,
+            turning_away: Arc::new(Semaphore::new(TURN_AWAY_SLOTS)),
+            limits: self
+                .live_limits
+                .unwrap_or_else(|| watch::channel(self.limits).1),
+            tcp_nodelay: self.tcp_nodelay,
+            tcp_keepalive: self.tcp_keepalive,
+            stats: self.stats,
+            buffers: self.buffers,
+            access_log: self.access_log,
+            request_ids: self.request_ids,
+            #[cfg(feature = "metrics")]
+            metrics: self.metrics,
+            #[cfg(feature = "metrics")]
+            statsd: self.statsd,
+            error_rate: self.error_rate,
+            slow_requests: self.slow_requests,
+            trace_wire: self.trace_wire,
+            hooks: self.hooks,
+            ip_rules: self.ip_rules,
+            per_ip: self.max_connections_per_ip.map(|limit| {
+                Arc::new(PerIp {
+                    limit,
+                    open: Default::default(),
+                })
+            }),
+            draining,
+            _alive: alive,
+        }))
+    }
+}
+
+async fn accept_loop(listener: Listener, server: Arc<Running>) {
+    let mut backoff = MIN_ACCEPT_BACKOFF;
+    loop {
+        let waited = match server.when_full {
+            WhenFull::Wait => server.slots.clone().acquire_owned().await.ok(),
+            WhenFull::Reject => None,
+        };
+        let accepted = match &listener {
+            Listener::Tcp(listener) => listener.accept().await.map(|(stream, addr)| {
+                server.configure(&stream);
+                let addr = Some(canonical(addr));
+                match server.admit(waited) {
+                    Some(slot) => spawn_connection(stream, addr, None, slot, server.clone()),
+                    None => server.turn_away(stream, addr, true),
+                }
+            }),
+            #[cfg(feature = "tls")]
+            Listener::Tls(listener, acceptor) => listener.accept().await.map(|(stream, addr)| {
+                server.configure(&stream);
+                let addr = Some(canonical(addr));
+                match server.admit(waited) {
+                    Some(slot) => {
+                        let acceptor = Some(acceptor.clone());
+                        spawn_connection(stream, addr, acceptor, slot, server.clone())
+                    }
+                    None => server.turn_away(stream, addr, false),
+                }
+            }),
+            #[cfg(unix)]
+            Listener::Unix(listener) => {
+                listener
+                    .accept()
+                    .await
+                    .map(|(stream, _)| match server.admit(waited) {
+                        Some(slot) => spawn_connection(stream, None, None, slot, server.clone()),
+                        None => server.turn_away(stream, None, true),
+                    })
+            }
+        };
+        let Err(e) = accepted else {
+            backoff = MIN_ACCEPT_BACKOFF;
+            continue;
+        };
+        // The client gave up while waiting in the backlog; nothing's wrong on this end.
+        if matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::Interrupted
+        ) {
+            continue;
+        }
+        // Retrying straight away would just fail again, keeping a core busy until whatever ran
+        // out frees up. Meanwhile, new connections wait in the backlog.
+        match is_out_of_descriptors(&e) {
+            true => warn!("Out of file descriptors, pausing accepts for {backoff:?}"),
+            false => warn!(
+                "error occurred during setting up the connection: {e}, retrying in {backoff:?}"
+            ),
+        }
+        rt::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_ACCEPT_BACKOFF);
+    }
+}
+
+/// Serves a connection accepted by [`Server::serve_blocking`] on the current thread.
+#[cfg(feature = "blocking")]
+fn serve_thread(
+    stream: std::net::TcpStream,
+    addr: SocketAddr,
+    waited: Option<OwnedSemaphorePermit>,
+    server: Arc<Running>,
+) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build();
+    let runtime = match runtime {
+        Ok(runtime) => runtime,
+        Err(e) => return warn!("Error starting a runtime for {addr}: {e}"),
+    };
+    runtime.block_on(async move {
+        let stream = match stream
+            .set_nonblocking(true)
+            .and_then(|()| TcpStream::from_std(stream))
+        {
+            Ok(stream) => stream,
+            Err(e) => return warn!("Error setting up the connection from {addr}: {e}"),
+        };
+        server.configure(&stream);
+        match server.admit(waited) {
+            Some(slot) => {
+                connection(stream, Some(addr), None, slot, server)
+                    .instrument(connection_span(Some(addr)))
+                    .await
+            }
+            // Answered here rather than by `turn_away`, whose task wouldn't outlive the runtime.
+            None => {
+                server.stats.turned_away.fetch_add(1, Ordering::Relaxed);
+                warn!("Too many connections, turning away {addr}");
+                send_busy(stream, &server.busy).await;
+            }
+        }
+    });
+}
+
+fn is_out_of_descriptors(e: &io::Error) -> bool {
+    #[cfg(unix)]
+    return matches!(e.raw_os_error(), Some(libc::EMFILE | libc::ENFILE));
+    #[cfg(not(unix))]
+    return false;
+}
+
+/// Dual-stack sockets see IPv4 clients as `::ffff:a.b.c.d`; report them as plain IPv4.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+pub async fn serve(listener: TcpListener, router: Arc<Router>) -> anyhow::Result<()> {
+    Server::new(router).serve(listener).await
+}
+
+/// What the server knows about a connection before reading requests off it.
+#[derive(Clone)]
+struct ConnectionInfo {
+    remote_addr: Option<SocketAddr>,
+    /// What sent the PROXY header naming `remote_addr`.
+    balancer: Option<SocketAddr>,
+    scheme: Scheme,
+    #[cfg(feature = "tls")]
+    client_cert: Option<ClientCertificate>,
+    tls: Option<TlsInfo>,
+}
+
+/// Stands in for the acceptor in builds without TLS, where a connection never has one.
+#[cfg(not(feature = "tls"))]
+enum TlsAcceptor {}
+
+fn spawn_connection<S>(
+    stream: S,
+    remote_addr: Option<SocketAddr>,
+    tls: Option<TlsAcceptor>,
+    slot: OwnedSemaphorePermit,
+    server: Arc<Running>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    let span = connection_span(remote_addr);
+    tokio::spawn(connection(stream, remote_addr, tls, slot, server).instrument(span));
+}
+
+/// The span everything logged about a connection goes in, requests included.
+fn connection_span(remote_addr: Option<SocketAddr>) -> Span {
+    info_span!("connection", remote = remote_addr.map(field::display))
+}
+
+async fn connection<S>(
+    stream: S,
+    remote_addr: Option<SocketAddr>,
+    tls: Option<TlsAcceptor>,
+    slot: OwnedSemaphorePermit,
+    server: Arc<Running>,
+) where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    // Frees the slot once the connection is done with.
+    let _slot = slot;
+    let mut info = ConnectionInfo {
+        remote_addr,
+        balancer: None,
+        scheme: Scheme::Http,
+        #[cfg(feature = "tls")]
+        client_cert: None,
+        tls: None,
+    };
+    // The PROXY header comes before anything else, the TLS handshake included. Whatever got
+    // buffered past it is kept for the connection to read first.
+    let stream = if server.proxy_protocol {
+        let mut buffered = PooledReader::new(stream, server.buffers.get());
+        match proxy_protocol::read_header(&mut buffered).await {
+            // Without an address (balancer health checks), the balancer is the client.
+            Ok(client) => {
+                if let Some(client) = client {
+                    Span::current().record("remote", field::display(client));
+                    info.balancer = info.remote_addr;
+                }
+                info.remote_addr = client.or(info.remote_addr);
+            }
+            Err(e) => return warn!("Dropping connection: {e:#}"),
+        }
+        buffered.into_unread()
+    } else {
+        Unread::new(stream)
+    };
+    if let (Some(rules), Some(addr)) = (&server.ip_rules, info.remote_addr) {
+        if !rules.borrow().admits(addr.ip()) {
+            server.stats.turned_away.fetch_add(1, Ordering::Relaxed);
+            return debug!("Closing connection from denied address {}", addr.ip());
+        }
+    }
+    let _ip_slot = match (&server.per_ip, info.remote_addr) {
+        (Some(per_ip), Some(addr)) => match per_ip.admit(addr.ip()) {
+            Some(slot) => Some(slot),
+            None => {
+                server.stats.turned_away.fetch_add(1, Ordering::Relaxed);
+                warn!("Too many connections from {}, turning away", addr.ip());
+                if tls.is_none() {
+                    send_busy(stream, &server.busy).await;
+                }
+                return;
+            }
+        },
+        _ => None,
+    };
+    let _open = server.stats.open();
+
+    let Some(acceptor) = tls else {
+        return serve_connection(stream, info, server).await;
+    };
+    #[cfg(not(feature = "tls"))]
+    match acceptor {}
+    #[cfg(feature = "tls")]
+    match rt::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => {
+            // TLS-ALPN-01 validation is over once the handshake is.
+            #[cfg(feature = "acme")]
+            if stream.get_ref().1.alpn_protocol() == Some(crate::acme::ACME_TLS_ALPN) {
+                return;
+            }
+            info.scheme = Scheme::Https;
+            info.client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(ClientCertificate::from_chain);
+            if let Some(cert) = &info.client_cert {
+                debug!("TLS client certificate: {}", cert.subject());
+            }
+            info.tls = Some(tls_info(stream.get_ref().1, info.client_cert.as_ref()));
+            serve_connection(stream, info, server).await
+        }
+        Ok(Err(e)) => warn!("TLS handshake failed: {e}"),
+        Err(_) => warn!("TLS handshake timed out"),
+    }
+}
+
+/// What the handshake of `session` settled on, for the hooks.
+#[cfg(feature = "tls")]
+fn tls_info(
+    session: &tokio_rustls::rustls::ServerConnection,
+    client_cert: Option<&ClientCertificate>,
+) -> TlsInfo {
+    TlsInfo {
+        version: session.protocol_version().map(|v| format!("{v:?}")),
+        cipher: session
+            .negotiated_cipher_suite()
+            .map(|suite| format!("{:?}", suite.suite())),
+        alpn: session
+            .alpn_protocol()
+            .map(|alpn| String::from_utf8_lossy(alpn).into_owned()),
+        server_name: session.server_name().map(str::to_owned),
+        client_subject: client_cert.map(|cert| cert.subject().to_owned()),
+    }
+}
+
+async fn serve_connection<S>(stream: S, info: ConnectionInfo, server: Arc<Running>)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+{
+    match info.remote_addr {
+        Some(addr) => debug!("accepted new connection from {addr}"),
+        None => debug!("accepted new connection"),
+    }
+
+    let stream = Traced::new(stream, server.trace_wire);
+    let (reader, writer) = tokio::io::split(stream);
+    let reader = PooledReader::new(reader, server.buffers.get());
+    let bytes_read = Arc::new(AtomicU64::new(0));
+    let reader: BoxReader = match server.counts_requests() {
+        true => Box::new(CountingReader::new(reader, bytes_read.clone())),
+        false => Box::new(reader),
+    };
+    let reader = Arc::new(Mutex::new(BodyReader::new(reader)));
+    let (write_timeout, min_rate) = {
+        let limits = server.limits.borrow();
+        (limits.write_timeout, limits.min_rate)
+    };
+    let writer = WriteTimeout::new(writer, write_timeout, min_rate);
+    let too_slow = Arc::new(AtomicBool::new(false));
+    let mut writer = PooledWriter::new(writer, server.buffers.get());
+    let mut tracked = server
+        .hooks
+        .open(info.remote_addr, info.scheme, info.tls.clone());
+    let mut served = 0;
+    let mut kept_alive = false;
+    loop {
+        let limits = *server.limits.borrow();
+        #[cfg(feature = "metrics")]
+        let read_before = bytes_read.load(Ordering::Relaxed);
+        let head = read_head(
+            reader.clone().lock_owned().await,
+            &info,
+            &server,
+            &limits,
+            kept_alive,
+            &too_slow,
+        );
+        let Some((mut request, arrived)) = head.await else {
+            break;
+        };
+        served += 1;
+        server.stats.requests.fetch_add(1, Ordering::Relaxed);
+        if let Some(tracked) = &mut tracked {
+            tracked.requests = served;
+        }
+        let start = Instant::now();
+        let request_id = server
+            .request_ids
+            .as_ref()
+            .map(|trusted| match &mut request {
+                Ok(req) => RequestId::assign(req, trusted),
+                Err(_) => RequestId::generate(),
+            });
+        let trace = request.as_mut().ok().map(TraceContext::assign);
+        let span = request_span(
+            request.as_ref().ok(),
+            info.remote_addr,
+            request_id.as_ref(),
+            trace.as_ref(),
+        );
+        let overridden = OverriddenMethod::default();
+        if let Ok(req) = &mut request {
+            req.extensions.insert(IdentitySlot::default());
+            req.extensions.insert(overridden.clone());
+        }
+        let entry = server.access_log.as_ref().map(|_| match &request {
+            Ok(req) => access_log::Entry::new(req),
+            Err(_) => access_log::Entry {
+                request_id: request_id.as_ref().map(|id| id.0.clone()),
+                ..access_log::Entry::unparsed(info.remote_addr)
+            },
+        });
+        let matched_route = MatchedRoute::default();
+        if let Ok(req) = &mut request {
+            req.extensions.insert(matched_route.clone());
+        }
+        #[cfg(feature = "metrics")]
+        let method = match &request {
+            _ if !server.counts_requests() => String::new(),
+            Ok(req) => req.method.to_string(),
+            Err(_) => "-".to_owned(),
+        };
+        let described = match &request {
+            _ if !tracked.as_ref().is_some_and(|t| t.wants_finished()) => None,
+            Ok(req) => Some((req.method.to_string(), req.path.clone())),
+            Err(_) => Some(("-".to_owned(), String::new())),
+        };
+        #[cfg(feature = "metrics")]
+        let _in_flight = server.metrics.as_ref().map(Metrics::start);
+        let mut panicked = false;
+        let mut wants_keep_alive = false;
+        let mut http_1_0 = false;
+        let (result, chunked_allowed, upgrade) = match request {
+            Ok(mut req) => {
+                let chunked_allowed = req.version == "HTTP/1.1";
+                let connection = req.headers.typed_get::<Connection>().ok().flatten();
+                http_1_0 = req.version == "HTTP/1.0";
+                wants_keep_alive = match &connection {
+                    _ if http_1_0 => connection.as_ref().is_some_and(Connection::is_keep_alive),
+                    Some(connection) => chunked_allowed && !connection.is_close(),
+                    None => chunked_allowed,
+                };
+                let upgrade = PendingUpgrade::new(&mut req);
+                if let Some(tracked) = &tracked {
+                    tracked.started(&req);
+                }
+                let result = AssertUnwindSafe(server.handler.call(req))
+                    .catch_unwind()
+                    .instrument(span.clone())
+                    .await
+                    .unwrap_or_else(|panic| {
+                        error!(parent: &span, "Handler panicked: {}", panic_message(&*panic));
+                        panicked = true;
+                        Err(anyhow::anyhow!("handler panicked").into())
+                    });
+                (result, chunked_allowed, Some(upgrade))
+            }
+            Err(e) => (Err(e), false, None),
+        };
+
+        let mut response = result.unwrap_or_else(|mut e| {
+            if let Some(id) = &request_id {
+                e.headers.insert(X_REQUEST_ID, &id.0);
+            }
+            (server.error_handler)(&e)
+        });
+        if let Some(id) = &request_id {
+            response.set_header(X_REQUEST_ID, &id.0);
+        }
+        let timing = Timing {
+            arrived,
+            read: start,
+            handled: Instant::now(),
+        };
+        let status = response.status.0;
+        let route = matched_route.get();
+        // What was described before the handler ran has the method as it was sent.
+        #[cfg(feature = "metrics")]
+        let method = match overridden.get() {
+            Some(overridden) if server.counts_requests() => overridden.to_string(),
+            _ => method,
+        };
+        let described = described.map(|(method, path)| match overridden.get() {
+            Some(overridden) => (overridden.to_string(), path),
+            None => (method, path),
+        });
+        span.record("status", status);
+        span.record("route", route.as_deref());
+        let report = |sent, completed| {
+            if let (Some(tracked), Some((method, path))) = (&tracked, &described) {
+                tracked.finished(&Finished {
+                    method: method.clone(),
+                    path: path.clone(),
+                    status,
+                    bytes_sent: sent,
+                    duration: start.elapsed(),
+                    completed,
+                });
+            }
+        };
+        let done = |sent| {
+            finished(&span, start, sent);
+            report(sent, true);
+            timing.warn_if_slow(server.slow_requests, &span, "finished");
+            if let (Some(log), Some(entry)) = (&server.access_log, &entry) {
+                log.record(entry, status, sent, start.elapsed());
+            }
+            if let Some(error_rate) = &server.error_rate {
+                error_rate.record(status);
+            }
+            #[cfg(feature = "metrics")]
+            if server.counts_requests() {
+                let sample = metrics::Sample {
+                    route: route.as_deref(),
+                    method: &method,
+                    status,
+                    bytes_in: bytes_read.load(Ordering::Relaxed) - read_before,
+                    bytes_out: sent,
+                    duration: start.elapsed(),
+                };
+                if let Some(metrics) = &server.metrics {
+                    metrics.record(&sample);
+                }
+                if let Some(statsd) = &server.statsd {
+                    statsd.record(&sample);
+                }
+            }
+        };
+        if too_slow.load(Ordering::Relaxed) {
+            report(0, false);
+            return match info.remote_addr {
+                Some(addr) => warn!(parent: &span, "{addr} sent its body too slowly, dropping it"),
+                None => warn!(parent: &span, "Client sent its body too slowly, dropping it"),
+            };
+        }
+        if let Some(upgrade) = upgrade.filter(|upgrade| upgrade.accepted_by(&response)) {
+            match response.write_upgrade_head(&mut writer).await {
+                Ok(()) => {
+                    done(0);
+                    upgrade.complete(reader.lock_owned().await, Box::new(writer));
+                }
+                Err(e) => {
+                    timing.warn_if_slow(server.slow_requests, &span, "cut short");
+                    report(0, false);
+                    warn!(parent: &span, "Error occurred while writing response: {e}")
+                }
+            }
+            return;
+        }
+
+        let handler_closes = response
+            .header(CONNECTION)
+            .is_some_and(|v| v.split(',').any(|o| o.trim().eq_ignore_ascii_case("close")));
+        let remaining = limits.max_requests.map(|max| max.saturating_sub(served));
+        let keep_alive = wants_keep_alive
+            && !panicked
+            && !handler_closes
+            && !limits.idle_timeout.is_zero()
+            && !*server.draining.borrow()
+            && remaining != Some(0)
+            && response.is_delimited(chunked_allowed);
+        if keep_alive {
+            if http_1_0 {
+                response.set_header(CONNECTION, "keep-alive");
+            }
+            let mut params = format!("timeout={}", limits.idle_timeout.as_secs());
+            if let Some(remaining) = remaining {
+                params.push_str(&format!(", max={remaining}"));
+            }
+            response.set_header(KEEP_ALIVE, &params);
+        } else {
+            response.set_header(CONNECTION, "close");
+        }
+
+        // Streamed bodies run handler code too, so a panic can still happen after the head has
+        // been sent. All that's left to do then is to drop the connection.
+        let written = AssertUnwindSafe(response.write_to_stream(&mut writer, chunked_allowed))
+            .catch_unwind()
+            .instrument(span.clone())
+            .await;
+        if !matches!(written, Ok(Ok(_))) {
+            timing.warn_if_slow(server.slow_requests, &span, "cut short");
+            report(0, false);
+        }
+        match written {
+            Ok(Ok(sent)) => done(sent),
+            Ok(Err(e)) if is_write_timeout(&e) => {
+                // Either it stopped reading, or it reads slower than the minimum rate.
+                let why = e.root_cause();
+                return match info.remote_addr {
+                    Some(addr) => {
+                        warn!(parent: &span, "{addr} fell behind on the response ({why}), dropping it")
+                    }
+                    None => {
+                        warn!(parent: &span, "Client fell behind on the response ({why}), dropping it")
+                    }
+                };
+            }
+            Ok(Err(e)) => return warn!(parent: &span, "Error occurred while writing response: {e}"),
+            Err(panic) => {
+                return error!(
+                    parent: &span,
+                    "Panicked while writing response: {}",
+                    panic_message(&*panic)
+                )
+            }
+        }
+        if !keep_alive || !skip_body(&reader, limits.idle_timeout).await {
+            break;
+        }
+        kept_alive = true;
+    }
+    // Over TLS, this is what sends close_notify so the client knows nothing got cut off.
+    let _ = writer.shutdown().await;
+}
+
+/// The span for one request on a connection, with the status, body size and time taken filled
+/// in by [`finished`]. A request whose head didn't parse has no method or path.
+///
+/// Handlers can fill in `file_path` with the file a request is for.
+fn request_span(
+    request: Option<&Request>,
+    client: Option<SocketAddr>,
+    id: Option<&RequestId>,
+    trace: Option<&TraceContext>,
+) -> Span {
+    info_span!(
+        "request",
+        id = id.map(|id| id.0.as_str()),
+        trace_id = trace.map(|trace| trace.trace_id.as_str()),
+        span_id = trace.map(|trace| trace.span_id.as_str()),
+        parent_span_id = trace.and_then(|trace| trace.parent_id.as_deref()),
+        tracestate = trace.and_then(|trace| trace.state.as_deref()),
+        method = request.map(|req| field::display(&req.method)),
+        path = request.map(|req| req.path.as_str()),
+        client_ip = client.map(|addr| field::display(addr.ip())),
+        route = field::Empty,
+        file_path = field::Empty,
+        status = field::Empty,
+        bytes = field::Empty,
+        duration_ms = field::Empty,
+    )
+}
+
+/// Records a response that went out in full, `sent` the bytes of body in it.
+fn finished(span: &Span, start: Instant, sent: u64) {
+    span.record("bytes", sent);
+    let micros = start.elapsed().as_micros() as f64;
+    span.record("duration_ms", micros / 1000.0);
+    info!(parent: span, "request finished");
+}
+
+/// When the parts of a request were done with.
+struct Timing {
+    /// The request's first byte.
+    arrived: Instant,
+    /// Its head, parsed.
+    read: Instant,
+    /// The response it got, before any of it was written.
+    handled: Instant,
+}
+
+impl Timing {
+    fn warn_if_slow(&self, threshold: Option<Duration>, span: &Span, outcome: &str) {
+        let total = self.arrived.elapsed();
+        if threshold.is_none_or(|threshold| total < threshold) {
+            return;
+        }
+        let millis = |d: Duration| (d.as_secs_f64() * 1e6).round() / 1e3;
+        warn!(
+            parent: span,
+            head_ms = millis(self.read - self.arrived),
+            handler_ms = millis(self.handled - self.read),
+            write_ms = millis(self.handled.elapsed()),
+            "Slow request, {outcome} after {} ms",
+            millis(total),
+        );
+    }
+}
+
+/// Reads past whatever the handler left of the request body, so the next request can be
+/// parsed. `false` means the connection can't be reused: too much was left, it was slow to
+/// arrive, or something (a CGI script still running, say) is still reading it.
+async fn skip_body(reader: &Arc<Mutex<BodyReader>>, timeout: Duration) -> bool {
+    let Ok(mut reader) = reader.clone().try_lock_owned() else {
+        return false;
+    };
+    let mut rest = AsyncReadExt::take(&mut *reader, MAX_SKIPPED_BODY);
+    let mut sink = tokio::io::sink();
+    let skipped = rt::timeout(timeout, tokio::io::copy(&mut rest, &mut sink)).await;
+    matches!(skipped, Ok(Ok(_))) && reader.is_done()
+}
+
+/// A connection's read half, counting the bytes taken from it into `count`.
+struct CountingReader<R> {
+    inner: R,
+    count: Arc<AtomicU64>,
+}
+
+impl<R> CountingReader<R> {
+    fn new(inner: R, count: Arc<AtomicU64>) -> Self {
+        Self { inner, count }
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let read = buf.filled().len() - before;
+        self.count.fetch_add(read as u64, Ordering::Relaxed);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for CountingReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        self.count.fetch_add(amt as u64, Ordering::Relaxed);
+        Pin::new(&mut self.inner).consume(amt);
+    }
+}
+
+/// The write half of a connection, failing writes that can't make progress for `timeout`
+/// because the client isn't reading, or that it takes slower than its [`MinRate`].
+struct WriteTimeout<W> {
+    inner: W,
+    timeout: Option<Duration>,
+    stalled: Option<Sleep>,
+    min_rate: Option<RateCheck>,
+}
+
+impl<W: AsyncWrite + Unpin> WriteTimeout<W> {
+    fn new(inner: W, timeout: Option<Duration>, min_rate: Option<MinRate>) -> Self {
+        Self {
+            inner,
+            timeout,
+            stalled: None,
+            min_rate: min_rate.map(RateCheck::new),
+        }
+    }
+
+    /// Runs one poll of the inner writer, which wrote `written` of what it returns, starting
+    /// the clock if it can't go on and resetting it once it does.
+    fn poll_timed<T>(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        poll: impl FnOnce(Pin<&mut W>, &mut TaskContext<'_>) -> Poll<io::Result<T>>,
+        written: impl FnOnce(&T) -> usize,
+    ) -> Poll<io::Result<T>> {
+        if let Poll::Ready(result) = poll(Pin::new(&mut self.inner), cx) {
+            self.stalled = None;
+            if let (Some(check), Ok(value)) = (&mut self.min_rate, &result) {
+                check.moved(written(value));
+            }
+            return Poll::Ready(result);
+        }
+        if let Some(check) = &mut self.min_rate {
+            if let Poll::Ready(e) = check.poll_pending(cx) {
+                return Poll::Ready(Err(e));
+            }
+        }
+        let Some(timeout) = self.timeout else {
+            return Poll::Pending;
+        };
+        let stalled = self.stalled.get_or_insert_with(|| rt::sleep(timeout));
+        ready!(stalled.as_mut().poll(cx));
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("client took nothing for {timeout:?}"),
+        )))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for WriteTimeout<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_timed(cx, |inner, cx| inner.poll_write(cx, buf), |n| *n)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut()
+            .poll_timed(cx, |inner, cx| inner.poll_write_vectored(cx, bufs), |n| *n)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_timed(cx, |inner, cx| inner.poll_flush(cx), |()| 0)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.get_mut()
+            .poll_timed(cx, |inner, cx| inner.poll_shutdown(cx), |()| 0)
+    }
+}
+
+fn is_write_timeout(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|e| e.downcast_ref::<io::Error>())
+        .any(|e| e.kind() == io::ErrorKind::TimedOut)
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic")
+}
+
+/// Reads the next request's head within the [head timeout](Server::head_timeout), if there
+/// is one. On a connection `kept_alive` from an earlier request, the client first gets the
+/// [idle timeout](Server::idle_timeout) to start on it, and a shutdown cuts that wait short.
+///
+/// `None` means the client left or didn't start on a request in time, which isn't worth an
+/// answer: browsers open spare connections they may never use.
+async fn read_head(
+    mut reader: OwnedMutexGuard<BodyReader>,
+    info: &ConnectionInfo,
+    server: &Running,
+    limits: &ConnectionLimits,
+    kept_alive: bool,
+    too_slow: &Arc<AtomicBool>,
+) -> Option<(Result<Request, HttpError>, Instant)> {
+    let start = Instant::now();
+    let mut draining = server.draining.clone();
+    let first_byte = async {
+        tokio::select! {
+            filled = reader.reader.fill_buf() => filled.is_ok_and(|buf| !buf.is_empty()),
+            _ = draining.wait_for(|draining| *draining), if kept_alive => false,
+        }
+    };
+    let wait = match kept_alive {
+        true => Some(limits.idle_timeout),
+        false => limits.head_timeout,
+    };
+    let started = match wait {
+        Some(wait) => rt::timeout(wait, first_byte).await.unwrap_or(false),
+        None => first_byte.await,
+    };
+    if !started {
+        return None;
+    }
+
+    let arrived = Instant::now();
+    let body_rate = limits.min_rate.map(|rate| (rate, too_slow.clone()));
+    let Some(timeout) = limits.head_timeout else {
+        return Some((read_request(reader, info, body_rate).await, arrived));
+    };
+    let deadline = match kept_alive {
+        true => Instant::now() + timeout,
+        false => start + timeout,
+    };
+    let request = rt::timeout_at(deadline, read_request(reader, info, body_rate)).await;
+    let request = request.unwrap_or_else(|_| {
+        warn!("Request head not received within {timeout:?}");
+        Err(HttpError::new(
+            StatusCode::REQUEST_TIMEOUT,
+            anyhow::anyhow!("request head not received in time"),
+        ))
+    });
+    Some((request, arrived))
+}
+
+/// Reads a request's head, leaving its body to be read at `min_rate` or faster, if given,
+/// with the flag to set otherwise.
+async fn read_request(
+    mut reader: OwnedMutexGuard<BodyReader>,
+    info: &ConnectionInfo,
+    min_rate: Option<(MinRate, Arc<AtomicBool>)>,
+) -> Result<Request, HttpError> {
+    let mut stream = AsyncReadExt::take(&mut reader.reader, MAX_HEAD_BYTES);
+    let mut request_line = String::new();
+    let too_long = StatusCode::URI_TOO_LONG;
+    read_head_line(
+        &mut stream,
+        &mut request_line,
+        too_long,
+        "reading request line",
+    )
+    .await?;
+
+    let mut request_line_parts = request_line.trim().splitn(3, ' ');
+    let method = request_line_parts
+        .next()
+        .filter(|method| !method.is_empty())
+        .ok_or_else(|| HttpError::bad_request("no method found in header"))?;
+
+    let target = request_line_parts
+        .next()
+        .ok_or_else(|| HttpError::bad_request("no path found in header"))?;
+
+    let standard = request_line_parts
+        .next()
+        .ok_or_else(|| HttpError::bad_request("no standard found in header"))?;
+
+    debug!("Incoming request: {method} {target} [{standard}]");
+
+    let mut headers = HeaderMap::new();
+    let mut header_line = String::new();
+    loop {
+        header_line.clear();
+        let too_large = StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE;
+        read_head_line(
+            &mut stream,
+            &mut header_line,
+            too_large,
+            "reading header line",
+        )
+        .await?;
+
+        if header_line.trim().is_empty() {
+            break;
+        }
+
+        let (k, v) = header_line
+            .split_once(':')
+            .ok_or_else(|| HttpError::bad_request("invalid header format"))?;
+        headers.append(k.trim(), v.trim());
+    }
+
+    debug!("Got {} headers", headers.len());
+
+    reader.framing = body_framing(&headers)?;
+    let min_rate = min_rate.filter(|_| !reader.is_done());
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut extensions = Extensions::default();
+    if let Some(balancer) = info.balancer {
+        extensions.insert(proxy_protocol::Balancer(balancer));
+    }
+    #[cfg(feature = "tls")]
+    if let Some(cert) = &info.client_cert {
+        extensions.insert(cert.clone());
+    }
+
+    Ok(Request {
+        method: Method::from(method),
+        target: target.to_owned(),
+        path: path.to_owned(),
+        query: query.to_owned(),
+        version: standard.to_owned(),
+        scheme: info.scheme,
+        remote_addr: info.remote_addr,
+        headers,
+        params: Vec::new(),
+        extensions,
+        body: match min_rate {
+            Some((rate, too_slow)) => Body::from_connection(reader).min_rate(rate, too_slow),
+            None => Body::from_connection(reader),
+        },
+    })
+}
+
+/// Reads a line of the head into `line`, failing with `too_large` if the head's limit runs
+/// out before the line ends.
+async fn read_head_line<R: AsyncBufRead + Unpin>(
+    stream: &mut tokio::io::Take<R>,
+    line: &mut String,
+    too_large: StatusCode,
+    context: &'static str,
+) -> Result<(), HttpError> {
+    stream.read_line(line).await.context(context)?;
+    if stream.limit() == 0 && !line.ends_with('\n') {
+        return Err(HttpError::new(
+            too_large,
+            anyhow::anyhow!("request head larger than {MAX_HEAD_BYTES} bytes"),
+        ));
+    }
+    Ok(())
+}
+
+fn body_framing(headers: &HeaderMap) -> Result<Framing, HttpError> {
+    let transfer_encoding = headers.get_all(TRANSFER_ENCODING).collect::<Vec<_>>();
+    if !transfer_encoding.is_empty() {
+        // A message with both is how request smuggling starts, so refuse it outright.
+        if headers.contains(CONTENT_LENGTH) {
+            return Err(HttpError::bad_request(
+                "both Transfer-Encoding and Content-Length were provided",
+            ));
+        }
+        let last_coding = transfer_encoding
+            .iter()
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .next_back()
+            .unwrap_or_default();
+        if !last_coding.eq_ignore_ascii_case("chunked") {
+            return Err(HttpError::bad_request("unsupported transfer encoding"));
+        }
+        return Ok(Framing::Chunked(ChunkState::Size(Vec::new())));
+    }
+
+    match headers.typed_get::<ContentLength>()? {
+        Some(ContentLength(len)) => Ok(Framing::Length(len)),
+        None => Ok(Framing::Empty),
+    }
+}
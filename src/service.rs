@@ -0,0 +1,102 @@
+//! Glue between the router/middleware types and the `tower` ecosystem.
+
+use std::{
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use tower::{util::BoxCloneService, BoxError, Layer, Service, ServiceExt};
+
+use crate::{
+    error::HttpError,
+    handler::{BoxFuture, Handler},
+    middleware::{Middleware, Next},
+    request::Request,
+    response::Response,
+    router::Router,
+};
+
+impl Service<Request> for Arc<Router> {
+    type Response = Response;
+    type Error = HttpError;
+    type Future = BoxFuture<'static, Result<Response, HttpError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        Handler::call(self, req)
+    }
+}
+
+/// The innermost service a tower layer wraps: it continues the middleware chain that was
+/// stashed into the request's extensions by [`TowerLayer`].
+#[derive(Clone, Copy)]
+pub struct NextService;
+
+impl Service<Request> for NextService {
+    type Response = Response;
+    type Error = HttpError;
+    type Future = BoxFuture<'static, Result<Response, HttpError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, mut req: Request) -> Self::Future {
+        Box::pin(async move {
+            let next = req
+                .extensions
+                .remove::<Next>()
+                .ok_or_else(|| anyhow::anyhow!("tower layer dropped the middleware chain"))?;
+            next.run(req).await
+        })
+    }
+}
+
+/// A tower layer applied once around [`NextService`], usable anywhere a [`Middleware`] is.
+pub struct TowerLayer {
+    service: Mutex<BoxCloneService<Request, Response, BoxError>>,
+}
+
+/// Adapts a `tower::Layer` (timeout, load shedding, ...) into a middleware.
+pub fn from_layer<L>(layer: L) -> TowerLayer
+where
+    L: Layer<NextService>,
+    L::Service: Service<Request, Response = Response> + Clone + Send + 'static,
+    <L::Service as Service<Request>>::Error: Into<BoxError>,
+    <L::Service as Service<Request>>::Future: Send + 'static,
+{
+    let service = layer.layer(NextService).map_err(Into::into);
+    TowerLayer {
+        service: Mutex::new(BoxCloneService::new(service)),
+    }
+}
+
+impl Middleware for TowerLayer {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let service = self.service.lock().unwrap().clone();
+        req.extensions.insert(next);
+        Box::pin(async move { service.oneshot(req).await.map_err(into_http_error) })
+    }
+}
+
+fn into_http_error(error: BoxError) -> HttpError {
+    let error = match error.downcast::<HttpError>() {
+        Ok(error) => return *error,
+        Err(error) => error,
+    };
+
+    if error.is::<tower::timeout::error::Elapsed>() {
+        HttpError::gateway_timeout()
+    } else if error.is::<tower::load_shed::error::Overloaded>() {
+        HttpError::service_unavailable()
+    } else {
+        HttpError::from(anyhow::anyhow!(error))
+    }
+}
@@ -0,0 +1,352 @@
+//! Server-side sessions, kept in a [`SessionStore`] under a random ID that the browser holds in
+//! a signed cookie.
+//!
+//! Handlers take a [`Session`] and read and write it as a map of JSON values:
+//!
+//! ```ignore
+//! async fn visit(session: Session) -> Result<String, HttpError> {
+//!     let visits = session.get::<u64>("visits").unwrap_or(0) + 1;
+//!     session.insert("visits", visits);
+//!     Ok(format!("{visits} visits"))
+//! }
+//! ```
+//!
+//! Nothing is stored, and no cookie set, until something is put in a session. After that the
+//! cookie and the stored session are both kept for the TTL from the last request that came
+//! with it. A cookie whose signature doesn't check out, or whose session has gone from the
+//! store, starts a new session with a new ID: clients never get to choose their own.
+//! [`Session::renew`] gives the session a new ID too, which should happen whenever who the
+//! client is changes, like on logging in.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hmac::{Hmac, Mac};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+use sha2::Sha256;
+use tracing::warn;
+
+use crate::{
+    bearer_auth::constant_time_eq,
+    cookies::{SameSite, SetCookie},
+    error::HttpError,
+    extract::FromRequest,
+    handler::BoxFuture,
+    headers::{HeaderMap, SET_COOKIE},
+    middleware::{Middleware, Next},
+    request::{Request, Scheme},
+    response::Response,
+};
+
+/// What a session holds.
+pub type SessionData = Map<String, Value>;
+
+/// Where sessions are kept between requests.
+pub trait SessionStore: Send + Sync + 'static {
+    /// The session with `id`, `None` if there's none or it expired.
+    fn load(&self, id: &str) -> BoxFuture<'_, anyhow::Result<Option<SessionData>>>;
+
+    /// Keeps `data` as the session with `id` for `ttl`, replacing whatever was there.
+    fn save(&self, id: &str, data: SessionData, ttl: Duration)
+        -> BoxFuture<'_, anyhow::Result<()>>;
+
+    fn remove(&self, id: &str) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+/// Sessions in memory, lost when the server exits. Expired ones are dropped once the store
+/// fills up, and past that the ones closest to expiring.
+pub struct MemoryStore {
+    max: usize,
+    sessions: Mutex<HashMap<String, (SessionData, Instant)>>,
+}
+
+impl MemoryStore {
+    /// A store of at most `max` sessions.
+    pub fn new(max: usize) -> Self {
+        Self {
+            max: max.max(1),
+            sessions: Mutex::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> BoxFuture<'_, anyhow::Result<Option<SessionData>>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let data = match sessions.get(id) {
+            Some((_, expires)) if *expires <= Instant::now() => {
+                sessions.remove(id);
+                None
+            }
+            Some((data, _)) => Some(data.clone()),
+            None => None,
+        };
+        Box::pin(async { Ok(data) })
+    }
+
+    fn save(
+        &self,
+        id: &str,
+        data: SessionData,
+        ttl: Duration,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        let now = Instant::now();
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.len() >= self.max && !sessions.contains_key(id) {
+            sessions.retain(|_, (_, expires)| *expires > now);
+            while sessions.len() >= self.max {
+                let soonest = sessions
+                    .iter()
+                    .min_by_key(|(_, (_, expires))| *expires)
+                    .map(|(id, _)| id.clone());
+                match soonest {
+                    Some(soonest) => sessions.remove(&soonest),
+                    None => break,
+                };
+            }
+        }
+        sessions.insert(id.to_owned(), (data, now + ttl));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn remove(&self, id: &str) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.sessions.lock().unwrap().remove(id);
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// The ID the session is stored under, `None` for one that hasn't been yet.
+    id: Option<String>,
+    data: SessionData,
+    changed: bool,
+    destroyed: bool,
+    /// The ID to drop from the store once a renewed session is saved under its new one.
+    renewed: Option<String>,
+}
+
+/// The session of a request, in its extensions. Clones share it.
+#[derive(Debug, Clone, Default)]
+pub struct Session(Arc<Mutex<State>>);
+
+impl Session {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// The session's ID, `None` until it's first saved.
+    pub fn id(&self) -> Option<String> {
+        self.state().id.clone()
+    }
+
+    /// The value under `key`, `None` if there's none or it isn't a `T`.
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        let value = self.state().data.get(key)?.clone();
+        serde_json::from_value(value).ok()
+    }
+
+    /// Puts `value` under `key`.
+    pub fn insert<T: Serialize>(&self, key: &str, value: T) {
+        let Ok(value) = serde_json::to_value(value) else {
+            warn!("Couldn't put {key} in the session");
+            return;
+        };
+        let mut state = self.state();
+        state.data.insert(key.to_owned(), value);
+        state.changed = true;
+    }
+
+    /// Takes the value under `key` out of the session.
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        let mut state = self.state();
+        let value = state.data.remove(key);
+        state.changed |= value.is_some();
+        value
+    }
+
+    /// Everything in the session.
+    pub fn data(&self) -> SessionData {
+        self.state().data.clone()
+    }
+
+    /// Moves the session to a new ID, leaving the old one unusable.
+    pub fn renew(&self) {
+        let mut state = self.state();
+        if let Some(old) = state.id.take() {
+            state.renewed.get_or_insert(old);
+        }
+        state.changed = true;
+    }
+
+    /// Ends the session, removing it from the store and telling the browser to forget its
+    /// cookie.
+    pub fn destroy(&self) {
+        let mut state = self.state();
+        state.data.clear();
+        state.destroyed = true;
+    }
+}
+
+impl FromRequest for Session {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            req.extensions
+                .get::<Session>()
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("sessions aren't enabled").into())
+        })
+    }
+}
+
+/// Gives each request its [`Session`], and saves it once the request has been handled.
+#[derive(Clone)]
+pub struct Sessions {
+    store: Arc<dyn SessionStore>,
+    key: Arc<[u8]>,
+    cookie: String,
+    ttl: Duration,
+}
+
+impl Sessions {
+    /// Sessions kept in `store`, with their cookies signed with `key`, in a `session` cookie
+    /// that lasts a day.
+    pub fn new(store: Arc<dyn SessionStore>, key: &[u8]) -> Self {
+        Self {
+            store,
+            key: key.into(),
+            cookie: "session".to_owned(),
+            ttl: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    /// The name of the session cookie.
+    pub fn cookie(mut self, name: impl Into<String>) -> Self {
+        self.cookie = name.into();
+        self
+    }
+
+    /// How long a session lasts after the last request that came with it.
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    fn sign(&self, id: &str) -> Vec<u8> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC takes keys of any length");
+        mac.update(id.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// The session ID in a cookie's `value`, if it was signed with the key.
+    fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (id, signature) = value.split_once('.')?;
+        let expected = hex(&self.sign(id));
+        constant_time_eq(expected.as_bytes(), signature.as_bytes()).then_some(id)
+    }
+
+    fn set_cookie(&self, id: &str, scheme: Scheme) -> SetCookie {
+        let value = format!("{id}.{}", hex(&self.sign(id)));
+        SetCookie::new(&self.cookie, value)
+            .path("/")
+            .max_age(self.ttl)
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .secure(scheme == Scheme::Https)
+    }
+
+    /// Saves `session` at the end of a request, returning the `Set-Cookie` to answer with.
+    async fn commit(
+        &self,
+        session: &Session,
+        sent_cookie: bool,
+        scheme: Scheme,
+    ) -> anyhow::Result<Option<SetCookie>> {
+        let state = std::mem::take(&mut *session.state());
+        if let Some(old) = &state.renewed {
+            self.store.remove(old).await?;
+        }
+        let forget = || {
+            (sent_cookie || state.renewed.is_some())
+                .then(|| SetCookie::removal(&self.cookie).path("/"))
+        };
+        if state.destroyed || (state.changed && state.data.is_empty()) {
+            if let Some(id) = &state.id {
+                self.store.remove(id).await?;
+            }
+            return Ok(forget());
+        }
+        if state.id.is_none() && !state.changed {
+            return Ok(None);
+        }
+        let id = state
+            .id
+            .unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string());
+        self.store.save(&id, state.data, self.ttl).await?;
+        Ok(Some(self.set_cookie(&id, scheme)))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn add(headers: &mut HeaderMap, cookie: &Option<SetCookie>) {
+    if let Some(cookie) = cookie {
+        headers.append(SET_COOKIE, &cookie.to_string());
+    }
+}
+
+impl Middleware for Sessions {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let sessions = self.clone();
+        Box::pin(async move {
+            let sent = req.cookie(&sessions.cookie).map(str::to_owned);
+            let id = sent
+                .as_deref()
+                .and_then(|value| sessions.verify(value))
+                .map(str::to_owned);
+            let loaded = match &id {
+                Some(id) => sessions.store.load(id).await?,
+                None => None,
+            };
+            let session = Session::default();
+            if let (Some(id), Some(data)) = (id, loaded) {
+                let mut state = session.state();
+                state.id = Some(id);
+                state.data = data;
+            }
+            let scheme = req.scheme;
+            req.extensions.insert(session.clone());
+            let result = next.run(req).await;
+            let cookie = sessions.commit(&session, sent.is_some(), scheme).await?;
+            match result {
+                Ok(mut response) => {
+                    add(&mut response.headers, &cookie);
+                    Ok(response)
+                }
+                Err(mut e) => {
+                    add(&mut e.headers, &cookie);
+                    Err(e)
+                }
+            }
+        })
+    }
+}
@@ -0,0 +1,89 @@
+//! Operational settings that can be changed while the server runs, without a reload: through
+//! the [admin endpoints](crate::admin), which apply them to requests arriving from then on.
+//!
+//! The middlewares following them, like [`BodyLimit`](crate::body_limit::BodyLimit),
+//! [`Compression`](crate::compression::Compression) and
+//! [`RateLimiter`](crate::rate_limit::RateLimiter), take a `watch::Receiver<Settings>` and look
+//! at its current value for every request.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Settings {
+    /// The largest request body accepted, in bytes; `None` for no limit.
+    pub max_body_size: Option<u64>,
+    /// Whether responses are gzipped for clients that accept it, in builds with the
+    /// `compression` feature.
+    pub compression: bool,
+    /// How many requests each client address may make; `None` for no limit.
+    pub rate_limit: Option<RateLimit>,
+}
+
+/// A request rate, with bursts of up to `burst` requests above it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimit {
+    /// Requests per second.
+    pub rate: f64,
+    pub burst: u32,
+}
+
+impl Settings {
+    /// These settings with the ones named in `changes`, a JSON object like
+    /// `{"compression": true}`, set to their values there. Unknown names and values of the
+    /// wrong type are refused, leaving everything as it was.
+    pub fn patch(&self, changes: &Value) -> Result<Settings, String> {
+        let Value::Object(changes) = changes else {
+            return Err("expected an object of settings to change".to_owned());
+        };
+        let mut settings = self.to_map();
+        for (name, value) in changes {
+            match settings.get_mut(name) {
+                Some(setting) => *setting = value.clone(),
+                None => return Err(format!("unknown setting {name}")),
+            }
+        }
+        let settings: Settings =
+            serde_json::from_value(Value::Object(settings)).map_err(|e| e.to_string())?;
+        if let Some(limit) = settings.rate_limit {
+            if !(limit.rate.is_finite() && limit.rate > 0.0) || limit.burst == 0 {
+                return Err("a rate limit needs a positive rate and burst".to_owned());
+            }
+        }
+        Ok(settings)
+    }
+
+    /// The settings that differ between `self` and `other`, as `(name, self's value, other's
+    /// value)`.
+    pub fn changes(&self, other: &Settings) -> Vec<(String, Value, Value)> {
+        let other = other.to_map();
+        self.to_map()
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let new = other.get(&name)?;
+                (*new != value).then(|| (name, value, new.clone()))
+            })
+            .collect()
+    }
+
+    /// Applies to these settings what changed from `old` to `new`, keeping the rest, so that a
+    /// reload changing one option doesn't undo adjustments made to the others in the meantime.
+    pub fn rebase(&self, old: &Settings, new: &Settings) -> Settings {
+        let changed = old
+            .changes(new)
+            .into_iter()
+            .map(|(name, _, value)| (name, value))
+            .collect();
+        self.patch(&Value::Object(changed))
+            .expect("settings patched with values of their own type")
+    }
+
+    fn to_map(&self) -> serde_json::Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map,
+            _ => unreachable!("settings serialize to an object"),
+        }
+    }
+}
@@ -0,0 +1,226 @@
+//! Short links: `POST /shorten` with a URL gets a code for it, and `/s/{code}` redirects to
+//! the URL, counting each time it's followed.
+//!
+//! Given a [data directory](crate::data_dir), the links are kept in [`FILE`] in it, which is
+//! rewritten whenever a link is added. Hits only mark the links as changed, for
+//! [`Shortener::spawn`] to save every so often, so a crash loses the last few counts but never
+//! a link.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{data_dir::JsonFile, error::HttpError, status::StatusCode};
+
+/// The name of the file the links are kept in.
+pub const FILE: &str = "links.json";
+
+/// The longest URL that can be shortened.
+pub const MAX_URL: usize = 2048;
+
+const CODE_LENGTH: usize = 7;
+
+const CODE_ALPHABET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// How many of the most followed links `/stats` lists.
+const TOP: usize = 10;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Link {
+    pub target: String,
+    /// How many times it's been followed.
+    #[serde(default)]
+    pub hits: u64,
+}
+
+pub struct Shortener {
+    file: Option<JsonFile>,
+    max_links: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    links: HashMap<String, Link>,
+    /// The code of each target, so that shortening one twice gives the same code.
+    codes: HashMap<String, String>,
+}
+
+/// Whether `url` is an absolute `http` or `https` URL that's fine to put in a `Location`.
+pub fn valid_url(url: &str) -> bool {
+    let rest = match url.split_once("://") {
+        Some((scheme, rest))
+            if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+        {
+            rest
+        }
+        _ => return false,
+    };
+    let host = rest.split(['/', '?', '#']).next().unwrap_or_default();
+    let host = host.rsplit('@').next().unwrap_or_default();
+    url.len() <= MAX_URL
+        && !host.is_empty()
+        && !host.starts_with(':')
+        && url.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn new_code() -> String {
+    let mut random = uuid::Uuid::new_v4().as_u128();
+    let base = CODE_ALPHABET.len() as u128;
+    (0..CODE_LENGTH)
+        .map(|_| {
+            let c = CODE_ALPHABET[(random % base) as usize];
+            random /= base;
+            c as char
+        })
+        .collect()
+}
+
+impl Default for Shortener {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shortener {
+    /// Links kept in memory only, up to 100,000 of them.
+    pub fn new() -> Self {
+        Self {
+            file: None,
+            max_links: 100_000,
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Links kept in [`FILE`] in `dir`, starting with the ones already there. The directory is
+    /// created if need be.
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        let (file, links): (_, HashMap<String, Link>) = JsonFile::open(dir, FILE)?;
+        let codes = links
+            .iter()
+            .map(|(code, link)| (link.target.clone(), code.clone()))
+            .collect();
+        let inner = Inner { links, codes };
+        Ok(Self {
+            file: Some(file),
+            inner: Mutex::new(inner),
+            ..Self::new()
+        })
+    }
+
+    pub fn max_links(mut self, max: usize) -> Self {
+        self.max_links = max;
+        self
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn changed(&self) {
+        if let Some(file) = &self.file {
+            file.changed();
+        }
+    }
+
+    /// The code for `target`, which has to be a [valid URL](valid_url), and whether it's new
+    /// rather than the one it was given before. New ones are saved before they're returned.
+    pub async fn shorten(&self, target: &str) -> Result<(String, bool), HttpError> {
+        if !valid_url(target) {
+            return Err(HttpError::bad_request(&format!(
+                "Only absolute http and https URLs of up to {MAX_URL} bytes can be shortened"
+            )));
+        }
+        let code = {
+            let mut inner = self.lock();
+            if let Some(code) = inner.codes.get(target) {
+                return Ok((code.clone(), false));
+            }
+            if inner.links.len() >= self.max_links {
+                return Err(HttpError::new(
+                    StatusCode(507),
+                    anyhow::anyhow!("There's no room for more short links"),
+                ));
+            }
+            let code = std::iter::repeat_with(new_code)
+                .find(|code| !inner.links.contains_key(code))
+                .expect("codes run out");
+            let link = Link {
+                target: target.to_owned(),
+                hits: 0,
+            };
+            inner.links.insert(code.clone(), link);
+            inner.codes.insert(target.to_owned(), code.clone());
+            self.changed();
+            code
+        };
+        self.save().await.context("saving short links")?;
+        Ok((code, true))
+    }
+
+    /// Where `code` leads, counting it as followed.
+    pub fn follow(&self, code: &str) -> Option<String> {
+        let mut inner = self.lock();
+        let link = inner.links.get_mut(code)?;
+        link.hits += 1;
+        let target = link.target.clone();
+        self.changed();
+        Some(target)
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock().links.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// For `/stats`: how many links there are, how often they've been followed in all, and the
+    /// most followed ones.
+    pub fn stats(&self) -> Value {
+        let inner = self.lock();
+        let mut top: Vec<_> = inner.links.iter().filter(|(_, l)| l.hits > 0).collect();
+        top.sort_by(|(a_code, a), (b_code, b)| b.hits.cmp(&a.hits).then(a_code.cmp(b_code)));
+        let top: Vec<_> = top
+            .into_iter()
+            .take(TOP)
+            .map(|(code, link)| json!({ "code": code, "target": link.target, "hits": link.hits }))
+            .collect();
+        json!({
+            "links": inner.links.len(),
+            "hits": inner.links.values().map(|link| link.hits).sum::<u64>(),
+            "top": top,
+        })
+    }
+
+    /// Writes the file, if there is one and anything changed since it was last written.
+    pub async fn save(&self) -> anyhow::Result<()> {
+        match &self.file {
+            Some(file) => file.save(|| self.lock().links.clone()).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Saves the hit counts every `every` until the runtime shuts down.
+    pub fn spawn(self: &Arc<Self>, every: Duration) {
+        let shortener = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(every);
+            loop {
+                ticks.tick().await;
+                if let Err(e) = shortener.save().await {
+                    warn!("Couldn't save short links: {e:#}");
+                }
+            }
+        });
+    }
+}
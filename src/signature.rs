@@ -0,0 +1,406 @@
+//! HMAC request signatures, for upload agents and other unattended clients that share a secret
+//! with the server but can't be handed client certificates.
+//!
+//! A signed request says who it's from and when it was made, and signs that along with what
+//! it does:
+//!
+//! ```text
+//! PUT /files/backups/db.tar HTTP/1.1
+//! X-Date: 2026-10-14T09:30:00Z
+//! Authorization: HMAC-SHA256 Credential=backup-agent, Signature=5d41402abc4b2a76b9719d911017c592...
+//! ```
+//!
+//! The signature is the hex HMAC-SHA256, keyed with the client's secret, of the method, the
+//! request target exactly as sent, the `X-Date` and the hex SHA-256 of the body, one per line:
+//!
+//! ```text
+//! PUT
+//! /files/backups/db.tar
+//! 2026-10-14T09:30:00Z
+//! e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855
+//! ```
+//!
+//! which takes a few lines of any language:
+//!
+//! ```sh
+//! date=$(date -u +%Y-%m-%dT%H:%M:%SZ)
+//! digest=$(sha256sum < db.tar | cut -d' ' -f1)
+//! signature=$(printf 'PUT\n/files/backups/db.tar\n%s\n%s' "$date" "$digest" \
+//!     | openssl dgst -sha256 -hmac "$SECRET" -r | cut -d' ' -f1)
+//! ```
+//!
+//! [`SignedRequests`] checks the signatures of the requests that change something under a
+//! prefix. It refuses ones dated further from its own clock than the skew it allows, and
+//! ones whose signature it has already seen within that window, so a captured request can't
+//! be sent again. The body is read into memory to check its digest before anything handles
+//! it, which keeps an upload with the wrong contents from ever being written, so bodies
+//! larger than its [`max_body`](SignedRequests::max_body) are refused without one.
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::{
+    bearer_auth::constant_time_eq,
+    body::Body,
+    error::HttpError,
+    handler::BoxFuture,
+    headers::{AUTHORIZATION, WWW_AUTHENTICATE},
+    middleware::{Middleware, Next},
+    request::{Grants, Identity, Request},
+    response::Response,
+    secret::read_private,
+    security_log::{self, Event},
+    status::StatusCode,
+};
+
+/// The header signed requests carry their date in.
+const X_DATE: &str = "X-Date";
+
+/// The secret of each client ID.
+#[derive(Debug, Default)]
+pub struct SigningSecrets {
+    secrets: HashMap<String, String>,
+}
+
+impl SigningSecrets {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `client`, signing with `secret`.
+    pub fn insert(&mut self, client: impl Into<String>, secret: impl Into<String>) {
+        self.secrets.insert(client.into(), secret.into());
+    }
+
+    /// Adds the `client:secret` lines of the file at `path`, skipping blank ones and `#`
+    /// comments.
+    pub fn load(&mut self, path: &Path) -> anyhow::Result<()> {
+        let text = read_private(path)?;
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((client, secret)) if !client.is_empty() && !secret.is_empty() => {
+                    self.insert(client, secret)
+                }
+                _ => bail!("line {} of {} isn't client:secret", i + 1, path.display()),
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+}
+
+/// Asks for a signature on the requests under a prefix that change something.
+#[derive(Clone)]
+pub struct SignedRequests {
+    prefix: String,
+    secrets: Arc<SigningSecrets>,
+    skew: Duration,
+    /// The largest body read into memory to check, `None` for as large as the memory budget
+    /// allows.
+    max_body: Option<u64>,
+    /// The signatures seen within the skew, with when they stop being accepted anyway.
+    seen: Arc<Mutex<HashMap<String, SystemTime>>>,
+}
+
+impl SignedRequests {
+    /// Checks requests under `prefix` against `secrets`, which can be shared between prefixes,
+    /// allowing their dates five minutes either side of the server's clock.
+    pub fn new(prefix: impl Into<String>, secrets: Arc<SigningSecrets>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            secrets,
+            skew: Duration::from_secs(300),
+            max_body: None,
+            seen: Arc::default(),
+        }
+    }
+
+    /// How far a request's date may be from the server's clock.
+    pub fn skew(mut self, skew: Duration) -> Self {
+        self.skew = skew;
+        self
+    }
+
+    /// Refuses bodies larger than `max` bytes with a 413 before checking their signature, and
+    /// without reading more than that of them.
+    pub fn max_body(mut self, max: u64) -> Self {
+        self.max_body = Some(max);
+        self
+    }
+
+    /// Checks everything about `req` but its body.
+    fn check_headers(&self, req: &Request) -> Result<Claimed, HttpError> {
+        let Some((client, signature)) = req.header(AUTHORIZATION).and_then(credentials) else {
+            return Err(refused("A request signature is required"));
+        };
+        let date = req.header(X_DATE).unwrap_or_default();
+        let Some(signed_at) = parse_date(date) else {
+            return Err(refused(
+                "X-Date must be a UTC time like 2026-10-14T09:30:00Z",
+            ));
+        };
+        let now = SystemTime::now();
+        let off = match now.duration_since(signed_at) {
+            Ok(behind) => behind,
+            Err(ahead) => ahead.duration(),
+        };
+        if off > self.skew {
+            warn!("Signed request from {client} is {}s off", off.as_secs());
+            let detail = format!(
+                "signature: {client:?} sent an X-Date {}s off",
+                off.as_secs()
+            );
+            security_log::record(req, Event::AuthFailure, detail);
+            return Err(refused(
+                "The request's X-Date is too far from the server's clock",
+            ));
+        }
+        let Some(secret) = self.secrets.secrets.get(client) else {
+            warn!("Unknown signing client {client} for {}", req.path);
+            let detail = format!("signature: unknown client {client:?}");
+            security_log::record(req, Event::AuthFailure, detail);
+            return Err(refused("Unknown client or bad signature"));
+        };
+        let Some(signature) = unhex(signature) else {
+            return Err(refused("Unknown client or bad signature"));
+        };
+        Ok(Claimed {
+            client: client.to_owned(),
+            secret: secret.clone(),
+            signature,
+            to_sign: format!("{}\n{}\n{date}\n", req.method, req.target),
+        })
+    }
+
+    /// Takes `signature` as used, unless it already was.
+    fn first_use(&self, signature: &[u8]) -> bool {
+        let now = SystemTime::now();
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, until| *until > now);
+        let key = hex(signature);
+        if seen.contains_key(&key) {
+            return false;
+        }
+        // A request dated up to a skew ahead is accepted until a skew after that.
+        seen.insert(key, now + 2 * self.skew);
+        true
+    }
+}
+
+impl Middleware for SignedRequests {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if !req.path.starts_with(&self.prefix) || req.method.is_safe() {
+            return Box::pin(next.run(req));
+        }
+        let Claimed {
+            client,
+            secret,
+            signature,
+            to_sign,
+        } = match self.check_headers(&req) {
+            Ok(checked) => checked,
+            Err(e) => return Box::pin(async { Err(e) }),
+        };
+        let signed = self.clone();
+        let max_body = self
+            .max_body
+            .map_or(usize::MAX, |max| usize::try_from(max).unwrap_or(usize::MAX));
+        Box::pin(async move {
+            let body = std::mem::replace(&mut req.body, Body::empty())
+                .to_bytes(max_body)
+                .await?;
+            let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+                .expect("HMAC takes keys of any length");
+            mac.update(to_sign.as_bytes());
+            mac.update(hex(&Sha256::digest(&body)).as_bytes());
+            let expected = mac.finalize().into_bytes();
+            if !constant_time_eq(&expected, &signature) {
+                warn!("Bad signature from {client} on {} {}", req.method, req.path);
+                let detail = format!("signature: bad signature from {client:?}");
+                security_log::record(&req, Event::AuthFailure, detail);
+                return Err(refused("Unknown client or bad signature"));
+            }
+            if !signed.first_use(&signature) {
+                warn!(
+                    "Replayed request from {client} on {} {}",
+                    req.method, req.path
+                );
+                let detail = format!("signature: replayed by {client:?}");
+                security_log::record(&req, Event::AuthFailure, detail);
+                return Err(refused("The request was already made"));
+            }
+            req.body = Body::from(body);
+            Identity(client).assign(&mut req);
+            req.extensions.insert(Grants::of("signature"));
+            next.run(req).await
+        })
+    }
+}
+
+/// What a request says about its signature, before its body is read.
+struct Claimed {
+    client: String,
+    secret: String,
+    signature: Vec<u8>,
+    /// Everything signed but the body's digest.
+    to_sign: String,
+}
+
+/// The client and signature of an `Authorization: HMAC-SHA256` header.
+fn credentials(header: &str) -> Option<(&str, &str)> {
+    let (scheme, params) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("hmac-sha256") {
+        return None;
+    }
+    let (mut client, mut signature) = (None, None);
+    for param in params.split(',') {
+        match param.trim().split_once('=') {
+            Some(("Credential", value)) => client = Some(value),
+            Some(("Signature", value)) => signature = Some(value),
+            _ => {}
+        }
+    }
+    Some((client?, signature?))
+}
+
+/// `YYYY-MM-DDTHH:MM:SSZ`.
+fn parse_date(date: &str) -> Option<SystemTime> {
+    let bytes = date.as_bytes();
+    if bytes.len() != 20
+        || [4, 7].map(|i| bytes[i]) != [b'-'; 2]
+        || bytes[10] != b'T'
+        || [13, 16].map(|i| bytes[i]) != [b':'; 2]
+        || bytes[19] != b'Z'
+    {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = &date[range];
+        digits
+            .bytes()
+            .all(|b| b.is_ascii_digit())
+            .then(|| digits.parse().ok())?
+    };
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    // Howard Hinnant's days_from_civil.
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(u64::try_from(secs).ok()?))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn refused(message: &'static str) -> HttpError {
+    HttpError::new(StatusCode::UNAUTHORIZED, anyhow::anyhow!(message))
+        .with_header(WWW_AUTHENTICATE, "HMAC-SHA256")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{access_log::rfc3339_time, request::Method};
+
+    fn signed_requests() -> Arc<[Arc<dyn Middleware>]> {
+        let mut secrets = SigningSecrets::new();
+        secrets.insert("agent", "s3cret");
+        let signed = SignedRequests::new("/files/", Arc::new(secrets)).max_body(8);
+        Arc::from([Arc::new(signed) as Arc<dyn Middleware>])
+    }
+
+    fn request(body: &[u8], chunked: bool) -> Request {
+        let date = format!("{}Z", &rfc3339_time(SystemTime::now())[..19]);
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(format!("PUT\n/files/a.txt\n{date}\n").as_bytes());
+        mac.update(hex(&Sha256::digest(body)).as_bytes());
+        let signature = hex(&mac.finalize().into_bytes());
+
+        let mut req = Request::new(Method::Put, "/files/a.txt");
+        let authorization = format!("HMAC-SHA256 Credential=agent, Signature={signature}");
+        req.headers.insert(AUTHORIZATION, &authorization);
+        req.headers.insert(X_DATE, &date);
+        req.body = match chunked {
+            true => Body::from_reader(std::io::Cursor::new(body.to_vec())),
+            false => Body::from(body.to_vec()),
+        };
+        req
+    }
+
+    async fn status(req: Request) -> StatusCode {
+        let echo = |req: Request| async move {
+            let body = req.body.to_bytes(usize::MAX).await?;
+            Ok(Response::bytes(StatusCode::OK, body))
+        };
+        match Next::new(signed_requests(), Arc::new(echo)).run(req).await {
+            Ok(response) => response.status,
+            Err(e) => e.status,
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_signed_bodies_on() {
+        assert_eq!(status(request(b"contents", false)).await, StatusCode::OK);
+        assert_eq!(status(request(b"contents", true)).await, StatusCode::OK);
+
+        let mut tampered = request(b"contents", false);
+        tampered.body = Body::from(b"CONTENTS".to_vec());
+        assert_eq!(status(tampered).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn refuses_bodies_over_max_body() {
+        for chunked in [false, true] {
+            let req = request(b"more than eight bytes", chunked);
+            assert_eq!(
+                status(req).await,
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "{chunked}"
+            );
+        }
+    }
+}
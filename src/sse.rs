@@ -0,0 +1,133 @@
+//! Server-sent events: a response streaming [`Event`]s as `text/event-stream`, for an
+//! `EventSource` in a browser to read.
+//!
+//! ```ignore
+//! async fn ticks() -> Sse<impl Stream<Item = Event>> {
+//!     Sse::new(futures_util::stream::iter([Event::new("tick").event("tick")]))
+//! }
+//! ```
+//!
+//! Each event is flushed as soon as it's produced. A client that lost the connection comes
+//! back with the id of the last event it saw, which [`LastEventId`] takes, so the stream can
+//! pick up from there.
+
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures_util::{Stream, StreamExt};
+
+use crate::{
+    error::HttpError,
+    extract::FromRequest,
+    handler::BoxFuture,
+    headers::{CACHE_CONTROL, CONTENT_TYPE},
+    request::Request,
+    response::{IntoResponse, Response},
+    status::StatusCode,
+};
+
+/// One event: its data, and optionally a type, an id and how long to wait before reconnecting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Event {
+    data: String,
+    event: Option<String>,
+    id: Option<String>,
+    retry: Option<Duration>,
+}
+
+/// `value` without line breaks, which would end the field early, nor NUL, which ids can't have.
+fn single_line(value: impl Into<String>) -> String {
+    let mut value = value.into();
+    value.retain(|c| !matches!(c, '\r' | '\n' | '\0'));
+    value
+}
+
+impl Event {
+    /// An event with `data`, which can run over several lines.
+    pub fn new(data: impl Into<String>) -> Self {
+        Self {
+            data: data.into(),
+            ..Self::default()
+        }
+    }
+
+    /// The type, which `EventSource` dispatches on; `message` without one.
+    pub fn event(mut self, event: impl Into<String>) -> Self {
+        self.event = Some(single_line(event));
+        self
+    }
+
+    /// The id, sent back in `Last-Event-ID` on reconnecting.
+    pub fn id(mut self, id: impl Into<String>) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// How long clients should wait before reconnecting.
+    pub fn retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    fn encode(&self) -> Bytes {
+        let mut out = String::new();
+        if let Some(event) = &self.event {
+            out += &format!("event: {event}\n");
+        }
+        if let Some(id) = &self.id {
+            out += &format!("id: {id}\n");
+        }
+        if let Some(retry) = self.retry {
+            out += &format!("retry: {}\n", retry.as_millis());
+        }
+        // Every line break, of whichever kind, starts another data line.
+        for line in self
+            .data
+            .split("\r\n")
+            .flat_map(|line| line.split(['\r', '\n']))
+        {
+            out += &format!("data: {line}\n");
+        }
+        out.push('\n');
+        out.into()
+    }
+}
+
+/// A `text/event-stream` response sending the events `S` yields, until it ends.
+pub struct Sse<S>(pub S);
+
+impl<S> Sse<S> {
+    pub fn new(events: S) -> Self {
+        Self(events)
+    }
+}
+
+impl<S> IntoResponse for Sse<S>
+where
+    S: Stream<Item = Event> + Send + 'static,
+{
+    fn into_response(self) -> Result<Response, HttpError> {
+        let stream = self.0.map(|event| Ok(event.encode()));
+        let mut response = Response::stream(StatusCode::OK, stream);
+        response.set_header(CONTENT_TYPE, "text/event-stream");
+        response.set_header(CACHE_CONTROL, "no-cache");
+        // So nginx and the like pass events on as they come.
+        response.set_header("X-Accel-Buffering", "no");
+        Ok(response)
+    }
+}
+
+/// The `Last-Event-ID` a client reconnects with. Taken as an `Option`, it's `None` on the
+/// first connection.
+pub struct LastEventId(pub String);
+
+impl FromRequest for LastEventId {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            match req.header("Last-Event-ID") {
+                Some(id) => Ok(LastEventId(id.trim().to_owned())),
+                None => Err(HttpError::bad_request("No Last-Event-ID was sent")),
+            }
+        })
+    }
+}
@@ -0,0 +1,33 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::broadcast;
+
+use crate::{
+    kv::KvStore, notices::Notices, served_dir::ServedDir, shortener::Shortener,
+    upload_expiry::UploadExpiry, upload_filter::UploadFilter, upload_scan::UploadScan,
+    websocket::Message,
+};
+
+/// State shared by the built-in routes, handed out through the `State` extractor.
+pub struct AppState {
+    /// The directory `/files` reads from and writes to.
+    pub base_dir: ServedDir,
+    /// What uploads to it are scanned with, if anything.
+    pub upload_scan: Option<UploadScan>,
+    /// What the names and types of the files `/upload` takes are checked with, as they are
+    /// for `/files` by the middleware.
+    pub upload_filter: Option<UploadFilter>,
+    /// When the uploads made with a TTL are to be deleted.
+    pub upload_expiry: Arc<UploadExpiry>,
+    /// The longest `/delay` waits, and `/stream` between lines.
+    pub max_delay: Duration,
+    /// What `/ws/broadcast` passes messages around with. A reload starts a new one, leaving the
+    /// clients already connected to talk among themselves.
+    pub broadcast: broadcast::Sender<Message>,
+    /// What happens to the server, for `/events`.
+    pub notices: Notices,
+    /// What `/kv/{key}` keeps.
+    pub kv: Arc<KvStore>,
+    /// The links `/shorten` makes and `/s/{code}` follows.
+    pub shortener: Arc<Shortener>,
+}
@@ -0,0 +1,305 @@
+//! A directory served as a sub-application, meant to be [mounted](crate::router::Router::mount)
+//! under a prefix.
+
+use std::{
+    fmt::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+
+use crate::{
+    access_file::{self, ACCESS_FILE},
+    error::HttpError,
+    handler::{BoxFuture, Handler},
+    headers::{ALLOW, CACHE_CONTROL, CONTENT_TYPE},
+    request::{Method, Request},
+    response::Response,
+    safe_path,
+    served_dir::{self, Backoff, ServedDir},
+    status::StatusCode,
+    upload_scan::UploadScan,
+};
+
+#[derive(Debug, Clone)]
+pub struct StaticDir {
+    dir: ServedDir,
+    cache_control: Option<String>,
+    autoindex: bool,
+    read_only: bool,
+    upload_scan: Option<UploadScan>,
+}
+
+impl StaticDir {
+    /// A read-only directory without listings or caching headers.
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: ServedDir::new(root),
+            cache_control: None,
+            autoindex: false,
+            read_only: true,
+            upload_scan: None,
+        }
+    }
+
+    /// Scans files put in the directory before they're kept, see [`UploadScan`].
+    pub fn upload_scan(mut self, scan: UploadScan) -> Self {
+        self.upload_scan = Some(scan);
+        self
+    }
+
+    /// The directory files are served from.
+    pub fn root(&self) -> &Path {
+        self.dir.root()
+    }
+
+    /// The `Cache-Control` value sent with every file.
+    pub fn cache_control(mut self, value: &str) -> Self {
+        self.cache_control = Some(value.to_owned());
+        self
+    }
+
+    /// Lists directories that have no `index.html` instead of answering 404.
+    pub fn autoindex(mut self, enabled: bool) -> Self {
+        self.autoindex = enabled;
+        self
+    }
+
+    /// Whether PUT and DELETE are refused, which they are by default.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Keeps file access from leaving the directory even through symlinks, see
+    /// [`ServedDir::confine`].
+    pub fn confine(mut self, confined: bool) -> Self {
+        self.dir = self.dir.confine(confined);
+        self
+    }
+
+    /// Answers 503 for a while once the file system keeps failing, see [`Backoff`].
+    pub fn backoff(mut self, backoff: Backoff) -> Self {
+        self.dir = self.dir.backoff(backoff);
+        self
+    }
+
+    fn allowed_methods(&self) -> &'static str {
+        match self.read_only {
+            true => "GET, HEAD, OPTIONS",
+            false => "GET, HEAD, PUT, DELETE, OPTIONS",
+        }
+    }
+
+    /// Maps the request path onto one relative to the directory, refusing anything that could
+    /// climb out of it, and the access files, as if they weren't there.
+    fn resolve(&self, path: &str) -> Result<PathBuf, HttpError> {
+        let path = safe_path::resolve(path)?;
+        match access_file::is_access_file(&path) {
+            true => Err(HttpError::not_found()),
+            false => Ok(path),
+        }
+    }
+
+    async fn get(&self, path: &str, target: &str) -> Result<Response, HttpError> {
+        let path = self.resolve(path)?;
+        let metadata = self
+            .dir
+            .metadata(&path)
+            .await
+            .map_err(served_dir::not_found)?;
+
+        if !metadata.is_dir() {
+            return self.file(&path).await;
+        }
+
+        // Relative links in listings and index pages only work below a trailing slash.
+        let (target_path, query) = target.split_once('?').unwrap_or((target, ""));
+        if !target_path.ends_with('/') {
+            let location = match query {
+                "" => format!("{target_path}/"),
+                query => format!("{target_path}/?{query}"),
+            };
+            return Ok(Response::moved_permanently(&location));
+        }
+
+        let index = path.join("index.html");
+        if self.dir.metadata(&index).await.is_ok_and(|m| m.is_file()) {
+            return self.file(&index).await;
+        }
+        if !self.autoindex {
+            return Err(HttpError::not_found());
+        }
+        self.listing(&path, target_path).await
+    }
+
+    async fn file(&self, path: &Path) -> Result<Response, HttpError> {
+        let file = self.dir.open(path).await.map_err(served_dir::not_found)?;
+        let len = file
+            .metadata()
+            .await
+            .context("reading file metadata")?
+            .len();
+        let mut response = Response::file(StatusCode::OK, file, len);
+        response.set_header(CONTENT_TYPE, content_type(path));
+        if let Some(cache_control) = &self.cache_control {
+            response.set_header(CACHE_CONTROL, cache_control);
+        }
+        Ok(response)
+    }
+
+    async fn listing(&self, dir: &Path, title: &str) -> Result<Response, HttpError> {
+        let read_dir = self
+            .dir
+            .read_dir(dir)
+            .await
+            .map_err(|e| served_dir::failed(e, "reading directory"))?;
+        let mut entries = read_dir
+            .into_iter()
+            .filter(|(name, _)| name != ACCESS_FILE)
+            .map(|(name, is_dir)| {
+                let mut name = name.to_string_lossy().into_owned();
+                if is_dir {
+                    name.push('/');
+                }
+                name
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+
+        let title = escape_html(title);
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><title>Index of {title}</title></head><body>\n\
+             <h1>Index of {title}</h1>\n<ul>\n<li><a href=\"../\">../</a></li>\n"
+        );
+        for name in entries {
+            let href = match name.strip_suffix('/') {
+                Some(dir) => format!("{}/", safe_path::encode_segment(dir)),
+                None => safe_path::encode_segment(&name),
+            };
+            let name = escape_html(&name);
+            let _ = writeln!(html, "<li><a href=\"{href}\">{name}</a></li>");
+        }
+        html.push_str("</ul>\n</body></html>\n");
+
+        Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/html")
+            .body(html))
+    }
+
+    async fn put(&self, req: Request) -> Result<Response, HttpError> {
+        let path = self.resolve(&req.path)?;
+        if !req.body.is_framed() {
+            return Err(HttpError::bad_request(
+                "No valid Content-Length was provided",
+            ));
+        }
+        let existed = self.dir.exists(&path).await;
+        let mut body = req.body;
+        self.dir
+            .write(&path, &mut body, self.upload_scan.as_ref())
+            .await?;
+        Ok(Response::empty(match existed {
+            true => StatusCode::NO_CONTENT,
+            false => StatusCode::CREATED,
+        }))
+    }
+
+    async fn delete(&self, path: &str) -> Result<Response, HttpError> {
+        let path = self.resolve(path)?;
+        match self.dir.remove_file(&path).await {
+            Ok(()) => Ok(Response::empty(StatusCode::NO_CONTENT)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(HttpError::not_found()),
+            Err(e) => Err(served_dir::failed(e, "deleting file")),
+        }
+    }
+
+    async fn serve(&self, req: Request) -> Result<Response, HttpError> {
+        match req.method {
+            Method::Get => self.get(&req.path, &req.target).await,
+            Method::Head => Ok(self.get(&req.path, &req.target).await?.without_body()),
+            Method::Options => {
+                Ok(Response::empty(StatusCode::NO_CONTENT)
+                    .with_header(ALLOW, self.allowed_methods()))
+            }
+            Method::Put if !self.read_only => self.put(req).await,
+            Method::Delete if !self.read_only => self.delete(&req.path).await,
+            method => Err(HttpError::method_not_allowed(method.as_str())
+                .with_header(ALLOW, self.allowed_methods())),
+        }
+    }
+}
+
+impl Handler for StaticDir {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let dir = self.clone();
+        Box::pin(async move { dir.serve(req).await })
+    }
+}
+
+/// A media type from the file extension, for the handful of types browsers care about.
+pub(crate) fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js" | "mjs") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        Some("gif") => "image/gif",
+        Some("webp") => "image/webp",
+        Some("ico") => "image/x-icon",
+        Some("wasm") => "application/wasm",
+        Some("pdf") => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+pub(crate) fn escape_html(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn never_serves_access_files() {
+        let dir = std::env::temp_dir().join(format!("static-files-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(dir.join("team")).unwrap();
+        std::fs::write(dir.join("team").join(ACCESS_FILE), "deny all\n").unwrap();
+        let static_dir = StaticDir::new(&dir).read_only(false);
+
+        for method in [Method::Get, Method::Head, Method::Put, Method::Delete] {
+            let req = Request::new(method.clone(), "/team/.http-access");
+            let status = static_dir.call(req).await.map(|r| r.status);
+            assert_eq!(
+                status.map_err(|e| e.status),
+                Err(StatusCode::NOT_FOUND),
+                "{method}"
+            );
+        }
+        assert_eq!(
+            std::fs::read_to_string(dir.join("team").join(ACCESS_FILE)).unwrap(),
+            "deny all\n"
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
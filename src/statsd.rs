@@ -0,0 +1,119 @@
+//! Request metrics pushed to a StatsD daemon over UDP, for setups that don't scrape
+//! [`/metrics`](crate::metrics). Every request answered goes out as one datagram:
+//!
+//! ```text
+//! http.requests:1|c
+//! http.responses.200:1|c
+//! http.request.duration:1.93|ms
+//! http.request.bytes:85|c
+//! http.response.bytes:2326|c
+//! ```
+//!
+//! In the DogStatsD format, the status goes in a tag instead of the name, next to the route
+//! and method and any tags given with [`StatsD::tags`]:
+//!
+//! ```text
+//! http.requests:1|c|#route:/files/{*name},method:GET,status:200,env:prod
+//! ```
+
+use std::{
+    fmt::Write as _,
+    io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use crate::metrics::Sample;
+
+/// Where the metrics go, and what they're called.
+#[derive(Debug)]
+pub struct StatsD {
+    socket: UdpSocket,
+    target: SocketAddr,
+    prefix: String,
+    dogstatsd: bool,
+    /// Added to every metric, in DogStatsD, already formatted.
+    tags: String,
+}
+
+impl StatsD {
+    /// Sends to the daemon at `addr`, like `localhost:8125`, with metric names starting with
+    /// `prefix` and a dot.
+    pub fn new(addr: impl ToSocketAddrs, prefix: &str) -> io::Result<Self> {
+        let target = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "the address resolved to nothing")
+        })?;
+        let bind = match target {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0)),
+        };
+        let socket = UdpSocket::bind(bind)?;
+        // Metrics are dropped rather than ever holding up a request.
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            target,
+            prefix: prefix.trim_end_matches('.').to_owned(),
+            dogstatsd: false,
+            tags: String::new(),
+        })
+    }
+
+    /// Sends the DogStatsD format, with the route, method and status as tags.
+    pub fn dogstatsd(mut self, dogstatsd: bool) -> Self {
+        self.dogstatsd = dogstatsd;
+        self
+    }
+
+    /// Tags every metric with these, given as `key:value` or just `key`. Only DogStatsD has
+    /// tags, so this turns it on.
+    pub fn tags<I, T>(mut self, tags: I) -> Self
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        for tag in tags {
+            self.tags.push(',');
+            self.tags.push_str(&sanitize(tag.as_ref()));
+            self.dogstatsd = true;
+        }
+        self
+    }
+
+    pub fn record(&self, sample: &Sample) {
+        let prefix = match self.prefix.as_str() {
+            "" => String::new(),
+            prefix => format!("{prefix}."),
+        };
+        let tags = match self.dogstatsd {
+            true => format!(
+                "|#route:{},method:{},status:{}{}",
+                sanitize(sample.route.unwrap_or("(none)")),
+                sanitize(sample.method),
+                sample.status,
+                self.tags
+            ),
+            false => String::new(),
+        };
+        let millis = sample.duration.as_micros() as f64 / 1000.0;
+        let mut packet = String::new();
+        writeln!(packet, "{prefix}requests:1|c{tags}").unwrap();
+        if !self.dogstatsd {
+            writeln!(packet, "{prefix}responses.{}:1|c", sample.status).unwrap();
+        }
+        writeln!(packet, "{prefix}request.duration:{millis}|ms{tags}").unwrap();
+        writeln!(packet, "{prefix}request.bytes:{}|c{tags}", sample.bytes_in).unwrap();
+        write!(
+            packet,
+            "{prefix}response.bytes:{}|c{tags}",
+            sample.bytes_out
+        )
+        .unwrap();
+        // Nothing listening, or a full buffer, only loses this request's metrics.
+        let _ = self.socket.send_to(packet.as_bytes(), self.target);
+    }
+}
+
+/// A tag with the characters that would end it, or the metric, replaced.
+fn sanitize(tag: &str) -> String {
+    tag.replace(['|', ',', '#', '\n'], "_")
+}
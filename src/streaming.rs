@@ -0,0 +1,88 @@
+//! Response bodies produced incrementally by the handler.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use tokio::sync::mpsc;
+
+pub enum Frame {
+    Data(Bytes),
+    /// Push everything written so far to the client instead of waiting for the buffer to fill.
+    Flush,
+}
+
+pub type BoxFrameStream = Pin<Box<dyn Stream<Item = io::Result<Frame>> + Send>>;
+
+/// Adapts a plain stream of byte chunks, flushing after each one.
+pub fn flushing<S>(stream: S) -> BoxFrameStream
+where
+    S: Stream<Item = io::Result<Bytes>> + Send + 'static,
+{
+    use futures_util::StreamExt;
+
+    Box::pin(stream.flat_map(|chunk| {
+        let frames = match chunk {
+            Ok(bytes) => vec![Ok(Frame::Data(bytes)), Ok(Frame::Flush)],
+            Err(e) => vec![Err(e)],
+        };
+        futures_util::stream::iter(frames)
+    }))
+}
+
+/// The handler side of a channel-backed body; dropping it ends the body.
+#[derive(Clone)]
+pub struct BodySender {
+    tx: mpsc::Sender<io::Result<Frame>>,
+}
+
+impl BodySender {
+    /// Queues a chunk, waiting while the connection is behind. Fails once the client is gone.
+    pub async fn send(&self, data: impl Into<Bytes>) -> io::Result<()> {
+        self.send_frame(Frame::Data(data.into())).await
+    }
+
+    pub async fn flush(&self) -> io::Result<()> {
+        self.send_frame(Frame::Flush).await
+    }
+
+    /// Sends some data and flushes it right away.
+    pub async fn send_flushed(&self, data: impl Into<Bytes>) -> io::Result<()> {
+        self.send(data).await?;
+        self.flush().await
+    }
+
+    /// Aborts the body with an error; the connection is closed without the final chunk so the
+    /// client can tell the response is incomplete.
+    pub async fn abort(self, error: io::Error) {
+        let _ = self.tx.send(Err(error)).await;
+    }
+
+    async fn send_frame(&self, frame: Frame) -> io::Result<()> {
+        self.tx.send(Ok(frame)).await.map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "response body receiver dropped")
+        })
+    }
+}
+
+struct ChannelStream {
+    rx: mpsc::Receiver<io::Result<Frame>>,
+}
+
+impl Stream for ChannelStream {
+    type Item = io::Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Creates a body that the returned sender feeds, with room for `buffer` pending frames.
+pub fn channel(buffer: usize) -> (BodySender, BoxFrameStream) {
+    let (tx, rx) = mpsc::channel(buffer);
+    (BodySender { tx }, Box::pin(ChannelStream { rx }))
+}
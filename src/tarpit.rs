@@ -0,0 +1,134 @@
+//! A tarpit for abusive clients: rather than being turned away at once, and trying again
+//! straight after, clients the server has decided against get their answer a byte at a time
+//! over minutes, which ties up the scanner or scraper on the other end at next to no cost here.
+//!
+//! What decides a client is abusive marks its request with [`mark`]: the
+//! [IP filter](crate::ip_filter::IpFilter) and `.http-access` files for denied addresses, the
+//! [rate limiter](crate::rate_limit::RateLimiter) and the [login lockout](crate::lockout). The
+//! [`Tarpit`] middleware, in front of all of them, drips out the answer to a marked request,
+//! with the same status and headers. Only so many answers drip at once; past that, marked
+//! requests are answered straight away, so the tarpit can't be used to take up the server's
+//! connections.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::info;
+
+use crate::{
+    error::HttpError,
+    forwarded::client_ip,
+    handler::BoxFuture,
+    headers::{CONNECTION, CONTENT_LENGTH},
+    middleware::{Middleware, Next},
+    request::Request,
+    response::{Response, ResponseBody},
+    rt, streaming,
+};
+
+/// In the extensions of requests the [`Tarpit`] sees, for [`mark`] to set.
+#[derive(Debug, Clone, Default)]
+struct Marked(Arc<AtomicBool>);
+
+/// Tells the [`Tarpit`], if there is one, that the client of `req` is abusive, so its answer
+/// should be dripped out.
+pub fn mark(req: &Request) {
+    if let Some(marked) = req.extensions.get::<Marked>() {
+        marked.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Drips out the answers to requests [marked](mark) as abusive.
+#[derive(Debug, Clone)]
+pub struct Tarpit {
+    slots: Arc<Semaphore>,
+    interval: Duration,
+    duration: Duration,
+}
+
+impl Tarpit {
+    /// Drips out up to `slots` answers at once, a byte every 10 seconds for 5 minutes.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(slots)),
+            interval: Duration::from_secs(10),
+            duration: Duration::from_secs(5 * 60),
+        }
+    }
+
+    /// How long to wait before each byte.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// How long an answer takes in all, padded out past the end of the message.
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// `response`, with `body` for its body, a byte at a time.
+    fn drip(&self, mut response: Response, body: Bytes, permit: OwnedSemaphorePermit) -> Response {
+        let interval = self.interval.max(Duration::from_millis(1));
+        let bytes = (self.duration.as_secs_f64() / interval.as_secs_f64()).ceil() as usize;
+        let bytes = bytes.max(body.len());
+        let stream = futures_util::stream::unfold((0, permit), move |(sent, permit)| {
+            let body = body.clone();
+            async move {
+                if sent == bytes {
+                    return None;
+                }
+                rt::sleep(interval).await;
+                let byte = match sent < body.len() {
+                    true => body.slice(sent..sent + 1),
+                    false => Bytes::from_static(b"\n"),
+                };
+                Some((Ok(byte), (sent + 1, permit)))
+            }
+        });
+        response.headers.remove(CONTENT_LENGTH);
+        response.set_header(CONNECTION, "close");
+        response.body = ResponseBody::Stream(streaming::flushing(stream));
+        response
+    }
+}
+
+impl Middleware for Tarpit {
+    fn handle(
+        &self,
+        mut req: Request,
+        next: Next,
+    ) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let marked = Marked::default();
+        req.extensions.insert(marked.clone());
+        let client = client_ip(&req);
+        let tarpit = self.clone();
+        Box::pin(async move {
+            let result = next.run(req).await;
+            if !marked.0.load(Ordering::Relaxed) {
+                return result;
+            }
+            let Ok(permit) = tarpit.slots.clone().try_acquire_owned() else {
+                return result;
+            };
+            // A response's own body gives way to the padding; an error's message comes first.
+            let (response, body) = match result {
+                Ok(response) => (response, Bytes::new()),
+                Err(e) => (e.to_response(), Bytes::from(format!("{}\n", e.error))),
+            };
+            match client {
+                Some(ip) => info!("Tarpitting {ip}"),
+                None => info!("Tarpitting a client"),
+            }
+            Ok(tarpit.drip(response, body, permit))
+        })
+    }
+}
@@ -0,0 +1,142 @@
+//! A deadline for the whole handling of a request, body included.
+
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use futures_util::Stream;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
+use tracing::warn;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::ContentLength,
+    middleware::{Middleware, Next},
+    request::Request,
+    response::{Response, ResponseBody},
+    rt::{self, Sleep},
+    status::StatusCode,
+    streaming::{BoxFrameStream, Frame},
+};
+
+/// Aborts requests that take longer than the configured duration.
+///
+/// Until the handler returns, a timeout becomes an error response with the configured status
+/// (503 by default). Once the head has gone out there's no way to change the status anymore, so
+/// a body still being produced at the deadline (a slow file read, a stalled stream) fails the
+/// write instead and the connection is closed, leaving the client with a truncated response.
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout {
+    duration: Duration,
+    status: StatusCode,
+}
+
+impl Timeout {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            status: StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+}
+
+impl Middleware for Timeout {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let Timeout { duration, status } = *self;
+        Box::pin(async move {
+            let deadline = Instant::now() + duration;
+            let mut response = rt::timeout_at(deadline, next.run(req))
+                .await
+                .map_err(|_| {
+                    warn!("Request timed out after {duration:?}");
+                    HttpError::new(status, anyhow::anyhow!("Request timed out"))
+                })??;
+
+            response.body = match std::mem::replace(&mut response.body, ResponseBody::Empty) {
+                body @ (ResponseBody::Empty | ResponseBody::Bytes(_) | ResponseBody::Omitted) => {
+                    body
+                }
+                ResponseBody::Reader(reader) => {
+                    ResponseBody::Reader(Box::new(DeadlineReader::new(reader, deadline)))
+                }
+                ResponseBody::File { file, len } => {
+                    response.headers.typed_insert(&ContentLength(len));
+                    let reader = DeadlineReader::new(file.take(len), deadline);
+                    ResponseBody::Reader(Box::new(reader))
+                }
+                ResponseBody::Stream(stream) => {
+                    ResponseBody::Stream(Box::pin(DeadlineStream::new(stream, deadline)))
+                }
+            };
+            Ok(response)
+        })
+    }
+}
+
+fn timed_out() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        "response body exceeded request timeout",
+    )
+}
+
+struct DeadlineReader<R> {
+    inner: R,
+    sleep: Sleep,
+}
+
+impl<R> DeadlineReader<R> {
+    fn new(inner: R, deadline: Instant) -> Self {
+        Self {
+            inner,
+            sleep: rt::sleep_until(deadline),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for DeadlineReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.sleep.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Err(timed_out()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+struct DeadlineStream {
+    inner: BoxFrameStream,
+    sleep: Sleep,
+}
+
+impl DeadlineStream {
+    fn new(inner: BoxFrameStream, deadline: Instant) -> Self {
+        Self {
+            inner,
+            sleep: rt::sleep_until(deadline),
+        }
+    }
+}
+
+impl Stream for DeadlineStream {
+    type Item = io::Result<Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.sleep.as_mut().poll(cx).is_ready() {
+            return Poll::Ready(Some(Err(timed_out())));
+        }
+        self.inner.as_mut().poll_next(cx)
+    }
+}
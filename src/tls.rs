@@ -0,0 +1,520 @@
+//! HTTPS, by terminating TLS with rustls on the connections a [`Listener::Tls`] accepts.
+//!
+//! [`Listener::Tls`]: crate::listener::Listener::Tls
+
+use std::{
+    fmt,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use anyhow::{bail, Context};
+use tokio_rustls::rustls::{
+    crypto::{ring, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::{ClientHello, NoServerSessionStorage, WebPkiClientVerifier},
+    sign::CertifiedKey,
+    version, RootCertStore, ServerConfig, SupportedProtocolVersion,
+};
+use x509_parser::prelude::{FromDer, GeneralName, X509Certificate};
+
+use crate::{
+    error::HttpError, extract::FromRequest, handler::BoxFuture, request::Request,
+    status::StatusCode,
+};
+
+pub use tokio_rustls::{rustls::server::ResolvesServerCert, TlsAcceptor};
+
+/// The server's certificate, and whether clients have to present one too.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    certificate: Certificate,
+    client_ca: Option<PathBuf>,
+    policy: TlsPolicy,
+    /// Protocols to offer besides HTTP/1.1, for validation handshakes that carry no requests.
+    pub(crate) extra_alpn: Vec<Vec<u8>>,
+}
+
+/// A TLS version handshakes may use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+impl std::str::FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "1.2" => Ok(Self::Tls12),
+            "1.3" => Ok(Self::Tls13),
+            _ => Err(format!(
+                "{s} isn't a TLS version this server speaks, 1.2 or 1.3"
+            )),
+        }
+    }
+}
+
+impl TlsVersion {
+    fn of(version: &SupportedProtocolVersion) -> Self {
+        match version == &version::TLS12 {
+            true => Self::Tls12,
+            false => Self::Tls13,
+        }
+    }
+}
+
+/// What handshakes may negotiate, and how sessions are resumed: by default TLS 1.2 and up with
+/// rustls's cipher suites and key exchange groups, resuming with session IDs but not tickets.
+#[derive(Debug, Clone)]
+pub struct TlsPolicy {
+    min_version: TlsVersion,
+    cipher_suites: Vec<String>,
+    kx_groups: Vec<String>,
+    tickets: bool,
+    resumption: bool,
+}
+
+impl Default for TlsPolicy {
+    fn default() -> Self {
+        Self {
+            min_version: TlsVersion::Tls12,
+            cipher_suites: Vec::new(),
+            kx_groups: Vec::new(),
+            tickets: false,
+            resumption: true,
+        }
+    }
+}
+
+impl TlsPolicy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn min_version(mut self, version: TlsVersion) -> Self {
+        self.min_version = version;
+        self
+    }
+
+    /// Only offers these cipher suites, by their IANA names like
+    /// `TLS13_AES_256_GCM_SHA384` or `TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256`.
+    pub fn cipher_suites(mut self, names: impl IntoIterator<Item = String>) -> Self {
+        self.cipher_suites = names.into_iter().collect();
+        self
+    }
+
+    /// Only offers these key exchange groups, like `X25519` or `secp384r1`.
+    pub fn kx_groups(mut self, names: impl IntoIterator<Item = String>) -> Self {
+        self.kx_groups = names.into_iter().collect();
+        self
+    }
+
+    /// Resumes sessions from tickets the client keeps rather than the server, which survive
+    /// the server's session cache filling up. Ticket keys are made at start and rotated
+    /// every few hours.
+    pub fn session_tickets(mut self, tickets: bool) -> Self {
+        self.tickets = tickets;
+        self
+    }
+
+    /// Lets clients resume earlier sessions, saving a full handshake when they reconnect.
+    pub fn resumption(mut self, resumption: bool) -> Self {
+        self.resumption = resumption;
+        self
+    }
+
+    /// The crypto provider and protocol versions the policy allows, or why it can't work.
+    fn provider(&self) -> anyhow::Result<(CryptoProvider, Vec<&'static SupportedProtocolVersion>)> {
+        let default = ring::default_provider();
+        let mut provider = default.clone();
+        if !self.cipher_suites.is_empty() {
+            provider.cipher_suites = pick(
+                &default.cipher_suites,
+                &self.cipher_suites,
+                "cipher suite",
+                |suite| format!("{:?}", suite.suite()),
+            )?;
+        }
+        if !self.kx_groups.is_empty() {
+            provider.kx_groups = pick(
+                &default.kx_groups,
+                &self.kx_groups,
+                "key exchange group",
+                |group| format!("{:?}", group.name()),
+            )?;
+        }
+        provider
+            .cipher_suites
+            .retain(|suite| TlsVersion::of(suite.version()) >= self.min_version);
+        if provider.cipher_suites.is_empty() {
+            bail!(
+                "none of the TLS cipher suites can be used with TLS {} and up",
+                match self.min_version {
+                    TlsVersion::Tls12 => "1.2",
+                    TlsVersion::Tls13 => "1.3",
+                }
+            );
+        }
+        let versions: Vec<_> = [&version::TLS12, &version::TLS13]
+            .into_iter()
+            .filter(|version| {
+                provider
+                    .cipher_suites
+                    .iter()
+                    .any(|suite| suite.version() == *version)
+            })
+            .collect();
+        if self.tickets && !self.resumption {
+            bail!("TLS session tickets are a way of resuming sessions, which is turned off");
+        }
+        Ok((provider, versions))
+    }
+
+    /// Checks that the policy can work, without setting up TLS.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.provider().map(drop)
+    }
+}
+
+/// The items of `available` with the `wanted` names, in the order they were asked for.
+fn pick<T: Clone>(
+    available: &[T],
+    wanted: &[String],
+    what: &str,
+    name: impl Fn(&T) -> String,
+) -> anyhow::Result<Vec<T>> {
+    wanted
+        .iter()
+        .map(|wanted| {
+            available
+                .iter()
+                .find(|item| name(item).eq_ignore_ascii_case(wanted))
+                .cloned()
+                .with_context(|| {
+                    let known: Vec<_> = available.iter().map(&name).collect();
+                    format!(
+                        "unknown TLS {what} {wanted}, expected one of {}",
+                        known.join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone)]
+enum Certificate {
+    Files { cert: PathBuf, key: PathBuf },
+    Resolver(Arc<dyn ResolvesServerCert>),
+}
+
+impl TlsConfig {
+    /// A PEM certificate chain, leaf first, and the PEM private key (PKCS#8, PKCS#1 or SEC1)
+    /// that goes with it.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self::with_certificate(Certificate::Files {
+            cert: cert.into(),
+            key: key.into(),
+        })
+    }
+
+    /// Picks the certificate for every handshake with `resolver`, which can change it at runtime.
+    pub fn from_resolver(resolver: Arc<dyn ResolvesServerCert>) -> Self {
+        Self::with_certificate(Certificate::Resolver(resolver))
+    }
+
+    fn with_certificate(certificate: Certificate) -> Self {
+        Self {
+            certificate,
+            client_ca: None,
+            policy: TlsPolicy::default(),
+            extra_alpn: Vec::new(),
+        }
+    }
+
+    /// Requires mutual TLS: handshakes only succeed with a client certificate issued by one of
+    /// the CAs in this PEM bundle.
+    pub fn client_ca(mut self, bundle: impl Into<PathBuf>) -> Self {
+        self.client_ca = Some(bundle.into());
+        self
+    }
+
+    pub fn policy(mut self, policy: TlsPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Loads the certificates and keys.
+    pub fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+        let (provider, versions) = self.policy.provider()?;
+        let provider = Arc::new(provider);
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(&versions)
+            .context("setting up TLS")?;
+        let builder = match &self.client_ca {
+            Some(bundle) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(bundle)? {
+                    roots
+                        .add(cert)
+                        .with_context(|| format!("adding a CA from {}", bundle.display()))?;
+                }
+                let verifier = WebPkiClientVerifier::builder_with_provider(roots.into(), provider)
+                    .build()
+                    .context("setting up client certificate verification")?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = match &self.certificate {
+            Certificate::Files { cert, key } => {
+                let key = PrivateKeyDer::from_pem_file(key)
+                    .with_context(|| format!("reading private key from {}", key.display()))?;
+                builder
+                    .with_single_cert(read_certs(cert)?, key)
+                    .context("the private key doesn't fit the certificate")?
+            }
+            Certificate::Resolver(resolver) => builder.with_cert_resolver(resolver.clone()),
+        };
+        if self.policy.tickets {
+            config.ticketer = ring::Ticketer::new().context("making TLS session ticket keys")?;
+        }
+        if !self.policy.resumption {
+            config.session_storage = Arc::new(NoServerSessionStorage {});
+            config.send_tls13_tickets = 0;
+        }
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        config
+            .alpn_protocols
+            .extend(self.extra_alpn.iter().cloned());
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+/// Pairs a PEM certificate chain with its PEM private key, ready to be handed to rustls.
+pub fn certified_key(chain_pem: &[u8], key_pem: &[u8]) -> anyhow::Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_slice_iter(chain_pem)
+        .collect::<Result<Vec<_>, _>>()
+        .context("reading certificates")?;
+    if chain.is_empty() {
+        bail!("no certificates found");
+    }
+    let key = PrivateKeyDer::from_pem_slice(key_pem).context("reading private key")?;
+    let key = ring::sign::any_supported_type(&key).context("unsupported private key")?;
+    let certified = CertifiedKey::new(chain, key);
+    certified
+        .keys_match()
+        .context("the private key doesn't fit the certificate")?;
+    Ok(Arc::new(certified))
+}
+
+/// A certificate and key read from files that can be read again while the server runs, for
+/// [`TlsConfig::from_resolver`]. Handshakes after a [`reload`](Self::reload) get the new
+/// certificate; connections already established carry on with the one they started with.
+#[derive(Debug)]
+pub struct CertificateFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateFiles {
+    pub fn load(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let (cert, key) = (cert.into(), key.into());
+        let current = RwLock::new(read_certified_key(&cert, &key)?);
+        Ok(Self { cert, key, current })
+    }
+
+    /// The files to watch for changes.
+    pub fn paths(&self) -> [&Path; 2] {
+        [&self.cert, &self.key]
+    }
+
+    /// Reads the files again. If they don't make a valid pair, say because only one of them
+    /// has been replaced so far, the certificate in use stays.
+    pub fn reload(&self) -> anyhow::Result<()> {
+        let certified = read_certified_key(&self.cert, &self.key)?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = certified;
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertificateFiles {
+    fn resolve(&self, _: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(
+            self.current
+                .read()
+                .unwrap_or_else(|e| e.into_inner())
+                .clone(),
+        )
+    }
+}
+
+/// Picks the certificate by the host name clients ask for with SNI, so one listener can serve
+/// HTTPS for several domains. Names match like [`VirtualHosts`] does, case-insensitively.
+///
+/// [`VirtualHosts`]: crate::vhost::VirtualHosts
+#[derive(Debug)]
+pub struct SniCertificates {
+    hosts: Vec<(String, Arc<dyn ResolvesServerCert>)>,
+    default: Option<Arc<dyn ResolvesServerCert>>,
+}
+
+impl SniCertificates {
+    /// `default` answers handshakes for unregistered names or without SNI; with none, those
+    /// handshakes fail.
+    pub fn new(default: Option<Arc<dyn ResolvesServerCert>>) -> Self {
+        Self {
+            hosts: Vec::new(),
+            default,
+        }
+    }
+
+    pub fn host(mut self, host: &str, certificate: Arc<dyn ResolvesServerCert>) -> Self {
+        self.hosts.push((host.to_ascii_lowercase(), certificate));
+        self
+    }
+}
+
+impl ResolvesServerCert for SniCertificates {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let certificate = hello
+            .server_name()
+            .and_then(|name| {
+                self.hosts
+                    .iter()
+                    .find(|(host, _)| host.eq_ignore_ascii_case(name))
+            })
+            .map(|(_, certificate)| certificate)
+            .or(self.default.as_ref())?
+            .clone();
+        certificate.resolve(hello)
+    }
+}
+
+fn read_certified_key(cert: &Path, key: &Path) -> anyhow::Result<Arc<CertifiedKey>> {
+    let chain_pem = std::fs::read(cert).with_context(|| format!("reading {}", cert.display()))?;
+    let key_pem = std::fs::read(key).with_context(|| format!("reading {}", key.display()))?;
+    certified_key(&chain_pem, &key_pem)
+        .with_context(|| format!("loading {} and {}", cert.display(), key.display()))
+}
+
+fn read_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .with_context(|| format!("reading certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        bail!("no certificates in {}", path.display());
+    }
+    Ok(certs)
+}
+
+/// A subject alternative name of a [`ClientCertificate`], of the kinds that name a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AltName {
+    Dns(String),
+    Email(String),
+    /// Like a SPIFFE ID, `spiffe://example.org/billing`.
+    Uri(String),
+    Ip(IpAddr),
+}
+
+/// As OpenSSL prints them, like `DNS:billing.internal` or `IP Address:10.0.0.7`.
+impl fmt::Display for AltName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AltName::Dns(name) => write!(f, "DNS:{name}"),
+            AltName::Email(email) => write!(f, "email:{email}"),
+            AltName::Uri(uri) => write!(f, "URI:{uri}"),
+            AltName::Ip(ip) => write!(f, "IP Address:{ip}"),
+        }
+    }
+}
+
+/// The verified certificate a client presented over mutual TLS. It's in the extensions of every
+/// request on the connection, and handlers can take it as an extractor, which refuses
+/// requests without one with a 403; take an `Option<ClientCertificate>` where it's optional.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    subject: String,
+    common_name: Option<String>,
+    alt_names: Vec<AltName>,
+    chain: Arc<[CertificateDer<'static>]>,
+}
+
+impl ClientCertificate {
+    pub(crate) fn from_chain(chain: &[CertificateDer<'static>]) -> Option<Self> {
+        let (_, leaf) = X509Certificate::from_der(chain.first()?).ok()?;
+        let common_name = leaf.subject().iter_common_name().next();
+        let common_name = common_name
+            .and_then(|cn| cn.as_str().ok())
+            .map(str::to_owned);
+        // A malformed extension leaves no names rather than refusing a certificate the
+        // verifier took.
+        let alt_names = match leaf.subject_alternative_name() {
+            Ok(Some(san)) => san
+                .value
+                .general_names
+                .iter()
+                .filter_map(alt_name)
+                .collect(),
+            _ => Vec::new(),
+        };
+        Some(Self {
+            subject: leaf.subject().to_string(),
+            common_name,
+            alt_names,
+            chain: chain.into(),
+        })
+    }
+
+    /// The subject's distinguished name, like `CN=billing, O=Example Corp`.
+    pub fn subject(&self) -> &str {
+        &self.subject
+    }
+
+    /// The subject's common name, the first if it has several.
+    pub fn common_name(&self) -> Option<&str> {
+        self.common_name.as_deref()
+    }
+
+    /// The subject alternative names, in the certificate's order.
+    pub fn alt_names(&self) -> &[AltName] {
+        &self.alt_names
+    }
+
+    /// The certificates as the client sent them, its own first.
+    pub fn chain(&self) -> &[CertificateDer<'static>] {
+        &self.chain
+    }
+}
+
+fn alt_name(name: &GeneralName) -> Option<AltName> {
+    match name {
+        GeneralName::DNSName(name) => Some(AltName::Dns((*name).to_owned())),
+        GeneralName::RFC822Name(email) => Some(AltName::Email((*email).to_owned())),
+        GeneralName::URI(uri) => Some(AltName::Uri((*uri).to_owned())),
+        GeneralName::IPAddress(bytes) => match bytes.len() {
+            4 => Some(AltName::Ip(<[u8; 4]>::try_from(*bytes).ok()?.into())),
+            16 => Some(AltName::Ip(<[u8; 16]>::try_from(*bytes).ok()?.into())),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl FromRequest for ClientCertificate {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        let cert = req.extensions.get::<ClientCertificate>().cloned();
+        Box::pin(async move {
+            cert.ok_or_else(|| {
+                HttpError::new(
+                    StatusCode::FORBIDDEN,
+                    anyhow::anyhow!("A client certificate is required"),
+                )
+            })
+        })
+    }
+}
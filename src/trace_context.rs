@@ -0,0 +1,111 @@
+//! W3C Trace Context: the `traceparent` and `tracestate` headers that carry a distributed
+//! trace from one service to the next.
+//!
+//! Every request gets a [`TraceContext`] in its extensions, continuing the trace its
+//! `traceparent` names or starting a new one, with a span ID of its own. Its `traceparent` is
+//! then rewritten to name that span as the parent, so that proxied upstreams and CGI scripts,
+//! which get the request's headers, carry the trace on from this server rather than from
+//! whoever called it. `tracestate` is passed on as it came, and dropped along with a
+//! `traceparent` that doesn't parse.
+
+use crate::{
+    headers::{TRACEPARENT, TRACESTATE},
+    request::Request,
+};
+
+/// Where a request sits in its trace, in its extensions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    /// 32 lowercase hex digits.
+    pub trace_id: String,
+    /// 16 lowercase hex digits, naming the span this server handles the request in.
+    pub span_id: String,
+    /// The span of the caller, for a trace that came with the request.
+    pub parent_id: Option<String>,
+    /// The trace flags; bit 0 is whether the trace is sampled.
+    pub flags: u8,
+    /// The vendor-specific `tracestate`, passed on untouched.
+    pub state: Option<String>,
+}
+
+impl TraceContext {
+    /// The context for `req`, which continues its `traceparent` if it has a valid one. The
+    /// header is set to name the new span as the parent either way.
+    pub(crate) fn assign(req: &mut Request) -> Self {
+        let parent = req.headers.get(TRACEPARENT).and_then(parse_traceparent);
+        let context = match parent {
+            Some((trace_id, parent_id, flags)) => Self {
+                trace_id,
+                span_id: random_hex(16),
+                parent_id: Some(parent_id),
+                flags,
+                state: req
+                    .headers
+                    .get(TRACESTATE)
+                    .map(str::trim)
+                    .filter(|state| !state.is_empty())
+                    .map(str::to_owned),
+            },
+            None => {
+                req.headers.remove(TRACESTATE);
+                Self {
+                    trace_id: random_hex(32),
+                    span_id: random_hex(16),
+                    parent_id: None,
+                    flags: 0x01,
+                    state: None,
+                }
+            }
+        };
+        req.headers.insert(TRACEPARENT, &context.traceparent());
+        req.extensions.insert(context.clone());
+        context
+    }
+
+    /// The `traceparent` naming this server's span as the parent.
+    pub fn traceparent(&self) -> String {
+        format!("00-{}-{}-{:02x}", self.trace_id, self.span_id, self.flags)
+    }
+
+    pub fn sampled(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+}
+
+/// The trace ID, parent ID and flags of a `traceparent`, `None` if it isn't one.
+fn parse_traceparent(value: &str) -> Option<(String, String, u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    if !is_hex(version, 2) || version == "ff" || !is_hex(flags, 2) {
+        return None;
+    }
+    // Later versions may add fields after these, but version 00 has just the four.
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    let all_zeros = |id: &str| id.bytes().all(|b| b == b'0');
+    if !is_hex(trace_id, 32)
+        || all_zeros(trace_id)
+        || !is_hex(parent_id, 16)
+        || all_zeros(parent_id)
+    {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    Some((trace_id.to_owned(), parent_id.to_owned(), flags))
+}
+
+/// Whether `s` is `len` lowercase hex digits, the only case the spec allows.
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// `len` random lowercase hex digits, at most 32.
+fn random_hex(len: usize) -> String {
+    let mut hex = uuid::Uuid::new_v4().simple().to_string();
+    hex.truncate(len);
+    hex
+}
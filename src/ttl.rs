@@ -0,0 +1,55 @@
+//! How long something should be kept, as clients write it: a number of seconds, or a number
+//! with a unit, like `90s`, `15m`, `1h` or `7d`, up to [`MAX`].
+
+use std::{str::FromStr, time::Duration};
+
+use crate::error::HttpError;
+
+/// The longest TTL, ten years, which is forever as far as anything here is concerned.
+pub const MAX: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ttl(pub Duration);
+
+/// What went wrong parsing a [`Ttl`].
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidTtl {
+    #[error("Invalid TTL {0:?}, expected seconds or a number with s, m, h or d after it")]
+    Malformed(String),
+    #[error("The TTL {0:?} is longer than ten years")]
+    TooLong(String),
+}
+
+impl From<InvalidTtl> for HttpError {
+    fn from(e: InvalidTtl) -> Self {
+        HttpError::bad_request(&e.to_string())
+    }
+}
+
+impl FromStr for Ttl {
+    type Err = InvalidTtl;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidTtl::Malformed(s.to_owned());
+        let trimmed = s.trim();
+        let (number, unit) = match trimmed.find(|c: char| !c.is_ascii_digit()) {
+            Some(at) => trimmed.split_at(at),
+            None => (trimmed, "s"),
+        };
+        let number: u64 = number.parse().map_err(|_| invalid())?;
+        let seconds = match unit {
+            "s" => 1,
+            "m" => 60,
+            "h" => 60 * 60,
+            "d" => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        let too_long = || InvalidTtl::TooLong(s.to_owned());
+        let seconds = number.checked_mul(seconds).ok_or_else(too_long)?;
+        match Duration::from_secs(seconds) {
+            Duration::ZERO => Err(invalid()),
+            ttl if ttl > MAX => Err(too_long()),
+            ttl => Ok(Ttl(ttl)),
+        }
+    }
+}
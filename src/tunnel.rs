@@ -0,0 +1,129 @@
+//! Forward proxying of CONNECT tunnels, so clients can reach allowlisted hosts (usually over
+//! TLS) through this server.
+
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+use anyhow::Context;
+use tokio::net::TcpStream;
+use tracing::{info, warn};
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    middleware::{Middleware, Next},
+    request::{Method, Request},
+    response::Response,
+    status::StatusCode,
+    upgrade::OnUpgrade,
+};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A `HOST:PORT` pattern tunnels may be opened to. `HOST` is either `*`, a name, or `*.` and a
+/// domain to match every name below it; `PORT` may be `*` too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllowedTarget {
+    host: String,
+    port: Option<u16>,
+}
+
+impl AllowedTarget {
+    fn matches(&self, host: &str, port: u16) -> bool {
+        let host_matches = match self.host.strip_prefix("*.") {
+            _ if self.host == "*" => true,
+            Some(domain) => host.len().checked_sub(domain.len() + 1).is_some_and(|dot| {
+                host.as_bytes()[dot] == b'.' && host[dot + 1..].eq_ignore_ascii_case(domain)
+            }),
+            None => host.eq_ignore_ascii_case(&self.host),
+        };
+        host_matches && self.port.is_none_or(|allowed| allowed == port)
+    }
+}
+
+impl FromStr for AllowedTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .filter(|(host, _)| !host.is_empty())
+            .ok_or_else(|| format!("expected HOST:PORT, got {s}"))?;
+        let port = match port {
+            "*" => None,
+            port => Some(port.parse().map_err(|_| format!("invalid port in {s}"))?),
+        };
+        Ok(Self {
+            host: host.trim_matches(['[', ']']).to_owned(),
+            port,
+        })
+    }
+}
+
+/// Answers CONNECT requests for allowed targets by splicing the client connection onto a TCP
+/// connection to the target. Other methods pass through.
+#[derive(Clone)]
+pub struct ConnectTunnel {
+    allowed: Arc<[AllowedTarget]>,
+}
+
+impl ConnectTunnel {
+    pub fn new(allowed: impl IntoIterator<Item = AllowedTarget>) -> Self {
+        Self {
+            allowed: allowed.into_iter().collect(),
+        }
+    }
+
+    fn check_target<'a>(&self, target: &'a str) -> Result<(&'a str, u16), HttpError> {
+        let (host, port) = target
+            .rsplit_once(':')
+            .and_then(|(host, port)| Some((host, port.parse::<u16>().ok()?)))
+            .filter(|(host, _)| !host.is_empty() && !host.contains('/'))
+            .ok_or_else(|| HttpError::bad_request("CONNECT target must be HOST:PORT"))?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if !self
+            .allowed
+            .iter()
+            .any(|allowed| allowed.matches(host, port))
+        {
+            warn!("Refusing tunnel to {target}");
+            return Err(HttpError::forbidden());
+        }
+        Ok((host, port))
+    }
+
+    async fn open(&self, mut req: Request) -> Result<Response, HttpError> {
+        let (host, port) = self.check_target(&req.target)?;
+        let target = req.target.clone();
+        let mut upstream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect((host, port)))
+            .await
+            .map_err(|_| HttpError::gateway_timeout())?
+            .with_context(|| format!("connecting to {target}"))
+            .map_err(|e| HttpError::new(StatusCode::BAD_GATEWAY, e))?;
+        let on_upgrade = OnUpgrade::from_request(&mut req)
+            .context("the connection can't be taken over for a tunnel")?;
+
+        tokio::spawn(async move {
+            let mut client = match on_upgrade.await {
+                Ok(client) => client,
+                Err(e) => return warn!("Tunnel to {target} wasn't established: {e}"),
+            };
+            match tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                Ok((sent, received)) => {
+                    info!("Tunnel to {target} closed after {sent} bytes sent, {received} received")
+                }
+                Err(e) => warn!("Tunnel to {target} failed: {e}"),
+            }
+        });
+        Ok(Response::empty(StatusCode::OK))
+    }
+}
+
+impl Middleware for ConnectTunnel {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        if req.method != Method::Connect {
+            return Box::pin(next.run(req));
+        }
+        let tunnel = self.clone();
+        Box::pin(async move { tunnel.open(req).await })
+    }
+}
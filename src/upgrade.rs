@@ -0,0 +1,118 @@
+//! Taking over the connection once the response head is out, for CONNECT tunnels and
+//! `101 Switching Protocols`.
+//!
+//! The server puts an [`OnUpgrade`] into every request's extensions. A handler that wants the
+//! connection takes it out, answers with 101 (or a 2xx to CONNECT), and awaits it: it resolves
+//! to the raw connection right after the head has been written. Any other response, or
+//! dropping the `OnUpgrade`, leaves the connection to the server.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::{oneshot, OwnedMutexGuard},
+};
+
+use crate::{
+    body::BodyReader,
+    request::{Method, Request},
+    response::Response,
+    status::StatusCode,
+};
+
+pub(crate) type BoxWriter = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// The client connection after an upgrade, including anything it sent past the request head.
+pub struct Upgraded {
+    reader: OwnedMutexGuard<BodyReader>,
+    writer: BoxWriter,
+}
+
+impl AsyncRead for Upgraded {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.reader.reader).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Upgraded {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.writer).poll_shutdown(cx)
+    }
+}
+
+/// Resolves to the connection once the upgrade response has been sent.
+pub struct OnUpgrade {
+    rx: oneshot::Receiver<Upgraded>,
+}
+
+impl OnUpgrade {
+    /// Takes the pending upgrade out of a request, `None` if something took it already.
+    pub fn from_request(req: &mut Request) -> Option<Self> {
+        req.extensions.remove::<OnUpgrade>()
+    }
+}
+
+impl Future for OnUpgrade {
+    type Output = io::Result<Upgraded>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.rx).poll(cx).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "the connection wasn't upgraded",
+            )
+        })
+    }
+}
+
+/// The server's side of a pending upgrade.
+pub(crate) struct PendingUpgrade {
+    tx: oneshot::Sender<Upgraded>,
+    connect: bool,
+}
+
+impl PendingUpgrade {
+    pub(crate) fn new(req: &mut Request) -> Self {
+        let (tx, rx) = oneshot::channel();
+        req.extensions.insert(OnUpgrade { rx });
+        Self {
+            tx,
+            connect: req.method == Method::Connect,
+        }
+    }
+
+    /// Whether the handler is waiting for the connection and `response` agrees to hand it over.
+    pub(crate) fn accepted_by(&self, response: &Response) -> bool {
+        let accepted = match self.connect {
+            true => (200..300).contains(&response.status.0),
+            false => response.status == StatusCode::SWITCHING_PROTOCOLS,
+        };
+        accepted && !self.tx.is_closed()
+    }
+
+    pub(crate) fn complete(self, reader: OwnedMutexGuard<BodyReader>, writer: BoxWriter) {
+        // If the handler stopped waiting in the meantime, the connection just gets dropped.
+        let _ = self.tx.send(Upgraded { reader, writer });
+    }
+}
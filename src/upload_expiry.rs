@@ -0,0 +1,201 @@
+//! Uploads that delete themselves: one made with a TTL is noted down with when it expires,
+//! and [`UploadExpiry::spawn`] sweeps away the ones past it. Until the sweep gets to a file,
+//! the routes treat it as already gone.
+//!
+//! Each note has the file's modification time as the upload left it, and only a file that
+//! still has that time is deleted, so one that's been replaced since, by another upload or
+//! anything else, is left alone. With a [data directory](crate::data_dir) the notes are kept
+//! in [`FILE`] in it and survive a restart; the sweep after one catches up with whatever
+//! expired while the server was down.
+
+use std::{
+    collections::HashMap,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::{
+    data_dir::JsonFile,
+    error::HttpError,
+    served_dir::{self, ServedDir},
+};
+
+/// The name of the file the expiry times are kept in.
+pub const FILE: &str = "uploads.json";
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Note {
+    /// The directory the file was uploaded to, as it was served.
+    dir: PathBuf,
+    /// The file, in the directory.
+    path: PathBuf,
+    expires: SystemTime,
+    modified: SystemTime,
+}
+
+pub struct UploadExpiry {
+    file: Option<JsonFile>,
+    notes: Mutex<HashMap<(PathBuf, PathBuf), Note>>,
+}
+
+impl Default for UploadExpiry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UploadExpiry {
+    /// Expiry times kept in memory only, forgotten when the server stops.
+    pub fn new() -> Self {
+        Self {
+            file: None,
+            notes: Mutex::default(),
+        }
+    }
+
+    /// Expiry times kept in [`FILE`] in `dir`, starting with the ones already there.
+    pub fn open(dir: &Path) -> anyhow::Result<Self> {
+        let (file, notes): (_, Vec<Note>) = JsonFile::open(dir, FILE)?;
+        let notes = notes
+            .into_iter()
+            .map(|note| ((note.dir.clone(), note.path.clone()), note))
+            .collect();
+        Ok(Self {
+            file: Some(file),
+            notes: Mutex::new(notes),
+        })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<(PathBuf, PathBuf), Note>> {
+        self.notes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn changed(&self) {
+        if let Some(file) = &self.file {
+            file.changed();
+        }
+    }
+
+    /// Notes that the file just uploaded to `path` in `dir` expires at `expires`, or never,
+    /// with `None`, saving the change before it returns.
+    pub async fn set(
+        &self,
+        dir: &ServedDir,
+        path: &Path,
+        expires: Option<SystemTime>,
+    ) -> Result<(), HttpError> {
+        let key = (dir.root().to_owned(), path.to_owned());
+        match expires {
+            Some(expires) => {
+                let metadata = dir
+                    .metadata(path)
+                    .await
+                    .map_err(|e| served_dir::failed(e, "reading the upload's metadata"))?;
+                let modified = metadata
+                    .modified()
+                    .context("reading the upload's metadata")?;
+                let note = Note {
+                    dir: key.0.clone(),
+                    path: key.1.clone(),
+                    expires,
+                    modified,
+                };
+                self.lock().insert(key, note);
+                self.changed();
+            }
+            None => {
+                if self.lock().remove(&key).is_some() {
+                    self.changed();
+                }
+            }
+        }
+        self.save().await.context("saving upload expiry times")?;
+        Ok(())
+    }
+
+    /// When the file at `path` in `dir` expires, if it was uploaded with a TTL and `metadata`
+    /// shows it's the same file.
+    pub fn expires(&self, dir: &ServedDir, path: &Path, metadata: &Metadata) -> Option<SystemTime> {
+        let notes = self.lock();
+        let note = notes.get(&(dir.root().to_owned(), path.to_owned()))?;
+        let modified = metadata.modified().ok()?;
+        (note.modified == modified).then_some(note.expires)
+    }
+
+    /// Deletes the files that have expired, returning how many there were.
+    pub async fn sweep(&self) -> usize {
+        let now = SystemTime::now();
+        let expired: Vec<_> = {
+            let notes = self.lock();
+            let notes = notes.values();
+            notes.filter(|note| note.expires <= now).cloned().collect()
+        };
+        let mut deleted = 0;
+        for note in expired {
+            // Nothing it deletes belongs outside the directory, whatever `--confine` says.
+            let dir = ServedDir::new(&note.dir).confine(true);
+            let removed = match dir.metadata(&note.path).await {
+                Ok(metadata) if metadata.modified().ok() == Some(note.modified) => {
+                    dir.remove_file(&note.path).await.map(|()| true)
+                }
+                // Replaced since, so no longer the upload that expired.
+                Ok(_) => Ok(false),
+                Err(e) => Err(e),
+            };
+            match removed {
+                Ok(removed) => deleted += usize::from(removed),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(
+                        "Couldn't delete expired upload {}: {e}",
+                        note.dir.join(&note.path).display()
+                    );
+                    continue;
+                }
+            }
+            let mut notes = self.lock();
+            let key = (note.dir.clone(), note.path.clone());
+            // Unless it was uploaded again while this was deleting.
+            if notes.get(&key) == Some(&note) {
+                notes.remove(&key);
+                self.changed();
+            }
+        }
+        if deleted > 0 {
+            info!("Deleted {deleted} expired uploads");
+        }
+        if let Err(e) = self.save().await {
+            warn!("Couldn't save upload expiry times: {e:#}");
+        }
+        deleted
+    }
+
+    async fn save(&self) -> anyhow::Result<()> {
+        match &self.file {
+            Some(file) => {
+                let notes = || self.lock().values().cloned().collect::<Vec<_>>();
+                file.save(notes).await
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Sweeps every `every` until the runtime shuts down, starting now.
+    pub fn spawn(self: &Arc<Self>, every: Duration) {
+        let expiry = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(every);
+            loop {
+                ticks.tick().await;
+                expiry.sweep().await;
+            }
+        });
+    }
+}
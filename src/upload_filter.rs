@@ -0,0 +1,200 @@
+//! Which files may be uploaded, by their name's extension and the `Content-Type` they're sent
+//! with, checked before any of the body is read, so a refused upload never touches the disk.
+//!
+//! A name with an extension that isn't allowed, or any denied one, gets a 403: every
+//! extension of the name counts for the denied ones, so with `php` denied, `shell.php.jpg`,
+//! which some servers would still run as PHP, is refused too. A `Content-Type` that isn't
+//! allowed, or a missing one while only some are, gets a 415. Both say why in the body.
+
+use tracing::warn;
+
+use crate::{
+    error::HttpError,
+    handler::BoxFuture,
+    headers::CONTENT_TYPE,
+    middleware::{Middleware, Next},
+    request::{Method, Request},
+    response::Response,
+    safe_path,
+    status::StatusCode,
+};
+
+/// Refuses `POST` and `PUT` uploads under some prefixes whose name or type isn't allowed.
+#[derive(Debug, Clone, Default)]
+pub struct UploadFilter {
+    prefixes: Vec<String>,
+    allow_extensions: Vec<String>,
+    deny_extensions: Vec<String>,
+    allow_types: Vec<String>,
+    deny_types: Vec<String>,
+}
+
+fn lowercase<S: AsRef<str>>(values: impl IntoIterator<Item = S>) -> Vec<String> {
+    values
+        .into_iter()
+        .map(|value| value.as_ref().trim().to_ascii_lowercase())
+        .collect()
+}
+
+/// Extensions, lowercased and without the dot they may have been written with.
+fn without_dots<S: AsRef<str>>(extensions: impl IntoIterator<Item = S>) -> Vec<String> {
+    let extensions = extensions.into_iter();
+    lowercase(extensions.map(|e| e.as_ref().trim().trim_start_matches('.').to_owned()))
+}
+
+impl UploadFilter {
+    /// Checks uploads to paths under any of `prefixes`, allowing everything until told
+    /// otherwise.
+    pub fn new<S: Into<String>>(prefixes: impl IntoIterator<Item = S>) -> Self {
+        Self {
+            prefixes: prefixes.into_iter().map(Into::into).collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Only allows names ending in one of these extensions, like `jpg` or `.pdf`.
+    pub fn allow_extensions<S: AsRef<str>>(
+        mut self,
+        extensions: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.allow_extensions = without_dots(extensions);
+        self
+    }
+
+    /// Refuses names with any of these extensions anywhere in them.
+    pub fn deny_extensions<S: AsRef<str>>(
+        mut self,
+        extensions: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.deny_extensions = without_dots(extensions);
+        self
+    }
+
+    /// Only allows these types, each like `image/png`, or `image/*` for all images.
+    pub fn allow_types<S: AsRef<str>>(mut self, types: impl IntoIterator<Item = S>) -> Self {
+        self.allow_types = lowercase(types);
+        self
+    }
+
+    /// Refuses these types, written like the allowed ones.
+    pub fn deny_types<S: AsRef<str>>(mut self, types: impl IntoIterator<Item = S>) -> Self {
+        self.deny_types = lowercase(types);
+        self
+    }
+
+    /// Refuses a file called `name` sent as `content_type` like an upload to a path with that
+    /// name, for uploads that come some other way, like the files of a form.
+    pub fn check(&self, name: &str, content_type: Option<&str>) -> Result<(), HttpError> {
+        let refused = match self.check_file_name(name) {
+            Err(why) => Some((StatusCode::FORBIDDEN, why)),
+            Ok(()) => self
+                .check_type(content_type)
+                .err()
+                .map(|why| (StatusCode::UNSUPPORTED_MEDIA_TYPE, why)),
+        };
+        match refused {
+            Some((status, why)) => {
+                warn!("Refused the upload of {name}: {why}");
+                Err(HttpError::new(status, anyhow::Error::msg(why)))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn check_name(&self, path: &str) -> Result<(), String> {
+        // What doesn't resolve, the handler refuses anyway.
+        let Ok(path) = safe_path::resolve(path) else {
+            return Ok(());
+        };
+        let name = path.file_name().unwrap_or_default();
+        self.check_file_name(&name.to_string_lossy())
+    }
+
+    fn check_file_name(&self, name: &str) -> Result<(), String> {
+        let name = name.to_ascii_lowercase();
+        // A leading dot starts a hidden file's name rather than an extension.
+        let mut extensions = name.trim_start_matches('.').split('.').skip(1);
+        if let Some(denied) = extensions.find(|e| self.deny_extensions.iter().any(|d| d == e)) {
+            return Err(format!(
+                "Files with the extension .{denied} can't be uploaded"
+            ));
+        }
+        if self.allow_extensions.is_empty() {
+            return Ok(());
+        }
+        let last = name
+            .trim_start_matches('.')
+            .rsplit_once('.')
+            .map(|(_, extension)| extension);
+        match last {
+            Some(extension) if self.allow_extensions.iter().any(|a| a == extension) => Ok(()),
+            _ => Err(format!(
+                "Only files ending in .{} can be uploaded",
+                self.allow_extensions.join(", .")
+            )),
+        }
+    }
+
+    fn check_type(&self, content_type: Option<&str>) -> Result<(), String> {
+        let essence = content_type.map(|value| {
+            let essence = value.split(';').next().unwrap_or_default();
+            essence.trim().to_ascii_lowercase()
+        });
+        let essence = essence.filter(|essence| !essence.is_empty());
+        if let Some(essence) = &essence {
+            if self
+                .deny_types
+                .iter()
+                .any(|pattern| is_of_type(essence, pattern))
+            {
+                return Err(format!("Files of type {essence} can't be uploaded"));
+            }
+        }
+        if self.allow_types.is_empty() {
+            return Ok(());
+        }
+        let allowed = self.allow_types.join(", ");
+        match essence {
+            Some(essence) if self.allow_types.iter().any(|p| is_of_type(&essence, p)) => Ok(()),
+            Some(essence) => Err(format!(
+                "Files of type {essence} can't be uploaded, only {allowed}"
+            )),
+            None => Err(format!("Uploads need a Content-Type, one of {allowed}")),
+        }
+    }
+}
+
+/// Whether `essence` is of the type `pattern`, which can end in `/*`.
+fn is_of_type(essence: &str, pattern: &str) -> bool {
+    match pattern.strip_suffix("/*") {
+        Some(kind) => essence
+            .strip_prefix(kind)
+            .is_some_and(|rest| rest.starts_with('/')),
+        None => pattern == essence,
+    }
+}
+
+impl Middleware for UploadFilter {
+    fn handle(&self, req: Request, next: Next) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let uploads = matches!(req.method, Method::Post | Method::Put)
+            && self
+                .prefixes
+                .iter()
+                .any(|prefix| req.path.starts_with(prefix.as_str()));
+        if !uploads {
+            return Box::pin(next.run(req));
+        }
+        let refused = match self.check_name(&req.path) {
+            Err(why) => Some((StatusCode::FORBIDDEN, why)),
+            Ok(()) => self
+                .check_type(req.header(CONTENT_TYPE))
+                .err()
+                .map(|why| (StatusCode::UNSUPPORTED_MEDIA_TYPE, why)),
+        };
+        let Some((status, why)) = refused else {
+            return Box::pin(next.run(req));
+        };
+        warn!("Refused the upload of {}: {why}", req.path);
+        Box::pin(async move { Err(HttpError::new(status, anyhow::Error::msg(why))) })
+    }
+}
@@ -0,0 +1,161 @@
+//! Scanning uploads, with a virus scanner or anything else that can look at a file, before
+//! they're put where anyone can download them.
+//!
+//! Uploads are written to a temporary file next to where they're going, and renamed into
+//! place only once they're complete (see [`ServedDir::write`](crate::served_dir::ServedDir::write)).
+//! With an [`UploadScan`], the complete temporary file is handed to its [`UploadScanner`] in
+//! between: an upload it rejects is deleted and answered with a 422. A scanner that fails or
+//! takes too long leaves the upload refused with a 503, or, failing open, kept anyway.
+//!
+//! [`CommandScanner`] runs a command on the file, like `clamdscan --no-summary --fdpass`;
+//! other scanners, like one posting the file to a scanning service, implement the trait.
+
+use std::{fmt, path::Path, process::Stdio, sync::Arc, time::Duration};
+
+use anyhow::Context as _;
+use tracing::warn;
+
+use crate::{error::HttpError, handler::BoxFuture, status::StatusCode};
+
+/// What a scanner made of a file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    Clean,
+    /// Not to be kept, with why, like the name of the virus found.
+    Rejected(String),
+}
+
+/// Looks at complete uploads. An error means the file couldn't be scanned, not that it
+/// failed the scan.
+pub trait UploadScanner: Send + Sync + 'static {
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<Verdict>>;
+}
+
+/// Runs a command with the file's path as its last argument, going by its exit status the
+/// way `clamscan` and `clamdscan` set it: 0 for clean, 1 for rejected, anything else for an
+/// error. What it prints on rejecting is the reason.
+#[derive(Debug, Clone)]
+pub struct CommandScanner {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandScanner {
+    /// `command` is split on whitespace into the program and its arguments.
+    pub fn new(command: &str) -> anyhow::Result<Self> {
+        let mut words = command.split_whitespace().map(str::to_owned);
+        let program = words.next().context("the scan command is empty")?;
+        Ok(Self {
+            program,
+            args: words.collect(),
+        })
+    }
+}
+
+impl UploadScanner for CommandScanner {
+    fn scan<'a>(&'a self, path: &'a Path) -> BoxFuture<'a, anyhow::Result<Verdict>> {
+        Box::pin(async move {
+            let output = tokio::process::Command::new(&self.program)
+                .args(&self.args)
+                .arg(path)
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .output()
+                .await
+                .with_context(|| format!("running {}", self.program))?;
+            match output.status.code() {
+                Some(0) => Ok(Verdict::Clean),
+                Some(1) => {
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+                    let reason = stdout.lines().find(|line| !line.trim().is_empty());
+                    let reason = reason.unwrap_or("rejected by the scanner");
+                    // clamdscan starts its lines with the path, which isn't the client's.
+                    let reason = reason.rsplit(": ").next().unwrap_or(reason).trim();
+                    Ok(Verdict::Rejected(reason.to_owned()))
+                }
+                _ => anyhow::bail!(
+                    "{} exited with {}: {}",
+                    self.program,
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            }
+        })
+    }
+}
+
+/// Scans uploads with a scanner, refusing the ones it rejects.
+#[derive(Clone)]
+pub struct UploadScan {
+    scanner: Arc<dyn UploadScanner>,
+    timeout: Duration,
+    fail_open: bool,
+}
+
+impl fmt::Debug for UploadScan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UploadScan")
+            .field("timeout", &self.timeout)
+            .field("fail_open", &self.fail_open)
+            .finish_non_exhaustive()
+    }
+}
+
+impl UploadScan {
+    /// Gives `scanner` 30 seconds a file, refusing uploads it can't scan.
+    pub fn new(scanner: impl UploadScanner) -> Self {
+        Self {
+            scanner: Arc::new(scanner),
+            timeout: Duration::from_secs(30),
+            fail_open: false,
+        }
+    }
+
+    /// How long the scanner gets before it counts as failed.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Keeps uploads the scanner fails on, rather than refusing them.
+    pub fn fail_open(mut self, fail_open: bool) -> Self {
+        self.fail_open = fail_open;
+        self
+    }
+
+    /// Scans the complete upload at `path`, on disk, for `name`, where it's going.
+    pub async fn check(&self, path: &Path, name: &Path) -> Result<(), HttpError> {
+        let scanned = tokio::time::timeout(self.timeout, self.scanner.scan(path)).await;
+        let error = match scanned {
+            Ok(Ok(Verdict::Clean)) => return Ok(()),
+            Ok(Ok(Verdict::Rejected(reason))) => {
+                warn!("Rejected the upload of {}: {reason}", name.display());
+                return Err(HttpError::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    anyhow::anyhow!("The upload was rejected: {reason}"),
+                ));
+            }
+            Ok(Err(e)) => e,
+            Err(_) => anyhow::anyhow!("timed out after {}s", self.timeout.as_secs()),
+        };
+        match self.fail_open {
+            true => {
+                warn!(
+                    "Keeping the upload of {} unscanned, as scanning failed: {error:#}",
+                    name.display()
+                );
+                Ok(())
+            }
+            false => {
+                warn!(
+                    "Refusing the upload of {}, as scanning failed: {error:#}",
+                    name.display()
+                );
+                Err(HttpError::new(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    anyhow::anyhow!("The upload couldn't be scanned"),
+                ))
+            }
+        }
+    }
+}
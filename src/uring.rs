@@ -0,0 +1,182 @@
+//! Reading the files served through io_uring, instead of `tokio::fs` which does every read on
+//! its blocking thread pool. Reads are queued on one ring from any thread and completed by a
+//! thread of its own; writing the data to the client stays with Tokio. The next part of a file
+//! is read while the last one is being sent.
+//!
+//! With `cargo bench --bench file_throughput` (eight clients downloading a 16 MiB file from the
+//! page cache) on a one-core VM, this did 1695 MB/s against 929 MB/s on the thread pool. Part
+//! of that is reading 64 KiB at a time where `tokio::io::copy` goes 8 KiB at a time, the rest
+//! is the thread hops saved.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Seek},
+    os::fd::AsRawFd,
+    sync::{Arc, Mutex, OnceLock},
+    thread,
+};
+
+use io_uring::{opcode, types, IoUring};
+use tokio::{
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::oneshot,
+};
+use tracing::warn;
+
+/// Submission queue entries; more reads than that at once wait for the kernel to take some.
+const ENTRIES: u32 = 256;
+/// How much of a file one read asks for.
+const READ_SIZE: u64 = 64 * 1024;
+
+/// The ring every file read goes through.
+pub struct Ring {
+    ring: IoUring,
+    /// Reads in flight by ID, also guarding the submission queue every thread pushes onto.
+    pending: Mutex<Pending>,
+}
+
+#[derive(Default)]
+struct Pending {
+    reads: HashMap<u64, Read>,
+    next_id: u64,
+}
+
+/// What a read in flight needs kept alive until the kernel is done with it, whether or not
+/// anyone still waits for it.
+struct Read {
+    buf: Vec<u8>,
+    _file: Arc<File>,
+    done: oneshot::Sender<io::Result<Vec<u8>>>,
+}
+
+impl Ring {
+    /// The process-wide ring, set up on first use. `None` if the kernel won't give us one
+    /// (it's too old, or io_uring is disabled), in which case files are read the usual way.
+    pub fn shared() -> Option<&'static Ring> {
+        static RING: OnceLock<Option<Ring>> = OnceLock::new();
+        let ring = RING.get_or_init(|| match IoUring::new(ENTRIES) {
+            Ok(ring) => Some(Ring {
+                ring,
+                pending: Mutex::default(),
+            }),
+            Err(e) => {
+                warn!("io_uring isn't available, reading files on the thread pool: {e}");
+                None
+            }
+        });
+        let ring = ring.as_ref()?;
+        static COMPLETER: OnceLock<()> = OnceLock::new();
+        COMPLETER.get_or_init(|| {
+            thread::Builder::new()
+                .name("io-uring".to_owned())
+                .spawn(|| ring.complete())
+                .expect("starting the io_uring thread");
+        });
+        Some(ring)
+    }
+
+    /// Sends `len` bytes of `file`, from where it's positioned, to `stream`.
+    pub async fn copy_file(
+        &self,
+        file: tokio::fs::File,
+        len: u64,
+        stream: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<()> {
+        let mut file = file.into_std().await;
+        let mut offset = file.stream_position()?;
+        let end = offset + len;
+        let file = Arc::new(file);
+        let mut next = self.read_at(&file, offset, end)?;
+        while let Some(read) = next {
+            let buf = read
+                .await
+                .unwrap_or_else(|_| Err(io::Error::other("the io_uring thread stopped")))?;
+            if buf.is_empty() {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            offset += buf.len() as u64;
+            next = self.read_at(&file, offset, end)?;
+            stream.write_all(&buf).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues a read of the file from `offset` up to `end`, at most [`READ_SIZE`] of it;
+    /// `None` once there's nothing left.
+    fn read_at(
+        &self,
+        file: &Arc<File>,
+        offset: u64,
+        end: u64,
+    ) -> io::Result<Option<oneshot::Receiver<io::Result<Vec<u8>>>>> {
+        if offset >= end {
+            return Ok(None);
+        }
+        let len = (end - offset).min(READ_SIZE) as u32;
+        let mut buf = vec![0; len as usize];
+        let (done, result) = oneshot::channel();
+
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let id = pending.next_id;
+        pending.next_id += 1;
+        let entry = opcode::Read::new(types::Fd(file.as_raw_fd()), buf.as_mut_ptr(), len)
+            .offset(offset)
+            .build()
+            .user_data(id);
+        // Moving the Vec leaves its heap buffer where the entry points.
+        let read = Read {
+            buf,
+            _file: file.clone(),
+            done,
+        };
+        pending.reads.insert(id, read);
+        loop {
+            // SAFETY: the submission queue is only touched with `pending` locked, and the
+            // buffer and file the entry refers to stay in `pending` until it completes.
+            let pushed = unsafe { self.ring.submission_shared().push(&entry) };
+            if pushed.is_ok() {
+                break;
+            }
+            // Full: have the kernel take what's queued to make room.
+            self.ring.submit()?;
+        }
+        // On failure, the read stays pending: the completion thread submits it eventually.
+        self.ring.submit()?;
+        Ok(Some(result))
+    }
+
+    /// Waits for reads to complete and hands their results over, forever.
+    fn complete(&self) {
+        loop {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    warn!("Error waiting for io_uring completions: {e}");
+                    thread::sleep(std::time::Duration::from_millis(100));
+                    continue;
+                }
+            }
+            // SAFETY: this thread is the only one touching the completion queue.
+            let completed = unsafe { self.ring.completion_shared() }
+                .map(|entry| (entry.user_data(), entry.result()))
+                .collect::<Vec<_>>();
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            for (id, result) in completed {
+                let Some(Read { mut buf, done, .. }) = pending.reads.remove(&id) else {
+                    continue;
+                };
+                let result = match usize::try_from(result) {
+                    Ok(n) => {
+                        buf.truncate(n);
+                        Ok(buf)
+                    }
+                    Err(_) => Err(io::Error::from_raw_os_error(-result)),
+                };
+                // Nobody's waiting if the client went away mid-download.
+                let _ = done.send(result);
+            }
+        }
+    }
+}
@@ -0,0 +1,50 @@
+//! Name-based virtual hosting: one router per site, picked by the request's Host header.
+
+use std::sync::Arc;
+
+use crate::{
+    error::HttpError,
+    handler::{BoxFuture, Handler},
+    headers::Host,
+    request::Request,
+    response::Response,
+    router::Router,
+};
+
+pub struct VirtualHosts {
+    hosts: Vec<(String, Arc<Router>)>,
+    default: Arc<Router>,
+}
+
+impl VirtualHosts {
+    /// `default` serves requests whose host isn't registered, or that don't name one at all.
+    pub fn new(default: Arc<Router>) -> Self {
+        Self {
+            hosts: Vec::new(),
+            default,
+        }
+    }
+
+    /// Serves requests for `host` (matched case-insensitively, port ignored) with `router`.
+    pub fn host(mut self, host: &str, router: Arc<Router>) -> Self {
+        self.hosts.push((host.to_ascii_lowercase(), router));
+        self
+    }
+
+    fn select(&self, req: &Request) -> &Arc<Router> {
+        let Ok(Some(host)) = req.headers.typed_get::<Host>() else {
+            return &self.default;
+        };
+        let hostname = host.hostname();
+        self.hosts
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(hostname))
+            .map_or(&self.default, |(_, router)| router)
+    }
+}
+
+impl Handler for VirtualHosts {
+    fn call(&self, req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        self.select(&req).call(req)
+    }
+}
@@ -0,0 +1,195 @@
+//! Handlers implemented as WebAssembly modules, loaded at startup.
+//!
+//! A plugin exports its `memory` plus two functions:
+//!
+//! - `alloc(len: i32) -> i32` returns a buffer of `len` bytes the host can write to.
+//! - `handle(req: i32, req_len: i32, body: i32, body_len: i32) -> i64` handles a request whose
+//!   JSON head (`{"method", "path", "query", "headers": [[name, value]...]}`) and raw body the
+//!   host wrote into two `alloc`ed buffers. It returns `ptr << 32 | len` of a buffer holding a
+//!   little-endian `u32` length, the JSON response head (`{"status", "headers"}`), then the
+//!   raw response body.
+//!
+//! Each request runs in a fresh instance, with its own fuel and memory budget.
+
+use std::path::Path;
+
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use wasmtime::{Config, Engine, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{
+    body::Body,
+    error::HttpError,
+    extract::DEFAULT_BODY_LIMIT,
+    handler::{BoxFuture, Handler},
+    request::Request,
+    response::Response,
+    status::StatusCode,
+};
+
+/// Limits applied to every invocation of a plugin.
+#[derive(Debug, Clone, Copy)]
+pub struct PluginLimits {
+    /// Roughly the number of wasm instructions a request may execute.
+    pub fuel: u64,
+    pub max_memory: usize,
+}
+
+impl Default for PluginLimits {
+    fn default() -> Self {
+        Self {
+            fuel: 100_000_000,
+            max_memory: 64 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RequestHead<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    headers: Vec<(&'a str, &'a str)>,
+}
+
+#[derive(Deserialize)]
+struct ResponseHead {
+    status: u16,
+    #[serde(default)]
+    headers: Vec<(String, String)>,
+}
+
+#[derive(Clone)]
+pub struct WasmPlugin {
+    engine: Engine,
+    module: Module,
+    limits: PluginLimits,
+}
+
+impl WasmPlugin {
+    pub fn load(path: &Path, limits: PluginLimits) -> anyhow::Result<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).context("creating wasm engine")?;
+        let module = Module::from_file(&engine, path)
+            .with_context(|| format!("loading wasm plugin {}", path.display()))?;
+
+        for export in ["memory", "alloc", "handle"] {
+            if module.get_export(export).is_none() {
+                bail!("wasm plugin {} doesn't export {export}", path.display());
+            }
+        }
+        Ok(Self {
+            engine,
+            module,
+            limits,
+        })
+    }
+
+    /// Runs the plugin to completion. Blocking, so it's called on the blocking pool.
+    fn invoke(&self, head: &[u8], body: &[u8]) -> anyhow::Result<Response> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(self.limits.max_memory)
+            .build();
+        let mut store = Store::new(&self.engine, limits);
+        store.limiter(|limits: &mut StoreLimits| limits);
+        store
+            .set_fuel(self.limits.fuel)
+            .context("setting plugin fuel")?;
+
+        let instance = Linker::new(&self.engine)
+            .instantiate(&mut store, &self.module)
+            .context("instantiating wasm plugin")?;
+        let head_ptr = write_buffer(&mut store, &instance, head)?;
+        let body_ptr = write_buffer(&mut store, &instance, body)?;
+
+        let handle = instance
+            .get_typed_func::<(i32, i32, i32, i32), i64>(&mut store, "handle")
+            .context("looking up plugin handle function")?;
+        let packed = handle
+            .call(
+                &mut store,
+                (head_ptr, head.len() as i32, body_ptr, body.len() as i32),
+            )
+            .context("running wasm plugin")?;
+
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .context("plugin has no memory export")?;
+        let data = memory.data(&store);
+        let (ptr, len) = ((packed >> 32) as u32 as usize, packed as u32 as usize);
+        let output = data
+            .get(ptr..ptr + len)
+            .context("plugin returned a buffer outside its memory")?;
+        parse_output(output)
+    }
+}
+
+fn write_buffer(
+    store: &mut Store<StoreLimits>,
+    instance: &Instance,
+    data: &[u8],
+) -> anyhow::Result<i32> {
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&mut *store, "alloc")
+        .context("looking up plugin alloc function")?;
+    let ptr = alloc
+        .call(&mut *store, data.len() as i32)
+        .context("allocating plugin memory")?;
+    let memory = instance
+        .get_memory(&mut *store, "memory")
+        .context("plugin has no memory export")?;
+    memory
+        .write(&mut *store, ptr as u32 as usize, data)
+        .context("writing to plugin memory")?;
+    Ok(ptr)
+}
+
+fn parse_output(output: &[u8]) -> anyhow::Result<Response> {
+    if output.len() < 4 {
+        bail!("plugin output too short");
+    }
+    let (len, rest) = output.split_at(4);
+    let head_len = u32::from_le_bytes(len.try_into().expect("4 bytes")) as usize;
+    if rest.len() < head_len {
+        bail!("plugin response head is truncated");
+    }
+    let (head, body) = rest.split_at(head_len);
+    let head: ResponseHead =
+        serde_json::from_slice(head).context("parsing plugin response head")?;
+    if !(100..1000).contains(&head.status) {
+        bail!("plugin returned invalid status {}", head.status);
+    }
+
+    let mut response = Response::builder()
+        .status(StatusCode(head.status))
+        .body(Bytes::copy_from_slice(body));
+    for (name, value) in &head.headers {
+        response.headers.append(name, value);
+    }
+    Ok(response)
+}
+
+impl Handler for WasmPlugin {
+    fn call(&self, mut req: Request) -> BoxFuture<'static, Result<Response, HttpError>> {
+        let plugin = self.clone();
+        Box::pin(async move {
+            let body = std::mem::replace(&mut req.body, Body::empty())
+                .to_bytes(DEFAULT_BODY_LIMIT)
+                .await?;
+            let head = serde_json::to_vec(&RequestHead {
+                method: req.method.as_str(),
+                path: &req.path,
+                query: &req.query,
+                headers: req.headers.iter().collect(),
+            })
+            .context("serializing plugin request")?;
+
+            let response = tokio::task::spawn_blocking(move || plugin.invoke(&head, &body))
+                .await
+                .context("joining plugin task")?;
+            response.map_err(|e| HttpError::new(StatusCode::BAD_GATEWAY, e))
+        })
+    }
+}
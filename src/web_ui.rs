@@ -0,0 +1,109 @@
+//! A page at `/_ui` for browsing the served directory from a browser: it lists directories,
+//! previews text and images, and uploads and deletes files, all through the `/files` routes,
+//! so whatever guards those guards it too. Its assets are built into the binary.
+//!
+//! The one thing `/files` can't do is list a directory, which `/_ui/list/{*dir}` does as JSON:
+//!
+//! ```json
+//! {"entries":[{"name":"photos","dir":true,"size":null,"modified":"2024-10-10T13:55:36.012Z"}]}
+//! ```
+
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::{
+    access_file::ACCESS_FILE,
+    access_log::rfc3339_time,
+    error::HttpError,
+    extract::{Path, State},
+    headers::{CACHE_CONTROL, CONTENT_SECURITY_POLICY, CONTENT_TYPE},
+    response::{Json, Response},
+    router::Router,
+    safe_path, served_dir,
+    state::AppState,
+    status::StatusCode,
+};
+
+const INDEX: &str = include_str!("web_ui/index.html");
+const SCRIPT: &str = include_str!("web_ui/app.js");
+const STYLE: &str = include_str!("web_ui/style.css");
+
+/// Only the page's own script, styles and the blobs it previews images from.
+const POLICY: &str =
+    "default-src 'self'; img-src 'self' blob:; object-src 'none'; frame-ancestors 'none'";
+
+/// Adds the page, its assets and the listing to `router`, which needs the [`AppState`].
+pub fn routes(router: Router) -> Router {
+    router
+        .get("/_ui", || async {
+            asset("text/html; charset=utf-8", INDEX)
+        })
+        .get("/_ui/", || async {
+            asset("text/html; charset=utf-8", INDEX)
+        })
+        .get("/_ui/app.js", || async {
+            asset("text/javascript; charset=utf-8", SCRIPT)
+        })
+        .get("/_ui/style.css", || async {
+            asset("text/css; charset=utf-8", STYLE)
+        })
+        .get("/_ui/list", list_root)
+        .get("/_ui/list/{*dir}", list)
+}
+
+fn asset(content_type: &str, body: &'static str) -> Response {
+    let mut response = Response::text(StatusCode::OK, body);
+    response.set_header(CONTENT_TYPE, content_type);
+    // They only change with the binary, but that can happen any time.
+    response.set_header(CACHE_CONTROL, "no-cache");
+    response.set_header(CONTENT_SECURITY_POLICY, POLICY);
+    response
+}
+
+#[derive(Serialize)]
+struct Listing {
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    name: String,
+    dir: bool,
+    /// For files only.
+    size: Option<u64>,
+    modified: Option<String>,
+}
+
+async fn list_root(state: State<Arc<AppState>>) -> Result<Json<Listing>, HttpError> {
+    list(state, Path(String::new())).await
+}
+
+/// Directories first, then files, each by name. The access files are left out, as the
+/// server never serves them.
+async fn list(
+    State(state): State<Arc<AppState>>,
+    Path(dir): Path<String>,
+) -> Result<Json<Listing>, HttpError> {
+    let dir = safe_path::resolve(&dir)?;
+    let names = state
+        .base_dir
+        .read_dir(&dir)
+        .await
+        .map_err(served_dir::not_found)?;
+    let mut entries = Vec::with_capacity(names.len());
+    for (name, is_dir) in names {
+        if name == ACCESS_FILE {
+            continue;
+        }
+        let metadata = state.base_dir.metadata(&dir.join(&name)).await.ok();
+        entries.push(Entry {
+            name: name.to_string_lossy().into_owned(),
+            dir: is_dir,
+            size: metadata.as_ref().filter(|_| !is_dir).map(|m| m.len()),
+            modified: metadata.and_then(|m| m.modified().ok()).map(rfc3339_time),
+        });
+    }
+    entries.sort_by(|a, b| b.dir.cmp(&a.dir).then_with(|| a.name.cmp(&b.name)));
+    Ok(Json(Listing { entries }))
+}
@@ -0,0 +1,147 @@
+"use strict";
+
+// Everything goes through the server's /files API; /_ui/list only adds directory listings.
+const TEXT = /\.(txt|md|log|csv|json|xml|html?|css|js|ts|rs|toml|ya?ml|ini|conf|sh|py|c|h|go)$/i;
+const IMAGES = { png: "image/png", jpg: "image/jpeg", jpeg: "image/jpeg", gif: "image/gif", webp: "image/webp", svg: "image/svg+xml", ico: "image/x-icon" };
+const MAX_PREVIEW = 1024 * 1024;
+
+const $ = (id) => document.getElementById(id);
+let dir = "";
+
+function encode(path) {
+  return path.split("/").filter(Boolean).map(encodeURIComponent).join("/");
+}
+
+function element(tag, text, attrs = {}) {
+  const el = document.createElement(tag);
+  if (text !== undefined) el.textContent = text;
+  Object.assign(el, attrs);
+  return el;
+}
+
+function size(bytes) {
+  if (bytes == null) return "";
+  const units = ["B", "KiB", "MiB", "GiB", "TiB"];
+  let i = 0;
+  while (bytes >= 1024 && i < units.length - 1) { bytes /= 1024; i++; }
+  return (i ? bytes.toFixed(1) : bytes) + " " + units[i];
+}
+
+function status(message, error = false) {
+  $("status").textContent = message;
+  $("status").className = error ? "error" : "";
+}
+
+async function failure(response) {
+  const text = (await response.text()).trim();
+  return `${response.status} ${response.statusText}${text ? ": " + text : ""}`;
+}
+
+function crumbs() {
+  const nav = $("crumbs");
+  nav.replaceChildren();
+  const parts = dir.split("/").filter(Boolean);
+  const link = (name, path) => {
+    const a = element("a", name, { href: "#" + encode(path) });
+    nav.append(a);
+  };
+  link("Files", "");
+  parts.forEach((part, i) => {
+    nav.append(" / ");
+    link(part, parts.slice(0, i + 1).join("/"));
+  });
+}
+
+async function load() {
+  dir = decodeURIComponent(location.hash.slice(1)).replace(/^\/+|\/+$/g, "");
+  crumbs();
+  const response = await fetch("/_ui/list/" + encode(dir));
+  if (!response.ok) return status(await failure(response), true);
+  const { entries } = await response.json();
+  const rows = entries.map((entry) => {
+    const path = dir ? dir + "/" + entry.name : entry.name;
+    const row = element("tr");
+    const name = element("td");
+    if (entry.dir) {
+      name.append(element("a", entry.name + "/", { href: "#" + encode(path) }));
+    } else {
+      name.append(element("a", entry.name, { href: "/files/" + encode(path), onclick: (e) => preview(e, path, entry) }));
+    }
+    const modified = entry.modified ? new Date(entry.modified).toLocaleString() : "";
+    const actions = element("td");
+    if (!entry.dir) {
+      actions.append(element("a", "Download", { href: "/files/" + encode(path), download: entry.name }), " ");
+      actions.append(element("button", "Delete", { className: "delete", onclick: () => remove(path) }));
+    }
+    row.append(name, element("td", entry.dir ? "" : size(entry.size)), element("td", modified), actions);
+    return row;
+  });
+  $("entries").replaceChildren(...rows);
+  if (!entries.length) {
+    const row = element("tr");
+    row.append(element("td", "Nothing here yet.", { colSpan: 4 }));
+    $("entries").append(row);
+  }
+}
+
+async function preview(event, path, entry) {
+  const extension = (entry.name.split(".").pop() || "").toLowerCase();
+  const image = IMAGES[extension];
+  if ((!image && !TEXT.test(entry.name)) || entry.size > MAX_PREVIEW) return;
+  event.preventDefault();
+  const response = await fetch("/files/" + encode(path));
+  if (!response.ok) return status(await failure(response), true);
+  const body = $("preview-body");
+  if (image) {
+    const blob = new Blob([await response.arrayBuffer()], { type: image });
+    body.replaceChildren(element("img", undefined, { src: URL.createObjectURL(blob), alt: entry.name }));
+  } else {
+    body.replaceChildren(element("pre", await response.text()));
+  }
+  $("preview-name").textContent = entry.name;
+  $("preview").hidden = false;
+}
+
+function closePreview() {
+  const img = $("preview-body").querySelector("img");
+  if (img) URL.revokeObjectURL(img.src);
+  $("preview").hidden = true;
+}
+
+async function upload(files) {
+  for (const file of files) {
+    status(`Uploading ${file.name}…`);
+    const path = dir ? dir + "/" + file.name : file.name;
+    const response = await fetch("/files/" + encode(path), {
+      method: "PUT",
+      body: file,
+      headers: { "Content-Type": file.type || "application/octet-stream" },
+    });
+    if (!response.ok) return status(`Uploading ${file.name} failed: ${await failure(response)}`, true);
+  }
+  status(`Uploaded ${files.length} file${files.length === 1 ? "" : "s"}.`);
+  load();
+}
+
+async function remove(path) {
+  if (!confirm(`Delete ${path}?`)) return;
+  const response = await fetch("/files/" + encode(path), { method: "DELETE" });
+  if (!response.ok) return status(`Deleting ${path} failed: ${await failure(response)}`, true);
+  status(`Deleted ${path}.`);
+  load();
+}
+
+window.addEventListener("hashchange", () => { status(""); load(); });
+window.addEventListener("DOMContentLoaded", () => {
+  const drop = $("drop");
+  $("picker").addEventListener("change", (e) => { upload([...e.target.files]); e.target.value = ""; });
+  $("preview-close").addEventListener("click", closePreview);
+  drop.addEventListener("dragover", (e) => { e.preventDefault(); drop.classList.add("dragging"); });
+  drop.addEventListener("dragleave", () => drop.classList.remove("dragging"));
+  drop.addEventListener("drop", (e) => {
+    e.preventDefault();
+    drop.classList.remove("dragging");
+    upload([...e.dataTransfer.files]);
+  });
+  load();
+});
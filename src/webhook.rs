@@ -0,0 +1,36 @@
+//! Blocking JSON POSTs to `http://` endpoints, for what sends from a thread of its own and
+//! only needs to know whether the other end took it.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use crate::proxy::Upstream;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts `body` to `path` below `endpoint`, failing unless it answers with a 2xx status.
+pub fn post_json(endpoint: &Upstream, path: &str, body: &str) -> io::Result<()> {
+    let mut stream = TcpStream::connect(endpoint.address())?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let head = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        endpoint.target(path, ""),
+        endpoint.authority(),
+        body.len()
+    );
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(body.as_bytes())?;
+    // The status line is all that matters; the rest is read so the close is clean.
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let status_line = response.split(|&b| b == b'\r').next().unwrap_or_default();
+    let status_line = String::from_utf8_lossy(status_line);
+    match status_line.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(format!("answered {status_line:?}"))),
+    }
+}
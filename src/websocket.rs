@@ -0,0 +1,561 @@
+//! WebSocket connections ([RFC 6455]), taken over from a request through its
+//! [`OnUpgrade`](crate::upgrade::OnUpgrade).
+//!
+//! A handler takes a [`WebSocketUpgrade`], which refuses requests that aren't a valid
+//! handshake, and answers with [`WebSocketUpgrade::on_upgrade`], giving it what to do with the
+//! [`WebSocket`] once the 101 is out:
+//!
+//! ```ignore
+//! async fn echo(ws: WebSocketUpgrade) -> Response {
+//!     ws.on_upgrade(|socket| async move {
+//!         let (sender, mut receiver) = socket.split();
+//!         while let Some(message) = receiver.recv().await {
+//!             if sender.send(message).await.is_err() {
+//!                 break;
+//!             }
+//!         }
+//!     })
+//! }
+//! ```
+//!
+//! The [`Sender`] half can be cloned and used from several tasks at once: each message goes
+//! out whole, never interleaved with another. The [`Receiver`] answers pings and the closing
+//! handshake itself, through the same sender, so handlers only ever see text and binary
+//! messages. Extensions like compression aren't offered.
+//!
+//! [RFC 6455]: https://www.rfc-editor.org/rfc/rfc6455
+
+use std::{future::Future, io, sync::Arc};
+
+use anyhow::Context as _;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytes::Bytes;
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    error::HttpError,
+    extract::FromRequest,
+    handler::BoxFuture,
+    headers::{CONNECTION, UPGRADE},
+    request::{Method, Request},
+    response::Response,
+    status::StatusCode,
+    upgrade::OnUpgrade,
+};
+
+type Reader = Box<dyn AsyncRead + Send + Unpin>;
+type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+const SEC_WEBSOCKET_ACCEPT: &str = "Sec-WebSocket-Accept";
+const SEC_WEBSOCKET_KEY: &str = "Sec-WebSocket-Key";
+const SEC_WEBSOCKET_VERSION: &str = "Sec-WebSocket-Version";
+
+/// What the key is suffixed with before hashing it for `Sec-WebSocket-Accept`.
+const HANDSHAKE_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// A text or binary message, or a close with its code and reason.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Text(String),
+    Binary(Bytes),
+    Close(Option<(u16, String)>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_owned())
+    }
+}
+
+impl From<Bytes> for Message {
+    fn from(bytes: Bytes) -> Self {
+        Message::Binary(bytes)
+    }
+}
+
+/// A request to open a WebSocket, checked to be a valid handshake.
+pub struct WebSocketUpgrade {
+    key: String,
+    on_upgrade: OnUpgrade,
+    max_message: usize,
+}
+
+/// A 400 for requests that aren't a WebSocket handshake, and a 426 for ones of a version
+/// other than 13.
+impl FromRequest for WebSocketUpgrade {
+    fn from_request(req: &mut Request) -> BoxFuture<'_, Result<Self, HttpError>> {
+        Box::pin(async move {
+            let has_token = |name: &str, token: &str| {
+                req.headers
+                    .get_all(name)
+                    .flat_map(|value| value.split(','))
+                    .any(|value| value.trim().eq_ignore_ascii_case(token))
+            };
+            if req.method != Method::Get || !has_token(UPGRADE, "websocket") {
+                return Err(HttpError::bad_request("Expected a WebSocket handshake"));
+            }
+            if !has_token(CONNECTION, "upgrade") {
+                return Err(HttpError::bad_request(
+                    "The handshake needs Connection: Upgrade",
+                ));
+            }
+            if req.header(SEC_WEBSOCKET_VERSION) != Some("13") {
+                return Err(HttpError::new(
+                    StatusCode(426),
+                    anyhow::anyhow!("Only version 13 of WebSocket is spoken here"),
+                )
+                .with_header(SEC_WEBSOCKET_VERSION, "13"));
+            }
+            let key = req.header(SEC_WEBSOCKET_KEY).unwrap_or_default().trim();
+            if STANDARD.decode(key).map_or(true, |nonce| nonce.len() != 16) {
+                return Err(HttpError::bad_request("Invalid Sec-WebSocket-Key"));
+            }
+            let key = key.to_owned();
+            let on_upgrade = OnUpgrade::from_request(req)
+                .context("the connection can't be taken over for a WebSocket")?;
+            Ok(WebSocketUpgrade {
+                key,
+                on_upgrade,
+                max_message: 1024 * 1024,
+            })
+        })
+    }
+}
+
+impl WebSocketUpgrade {
+    /// The biggest message taken, in bytes, all its fragments together; the connection is
+    /// closed with 1009 on a bigger one. 1 MiB by default.
+    pub fn max_message(mut self, bytes: usize) -> Self {
+        self.max_message = bytes;
+        self
+    }
+
+    /// The 101 to answer with, running `callback` on a task of its own with the socket once
+    /// it's been sent.
+    pub fn on_upgrade<F, Fut>(self, callback: F) -> Response
+    where
+        F: FnOnce(WebSocket) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let accept = accept_key(&self.key);
+        let max_message = self.max_message;
+        let on_upgrade = self.on_upgrade;
+        tokio::spawn(async move {
+            match on_upgrade.await {
+                Ok(upgraded) => callback(WebSocket::from_io(upgraded, max_message)).await,
+                Err(e) => debug!("WebSocket wasn't opened: {e}"),
+            }
+        });
+        Response::empty(StatusCode::SWITCHING_PROTOCOLS)
+            .with_header(UPGRADE, "websocket")
+            .with_header(CONNECTION, "Upgrade")
+            .with_header(SEC_WEBSOCKET_ACCEPT, &accept)
+    }
+}
+
+/// `Sec-WebSocket-Accept` for `key`. The SHA-1 proves the server understood the handshake,
+/// not anything about security.
+fn accept_key(key: &str) -> String {
+    STANDARD.encode(Sha1::digest(format!("{key}{HANDSHAKE_GUID}").as_bytes()))
+}
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+/// Close codes.
+const PROTOCOL_ERROR: u16 = 1002;
+const INVALID_DATA: u16 = 1007;
+const TOO_BIG: u16 = 1009;
+
+/// An open WebSocket, to [`split`](Self::split) into its halves.
+pub struct WebSocket {
+    reader: Reader,
+    writer: Writer,
+    max_message: usize,
+}
+
+impl WebSocket {
+    /// Over any connection the handshake has already been done on.
+    fn from_io<S>(io: S, max_message: usize) -> Self
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        let (reader, writer) = tokio::io::split(io);
+        Self {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            max_message,
+        }
+    }
+
+    pub fn split(self) -> (Sender, Receiver) {
+        let sender = Sender {
+            writer: Arc::new(Mutex::new(Some(self.writer))),
+        };
+        let receiver = Receiver {
+            reader: self.reader,
+            sender: sender.clone(),
+            max_message: self.max_message,
+        };
+        (sender, receiver)
+    }
+}
+
+/// Sends messages, whole, from however many tasks hold a clone of it.
+#[derive(Clone)]
+pub struct Sender {
+    /// `None` once a close has been sent, after which nothing else may be.
+    writer: Arc<Mutex<Option<Writer>>>,
+}
+
+impl Sender {
+    pub async fn send(&self, message: impl Into<Message>) -> io::Result<()> {
+        match message.into() {
+            Message::Text(text) => self.frame(TEXT, text.as_bytes()).await,
+            Message::Binary(bytes) => self.frame(BINARY, &bytes).await,
+            Message::Close(close) => self.close(close).await,
+        }
+    }
+
+    /// Sends a close, which is the last thing sent, and shuts the connection down for writing.
+    pub async fn close(&self, close: Option<(u16, String)>) -> io::Result<()> {
+        let mut payload = Vec::new();
+        if let Some((code, reason)) = close {
+            payload.extend_from_slice(&code.to_be_bytes());
+            // Control frames carry at most 125 bytes.
+            let mut end = reason.len().min(123);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            payload.extend_from_slice(&reason.as_bytes()[..end]);
+        }
+        self.frame(CLOSE, &payload).await?;
+        let writer = self.writer.lock().await.take();
+        if let Some(mut writer) = writer {
+            writer.shutdown().await?;
+        }
+        Ok(())
+    }
+
+    async fn frame(&self, opcode: u8, payload: &[u8]) -> io::Result<()> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(0x80 | opcode);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(payload);
+        let mut writer = self.writer.lock().await;
+        let writer = writer
+            .as_mut()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::BrokenPipe, "the WebSocket is closed"))?;
+        writer.write_all(&frame).await?;
+        writer.flush().await
+    }
+}
+
+/// Receives messages, answering pings and the close handshake along the way.
+pub struct Receiver {
+    reader: Reader,
+    sender: Sender,
+    max_message: usize,
+}
+
+/// Why reading stopped: the connection broke, or the client broke the protocol.
+enum Stop {
+    Io(io::Error),
+    Fail(u16, &'static str),
+}
+
+impl From<io::Error> for Stop {
+    fn from(e: io::Error) -> Self {
+        Stop::Io(e)
+    }
+}
+
+impl Receiver {
+    /// The next text or binary message, `None` once the socket is closed, by either side or
+    /// by the connection breaking.
+    pub async fn recv(&mut self) -> Option<Message> {
+        match self.read_message().await {
+            Ok(message) => message,
+            Err(Stop::Io(e)) => {
+                debug!("WebSocket connection broke: {e}");
+                None
+            }
+            Err(Stop::Fail(code, why)) => {
+                warn!("Closing a WebSocket: {why}");
+                let _ = self.sender.close(Some((code, why.to_owned()))).await;
+                None
+            }
+        }
+    }
+
+    async fn read_message(&mut self) -> Result<Option<Message>, Stop> {
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let (fin, opcode, payload) = self.read_frame().await?;
+            match opcode {
+                PING => {
+                    // A client that's gone doesn't need its pong.
+                    let _ = self.sender.frame(PONG, &payload).await;
+                }
+                PONG => {}
+                CLOSE => {
+                    let close = match payload.len() {
+                        0 => None,
+                        1 => return Err(Stop::Fail(PROTOCOL_ERROR, "malformed close")),
+                        _ => {
+                            let code = u16::from_be_bytes([payload[0], payload[1]]);
+                            let reason = String::from_utf8(payload[2..].to_vec())
+                                .map_err(|_| Stop::Fail(INVALID_DATA, "close reason not UTF-8"))?;
+                            Some((code, reason))
+                        }
+                    };
+                    // Echoing the code back completes the handshake.
+                    let _ = self
+                        .sender
+                        .close(close.map(|(code, _)| (code, String::new())))
+                        .await;
+                    return Ok(None);
+                }
+                TEXT | BINARY if message.is_some() => {
+                    return Err(Stop::Fail(
+                        PROTOCOL_ERROR,
+                        "new message inside a fragmented one",
+                    ))
+                }
+                TEXT | BINARY => message = Some((opcode, payload)),
+                CONTINUATION => match &mut message {
+                    Some((_, data)) => data.extend_from_slice(&payload),
+                    None => return Err(Stop::Fail(PROTOCOL_ERROR, "continuation of nothing")),
+                },
+                _ => return Err(Stop::Fail(PROTOCOL_ERROR, "unknown opcode")),
+            }
+            if let Some((_, data)) = &message {
+                if data.len() > self.max_message {
+                    return Err(Stop::Fail(TOO_BIG, "message too big"));
+                }
+            }
+            if !fin || !matches!(opcode, TEXT | BINARY | CONTINUATION) {
+                continue;
+            }
+            let Some((kind, data)) = message.take() else {
+                continue;
+            };
+            return match kind {
+                TEXT => String::from_utf8(data)
+                    .map(|text| Some(Message::Text(text)))
+                    .map_err(|_| Stop::Fail(INVALID_DATA, "text message not UTF-8")),
+                _ => Ok(Some(Message::Binary(data.into()))),
+            };
+        }
+    }
+
+    /// A frame's FIN bit, opcode and unmasked payload.
+    async fn read_frame(&mut self) -> Result<(bool, u8, Vec<u8>), Stop> {
+        let mut head = [0; 2];
+        self.reader.read_exact(&mut head).await?;
+        let fin = head[0] & 0x80 != 0;
+        let opcode = head[0] & 0x0f;
+        if head[0] & 0x70 != 0 {
+            return Err(Stop::Fail(PROTOCOL_ERROR, "reserved bits set"));
+        }
+        if head[1] & 0x80 == 0 {
+            return Err(Stop::Fail(PROTOCOL_ERROR, "unmasked frame from the client"));
+        }
+        let len = match head[1] & 0x7f {
+            126 => u64::from(self.reader.read_u16().await?),
+            127 => self.reader.read_u64().await?,
+            len => u64::from(len),
+        };
+        if opcode & 0x8 != 0 && (len > 125 || !fin) {
+            return Err(Stop::Fail(
+                PROTOCOL_ERROR,
+                "oversized or fragmented control frame",
+            ));
+        }
+        if len > self.max_message as u64 {
+            return Err(Stop::Fail(TOO_BIG, "message too big"));
+        }
+        let mut mask = [0; 4];
+        self.reader.read_exact(&mut mask).await?;
+        let mut payload = vec![0; len as usize];
+        self.reader.read_exact(&mut payload).await?;
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok((fin, opcode, payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{duplex, DuplexStream};
+
+    use super::*;
+
+    /// A frame as a client sends it, masked.
+    fn frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![u8::from(fin) << 7 | opcode];
+        match payload.len() {
+            len @ 0..=125 => frame.push(0x80 | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(0x80 | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+            len => {
+                frame.push(0x80 | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            }
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    /// The server's receiver, with the client end already having sent `sent`.
+    async fn receiving(sent: &[u8], max_message: usize) -> (Receiver, DuplexStream) {
+        let (mut client, server) = duplex(1 << 20);
+        client.write_all(sent).await.unwrap();
+        let (_, receiver) = WebSocket::from_io(server, max_message).split();
+        (receiver, client)
+    }
+
+    /// Everything the server sent, up to it shutting down its side.
+    async fn sent_back(client: &mut DuplexStream) -> Vec<u8> {
+        let mut sent = Vec::new();
+        client.read_to_end(&mut sent).await.unwrap();
+        sent
+    }
+
+    /// The close code the server fails the connection with after `sent`.
+    async fn closed_with(sent: &[u8], max_message: usize) -> u16 {
+        let (mut receiver, mut client) = receiving(sent, max_message).await;
+        assert!(receiver.recv().await.is_none());
+        let back = sent_back(&mut client).await;
+        assert_eq!(back[0], 0x80 | CLOSE, "expected a close, got {back:?}");
+        u16::from_be_bytes([back[2], back[3]])
+    }
+
+    #[test]
+    fn accept_key_matches_the_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn unmasks_frames() {
+        // The masked "Hello" from RFC 6455, section 5.7.
+        let sent = [
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ];
+        let (mut receiver, _client) = receiving(&sent, 1024).await;
+        assert_eq!(receiver.recv().await, Some(Message::Text("Hello".into())));
+    }
+
+    #[tokio::test]
+    async fn joins_fragments() {
+        let mut sent = frame(false, TEXT, b"Hel");
+        sent.extend(frame(true, CONTINUATION, b"lo"));
+        sent.extend(frame(true, BINARY, &[0; 200]));
+        let (mut receiver, _client) = receiving(&sent, 1024).await;
+        assert_eq!(receiver.recv().await, Some(Message::Text("Hello".into())));
+        let binary = Message::Binary(Bytes::from_static(&[0; 200]));
+        assert_eq!(receiver.recv().await, Some(binary));
+    }
+
+    #[tokio::test]
+    async fn answers_pings_between_fragments() {
+        let mut sent = frame(false, TEXT, b"Hel");
+        sent.extend(frame(true, PING, b"are you there"));
+        sent.extend(frame(true, CONTINUATION, b"lo"));
+        sent.extend(frame(true, CLOSE, &1000u16.to_be_bytes()));
+        let (mut receiver, mut client) = receiving(&sent, 1024).await;
+        assert_eq!(receiver.recv().await, Some(Message::Text("Hello".into())));
+        assert_eq!(receiver.recv().await, None);
+        let mut pong = vec![0x80 | PONG, 13];
+        pong.extend_from_slice(b"are you there");
+        // The close echoes the client's code.
+        pong.extend_from_slice(&[0x80 | CLOSE, 2, 0x03, 0xe8]);
+        assert_eq!(sent_back(&mut client).await, pong);
+    }
+
+    #[tokio::test]
+    async fn refuses_unmasked_frames() {
+        assert_eq!(
+            closed_with(&[0x81, 0x02, b'h', b'i'], 1024).await,
+            PROTOCOL_ERROR
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_reserved_bits() {
+        let mut sent = frame(true, TEXT, b"hi");
+        sent[0] |= 0x40;
+        assert_eq!(closed_with(&sent, 1024).await, PROTOCOL_ERROR);
+    }
+
+    #[tokio::test]
+    async fn limits_control_frames() {
+        let oversized = frame(true, PING, &[0; 126]);
+        assert_eq!(closed_with(&oversized, 1024).await, PROTOCOL_ERROR);
+        let fragmented = frame(false, PING, b"hi");
+        assert_eq!(closed_with(&fragmented, 1024).await, PROTOCOL_ERROR);
+    }
+
+    #[tokio::test]
+    async fn refuses_broken_fragmentation() {
+        let stray = frame(true, CONTINUATION, b"hi");
+        assert_eq!(closed_with(&stray, 1024).await, PROTOCOL_ERROR);
+        let mut interleaved = frame(false, TEXT, b"hi");
+        interleaved.extend(frame(true, TEXT, b"there"));
+        assert_eq!(closed_with(&interleaved, 1024).await, PROTOCOL_ERROR);
+    }
+
+    #[tokio::test]
+    async fn caps_messages() {
+        // A frame that says it's too big is refused before its payload is read.
+        let big = frame(true, BINARY, &[0; 1025]);
+        assert_eq!(closed_with(&big[..10], 1024).await, TOO_BIG);
+        // As is a message that grows too big a fragment at a time.
+        let mut fragments = frame(false, BINARY, &[0; 1000]);
+        fragments.extend(frame(true, CONTINUATION, &[0; 25]));
+        assert_eq!(closed_with(&fragments, 1024).await, TOO_BIG);
+        let mut fits = frame(false, BINARY, &[0; 1000]);
+        fits.extend(frame(true, CONTINUATION, &[0; 24]));
+        let (mut receiver, _client) = receiving(&fits, 1024).await;
+        assert!(matches!(receiver.recv().await, Some(Message::Binary(b)) if b.len() == 1024));
+    }
+
+    #[tokio::test]
+    async fn refuses_text_that_isnt_utf8() {
+        let sent = frame(true, TEXT, &[0xff, 0xfe]);
+        assert_eq!(closed_with(&sent, 1024).await, INVALID_DATA);
+    }
+}
@@ -0,0 +1,249 @@
+//! Hexdumps of the bytes a connection reads and writes, for working out what a client that
+//! gets framing wrong actually sent. They're logged as they go by, after TLS, one event per
+//! read or write, each line with the offset into that direction of the connection:
+//!
+//! ```text
+//! read 35 bytes
+//! < 00000000  47 45 54 20 2f 20 48 54  54 50 2f 31 2e 31 0d 0a  |GET / HTTP/1.1..|
+//! < 00000010  48 6f 73 74 3a 20 6c 6f  63 61 6c 68 6f 73 74 0d  |Host: localhost.|
+//! < 00000020  0a 0d 0a                                          |...|
+//! ```
+
+use std::{
+    fmt::Write as _,
+    io,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::info;
+
+/// Headers whose values [`WireTrace::redact`] masks.
+const SECRET_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+];
+/// Header names longer than any of the secret ones don't need looking at.
+const LONGEST_SECRET: usize = 19;
+
+/// How much of each connection is dumped.
+#[derive(Debug, Clone, Copy)]
+pub struct WireTrace {
+    limit: u64,
+    redact: bool,
+}
+
+impl WireTrace {
+    /// Dumps up to `limit` bytes each way on every connection, then just says there's more.
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            redact: false,
+        }
+    }
+
+    /// Masks the values of `Authorization`, `Cookie` and the like with `*`s, keeping their
+    /// length so that the framing still shows.
+    pub fn redact(mut self, redact: bool) -> Self {
+        self.redact = redact;
+        self
+    }
+}
+
+/// A connection whose bytes are dumped if there's a [`WireTrace`] for it, and passed through
+/// untouched if not.
+pub(crate) struct Traced<S> {
+    inner: S,
+    trace: Option<WireTrace>,
+    read: Direction,
+    written: Direction,
+}
+
+impl<S> Traced<S> {
+    pub(crate) fn new(inner: S, trace: Option<WireTrace>) -> Self {
+        Self {
+            inner,
+            trace,
+            read: Direction::new('<', "read"),
+            written: Direction::new('>', "wrote"),
+        }
+    }
+}
+
+/// One way of a connection, as dumped so far.
+struct Direction {
+    marker: char,
+    verb: &'static str,
+    offset: u64,
+    cut_off: bool,
+    header: HeaderState,
+}
+
+/// Where in a line the redaction is, carried over from one read or write to the next since a
+/// header can be split between them.
+enum HeaderState {
+    /// Reading what might be a header name.
+    Name(String),
+    /// In the value of a secret header.
+    Secret,
+    /// In a line that's nothing to hide.
+    Other,
+}
+
+impl Direction {
+    fn new(marker: char, verb: &'static str) -> Self {
+        Self {
+            marker,
+            verb,
+            offset: 0,
+            cut_off: false,
+            header: HeaderState::Name(String::new()),
+        }
+    }
+
+    fn dump(&mut self, trace: &WireTrace, bytes: &[u8]) {
+        if bytes.is_empty() || self.cut_off {
+            return;
+        }
+        let left = trace.limit.saturating_sub(self.offset);
+        let shown = &bytes[..bytes.len().min(left as usize)];
+        if !shown.is_empty() {
+            let shown = match trace.redact {
+                true => self.redact(shown),
+                false => shown.to_vec(),
+            };
+            info!(
+                "{} {} bytes\n{}",
+                self.verb,
+                bytes.len(),
+                hexdump(self.marker, self.offset, &shown)
+            );
+            self.offset += shown.len() as u64;
+        }
+        if shown.len() < bytes.len() {
+            self.cut_off = true;
+            info!(
+                "Not dumping what's {} past the first {} bytes",
+                self.verb, trace.limit
+            );
+        }
+    }
+
+    fn redact(&mut self, bytes: &[u8]) -> Vec<u8> {
+        let mut redacted = bytes.to_vec();
+        for byte in &mut redacted {
+            if *byte == b'\n' {
+                self.header = HeaderState::Name(String::new());
+                continue;
+            }
+            match &mut self.header {
+                HeaderState::Name(name) => match *byte {
+                    b':' => {
+                        let secret = SECRET_HEADERS.contains(&name.trim().to_lowercase().as_str());
+                        self.header = match secret {
+                            true => HeaderState::Secret,
+                            false => HeaderState::Other,
+                        };
+                    }
+                    _ if name.len() >= LONGEST_SECRET => self.header = HeaderState::Other,
+                    _ => name.push(char::from(*byte)),
+                },
+                HeaderState::Secret if *byte != b'\r' && *byte != b' ' => *byte = b'*',
+                HeaderState::Secret | HeaderState::Other => {}
+            }
+        }
+        redacted
+    }
+}
+
+/// `bytes` as lines of 16, each with its offset, hex and printable ASCII.
+fn hexdump(marker: char, offset: u64, bytes: &[u8]) -> String {
+    let mut dump = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        if i > 0 {
+            dump.push('\n');
+        }
+        write!(dump, "{marker} {:08x} ", offset + i as u64 * 16).unwrap();
+        for column in 0..16 {
+            if column == 8 {
+                dump.push(' ');
+            }
+            match line.get(column) {
+                Some(byte) => write!(dump, " {byte:02x}").unwrap(),
+                None => dump.push_str("   "),
+            }
+        }
+        dump.push_str("  |");
+        dump.extend(line.iter().map(|&byte| match byte {
+            0x20..=0x7e => char::from(byte),
+            _ => '.',
+        }));
+        dump.push('|');
+    }
+    dump
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Traced<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let (Poll::Ready(Ok(())), Some(trace)) = (&polled, &this.trace) {
+            this.read.dump(trace, &buf.filled()[before..]);
+        }
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Traced<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(written)), Some(trace)) = (&polled, &this.trace) {
+            this.written.dump(trace, &buf[..*written]);
+        }
+        polled
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        if let (Poll::Ready(Ok(written)), Some(trace)) = (&polled, &this.trace) {
+            let mut left = *written;
+            for buf in bufs {
+                let taken = left.min(buf.len());
+                this.written.dump(trace, &buf[..taken]);
+                left -= taken;
+            }
+        }
+        polled
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}